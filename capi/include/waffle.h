@@ -0,0 +1,62 @@
+/* C bindings for WAFFLE. See capi/src/lib.rs for the documentation of
+ * each function.
+ *
+ * Functions that can fail return NULL or a negative value, and leave a
+ * message for waffle_last_error(). */
+
+#ifndef WAFFLE_H
+#define WAFFLE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct WaffleModule WaffleModule;
+
+typedef struct WaffleBytes {
+    uint8_t *data;
+    size_t len;
+} WaffleBytes;
+
+#define WAFFLE_FUNC_IMPORT 0
+#define WAFFLE_FUNC_BODY 1
+#define WAFFLE_FUNC_UNPARSED 2
+#define WAFFLE_FUNC_COMPILED 3
+
+/* `name` is not NUL-terminated, and is valid only during the callback. */
+typedef struct WaffleFunc {
+    uint32_t index;
+    int kind;
+    const char *name;
+    size_t name_len;
+    uint32_t num_params;
+    uint32_t num_results;
+    size_t num_insts;
+} WaffleFunc;
+
+typedef int (*WaffleFuncCallback)(void *user, const WaffleFunc *func);
+
+/* Valid until the next call that fails on the same thread. */
+const char *waffle_last_error(void);
+
+WaffleModule *waffle_module_load(const uint8_t *bytes, size_t len);
+void waffle_module_free(WaffleModule *module);
+
+/* Comma-separated pass names, e.g. "optimize" or "maxssa,gvn". */
+int waffle_module_run_passes(WaffleModule *module, const char *pipeline);
+
+uint32_t waffle_module_num_funcs(const WaffleModule *module);
+int waffle_module_for_each_func(const WaffleModule *module,
+                                WaffleFuncCallback callback, void *user);
+
+int waffle_module_emit(const WaffleModule *module, WaffleBytes *out);
+void waffle_bytes_free(WaffleBytes bytes);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WAFFLE_H */
@@ -0,0 +1,274 @@
+//! C bindings for WAFFLE: load a module, run a pipeline of passes over
+//! it, look at its functions, and write it back out. The declarations
+//! are in `include/waffle.h`.
+//!
+//! Functions that can fail return null or a negative value, and leave
+//! a message for `waffle_last_error`. Panics are caught and reported
+//! the same way rather than unwinding into the caller.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::mem::ManuallyDrop;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use waffle::entity::EntityRef;
+use waffle::passes::manager::PassManager;
+use waffle::{FrontendOptions, FuncDecl, Module};
+
+/// A module, with the bytes it was parsed from, which it borrows.
+pub struct WaffleModule {
+    module: ManuallyDrop<Module<'static>>,
+    // From `Box::into_raw`, so that borrowing them for `module` does
+    // not alias a live `Box`.
+    bytes: *mut [u8],
+}
+
+impl Drop for WaffleModule {
+    fn drop(&mut self) {
+        unsafe {
+            // The module first, as it borrows the bytes.
+            ManuallyDrop::drop(&mut self.module);
+            drop(Box::from_raw(self.bytes));
+        }
+    }
+}
+
+/// Bytes allocated by WAFFLE, to be freed with `waffle_bytes_free`.
+#[repr(C)]
+pub struct WaffleBytes {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+pub const WAFFLE_FUNC_IMPORT: c_int = 0;
+pub const WAFFLE_FUNC_BODY: c_int = 1;
+pub const WAFFLE_FUNC_UNPARSED: c_int = 2;
+pub const WAFFLE_FUNC_COMPILED: c_int = 3;
+
+/// A function, as passed to the callback of
+/// `waffle_module_for_each_func`. `name` is not NUL-terminated, and is
+/// valid only during the callback.
+#[repr(C)]
+pub struct WaffleFunc {
+    pub index: u32,
+    /// One of the `WAFFLE_FUNC_*` constants.
+    pub kind: c_int,
+    pub name: *const c_char,
+    pub name_len: usize,
+    pub num_params: u32,
+    pub num_results: u32,
+    /// The number of instructions, for bodies parsed into IR; 0 for
+    /// other functions.
+    pub num_insts: usize,
+}
+
+pub type WaffleFuncCallback =
+    Option<unsafe extern "C" fn(user: *mut c_void, func: *const WaffleFunc) -> c_int>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Run `f`, returning `failed` and setting the last error if it fails
+/// or panics.
+fn guard<T, F: FnOnce() -> anyhow::Result<T>>(failed: T, f: F) -> T {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(err)) => {
+            set_error(format!("{:#}", err));
+            failed
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_owned());
+            set_error(format!("panic: {}", message));
+            failed
+        }
+    }
+}
+
+fn module_ref<'a>(module: *const WaffleModule) -> anyhow::Result<&'a WaffleModule> {
+    unsafe { module.as_ref() }.ok_or_else(|| anyhow::anyhow!("Null module"))
+}
+
+fn module_mut<'a>(module: *mut WaffleModule) -> anyhow::Result<&'a mut WaffleModule> {
+    unsafe { module.as_mut() }.ok_or_else(|| anyhow::anyhow!("Null module"))
+}
+
+/// The message of the last error on this thread, or null if there was
+/// none. Valid until the next call that fails on this thread.
+#[no_mangle]
+pub extern "C" fn waffle_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Parse the module in `bytes`, which are copied. Function bodies are
+/// parsed when first needed. Returns null on failure.
+///
+/// # Safety
+///
+/// `bytes` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn waffle_module_load(bytes: *const u8, len: usize) -> *mut WaffleModule {
+    guard(std::ptr::null_mut(), || {
+        if bytes.is_null() {
+            anyhow::bail!("Null bytes");
+        }
+        let copy: Box<[u8]> = std::slice::from_raw_parts(bytes, len).into();
+        let bytes = Box::into_raw(copy);
+        // The module never outlives the bytes: they are freed after it,
+        // when the `WaffleModule` is dropped.
+        let borrowed: &'static [u8] = &*bytes;
+        match Module::from_wasm_bytes(borrowed, &FrontendOptions::default()) {
+            Ok(module) => Ok(Box::into_raw(Box::new(WaffleModule {
+                module: ManuallyDrop::new(module),
+                bytes,
+            }))),
+            Err(err) => {
+                drop(Box::from_raw(bytes));
+                Err(err)
+            }
+        }
+    })
+}
+
+/// Free a module returned by `waffle_module_load`. Null is ignored.
+///
+/// # Safety
+///
+/// `module` must be null or a module not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn waffle_module_free(module: *mut WaffleModule) {
+    if !module.is_null() {
+        drop(Box::from_raw(module));
+    }
+}
+
+/// Run the passes named, comma-separated, in `pipeline` (as accepted by
+/// `PassManager::from_pipeline`) over every body of the module. Returns
+/// 0, or -1 on failure.
+///
+/// # Safety
+///
+/// `module` must be a live module, and `pipeline` a NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn waffle_module_run_passes(
+    module: *mut WaffleModule,
+    pipeline: *const c_char,
+) -> c_int {
+    guard(-1, || {
+        let module = module_mut(module)?;
+        if pipeline.is_null() {
+            anyhow::bail!("Null pipeline");
+        }
+        let pipeline = CStr::from_ptr(pipeline).to_str()?;
+        PassManager::from_pipeline(pipeline)?.run(&mut module.module)?;
+        Ok(0)
+    })
+}
+
+/// The number of functions, imported ones included.
+///
+/// # Safety
+///
+/// `module` must be a live module.
+#[no_mangle]
+pub unsafe extern "C" fn waffle_module_num_funcs(module: *const WaffleModule) -> u32 {
+    guard(0, || Ok(module_ref(module)?.module.funcs.len() as u32))
+}
+
+/// Call `callback` with `user` and each function in order, stopping at
+/// the first call that returns nonzero. Returns that value, 0 if every
+/// call returned 0, or -1 on failure.
+///
+/// # Safety
+///
+/// `module` must be a live module, and `callback` safe to call with
+/// `user`.
+#[no_mangle]
+pub unsafe extern "C" fn waffle_module_for_each_func(
+    module: *const WaffleModule,
+    callback: WaffleFuncCallback,
+    user: *mut c_void,
+) -> c_int {
+    guard(-1, || {
+        let module = &module_ref(module)?.module;
+        let callback = callback.ok_or_else(|| anyhow::anyhow!("Null callback"))?;
+        for (func, decl) in module.funcs.entries() {
+            let kind = match decl {
+                FuncDecl::Import(..) => WAFFLE_FUNC_IMPORT,
+                FuncDecl::Body(..) => WAFFLE_FUNC_BODY,
+                FuncDecl::Lazy(..) => WAFFLE_FUNC_UNPARSED,
+                FuncDecl::Compiled(..) => WAFFLE_FUNC_COMPILED,
+                FuncDecl::None => continue,
+            };
+            let sig = &module.signatures[decl.sig()];
+            let name = decl.name();
+            let info = WaffleFunc {
+                index: func.index() as u32,
+                kind,
+                name: name.as_ptr() as *const c_char,
+                name_len: name.len(),
+                num_params: sig.params.len() as u32,
+                num_results: sig.returns.len() as u32,
+                num_insts: decl.body().map_or(0, |body| {
+                    body.blocks.values().map(|block| block.insts.len()).sum()
+                }),
+            };
+            let result = callback(user, &info);
+            if result != 0 {
+                return Ok(result);
+            }
+        }
+        Ok(0)
+    })
+}
+
+/// Write the module out as Wasm into `out`, to be freed with
+/// `waffle_bytes_free`. Returns 0, or -1 on failure.
+///
+/// # Safety
+///
+/// `module` must be a live module, and `out` writable.
+#[no_mangle]
+pub unsafe extern "C" fn waffle_module_emit(
+    module: *const WaffleModule,
+    out: *mut WaffleBytes,
+) -> c_int {
+    guard(-1, || {
+        let module = module_ref(module)?;
+        let out = out.as_mut().ok_or_else(|| anyhow::anyhow!("Null output"))?;
+        let bytes = module.module.to_wasm_bytes()?.into_boxed_slice();
+        out.len = bytes.len();
+        out.data = Box::into_raw(bytes) as *mut u8;
+        Ok(0)
+    })
+}
+
+/// Free bytes returned by `waffle_module_emit`.
+///
+/// # Safety
+///
+/// `bytes` must have been returned by `waffle_module_emit` and not
+/// freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn waffle_bytes_free(bytes: WaffleBytes) {
+    if !bytes.data.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            bytes.data, bytes.len,
+        )));
+    }
+}
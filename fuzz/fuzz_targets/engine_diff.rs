@@ -0,0 +1,21 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use waffle::differential::{engine_diff_roundtrip, EngineDiffOptions};
+
+fuzz_target!(
+    |module: wasm_smith::ConfiguredModule<waffle::fuzzing::Config>| {
+        let _ = env_logger::try_init();
+        let orig_bytes = module.module.to_bytes();
+        if waffle::fuzzing::reject(&orig_bytes[..]) {
+            log::debug!("Discarding fuzz run. Body:\n{:?}", module);
+            return;
+        } else {
+            log::info!("body: {:?}", module);
+        }
+
+        let compared = engine_diff_roundtrip(&orig_bytes[..], &EngineDiffOptions::default())
+            .expect("round-tripped module behaves differently");
+        log::info!("compared {} runs", compared);
+    }
+);
@@ -0,0 +1,35 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use waffle::fuzzing::{IrModule, Outcome};
+use waffle::{FrontendOptions, InterpContext, Module};
+
+fuzz_target!(|module: IrModule| {
+    let _ = env_logger::try_init();
+    log::debug!("original module:\n{}", module.module.display());
+
+    let mut orig_ctx = InterpContext::new(&module.module).unwrap();
+    orig_ctx.fuel = 10000;
+    let orig = match Outcome::of(orig_ctx.call(&module.module, module.func, &module.args)) {
+        Some(outcome) => outcome,
+        None => {
+            log::trace!("Rejecting due to timeout in orig");
+            return;
+        }
+    };
+
+    // Compile, parse back and optimize, then run again: the generated
+    // IR's behavior must survive the backend and the frontend.
+    let bytes = module.module.to_wasm_bytes().unwrap();
+    let mut parsed_module =
+        Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+    parsed_module.expand_all_funcs().unwrap();
+    parsed_module.per_func_body(|body| body.optimize());
+
+    let mut roundtrip_ctx = InterpContext::new(&parsed_module).unwrap();
+    // Allow a little leeway for the roundtrip to add instructions.
+    roundtrip_ctx.fuel = 20000;
+    let roundtrip = Outcome::of(roundtrip_ctx.call(&parsed_module, module.func, &module.args))
+        .expect("roundtripped function did not finish");
+    assert_eq!(orig, roundtrip);
+});
@@ -0,0 +1,22 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use waffle::fuzzing::IrModule;
+use waffle::{FrontendOptions, Module};
+
+fuzz_target!(|module: IrModule| {
+    let _ = env_logger::try_init();
+    log::debug!("original module:\n{}", module.module.display());
+    let body = module.module.funcs[module.func].body().unwrap();
+    body.validate().expect("generated body does not validate");
+
+    let bytes = module.module.to_wasm_bytes().unwrap();
+    wasmparser::validate(&bytes[..]).expect("compiled module does not validate");
+
+    let mut parsed_module =
+        Module::from_wasm_bytes(&bytes[..], &FrontendOptions::default()).unwrap();
+    parsed_module.expand_all_funcs().unwrap();
+    parsed_module.per_func_body(|body| body.optimize());
+    let bytes = parsed_module.to_wasm_bytes().unwrap();
+    wasmparser::validate(&bytes[..]).expect("recompiled module does not validate");
+});
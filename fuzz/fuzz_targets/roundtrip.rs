@@ -1,7 +1,7 @@
 #![no_main]
 use libfuzzer_sys::fuzz_target;
 
-use waffle::{FrontendError, FrontendOptions, Module};
+use waffle::{FrontendOptions, Module, WaffleError};
 
 fuzz_target!(|module: wasm_smith::Module| {
     let _ = env_logger::try_init();
@@ -11,8 +11,8 @@ fuzz_target!(|module: wasm_smith::Module| {
         match Module::from_wasm_bytes(&orig_bytes[..], &FrontendOptions::default()) {
             Ok(m) => m,
             Err(e) => {
-                match e.downcast::<FrontendError>() {
-                    Ok(FrontendError::UnsupportedFeature(_)) | Ok(FrontendError::TooLarge(_)) => {
+                match e.downcast::<WaffleError>() {
+                    Ok(WaffleError::UnsupportedFeature { .. }) => {
                         // Just skip this case.
                         return;
                     }
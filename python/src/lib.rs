@@ -0,0 +1,465 @@
+//! Python bindings for WAFFLE, for prototyping transformations of
+//! modules from a script or notebook:
+//!
+//! ```python
+//! import waffle
+//!
+//! module = waffle.Module(open("in.wasm", "rb").read())
+//! for func in module.funcs():
+//!     body = module.body(func.index)
+//!     if body is None:
+//!         continue
+//!     for block in body.blocks():
+//!         for value in body.insts(block):
+//!             print(func.name, value, body.value(value).op)
+//! module.run_passes("optimize")
+//! open("out.wasm", "wb").write(module.to_bytes())
+//! ```
+//!
+//! Entities (functions, blocks and values) are passed to and from
+//! Python as their indices. A `Body` is a copy of a function's IR:
+//! changes to it take effect when it is put back with
+//! `Module.set_body`.
+
+// The code generated by pyo3 0.20 for `#[pymethods]` trips this lint.
+#![allow(non_local_definitions)]
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::mem::ManuallyDrop;
+use waffle::entity::EntityRef;
+use waffle::passes::manager::PassManager;
+use waffle::{
+    Block, FrontendOptions, Func, FuncDecl, FunctionBody, Operator, Type, Value, ValueDef,
+};
+
+create_exception!(waffle, WaffleError, PyException);
+
+fn to_py_err(err: anyhow::Error) -> PyErr {
+    WaffleError::new_err(format!("{:#}", err))
+}
+
+fn bad_arg(message: String) -> PyErr {
+    WaffleError::new_err(message)
+}
+
+fn parse_type(ty: &str) -> PyResult<Type> {
+    Ok(match ty {
+        "i32" => Type::I32,
+        "i64" => Type::I64,
+        "f32" => Type::F32,
+        "f64" => Type::F64,
+        "v128" => Type::V128,
+        "funcref" => Type::FuncRef,
+        _ => return Err(bad_arg(format!("Unknown type: {}", ty))),
+    })
+}
+
+fn type_names(tys: &[Type]) -> Vec<String> {
+    tys.iter().map(|ty| ty.to_string()).collect()
+}
+
+/// A module, with the bytes it was parsed from, which it borrows.
+#[pyclass]
+struct Module {
+    module: ManuallyDrop<waffle::Module<'static>>,
+    // From `Box::into_raw`, so that borrowing them for `module` does
+    // not alias a live `Box`.
+    bytes: *mut [u8],
+}
+
+// Only the module reads the bytes, and it is `Send` itself.
+unsafe impl Send for Module {}
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        unsafe {
+            // The module first, as it borrows the bytes.
+            ManuallyDrop::drop(&mut self.module);
+            drop(Box::from_raw(self.bytes));
+        }
+    }
+}
+
+impl Module {
+    fn func(&self, index: usize) -> PyResult<Func> {
+        if index >= self.module.funcs.len() {
+            return Err(bad_arg(format!("No function {}", index)));
+        }
+        Ok(Func::new(index))
+    }
+}
+
+#[pymethods]
+impl Module {
+    /// Parse a module from its bytes, which are copied. Function bodies
+    /// are parsed when first needed.
+    #[new]
+    fn new(bytes: &[u8]) -> PyResult<Module> {
+        let copy: Box<[u8]> = bytes.into();
+        let bytes = Box::into_raw(copy);
+        // The module never outlives the bytes, which are freed after it
+        // when the `Module` is dropped.
+        let borrowed: &'static [u8] = unsafe { &*bytes };
+        match waffle::Module::from_wasm_bytes(borrowed, &FrontendOptions::default()) {
+            Ok(module) => Ok(Module {
+                module: ManuallyDrop::new(module),
+                bytes,
+            }),
+            Err(err) => {
+                drop(unsafe { Box::from_raw(bytes) });
+                Err(to_py_err(err))
+            }
+        }
+    }
+
+    /// Write the module out as Wasm.
+    fn to_bytes<'py>(&self, py: Python<'py>) -> PyResult<&'py PyBytes> {
+        let bytes = self.module.to_wasm_bytes().map_err(to_py_err)?;
+        Ok(PyBytes::new(py, &bytes))
+    }
+
+    /// Run the passes named, comma-separated, in `pipeline` over every
+    /// body, as `PassManager::from_pipeline` accepts them.
+    fn run_passes(&mut self, pipeline: &str) -> PyResult<()> {
+        PassManager::from_pipeline(pipeline)
+            .and_then(|pm| pm.run(&mut self.module))
+            .map_err(to_py_err)?;
+        Ok(())
+    }
+
+    /// Every function, imported ones included.
+    fn funcs(&self) -> Vec<FuncInfo> {
+        let module = &self.module;
+        module
+            .funcs
+            .entries()
+            .filter_map(|(func, decl)| {
+                let kind = match decl {
+                    FuncDecl::Import(..) => "import",
+                    FuncDecl::Body(..) => "body",
+                    FuncDecl::Lazy(..) => "unparsed",
+                    FuncDecl::Compiled(..) => "compiled",
+                    FuncDecl::None => return None,
+                };
+                let sig = &module.signatures[decl.sig()];
+                Some(FuncInfo {
+                    index: func.index(),
+                    name: decl.name().to_owned(),
+                    kind,
+                    params: type_names(&sig.params),
+                    results: type_names(&sig.returns),
+                })
+            })
+            .collect()
+    }
+
+    /// The exports, as `(name, entity)` pairs, where the entity is
+    /// named as in the IR's text form, e.g. `func3` or `memory0`.
+    fn exports(&self) -> Vec<(String, String)> {
+        self.module
+            .exports
+            .iter()
+            .map(|export| (export.name.clone(), export.kind.to_string()))
+            .collect()
+    }
+
+    /// The imports, as `(module, name, entity)` triples.
+    fn imports(&self) -> Vec<(String, String, String)> {
+        self.module
+            .imports
+            .iter()
+            .map(|import| {
+                (
+                    import.module.clone(),
+                    import.name.clone(),
+                    import.kind.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    /// A copy of the IR of function `func`, or `None` for imported
+    /// and already-compiled functions.
+    fn body(&self, func: usize) -> PyResult<Option<Body>> {
+        let func = self.func(func)?;
+        match &self.module.funcs[func] {
+            FuncDecl::Body(..) | FuncDecl::Lazy(..) => {}
+            _ => return Ok(None),
+        }
+        let body = self.module.clone_and_expand_body(func).map_err(to_py_err)?;
+        Ok(Some(Body { body }))
+    }
+
+    /// Replace the body of function `func` with `body`, which must
+    /// validate.
+    fn set_body(&mut self, func: usize, body: &Body) -> PyResult<()> {
+        let func = self.func(func)?;
+        if let FuncDecl::Import(..) = self.module.funcs[func] {
+            return Err(bad_arg(format!("Cannot set the body of import {}", func)));
+        }
+        body.body.validate().map_err(to_py_err)?;
+        self.module.replace_body(func, body.body.clone());
+        Ok(())
+    }
+
+    fn __str__(&self) -> String {
+        format!("{}", self.module.display())
+    }
+}
+
+/// A function, as listed by `Module.funcs`.
+#[pyclass]
+struct FuncInfo {
+    #[pyo3(get)]
+    index: usize,
+    #[pyo3(get)]
+    name: String,
+    /// One of "import", "body" (parsed into IR), "unparsed" and
+    /// "compiled".
+    #[pyo3(get)]
+    kind: &'static str,
+    #[pyo3(get)]
+    params: Vec<String>,
+    #[pyo3(get)]
+    results: Vec<String>,
+}
+
+/// A value's definition, as returned by `Body.value`.
+#[pyclass]
+struct ValueInfo {
+    /// One of "op", "blockparam", "pick", "alias", "placeholder",
+    /// "trace" and "none".
+    #[pyo3(get)]
+    kind: &'static str,
+    /// The operator, in the IR's text form, for "op" values.
+    #[pyo3(get)]
+    op: Option<String>,
+    /// The values used: an operator's arguments, the value picked
+    /// from, or the value aliased.
+    #[pyo3(get)]
+    args: Vec<usize>,
+    #[pyo3(get)]
+    types: Vec<String>,
+    /// The block the value is defined in, if any.
+    #[pyo3(get)]
+    block: Option<usize>,
+}
+
+/// A copy of a function's IR.
+#[pyclass]
+#[derive(Clone)]
+struct Body {
+    body: FunctionBody,
+}
+
+impl Body {
+    fn block(&self, index: usize) -> PyResult<Block> {
+        if index >= self.body.blocks.len() {
+            return Err(bad_arg(format!("No block {}", index)));
+        }
+        Ok(Block::new(index))
+    }
+
+    fn value_ref(&self, index: usize) -> PyResult<Value> {
+        if index >= self.body.values.len() {
+            return Err(bad_arg(format!("No value {}", index)));
+        }
+        Ok(Value::new(index))
+    }
+
+    fn insert(&mut self, block: Block, index: usize, def: ValueDef) -> PyResult<usize> {
+        if index > self.body.blocks[block].insts.len() {
+            return Err(bad_arg(format!("No position {} in {}", index, block)));
+        }
+        let value = self.body.add_value(def);
+        self.body.blocks[block].insts.insert(index, value);
+        self.body.value_blocks[value] = block;
+        Ok(value.index())
+    }
+}
+
+#[pymethods]
+impl Body {
+    #[getter]
+    fn entry(&self) -> usize {
+        self.body.entry.index()
+    }
+
+    fn blocks(&self) -> Vec<usize> {
+        self.body.blocks.iter().map(|block| block.index()).collect()
+    }
+
+    /// The parameters of `block`, as `(type, value)` pairs.
+    fn params(&self, block: usize) -> PyResult<Vec<(String, usize)>> {
+        let block = self.block(block)?;
+        Ok(self.body.blocks[block]
+            .params
+            .iter()
+            .map(|&(ty, value)| (ty.to_string(), value.index()))
+            .collect())
+    }
+
+    /// The instructions of `block`, in order.
+    fn insts(&self, block: usize) -> PyResult<Vec<usize>> {
+        let block = self.block(block)?;
+        Ok(self.body.blocks[block]
+            .insts
+            .iter()
+            .map(|value| value.index())
+            .collect())
+    }
+
+    /// The terminator of `block`, in the IR's text form.
+    fn terminator(&self, block: usize) -> PyResult<String> {
+        let block = self.block(block)?;
+        Ok(self.body.blocks[block].terminator.to_string())
+    }
+
+    fn succs(&self, block: usize) -> PyResult<Vec<usize>> {
+        let block = self.block(block)?;
+        Ok(self
+            .body
+            .succs(block)
+            .iter()
+            .map(|succ| succ.index())
+            .collect())
+    }
+
+    fn value(&self, value: usize) -> PyResult<ValueInfo> {
+        let value = self.value_ref(value)?;
+        let body = &self.body;
+        let def = &body.values[value];
+        let (kind, op, args) = match def {
+            ValueDef::Operator(op, args, _) => {
+                ("op", Some(op.to_string()), body.arg_pool[*args].to_vec())
+            }
+            ValueDef::BlockParam(..) => ("blockparam", None, vec![]),
+            ValueDef::PickOutput(from, ..) => ("pick", None, vec![*from]),
+            ValueDef::Alias(to) => ("alias", None, vec![*to]),
+            ValueDef::Placeholder(..) => ("placeholder", None, vec![]),
+            ValueDef::Trace(_, args) => ("trace", None, body.arg_pool[*args].to_vec()),
+            ValueDef::None => ("none", None, vec![]),
+        };
+        let block = match def {
+            ValueDef::BlockParam(block, ..) => Some(*block),
+            _ if body.blocks[body.value_blocks[value]].insts.contains(&value) => {
+                Some(body.value_blocks[value])
+            }
+            _ => None,
+        };
+        Ok(ValueInfo {
+            kind,
+            op,
+            args: args.iter().map(|arg| arg.index()).collect(),
+            types: type_names(def.tys(&body.type_pool)),
+            block: block.map(|block| block.index()),
+        })
+    }
+
+    /// `value` with aliases followed.
+    fn resolve_alias(&self, value: usize) -> PyResult<usize> {
+        let value = self.value_ref(value)?;
+        Ok(self.body.resolve_alias(value).index())
+    }
+
+    /// Make every use of `value`, an instruction, use `with` instead.
+    /// `value` stays in its block, and is removed when the body is
+    /// next compiled.
+    fn replace_uses(&mut self, value: usize, with: usize) -> PyResult<()> {
+        let value = self.value_ref(value)?;
+        let with = self.value_ref(with)?;
+        match self.body.values[value] {
+            ValueDef::Operator(..) | ValueDef::PickOutput(..) | ValueDef::Alias(..) => {}
+            _ => return Err(bad_arg(format!("{} is not an instruction", value))),
+        }
+        if self.body.resolve_alias(with) == value {
+            return Err(bad_arg(format!("{} would alias itself", value)));
+        }
+        self.body.set_alias(value, with);
+        Ok(())
+    }
+
+    /// Remove the instruction at `index` in `block`, returning it. It
+    /// must have no remaining uses.
+    fn remove_inst(&mut self, block: usize, index: usize) -> PyResult<usize> {
+        let block = self.block(block)?;
+        if index >= self.body.blocks[block].insts.len() {
+            return Err(bad_arg(format!("No position {} in {}", index, block)));
+        }
+        Ok(self.body.blocks[block].insts.remove(index).index())
+    }
+
+    /// Insert a constant of type `ty` with the given bits at `index`
+    /// in `block`, returning it.
+    fn add_const(&mut self, block: usize, index: usize, ty: &str, bits: u64) -> PyResult<usize> {
+        let block = self.block(block)?;
+        let ty = parse_type(ty)?;
+        let op = match ty {
+            Type::I32 => Operator::I32Const { value: bits as u32 },
+            Type::I64 => Operator::I64Const { value: bits },
+            Type::F32 => Operator::F32Const { value: bits as u32 },
+            Type::F64 => Operator::F64Const { value: bits },
+            _ => return Err(bad_arg(format!("No constants of type {}", ty))),
+        };
+        let args = self.body.arg_pool.from_iter(std::iter::empty());
+        let tys = self.body.single_type_list(ty);
+        self.insert(block, index, ValueDef::Operator(op, args, tys))
+    }
+
+    /// Insert a copy of the operator of `like` applied to `args` at
+    /// `index` in `block`, returning it. This builds any operator that
+    /// is already in the body, e.g. an `i32.add` from another one.
+    fn add_op_like(
+        &mut self,
+        block: usize,
+        index: usize,
+        like: usize,
+        args: Vec<usize>,
+    ) -> PyResult<usize> {
+        let block = self.block(block)?;
+        let like = self.value_ref(like)?;
+        let args = args
+            .into_iter()
+            .map(|arg| self.value_ref(arg))
+            .collect::<PyResult<Vec<_>>>()?;
+        let (op, tys) = match &self.body.values[like] {
+            ValueDef::Operator(op, like_args, tys) => {
+                if self.body.arg_pool[*like_args].len() != args.len() {
+                    return Err(bad_arg(format!(
+                        "{} takes {} arguments",
+                        op,
+                        self.body.arg_pool[*like_args].len()
+                    )));
+                }
+                (*op, *tys)
+            }
+            _ => return Err(bad_arg(format!("{} is not an operator", like))),
+        };
+        let args = self.body.arg_pool.from_iter(args.into_iter());
+        self.insert(block, index, ValueDef::Operator(op, args, tys))
+    }
+
+    /// Check the body's invariants, raising `WaffleError` if one does
+    /// not hold.
+    fn validate(&self) -> PyResult<()> {
+        self.body.validate().map_err(to_py_err)
+    }
+
+    fn __str__(&self) -> String {
+        format!("{}", self.body.display("", None))
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "waffle")]
+fn waffle_python(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("WaffleError", py.get_type::<WaffleError>())?;
+    m.add_class::<Module>()?;
+    m.add_class::<FuncInfo>()?;
+    m.add_class::<ValueInfo>()?;
+    m.add_class::<Body>()?;
+    Ok(())
+}
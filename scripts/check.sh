@@ -4,4 +4,13 @@ set -e
 
 cargo fmt --check
 cargo check
+cargo test --features differential --test skip_unsupported --test asyncify
+# Without `std`, the crate is `no_std`.
+cargo check --lib --no-default-features
+cargo check --lib --no-default-features --target wasm32-unknown-unknown
+cargo check --lib --no-default-features --features frontend
+cargo check --lib --no-default-features --features backend
+# Emit-only builds must not pull in the parser.
+! cargo tree --no-default-features --features backend -e normal | grep -q wasmparser
+cargo check --lib --target wasm32-unknown-unknown
 cargo +nightly fuzz check
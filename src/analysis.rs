@@ -0,0 +1,54 @@
+//! Whole-module and per-function analyses.
+
+use crate::ir::{Func, FuncDecl, FunctionBody, Module};
+use alloc::borrow::Cow;
+use anyhow::Result;
+
+pub mod alias;
+pub mod call_graph;
+pub mod diff;
+pub mod effects;
+pub mod globals;
+pub mod indirect_targets;
+pub mod liveness;
+pub mod loops;
+pub mod memory_ssa;
+pub mod ranges;
+pub mod reachability;
+pub mod stack_frame;
+// Measures operand stacks in the compiled code, which it parses.
+#[cfg(all(feature = "frontend", feature = "backend"))]
+pub mod stack_usage;
+pub mod symbolic;
+pub mod taint;
+
+pub use alias::{AliasAnalysis, Location};
+pub use call_graph::{CallEdge, CallGraph, CallKind, CallSite};
+pub use diff::{DiffLine, FuncDiff, ModuleDiff};
+pub use effects::{EffectSummary, Effects};
+pub use globals::{ConstantGlobal, ConstantGlobals};
+pub use indirect_targets::{IndirectTargets, Precision, TargetSet};
+pub use liveness::Liveness;
+pub use loops::{Comparison, ExitTest, InductionVariable, Loop, Loops, Predicate};
+pub use memory_ssa::{MemoryAccess, MemoryAccessDef, MemorySsa};
+pub use ranges::{ValueRange, ValueRanges};
+pub use reachability::{Reachability, Root};
+pub use stack_frame::{stack_pointer, FrameAccess, StackFrame, StackSlot};
+#[cfg(all(feature = "frontend", feature = "backend"))]
+pub use stack_usage::{Bound, ExportUsage, FrameUsage, StackUsage};
+pub use symbolic::{BlockReach, SymbolicExecution, SymbolicOptions, Term, TermDef};
+pub use taint::{Sink, Source, TaintAnalysis, TaintConfig, TaintFlow};
+
+/// A function's body, parsing it if it has not been parsed yet, or
+/// `None` if it has none.
+pub(crate) fn body<'m>(
+    module: &'m Module<'_>,
+    func: Func,
+) -> Result<Option<Cow<'m, FunctionBody>>> {
+    match &module.funcs[func] {
+        FuncDecl::Body(_, _, body) => Ok(Some(Cow::Borrowed(body))),
+        FuncDecl::Lazy(..) => Ok(Some(Cow::Owned(module.clone_and_expand_body(func)?))),
+        FuncDecl::Compiled(..) => anyhow::bail!("Cannot analyze {}: it is already compiled", func),
+        FuncDecl::Import(..) | FuncDecl::None => Ok(None),
+    }
+}
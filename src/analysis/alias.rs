@@ -0,0 +1,235 @@
+//! Alias analysis for linear memory.
+
+use super::ranges::ValueRanges;
+use super::stack_frame::StackFrame;
+use crate::cfg::CFGInfo;
+use crate::collections::HashMap;
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::passes::memtrace::access;
+use crate::Operator;
+
+/// The bytes a load or store accesses: `size` bytes at
+/// `base + delta` (wrapping to 32 bits) plus `offset`, where `base`
+/// is a value (or zero if `None`), `delta` the constants added to it
+/// and `offset` the static offset of the instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Location {
+    pub memory: Memory,
+    pub base: Option<Value>,
+    pub delta: u32,
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl Location {
+    /// The address relative to the base, if adding the delta doesn't
+    /// wrap.
+    fn start(&self) -> i64 {
+        let delta = match self.base {
+            Some(_) => self.delta as i32 as i64,
+            None => self.delta as i64,
+        };
+        delta + self.offset as i64
+    }
+
+    /// The possible distances from `a`'s first byte to `b`'s, given
+    /// that they have the same base.
+    fn distances(a: &Location, b: &Location) -> [i64; 2] {
+        let offsets = b.offset as i64 - a.offset as i64;
+        if a.base.is_some() {
+            // The wrapped sums of the base and the deltas differ by the
+            // difference of the deltas, modulo 2^32.
+            let d = b.delta.wrapping_sub(a.delta) as i64 + offsets;
+            [d, d - (1 << 32)]
+        } else {
+            let d = b.delta as i64 - a.delta as i64 + offsets;
+            [d, d]
+        }
+    }
+}
+
+fn overlap(distance: i64, a_size: u32, b_size: u32) -> bool {
+    -(b_size as i64) < distance && distance < a_size as i64
+}
+
+/// May-alias information for the loads and stores of a body.
+///
+/// Two accesses do not alias if:
+///
+/// - they are to different memories;
+/// - their addresses are the same value (or both constant) plus
+///   constants, and the accessed bytes don't overlap;
+/// - the value ranges of their addresses show that the accessed bytes
+///   are disjoint, for example an access to a constant address and one
+///   through a pointer known to be above it; or
+/// - one is to a slot of the function's shadow-stack frame, whose
+///   address doesn't escape (see `StackFrame`), and the other is not.
+#[derive(Clone, Debug, Default)]
+pub struct AliasAnalysis {
+    locations: HashMap<Value, Location>,
+    /// The effective-address ranges, as `[start, end)`.
+    extents: HashMap<Value, (u64, u64)>,
+    /// The frame offsets of the accesses to a private stack frame.
+    frame: HashMap<Value, u32>,
+}
+
+impl AliasAnalysis {
+    /// Analyze the accesses of `body`, given the shadow-stack pointer
+    /// if the module has one (see `stack_pointer`).
+    pub fn compute(body: &FunctionBody, cfg: &CFGInfo, sp: Option<Global>) -> AliasAnalysis {
+        let ranges = ValueRanges::compute(body, cfg);
+        let mut analysis = AliasAnalysis::default();
+        if let Some(frame) = sp.and_then(|sp| StackFrame::compute(body, sp)) {
+            if frame.escape.is_none() {
+                analysis.frame = frame
+                    .accesses
+                    .iter()
+                    .map(|access| (access.inst, access.offset))
+                    .collect();
+            }
+        }
+        for block in body.blocks.values() {
+            for &inst in &block.insts {
+                let (op, args) = match &body.values[inst] {
+                    ValueDef::Operator(op, args, _) => (op, &body.arg_pool[*args]),
+                    _ => continue,
+                };
+                let (memarg, size, _) = match access(op) {
+                    Some(access) => access,
+                    None => continue,
+                };
+                let (base, delta) = decompose(body, args[0]);
+                let location = Location {
+                    memory: memarg.memory,
+                    base,
+                    delta,
+                    offset: memarg.offset,
+                    size,
+                };
+                // Where `base + delta` may be, if it can't wrap.
+                let start = match base {
+                    None => Some((delta as u64, delta as u64)),
+                    Some(base) => ranges
+                        .range_at(body.value_blocks[inst], base)
+                        .map(|range| {
+                            let delta = delta as i32 as i64;
+                            (range.min as i64 + delta, range.max as i64 + delta)
+                        })
+                        .filter(|&(min, max)| min >= 0 && max <= u32::MAX as i64)
+                        .map(|(min, max)| (min as u64, max as u64)),
+                };
+                if let Some((min, max)) = start {
+                    let start = min + memarg.offset as u64;
+                    let end = max + memarg.offset as u64 + size as u64;
+                    analysis.extents.insert(inst, (start, end));
+                }
+                analysis.locations.insert(inst, location);
+            }
+        }
+        analysis
+    }
+
+    /// The location accessed by the load or store `inst`.
+    pub fn location(&self, inst: Value) -> Option<&Location> {
+        self.locations.get(&inst)
+    }
+
+    /// Whether the loads or stores `a` and `b` may access the same
+    /// bytes. Anything that is not a load or store may alias anything.
+    pub fn may_alias(&self, a: Value, b: Value) -> bool {
+        let (la, lb) = match (self.locations.get(&a), self.locations.get(&b)) {
+            (Some(la), Some(lb)) => (la, lb),
+            _ => return true,
+        };
+        if la.memory != lb.memory {
+            return false;
+        }
+        match (self.frame.get(&a), self.frame.get(&b)) {
+            (Some(&fa), Some(&fb)) => {
+                return overlap(fb as i64 - fa as i64, la.size, lb.size);
+            }
+            (Some(_), None) | (None, Some(_)) => return false,
+            (None, None) => {}
+        }
+        if la.base == lb.base {
+            return Location::distances(la, lb)
+                .iter()
+                .any(|&distance| overlap(distance, la.size, lb.size));
+        }
+        match (self.extents.get(&a), self.extents.get(&b)) {
+            (Some(&(sa, ea)), Some(&(sb, eb))) => sa < eb && sb < ea,
+            _ => true,
+        }
+    }
+
+    /// Whether `a` and `b` certainly access the same bytes.
+    pub fn must_alias(&self, a: Value, b: Value) -> bool {
+        match (self.locations.get(&a), self.locations.get(&b)) {
+            (Some(la), Some(lb)) if la.memory == lb.memory && la.size == lb.size => {
+                match (self.frame.get(&a), self.frame.get(&b)) {
+                    (Some(fa), Some(fb)) => fa == fb,
+                    _ if la.base != lb.base => false,
+                    // Moving a constant between the delta and the offset
+                    // changes the address only if the sum wraps, which
+                    // constant addresses and known ranges rule out.
+                    _ if la.base.is_none()
+                        || (self.extents.contains_key(&a) && self.extents.contains_key(&b)) =>
+                    {
+                        la.start() == lb.start()
+                    }
+                    _ => la == lb,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `inst` accesses the function's private stack frame,
+    /// which nothing outside the function can access.
+    pub fn is_private(&self, inst: Value) -> bool {
+        self.frame.contains_key(&inst)
+    }
+}
+
+/// Split an address into a base value (or zero) and the constants added
+/// to it.
+fn decompose(body: &FunctionBody, mut address: Value) -> (Option<Value>, u32) {
+    let mut delta = 0u32;
+    loop {
+        address = body.resolve_alias(address);
+        if !address.is_valid() {
+            return (Some(address), delta);
+        }
+        let (op, args) = match &body.values[address] {
+            ValueDef::Operator(op, args, _) => (op, &body.arg_pool[*args]),
+            _ => return (Some(address), delta),
+        };
+        let constant = |value: Value| match body.values[body.resolve_alias(value)] {
+            ValueDef::Operator(Operator::I32Const { value }, ..) => Some(value),
+            _ => None,
+        };
+        match (op, args) {
+            (&Operator::I32Const { value }, _) => return (None, delta.wrapping_add(value)),
+            (Operator::I32Add, &[a, b]) => match (constant(a), constant(b)) {
+                (_, Some(c)) => {
+                    delta = delta.wrapping_add(c);
+                    address = a;
+                }
+                (Some(c), None) => {
+                    delta = delta.wrapping_add(c);
+                    address = b;
+                }
+                (None, None) => return (Some(address), delta),
+            },
+            (Operator::I32Sub, &[a, b]) => match constant(b) {
+                Some(c) => {
+                    delta = delta.wrapping_sub(c);
+                    address = a;
+                }
+                None => return (Some(address), delta),
+            },
+            _ => return (Some(address), delta),
+        }
+    }
+}
@@ -0,0 +1,417 @@
+//! Call graph construction.
+
+use super::body;
+use super::indirect_targets::{IndirectTargets, Precision};
+use crate::collections::HashMap;
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::*;
+use crate::json;
+use crate::passes::call_profile::{CallEdgeCount, Callee};
+use crate::prelude::*;
+use crate::Operator;
+use alloc::collections::{BTreeMap, BTreeSet};
+use anyhow::Result;
+use core::fmt::Write as _;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CallKind {
+    Direct,
+    /// A `call_indirect` through the table.
+    Indirect(Table),
+}
+
+/// One call instruction and the functions it may call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallSite {
+    pub inst: Value,
+    pub kind: CallKind,
+    /// The possible callees, sorted.
+    pub callees: Vec<Func>,
+    /// Whether the call may also reach functions outside the module,
+    /// which the embedder can put in imported and exported tables.
+    pub external: bool,
+}
+
+/// The call sites of one kind in `caller` that may call `callee`, as
+/// exported by `CallGraph::edges`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallEdge {
+    pub caller: Func,
+    pub callee: Func,
+    pub kind: CallKind,
+    /// The number of call sites.
+    pub sites: usize,
+    /// How often the edge was taken, if a profile was given. Indirect
+    /// calls count against the function in the table slot they went
+    /// through.
+    pub count: Option<u64>,
+}
+
+/// The direct call edges of a module, plus conservative edges for
+/// indirect calls to every function in their `IndirectTargets` set.
+#[derive(Clone, Debug, Default)]
+pub struct CallGraph {
+    sites: PerEntity<Func, Vec<CallSite>>,
+    callees: PerEntity<Func, Vec<Func>>,
+    callers: PerEntity<Func, Vec<Func>>,
+    sccs: Vec<Vec<Func>>,
+    /// The index in `sccs` of each function's component.
+    scc_of: PerEntity<Func, usize>,
+}
+
+impl CallGraph {
+    /// Build the call graph, with indirect call targets at
+    /// `Precision::Slot`. Bodies that have not been parsed yet are
+    /// parsed (without changing the module); already-compiled bodies
+    /// cannot be analyzed.
+    pub fn compute(module: &Module<'_>) -> Result<CallGraph> {
+        Self::compute_with_precision(module, Precision::Slot)
+    }
+
+    pub fn compute_with_precision(module: &Module<'_>, precision: Precision) -> Result<CallGraph> {
+        let bodies = module
+            .funcs
+            .iter()
+            .map(|func| body(module, func))
+            .collect::<Result<Vec<_>>>()?;
+
+        let indirect = IndirectTargets::compute_for(module, &bodies, precision);
+
+        let mut graph = CallGraph::default();
+        for (caller, body) in module.funcs.iter().zip(&bodies) {
+            let body = match body {
+                Some(body) => body,
+                None => continue,
+            };
+            let mut sites = vec![];
+            for block in body.blocks.values() {
+                for &inst in &block.insts {
+                    let site = match body.values[inst] {
+                        ValueDef::Operator(Operator::Call { function_index }, ..) => CallSite {
+                            inst,
+                            kind: CallKind::Direct,
+                            callees: vec![function_index],
+                            external: false,
+                        },
+                        ValueDef::Operator(Operator::CallIndirect { table_index, .. }, ..) => {
+                            let set = indirect.targets(caller, inst).unwrap();
+                            CallSite {
+                                inst,
+                                kind: CallKind::Indirect(table_index),
+                                callees: set.funcs.clone(),
+                                external: set.external,
+                            }
+                        }
+                        _ => continue,
+                    };
+                    sites.push(site);
+                }
+            }
+            let mut callees = sites
+                .iter()
+                .flat_map(|site| site.callees.iter().copied())
+                .collect::<Vec<_>>();
+            callees.sort();
+            callees.dedup();
+            for &callee in &callees {
+                graph.callers[callee].push(caller);
+            }
+            graph.callees[caller] = callees;
+            graph.sites[caller] = sites;
+        }
+        graph.sccs = graph.compute_sccs(module.funcs.len());
+        for (i, scc) in graph.sccs.iter().enumerate() {
+            for &func in scc {
+                graph.scc_of[func] = i;
+            }
+        }
+        Ok(graph)
+    }
+
+    /// The functions `func` may call, sorted.
+    pub fn callees(&self, func: Func) -> &[Func] {
+        &self.callees[func][..]
+    }
+
+    /// The functions that may call `func`, sorted.
+    pub fn callers(&self, func: Func) -> &[Func] {
+        &self.callers[func][..]
+    }
+
+    /// The call sites in `func`, in block order.
+    pub fn sites(&self, func: Func) -> &[CallSite] {
+        &self.sites[func][..]
+    }
+
+    /// Whether `func` may call itself, directly or through other
+    /// functions.
+    pub fn is_recursive(&self, func: Func) -> bool {
+        self.scc(func).len() > 1 || self.callees(func).contains(&func)
+    }
+
+    /// The strongly connected components of the graph, each a sorted
+    /// set of mutually recursive functions, with callees before their
+    /// callers (so in the order a bottom-up analysis visits them).
+    pub fn sccs(&self) -> &[Vec<Func>] {
+        &self.sccs[..]
+    }
+
+    /// The component containing `func`.
+    pub fn scc(&self, func: Func) -> &[Func] {
+        &self.sccs[self.scc_of[func]][..]
+    }
+
+    /// The edges of the graph, sorted by caller, callee and kind, with
+    /// counts from `profile` (decoded from a `call_profile`
+    /// instrumented run) if given.
+    pub fn edges(&self, module: &Module<'_>, profile: Option<&[CallEdgeCount]>) -> Vec<CallEdge> {
+        // Counts by caller, callee and table, following indirect calls
+        // to the function in the slot they went through.
+        let mut counts: HashMap<(Func, Func, Option<Table>), u64> = HashMap::new();
+        for edge in profile.unwrap_or(&[]) {
+            let (callee, table) = match edge.callee {
+                Callee::Func(callee) => (callee, None),
+                Callee::TableSlot {
+                    table,
+                    slot: Some(slot),
+                } => {
+                    let callee = module
+                        .tables
+                        .get(table)
+                        .and_then(|table| table.func_elements.as_ref())
+                        .and_then(|elements| elements.get(slot as usize).copied());
+                    match callee {
+                        Some(callee) if callee.is_valid() => (callee, Some(table)),
+                        _ => continue,
+                    }
+                }
+                Callee::TableSlot { slot: None, .. } => continue,
+            };
+            *counts.entry((edge.caller, callee, table)).or_default() += edge.count;
+        }
+
+        let mut edges: BTreeMap<(Func, Func, Option<Table>), usize> = BTreeMap::new();
+        for caller in module.funcs.iter() {
+            for site in self.sites(caller) {
+                let table = match site.kind {
+                    CallKind::Direct => None,
+                    CallKind::Indirect(table) => Some(table),
+                };
+                for &callee in &site.callees {
+                    *edges.entry((caller, callee, table)).or_default() += 1;
+                }
+            }
+        }
+        edges
+            .into_iter()
+            .map(|((caller, callee, table), sites)| CallEdge {
+                caller,
+                callee,
+                kind: table.map_or(CallKind::Direct, CallKind::Indirect),
+                sites,
+                count: profile.map(|_| counts.get(&(caller, callee, table)).copied().unwrap_or(0)),
+            })
+            .collect()
+    }
+
+    /// The functions with call sites that may call outside the module.
+    fn external_callers(&self, module: &Module<'_>) -> BTreeSet<Func> {
+        module
+            .funcs
+            .iter()
+            .filter(|&func| self.sites(func).iter().any(|site| site.external))
+            .collect()
+    }
+
+    /// A Graphviz graph of the functions, with imports dashed, and the
+    /// edges labelled by the callee's signature and, with a profile,
+    /// their count. Indirect edges are dashed, and functions that may
+    /// call outside the module have a dotted edge to `external`.
+    pub fn to_dot(&self, module: &Module<'_>, profile: Option<&[CallEdgeCount]>) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph calls {{").unwrap();
+        writeln!(out, "  node [shape=box, fontname=\"monospace\"];").unwrap();
+        for (func, decl) in module.funcs.entries() {
+            let style = match decl {
+                FuncDecl::Import(..) => ", style=dashed",
+                _ => "",
+            };
+            let label = dot_label(&[
+                format!("{} {}", func, decl.name()),
+                sig_string(module, decl.sig()),
+            ]);
+            writeln!(out, "  {} [label={}{}];", func, label, style).unwrap();
+        }
+        for edge in self.edges(module, profile) {
+            let mut label = vec![sig_string(module, module.funcs[edge.callee].sig())];
+            if edge.sites > 1 {
+                label.push(format!("{} sites", edge.sites));
+            }
+            if let Some(count) = edge.count {
+                label.push(format!("{} calls", count));
+            }
+            let style = match edge.kind {
+                CallKind::Direct => "",
+                CallKind::Indirect(_) => ", style=dashed",
+            };
+            writeln!(
+                out,
+                "  {} -> {} [label={}{}];",
+                edge.caller,
+                edge.callee,
+                dot_label(&label),
+                style
+            )
+            .unwrap();
+        }
+        let external = self.external_callers(module);
+        if !external.is_empty() {
+            writeln!(out, "  external [shape=ellipse];").unwrap();
+            for func in external {
+                writeln!(out, "  {} -> external [style=dotted];", func).unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    /// The graph as JSON: `functions` (with `index`, `name`, `import`
+    /// and `signature`), `edges` (with `caller`, `callee`, `kind`,
+    /// `table` for indirect edges, `sites`, `signature`, and `count`
+    /// with a profile), and `external_callers`.
+    pub fn to_json(&self, module: &Module<'_>, profile: Option<&[CallEdgeCount]>) -> String {
+        let funcs = module
+            .funcs
+            .entries()
+            .map(|(func, decl)| {
+                format!(
+                    "{{\"index\":{},\"name\":{},\"import\":{},\"signature\":{}}}",
+                    func.index(),
+                    json::string(decl.name()),
+                    matches!(decl, FuncDecl::Import(..)),
+                    json::string(&sig_string(module, decl.sig()))
+                )
+            })
+            .collect::<Vec<_>>();
+        let edges = self
+            .edges(module, profile)
+            .into_iter()
+            .map(|edge| {
+                let kind = match edge.kind {
+                    CallKind::Direct => "\"kind\":\"direct\"".to_owned(),
+                    CallKind::Indirect(table) => {
+                        format!("\"kind\":\"indirect\",\"table\":{}", table.index())
+                    }
+                };
+                let count = edge
+                    .count
+                    .map_or(String::new(), |count| format!(",\"count\":{}", count));
+                format!(
+                    "{{\"caller\":{},\"callee\":{},{},\"sites\":{},\"signature\":{}{}}}",
+                    edge.caller.index(),
+                    edge.callee.index(),
+                    kind,
+                    edge.sites,
+                    json::string(&sig_string(module, module.funcs[edge.callee].sig())),
+                    count
+                )
+            })
+            .collect::<Vec<_>>();
+        let external = self
+            .external_callers(module)
+            .into_iter()
+            .map(|func| func.index().to_string())
+            .collect::<Vec<_>>();
+        format!(
+            "{{\"functions\":[{}],\"edges\":[{}],\"external_callers\":[{}]}}",
+            funcs.join(","),
+            edges.join(","),
+            external.join(",")
+        )
+    }
+
+    /// Tarjan's algorithm, with an explicit stack.
+    fn compute_sccs(&self, n: usize) -> Vec<Vec<Func>> {
+        const UNVISITED: usize = usize::MAX;
+        let mut index = vec![UNVISITED; n];
+        let mut lowlink = vec![0; n];
+        let mut on_stack = vec![false; n];
+        let mut stack = vec![];
+        let mut sccs = vec![];
+        let mut next_index = 0;
+        for root in 0..n {
+            if index[root] != UNVISITED {
+                continue;
+            }
+            let mut work = vec![(root, 0)];
+            while let Some(&mut (node, ref mut next_callee)) = work.last_mut() {
+                if *next_callee == 0 {
+                    index[node] = next_index;
+                    lowlink[node] = next_index;
+                    next_index += 1;
+                    stack.push(node);
+                    on_stack[node] = true;
+                }
+                let callees = self.callees(Func::new(node));
+                if let Some(&callee) = callees.get(*next_callee) {
+                    *next_callee += 1;
+                    let callee = callee.index();
+                    if index[callee] == UNVISITED {
+                        work.push((callee, 0));
+                    } else if on_stack[callee] {
+                        lowlink[node] = lowlink[node].min(index[callee]);
+                    }
+                    continue;
+                }
+                work.pop();
+                if let Some(&(parent, _)) = work.last() {
+                    lowlink[parent] = lowlink[parent].min(lowlink[node]);
+                }
+                if lowlink[node] == index[node] {
+                    let mut scc = vec![];
+                    loop {
+                        let member = stack.pop().unwrap();
+                        on_stack[member] = false;
+                        scc.push(Func::new(member));
+                        if member == node {
+                            break;
+                        }
+                    }
+                    scc.sort();
+                    sccs.push(scc);
+                }
+            }
+        }
+        sccs
+    }
+}
+
+/// `sig` as written in the IR, e.g. `(i32, i32) -> (i64)`.
+fn sig_string(module: &Module<'_>, sig: Signature) -> String {
+    let types = |tys: &[Type]| {
+        tys.iter()
+            .map(|ty| ty.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let data = &module.signatures[sig];
+    format!("({}) -> ({})", types(&data.params), types(&data.returns))
+}
+
+/// A quoted Graphviz label of centered `lines`.
+fn dot_label(lines: &[String]) -> String {
+    let mut out = String::from("\"");
+    for (i, line) in lines.iter().enumerate() {
+        if i > 0 {
+            out.push_str("\\n");
+        }
+        for c in line.chars() {
+            if c == '"' || c == '\\' {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,365 @@
+//! Comparing the functions of two modules, e.g. before and after a
+//! transformation.
+//!
+//! Functions are compared by a canonical listing of their bodies, in
+//! which blocks are numbered in reverse postorder and values by their
+//! block and order of definition in it, aliases are resolved, and direct calls name their
+//! callee. Bodies that differ only in numbering, block order or source
+//! locations, or that call moved functions, compare equal.
+
+use super::body;
+use crate::cfg::CFGInfo;
+use crate::collections::HashMap;
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// One line of a function's listing, as it changed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DiffLine {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+/// A function in both modules whose listing differs.
+#[derive(Clone, Debug)]
+pub struct FuncDiff {
+    pub old: Func,
+    pub new: Func,
+    /// The old listing turned into the new one.
+    pub lines: Vec<DiffLine>,
+}
+
+impl FuncDiff {
+    pub fn lines_added(&self) -> usize {
+        self.lines
+            .iter()
+            .filter(|line| matches!(line, DiffLine::Added(_)))
+            .count()
+    }
+
+    pub fn lines_removed(&self) -> usize {
+        self.lines
+            .iter()
+            .filter(|line| matches!(line, DiffLine::Removed(_)))
+            .count()
+    }
+}
+
+/// How the functions of a module changed. Functions are matched by
+/// name, if it is unique in both modules, then by identical listings,
+/// and the rest by index. All lists are in function order.
+#[derive(Clone, Debug, Default)]
+pub struct ModuleDiff {
+    pub changed: Vec<FuncDiff>,
+    /// Matched functions whose listings are the same, old then new.
+    pub unchanged: Vec<(Func, Func)>,
+    /// Functions only in the old module.
+    pub removed: Vec<Func>,
+    /// Functions only in the new module.
+    pub added: Vec<Func>,
+}
+
+impl ModuleDiff {
+    /// Compare `old` with `new`. Bodies that have not been parsed yet
+    /// are parsed (without changing the modules); already-compiled
+    /// bodies cannot be compared.
+    pub fn compute(old: &Module<'_>, new: &Module<'_>) -> Result<ModuleDiff> {
+        let old_listings = old
+            .funcs
+            .iter()
+            .map(|func| listing(old, func))
+            .collect::<Result<Vec<_>>>()?;
+        let new_listings = new
+            .funcs
+            .iter()
+            .map(|func| listing(new, func))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut pairs = vec![];
+        let mut old_matched = vec![false; old.funcs.len()];
+        let mut new_matched = vec![false; new.funcs.len()];
+        let old_names = unique_names(old);
+        for (name, &new_func) in &unique_names(new) {
+            if let Some(&old_func) = old_names.get(name) {
+                pairs.push((old_func, new_func));
+                old_matched[old_func.index()] = true;
+                new_matched[new_func.index()] = true;
+            }
+        }
+        let mut by_listing: HashMap<&[String], Vec<Func>> = HashMap::new();
+        for func in old.funcs.iter().rev() {
+            if !old_matched[func.index()] {
+                by_listing
+                    .entry(&old_listings[func.index()][..])
+                    .or_default()
+                    .push(func);
+            }
+        }
+        for func in new.funcs.iter() {
+            if new_matched[func.index()] {
+                continue;
+            }
+            let same = by_listing
+                .get_mut(&new_listings[func.index()][..])
+                .and_then(|funcs| funcs.pop());
+            if let Some(old_func) = same {
+                pairs.push((old_func, func));
+                old_matched[old_func.index()] = true;
+                new_matched[func.index()] = true;
+            }
+        }
+        for func in 0..old.funcs.len().min(new.funcs.len()) {
+            if !old_matched[func] && !new_matched[func] {
+                pairs.push((Func::new(func), Func::new(func)));
+                old_matched[func] = true;
+                new_matched[func] = true;
+            }
+        }
+        pairs.sort();
+
+        let mut diff = ModuleDiff::default();
+        for (old_func, new_func) in pairs {
+            let (a, b) = (
+                &old_listings[old_func.index()],
+                &new_listings[new_func.index()],
+            );
+            if a == b {
+                diff.unchanged.push((old_func, new_func));
+            } else {
+                diff.changed.push(FuncDiff {
+                    old: old_func,
+                    new: new_func,
+                    lines: diff_lines(a, b),
+                });
+            }
+        }
+        diff.removed = old
+            .funcs
+            .iter()
+            .filter(|f| !old_matched[f.index()])
+            .collect();
+        diff.added = new
+            .funcs
+            .iter()
+            .filter(|f| !new_matched[f.index()])
+            .collect();
+        Ok(diff)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty() && self.added.is_empty()
+    }
+}
+
+/// The names that name exactly one function of `module`.
+fn unique_names<'m>(module: &'m Module<'_>) -> HashMap<&'m str, Func> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for decl in module.funcs.values() {
+        *counts.entry(decl.name()).or_default() += 1;
+    }
+    module
+        .funcs
+        .entries()
+        .filter(|(_, decl)| !decl.name().is_empty() && counts[decl.name()] == 1)
+        .map(|(func, decl)| (decl.name(), func))
+        .collect()
+}
+
+fn types(tys: &[Type]) -> String {
+    tys.iter()
+        .map(|ty| ty.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The canonical listing of `func`: its signature, then its reachable
+/// blocks.
+fn listing(module: &Module<'_>, func: Func) -> Result<Vec<String>> {
+    let sig = &module.signatures[module.funcs[func].sig()];
+    let mut lines = vec![format!(
+        "function({}) -> {}",
+        types(&sig.params),
+        types(&sig.returns)
+    )];
+    let body = match body(module, func)? {
+        Some(body) => body,
+        None => {
+            lines.push("import".to_owned());
+            return Ok(lines);
+        }
+    };
+
+    let cfg = CFGInfo::new(&body);
+    let mut blocks = HashMap::new();
+    for (index, &block) in cfg.rpo.values().enumerate() {
+        blocks.insert(block, Block::new(index));
+    }
+    // Values are named after their block and their place in it, so
+    // that a change renames values in that block only.
+    let mut values: HashMap<Value, String> = HashMap::new();
+    let use_of = |value: Value, values: &HashMap<Value, String>| {
+        values
+            .get(&body.resolve_alias(value))
+            .cloned()
+            .unwrap_or_else(|| "?".to_owned())
+    };
+    let args = |args: &[Value], values: &HashMap<Value, String>| {
+        args.iter()
+            .map(|&arg| use_of(arg, values))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let target = |target: &BlockTarget, values: &HashMap<Value, String>| {
+        format!("{}({})", blocks[&target.block], args(&target.args, values))
+    };
+
+    for &block in cfg.rpo.values() {
+        let index = blocks[&block].index();
+        let mut next = 0;
+        let mut name = |value: Value, values: &mut HashMap<Value, String>| {
+            let name = format!("v{}.{}", index, next);
+            next += 1;
+            values.insert(value, name.clone());
+            name
+        };
+        let def = &body.blocks[block];
+        let params = def
+            .params
+            .iter()
+            .map(|&(ty, param)| format!("{}: {}", name(param, &mut values), ty))
+            .collect::<Vec<_>>();
+        lines.push(format!("{}({}):", blocks[&block], params.join(", ")));
+        for &inst in &def.insts {
+            let line = match &body.values[inst] {
+                ValueDef::Alias(_) => continue,
+                ValueDef::Operator(op, op_args, _) => {
+                    let op = match op {
+                        Operator::Call { function_index } => {
+                            match module.funcs[*function_index].name() {
+                                "" => op.to_string(),
+                                name => format!("call<{}>", name),
+                            }
+                        }
+                        op => op.to_string(),
+                    };
+                    let op_args = args(&body.arg_pool[*op_args], &values);
+                    let op = format!("{} {}", op, op_args);
+                    format!("{} = {}", name(inst, &mut values), op.trim_end())
+                }
+                &ValueDef::PickOutput(from, index, _) => {
+                    let from = use_of(from, &values);
+                    format!("{} = {}.{}", name(inst, &mut values), from, index)
+                }
+                ValueDef::Trace(id, trace_args) => {
+                    format!(
+                        "trace {}, {}",
+                        id,
+                        args(&body.arg_pool[*trace_args], &values)
+                    )
+                }
+                &ValueDef::Placeholder(ty) => {
+                    format!("{} = placeholder # {}", name(inst, &mut values), ty)
+                }
+                ValueDef::BlockParam(..) | ValueDef::None => continue,
+            };
+            lines.push(format!("  {}", line));
+        }
+        let terminator = match &def.terminator {
+            Terminator::Br { target: to } => format!("br {}", target(to, &values)),
+            Terminator::CondBr {
+                cond,
+                if_true,
+                if_false,
+            } => format!(
+                "if {}, {}, {}",
+                use_of(*cond, &values),
+                target(if_true, &values),
+                target(if_false, &values)
+            ),
+            Terminator::Select {
+                value,
+                targets,
+                default,
+            } => format!(
+                "select {}, [{}], {}",
+                use_of(*value, &values),
+                targets
+                    .iter()
+                    .map(|to| target(to, &values))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                target(default, &values)
+            ),
+            Terminator::Return { values: results } => {
+                format!("return {}", args(results, &values))
+            }
+            terminator => terminator.to_string(),
+        };
+        lines.push(format!("  {}", terminator));
+    }
+    Ok(lines)
+}
+
+/// Listings longer than this, in lines times lines, after their
+/// common start and end, are shown as entirely replaced rather than
+/// diffed line by line.
+const MAX_DIFF_CELLS: usize = 1 << 24;
+
+/// The longest-common-subsequence diff from `a` to `b`.
+fn diff_lines(a: &[String], b: &[String]) -> Vec<DiffLine> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut lines = a[..prefix]
+        .iter()
+        .map(|line| DiffLine::Same(line.clone()))
+        .collect::<Vec<_>>();
+    let (n, m) = (a_mid.len(), b_mid.len());
+    if (n + 1) * (m + 1) > MAX_DIFF_CELLS {
+        lines.extend(a_mid.iter().map(|line| DiffLine::Removed(line.clone())));
+        lines.extend(b_mid.iter().map(|line| DiffLine::Added(line.clone())));
+    } else {
+        // `lcs[i * (m + 1) + j]` is the length of the longest common
+        // subsequence of `a_mid[i..]` and `b_mid[j..]`.
+        let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i * (m + 1) + j] = if a_mid[i] == b_mid[j] {
+                    lcs[(i + 1) * (m + 1) + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && a_mid[i] == b_mid[j] {
+                lines.push(DiffLine::Same(a_mid[i].clone()));
+                i += 1;
+                j += 1;
+            } else if j == m || (i < n && lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1]) {
+                lines.push(DiffLine::Removed(a_mid[i].clone()));
+                i += 1;
+            } else {
+                lines.push(DiffLine::Added(b_mid[j].clone()));
+                j += 1;
+            }
+        }
+    }
+    lines.extend(
+        a[a.len() - suffix..]
+            .iter()
+            .map(|line| DiffLine::Same(line.clone())),
+    );
+    lines
+}
@@ -0,0 +1,198 @@
+//! Per-function side-effect summaries.
+
+use super::body;
+use super::call_graph::{CallGraph, CallKind, CallSite};
+use crate::cfg::CFGInfo;
+use crate::entity::PerEntity;
+use crate::ir::*;
+use crate::op_traits::SideEffect;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// What calling a function may do besides computing its results.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Effects {
+    pub reads_memory: bool,
+    pub writes_memory: bool,
+    pub reads_globals: bool,
+    pub writes_globals: bool,
+    pub reads_tables: bool,
+    pub writes_tables: bool,
+    pub may_trap: bool,
+    pub calls_imports: bool,
+    /// Whether the call may not return, because the function (or one
+    /// it calls) has a loop or is recursive.
+    pub may_loop: bool,
+}
+
+impl Effects {
+    /// The effects of something the analysis can't see into.
+    pub fn all() -> Effects {
+        Effects {
+            reads_memory: true,
+            writes_memory: true,
+            reads_globals: true,
+            writes_globals: true,
+            reads_tables: true,
+            writes_tables: true,
+            may_trap: true,
+            calls_imports: true,
+            may_loop: true,
+        }
+    }
+
+    pub fn union(self, other: Effects) -> Effects {
+        Effects {
+            reads_memory: self.reads_memory | other.reads_memory,
+            writes_memory: self.writes_memory | other.writes_memory,
+            reads_globals: self.reads_globals | other.reads_globals,
+            writes_globals: self.writes_globals | other.writes_globals,
+            reads_tables: self.reads_tables | other.reads_tables,
+            writes_tables: self.writes_tables | other.writes_tables,
+            may_trap: self.may_trap | other.may_trap,
+            calls_imports: self.calls_imports | other.calls_imports,
+            may_loop: self.may_loop | other.may_loop,
+        }
+    }
+
+    /// Whether a call whose results are unused can be removed: it
+    /// writes nothing, can't trap, calls no imports and returns.
+    pub fn is_removable(&self) -> bool {
+        !(self.writes_memory
+            || self.writes_globals
+            || self.writes_tables
+            || self.may_trap
+            || self.calls_imports
+            || self.may_loop)
+    }
+
+    /// Whether a call is a pure function of its arguments: it is
+    /// removable and reads no state either, so it can also be moved
+    /// and calls with the same arguments merged.
+    pub fn is_pure(&self) -> bool {
+        self.is_removable() && !(self.reads_memory || self.reads_globals || self.reads_tables)
+    }
+
+    fn add_op(&mut self, op: &Operator) {
+        for effect in op.effects() {
+            match effect {
+                SideEffect::Trap => self.may_trap = true,
+                SideEffect::ReadMem => self.reads_memory = true,
+                SideEffect::WriteMem => self.writes_memory = true,
+                SideEffect::ReadGlobal => self.reads_globals = true,
+                SideEffect::WriteGlobal => self.writes_globals = true,
+                SideEffect::ReadTable => self.reads_tables = true,
+                SideEffect::WriteTable => self.writes_tables = true,
+                SideEffect::ReadLocal | SideEffect::WriteLocal => {}
+                // Calls are accounted for through the call graph.
+                SideEffect::All => {}
+            }
+        }
+    }
+}
+
+/// The effects of every function, including those of the functions it
+/// may call. Imports may do anything.
+#[derive(Clone, Debug, Default)]
+pub struct EffectSummary {
+    effects: PerEntity<Func, Effects>,
+    /// The effects of each call site, by function and instruction.
+    sites: PerEntity<Func, Vec<(Value, Effects)>>,
+}
+
+impl EffectSummary {
+    /// Summarize the functions bottom-up over `graph`'s components.
+    /// Bodies that have not been parsed yet are parsed (without
+    /// changing the module); already-compiled bodies cannot be
+    /// analyzed.
+    pub fn compute(module: &Module<'_>, graph: &CallGraph) -> Result<EffectSummary> {
+        let mut summary = EffectSummary::default();
+        for scc in graph.sccs() {
+            let mut effects = Effects::default();
+            if graph.is_recursive(scc[0]) {
+                effects.may_loop = true;
+            }
+            for &func in scc {
+                let body = match body(module, func)? {
+                    Some(body) => body,
+                    None => {
+                        if let FuncDecl::Import(..) = module.funcs[func] {
+                            effects = Effects::all();
+                        }
+                        continue;
+                    }
+                };
+                let cfg = CFGInfo::new(&body);
+                for (rpo, &block) in cfg.rpo.entries() {
+                    body.blocks[block].terminator.visit_successors(|succ| {
+                        if cfg.rpo_pos[succ].is_some_and(|succ_rpo| succ_rpo <= rpo) {
+                            effects.may_loop = true;
+                        }
+                    });
+                    if let Terminator::Unreachable = body.blocks[block].terminator {
+                        effects.may_trap = true;
+                    }
+                    for &inst in &body.blocks[block].insts {
+                        if let ValueDef::Operator(op, ..) = &body.values[inst] {
+                            effects.add_op(op);
+                        }
+                    }
+                }
+            }
+            // Callees in this component are not summarized yet, so
+            // their sites only add the effects of callees outside it;
+            // the component's own effects are all in `effects` anyway.
+            for &func in scc {
+                for site in graph.sites(func) {
+                    effects = effects.union(summary.site_effects(site));
+                }
+            }
+            for &func in scc {
+                summary.effects[func] = effects;
+            }
+            for &func in scc {
+                summary.sites[func] = graph
+                    .sites(func)
+                    .iter()
+                    .map(|site| (site.inst, summary.site_effects(site)))
+                    .collect();
+            }
+        }
+        Ok(summary)
+    }
+
+    fn site_effects(&self, site: &CallSite) -> Effects {
+        if site.external {
+            return Effects::all();
+        }
+        let mut effects = site
+            .callees
+            .iter()
+            .fold(Effects::default(), |effects, &callee| {
+                effects.union(self.effects[callee])
+            });
+        if let CallKind::Indirect(_) = site.kind {
+            // The call reads the table, and its bounds and signature
+            // checks may trap.
+            effects.reads_tables = true;
+            effects.may_trap = true;
+        }
+        effects
+    }
+
+    /// The effects of calling `func`.
+    pub fn effects(&self, func: Func) -> Effects {
+        self.effects[func]
+    }
+
+    /// The effects of the call `inst` in `func`, which for an indirect
+    /// call are those of all its possible targets, or `None` if `inst`
+    /// is not a call.
+    pub fn call_effects(&self, func: Func, inst: Value) -> Option<Effects> {
+        self.sites[func]
+            .iter()
+            .find(|(site, _)| *site == inst)
+            .map(|(_, effects)| *effects)
+    }
+}
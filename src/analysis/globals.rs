@@ -0,0 +1,143 @@
+//! Globals whose value never changes once code can read it.
+
+use super::body;
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use alloc::collections::BTreeMap;
+use anyhow::Result;
+
+/// The value every read of a global sees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConstantGlobal {
+    pub ty: Type,
+    /// The value's bits, as in `GlobalInit::Const`.
+    pub bits: u64,
+    /// The one write of a write-once global, in the start function, or
+    /// `None` if the global is never written.
+    pub init: Option<(Func, Value)>,
+}
+
+/// The `i32`, `i64`, `f32` and `f64` globals that are effectively
+/// constant:
+///
+/// - immutable globals with a constant initializer;
+/// - mutable globals that are neither imported nor exported (so only
+///   the module can write them) and are never written; and
+/// - such globals written exactly once, with a constant, in the entry
+///   block of the start function before any call or read of them. The
+///   start function runs before anything else can read the global, and
+///   running it again only writes the same value.
+#[derive(Clone, Debug, Default)]
+pub struct ConstantGlobals {
+    globals: BTreeMap<Global, ConstantGlobal>,
+}
+
+impl ConstantGlobals {
+    /// Find the constant globals. Bodies that have not been parsed yet
+    /// are parsed (without changing the module); already-compiled
+    /// bodies cannot be analyzed.
+    pub fn compute(module: &Module<'_>) -> Result<ConstantGlobals> {
+        let mut external = vec![false; module.globals.len()];
+        for import in &module.imports {
+            if let ImportKind::Global(global) = import.kind {
+                external[global.index()] = true;
+            }
+        }
+        for export in &module.exports {
+            if let ExportKind::Global(global) = export.kind {
+                external[global.index()] = true;
+            }
+        }
+
+        // The writes of each global.
+        let mut writes: BTreeMap<Global, Vec<(Func, Value)>> = BTreeMap::new();
+        for func in module.funcs.iter() {
+            let body = match body(module, func)? {
+                Some(body) => body,
+                None => continue,
+            };
+            for block in body.blocks.values() {
+                for &inst in &block.insts {
+                    if let ValueDef::Operator(Operator::GlobalSet { global_index }, ..) =
+                        body.values[inst]
+                    {
+                        writes.entry(global_index).or_default().push((func, inst));
+                    }
+                }
+            }
+        }
+        let start = match module.start_func {
+            Some(start) => body(module, start)?.map(|body| (start, body)),
+            None => None,
+        };
+
+        let mut result = ConstantGlobals::default();
+        for (global, data) in module.globals.entries() {
+            if !matches!(data.ty, Type::I32 | Type::I64 | Type::F32 | Type::F64) {
+                continue;
+            }
+            let external = external[global.index()];
+            let init = |bits| ConstantGlobal {
+                ty: data.ty,
+                bits,
+                init: None,
+            };
+            let constant = match writes.get(&global).map(|writes| &writes[..]) {
+                _ if !data.mutable => module.global_value(global).map(init),
+                None if !external => module.global_value(global).map(init),
+                Some(&[(func, inst)]) if !external => match &start {
+                    Some((start, body)) if *start == func => {
+                        write_once(body, global, inst).map(|bits| ConstantGlobal {
+                            init: Some((func, inst)),
+                            ..init(bits)
+                        })
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some(constant) = constant {
+                result.globals.insert(global, constant);
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn get(&self, global: Global) -> Option<&ConstantGlobal> {
+        self.globals.get(&global)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Global, &ConstantGlobal)> + '_ {
+        self.globals
+            .iter()
+            .map(|(&global, constant)| (global, constant))
+    }
+}
+
+/// The constant `write` writes to `global`, if it is in the entry
+/// block of `body` and nothing before it there may read the global.
+fn write_once(body: &FunctionBody, global: Global, write: Value) -> Option<u64> {
+    for &inst in &body.blocks[body.entry].insts {
+        let (op, args) = match &body.values[inst] {
+            ValueDef::Operator(op, args, _) => (op, &body.arg_pool[*args]),
+            _ => continue,
+        };
+        if inst == write {
+            return match body.values[body.resolve_alias(args[0])] {
+                ValueDef::Operator(Operator::I32Const { value }, ..) => Some(value as u64),
+                ValueDef::Operator(Operator::I64Const { value }, ..) => Some(value),
+                ValueDef::Operator(Operator::F32Const { value }, ..) => Some(value as u64),
+                ValueDef::Operator(Operator::F64Const { value }, ..) => Some(value),
+                _ => None,
+            };
+        }
+        match op {
+            Operator::GlobalGet { global_index } if *global_index == global => return None,
+            Operator::Call { .. } | Operator::CallIndirect { .. } => return None,
+            _ => {}
+        }
+    }
+    None
+}
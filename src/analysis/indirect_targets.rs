@@ -0,0 +1,229 @@
+//! Possible targets of indirect calls.
+
+use super::body;
+use crate::collections::HashSet;
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use alloc::borrow::Cow;
+use anyhow::Result;
+
+/// How hard to try to narrow down the targets of a `call_indirect`.
+/// Each level's sets are subsets of the previous level's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Precision {
+    /// Any function with the call's signature.
+    Signature,
+    /// Functions with the call's signature that its table can hold:
+    /// the table's initial contents and, if the table can be written,
+    /// every function whose reference escapes.
+    Table,
+    /// As `Table`, but a call with a constant index into a table that
+    /// is never written can only reach the function in that slot.
+    Slot,
+}
+
+/// The functions one `call_indirect` may reach.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TargetSet {
+    /// The possible callees in the module, sorted.
+    pub funcs: Vec<Func>,
+    /// Whether the call may also reach functions outside the module,
+    /// which the embedder can put in imported and exported tables.
+    pub external: bool,
+}
+
+impl TargetSet {
+    /// The only function the call can reach, if there is one.
+    pub fn single(&self) -> Option<Func> {
+        match &self.funcs[..] {
+            &[func] if !self.external => Some(func),
+            _ => None,
+        }
+    }
+}
+
+/// The target sets of every `call_indirect` in a module.
+///
+/// A function's reference escapes if it is exported (the embedder may
+/// store it in a table), in a table's initial contents, or in any
+/// element segment (for `table.init` and `ref.func`). A table can be
+/// written if it is imported or exported, an active segment in
+/// `elem_segments` writes it, or some body uses `table.set` or
+/// `table.grow` on it.
+#[derive(Clone, Debug)]
+pub struct IndirectTargets {
+    pub precision: Precision,
+    escaped: Vec<Func>,
+    written: HashSet<Table>,
+    external: HashSet<Table>,
+    sites: PerEntity<Func, Vec<(Value, TargetSet)>>,
+}
+
+impl IndirectTargets {
+    /// Compute the target sets. Bodies that have not been parsed yet
+    /// are parsed (without changing the module); already-compiled
+    /// bodies cannot be analyzed.
+    pub fn compute(module: &Module<'_>, precision: Precision) -> Result<IndirectTargets> {
+        let bodies = module
+            .funcs
+            .iter()
+            .map(|func| body(module, func))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self::compute_for(module, &bodies, precision))
+    }
+
+    /// Compute the target sets given every function's body.
+    pub(crate) fn compute_for(
+        module: &Module<'_>,
+        bodies: &[Option<Cow<'_, FunctionBody>>],
+        precision: Precision,
+    ) -> IndirectTargets {
+        let mut escaped: HashSet<Func> = HashSet::new();
+        for table in module.tables.values() {
+            for &func in table.func_elements.iter().flatten() {
+                if func.is_valid() {
+                    escaped.insert(func);
+                }
+            }
+        }
+        for segment in &module.elem_segments {
+            escaped.extend(segment.items.funcs());
+        }
+        let mut external: HashSet<Table> = HashSet::new();
+        for export in &module.exports {
+            match export.kind {
+                ExportKind::Func(func) => {
+                    escaped.insert(func);
+                }
+                ExportKind::Table(table) => {
+                    external.insert(table);
+                }
+                _ => {}
+            }
+        }
+        for import in &module.imports {
+            if let ImportKind::Table(table) = import.kind {
+                external.insert(table);
+            }
+        }
+        let mut written = external.clone();
+        // Initial contents written by active segments aren't tracked
+        // per slot.
+        for segment in &module.elem_segments {
+            if let ElementSegmentKind::Active { table, .. } = segment.kind {
+                written.insert(table);
+            }
+        }
+        for body in bodies.iter().flatten() {
+            for def in body.values.values() {
+                match def {
+                    ValueDef::Operator(Operator::TableSet { table_index }, ..)
+                    | ValueDef::Operator(Operator::TableGrow { table_index }, ..) => {
+                        written.insert(*table_index);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let mut escaped = escaped.into_iter().collect::<Vec<_>>();
+        escaped.sort();
+
+        let mut targets = IndirectTargets {
+            precision,
+            escaped,
+            written,
+            external,
+            sites: PerEntity::default(),
+        };
+        for (func, body) in module.funcs.iter().zip(bodies) {
+            let body = match body {
+                Some(body) => body,
+                None => continue,
+            };
+            let mut sites = vec![];
+            for block in body.blocks.values() {
+                for &inst in &block.insts {
+                    if let ValueDef::Operator(
+                        Operator::CallIndirect {
+                            sig_index,
+                            table_index,
+                        },
+                        args,
+                        _,
+                    ) = body.values[inst]
+                    {
+                        let index = body.resolve_alias(*body.arg_pool[args].last().unwrap());
+                        let set = targets.site(module, body, sig_index, table_index, index);
+                        sites.push((inst, set));
+                    }
+                }
+            }
+            targets.sites[func] = sites;
+        }
+        targets
+    }
+
+    fn site(
+        &self,
+        module: &Module<'_>,
+        body: &FunctionBody,
+        sig: Signature,
+        table: Table,
+        index: Value,
+    ) -> TargetSet {
+        let sig = &module.signatures[sig];
+        let elements = module.tables[table].func_elements.as_deref().unwrap_or(&[]);
+        let candidates: Vec<Func> = match self.precision {
+            Precision::Signature => module.funcs.iter().collect(),
+            Precision::Slot if !self.written.contains(&table) => match body.values[index] {
+                ValueDef::Operator(Operator::I32Const { value }, ..) => {
+                    elements.get(value as usize).copied().into_iter().collect()
+                }
+                _ => elements.to_vec(),
+            },
+            _ => {
+                let mut candidates = elements.to_vec();
+                if self.written.contains(&table) {
+                    candidates.extend(self.escaped.iter().copied());
+                }
+                candidates
+            }
+        };
+        let mut funcs = candidates
+            .into_iter()
+            .filter(|func| func.is_valid() && module.signatures[module.funcs[*func].sig()] == *sig)
+            .collect::<Vec<_>>();
+        funcs.sort();
+        funcs.dedup();
+        TargetSet {
+            funcs,
+            external: self.external.contains(&table),
+        }
+    }
+
+    /// The target set of the `call_indirect` `inst` in `func`.
+    pub fn targets(&self, func: Func, inst: Value) -> Option<&TargetSet> {
+        self.sites[func]
+            .iter()
+            .find(|(site, _)| *site == inst)
+            .map(|(_, set)| set)
+    }
+
+    /// The `call_indirect`s in `func` and their target sets, in block
+    /// order.
+    pub fn sites(&self, func: Func) -> &[(Value, TargetSet)] {
+        &self.sites[func][..]
+    }
+
+    /// The functions whose references escape, sorted.
+    pub fn escaped(&self) -> &[Func] {
+        &self.escaped[..]
+    }
+
+    /// Whether the contents of `table` can change at runtime.
+    pub fn is_written(&self, table: Table) -> bool {
+        self.written.contains(&table)
+    }
+}
@@ -0,0 +1,80 @@
+//! Liveness of values at block boundaries.
+
+use crate::cfg::CFGInfo;
+use crate::entity::PerEntity;
+use crate::ir::*;
+use alloc::collections::BTreeSet;
+
+/// The values live on entry to and exit from each reachable block,
+/// aliases resolved. A block's parameters are defined on entry, so
+/// they are never live into it.
+#[derive(Clone, Debug, Default)]
+pub struct Liveness {
+    live_in: PerEntity<Block, BTreeSet<Value>>,
+    live_out: PerEntity<Block, BTreeSet<Value>>,
+}
+
+impl Liveness {
+    pub fn compute(body: &FunctionBody, cfg: &CFGInfo) -> Liveness {
+        // What each block uses before defining it, and defines.
+        let mut uses: PerEntity<Block, BTreeSet<Value>> = PerEntity::default();
+        let mut defs: PerEntity<Block, BTreeSet<Value>> = PerEntity::default();
+        for &block in cfg.rpo.values() {
+            let (uses, defs) = (&mut uses[block], &mut defs[block]);
+            defs.extend(body.blocks[block].params.iter().map(|&(_, param)| param));
+            for &inst in &body.blocks[block].insts {
+                body.values[inst].visit_uses(&body.arg_pool, |arg| {
+                    let arg = body.resolve_alias(arg);
+                    if !defs.contains(&arg) {
+                        uses.insert(arg);
+                    }
+                });
+                defs.insert(inst);
+            }
+            body.blocks[block].terminator.visit_uses(|arg| {
+                let arg = body.resolve_alias(arg);
+                if !defs.contains(&arg) {
+                    uses.insert(arg);
+                }
+            });
+        }
+
+        let mut result = Liveness::default();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            // Successors first, so that most blocks settle in one pass.
+            for &block in cfg.rpo.values().rev() {
+                let mut live_out = BTreeSet::new();
+                body.blocks[block].terminator.visit_successors(|succ| {
+                    live_out.extend(result.live_in[succ].iter().copied());
+                });
+                let mut live_in = uses[block].clone();
+                live_in.extend(live_out.difference(&defs[block]).copied());
+                if live_in != result.live_in[block] {
+                    result.live_in[block] = live_in;
+                    changed = true;
+                }
+                result.live_out[block] = live_out;
+            }
+        }
+        result
+    }
+
+    /// The values live on entry to `block`.
+    pub fn live_in(&self, block: Block) -> &BTreeSet<Value> {
+        &self.live_in[block]
+    }
+
+    /// The values live on exit from `block`: those live into any of its
+    /// successors. The arguments it passes them are used by `block`
+    /// itself.
+    pub fn live_out(&self, block: Block) -> &BTreeSet<Value> {
+        &self.live_out[block]
+    }
+
+    /// Whether `value` is live on entry to `block`.
+    pub fn is_live_in(&self, block: Block, value: Value) -> bool {
+        self.live_in[block].contains(&value)
+    }
+}
@@ -0,0 +1,442 @@
+//! Natural loops, induction variables and trip counts.
+
+use crate::cfg::CFGInfo;
+use crate::entity::PerEntity;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use alloc::collections::BTreeSet;
+use core::convert::TryFrom;
+
+/// How a comparison relates its operands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A comparison of two integers, as signed or unsigned values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Predicate {
+    pub comparison: Comparison,
+    pub signed: bool,
+}
+
+impl Predicate {
+    /// The predicate of an integer comparison operator.
+    pub fn of(op: &Operator) -> Option<Predicate> {
+        use Comparison::*;
+        let (comparison, signed) = match op {
+            Operator::I32Eq | Operator::I64Eq => (Eq, false),
+            Operator::I32Ne | Operator::I64Ne => (Ne, false),
+            Operator::I32LtS | Operator::I64LtS => (Lt, true),
+            Operator::I32LtU | Operator::I64LtU => (Lt, false),
+            Operator::I32LeS | Operator::I64LeS => (Le, true),
+            Operator::I32LeU | Operator::I64LeU => (Le, false),
+            Operator::I32GtS | Operator::I64GtS => (Gt, true),
+            Operator::I32GtU | Operator::I64GtU => (Gt, false),
+            Operator::I32GeS | Operator::I64GeS => (Ge, true),
+            Operator::I32GeU | Operator::I64GeU => (Ge, false),
+            _ => return None,
+        };
+        Some(Predicate { comparison, signed })
+    }
+
+    /// The predicate that holds exactly when this one doesn't.
+    pub fn negate(self) -> Predicate {
+        use Comparison::*;
+        let comparison = match self.comparison {
+            Eq => Ne,
+            Ne => Eq,
+            Lt => Ge,
+            Le => Gt,
+            Gt => Le,
+            Ge => Lt,
+        };
+        Predicate { comparison, ..self }
+    }
+
+    /// The predicate with its operands swapped.
+    pub fn swap(self) -> Predicate {
+        use Comparison::*;
+        let comparison = match self.comparison {
+            Eq => Eq,
+            Ne => Ne,
+            Lt => Gt,
+            Le => Ge,
+            Gt => Lt,
+            Ge => Le,
+        };
+        Predicate { comparison, ..self }
+    }
+
+    fn eval(self, a: i128, b: i128) -> bool {
+        match self.comparison {
+            Comparison::Eq => a == b,
+            Comparison::Ne => a != b,
+            Comparison::Lt => a < b,
+            Comparison::Le => a <= b,
+            Comparison::Gt => a > b,
+            Comparison::Ge => a >= b,
+        }
+    }
+}
+
+/// A parameter of a loop header that starts at a loop-invariant value
+/// and has a constant added to it on every iteration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InductionVariable {
+    /// The header parameter: the value in the current iteration.
+    pub param: Value,
+    /// `i32` or `i64`.
+    pub ty: Type,
+    /// The value on entry to the loop.
+    pub init: Value,
+    /// What each iteration adds, wrapping.
+    pub step: i64,
+    /// `param` plus `step`: the value passed to the next iteration.
+    pub update: Value,
+}
+
+/// The test that decides whether a loop runs another iteration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ExitTest {
+    /// The loop's only exiting block, which runs once per iteration.
+    pub exiting: Block,
+    /// The index in `Loop::induction_variables` of the tested variable.
+    pub iv: usize,
+    /// Whether the test is on the variable's `update` rather than its
+    /// `param`.
+    pub after_step: bool,
+    /// The loop keeps going while `variable predicate bound` holds.
+    pub predicate: Predicate,
+    /// A loop-invariant value.
+    pub bound: Value,
+}
+
+/// A natural loop: the blocks that can reach a back edge to `header`
+/// without going through it. Retreating edges to a block that doesn't
+/// dominate their source (in irreducible control flow) form no loop.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Loop {
+    pub header: Block,
+    /// The blocks of the loop, including those of nested loops.
+    pub blocks: BTreeSet<Block>,
+    /// The sources of the back edges to the header.
+    pub latches: Vec<Block>,
+    /// The edges leaving the loop, as (from, to).
+    pub exits: Vec<(Block, Block)>,
+    /// The index of the innermost enclosing loop.
+    pub parent: Option<usize>,
+    pub induction_variables: Vec<InductionVariable>,
+    pub exit_test: Option<ExitTest>,
+    /// How many times the header runs per entry to the loop, if the
+    /// exit test and its operands make that a constant.
+    pub trip_count: Option<u64>,
+}
+
+/// The loops of a body.
+#[derive(Clone, Debug, Default)]
+pub struct Loops {
+    /// Outer loops before the loops they contain.
+    loops: Vec<Loop>,
+    innermost: PerEntity<Block, Option<usize>>,
+}
+
+impl Loops {
+    pub fn compute(body: &FunctionBody, cfg: &CFGInfo) -> Loops {
+        let mut result = Loops::default();
+        // A header dominates its loop, so it comes before the headers
+        // of loops nested in it in reverse postorder.
+        for &header in cfg.rpo.values() {
+            let latches = body
+                .preds(header)
+                .iter()
+                .copied()
+                .filter(|&pred| cfg.rpo_pos[pred].is_some() && cfg.dominates(header, pred))
+                .collect::<Vec<_>>();
+            if latches.is_empty() {
+                continue;
+            }
+            let mut blocks = BTreeSet::new();
+            blocks.insert(header);
+            let mut work = latches.clone();
+            while let Some(block) = work.pop() {
+                if blocks.insert(block) {
+                    work.extend(
+                        body.preds(block)
+                            .iter()
+                            .copied()
+                            .filter(|&pred| cfg.rpo_pos[pred].is_some()),
+                    );
+                }
+            }
+            let mut exits = vec![];
+            for &block in &blocks {
+                body.blocks[block].terminator.visit_successors(|succ| {
+                    if !blocks.contains(&succ) {
+                        exits.push((block, succ));
+                    }
+                });
+            }
+            let index = result.loops.len();
+            let parent = result.innermost[header];
+            for &block in &blocks {
+                result.innermost[block] = Some(index);
+            }
+            result.loops.push(Loop {
+                header,
+                blocks,
+                latches,
+                exits,
+                parent,
+                induction_variables: vec![],
+                exit_test: None,
+                trip_count: None,
+            });
+        }
+        for index in 0..result.loops.len() {
+            let ivs = induction_variables(body, &result.loops[index]);
+            result.loops[index].induction_variables = ivs;
+            let test = result.exit_test(body, cfg, index);
+            let lp = &mut result.loops[index];
+            lp.exit_test = test;
+            lp.trip_count = test.and_then(|test| trip_count(body, lp, &test));
+        }
+        result
+    }
+
+    /// The loops, outer loops before the loops they contain.
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops[..]
+    }
+
+    /// The index of the innermost loop containing `block`.
+    pub fn innermost(&self, block: Block) -> Option<usize> {
+        self.innermost[block]
+    }
+
+    /// How many loops contain `block`.
+    pub fn depth(&self, block: Block) -> usize {
+        let mut depth = 0;
+        let mut lp = self.innermost[block];
+        while let Some(index) = lp {
+            depth += 1;
+            lp = self.loops[index].parent;
+        }
+        depth
+    }
+
+    fn exit_test(&self, body: &FunctionBody, cfg: &CFGInfo, index: usize) -> Option<ExitTest> {
+        let lp = &self.loops[index];
+        let exiting = lp.exits.first()?.0;
+        if lp.exits.len() != 1
+            || self.innermost[exiting] != Some(index)
+            || !lp
+                .latches
+                .iter()
+                .all(|&latch| cfg.dominates(exiting, latch))
+        {
+            return None;
+        }
+        let (mut cond, stays_if_true) = match &body.blocks[exiting].terminator {
+            Terminator::CondBr {
+                cond,
+                if_true,
+                if_false,
+            } => (
+                *cond,
+                lp.blocks.contains(&if_true.block) && !lp.blocks.contains(&if_false.block),
+            ),
+            _ => return None,
+        };
+        let mut negated = !stays_if_true;
+        let (predicate, a, b) = loop {
+            cond = body.resolve_alias(cond);
+            match &body.values[cond] {
+                ValueDef::Operator(Operator::I32Eqz, args, _) => {
+                    negated = !negated;
+                    cond = body.arg_pool[*args][0];
+                }
+                ValueDef::Operator(op, args, _) => {
+                    let args = &body.arg_pool[*args];
+                    break (
+                        Predicate::of(op)?,
+                        body.resolve_alias(args[0]),
+                        body.resolve_alias(args[1]),
+                    );
+                }
+                _ => return None,
+            }
+        };
+        let predicate = if negated {
+            predicate.negate()
+        } else {
+            predicate
+        };
+        let invariant = |value: Value| {
+            let block = match body.values[value] {
+                ValueDef::BlockParam(block, ..) => block,
+                _ => body.value_blocks[value],
+            };
+            is_constant(body, value).is_some() || !lp.blocks.contains(&block)
+        };
+        for (iv, var) in lp.induction_variables.iter().enumerate() {
+            for (tested, after_step) in [(var.param, false), (var.update, true)] {
+                let (predicate, bound) = if a == tested && invariant(b) {
+                    (predicate, b)
+                } else if b == tested && invariant(a) {
+                    (predicate.swap(), a)
+                } else {
+                    continue;
+                };
+                return Some(ExitTest {
+                    exiting,
+                    iv,
+                    after_step,
+                    predicate,
+                    bound,
+                });
+            }
+        }
+        None
+    }
+}
+
+/// The value of an integer constant, sign-extended.
+fn is_constant(body: &FunctionBody, value: Value) -> Option<i64> {
+    match body.values[body.resolve_alias(value)] {
+        ValueDef::Operator(Operator::I32Const { value }, ..) => Some(value as i32 as i64),
+        ValueDef::Operator(Operator::I64Const { value }, ..) => Some(value as i64),
+        _ => None,
+    }
+}
+
+fn induction_variables(body: &FunctionBody, lp: &Loop) -> Vec<InductionVariable> {
+    let header = &body.blocks[lp.header];
+    let mut ivs = vec![];
+    for (i, &(ty, param)) in header.params.iter().enumerate() {
+        if ty != Type::I32 && ty != Type::I64 {
+            continue;
+        }
+        // The values passed to the parameter from outside the loop and
+        // from the latches.
+        let mut inits = vec![];
+        let mut updates = vec![];
+        for (pred_index, &pred) in body.edge_pool[header.preds].iter().enumerate() {
+            let succ_index = body.edge_pos_pool[header.pos_in_pred_succ][pred_index];
+            let mut arg = None;
+            let mut succ = 0;
+            body.blocks[pred].terminator.visit_targets(|target| {
+                if succ == succ_index {
+                    arg = target.args.get(i).copied();
+                }
+                succ += 1;
+            });
+            let arg = match arg {
+                Some(arg) => body.resolve_alias(arg),
+                None => continue,
+            };
+            if lp.blocks.contains(&pred) {
+                updates.push(arg);
+            } else {
+                inits.push(arg);
+            }
+        }
+        let (init, update) = match (&inits[..], &updates[..]) {
+            ([init, rest @ ..], [update, others @ ..])
+                if rest.iter().all(|v| v == init) && others.iter().all(|v| v == update) =>
+            {
+                (*init, *update)
+            }
+            _ => continue,
+        };
+        let step = match &body.values[update] {
+            ValueDef::Operator(Operator::I32Add | Operator::I64Add, args, _) => {
+                match body.arg_pool[*args]
+                    .iter()
+                    .map(|&arg| body.resolve_alias(arg))
+                    .collect::<Vec<_>>()[..]
+                {
+                    [a, b] if a == param => is_constant(body, b),
+                    [a, b] if b == param => is_constant(body, a),
+                    _ => None,
+                }
+            }
+            ValueDef::Operator(Operator::I32Sub | Operator::I64Sub, args, _) => {
+                let args = &body.arg_pool[*args];
+                if body.resolve_alias(args[0]) == param {
+                    is_constant(body, args[1]).map(i64::wrapping_neg)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        if let Some(step) = step {
+            ivs.push(InductionVariable {
+                param,
+                ty,
+                init,
+                step,
+                update,
+            });
+        }
+    }
+    ivs
+}
+
+/// The number of times the header runs, from constant operands: the
+/// first iteration whose test fails, plus one. `None` if the variable
+/// would wrap before then.
+fn trip_count(body: &FunctionBody, lp: &Loop, test: &ExitTest) -> Option<u64> {
+    let iv = &lp.induction_variables[test.iv];
+    let bits = if iv.ty == Type::I32 { 32 } else { 64 };
+    let interpret = |value: i64| -> i128 {
+        let unsigned = (value as u64 as u128 & (u128::MAX >> (128 - bits))) as i128;
+        if test.predicate.signed && unsigned >= 1 << (bits - 1) {
+            unsigned - (1 << bits)
+        } else {
+            unsigned
+        }
+    };
+    let (lo, hi) = if test.predicate.signed {
+        (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)
+    } else {
+        (0, (1i128 << bits) - 1)
+    };
+    let init = interpret(is_constant(body, iv.init)?);
+    let bound = interpret(is_constant(body, test.bound)?);
+    let step = iv.step as i128;
+    let first = init + if test.after_step { step } else { 0 };
+    // The tested value in iteration `k`, if it doesn't wrap.
+    let at = |k: i128| first + k * step;
+    let stay = |x: i128| test.predicate.eval(x, bound);
+    if first < lo || first > hi {
+        return None;
+    }
+    let div_ceil = |a: i128, b: i128| (a + b - 1).div_euclid(b);
+    let last = if !stay(first) {
+        0
+    } else {
+        match (test.predicate.comparison, step.signum()) {
+            (Comparison::Lt, 1) => div_ceil(bound - first, step),
+            (Comparison::Le, 1) => div_ceil(bound + 1 - first, step),
+            (Comparison::Gt, -1) => div_ceil(first - bound, -step),
+            (Comparison::Ge, -1) => div_ceil(first - (bound - 1), -step),
+            (Comparison::Ne, _) if step != 0 && (bound - first) % step == 0 => {
+                (bound - first) / step
+            }
+            (Comparison::Eq, _) if step != 0 => 1,
+            _ => return None,
+        }
+    };
+    if last < 0 || at(last) < lo || at(last) > hi {
+        return None;
+    }
+    u64::try_from(last + 1).ok()
+}
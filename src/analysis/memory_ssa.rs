@@ -0,0 +1,284 @@
+//! Memory SSA: def-use chains for the state of linear memory.
+
+use super::alias::AliasAnalysis;
+use crate::cfg::CFGInfo;
+use crate::collections::HashMap;
+use crate::declare_entity;
+use crate::entity::{EntityRef, EntityVec, PerEntity};
+use crate::ir::*;
+use crate::op_traits::SideEffect;
+use crate::passes::memtrace::access;
+use crate::prelude::*;
+use crate::Operator;
+use alloc::collections::BTreeSet;
+
+declare_entity!(MemoryAccess, "mem");
+
+/// A state of memory, all memories together.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemoryAccessDef {
+    /// The state on entry to the function.
+    LiveOnEntry,
+    /// The state after an instruction that may write memory: a store,
+    /// a call or another writing operator, modifying `prev`.
+    Def { inst: Value, prev: MemoryAccess },
+    /// The merge of the states at the ends of the reachable
+    /// predecessors of `block`, on entry to it. The entry block also
+    /// merges `LiveOnEntry`, from an invalid block.
+    Phi {
+        block: Block,
+        incoming: Vec<(Block, MemoryAccess)>,
+    },
+}
+
+/// The memory states of a body, like LLVM's MemorySSA: every
+/// instruction that may write memory defines a new state, every one
+/// that may read memory (without writing it) uses one, and blocks
+/// where states from different paths merge start with a phi. Walking
+/// from a load up through the states, skipping stores that the alias
+/// analysis shows don't touch the loaded bytes, finds what may have
+/// last written them without scanning unrelated code.
+#[derive(Clone, Debug, Default)]
+pub struct MemorySsa {
+    accesses: EntityVec<MemoryAccess, MemoryAccessDef>,
+    /// The state each writing instruction defines.
+    defs: HashMap<Value, MemoryAccess>,
+    /// The state each reading instruction reads.
+    uses: HashMap<Value, MemoryAccess>,
+    /// The readers of each state.
+    readers: PerEntity<MemoryAccess, Vec<Value>>,
+    entry: PerEntity<Block, MemoryAccess>,
+    exit: PerEntity<Block, MemoryAccess>,
+}
+
+/// Whether `op` may write or read memory.
+fn memory_effects(op: &Operator) -> (bool, bool) {
+    let (mut writes, mut reads) = (false, false);
+    for effect in op.effects() {
+        match effect {
+            SideEffect::WriteMem | SideEffect::All => writes = true,
+            SideEffect::ReadMem => reads = true,
+            _ => {}
+        }
+    }
+    (writes, reads)
+}
+
+impl MemorySsa {
+    /// Build the memory states of `body`, with phis on the iterated
+    /// dominance frontier of the blocks that write memory.
+    pub fn compute(body: &FunctionBody, cfg: &CFGInfo) -> MemorySsa {
+        let mut ssa = MemorySsa::default();
+        let live_on_entry = ssa.accesses.push(MemoryAccessDef::LiveOnEntry);
+        let reachable = |block: Block| cfg.rpo_pos[block].is_some();
+        let preds = |block: Block| {
+            body.preds(block)
+                .iter()
+                .copied()
+                .filter(move |&pred| reachable(pred))
+        };
+
+        // The dominance frontier of each block.
+        let mut frontier: PerEntity<Block, BTreeSet<Block>> = PerEntity::default();
+        // The entry block is also entered from outside the function.
+        let entries = |block: Block| preds(block).count() + (block == cfg.entry) as usize;
+        for &block in cfg.rpo.values() {
+            if entries(block) < 2 {
+                continue;
+            }
+            for pred in preds(block) {
+                let mut runner = pred;
+                while runner.is_valid() && runner != cfg.domtree[block] {
+                    frontier[runner].insert(block);
+                    runner = cfg.domtree[runner];
+                }
+            }
+        }
+        let writes = |block: Block| {
+            body.blocks[block]
+                .insts
+                .iter()
+                .any(|&inst| match &body.values[inst] {
+                    ValueDef::Operator(op, ..) => memory_effects(op).0,
+                    _ => false,
+                })
+        };
+        let mut phis: PerEntity<Block, bool> = PerEntity::default();
+        let mut work = cfg
+            .rpo
+            .values()
+            .copied()
+            .filter(|&block| writes(block))
+            .collect::<Vec<_>>();
+        while let Some(block) = work.pop() {
+            for &join in &frontier[block] {
+                if !phis[join] {
+                    phis[join] = true;
+                    work.push(join);
+                }
+            }
+        }
+
+        // A block without a phi sees the state at the end of its
+        // immediate dominator, which comes before it in RPO.
+        for &block in cfg.rpo.values() {
+            let mut state = if phis[block] {
+                ssa.accesses.push(MemoryAccessDef::Phi {
+                    block,
+                    incoming: vec![],
+                })
+            } else if block == cfg.entry {
+                live_on_entry
+            } else {
+                ssa.exit[cfg.domtree[block]]
+            };
+            ssa.entry[block] = state;
+            for &inst in &body.blocks[block].insts {
+                let (writes, reads) = match &body.values[inst] {
+                    ValueDef::Operator(op, ..) => memory_effects(op),
+                    _ => continue,
+                };
+                if writes {
+                    state = ssa
+                        .accesses
+                        .push(MemoryAccessDef::Def { inst, prev: state });
+                    ssa.defs.insert(inst, state);
+                } else if reads {
+                    ssa.uses.insert(inst, state);
+                    ssa.readers[state].push(inst);
+                }
+            }
+            ssa.exit[block] = state;
+        }
+        for &block in cfg.rpo.values() {
+            if !phis[block] {
+                continue;
+            }
+            let mut incoming = preds(block)
+                .map(|pred| (pred, ssa.exit[pred]))
+                .collect::<Vec<_>>();
+            if block == cfg.entry {
+                incoming.insert(0, (Block::invalid(), live_on_entry));
+            }
+            let phi = ssa.entry[block];
+            if let MemoryAccessDef::Phi { incoming: slot, .. } = &mut ssa.accesses[phi] {
+                *slot = incoming;
+            }
+        }
+        ssa
+    }
+
+    pub fn access(&self, access: MemoryAccess) -> &MemoryAccessDef {
+        &self.accesses[access]
+    }
+
+    /// The state the writing instruction `inst` defines.
+    pub fn def(&self, inst: Value) -> Option<MemoryAccess> {
+        self.defs.get(&inst).copied()
+    }
+
+    /// The state the instruction `inst` reads or modifies.
+    pub fn defining_access(&self, inst: Value) -> Option<MemoryAccess> {
+        match self.defs.get(&inst) {
+            Some(&def) => match self.accesses[def] {
+                MemoryAccessDef::Def { prev, .. } => Some(prev),
+                _ => unreachable!(),
+            },
+            None => self.uses.get(&inst).copied(),
+        }
+    }
+
+    /// The instructions that read `access` without writing memory.
+    pub fn readers(&self, access: MemoryAccess) -> &[Value] {
+        &self.readers[access][..]
+    }
+
+    /// The state on entry to `block`.
+    pub fn entry(&self, block: Block) -> MemoryAccess {
+        self.entry[block]
+    }
+
+    /// The state at the end of `block`.
+    pub fn exit(&self, block: Block) -> MemoryAccess {
+        self.exit[block]
+    }
+
+    /// The nearest state above the load or store `inst` that may have
+    /// written the bytes it accesses: a store that may alias them, a
+    /// call or other writing operator (unless `inst` accesses a private
+    /// stack frame), or the entry state. A phi is walked through if
+    /// the walks from all its incoming states (other than those coming
+    /// back around a loop to it) agree, and is the result otherwise.
+    /// Gives up and returns the state reached after `limit` steps.
+    pub fn clobbering(
+        &self,
+        body: &FunctionBody,
+        aliases: &AliasAnalysis,
+        inst: Value,
+        limit: usize,
+    ) -> Option<MemoryAccess> {
+        let state = self.defining_access(inst)?;
+        let mut budget = limit;
+        Some(self.walk(body, aliases, inst, state, &mut budget, &mut vec![]))
+    }
+
+    fn walk(
+        &self,
+        body: &FunctionBody,
+        aliases: &AliasAnalysis,
+        inst: Value,
+        mut state: MemoryAccess,
+        budget: &mut usize,
+        phis: &mut Vec<MemoryAccess>,
+    ) -> MemoryAccess {
+        loop {
+            if *budget == 0 {
+                return state;
+            }
+            *budget -= 1;
+            match &self.accesses[state] {
+                &MemoryAccessDef::Def { inst: def, prev } => {
+                    let is_access = match &body.values[def] {
+                        ValueDef::Operator(op, ..) => access(op).is_some(),
+                        _ => false,
+                    };
+                    let clobbers = if is_access {
+                        aliases.may_alias(def, inst)
+                    } else {
+                        !aliases.is_private(inst)
+                    };
+                    if clobbers {
+                        return state;
+                    }
+                    state = prev;
+                }
+                MemoryAccessDef::LiveOnEntry => return state,
+                MemoryAccessDef::Phi { incoming, .. } => {
+                    // A phi being walked through already is reached
+                    // again around a loop.
+                    if phis.contains(&state) {
+                        return state;
+                    }
+                    phis.push(state);
+                    let mut result = None;
+                    for &(_, incoming) in incoming {
+                        let found = self.walk(body, aliases, inst, incoming, budget, phis);
+                        if found == state {
+                            continue;
+                        }
+                        match result {
+                            None => result = Some(found),
+                            Some(result) if result == found => {}
+                            Some(_) => {
+                                result = None;
+                                break;
+                            }
+                        }
+                    }
+                    phis.pop();
+                    return result.unwrap_or(state);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,649 @@
+//! Integer value-range analysis.
+
+use crate::cfg::CFGInfo;
+use crate::entity::PerEntity;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use alloc::collections::BTreeMap;
+
+/// An inclusive range of an `i32` or `i64` value, as unsigned
+/// integers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ValueRange {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl ValueRange {
+    pub fn new(min: u64, max: u64) -> ValueRange {
+        debug_assert!(min <= max);
+        ValueRange { min, max }
+    }
+
+    pub fn constant(value: u64) -> ValueRange {
+        ValueRange::new(value, value)
+    }
+
+    /// Every value of `ty`, which must be `i32` or `i64`.
+    pub fn full(ty: Type) -> ValueRange {
+        ValueRange::new(0, type_max(ty))
+    }
+
+    pub fn as_constant(&self) -> Option<u64> {
+        (self.min == self.max).then_some(self.min)
+    }
+
+    pub fn contains(&self, value: u64) -> bool {
+        self.min <= value && value <= self.max
+    }
+
+    pub fn union(self, other: ValueRange) -> ValueRange {
+        ValueRange::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    /// The values in both ranges, or `None` if there are none.
+    pub fn intersect(self, other: ValueRange) -> Option<ValueRange> {
+        let (min, max) = (self.min.max(other.min), self.max.min(other.max));
+        (min <= max).then(|| ValueRange::new(min, max))
+    }
+}
+
+pub(crate) fn type_max(ty: Type) -> u64 {
+    match ty {
+        Type::I32 => u32::MAX as u64,
+        _ => u64::MAX,
+    }
+}
+
+pub(crate) fn is_int(ty: Type) -> bool {
+    matches!(ty, Type::I32 | Type::I64)
+}
+
+/// Whether every value in `range` is non-negative as a signed `ty`.
+fn non_negative(range: ValueRange, ty: Type) -> bool {
+    range.max <= type_max(ty) >> 1
+}
+
+/// The smallest all-ones mask covering `value`.
+fn mask(value: u64) -> u64 {
+    u64::MAX.checked_shr(value.leading_zeros()).unwrap_or(0)
+}
+
+/// The operand type of an integer comparison, and its unsigned
+/// counterpart.
+pub(crate) fn comparison(op: &Operator) -> Option<(Type, Operator)> {
+    use Operator::*;
+    Some(match *op {
+        I32Eq | I32Ne | I32LtU | I32GtU | I32LeU | I32GeU => (Type::I32, *op),
+        I64Eq | I64Ne | I64LtU | I64GtU | I64LeU | I64GeU => (Type::I64, *op),
+        I32LtS => (Type::I32, I32LtU),
+        I32GtS => (Type::I32, I32GtU),
+        I32LeS => (Type::I32, I32LeU),
+        I32GeS => (Type::I32, I32GeU),
+        I64LtS => (Type::I64, I64LtU),
+        I64GtS => (Type::I64, I64GtU),
+        I64LeS => (Type::I64, I64LeU),
+        I64GeS => (Type::I64, I64GeU),
+        _ => return None,
+    })
+}
+
+/// A comparison as `(a < b, strict)` with its operands possibly
+/// swapped, or `None` for equality.
+fn as_less(op: &Operator) -> Option<(bool, bool)> {
+    use Operator::*;
+    match op {
+        I32LtU | I64LtU => Some((false, true)),
+        I32LeU | I64LeU => Some((false, false)),
+        I32GtU | I64GtU => Some((true, true)),
+        I32GeU | I64GeU => Some((true, false)),
+        _ => None,
+    }
+}
+
+/// The comparison `op`, made unsigned, if its operands allow it.
+fn unsigned_comparison(op: &Operator, a: ValueRange, b: ValueRange) -> Option<Operator> {
+    let (ty, unsigned) = comparison(op)?;
+    (unsigned == *op || (non_negative(a, ty) && non_negative(b, ty))).then_some(unsigned)
+}
+
+/// The outcome of the comparison `op` of `a` and `b`, if the ranges
+/// decide it.
+fn compare(op: &Operator, a: ValueRange, b: ValueRange) -> Option<bool> {
+    let op = unsigned_comparison(op, a, b)?;
+    match as_less(&op) {
+        Some((swap, strict)) => {
+            let (x, y) = if swap { (b, a) } else { (a, b) };
+            let always = if strict {
+                x.max < y.min
+            } else {
+                x.max <= y.min
+            };
+            let never = if strict {
+                x.min >= y.max
+            } else {
+                x.min > y.max
+            };
+            if always {
+                Some(true)
+            } else if never {
+                Some(false)
+            } else {
+                None
+            }
+        }
+        None => {
+            let equal = match (a.as_constant(), b.as_constant()) {
+                (Some(a), Some(b)) if a == b => Some(true),
+                _ if a.intersect(b).is_none() => Some(false),
+                _ => None,
+            };
+            let ne = matches!(op, Operator::I32Ne | Operator::I64Ne);
+            equal.map(|equal| equal != ne)
+        }
+    }
+}
+
+/// Narrow `a` and `b` given that `op` of them is `taken`. Returns
+/// `None` if that cannot happen.
+pub(crate) fn refine(
+    op: &Operator,
+    a: ValueRange,
+    b: ValueRange,
+    taken: bool,
+) -> Option<(ValueRange, ValueRange)> {
+    let op = match unsigned_comparison(op, a, b) {
+        Some(op) => op,
+        None => return Some((a, b)),
+    };
+    match as_less(&op) {
+        Some((swap, strict)) => {
+            // Not taken is the opposite comparison with the operands
+            // swapped.
+            let (swap, strict) = if taken {
+                (swap, strict)
+            } else {
+                (!swap, !strict)
+            };
+            let (x, y) = if swap { (b, a) } else { (a, b) };
+            let delta = strict as u64;
+            let x = x.intersect(ValueRange::new(0, y.max.checked_sub(delta)?))?;
+            let y = y.intersect(ValueRange::new(x.min.checked_add(delta)?, u64::MAX))?;
+            Some(if swap { (y, x) } else { (x, y) })
+        }
+        None => {
+            let ne = matches!(op, Operator::I32Ne | Operator::I64Ne);
+            if taken != ne {
+                let both = a.intersect(b)?;
+                Some((both, both))
+            } else {
+                Some((exclude(a, b)?, exclude(b, a)?))
+            }
+        }
+    }
+}
+
+/// `a` without `b`'s value, if `b` is a constant at an end of `a`.
+fn exclude(a: ValueRange, b: ValueRange) -> Option<ValueRange> {
+    match b.as_constant() {
+        Some(c) if a.as_constant() == Some(c) => None,
+        Some(c) if a.min == c => Some(ValueRange::new(c + 1, a.max)),
+        Some(c) if a.max == c => Some(ValueRange::new(a.min, c - 1)),
+        _ => Some(a),
+    }
+}
+
+fn checked(min: Option<u64>, max: Option<u64>) -> Option<ValueRange> {
+    Some(ValueRange::new(min?, max?))
+}
+
+/// The range of the result of `op`, of type `ty`, given its operands'.
+pub(crate) fn eval(op: &Operator, args: &[ValueRange], ty: Type) -> ValueRange {
+    use Operator::*;
+    let full = ValueRange::full(ty);
+    let bits = if ty == Type::I32 { 32 } else { 64 };
+    let arg = |i: usize| args[i];
+    let or_full = |range: Option<ValueRange>| range.filter(|r| r.max <= full.max).unwrap_or(full);
+    match op {
+        &I32Const { value } => ValueRange::constant(value as u64),
+        &I64Const { value } => ValueRange::constant(value),
+        I32Eqz | I64Eqz => match (arg(0).min, arg(0).max) {
+            (0, 0) => ValueRange::constant(1),
+            (0, _) => ValueRange::new(0, 1),
+            _ => ValueRange::constant(0),
+        },
+        _ if comparison(op).is_some() => match compare(op, arg(0), arg(1)) {
+            Some(result) => ValueRange::constant(result as u64),
+            None => ValueRange::new(0, 1),
+        },
+        F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F64Eq | F64Ne | F64Lt | F64Gt | F64Le
+        | F64Ge => ValueRange::new(0, 1),
+        I32Add | I64Add => or_full(checked(
+            arg(0).min.checked_add(arg(1).min),
+            arg(0).max.checked_add(arg(1).max),
+        )),
+        I32Sub | I64Sub if arg(0).min >= arg(1).max => {
+            ValueRange::new(arg(0).min - arg(1).max, arg(0).max - arg(1).min)
+        }
+        I32Mul | I64Mul => or_full(checked(
+            arg(0).min.checked_mul(arg(1).min),
+            arg(0).max.checked_mul(arg(1).max),
+        )),
+        // Division by zero traps, so a result means a non-zero divisor.
+        I32DivU | I64DivU => ValueRange::new(
+            arg(0).min / arg(1).max.max(1),
+            arg(0).max / arg(1).min.max(1),
+        ),
+        I32RemU | I64RemU => ValueRange::new(0, arg(0).max.min(arg(1).max.saturating_sub(1))),
+        I32DivS | I64DivS if non_negative(arg(0), ty) && non_negative(arg(1), ty) => {
+            ValueRange::new(
+                arg(0).min / arg(1).max.max(1),
+                arg(0).max / arg(1).min.max(1),
+            )
+        }
+        I32RemS | I64RemS if non_negative(arg(0), ty) && non_negative(arg(1), ty) => {
+            ValueRange::new(0, arg(0).max.min(arg(1).max.saturating_sub(1)))
+        }
+        I32And | I64And => ValueRange::new(0, arg(0).max.min(arg(1).max)),
+        I32Or | I64Or => ValueRange::new(arg(0).min.max(arg(1).min), mask(arg(0).max | arg(1).max)),
+        I32Xor | I64Xor => ValueRange::new(0, mask(arg(0).max | arg(1).max)),
+        // Shift amounts are taken modulo the width.
+        I32Shl | I64Shl => match arg(1).as_constant().map(|c| (c % bits) as u32) {
+            Some(c) if arg(0).max <= full.max >> c => {
+                ValueRange::new(arg(0).min << c, arg(0).max << c)
+            }
+            _ => full,
+        },
+        I32ShrU | I64ShrU if arg(1).max < bits => {
+            ValueRange::new(arg(0).min >> arg(1).max, arg(0).max >> arg(1).min)
+        }
+        I32ShrU | I64ShrU => ValueRange::new(0, arg(0).max),
+        I32ShrS | I64ShrS if non_negative(arg(0), ty) => {
+            if arg(1).max < bits {
+                ValueRange::new(arg(0).min >> arg(1).max, arg(0).max >> arg(1).min)
+            } else {
+                ValueRange::new(0, arg(0).max)
+            }
+        }
+        I32Clz | I32Ctz | I32Popcnt | I64Clz | I64Ctz | I64Popcnt => ValueRange::new(0, bits),
+        I32WrapI64 => or_full(Some(arg(0))),
+        I64ExtendI32U => arg(0),
+        I64ExtendI32S if non_negative(arg(0), Type::I32) => arg(0),
+        I32Load8U { .. } | I64Load8U { .. } => ValueRange::new(0, 0xff),
+        I32Load16U { .. } | I64Load16U { .. } => ValueRange::new(0, 0xffff),
+        I64Load32U { .. } => ValueRange::new(0, u32::MAX as u64),
+        Select | TypedSelect { .. } => match arg(2).as_constant() {
+            Some(0) => arg(1),
+            Some(_) => arg(0),
+            None => arg(0).union(arg(1)),
+        },
+        MemorySize { .. } => ValueRange::new(0, 0x1_0000),
+        _ => full,
+    }
+}
+
+/// A value's range from a fact about it and from its definition, both
+/// of which hold.
+fn narrow(fact: Option<ValueRange>, def: Option<ValueRange>) -> Option<ValueRange> {
+    match (fact, def) {
+        (Some(fact), Some(def)) => fact.intersect(def).or(Some(fact)),
+        (fact, def) => fact.or(def),
+    }
+}
+
+/// Facts about values known to hold in a block, from the branches
+/// that lead to it.
+type Facts = BTreeMap<Value, ValueRange>;
+
+/// How many times a value's range may grow before it is widened.
+const WIDEN_AFTER: u32 = 2;
+/// How many passes over the body to make before giving up.
+const MAX_ROUNDS: usize = 64;
+/// How many passes to make after the ranges stop growing, to narrow
+/// those that were widened.
+const NARROW_ROUNDS: usize = 2;
+
+/// The ranges of the `i32` and `i64` values in a body: those at each
+/// value's definition, and narrower ones that hold in blocks reached
+/// only through branches that compare a value (for example, the
+/// `if_true` side of `x <u 10`, or the targets of a `br_table` on
+/// `x`).
+///
+/// Ranges are unsigned; signed operations refine or compute ranges
+/// only when their operands are known to be non-negative.
+#[derive(Clone, Debug, Default)]
+pub struct ValueRanges {
+    ranges: PerEntity<Value, Option<ValueRange>>,
+    facts: PerEntity<Block, Facts>,
+}
+
+impl ValueRanges {
+    pub fn compute(body: &FunctionBody, cfg: &CFGInfo) -> ValueRanges {
+        let mut analysis = Analysis {
+            body,
+            ranges: PerEntity::default(),
+            grown: PerEntity::default(),
+            facts: PerEntity::default(),
+            next: None,
+            changed: false,
+        };
+        for &(ty, param) in &body.blocks[body.entry].params {
+            if is_int(ty) {
+                analysis.ranges[param] = Some(ValueRange::full(ty));
+            }
+        }
+        analysis.facts[body.entry] = Some(Facts::new());
+        let mut converged = false;
+        for _ in 0..MAX_ROUNDS {
+            analysis.changed = false;
+            for &block in cfg.rpo.values() {
+                analysis.visit(block);
+            }
+            if !analysis.changed {
+                converged = true;
+                break;
+            }
+        }
+        if converged {
+            // Recompute everything once more from the (sound) result,
+            // replacing rather than growing the ranges, which undoes
+            // the widening where the loop bounds are known; for example
+            // a counter compared against a constant.
+            for _ in 0..NARROW_ROUNDS {
+                let mut next = Next::default();
+                next.facts[body.entry] = Some(Facts::new());
+                analysis.next = Some(next);
+                for &block in cfg.rpo.values() {
+                    analysis.visit(block);
+                }
+                let next = analysis.next.take().unwrap();
+                for &block in cfg.rpo.values() {
+                    if block != body.entry {
+                        for &(_, param) in &body.blocks[block].params {
+                            analysis.ranges[param] = next.params[param];
+                        }
+                    }
+                }
+                analysis.facts = next.facts;
+            }
+        }
+
+        let mut ranges = ValueRanges::default();
+        for (value, def) in body.values.entries() {
+            let ty = match def {
+                ValueDef::Alias(_) | ValueDef::None => continue,
+                def => def.ty(&body.type_pool),
+            };
+            ranges.ranges[value] = match ty {
+                Some(ty) if !converged && is_int(ty) => Some(ValueRange::full(ty)),
+                _ => analysis.ranges[value],
+            };
+        }
+        if converged {
+            for &block in cfg.rpo.values() {
+                ranges.facts[block] = analysis.facts[block].take().unwrap_or_default();
+            }
+        }
+        ranges
+    }
+
+    /// The range of `value` wherever it is defined, or `None` if it is
+    /// not an integer or is never computed (its block is unreachable).
+    pub fn range(&self, value: Value) -> Option<ValueRange> {
+        self.ranges[value]
+    }
+
+    /// The range of `value` in `block`, which must be dominated by its
+    /// definition.
+    pub fn range_at(&self, block: Block, value: Value) -> Option<ValueRange> {
+        narrow(self.facts[block].get(&value).copied(), self.ranges[value])
+    }
+}
+
+struct Analysis<'a> {
+    body: &'a FunctionBody,
+    ranges: PerEntity<Value, Option<ValueRange>>,
+    grown: PerEntity<Value, u32>,
+    /// The facts on entry to each block reached so far.
+    facts: PerEntity<Block, Option<Facts>>,
+    /// While narrowing, the block parameters' ranges and the facts
+    /// computed by this pass, which replace the current ones after it.
+    next: Option<Next>,
+    changed: bool,
+}
+
+#[derive(Default)]
+struct Next {
+    params: PerEntity<Value, Option<ValueRange>>,
+    facts: PerEntity<Block, Option<Facts>>,
+}
+
+/// Join `facts` into `into`, keeping those that hold on every edge.
+/// Returns whether `into` changed.
+fn join(into: &mut Option<Facts>, facts: Facts) -> bool {
+    let joined = match into {
+        None => facts,
+        Some(old) => old
+            .iter()
+            .filter_map(|(&value, &range)| {
+                facts.get(&value).map(|&other| (value, range.union(other)))
+            })
+            .collect(),
+    };
+    let changed = into.as_ref() != Some(&joined);
+    *into = Some(joined);
+    changed
+}
+
+impl<'a> Analysis<'a> {
+    fn lookup(&self, facts: &Facts, value: Value) -> Option<ValueRange> {
+        let value = self.body.resolve_alias(value);
+        narrow(facts.get(&value).copied(), self.ranges[value])
+    }
+
+    /// Grow the range of `value` to include `range`, widening it if it
+    /// keeps growing.
+    fn update(&mut self, value: Value, ty: Type, range: ValueRange) {
+        if self.next.is_some() {
+            self.ranges[value] = Some(range);
+            return;
+        }
+        let old = match self.ranges[value] {
+            Some(old) => old,
+            None => {
+                self.ranges[value] = Some(range);
+                self.changed = true;
+                return;
+            }
+        };
+        let mut new = old.union(range);
+        if new == old {
+            return;
+        }
+        self.grown[value] += 1;
+        if self.grown[value] > WIDEN_AFTER {
+            if new.min < old.min {
+                new.min = 0;
+            }
+            if new.max > old.max {
+                new.max = type_max(ty);
+            }
+        }
+        self.ranges[value] = Some(new);
+        self.changed = true;
+    }
+
+    fn visit(&mut self, block: Block) {
+        let body = self.body;
+        let facts = match &self.facts[block] {
+            Some(facts) => facts.clone(),
+            None => return,
+        };
+        for &inst in &body.blocks[block].insts {
+            match &body.values[inst] {
+                ValueDef::Operator(op, args, tys) => {
+                    let ty = match &body.type_pool[*tys] {
+                        &[ty] if is_int(ty) => ty,
+                        _ => continue,
+                    };
+                    let mut ranges = vec![];
+                    let mut ready = true;
+                    for &arg in &body.arg_pool[*args] {
+                        let arg = body.resolve_alias(arg);
+                        match body.values[arg].ty(&body.type_pool) {
+                            Some(arg_ty) if is_int(arg_ty) => match self.lookup(&facts, arg) {
+                                Some(range) => ranges.push(range),
+                                None => ready = false,
+                            },
+                            // Operands without ranges (floats being
+                            // converted, say) are not looked at.
+                            _ => ranges.push(ValueRange::full(Type::I64)),
+                        }
+                    }
+                    if ready {
+                        self.update(inst, ty, eval(op, &ranges, ty));
+                    }
+                }
+                ValueDef::PickOutput(_, _, ty) if is_int(*ty) => {
+                    self.update(inst, *ty, ValueRange::full(*ty));
+                }
+                _ => {}
+            }
+        }
+
+        let edges = self.edges(block, &facts);
+        for (target, facts) in edges {
+            for (i, &arg) in target.args.iter().enumerate() {
+                let (ty, param) = body.blocks[target.block].params[i];
+                if !is_int(ty) {
+                    continue;
+                }
+                if let Some(range) = self.lookup(&facts, arg) {
+                    match &mut self.next {
+                        Some(next) => {
+                            let old = next.params[param];
+                            next.params[param] = Some(old.map_or(range, |old| old.union(range)));
+                        }
+                        None => self.update(param, ty, range),
+                    }
+                }
+            }
+            match &mut self.next {
+                Some(next) => {
+                    join(&mut next.facts[target.block], facts);
+                }
+                None => self.changed |= join(&mut self.facts[target.block], facts),
+            }
+        }
+    }
+
+    /// The feasible edges out of `block` and the facts that hold along
+    /// each.
+    fn edges(&self, block: Block, facts: &Facts) -> Vec<(&'a BlockTarget, Facts)> {
+        let body = self.body;
+        match &body.blocks[block].terminator {
+            Terminator::Br { target } => vec![(target, facts.clone())],
+            Terminator::CondBr {
+                cond,
+                if_true,
+                if_false,
+            } => {
+                let mut edges = vec![];
+                for (target, taken) in [(if_true, true), (if_false, false)].iter().copied() {
+                    let mut facts = facts.clone();
+                    if self.assume(&mut facts, *cond, taken, 2) {
+                        edges.push((target, facts));
+                    }
+                }
+                edges
+            }
+            Terminator::Select {
+                value,
+                targets,
+                default,
+            } => {
+                let value = body.resolve_alias(*value);
+                let range = self.lookup(facts, value);
+                let mut edges = vec![];
+                for (i, target) in targets.iter().enumerate() {
+                    let i = ValueRange::constant(i as u64);
+                    if let Some(range) = range.map_or(Some(i), |range| range.intersect(i)) {
+                        let mut facts = facts.clone();
+                        facts.insert(value, range);
+                        edges.push((target, facts));
+                    }
+                }
+                let rest = ValueRange::new(targets.len() as u64, u32::MAX as u64);
+                let rest = range.map_or(Some(rest), |range| range.intersect(rest));
+                if let Some(rest) = rest {
+                    let mut facts = facts.clone();
+                    facts.insert(value, rest);
+                    edges.push((default, facts));
+                }
+                edges
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Add to `facts` what holds when `cond` is non-zero (if `taken`)
+    /// or zero, looking through up to `depth` comparisons and
+    /// `eqz`s. Returns false if that is impossible.
+    fn assume(&self, facts: &mut Facts, cond: Value, taken: bool, depth: u32) -> bool {
+        let body = self.body;
+        let cond = body.resolve_alias(cond);
+        let ty = match body.values[cond].ty(&body.type_pool) {
+            Some(ty) if is_int(ty) => ty,
+            _ => return true,
+        };
+        let known = match self.lookup(facts, cond) {
+            Some(range) => range,
+            None => ValueRange::full(ty),
+        };
+        let assumed = if taken {
+            ValueRange::new(1, type_max(ty))
+        } else {
+            ValueRange::constant(0)
+        };
+        match known.intersect(assumed) {
+            Some(range) => {
+                facts.insert(cond, range);
+            }
+            None => return false,
+        }
+        if depth == 0 {
+            return true;
+        }
+        let (op, args) = match &body.values[cond] {
+            ValueDef::Operator(op, args, _) => (op, &body.arg_pool[*args]),
+            _ => return true,
+        };
+        match op {
+            Operator::I32Eqz | Operator::I64Eqz => self.assume(facts, args[0], !taken, depth - 1),
+            _ if comparison(op).is_some() => {
+                let (a, b) = (body.resolve_alias(args[0]), body.resolve_alias(args[1]));
+                let (ra, rb) = match (self.lookup(facts, a), self.lookup(facts, b)) {
+                    (Some(ra), Some(rb)) => (ra, rb),
+                    _ => return true,
+                };
+                match refine(op, ra, rb, taken) {
+                    Some((ra, rb)) if a == b => match ra.intersect(rb) {
+                        Some(range) => {
+                            facts.insert(a, range);
+                            true
+                        }
+                        None => false,
+                    },
+                    Some((ra, rb)) => {
+                        facts.insert(a, ra);
+                        facts.insert(b, rb);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            _ => true,
+        }
+    }
+}
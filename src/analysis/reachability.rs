@@ -0,0 +1,181 @@
+//! Reachability of module-level entities from chosen roots.
+
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::ops::EntityUse;
+use crate::prelude::*;
+use alloc::collections::BTreeSet;
+use anyhow::Result;
+
+/// Where a reachability search starts.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Root {
+    /// Every export.
+    Exports,
+    /// The export with this name.
+    Export(String),
+    /// The start function, if there is one.
+    Start,
+    /// The active data and element segments (their memories, tables
+    /// and offset globals) and the functions in all element segments.
+    Segments,
+    /// The stack pointer and memory of the spill configuration, if
+    /// there is one.
+    SpillConfig,
+    Func(Func),
+    /// Every function with this name.
+    FuncName(String),
+    Global(Global),
+    Table(Table),
+    Memory(Memory),
+    /// The function in one slot of a table, without the rest of the
+    /// table.
+    TableSlot(Table, u32),
+}
+
+impl Root {
+    /// The roots of `Module::remove_unreachable`: everything the
+    /// embedder or the module itself can reach without calling in.
+    pub fn module_roots() -> Vec<Root> {
+        vec![
+            Root::Exports,
+            Root::Start,
+            Root::Segments,
+            Root::SpillConfig,
+        ]
+    }
+}
+
+/// The functions, globals, tables and memories reachable from some
+/// roots. A function reaches whatever its body uses, a global the
+/// import it is initialized from, and a table its contents.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Reachability {
+    pub funcs: BTreeSet<Func>,
+    pub globals: BTreeSet<Global>,
+    pub tables: BTreeSet<Table>,
+    pub memories: BTreeSet<Memory>,
+}
+
+impl Reachability {
+    /// Find what `roots` reach. Bodies that have not been parsed yet
+    /// are scanned without parsing them; fails if a reachable function
+    /// is already compiled, or if a root names an export, function or
+    /// table slot that doesn't exist.
+    pub fn compute(module: &Module<'_>, roots: &[Root]) -> Result<Reachability> {
+        let mut work = vec![];
+        for root in roots {
+            add_root(module, root, &mut work)?;
+        }
+
+        let mut reachable = Reachability::default();
+        while let Some(entity) = work.pop() {
+            match entity {
+                EntityUse::Func(func) => {
+                    if reachable.funcs.insert(func) {
+                        work.extend(body_uses(&module.funcs[func])?);
+                    }
+                }
+                EntityUse::Global(global) => {
+                    if reachable.globals.insert(global) {
+                        if let Some(GlobalInit::GlobalGet(import)) = module.globals[global].init {
+                            work.push(EntityUse::Global(import));
+                        }
+                    }
+                }
+                EntityUse::Table(table) => {
+                    if reachable.tables.insert(table) {
+                        if let Some(elts) = &module.tables[table].func_elements {
+                            work.extend(
+                                elts.iter()
+                                    .filter(|func| func.is_valid())
+                                    .map(|&func| EntityUse::Func(func)),
+                            );
+                        }
+                    }
+                }
+                EntityUse::Memory(memory) => {
+                    reachable.memories.insert(memory);
+                }
+            }
+        }
+        Ok(reachable)
+    }
+
+    /// Find what the module's own roots reach (see
+    /// `Root::module_roots`): what `remove_unreachable` keeps.
+    pub fn of_module(module: &Module<'_>) -> Result<Reachability> {
+        Self::compute(module, &Root::module_roots())
+    }
+}
+
+fn add_root(module: &Module<'_>, root: &Root, work: &mut Vec<EntityUse>) -> Result<()> {
+    let export_use = |kind: &ExportKind| match *kind {
+        ExportKind::Func(func) => EntityUse::Func(func),
+        ExportKind::Global(global) => EntityUse::Global(global),
+        ExportKind::Table(table) => EntityUse::Table(table),
+        ExportKind::Memory(memory) => EntityUse::Memory(memory),
+    };
+    let offset_use = |offset: SegmentOffset| match offset {
+        SegmentOffset::Global(global) => Some(EntityUse::Global(global)),
+        SegmentOffset::Const(_) => None,
+    };
+    match root {
+        Root::Exports => work.extend(module.exports.iter().map(|export| export_use(&export.kind))),
+        Root::Export(name) => match module.exports.iter().find(|export| export.name == *name) {
+            Some(export) => work.push(export_use(&export.kind)),
+            None => anyhow::bail!("No such export: {}", name),
+        },
+        Root::Start => work.extend(module.start_func.map(EntityUse::Func)),
+        Root::Segments => {
+            for segment in &module.data_segments {
+                if let DataSegmentKind::Active { memory, offset } = segment.kind {
+                    work.push(EntityUse::Memory(memory));
+                    work.extend(offset_use(offset));
+                }
+            }
+            for segment in &module.elem_segments {
+                if let ElementSegmentKind::Active { table, offset } = segment.kind {
+                    work.push(EntityUse::Table(table));
+                    work.extend(offset_use(offset));
+                }
+                work.extend(segment.items.funcs().map(EntityUse::Func));
+            }
+        }
+        Root::SpillConfig => {
+            if let Some(config) = &module.spill_config {
+                work.push(EntityUse::Global(config.stack_pointer));
+                work.push(EntityUse::Memory(config.memory));
+            }
+        }
+        Root::Func(func) => work.push(EntityUse::Func(*func)),
+        Root::FuncName(name) => {
+            let len = work.len();
+            work.extend(
+                module
+                    .funcs
+                    .entries()
+                    .filter(|(_, decl)| decl.name() == name)
+                    .map(|(func, _)| EntityUse::Func(func)),
+            );
+            if work.len() == len {
+                anyhow::bail!("No function named {}", name);
+            }
+        }
+        Root::Global(global) => work.push(EntityUse::Global(*global)),
+        Root::Table(table) => work.push(EntityUse::Table(*table)),
+        Root::Memory(memory) => work.push(EntityUse::Memory(*memory)),
+        Root::TableSlot(table, slot) => {
+            let func = module
+                .tables
+                .get(*table)
+                .and_then(|data| data.func_elements.as_ref())
+                .and_then(|elts| elts.get(*slot as usize));
+            match func {
+                Some(func) if func.is_valid() => work.push(EntityUse::Func(*func)),
+                _ => anyhow::bail!("No function in slot {} of {}", slot, table),
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,347 @@
+//! Shadow-stack frames and whether their addresses escape.
+
+use crate::collections::HashMap;
+use crate::ir::*;
+use crate::passes::memtrace::access;
+use crate::prelude::*;
+use crate::Operator;
+
+/// The global holding the shadow-stack pointer, which LLVM names
+/// `__stack_pointer`: found through the name section, or by the name
+/// it is imported under.
+pub fn stack_pointer(module: &Module<'_>) -> Option<Global> {
+    module
+        .names
+        .globals
+        .iter()
+        .find(|(_, name)| *name == "__stack_pointer")
+        .map(|(&global, _)| global)
+        .or_else(|| {
+            module.imports.iter().find_map(|import| match import.kind {
+                ImportKind::Global(global) if import.name == "__stack_pointer" => Some(global),
+                _ => None,
+            })
+        })
+        .filter(|&global| {
+            module
+                .globals
+                .get(global)
+                .is_some_and(|data| data.ty == Type::I32 && data.mutable)
+        })
+}
+
+/// A load or store through an address in the frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameAccess {
+    pub inst: Value,
+    /// The offset of the first accessed byte from the frame base.
+    pub offset: u32,
+    pub size: u32,
+    pub is_store: bool,
+    /// The type of the loaded or stored value, if the access covers
+    /// all of it (it is not a narrow or extending access).
+    pub ty: Option<Type>,
+}
+
+/// Accesses to the same bytes of the frame, all loads and stores of a
+/// whole value of one type, that no other access overlaps. Such a
+/// slot can be kept in an SSA value instead of memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackSlot {
+    pub offset: u32,
+    pub ty: Type,
+    /// The loads and stores of the slot, in block order.
+    pub accesses: Vec<Value>,
+}
+
+/// A user of a value in a body.
+#[derive(Clone, Copy, Debug)]
+enum User {
+    Inst(Value),
+    Terminator(Block),
+}
+
+/// The frame a function allocates on the shadow stack with the idiom
+/// LLVM emits: read the stack pointer in the entry block, subtract
+/// the frame size, and (unless the function calls nothing) write the
+/// result back, restoring the old value before returning.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackFrame {
+    /// The stack pointer read on entry.
+    pub entry_sp: Value,
+    /// The frame base: `entry_sp` minus the size.
+    pub base: Value,
+    pub size: u32,
+    /// The accesses through addresses in the frame, in block order.
+    pub accesses: Vec<FrameAccess>,
+    /// A use that lets a frame address escape, if there is one:
+    /// anything other than the address of a load or store within the
+    /// frame, adding or subtracting a constant, or writing the base or
+    /// `entry_sp` back to the stack pointer. Reading the stack pointer
+    /// again also counts, since the value may point into the frame.
+    /// An escaped address may reach any part of the frame.
+    pub escape: Option<Value>,
+}
+
+impl StackFrame {
+    /// Find the frame of `body`, given the stack-pointer global, or
+    /// `None` if it does not allocate one in the expected way.
+    pub fn compute(body: &FunctionBody, sp: Global) -> Option<StackFrame> {
+        let reads_sp = |inst: Value| {
+            matches!(
+                body.values[inst],
+                ValueDef::Operator(Operator::GlobalGet { global_index }, ..) if global_index == sp
+            )
+        };
+        let mut sp_reads = body
+            .blocks
+            .values()
+            .flat_map(|block| block.insts.iter().copied())
+            .filter(|&inst| reads_sp(inst));
+        let entry_sp = body.blocks[body.entry]
+            .insts
+            .iter()
+            .copied()
+            .find(|&inst| reads_sp(inst))?;
+
+        let mut users: HashMap<Value, Vec<User>> = HashMap::new();
+        for (block, def) in body.blocks.entries() {
+            for &inst in &def.insts {
+                match &body.values[inst] {
+                    ValueDef::Alias(_) | ValueDef::None => {}
+                    def => def.visit_uses(&body.arg_pool, |arg| {
+                        users
+                            .entry(body.resolve_alias(arg))
+                            .or_default()
+                            .push(User::Inst(inst))
+                    }),
+                }
+            }
+            def.terminator.visit_uses(|arg| {
+                users
+                    .entry(body.resolve_alias(arg))
+                    .or_default()
+                    .push(User::Terminator(block))
+            });
+        }
+        let users = |value: Value| users.get(&value).map_or(&[][..], |users| &users[..]);
+        let args = |inst: Value| match &body.values[inst] {
+            ValueDef::Operator(op, args, _) => Some((
+                op,
+                body.arg_pool[*args]
+                    .iter()
+                    .map(|&arg| body.resolve_alias(arg))
+                    .collect::<Vec<_>>(),
+            )),
+            _ => None,
+        };
+        let constant = |value: Value| match body.values[body.resolve_alias(value)] {
+            ValueDef::Operator(Operator::I32Const { value }, ..) => Some(value as i32 as i64),
+            _ => None,
+        };
+        // The offset from `value` that an `i32.add` or `i32.sub` of a
+        // constant computes.
+        let offset_from = |inst: Value, value: Value| {
+            let (op, args) = args(inst)?;
+            match (op, &args[..]) {
+                (Operator::I32Add, &[a, b]) if a == value && b != value => constant(b),
+                (Operator::I32Add, &[a, b]) if b == value && a != value => constant(a),
+                (Operator::I32Sub, &[a, b]) if a == value && b != value => Some(-constant(b)?),
+                _ => None,
+            }
+        };
+
+        let (base, size) = users(entry_sp).iter().find_map(|&user| match user {
+            User::Inst(inst) => match offset_from(inst, entry_sp) {
+                Some(offset) if offset < 0 && offset >= -(u32::MAX as i64) => {
+                    Some((inst, (-offset) as u32))
+                }
+                _ => None,
+            },
+            User::Terminator(_) => None,
+        })?;
+
+        let mut frame = StackFrame {
+            entry_sp,
+            base,
+            size,
+            accesses: vec![],
+            escape: sp_reads.find(|&inst| inst != entry_sp),
+        };
+        let restores_sp = |inst: Value| {
+            matches!(
+                body.values[inst],
+                ValueDef::Operator(Operator::GlobalSet { global_index }, ..) if global_index == sp
+            )
+        };
+        for &user in users(entry_sp) {
+            match user {
+                User::Inst(inst) if inst == base || restores_sp(inst) => {}
+                User::Inst(inst) => {
+                    frame.escape.get_or_insert(inst);
+                }
+                User::Terminator(_) => {
+                    frame.escape.get_or_insert(entry_sp);
+                }
+            }
+        }
+
+        // The frame addresses found so far and their offsets from the
+        // base. A block parameter is assumed to be one once some
+        // predecessor passes it one, and checked below.
+        let mut known: HashMap<Value, i64> = HashMap::new();
+        let mut memory = None;
+        let mut work = vec![(base, 0i64)];
+        while let Some((value, offset)) = work.pop() {
+            match known.insert(value, offset) {
+                Some(known) if known == offset => continue,
+                Some(_) => {
+                    frame.escape.get_or_insert(value);
+                    continue;
+                }
+                None => {}
+            }
+            for &user in users(value) {
+                let inst = match user {
+                    User::Inst(inst) => inst,
+                    User::Terminator(block) => {
+                        let terminator = &body.blocks[block].terminator;
+                        let mut other_uses = 0;
+                        terminator.visit_uses(|arg| {
+                            if body.resolve_alias(arg) == value {
+                                other_uses += 1;
+                            }
+                        });
+                        terminator.visit_targets(|target| {
+                            for (i, &arg) in target.args.iter().enumerate() {
+                                if body.resolve_alias(arg) == value {
+                                    let param = body.blocks[target.block].params[i].1;
+                                    work.push((param, offset));
+                                    other_uses -= 1;
+                                }
+                            }
+                        });
+                        if other_uses > 0 {
+                            frame.escape.get_or_insert(value);
+                        }
+                        continue;
+                    }
+                };
+                if let Some(delta) = offset_from(inst, value) {
+                    work.push((inst, offset + delta));
+                    continue;
+                }
+                let (op, args) = match args(inst) {
+                    Some(args) => args,
+                    None => {
+                        frame.escape.get_or_insert(inst);
+                        continue;
+                    }
+                };
+                if let Some((memarg, access_size, is_store)) = access(op) {
+                    let start = offset + memarg.offset as i64;
+                    let in_frame = start >= 0 && start + access_size as i64 <= size as i64;
+                    let same_memory = *memory.get_or_insert(memarg.memory) == memarg.memory;
+                    let address_only =
+                        args[0] == value && args[1..].iter().all(|&arg| arg != value);
+                    if in_frame && same_memory && address_only {
+                        frame.accesses.push(FrameAccess {
+                            inst,
+                            offset: start as u32,
+                            size: access_size,
+                            is_store,
+                            ty: whole_value_type(op),
+                        });
+                        continue;
+                    }
+                } else if restores_sp(inst) && (offset == 0 || offset == size as i64) {
+                    continue;
+                }
+                frame.escape.get_or_insert(inst);
+            }
+        }
+        // A parameter that some predecessor passes anything other than
+        // the same frame address may point elsewhere, so accesses
+        // through it may reach the frame or not.
+        for block in body.blocks.values() {
+            block.terminator.visit_targets(|target| {
+                for (i, &arg) in target.args.iter().enumerate() {
+                    let param = body.blocks[target.block].params[i].1;
+                    if let Some(&offset) = known.get(&param) {
+                        if known.get(&body.resolve_alias(arg)) != Some(&offset) {
+                            frame.escape.get_or_insert(param);
+                        }
+                    }
+                }
+            });
+        }
+        let order = body
+            .blocks
+            .values()
+            .flat_map(|block| block.insts.iter().copied())
+            .enumerate()
+            .map(|(i, inst)| (inst, i))
+            .collect::<HashMap<_, _>>();
+        frame.accesses.sort_by_key(|access| order[&access.inst]);
+        Some(frame)
+    }
+
+    /// The slots that can be promoted to SSA values, by offset; none
+    /// if an address escapes.
+    pub fn promotable_slots(&self) -> Vec<StackSlot> {
+        if self.escape.is_some() {
+            return vec![];
+        }
+        let mut slots: Vec<StackSlot> = vec![];
+        let mut offsets = self
+            .accesses
+            .iter()
+            .map(|access| access.offset)
+            .collect::<Vec<_>>();
+        offsets.sort();
+        offsets.dedup();
+        for offset in offsets {
+            let at = self
+                .accesses
+                .iter()
+                .filter(|access| access.offset == offset)
+                .collect::<Vec<_>>();
+            let (size, ty) = (at[0].size, at[0].ty);
+            let ty = match ty {
+                Some(ty)
+                    if at
+                        .iter()
+                        .all(|access| access.size == size && access.ty == Some(ty)) =>
+                {
+                    ty
+                }
+                _ => continue,
+            };
+            let overlapped = self.accesses.iter().any(|access| {
+                access.offset != offset
+                    && access.offset < offset + size
+                    && offset < access.offset + access.size
+            });
+            if !overlapped {
+                slots.push(StackSlot {
+                    offset,
+                    ty,
+                    accesses: at.iter().map(|access| access.inst).collect(),
+                });
+            }
+        }
+        slots
+    }
+}
+
+/// The type of the value loaded or stored by `op`, if it is a full
+/// access of that type.
+fn whole_value_type(op: &Operator) -> Option<Type> {
+    match op {
+        Operator::I32Load { .. } | Operator::I32Store { .. } => Some(Type::I32),
+        Operator::I64Load { .. } | Operator::I64Store { .. } => Some(Type::I64),
+        Operator::F32Load { .. } | Operator::F32Store { .. } => Some(Type::F32),
+        Operator::F64Load { .. } | Operator::F64Store { .. } => Some(Type::F64),
+        _ => None,
+    }
+}
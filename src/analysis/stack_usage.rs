@@ -0,0 +1,206 @@
+//! Static bounds on stack usage.
+
+use super::body;
+use super::call_graph::CallGraph;
+use super::stack_frame::{stack_pointer, StackFrame};
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::*;
+use anyhow::Result;
+
+/// What one activation of a function puts on the stacks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FrameUsage {
+    /// Parameters and locals, in values.
+    pub locals: u32,
+    /// The greatest height of the Wasm operand stack, in values.
+    pub max_operands: u32,
+    /// The bytes allocated on the shadow stack (see `StackFrame`).
+    pub shadow_frame: u32,
+}
+
+/// The worst case over the call chains starting at a function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bound {
+    /// The summed usage of the frames on `chain`, or `None` if it is
+    /// unbounded: the chain ends in a recursive function, or in one
+    /// making an indirect call that may leave the module.
+    pub total: Option<u64>,
+    /// The functions on the worst chain, from the outermost call in.
+    pub chain: Vec<Func>,
+}
+
+/// The bounds for one exported function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportUsage<'m> {
+    pub name: &'m str,
+    pub func: Func,
+    /// Values on the Wasm stack: locals plus operands, per frame.
+    pub value_stack: Bound,
+    /// Bytes on the shadow stack.
+    pub shadow_stack: Bound,
+}
+
+/// The worst total from a function, and the callee it continues to.
+#[derive(Clone, Copy, Debug, Default)]
+struct Worst {
+    total: Option<u64>,
+    next: Option<Func>,
+}
+
+/// The stack a call to each function may use in the worst case, for
+/// embedders that must provision stacks ahead of time.
+///
+/// The operand stack is measured on the Wasm the module compiles to,
+/// so it bounds what the output of `to_wasm_bytes` needs. Imports are
+/// assumed to use no stack of their own.
+#[derive(Clone, Debug, Default)]
+pub struct StackUsage {
+    frames: PerEntity<Func, FrameUsage>,
+    value_stack: PerEntity<Func, Worst>,
+    shadow_stack: PerEntity<Func, Worst>,
+}
+
+impl StackUsage {
+    /// Bound the stack usage of every function, bottom-up over
+    /// `graph`'s components. Bodies that have not been parsed yet are
+    /// parsed (without changing the module); already-compiled bodies
+    /// cannot be analyzed.
+    pub fn compute(module: &Module<'_>, graph: &CallGraph) -> Result<StackUsage> {
+        let mut usage = StackUsage::default();
+        usage.measure_operands(&module.to_wasm_bytes()?)?;
+        if let Some(sp) = stack_pointer(module) {
+            for func in module.funcs.iter() {
+                if let Some(body) = body(module, func)? {
+                    if let Some(frame) = StackFrame::compute(&body, sp) {
+                        usage.frames[func].shadow_frame = frame.size;
+                    }
+                }
+            }
+        }
+
+        for scc in graph.sccs() {
+            let recursive = graph.is_recursive(scc[0]);
+            for &func in scc {
+                let frame = usage.frames[func];
+                if recursive {
+                    usage.value_stack[func] = Worst::default();
+                    usage.shadow_stack[func] = Worst::default();
+                    continue;
+                }
+                let external = graph.sites(func).iter().any(|site| site.external);
+                let callees = graph.callees(func);
+                let values = frame.locals as u64 + frame.max_operands as u64;
+                usage.value_stack[func] = worst(&usage.value_stack, values, external, callees);
+                let bytes = frame.shadow_frame as u64;
+                usage.shadow_stack[func] = worst(&usage.shadow_stack, bytes, external, callees);
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Find the greatest operand stack height of each function body in
+    /// the compiled module `bytes`, and its number of locals.
+    fn measure_operands(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut validator = wasmparser::Validator::new_with_features(wasmparser::WasmFeatures {
+            threads: true,
+            ..Default::default()
+        });
+        for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+            let payload = payload?;
+            let (func, body) = match validator.payload(&payload)? {
+                wasmparser::ValidPayload::Func(func, body) => (func, body),
+                _ => continue,
+            };
+            let mut func = func.into_validator(Default::default());
+            let mut locals = body.get_locals_reader()?;
+            for _ in 0..locals.get_count() {
+                let offset = locals.original_position();
+                let (count, ty) = locals.read()?;
+                func.define_locals(offset, count, ty)?;
+            }
+            let mut max_operands = 0;
+            let mut ops = body.get_operators_reader()?;
+            while !ops.eof() {
+                let offset = ops.original_position();
+                func.op(offset, &ops.read()?)?;
+                max_operands = std::cmp::max(max_operands, func.operand_stack_height());
+            }
+            func.finish(ops.original_position())?;
+            let frame = &mut self.frames[Func::new(func.index() as usize)];
+            frame.locals = func.len_locals();
+            frame.max_operands = max_operands;
+        }
+        Ok(())
+    }
+
+    /// The stack one activation of `func` uses.
+    pub fn frame(&self, func: Func) -> FrameUsage {
+        self.frames[func]
+    }
+
+    /// The values a call to `func` may put on the Wasm stack, counting
+    /// the locals and operands of every frame.
+    pub fn value_stack(&self, func: Func) -> Bound {
+        chain(&self.value_stack, func)
+    }
+
+    /// The bytes a call to `func` may allocate on the shadow stack.
+    pub fn shadow_stack(&self, func: Func) -> Bound {
+        chain(&self.shadow_stack, func)
+    }
+
+    /// The bounds of each exported function, in export order.
+    pub fn exports<'m>(&self, module: &'m Module<'_>) -> Vec<ExportUsage<'m>> {
+        module
+            .exports
+            .iter()
+            .filter_map(|export| match export.kind {
+                ExportKind::Func(func) => Some(ExportUsage {
+                    name: &export.name[..],
+                    func,
+                    value_stack: self.value_stack(func),
+                    shadow_stack: self.shadow_stack(func),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// The worst total of a non-recursive function using `own` itself and
+/// calling `callees`, preferring an unbounded callee over any bound.
+fn worst(worsts: &PerEntity<Func, Worst>, own: u64, external: bool, callees: &[Func]) -> Worst {
+    if external {
+        return Worst::default();
+    }
+    let mut result = Worst {
+        total: Some(own),
+        next: None,
+    };
+    for &callee in callees {
+        let total = worsts[callee].total.map(|total| own + total);
+        let worse = match (total, result.total) {
+            (None, _) => result.total.is_some(),
+            (Some(total), Some(best)) => total > best,
+            (Some(_), None) => false,
+        };
+        if worse {
+            result = Worst {
+                total,
+                next: Some(callee),
+            };
+        }
+    }
+    result
+}
+
+fn chain(worsts: &PerEntity<Func, Worst>, func: Func) -> Bound {
+    let mut chain = vec![func];
+    while let Some(next) = worsts[*chain.last().unwrap()].next {
+        chain.push(next);
+    }
+    Bound {
+        total: worsts[func].total,
+        chain,
+    }
+}
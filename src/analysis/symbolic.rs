@@ -0,0 +1,793 @@
+//! Bounded symbolic execution of a function body.
+
+use super::ranges::{comparison, eval, is_int, refine, type_max, ValueRange};
+use crate::collections::{HashMap, HashSet};
+use crate::declare_entity;
+use crate::entity::{EntityVec, PerEntity};
+use crate::interp::diff::Rng;
+use crate::interp::{const_eval, ConstVal};
+use crate::ir::*;
+use crate::op_traits::SideEffect;
+use crate::passes::memtrace::access;
+use crate::prelude::*;
+use crate::Operator;
+use alloc::sync::Arc;
+
+declare_entity!(Term, "term");
+
+/// A bitvector (or float) term: what an SSA value is on a path, in
+/// terms of the function's arguments.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TermDef {
+    Const(ConstVal),
+    /// The argument for the entry block's parameter with this index.
+    Param(Type, usize),
+    /// A value that is not modeled, defined by this value: a load from
+    /// memory not stored to on the path, a global, a call result, etc.
+    /// Each execution of the value gives a new one.
+    Unknown(Type, Value),
+    /// The pure operator applied to terms.
+    Op(Operator, Vec<Term>, Type),
+}
+
+impl TermDef {
+    pub fn ty(&self) -> Type {
+        match self {
+            TermDef::Const(ConstVal::I64(_)) => Type::I64,
+            TermDef::Const(ConstVal::F32(_)) => Type::F32,
+            TermDef::Const(ConstVal::F64(_)) => Type::F64,
+            TermDef::Const(_) => Type::I32,
+            &TermDef::Param(ty, _) | &TermDef::Unknown(ty, _) | &TermDef::Op(_, _, ty) => ty,
+        }
+    }
+}
+
+/// The bounds of a `SymbolicExecution`.
+#[derive(Clone, Copy, Debug)]
+pub struct SymbolicOptions {
+    /// The most blocks a path may run before it is cut off.
+    pub max_blocks: usize,
+    /// The most paths to explore.
+    pub max_paths: usize,
+    /// How many assignments to try, over all paths to a block, when
+    /// looking for arguments that satisfy a path's conditions.
+    pub tries: usize,
+    /// The seed those assignments are generated from.
+    pub seed: u64,
+}
+
+impl Default for SymbolicOptions {
+    fn default() -> Self {
+        SymbolicOptions {
+            max_blocks: 256,
+            max_paths: 1024,
+            tries: 1000,
+            seed: 0x5eed,
+        }
+    }
+}
+
+/// Whether a block can be reached.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockReach {
+    /// No path reaches the block: every path was explored to its end,
+    /// and the conditions of those that reach it contradict each other.
+    Unreachable,
+    /// Calling the function with `args` reaches the block. Unless
+    /// `exact`, the path also depends on unknown values, which are
+    /// assumed to take values that may not be possible.
+    Reachable { args: Vec<ConstVal>, exact: bool },
+    /// Neither could be shown within the bounds.
+    Unknown,
+}
+
+/// A branch condition on a path: `term` is non-zero if `taken`. The
+/// conditions of all paths form a tree, through `parent`.
+#[derive(Clone, Debug)]
+struct Condition {
+    term: Term,
+    taken: bool,
+    parent: Option<usize>,
+    /// The ranges implied by this condition and those before it.
+    facts: Arc<Facts>,
+}
+
+type Facts = HashMap<Term, ValueRange>;
+
+/// A path being explored.
+#[derive(Clone, Debug)]
+struct State {
+    block: Block,
+    blocks: usize,
+    values: HashMap<Value, Vec<Term>>,
+    /// The stores on the path since the last call or other write of
+    /// unknown memory: address, operator and value.
+    stores: Vec<(Term, Operator, Term)>,
+    /// The last condition on the path.
+    conditions: Option<usize>,
+    /// The ranges the conditions imply.
+    facts: Arc<Facts>,
+}
+
+/// How many operators deep the ranges of terms are computed.
+const RANGE_DEPTH: u32 = 8;
+/// How many comparisons and `eqz`s deep a condition is looked through.
+const ASSUME_DEPTH: u32 = 4;
+
+/// The paths through a body, explored up to a bound: every value is a
+/// term over the arguments, and every branch adds a condition to the
+/// path. Paths whose conditions contradict each other, as shown by the
+/// value ranges they imply, are not followed.
+///
+/// Memory is modeled only by forwarding stores on the path to loads
+/// of the same address, and the results of calls, loads and other
+/// operators that read state are unknown, so results are about every
+/// behavior of memory and callees: an unreachable block cannot be
+/// reached whatever they do. Traps are not modeled: a path continues
+/// past an operator that may trap.
+#[derive(Clone, Debug)]
+pub struct SymbolicExecution {
+    terms: EntityVec<Term, TermDef>,
+    interned: HashMap<TermDef, Term>,
+    conditions: Vec<Condition>,
+    /// The last condition of each path on entry to each block.
+    visits: PerEntity<Block, Vec<Option<usize>>>,
+    params: Vec<Type>,
+    paths: usize,
+    complete: bool,
+    options: SymbolicOptions,
+}
+
+fn truthy(value: ConstVal) -> Option<bool> {
+    match value {
+        ConstVal::I32(x) => Some(x != 0),
+        ConstVal::I64(x) => Some(x != 0),
+        _ => None,
+    }
+}
+
+fn zero(ty: Type) -> ConstVal {
+    match ty {
+        Type::I32 => ConstVal::I32(0),
+        Type::I64 => ConstVal::I64(0),
+        Type::F32 => ConstVal::F32(0),
+        Type::F64 => ConstVal::F64(0),
+        _ => ConstVal::None,
+    }
+}
+
+fn bits(value: ConstVal) -> Option<u64> {
+    match value {
+        ConstVal::I32(x) => Some(x as u64),
+        ConstVal::I64(x) => Some(x),
+        _ => None,
+    }
+}
+
+fn int(ty: Type, bits: u64) -> ConstVal {
+    match ty {
+        Type::I32 => ConstVal::I32(bits as u32),
+        _ => ConstVal::I64(bits),
+    }
+}
+
+/// The load that reads back what `store` writes, whole.
+fn reads_back(store: &Operator, load: &Operator) -> bool {
+    matches!(
+        (store, load),
+        (Operator::I32Store { .. }, Operator::I32Load { .. })
+            | (Operator::I64Store { .. }, Operator::I64Load { .. })
+            | (Operator::F32Store { .. }, Operator::F32Load { .. })
+            | (Operator::F64Store { .. }, Operator::F64Load { .. })
+    )
+}
+
+impl SymbolicExecution {
+    /// Explore the paths through `body` from its entry, within the
+    /// bounds of `options`.
+    pub fn explore(body: &FunctionBody, options: &SymbolicOptions) -> SymbolicExecution {
+        let mut exec = SymbolicExecution {
+            terms: EntityVec::default(),
+            interned: HashMap::new(),
+            conditions: vec![],
+            visits: PerEntity::default(),
+            params: vec![],
+            paths: 0,
+            complete: true,
+            options: *options,
+        };
+        let mut state = State {
+            block: body.entry,
+            blocks: 0,
+            values: HashMap::new(),
+            stores: vec![],
+            conditions: None,
+            facts: Arc::default(),
+        };
+        for (i, &(ty, param)) in body.blocks[body.entry].params.iter().enumerate() {
+            exec.params.push(ty);
+            let term = exec.intern(TermDef::Param(ty, i));
+            state.values.insert(param, vec![term]);
+        }
+        let mut work = vec![state];
+        while let Some(state) = work.pop() {
+            if exec.paths == options.max_paths {
+                exec.complete = false;
+                break;
+            }
+            exec.paths += 1;
+            exec.run(body, state, &mut work);
+        }
+        exec
+    }
+
+    pub fn term(&self, term: Term) -> &TermDef {
+        &self.terms[term]
+    }
+
+    /// The number of paths explored.
+    pub fn paths(&self) -> usize {
+        self.paths
+    }
+
+    /// Whether every path was explored to its end.
+    pub fn complete(&self) -> bool {
+        self.complete
+    }
+
+    /// The branch conditions of each path that reaches `block`, up to
+    /// its entry: terms and whether they are non-zero.
+    pub fn conditions(&self, block: Block) -> Vec<Vec<(Term, bool)>> {
+        self.visits[block]
+            .iter()
+            .map(|&head| self.path(head))
+            .collect()
+    }
+
+    /// Whether `block` can be reached, with arguments that reach it if
+    /// they can be found. `Unreachable` can be used to prove that a
+    /// failing bounds check never traps, for example.
+    pub fn reach(&self, block: Block) -> BlockReach {
+        let mut heads = self.visits[block].clone();
+        heads.sort_unstable();
+        heads.dedup();
+        // The tries are shared by the paths.
+        let tries = (self.options.tries / heads.len().max(1)).max(1);
+        let none = Facts::default();
+        for &head in &heads {
+            let facts = match head {
+                Some(index) => &self.conditions[index].facts,
+                None => &none,
+            };
+            if let Some((args, exact)) = self.solve(head, facts, tries) {
+                return BlockReach::Reachable { args, exact };
+            }
+        }
+        if heads.is_empty() && self.complete {
+            BlockReach::Unreachable
+        } else {
+            BlockReach::Unknown
+        }
+    }
+
+    fn intern(&mut self, def: TermDef) -> Term {
+        if let Some(&term) = self.interned.get(&def) {
+            return term;
+        }
+        let term = self.terms.push(def.clone());
+        self.interned.insert(def, term);
+        term
+    }
+
+    fn unknown(&mut self, ty: Type, value: Value) -> Term {
+        self.terms.push(TermDef::Unknown(ty, value))
+    }
+
+    fn op(&mut self, op: Operator, args: Vec<Term>, ty: Type) -> Term {
+        let consts = args
+            .iter()
+            .map(|&arg| match self.terms[arg] {
+                TermDef::Const(value) => Some(value),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>();
+        if let Some(consts) = consts {
+            if let Some(value) = const_eval(&op, &consts[..], None) {
+                return self.intern(TermDef::Const(value));
+            }
+        }
+        // Operators of a term with itself.
+        if args.len() == 2 && args[0] == args[1] {
+            use Operator::*;
+            let value = match op {
+                I32Eq | I32LeU | I32LeS | I32GeU | I32GeS | I64Eq | I64LeU | I64LeS | I64GeU
+                | I64GeS => Some(ConstVal::I32(1)),
+                I32Ne | I32LtU | I32LtS | I32GtU | I32GtS | I64Ne | I64LtU | I64LtS | I64GtU
+                | I64GtS => Some(ConstVal::I32(0)),
+                I32Sub | I32Xor => Some(ConstVal::I32(0)),
+                I64Sub | I64Xor => Some(ConstVal::I64(0)),
+                I32And | I32Or | I64And | I64Or => return args[0],
+                _ => None,
+            };
+            if let Some(value) = value {
+                return self.intern(TermDef::Const(value));
+            }
+        }
+        self.intern(TermDef::Op(op, args, ty))
+    }
+
+    fn value(&self, body: &FunctionBody, state: &State, value: Value) -> Term {
+        state.values[&body.resolve_alias(value)][0]
+    }
+
+    /// Follow a path from `state` until it ends, pushing the other
+    /// sides of its branches onto `work`.
+    fn run(&mut self, body: &FunctionBody, mut state: State, work: &mut Vec<State>) {
+        loop {
+            if state.blocks == self.options.max_blocks {
+                self.complete = false;
+                return;
+            }
+            state.blocks += 1;
+            self.visits[state.block].push(state.conditions);
+            for &inst in &body.blocks[state.block].insts {
+                self.step(body, &mut state, inst);
+            }
+
+            let targets = match &body.blocks[state.block].terminator {
+                Terminator::Br { target } => vec![(target, None)],
+                &Terminator::CondBr {
+                    cond,
+                    ref if_true,
+                    ref if_false,
+                } => {
+                    let cond = self.value(body, &state, cond);
+                    vec![
+                        (if_true, Some((cond, true))),
+                        (if_false, Some((cond, false))),
+                    ]
+                }
+                &Terminator::Select {
+                    value,
+                    ref targets,
+                    ref default,
+                } => {
+                    let value = self.value(body, &state, value);
+                    let mut cases = vec![];
+                    for (i, target) in targets.iter().enumerate() {
+                        let index = self.intern(TermDef::Const(ConstVal::I32(i as u32)));
+                        let cond = self.op(Operator::I32Eq, vec![value, index], Type::I32);
+                        cases.push((target, Some((cond, true))));
+                    }
+                    let len = self.intern(TermDef::Const(ConstVal::I32(targets.len() as u32)));
+                    let cond = self.op(Operator::I32GeU, vec![value, len], Type::I32);
+                    cases.push((default, Some((cond, true))));
+                    cases
+                }
+                Terminator::Return { .. } | Terminator::Unreachable | Terminator::None => return,
+            };
+
+            // The targets whose conditions may hold.
+            let mut next = vec![];
+            for (target, cond) in targets {
+                let (term, taken) = match cond {
+                    Some((term, taken)) => (term, taken),
+                    None => {
+                        next.push((target, state.conditions, None));
+                        continue;
+                    }
+                };
+                match self.terms[term] {
+                    TermDef::Const(value) if truthy(value) == Some(taken) => {
+                        next.push((target, state.conditions, None));
+                        continue;
+                    }
+                    TermDef::Const(_) => continue,
+                    _ => {}
+                }
+                let mut facts = (*state.facts).clone();
+                if !self.assume(&mut facts, term, taken, ASSUME_DEPTH) {
+                    continue;
+                }
+                let facts = Arc::new(facts);
+                self.conditions.push(Condition {
+                    term,
+                    taken,
+                    parent: state.conditions,
+                    facts: facts.clone(),
+                });
+                next.push((target, Some(self.conditions.len() - 1), Some(facts)));
+            }
+            let (target, conditions, facts) = match next.pop() {
+                Some(last) => last,
+                None => return,
+            };
+            for (target, conditions, facts) in next {
+                let mut fork = state.clone();
+                fork.conditions = conditions;
+                if let Some(facts) = facts {
+                    fork.facts = facts;
+                }
+                self.enter(body, &mut fork, target);
+                work.push(fork);
+            }
+            state.conditions = conditions;
+            if let Some(facts) = facts {
+                state.facts = facts;
+            }
+            self.enter(body, &mut state, target);
+        }
+    }
+
+    fn enter(&mut self, body: &FunctionBody, state: &mut State, target: &BlockTarget) {
+        let args = target
+            .args
+            .iter()
+            .map(|&arg| self.value(body, state, arg))
+            .collect::<Vec<_>>();
+        for (arg, &(_, param)) in args
+            .into_iter()
+            .zip(body.blocks[target.block].params.iter())
+        {
+            state.values.insert(param, vec![arg]);
+        }
+        state.block = target.block;
+    }
+
+    fn step(&mut self, body: &FunctionBody, state: &mut State, inst: Value) {
+        let results = match &body.values[inst] {
+            &ValueDef::PickOutput(value, index, _) => {
+                let value = body.resolve_alias(value);
+                vec![state.values[&value][index as usize]]
+            }
+            ValueDef::Operator(op, args, tys) => {
+                let args = body.arg_pool[*args]
+                    .iter()
+                    .map(|&arg| self.value(body, state, arg))
+                    .collect::<Vec<_>>();
+                let tys = &body.type_pool[*tys];
+                self.operator(state, inst, op, args, tys)
+            }
+            _ => return,
+        };
+        state.values.insert(inst, results);
+    }
+
+    fn operator(
+        &mut self,
+        state: &mut State,
+        inst: Value,
+        op: &Operator,
+        args: Vec<Term>,
+        tys: &[Type],
+    ) -> Vec<Term> {
+        if let Some((_, _, is_store)) = access(op) {
+            if is_store {
+                state.stores.push((args[0], *op, args[1]));
+                return vec![];
+            }
+            if let Some(value) = self.forward(state, op, args[0]) {
+                return vec![value];
+            }
+        } else if op
+            .effects()
+            .iter()
+            .any(|effect| matches!(effect, SideEffect::WriteMem | SideEffect::All))
+        {
+            state.stores.clear();
+        } else if tys.len() == 1
+            && op
+                .effects()
+                .iter()
+                .all(|effect| matches!(effect, SideEffect::Trap))
+        {
+            return vec![self.op(*op, args, tys[0])];
+        }
+        tys.iter().map(|&ty| self.unknown(ty, inst)).collect()
+    }
+
+    /// The value stored on the path that the load `op` of `addr` reads,
+    /// if no store since may have overwritten it.
+    fn forward(&self, state: &State, op: &Operator, addr: Term) -> Option<Term> {
+        let (memory, size, _) = access(op)?;
+        let start = |addr: Term, offset: u32| match self.terms[addr] {
+            TermDef::Const(value) => bits(value).map(|bits| bits + offset as u64),
+            _ => None,
+        };
+        for &(store_addr, store_op, value) in state.stores.iter().rev() {
+            let (store_memory, store_size, _) = access(&store_op).unwrap();
+            if store_memory.memory != memory.memory {
+                continue;
+            }
+            if store_addr == addr && store_memory.offset == memory.offset {
+                return reads_back(&store_op, op).then_some(value);
+            }
+            match (
+                start(addr, memory.offset),
+                start(store_addr, store_memory.offset),
+            ) {
+                (Some(a), Some(b)) if a + size as u64 <= b || b + store_size as u64 <= a => {}
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    /// The conditions of the path ending at `head`, in order.
+    fn path(&self, mut head: Option<usize>) -> Vec<(Term, bool)> {
+        let mut path = vec![];
+        while let Some(index) = head {
+            let cond = &self.conditions[index];
+            path.push((cond.term, cond.taken));
+            head = cond.parent;
+        }
+        path.reverse();
+        path
+    }
+
+    fn range(&self, facts: &Facts, term: Term, depth: u32) -> ValueRange {
+        let ty = self.terms[term].ty();
+        let def = match &self.terms[term] {
+            &TermDef::Const(value) => ValueRange::constant(bits(value).unwrap()),
+            TermDef::Op(op, args, _) if depth > 0 => {
+                let args = args
+                    .iter()
+                    .map(|&arg| match self.terms[arg].ty() {
+                        ty if is_int(ty) => self.range(facts, arg, depth - 1),
+                        _ => ValueRange::full(Type::I64),
+                    })
+                    .collect::<Vec<_>>();
+                eval(op, &args[..], ty)
+            }
+            _ => ValueRange::full(ty),
+        };
+        match facts.get(&term) {
+            Some(fact) => fact.intersect(def).unwrap_or(*fact),
+            None => def,
+        }
+    }
+
+    /// Record in `facts` that `cond` is non-zero if `taken` and zero
+    /// otherwise, looking through up to `depth` comparisons and `eqz`s.
+    /// Returns false if that is impossible.
+    fn assume(&self, facts: &mut Facts, cond: Term, taken: bool, depth: u32) -> bool {
+        let ty = self.terms[cond].ty();
+        if !is_int(ty) {
+            return true;
+        }
+        let assumed = if taken {
+            ValueRange::new(1, type_max(ty))
+        } else {
+            ValueRange::constant(0)
+        };
+        match self.range(facts, cond, RANGE_DEPTH).intersect(assumed) {
+            Some(range) => {
+                facts.insert(cond, range);
+            }
+            None => return false,
+        }
+        if depth == 0 {
+            return true;
+        }
+        match &self.terms[cond] {
+            TermDef::Op(Operator::I32Eqz | Operator::I64Eqz, args, _) => {
+                self.assume(facts, args[0], !taken, depth - 1)
+            }
+            TermDef::Op(op, args, _) if comparison(op).is_some() => {
+                let (a, b) = (args[0], args[1]);
+                let ra = self.range(facts, a, RANGE_DEPTH);
+                let rb = self.range(facts, b, RANGE_DEPTH);
+                match refine(op, ra, rb, taken) {
+                    Some((ra, rb)) if a == b => match ra.intersect(rb) {
+                        Some(range) => {
+                            facts.insert(a, range);
+                            true
+                        }
+                        None => false,
+                    },
+                    Some((ra, rb)) => {
+                        facts.insert(a, ra);
+                        facts.insert(b, rb);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            _ => true,
+        }
+    }
+
+    /// The value of `term` with the given values of the parameters and
+    /// unknowns (zero if not given).
+    fn evaluate(
+        &self,
+        term: Term,
+        model: &HashMap<Term, ConstVal>,
+        memo: &mut HashMap<Term, Option<ConstVal>>,
+    ) -> Option<ConstVal> {
+        if let Some(&value) = memo.get(&term) {
+            return value;
+        }
+        let value = match &self.terms[term] {
+            &TermDef::Const(value) => Some(value),
+            &TermDef::Param(ty, _) | &TermDef::Unknown(ty, _) => {
+                Some(model.get(&term).copied().unwrap_or(zero(ty)))
+            }
+            TermDef::Op(op, args, _) => {
+                let args = args
+                    .iter()
+                    .map(|&arg| self.evaluate(arg, model, memo))
+                    .collect::<Option<Vec<_>>>();
+                args.and_then(|args| const_eval(op, &args[..], None))
+            }
+        };
+        memo.insert(term, value);
+        value
+    }
+
+    /// Record the value of the parameter or unknown that `term` is
+    /// built from that makes `term` equal `value`, if `term` is one of
+    /// them with invertible operators applied.
+    fn invert(&self, term: Term, value: u64, targets: &mut HashMap<Term, Vec<u64>>) {
+        use Operator::*;
+        let ty = self.terms[term].ty();
+        if !is_int(ty) {
+            return;
+        }
+        let mask = type_max(ty);
+        let value = value & mask;
+        let (op, args) = match &self.terms[term] {
+            TermDef::Param(..) | TermDef::Unknown(..) => {
+                targets.entry(term).or_default().push(value);
+                return;
+            }
+            TermDef::Op(op, args, _) => (op, args),
+            TermDef::Const(_) => return,
+        };
+        if let (I32WrapI64, &[arg]) | (I64ExtendI32U, &[arg]) | (I64ExtendI32S, &[arg]) =
+            (op, &args[..])
+        {
+            return self.invert(arg, value, targets);
+        }
+        let (arg, c, left) = match &args[..] {
+            &[a, b] => match (&self.terms[a], &self.terms[b]) {
+                (_, &TermDef::Const(c)) => (a, bits(c).unwrap(), false),
+                (&TermDef::Const(c), _) => (b, bits(c).unwrap(), true),
+                _ => return,
+            },
+            _ => return,
+        };
+        let value = match op {
+            I32Add | I64Add => value.wrapping_sub(c),
+            I32Sub | I64Sub if left => c.wrapping_sub(value),
+            I32Sub | I64Sub => value.wrapping_add(c),
+            I32Xor | I64Xor => value ^ c,
+            // An odd factor has an inverse modulo the width.
+            I32Mul | I64Mul if c & 1 == 1 => {
+                let mut inverse = c;
+                for _ in 0..5 {
+                    inverse = inverse.wrapping_mul(2u64.wrapping_sub(c.wrapping_mul(inverse)));
+                }
+                value.wrapping_mul(inverse)
+            }
+            _ => return,
+        };
+        self.invert(arg, value, targets);
+    }
+
+    /// Arguments satisfying the conditions of the path ending at
+    /// `head`, found by trying values near the constants they compare
+    /// against and the bounds of `facts`, and whether the conditions
+    /// depend only on the arguments.
+    fn solve(
+        &self,
+        head: Option<usize>,
+        facts: &Facts,
+        tries: usize,
+    ) -> Option<(Vec<ConstVal>, bool)> {
+        let path = self.path(head);
+        // Values that make the terms of the conditions equal to, or
+        // just past, what they are compared against.
+        let mut targets: HashMap<Term, Vec<u64>> = HashMap::new();
+        for &(term, _) in &path {
+            match &self.terms[term] {
+                TermDef::Op(op, args, _) if comparison(op).is_some() => {
+                    let (a, b) = (args[0], args[1]);
+                    let (term, c) = match (&self.terms[a], &self.terms[b]) {
+                        (_, &TermDef::Const(c)) => (a, c),
+                        (&TermDef::Const(c), _) => (b, c),
+                        _ => continue,
+                    };
+                    let c = bits(c).unwrap();
+                    for &value in [c, c.wrapping_add(1), c.wrapping_sub(1)].iter() {
+                        self.invert(term, value, &mut targets);
+                    }
+                }
+                _ => {
+                    self.invert(term, 0, &mut targets);
+                    self.invert(term, 1, &mut targets);
+                }
+            }
+        }
+        let mut vars = vec![];
+        let mut consts = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut stack = path.iter().map(|&(term, _)| term).collect::<Vec<_>>();
+        while let Some(term) = stack.pop() {
+            if !seen.insert(term) {
+                continue;
+            }
+            match &self.terms[term] {
+                &TermDef::Const(value) => {
+                    consts.extend(bits(value));
+                }
+                TermDef::Param(..) | TermDef::Unknown(..) => vars.push(term),
+                TermDef::Op(_, args, _) => stack.extend(args.iter().copied()),
+            }
+        }
+        vars.sort();
+
+        let candidates = vars
+            .iter()
+            .map(|&var| {
+                let ty = self.terms[var].ty();
+                let mut values = vec![zero(ty)];
+                if is_int(ty) {
+                    let mask = type_max(ty);
+                    for &value in targets.get(&var).into_iter().flatten() {
+                        values.push(int(ty, value));
+                    }
+                    if let Some(range) = facts.get(&var) {
+                        values.push(int(ty, range.min));
+                        values.push(int(ty, range.max));
+                    }
+                    for &c in &consts {
+                        for value in [c, c.wrapping_add(1), c.wrapping_sub(1)].iter() {
+                            values.push(int(ty, value & mask));
+                        }
+                    }
+                }
+                values
+            })
+            .collect::<Vec<_>>();
+
+        let mut rng = Rng::new(self.options.seed);
+        for attempt in 0..tries {
+            let mut model = HashMap::new();
+            for (&var, values) in vars.iter().zip(candidates.iter()) {
+                let ty = self.terms[var].ty();
+                // First the candidates in turn, then random ones.
+                let value = if attempt < values.len() {
+                    values[attempt]
+                } else if rng.next() & 3 == 0 {
+                    rng.value(ty).unwrap_or(zero(ty))
+                } else {
+                    values[rng.next() as usize % values.len()]
+                };
+                model.insert(var, value);
+            }
+            let mut memo = HashMap::new();
+            let holds = path.iter().all(|&(term, taken)| {
+                self.evaluate(term, &model, &mut memo).and_then(truthy) == Some(taken)
+            });
+            if holds {
+                let args = self
+                    .params
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &ty)| {
+                        self.interned
+                            .get(&TermDef::Param(ty, i))
+                            .and_then(|term| model.get(term).copied())
+                            .unwrap_or(zero(ty))
+                    })
+                    .collect();
+                let exact = vars
+                    .iter()
+                    .all(|&var| matches!(self.terms[var], TermDef::Param(..)));
+                return Some((args, exact));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,483 @@
+//! Taint tracking: dataflow from chosen sources to chosen sinks.
+
+use super::body;
+use super::call_graph::{CallGraph, CallKind};
+use crate::collections::{HashMap, HashSet};
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::*;
+use crate::passes::memtrace::{access, atomic_access};
+use crate::prelude::*;
+use crate::Operator;
+use alloc::borrow::Cow;
+use alloc::collections::VecDeque;
+use anyhow::Result;
+
+/// Values that are tainted to begin with.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Source {
+    /// The results of every call to the function, typically an import.
+    Results(Func),
+    /// The parameters of the function, typically an export.
+    Params(Func),
+    /// Every read of the global.
+    Global(Global),
+    /// One value in one function.
+    Value(Func, Value),
+}
+
+/// Uses of values that a tainted value must not reach.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Sink {
+    /// The arguments of every call to the function.
+    Args(Func),
+    /// The address or value of every store to the memory, atomic or
+    /// not.
+    Store(Memory),
+    /// Every write of the global.
+    GlobalSet(Global),
+    /// The table index of every indirect call.
+    IndirectCallee,
+    /// The values returned by the function.
+    Return(Func),
+    /// One value in one function, if it is tainted.
+    Value(Func, Value),
+}
+
+/// What to track.
+#[derive(Clone, Debug, Default)]
+pub struct TaintConfig {
+    pub sources: Vec<Source>,
+    pub sinks: Vec<Sink>,
+    /// Whether storing a tainted value to memory taints every load of
+    /// that memory. Without this, flows through memory are missed.
+    pub through_memory: bool,
+}
+
+/// A flow from a source to a sink.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaintFlow {
+    pub sink: Sink,
+    /// The function containing the sink.
+    pub func: Func,
+    /// The instruction using the tainted value (or the value itself,
+    /// for `Sink::Value`), or `None` if the sink is a return.
+    pub inst: Option<Value>,
+    /// The values the taint flowed through, from a source to the value
+    /// used by the sink. Consecutive values are in different functions
+    /// where the taint flowed through a call, a return, a global or
+    /// memory.
+    pub path: Vec<(Func, Value)>,
+}
+
+/// The memory `op` reads a value from, and the one it writes a value
+/// to: loads and stores, and atomic accesses, whose read-modify-writes
+/// do both.
+fn memory_flow(op: &Operator) -> (Option<Memory>, Option<Memory>) {
+    if let Some((memarg, _, is_store)) = access(op) {
+        return if is_store {
+            (None, Some(memarg.memory))
+        } else {
+            (Some(memarg.memory), None)
+        };
+    }
+    let memory = match atomic_access(op) {
+        Some((memarg, _)) => memarg.memory,
+        None => return (None, None),
+    };
+    match op {
+        Operator::MemoryAtomicNotify { .. }
+        | Operator::MemoryAtomicWait32 { .. }
+        | Operator::MemoryAtomicWait64 { .. } => (None, None),
+        Operator::I32AtomicLoad { .. }
+        | Operator::I64AtomicLoad { .. }
+        | Operator::I32AtomicLoad8U { .. }
+        | Operator::I32AtomicLoad16U { .. }
+        | Operator::I64AtomicLoad8U { .. }
+        | Operator::I64AtomicLoad16U { .. }
+        | Operator::I64AtomicLoad32U { .. } => (Some(memory), None),
+        Operator::I32AtomicStore { .. }
+        | Operator::I64AtomicStore { .. }
+        | Operator::I32AtomicStore8 { .. }
+        | Operator::I32AtomicStore16 { .. }
+        | Operator::I64AtomicStore8 { .. }
+        | Operator::I64AtomicStore16 { .. }
+        | Operator::I64AtomicStore32 { .. } => (None, Some(memory)),
+        _ => (Some(memory), Some(memory)),
+    }
+}
+
+/// A user of a value in a body.
+#[derive(Clone, Copy, Debug)]
+enum User {
+    Inst(Value),
+    Terminator(Block),
+}
+
+/// The values reachable from some sources through explicit dataflow:
+/// operator arguments to results, branch arguments to block
+/// parameters, call arguments to the callee's parameters, returned
+/// values to call results, and global writes to global reads (and, if
+/// enabled, stores to loads). Calls to imports, or to functions outside
+/// the module, pass their arguments' taint to their results. Flows
+/// through control dependence, such as branching on a tainted
+/// condition, are not tracked.
+#[derive(Clone, Debug, Default)]
+pub struct TaintAnalysis {
+    /// Each tainted value and the one it was tainted by, if it is not a
+    /// source itself.
+    tainted: HashMap<(Func, Value), Option<(Func, Value)>>,
+    flows: Vec<TaintFlow>,
+}
+
+struct Context<'a, 'm> {
+    module: &'a Module<'m>,
+    graph: &'a CallGraph,
+    config: &'a TaintConfig,
+    bodies: Vec<Option<Cow<'a, FunctionBody>>>,
+    users: PerEntity<Func, HashMap<Value, Vec<User>>>,
+    /// The call instructions that may call each function.
+    calls: PerEntity<Func, Vec<(Func, Value)>>,
+    /// The reads of each global, and the loads from each memory.
+    global_gets: HashMap<Global, Vec<(Func, Value)>>,
+    loads: HashMap<Memory, Vec<(Func, Value)>>,
+    analysis: TaintAnalysis,
+    work: VecDeque<(Func, Value)>,
+    /// The sinks already reported, by function and instruction.
+    reported: HashSet<(Func, Option<Value>, Option<Block>)>,
+}
+
+impl TaintAnalysis {
+    /// Track the sources of `config` through the module, reporting the
+    /// first path found to each sink. Bodies that have not been parsed
+    /// yet are parsed (without changing the module); already-compiled
+    /// bodies cannot be analyzed.
+    pub fn compute(
+        module: &Module<'_>,
+        graph: &CallGraph,
+        config: &TaintConfig,
+    ) -> Result<TaintAnalysis> {
+        let bodies = module
+            .funcs
+            .iter()
+            .map(|func| body(module, func))
+            .collect::<Result<Vec<_>>>()?;
+        let mut cx = Context {
+            module,
+            graph,
+            config,
+            bodies,
+            users: PerEntity::default(),
+            calls: PerEntity::default(),
+            global_gets: HashMap::new(),
+            loads: HashMap::new(),
+            analysis: TaintAnalysis::default(),
+            work: VecDeque::new(),
+            reported: HashSet::new(),
+        };
+        cx.index();
+        for source in &config.sources {
+            cx.seed(source);
+        }
+        while let Some(node) = cx.work.pop_front() {
+            cx.propagate(node);
+        }
+        Ok(cx.analysis)
+    }
+
+    /// Whether `value` in `func` may carry taint.
+    pub fn is_tainted(&self, func: Func, value: Value) -> bool {
+        self.tainted.contains_key(&(func, value))
+    }
+
+    /// How `value` in `func` was tainted, from a source to it, or
+    /// `None` if it isn't.
+    pub fn path(&self, func: Func, value: Value) -> Option<Vec<(Func, Value)>> {
+        let mut node = (func, value);
+        let mut path = vec![node];
+        while let Some(from) = *self.tainted.get(&node)? {
+            path.push(from);
+            node = from;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// The flows found, one per sink instruction (or return), in the
+    /// order they were found; shorter paths are found first.
+    pub fn flows(&self) -> &[TaintFlow] {
+        &self.flows[..]
+    }
+}
+
+impl<'a, 'm> Context<'a, 'm> {
+    fn body(&self, func: Func) -> Option<&FunctionBody> {
+        self.bodies[func.index()].as_deref()
+    }
+
+    /// Find the users of every value, the calls to every function and
+    /// the reads of every global and memory.
+    fn index(&mut self) {
+        for func in self.module.funcs.iter() {
+            let body = match self.bodies[func.index()].as_deref() {
+                Some(body) => body,
+                None => continue,
+            };
+            let mut users: HashMap<Value, Vec<User>> = HashMap::new();
+            for (block, def) in body.blocks.entries() {
+                for &inst in &def.insts {
+                    match &body.values[inst] {
+                        ValueDef::Alias(_) | ValueDef::None => {}
+                        def => def.visit_uses(&body.arg_pool, |arg| {
+                            users
+                                .entry(body.resolve_alias(arg))
+                                .or_default()
+                                .push(User::Inst(inst))
+                        }),
+                    }
+                    match body.values[inst] {
+                        ValueDef::Operator(Operator::GlobalGet { global_index }, ..) => self
+                            .global_gets
+                            .entry(global_index)
+                            .or_default()
+                            .push((func, inst)),
+                        ValueDef::Operator(ref op, ..) => {
+                            if let (Some(memory), _) = memory_flow(op) {
+                                self.loads.entry(memory).or_default().push((func, inst));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                def.terminator.visit_uses(|arg| {
+                    users
+                        .entry(body.resolve_alias(arg))
+                        .or_default()
+                        .push(User::Terminator(block))
+                });
+            }
+            self.users[func] = users;
+            for site in self.graph.sites(func) {
+                for &callee in &site.callees {
+                    self.calls[callee].push((func, site.inst));
+                }
+            }
+        }
+    }
+
+    fn taint(&mut self, node: (Func, Value), from: Option<(Func, Value)>) {
+        if let crate::collections::hash_map::Entry::Vacant(entry) =
+            self.analysis.tainted.entry(node)
+        {
+            entry.insert(from);
+            self.work.push_back(node);
+            let config = self.config;
+            for sink in &config.sinks {
+                if *sink == Sink::Value(node.0, node.1) {
+                    let block = self.body(node.0).unwrap().value_blocks[node.1];
+                    self.report(sink, node, Some(node.1), block);
+                }
+            }
+        }
+    }
+
+    fn seed(&mut self, source: &Source) {
+        let nodes = match source {
+            Source::Results(func) => self.calls[*func].clone(),
+            Source::Params(func) => match self.body(*func) {
+                Some(body) => body.blocks[body.entry]
+                    .params
+                    .iter()
+                    .map(|&(_, param)| (*func, param))
+                    .collect(),
+                None => vec![],
+            },
+            Source::Global(global) => self.global_gets.get(global).cloned().unwrap_or_default(),
+            Source::Value(func, value) => match self.body(*func) {
+                Some(body) => vec![(*func, body.resolve_alias(*value))],
+                None => vec![],
+            },
+        };
+        for node in nodes {
+            self.taint(node, None);
+        }
+    }
+
+    fn report(&mut self, sink: &Sink, node: (Func, Value), inst: Option<Value>, block: Block) {
+        let block = inst.is_none().then_some(block);
+        if self.reported.insert((node.0, inst, block)) {
+            self.analysis.flows.push(TaintFlow {
+                sink: sink.clone(),
+                func: node.0,
+                inst,
+                path: self.analysis.path(node.0, node.1).unwrap(),
+            });
+        }
+    }
+
+    /// Report the sinks among the uses of `node` by `inst`, where
+    /// `index` is the argument position.
+    fn check_inst(&mut self, node: (Func, Value), inst: Value, index: usize) {
+        let func = node.0;
+        let op = match &self.body(func).unwrap().values[inst] {
+            ValueDef::Operator(op, args, _) => Some((*op, args.len())),
+            _ => None,
+        };
+        let block = self.body(func).unwrap().value_blocks[inst];
+        let config = self.config;
+        let site = self.graph.sites(func).iter().find(|site| site.inst == inst);
+        for sink in &config.sinks {
+            let hit = match (sink, op) {
+                (Sink::Args(callee), Some((_, len))) => site.is_some_and(|site| {
+                    site.callees.contains(callee)
+                        && (site.kind == CallKind::Direct || index + 1 < len)
+                }),
+                (Sink::IndirectCallee, Some((Operator::CallIndirect { .. }, len))) => {
+                    index + 1 == len
+                }
+                (Sink::Store(memory), Some((op, _))) => memory_flow(&op).1 == Some(*memory),
+                (Sink::GlobalSet(global), Some((Operator::GlobalSet { global_index }, _))) => {
+                    global_index == *global
+                }
+                _ => false,
+            };
+            if hit {
+                self.report(sink, node, Some(inst), block);
+            }
+        }
+    }
+
+    fn propagate(&mut self, node: (Func, Value)) {
+        let (func, value) = node;
+        let users = self.users[func].get(&value).cloned().unwrap_or_default();
+        for user in users {
+            match user {
+                User::Inst(inst) => self.propagate_to_inst(node, inst),
+                User::Terminator(block) => self.propagate_to_terminator(node, block),
+            }
+        }
+    }
+
+    fn propagate_to_inst(&mut self, node: (Func, Value), inst: Value) {
+        let (func, value) = node;
+        let body = self.body(func).unwrap();
+        let (op, args) = match &body.values[inst] {
+            ValueDef::Operator(op, args, _) => (*op, body.arg_pool[*args].to_vec()),
+            // A `PickOutput` or `Trace`.
+            _ => {
+                self.check_inst(node, inst, 0);
+                self.taint((func, inst), Some(node));
+                return;
+            }
+        };
+        let args = args
+            .iter()
+            .map(|&arg| body.resolve_alias(arg))
+            .collect::<Vec<_>>();
+        for index in (0..args.len()).filter(|&i| args[i] == value) {
+            self.check_inst(node, inst, index);
+        }
+
+        let call = self
+            .graph
+            .sites(func)
+            .iter()
+            .find(|site| site.inst == inst)
+            .cloned();
+        if let Some(site) = call {
+            let nargs = match site.kind {
+                CallKind::Direct => args.len(),
+                CallKind::Indirect(_) => args.len() - 1,
+            };
+            let mut opaque = site.external;
+            for &callee in &site.callees {
+                let params = match self.body(callee) {
+                    Some(body) => body.blocks[body.entry]
+                        .params
+                        .iter()
+                        .map(|&(_, param)| param)
+                        .collect::<Vec<_>>(),
+                    None => {
+                        opaque = true;
+                        continue;
+                    }
+                };
+                for index in (0..nargs).filter(|&i| args[i] == value) {
+                    if let Some(&param) = params.get(index) {
+                        self.taint((callee, param), Some(node));
+                    }
+                }
+            }
+            if opaque && args[..nargs].contains(&value) {
+                self.taint((func, inst), Some(node));
+            }
+            return;
+        }
+
+        match op {
+            Operator::GlobalSet { global_index } => {
+                self.taint((func, inst), Some(node));
+                let gets = self
+                    .global_gets
+                    .get(&global_index)
+                    .cloned()
+                    .unwrap_or_default();
+                for get in gets {
+                    self.taint(get, Some((func, inst)));
+                }
+            }
+            op => match memory_flow(&op) {
+                (loaded, Some(memory)) => {
+                    // Only the stored value taints memory, not the
+                    // address.
+                    if self.config.through_memory && args[1..].contains(&value) {
+                        self.taint((func, inst), Some(node));
+                        let loads = self.loads.get(&memory).cloned().unwrap_or_default();
+                        for load in loads {
+                            self.taint(load, Some((func, inst)));
+                        }
+                    }
+                    // A read-modify-write's result is computed from
+                    // its operands, like any other operator's.
+                    if loaded.is_some() {
+                        self.taint((func, inst), Some(node));
+                    }
+                }
+                _ => self.taint((func, inst), Some(node)),
+            },
+        }
+    }
+
+    fn propagate_to_terminator(&mut self, node: (Func, Value), block: Block) {
+        let (func, value) = node;
+        let body = self.body(func).unwrap();
+        let mut params = vec![];
+        body.blocks[block].terminator.visit_targets(|target| {
+            for (i, &arg) in target.args.iter().enumerate() {
+                if body.resolve_alias(arg) == value {
+                    params.push(body.blocks[target.block].params[i].1);
+                }
+            }
+        });
+        let returned = match &body.blocks[block].terminator {
+            Terminator::Return { values } => values
+                .iter()
+                .any(|&returned| body.resolve_alias(returned) == value),
+            _ => false,
+        };
+        for param in params {
+            self.taint((func, param), Some(node));
+        }
+        if !returned {
+            return;
+        }
+        let config = self.config;
+        for sink in &config.sinks {
+            if *sink == Sink::Return(func) {
+                self.report(sink, node, None, block);
+            }
+        }
+        for call in self.calls[func].clone() {
+            self.taint(call, Some(node));
+        }
+    }
+}
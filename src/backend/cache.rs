@@ -0,0 +1,53 @@
+//! Reuse of compiled bodies across serializations of a module.
+
+use super::CodegenOptions;
+use crate::ir::{Func, SpillConfig};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// A body as last compiled, with what its compilation depended on
+/// besides the body itself.
+#[derive(Debug)]
+pub(crate) struct CompiledBody {
+    pub(crate) func: wasm_encoder::Function,
+    pub(crate) local_names: Option<BTreeMap<u32, String>>,
+    pub(crate) offsets: Option<Vec<(u32, u32)>>,
+    pub(crate) orig_local_names: Option<BTreeMap<u32, String>>,
+    pub(crate) spill_config: Option<SpillConfig>,
+    pub(crate) codegen_options: CodegenOptions,
+}
+
+/// The compiled bodies of the functions that have not changed since
+/// they were last compiled. Filled in only once enabled.
+#[derive(Debug, Default)]
+pub(crate) struct CompileCache {
+    pub(crate) enabled: bool,
+    bodies: Mutex<HashMap<Func, Arc<CompiledBody>>>,
+}
+
+impl Clone for CompileCache {
+    fn clone(&self) -> Self {
+        CompileCache {
+            enabled: self.enabled,
+            bodies: Mutex::new(self.bodies.lock().unwrap().clone()),
+        }
+    }
+}
+
+impl CompileCache {
+    pub(crate) fn get(&self, func: Func) -> Option<Arc<CompiledBody>> {
+        self.bodies.lock().unwrap().get(&func).cloned()
+    }
+
+    pub(crate) fn insert(&self, func: Func, body: CompiledBody) {
+        self.bodies.lock().unwrap().insert(func, Arc::new(body));
+    }
+
+    pub(crate) fn remove(&mut self, func: Func) {
+        self.bodies.get_mut().unwrap().remove(&func);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.bodies.get_mut().unwrap().clear();
+    }
+}
@@ -0,0 +1,283 @@
+//! Rewriting of DWARF debug info to match the emitted code section.
+
+use super::layout::{CodeLayout, OpOffsets};
+use crate::ir::DwarfSections;
+use anyhow::Result;
+use gimli::write::{
+    Address, AttributeValue, DirectoryId, FileId, FileInfo, LineProgram, LineRow, LineString,
+    LineStringTable, StringTable,
+};
+use gimli::{EndianSlice, LittleEndian};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+type Reader<'a> = EndianSlice<'a, LittleEndian>;
+
+/// Rewrite the DWARF sections for the given code layout. Returns the
+/// new sections, by name.
+pub(crate) fn rewrite(
+    sections: &DwarfSections,
+    layout: &CodeLayout,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    let dwarf = gimli::Dwarf::load(|id| -> Result<Reader<'_>> {
+        let data = sections
+            .sections
+            .get(id.name())
+            .map(|data| &data[..])
+            .unwrap_or(&[]);
+        Ok(EndianSlice::new(data, LittleEndian))
+    })?;
+
+    // Remember which old address each new one came from, to fix up
+    // `DW_AT_high_pc` lengths below.
+    let origins = RefCell::new(HashMap::new());
+    // Note that this also applies to `DW_OP_addr` operands, which on
+    // Wasm are memory rather than code addresses; gimli does not let
+    // us tell them apart.
+    let convert_address = |addr: u64| {
+        let new = layout.translate(addr);
+        origins.borrow_mut().entry(new).or_insert(addr);
+        Some(Address::Constant(new))
+    };
+    let mut out = gimli::write::Dwarf::from(&dwarf, &convert_address)?;
+    let origins = origins.into_inner();
+
+    // gimli only relocates the start of each line sequence; rebuild
+    // the rows so that they follow the code.
+    let mut headers = dwarf.units();
+    let mut index = 0;
+    while let Some(header) = headers.next()? {
+        let unit = dwarf.unit(header)?;
+        let id = out.units.id(index);
+        index += 1;
+        if let Some(program) = unit.line_program {
+            out.units.get_mut(id).line_program = rewrite_line_program(
+                program,
+                &dwarf,
+                &mut out.line_strings,
+                &mut out.strings,
+                layout,
+            )?;
+        }
+
+        let unit = out.units.get_mut(id);
+        let mut entries = vec![unit.root()];
+        while let Some(entry) = entries.pop() {
+            let entry = unit.get_mut(entry);
+            entries.extend(entry.children().copied());
+            let low = match entry.get(gimli::DW_AT_low_pc) {
+                Some(&AttributeValue::Address(Address::Constant(low))) => low,
+                _ => continue,
+            };
+            let len = match entry.get(gimli::DW_AT_high_pc) {
+                Some(&AttributeValue::Udata(len)) => len,
+                Some(&AttributeValue::Data4(len)) => len as u64,
+                Some(&AttributeValue::Data8(len)) => len,
+                _ => continue,
+            };
+            if let Some(&old_low) = origins.get(&low) {
+                let high = layout.translate(old_low + len);
+                entry.set(
+                    gimli::DW_AT_high_pc,
+                    AttributeValue::Udata(high.saturating_sub(low)),
+                );
+            }
+        }
+    }
+
+    let mut sections = gimli::write::Sections::new(gimli::write::EndianVec::new(LittleEndian));
+    out.write(&mut sections)?;
+    let mut result = vec![];
+    sections.for_each(|id, data| -> Result<()> {
+        if !data.slice().is_empty() {
+            result.push((id.name().to_owned(), data.slice().to_vec()));
+        }
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+fn line_string(
+    attr: gimli::AttributeValue<Reader<'_>>,
+    dwarf: &gimli::Dwarf<Reader<'_>>,
+    line_strings: &mut LineStringTable,
+    strings: &mut StringTable,
+) -> Result<LineString> {
+    Ok(match attr {
+        gimli::AttributeValue::String(s) => LineString::String(s.to_vec()),
+        gimli::AttributeValue::DebugStrRef(offset) => {
+            LineString::StringRef(strings.add(dwarf.debug_str.get_str(offset)?.to_vec()))
+        }
+        gimli::AttributeValue::DebugLineStrRef(offset) => LineString::LineStringRef(
+            line_strings.add(dwarf.debug_line_str.get_str(offset)?.to_vec()),
+        ),
+        attr => anyhow::bail!("Unsupported line program string: {:?}", attr),
+    })
+}
+
+/// Rebuild a unit's line program. The header is converted the same
+/// way gimli converts it, so that file IDs referenced from the unit's
+/// DIEs stay valid; rows are then placed at their new addresses.
+fn rewrite_line_program(
+    program: gimli::IncompleteLineProgram<Reader<'_>>,
+    dwarf: &gimli::Dwarf<Reader<'_>>,
+    line_strings: &mut LineStringTable,
+    strings: &mut StringTable,
+    layout: &CodeLayout,
+) -> Result<LineProgram> {
+    let header = program.header();
+    let comp_dir = match header.directory(0) {
+        Some(dir) => line_string(dir, dwarf, line_strings, strings)?,
+        None => LineString::new(&[][..], header.encoding(), line_strings),
+    };
+    let (comp_name, comp_info) = match header.file(0) {
+        Some(file) => (
+            line_string(file.path_name(), dwarf, line_strings, strings)?,
+            Some(FileInfo {
+                timestamp: file.timestamp(),
+                size: file.size(),
+                md5: *file.md5(),
+            }),
+        ),
+        None => (
+            LineString::new(&[][..], header.encoding(), line_strings),
+            None,
+        ),
+    };
+    let mut new_program = LineProgram::new(
+        header.encoding(),
+        header.line_encoding(),
+        comp_dir,
+        comp_name.clone(),
+        comp_info,
+    );
+
+    let mut dirs: Vec<DirectoryId> = vec![];
+    if header.version() <= 4 {
+        dirs.push(new_program.default_directory());
+    }
+    for &dir in header.include_directories() {
+        let dir = line_string(dir, dwarf, line_strings, strings)?;
+        dirs.push(new_program.add_directory(dir));
+    }
+    new_program.file_has_timestamp = header.file_has_timestamp();
+    new_program.file_has_size = header.file_has_size();
+    new_program.file_has_md5 = header.file_has_md5();
+    // Indexed by the file register's value. Index 0 is invalid before
+    // DWARF 5, and is the unit's primary file from DWARF 5 on.
+    let mut files: Vec<Option<FileId>> = vec![None];
+    let skip = if header.version() <= 4 { 0 } else { 1 };
+    for file in header.file_names().iter().skip(skip) {
+        let name = line_string(file.path_name(), dwarf, line_strings, strings)?;
+        let dir = dirs
+            .get(file.directory_index() as usize)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Invalid directory index in line program"))?;
+        let info = FileInfo {
+            timestamp: file.timestamp(),
+            size: file.size(),
+            md5: *file.md5(),
+        };
+        files.push(Some(new_program.add_file(name, dir, Some(info))));
+    }
+    if header.version() >= 5 {
+        // A file entry can't refer to the primary file's own slot, so
+        // add it again after the others.
+        let dir = new_program.default_directory();
+        files[0] = Some(new_program.add_file(comp_name, dir, comp_info));
+    }
+
+    // Collect each sequence's rows with their original addresses.
+    let mut sequences = vec![];
+    let mut rows = vec![];
+    let mut iter = program.rows();
+    while let Some((_, row)) = iter.next_row()? {
+        if row.end_sequence() {
+            sequences.push((std::mem::take(&mut rows), row.address()));
+            continue;
+        }
+        let file = files
+            .get(row.file_index() as usize)
+            .copied()
+            .flatten()
+            .ok_or_else(|| anyhow::anyhow!("Invalid file index in line program"))?;
+        let new_row = LineRow {
+            address_offset: 0,
+            op_index: 0,
+            file,
+            line: row.line().map(|line| line.get()).unwrap_or(0),
+            column: match row.column() {
+                gimli::ColumnType::LeftEdge => 0,
+                gimli::ColumnType::Column(col) => col.get(),
+            },
+            discriminator: row.discriminator(),
+            is_statement: row.is_stmt(),
+            basic_block: row.basic_block(),
+            prologue_end: row.prologue_end(),
+            epilogue_begin: row.epilogue_begin(),
+            isa: row.isa(),
+        };
+        rows.push((row.address(), new_row));
+    }
+
+    for (rows, end) in &sequences {
+        let end = *end;
+        let start = match rows.first() {
+            Some(&(start, _)) => start,
+            None => continue,
+        };
+        for func in layout.overlapping(start..end) {
+            let lo = start.max(func.old.start as u64);
+            let hi = end.min(func.old.end as u64);
+            let new_lo = layout.translate(lo);
+            let new_hi = layout.translate(hi);
+            let new_rows: Vec<(u64, LineRow)> = match &func.ops {
+                None => rows
+                    .iter()
+                    .filter(|&&(addr, _)| lo <= addr && addr < hi)
+                    .map(|&(addr, row)| (layout.translate(addr), row))
+                    .collect(),
+                Some(OpOffsets { emitted, .. }) => {
+                    let mut new_rows: Vec<(u64, LineRow)> = vec![];
+                    for &(new, old) in emitted {
+                        let (new, old) = (new as u64, old as u64);
+                        if old < lo || old >= hi || new < new_lo {
+                            continue;
+                        }
+                        let idx = rows.partition_point(|&(addr, _)| addr <= old);
+                        let row = match idx.checked_sub(1) {
+                            Some(idx) => rows[idx].1,
+                            None => continue,
+                        };
+                        let same_as_last = new_rows.last().is_some_and(|(_, last)| {
+                            (last.file, last.line, last.column) == (row.file, row.line, row.column)
+                        });
+                        if !same_as_last {
+                            new_rows.push((new, row));
+                        }
+                    }
+                    // Code emitted before the first operator (e.g.
+                    // local declarations) belongs to the first row.
+                    if let Some(first) = new_rows.first_mut() {
+                        first.0 = new_lo;
+                    }
+                    new_rows
+                }
+            };
+            let last = match new_rows.last() {
+                Some(&(last, _)) => last,
+                None => continue,
+            };
+
+            new_program.begin_sequence(Some(Address::Constant(new_lo)));
+            for (addr, mut row) in new_rows {
+                row.address_offset = addr - new_lo;
+                *new_program.row() = row;
+                new_program.generate_row();
+            }
+            new_program.end_sequence(new_hi.max(last + 1) - new_lo);
+        }
+    }
+
+    Ok(new_program)
+}
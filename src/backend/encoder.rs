@@ -0,0 +1,279 @@
+//! Pluggable final emission: where the backend's encoded output goes.
+
+use super::size::{section_name, SectionSize};
+use crate::ir::Value;
+use crate::leb128::{padded_u32, write_u32, Reader};
+use anyhow::Result;
+use std::convert::TryFrom;
+use std::io::{Seek, SeekFrom, Write};
+
+/// A destination for a compiled module. The backend hands each
+/// section to the encoder, in order, as soon as it is complete.
+pub trait ModuleEncoder {
+    type Output;
+
+    /// Append a section with the given section ID. `contents` is the
+    /// section's payload, without the ID or size prefix.
+    fn section(&mut self, id: u8, contents: &[u8]) -> Result<()>;
+
+    /// Start a section with the given section ID whose contents are
+    /// appended in pieces with `section_part`, before their size is
+    /// known, until `end_section`. Returns whether the encoder can take
+    /// a section this way; by default it can't, and the backend gives
+    /// it the whole section with `section` instead.
+    fn begin_section(&mut self, _id: u8) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Append to the section started with `begin_section`.
+    fn section_part(&mut self, _contents: &[u8]) -> Result<()> {
+        anyhow::bail!("This encoder does not take sections in pieces")
+    }
+
+    /// Finish the section started with `begin_section`.
+    fn end_section(&mut self) -> Result<()> {
+        anyhow::bail!("This encoder does not take sections in pieces")
+    }
+
+    /// Finish the module once all sections have been provided.
+    fn finish(self) -> Result<Self::Output>;
+}
+
+/// Receives the instructions of one function body as they are lowered.
+pub trait FunctionSink {
+    fn instruction(&mut self, inst: &wasm_encoder::Instruction<'_>);
+
+    /// Called before the instructions lowered from the operator at
+    /// `offset` in the original module's bytes.
+    fn source_offset(&mut self, _offset: u32) {}
+
+    /// The instructions until the matching `leave_value` compute or
+    /// store `value`, apart from those within nested pairs, which
+    /// compute its operands.
+    fn enter_value(&mut self, _value: Value) {}
+
+    fn leave_value(&mut self) {}
+}
+
+impl FunctionSink for wasm_encoder::Function {
+    fn instruction(&mut self, inst: &wasm_encoder::Instruction<'_>) {
+        wasm_encoder::Function::instruction(self, inst);
+    }
+}
+
+/// Encodes to an in-memory Wasm binary via `wasm-encoder`.
+#[derive(Debug, Default)]
+pub struct BinaryEncoder {
+    module: wasm_encoder::Module,
+}
+
+impl BinaryEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ModuleEncoder for BinaryEncoder {
+    type Output = Vec<u8>;
+
+    fn section(&mut self, id: u8, contents: &[u8]) -> Result<()> {
+        self.module
+            .section(&wasm_encoder::RawSection { id, data: contents });
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Vec<u8>> {
+        Ok(self.module.finish())
+    }
+}
+
+/// Writes a Wasm binary to an `io::Write`, one section at a time.
+pub struct WriterEncoder<W: Write> {
+    out: W,
+    wrote_header: bool,
+}
+
+impl<W: Write> WriterEncoder<W> {
+    pub fn new(out: W) -> Self {
+        WriterEncoder {
+            out,
+            wrote_header: false,
+        }
+    }
+
+    fn header(&mut self) -> Result<()> {
+        if !self.wrote_header {
+            self.out
+                .write_all(&[0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00])?;
+            self.wrote_header = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> ModuleEncoder for WriterEncoder<W> {
+    type Output = W;
+
+    fn section(&mut self, id: u8, contents: &[u8]) -> Result<()> {
+        self.header()?;
+        let mut prefix = vec![id];
+        write_u32(&mut prefix, contents.len() as u32);
+        self.out.write_all(&prefix[..])?;
+        self.out.write_all(contents)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W> {
+        self.header()?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Hands sections on to a `BinaryEncoder`, and records where each
+/// one's contents go in the output, so that the output need not be
+/// parsed again to find them.
+#[derive(Debug, Default)]
+pub(crate) struct MeasuringEncoder {
+    binary: BinaryEncoder,
+    /// The size of the output so far, starting with the header.
+    len: usize,
+    pub(crate) sections: Vec<SectionSize>,
+    /// The offset of the code section's contents.
+    pub(crate) code_start: Option<u32>,
+}
+
+impl MeasuringEncoder {
+    pub(crate) fn new() -> Self {
+        MeasuringEncoder {
+            len: 8,
+            ..Self::default()
+        }
+    }
+}
+
+impl ModuleEncoder for MeasuringEncoder {
+    type Output = Vec<u8>;
+
+    fn section(&mut self, id: u8, contents: &[u8]) -> Result<()> {
+        let mut prefix = vec![id];
+        write_u32(&mut prefix, contents.len() as u32);
+        self.len += prefix.len();
+        if id == 10 {
+            self.code_start = Some(self.len as u32);
+        }
+        let name = match id {
+            0 => Reader::new(contents).read_string()?.to_owned(),
+            _ => section_name(id).to_owned(),
+        };
+        self.sections.push(SectionSize {
+            id,
+            name,
+            size: contents.len() as u32,
+        });
+        self.len += contents.len();
+        self.binary.section(id, contents)
+    }
+
+    fn finish(self) -> Result<Vec<u8>> {
+        self.binary.finish()
+    }
+}
+
+/// Writes a Wasm binary to a seekable `io::Write` like `WriterEncoder`,
+/// and also takes sections in pieces: room is left for the section's
+/// size, which is written there once the section ends.
+pub(crate) struct SeekWriterEncoder<W: Write + Seek> {
+    writer: WriterEncoder<W>,
+    /// Where the size of the section being written in pieces goes.
+    size_at: Option<u64>,
+}
+
+impl<W: Write + Seek> SeekWriterEncoder<W> {
+    pub(crate) fn new(out: W) -> Self {
+        SeekWriterEncoder {
+            writer: WriterEncoder::new(out),
+            size_at: None,
+        }
+    }
+}
+
+impl<W: Write + Seek> ModuleEncoder for SeekWriterEncoder<W> {
+    type Output = W;
+
+    fn section(&mut self, id: u8, contents: &[u8]) -> Result<()> {
+        self.writer.section(id, contents)
+    }
+
+    fn begin_section(&mut self, id: u8) -> Result<bool> {
+        self.writer.header()?;
+        let out = &mut self.writer.out;
+        out.write_all(&[id])?;
+        self.size_at = Some(out.stream_position()?);
+        out.write_all(&padded_u32(0))?;
+        Ok(true)
+    }
+
+    fn section_part(&mut self, contents: &[u8]) -> Result<()> {
+        self.writer.out.write_all(contents)?;
+        Ok(())
+    }
+
+    fn end_section(&mut self) -> Result<()> {
+        let size_at = match self.size_at.take() {
+            Some(size_at) => size_at,
+            None => anyhow::bail!("No section was begun"),
+        };
+        let out = &mut self.writer.out;
+        let end = out.stream_position()?;
+        let size = match u32::try_from(end - size_at - 5) {
+            Ok(size) => size,
+            Err(_) => anyhow::bail!("Section of {} bytes is too large", end - size_at - 5),
+        };
+        out.seek(SeekFrom::Start(size_at))?;
+        out.write_all(&padded_u32(size))?;
+        out.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<W> {
+        self.writer.finish()
+    }
+}
+
+/// Produces the WAT text format, by printing the encoded binary.
+#[cfg(feature = "wat")]
+#[derive(Debug, Default)]
+pub struct WatEncoder {
+    binary: BinaryEncoder,
+}
+
+#[cfg(feature = "wat")]
+impl WatEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "wat")]
+impl ModuleEncoder for WatEncoder {
+    type Output = String;
+
+    fn section(&mut self, id: u8, contents: &[u8]) -> Result<()> {
+        self.binary.section(id, contents)
+    }
+
+    fn finish(self) -> Result<String> {
+        let bytes = self.binary.finish()?;
+        wasmprinter::print_bytes(&bytes[..])
+    }
+}
+
+/// Split an encoded (size-prefixed) section body into its payload.
+pub(crate) fn strip_size_prefix(encoded: &[u8]) -> &[u8] {
+    let mut pos = 0;
+    while encoded[pos] & 0x80 != 0 {
+        pos += 1;
+    }
+    &encoded[pos + 1..]
+}
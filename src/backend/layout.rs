@@ -0,0 +1,126 @@
+//! Where code moved between the original and the emitted module.
+
+use crate::ir::Func;
+use std::ops::Range;
+
+/// Where one function body went in the emitted code section. All
+/// offsets are relative to the start of the code section's contents,
+/// as DWARF code addresses are.
+#[derive(Clone, Debug)]
+pub(crate) struct FuncLayout {
+    pub(crate) old: Range<u32>,
+    pub(crate) new: Range<u32>,
+    /// `None` if the body was copied verbatim.
+    pub(crate) ops: Option<OpOffsets>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct OpOffsets {
+    /// The `(new, old)` offsets of each lowered operator, in emission
+    /// order.
+    pub(crate) emitted: Vec<(u32, u32)>,
+    /// `(old, new)` translations, sorted by old offset.
+    by_old: Vec<(u32, u32)>,
+}
+
+impl FuncLayout {
+    pub(crate) fn verbatim(old: Range<u32>, new: Range<u32>) -> FuncLayout {
+        FuncLayout {
+            old,
+            new,
+            ops: None,
+        }
+    }
+
+    pub(crate) fn recompiled(old: Range<u32>, new: Range<u32>, ops: Vec<(u32, u32)>) -> FuncLayout {
+        let mut by_old = ops.iter().map(|&(new, old)| (old, new)).collect::<Vec<_>>();
+        by_old.sort();
+        // Map each old offset to the earliest code emitted for it or
+        // any later operator, so that translation is monotonic and
+        // ranges stay well-formed.
+        let mut earliest = new.end;
+        for (_, new) in by_old.iter_mut().rev() {
+            earliest = earliest.min(*new);
+            *new = earliest;
+        }
+        FuncLayout {
+            old,
+            new,
+            ops: Some(OpOffsets {
+                emitted: ops,
+                by_old,
+            }),
+        }
+    }
+
+    fn translate(&self, addr: u32) -> u32 {
+        if addr <= self.old.start {
+            return self.new.start;
+        }
+        if addr >= self.old.end {
+            return self.new.end;
+        }
+        match &self.ops {
+            None => addr - self.old.start + self.new.start,
+            Some(OpOffsets { by_old, .. }) => {
+                let idx = by_old.partition_point(|&(old, _)| old < addr);
+                by_old.get(idx).map(|&(_, new)| new).unwrap_or(self.new.end)
+            }
+        }
+    }
+}
+
+/// The old-to-new code offset mapping for a whole module.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CodeLayout {
+    funcs: Vec<FuncLayout>,
+    /// Where every emitted body went, whether or not it came from the
+    /// original module, in emission order.
+    bodies: Vec<(Func, Range<u32>)>,
+}
+
+impl CodeLayout {
+    pub(crate) fn push(&mut self, func: FuncLayout) {
+        self.funcs.push(func);
+    }
+
+    pub(crate) fn push_body(&mut self, func: Func, new: Range<u32>) {
+        self.bodies.push((func, new));
+    }
+
+    /// Sort by original position; must be called before lookups.
+    pub(crate) fn finish(&mut self) {
+        self.funcs.sort_by_key(|func| func.old.start);
+    }
+
+    pub(crate) fn funcs(&self) -> &[FuncLayout] {
+        &self.funcs[..]
+    }
+
+    pub(crate) fn bodies(&self) -> &[(Func, Range<u32>)] {
+        &self.bodies[..]
+    }
+
+    /// Functions whose original bodies overlap `range`.
+    pub(crate) fn overlapping(&self, range: Range<u64>) -> impl Iterator<Item = &FuncLayout> {
+        let start = self
+            .funcs
+            .partition_point(|func| (func.old.end as u64) <= range.start);
+        self.funcs[start..]
+            .iter()
+            .take_while(move |func| (func.old.start as u64) < range.end)
+    }
+
+    /// Translate a code address. Addresses outside every emitted
+    /// function body (e.g. tombstones for discarded code) are left
+    /// alone.
+    pub(crate) fn translate(&self, addr: u64) -> u64 {
+        let idx = self
+            .funcs
+            .partition_point(|func| (func.old.start as u64) <= addr);
+        match idx.checked_sub(1).map(|idx| &self.funcs[idx]) {
+            Some(func) if addr <= func.old.end as u64 => func.translate(addr as u32) as u64,
+            _ => addr,
+        }
+    }
+}
@@ -3,8 +3,8 @@
 
 use crate::backend::treeify::Trees;
 use crate::cfg::CFGInfo;
-use crate::entity::{EntityVec, PerEntity};
-use crate::ir::{Block, FunctionBody, Local, Type, Value, ValueDef};
+use crate::entity::{EntityRef, EntityVec, PerEntity};
+use crate::ir::{AllocHint, Block, FunctionBody, Local, Type, Value, ValueDef};
 use smallvec::{smallvec, SmallVec};
 use std::collections::{HashMap, HashSet};
 use std::ops::Range;
@@ -13,11 +13,95 @@ use std::ops::Range;
 pub struct Localifier {
     pub values: PerEntity<Value, SmallVec<[Local; 2]>>,
     pub locals: EntityVec<Local, Type>,
+    /// Locals that have been spilled to the shadow stack rather than
+    /// emitted as Wasm locals, with the byte offset of each one's slot
+    /// within the frame.
+    pub spill_slots: PerEntity<Local, Option<u32>>,
+    /// Size in bytes of the shadow-stack frame holding spill slots;
+    /// zero if nothing was spilled.
+    pub frame_size: u32,
+}
+
+/// Size of one spill slot. Only scalar (non-reference, non-vector)
+/// locals are spilled, so every slot can hold any spilled type.
+const SPILL_SLOT_SIZE: u32 = 8;
+/// Scratch locals needed to spill: one per scalar type, plus the frame
+/// pointer.
+const SPILL_SCRATCH_LOCALS: usize = 5;
+
+/// Strategy used to assign locals to liveranges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocStrategy {
+    /// Linear scan over each value's whole range (first to last
+    /// point), ignoring any holes in its lifetime. Fast, but may use
+    /// more locals than necessary.
+    LinearScan,
+    /// Second-chance binpacking: each value is described by the set of
+    /// segments where it is actually live, and may occupy a local
+    /// during another value's lifetime hole. A value first tries the
+    /// locals of values it is connected to via blockparams (so that
+    /// the transfer becomes a no-op), then falls back to first-fit.
+    Binpack,
+    /// Pick `Binpack` for functions with at least `binpack_threshold`
+    /// values and `LinearScan` otherwise.
+    Auto { binpack_threshold: usize },
+}
+
+impl std::default::Default for AllocStrategy {
+    fn default() -> Self {
+        AllocStrategy::Auto {
+            binpack_threshold: 1000,
+        }
+    }
+}
+
+impl AllocStrategy {
+    /// Resolve `Auto` to a concrete strategy for the given body.
+    pub fn for_body(self, body: &FunctionBody) -> AllocStrategy {
+        match self {
+            AllocStrategy::Auto { binpack_threshold } if body.values.len() >= binpack_threshold => {
+                AllocStrategy::Binpack
+            }
+            AllocStrategy::Auto { .. } => AllocStrategy::LinearScan,
+            other => other,
+        }
+    }
 }
 
 impl Localifier {
     pub fn compute(body: &FunctionBody, cfg: &CFGInfo, trees: &Trees) -> Self {
-        Context::new(body, cfg, trees).compute()
+        Self::compute_with_strategy(body, cfg, trees, AllocStrategy::default())
+    }
+
+    pub fn compute_with_strategy(
+        body: &FunctionBody,
+        cfg: &CFGInfo,
+        trees: &Trees,
+        strategy: AllocStrategy,
+    ) -> Self {
+        Context::new(body, cfg, trees, strategy.for_body(body)).compute()
+    }
+
+    /// Compute an allocation as above, then spill locals until the
+    /// function uses at most `max_locals` Wasm locals (including
+    /// params and the scratch locals that spilling itself requires).
+    pub fn compute_with_limit(
+        body: &FunctionBody,
+        cfg: &CFGInfo,
+        trees: &Trees,
+        strategy: AllocStrategy,
+        max_locals: usize,
+    ) -> Self {
+        let mut ctx = Context::new(body, cfg, trees, strategy.for_body(body));
+        ctx.compute_liveness();
+        ctx.find_ranges();
+        ctx.allocate();
+        ctx.spill(max_locals);
+        ctx.results
+    }
+
+    pub fn is_spilled(&self, local: Local) -> bool {
+        self.spill_slots[local].is_some()
     }
 }
 
@@ -26,6 +110,7 @@ struct Context<'a> {
     cfg: &'a CFGInfo,
     trees: &'a Trees,
     results: Localifier,
+    strategy: AllocStrategy,
 
     /// Precise liveness for each block: live Values at the end.
     block_end_live: PerEntity<Block, HashSet<Value>>,
@@ -33,7 +118,11 @@ struct Context<'a> {
     /// Liveranges for each Value, in an arbitrary index space
     /// (concretely, the span of first to last instruction visit step
     /// index in an RPO walk over the function body).
-    ranges: HashMap<Value, Range<usize>>,
+    ranges: PerEntity<Value, Option<Range<usize>>>,
+    /// The individual per-block segments making up each Value's
+    /// liverange, sorted and coalesced. The gaps between segments are
+    /// lifetime holes.
+    segments: PerEntity<Value, Vec<Range<usize>>>,
     /// Number of points.
     points: usize,
 }
@@ -74,7 +163,7 @@ impl<'a, V: Visitor> BlockVisitor<'a, V> {
         self.visitor.pre_term();
 
         for &inst in self.body.blocks[block].insts.iter().rev() {
-            if self.trees.owner.contains_key(&inst) || self.trees.remat.contains(&inst) {
+            if self.trees.is_inline(inst) {
                 continue;
             }
             self.visitor.post_inst(inst);
@@ -107,7 +196,7 @@ impl<'a, V: Visitor> BlockVisitor<'a, V> {
             self.visit_use(value);
             return;
         }
-        if self.trees.owner.contains_key(&value) {
+        if self.trees.owner[value].is_some() {
             // If this is a treeified value, then don't process the use,
             // but process the instruction directly here.
             self.visit_inst(value, /* root = */ false);
@@ -119,7 +208,12 @@ impl<'a, V: Visitor> BlockVisitor<'a, V> {
 }
 
 impl<'a> Context<'a> {
-    fn new(body: &'a FunctionBody, cfg: &'a CFGInfo, trees: &'a Trees) -> Self {
+    fn new(
+        body: &'a FunctionBody,
+        cfg: &'a CFGInfo,
+        trees: &'a Trees,
+        strategy: AllocStrategy,
+    ) -> Self {
         let mut results = Localifier::default();
 
         // Create locals for function args.
@@ -133,8 +227,10 @@ impl<'a> Context<'a> {
             cfg,
             trees,
             results,
+            strategy,
             block_end_live: PerEntity::default(),
-            ranges: HashMap::default(),
+            ranges: PerEntity::default(),
+            segments: PerEntity::default(),
             points: 0,
         }
     }
@@ -161,7 +257,7 @@ impl<'a> Context<'a> {
             visitor.visit_block(block);
             let live = visitor.visitor.live;
 
-            for &pred in &self.body.blocks[block].preds {
+            for &pred in self.body.preds(block) {
                 let pred_live = &mut self.block_end_live[pred];
                 let mut changed = false;
                 for &value in &live {
@@ -182,7 +278,8 @@ impl<'a> Context<'a> {
         struct LiveRangeVisitor<'b> {
             point: &'b mut usize,
             live: HashMap<Value, usize>,
-            ranges: &'b mut HashMap<Value, Range<usize>>,
+            ranges: &'b mut PerEntity<Value, Option<Range<usize>>>,
+            segments: &'b mut PerEntity<Value, Vec<Range<usize>>>,
         }
         impl<'b> Visitor for LiveRangeVisitor<'b> {
             fn pre_params(&mut self) {
@@ -203,7 +300,8 @@ impl<'a> Context<'a> {
                 } else {
                     *self.point..(*self.point + 1)
                 };
-                let existing_range = self.ranges.entry(value).or_insert(range.clone());
+                self.segments[value].push(range.clone());
+                let existing_range = self.ranges[value].get_or_insert(range.clone());
                 existing_range.start = std::cmp::min(existing_range.start, range.start);
                 existing_range.end = std::cmp::max(existing_range.end, range.end);
             }
@@ -214,6 +312,7 @@ impl<'a> Context<'a> {
                 live: HashMap::default(),
                 point: &mut point,
                 ranges: &mut self.ranges,
+                segments: &mut self.segments,
             };
             let mut visitor = BlockVisitor::new(&self.body, &self.trees, visitor);
             // Live-outs to succ blocks: in this block-local
@@ -233,16 +332,78 @@ impl<'a> Context<'a> {
         }
 
         self.points = point + 1;
+
+        // Sort and coalesce segments.
+        for value in self.body.values.iter() {
+            let segments = &mut self.segments[value];
+            if segments.is_empty() {
+                continue;
+            }
+            segments.sort_unstable_by_key(|range| range.start);
+            let mut out: Vec<Range<usize>> = vec![];
+            for range in segments.drain(..) {
+                match out.last_mut() {
+                    Some(last) if last.end >= range.start => {
+                        last.end = std::cmp::max(last.end, range.end);
+                    }
+                    _ => out.push(range),
+                }
+            }
+            *segments = out;
+        }
+    }
+
+    fn is_entry_param(&self, value: Value) -> bool {
+        match &self.body.values[value] {
+            &ValueDef::BlockParam(b, _, _) => b == self.body.entry,
+            _ => false,
+        }
     }
 
     fn allocate(&mut self) {
-        // Sort values by ranges' starting points, then value to break ties.
-        let mut ranges: Vec<(Value, std::ops::Range<usize>)> =
-            self.ranges.iter().map(|(k, v)| (*k, v.clone())).collect();
-        ranges.sort_unstable_by_key(|(val, range)| (range.start, *val));
+        match self.strategy {
+            AllocStrategy::LinearScan => self.allocate_linear_scan(),
+            AllocStrategy::Binpack => self.allocate_binpack(),
+            AllocStrategy::Auto { .. } => unreachable!("strategy should be resolved"),
+        }
+    }
+
+    /// The values for which `start` gives a point, with that point, in
+    /// order of it, then of value. Points are bounded by `self.points`,
+    /// so they are bucketed rather than sorted.
+    fn by_start<F: Fn(Value) -> Option<usize>>(&self, start: F) -> Vec<(usize, Value)> {
+        let values = self
+            .body
+            .values
+            .iter()
+            .filter_map(|value| Some((start(value)?, value)))
+            .collect::<Vec<_>>();
+        // The number of values at each point, then the position of the
+        // next one in the order.
+        let mut positions = vec![0; self.points + 1];
+        for &(point, _) in &values {
+            positions[point] += 1;
+        }
+        let mut next = 0;
+        for position in &mut positions {
+            let count = *position;
+            *position = next;
+            next += count;
+        }
+        let mut ordered = vec![(0, Value::invalid()); values.len()];
+        for (point, value) in values {
+            ordered[positions[point]] = (point, value);
+            positions[point] += 1;
+        }
+        ordered
+    }
+
+    fn allocate_linear_scan(&mut self) {
+        // Order values by ranges' starting points, then value to break ties.
+        let ranges = self.by_start(|value| Some(self.ranges[value].as_ref()?.start));
 
         // Keep a list of expiring Locals by expiry point.
-        let mut expiring: HashMap<usize, SmallVec<[(Type, Local); 8]>> = HashMap::new();
+        let mut expiring: Vec<Vec<(Type, Local)>> = vec![vec![]; self.points + 1];
 
         // Iterate over allocation space, processing range starts (at
         // which point we allocate) and ends (at which point we add to
@@ -254,16 +415,15 @@ impl<'a> Context<'a> {
             // Process ends. (Ends are exclusive, so we do them
             // first; another range can grab the local at the same
             // point index in this same iteration.)
-            if let Some(expiring) = expiring.remove(&i) {
-                for (ty, local) in expiring {
-                    log::trace!(" -> expiring {} of type {} back to freelist", local, ty);
-                    freelist.entry(ty).or_insert_with(|| vec![]).push(local);
-                }
+            for (ty, local) in std::mem::take(&mut expiring[i]) {
+                log::trace!(" -> expiring {} of type {} back to freelist", local, ty);
+                freelist.entry(ty).or_insert_with(|| vec![]).push(local);
             }
 
             // Process starts.
-            while range_idx < ranges.len() && ranges[range_idx].1.start == i {
-                let (value, range) = ranges[range_idx].clone();
+            while range_idx < ranges.len() && ranges[range_idx].0 == i {
+                let value = ranges[range_idx].1;
+                let range = self.ranges[value].clone().unwrap();
                 range_idx += 1;
                 log::trace!(
                     "localify: processing range for {}: {}..{}",
@@ -274,20 +434,43 @@ impl<'a> Context<'a> {
 
                 // If the value is an arg on block0, ignore; these
                 // already have fixed locations.
-                if let &ValueDef::BlockParam(b, _, _) = &self.body.values[value] {
-                    if b == self.body.entry {
-                        continue;
-                    }
+                if self.is_entry_param(value) {
+                    continue;
                 }
 
-                // Try getting a local from the freelist; if not,
-                // allocate a new one.
+                let hint = self.body.alloc_hints[value];
+
+                // Try getting a local from the freelist (preferring
+                // the one named by a hint, if any); if not, allocate
+                // a new one.
                 let mut allocs = smallvec![];
-                let expiring = expiring.entry(range.end).or_insert_with(|| smallvec![]);
-                for &ty in self.body.values[value].tys(&self.body.type_pool) {
-                    let local = freelist
-                        .get_mut(&ty)
-                        .and_then(|v| v.pop())
+                let expiring = &mut expiring[range.end];
+                for (i, &ty) in self.body.values[value]
+                    .tys(&self.body.type_pool)
+                    .iter()
+                    .enumerate()
+                {
+                    if hint == Some(AllocHint::Fresh) {
+                        // Never returned to the freelist, so never
+                        // shared.
+                        let local = self.results.locals.push(ty);
+                        log::trace!(" -> got fresh local {} of type {}", local, ty);
+                        allocs.push(local);
+                        continue;
+                    }
+                    let hinted = match hint {
+                        Some(AllocHint::ShareWith(other)) if i == 0 => {
+                            let other = self.body.resolve_alias(other);
+                            let other_local = self.results.values[other].first().copied();
+                            freelist.get_mut(&ty).and_then(|v| {
+                                let idx = v.iter().position(|&l| Some(l) == other_local)?;
+                                Some(v.remove(idx))
+                            })
+                        }
+                        _ => None,
+                    };
+                    let local = hinted
+                        .or_else(|| freelist.get_mut(&ty).and_then(|v| v.pop()))
                         .unwrap_or_else(|| {
                             log::trace!(" -> allocating new local of type {}", ty);
                             self.results.locals.push(ty)
@@ -301,6 +484,199 @@ impl<'a> Context<'a> {
         }
     }
 
+    /// Compute, for each value, the values it is connected to by a
+    /// blockparam transfer (branch arg to blockparam). Sharing a local
+    /// with one of these makes the transfer a no-op.
+    fn compute_affinities(&self) -> PerEntity<Value, SmallVec<[Value; 2]>> {
+        let mut affinities: PerEntity<Value, SmallVec<[Value; 2]>> = PerEntity::default();
+        for &block in self.cfg.rpo.values() {
+            self.body.blocks[block].terminator.visit_targets(|target| {
+                for (&arg, &(_, param)) in target
+                    .args
+                    .iter()
+                    .zip(self.body.blocks[target.block].params.iter())
+                {
+                    let arg = self.body.resolve_alias(arg);
+                    if arg == param || self.body.values[arg].tys(&self.body.type_pool).len() != 1 {
+                        continue;
+                    }
+                    affinities[arg].push(param);
+                    affinities[param].push(arg);
+                }
+            });
+        }
+        affinities
+    }
+
+    fn allocate_binpack(&mut self) {
+        // Process values in order of their first live point (then
+        // value index to break ties), as for the linear scan.
+        let values = self.by_start(|value| Some(self.segments[value].first()?.start));
+
+        let affinities = self.compute_affinities();
+
+        // Occupied segments of each (non-arg) local, sorted by start.
+        let mut occupied: HashMap<Local, Vec<Range<usize>>> = HashMap::new();
+        // All non-arg locals of a given type, in allocation order.
+        let mut bins: HashMap<Type, Vec<Local>> = HashMap::new();
+
+        fn fits(occupied: &[Range<usize>], segments: &[Range<usize>]) -> bool {
+            segments.iter().all(|seg| {
+                // Find the first occupied range that ends after this
+                // segment starts; it must start at or after this
+                // segment's end.
+                let idx = occupied.partition_point(|occ| occ.end <= seg.start);
+                idx == occupied.len() || occupied[idx].start >= seg.end
+            })
+        }
+        fn occupy(occupied: &mut Vec<Range<usize>>, segments: &[Range<usize>]) {
+            for seg in segments {
+                let idx = occupied.partition_point(|occ| occ.start < seg.start);
+                occupied.insert(idx, seg.clone());
+            }
+        }
+
+        for (_, value) in values {
+            if self.is_entry_param(value) {
+                continue;
+            }
+            let segments = self.segments[value].clone();
+            log::trace!("localify: binpacking {}: {:?}", value, segments);
+
+            let hint = self.body.alloc_hints[value];
+            let hinted: Option<Value> = match hint {
+                Some(AllocHint::ShareWith(other)) => Some(self.body.resolve_alias(other)),
+                _ => None,
+            };
+
+            let mut allocs: SmallVec<[Local; 2]> = smallvec![];
+            for (i, &ty) in self.body.values[value]
+                .tys(&self.body.type_pool)
+                .iter()
+                .enumerate()
+            {
+                if hint == Some(AllocHint::Fresh) {
+                    // Not entered into the bins or occupancy map, so
+                    // no other value can be packed into it.
+                    let local = self.results.locals.push(ty);
+                    log::trace!(" -> got fresh local {} of type {}", local, ty);
+                    allocs.push(local);
+                    continue;
+                }
+
+                // First chance: a local already holding the value
+                // named by a hint, or a value that we are connected to
+                // via a blockparam transfer.
+                let preferred = if i == 0 {
+                    hinted
+                        .into_iter()
+                        .chain(affinities[value].iter().copied())
+                        .filter_map(|related| self.results.values[related].first().copied())
+                        .find(|&local| {
+                            self.results.locals[local] == ty
+                                && occupied
+                                    .get(&local)
+                                    .map(|occ| fits(&occ[..], &segments[..]))
+                                    .unwrap_or(false)
+                        })
+                } else {
+                    None
+                };
+                // Second chance: first fit among all locals of this
+                // type; otherwise allocate a new local.
+                let first_fit = || {
+                    bins.get(&ty).and_then(|bins| {
+                        bins.iter()
+                            .copied()
+                            .find(|local| fits(&occupied[local][..], &segments[..]))
+                    })
+                };
+                let local = match preferred.or_else(first_fit) {
+                    Some(local) => local,
+                    None => {
+                        log::trace!(" -> allocating new local of type {}", ty);
+                        let local = self.results.locals.push(ty);
+                        bins.entry(ty).or_default().push(local);
+                        occupied.insert(local, vec![]);
+                        local
+                    }
+                };
+                log::trace!(" -> got local {} of type {}", local, ty);
+                occupy(occupied.get_mut(&local).unwrap(), &segments[..]);
+                allocs.push(local);
+            }
+            self.results.values[value] = allocs;
+        }
+    }
+
+    /// Spill the least-frequently-accessed locals to the shadow stack
+    /// until at most `max_locals` locals remain. A local is spilled as
+    /// a whole, so every value allocated to it moves to its slot; since
+    /// those values' liveranges never overlap, they can share the slot
+    /// just as they shared the local.
+    fn spill(&mut self, max_locals: usize) {
+        if self.results.locals.len() <= max_locals {
+            return;
+        }
+
+        // Count static accesses (defs and uses) of each value.
+        struct AccessCounter<'b> {
+            counts: &'b mut HashMap<Value, usize>,
+        }
+        impl<'b> Visitor for AccessCounter<'b> {
+            fn visit_use(&mut self, value: Value) {
+                *self.counts.entry(value).or_insert(0) += 1;
+            }
+            fn visit_def(&mut self, value: Value) {
+                *self.counts.entry(value).or_insert(0) += 1;
+            }
+        }
+        let mut counts = HashMap::new();
+        let mut visitor = BlockVisitor::new(
+            self.body,
+            self.trees,
+            AccessCounter {
+                counts: &mut counts,
+            },
+        );
+        for &block in self.cfg.rpo.values() {
+            visitor.visit_block(block);
+        }
+
+        // Weigh each local by the accesses of all values assigned to it.
+        let mut weights: PerEntity<Local, usize> = PerEntity::default();
+        for (&value, &count) in &counts {
+            for &local in &self.results.values[value] {
+                weights[local] += count;
+            }
+        }
+
+        let mut candidates = self
+            .results
+            .locals
+            .entries()
+            .skip(self.body.n_params)
+            .filter(|(_, &ty)| matches!(ty, Type::I32 | Type::I64 | Type::F32 | Type::F64))
+            .map(|(local, _)| (weights[local], local))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+
+        let to_spill = self.results.locals.len() + SPILL_SCRATCH_LOCALS - max_locals;
+        log::debug!(
+            "localify: {} locals exceeds limit of {}; spilling {}",
+            self.results.locals.len(),
+            max_locals,
+            to_spill
+        );
+        for (i, &(weight, local)) in candidates.iter().take(to_spill).enumerate() {
+            log::trace!(" -> spilling {} (weight {})", local, weight);
+            self.results.spill_slots[local] = Some(i as u32 * SPILL_SLOT_SIZE);
+        }
+        let spilled = std::cmp::min(to_spill, candidates.len()) as u32;
+        // Keep the frame 16-byte aligned, as the stack pointer is.
+        self.results.frame_size = (spilled * SPILL_SLOT_SIZE + 15) & !15;
+    }
+
     fn compute(mut self) -> Localifier {
         self.compute_liveness();
         self.find_ranges();
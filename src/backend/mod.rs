@@ -2,24 +2,83 @@
 
 use crate::cfg::CFGInfo;
 use crate::entity::EntityRef;
-use crate::ir::{ExportKind, FuncDecl, FunctionBody, ImportKind, Module, Type, Value, ValueDef};
-use crate::Operator;
+use crate::entity::PerEntity;
+use crate::errors::{locate, WaffleError};
+use crate::ir::{
+    CustomSection, CustomSectionPlacement, DataSegmentKind, ElementItems, ElementSegmentKind,
+    ExportKind, Func, FuncDecl, FunctionBody, GlobalInit, ImportKind, Local, Module, SegmentOffset,
+    SpillConfig, Type, Value, ValueDef,
+};
+use crate::passes::determinism;
+use crate::{Operator, Timeline};
 use anyhow::Result;
+#[cfg(feature = "parallel")]
 use rayon::prelude::*;
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{Seek, Write};
+use std::time::Duration;
 
 pub mod stackify;
-use stackify::{Context as StackifyContext, WasmBlock};
+use stackify::{Context as StackifyContext, WasmBlock, WasmLabel};
 pub mod treeify;
 use treeify::Trees;
 pub mod localify;
+pub use localify::AllocStrategy;
 use localify::Localifier;
+mod cache;
+mod dwarf;
+pub mod encoder;
+mod layout;
+mod options;
+mod size;
+mod sourcemap;
+#[cfg(feature = "wat")]
+mod wat;
+pub(crate) use cache::CompileCache;
+use cache::CompiledBody;
+#[cfg(feature = "wat")]
+pub use encoder::WatEncoder;
+pub use encoder::{BinaryEncoder, FunctionSink, ModuleEncoder, WriterEncoder};
+use encoder::{MeasuringEncoder, SeekWriterEncoder};
+use layout::{CodeLayout, FuncLayout};
+pub use options::{CodegenOptions, NamePolicy, Structuring};
+pub use size::{FuncSize, SectionSize, SizeProfile};
+pub use sourcemap::{SourceMap, SourceMapping};
+#[cfg(feature = "wat")]
+pub use wat::{compile_to_annotated_wat, disassemble};
 
 pub struct WasmFuncBackend<'a> {
     body: &'a FunctionBody,
     trees: Trees,
     ctrl: Vec<WasmBlock<'a>>,
     locals: Localifier,
+    spill_config: Option<SpillConfig>,
+    structuring: Structuring,
+    /// Wasm local index of each non-spilled local.
+    local_indices: PerEntity<Local, u32>,
+    /// Scratch local used to store each type of spilled value.
+    spill_scratch: Vec<(Type, u32)>,
+    /// Local holding the base address of the spill frame. The code
+    /// being compiled may itself move the stack pointer (e.g. for its
+    /// own shadow-stack frame), so slots are addressed relative to the
+    /// value of the stack pointer at entry instead.
+    frame_pointer: u32,
+}
+
+struct OffsetTrackingSink {
+    func: wasm_encoder::Function,
+    offsets: Vec<(u32, u32)>,
+}
+
+impl FunctionSink for OffsetTrackingSink {
+    fn instruction(&mut self, inst: &wasm_encoder::Instruction<'_>) {
+        self.func.instruction(inst);
+    }
+
+    fn source_offset(&mut self, offset: u32) {
+        self.offsets.push((self.func.byte_len() as u32, offset));
+    }
 }
 
 macro_rules! op {
@@ -28,8 +87,31 @@ macro_rules! op {
     };
 }
 
+/// The Wasm depth of `label`, counting the blocks each of the
+/// innermost `frames` it branches out of was lowered to.
+fn label_depth(frames: &[u32], label: WasmLabel) -> u32 {
+    frames.iter().rev().take(label.index() as usize).sum()
+}
+
 impl<'a> WasmFuncBackend<'a> {
     pub fn new(body: &'a FunctionBody) -> Result<WasmFuncBackend<'a>> {
+        Self::with_spill_config(body, None)
+    }
+
+    pub fn with_spill_config(
+        body: &'a FunctionBody,
+        spill_config: Option<&SpillConfig>,
+    ) -> Result<WasmFuncBackend<'a>> {
+        Self::with_options(body, spill_config, &CodegenOptions::default())
+    }
+
+    /// Compile with the structuring and locals strategy of `options`.
+    /// Its other options apply to whole modules.
+    pub fn with_options(
+        body: &'a FunctionBody,
+        spill_config: Option<&SpillConfig>,
+        options: &CodegenOptions,
+    ) -> Result<WasmFuncBackend<'a>> {
         body.validate()?;
         log::debug!("Backend compiling:\n{}\n", body.display_verbose("| ", None));
         let cfg = CFGInfo::new(body);
@@ -38,28 +120,178 @@ impl<'a> WasmFuncBackend<'a> {
         log::debug!("Trees:\n{:?}\n", trees);
         let ctrl = StackifyContext::new(body, &cfg)?.compute();
         log::debug!("Ctrl:\n{:?}\n", ctrl);
-        let locals = Localifier::compute(body, &cfg, &trees);
+        let locals = match spill_config {
+            Some(config) => Localifier::compute_with_limit(
+                body,
+                &cfg,
+                &trees,
+                options.locals,
+                config.max_locals,
+            ),
+            None => Localifier::compute_with_strategy(body, &cfg, &trees, options.locals),
+        };
         log::debug!("Locals:\n{:?}\n", locals);
+
+        // Number the locals that remain, then add a scratch local
+        // for each type of spilled local.
+        let mut local_indices = PerEntity::default();
+        let mut next_index = 0;
+        for local in locals.locals.iter() {
+            if !locals.is_spilled(local) {
+                local_indices[local] = next_index;
+                next_index += 1;
+            }
+        }
+        let mut spill_scratch = vec![];
+        let frame_pointer = next_index;
+        if locals.frame_size > 0 {
+            next_index += 1;
+        }
+        for ty in [Type::I32, Type::I64, Type::F32, Type::F64] {
+            if locals
+                .locals
+                .entries()
+                .any(|(local, &local_ty)| local_ty == ty && locals.is_spilled(local))
+            {
+                spill_scratch.push((ty, next_index));
+                next_index += 1;
+            }
+        }
+
         Ok(WasmFuncBackend {
             body,
             trees,
             ctrl,
             locals,
+            spill_config: spill_config.cloned(),
+            structuring: options.structuring,
+            local_indices,
+            spill_scratch,
+            frame_pointer,
         })
     }
 
+    /// The types of the function's non-param locals, in order.
+    pub fn locals(&self) -> Vec<Type> {
+        self.locals
+            .locals
+            .entries()
+            .skip(self.body.blocks[self.body.entry].params.len())
+            .filter(|&(local, _)| !self.locals.is_spilled(local))
+            .map(|(_, &ty)| ty)
+            .chain(if self.locals.frame_size > 0 {
+                Some(Type::I32)
+            } else {
+                None
+            })
+            .chain(self.spill_scratch.iter().map(|&(ty, _)| ty))
+            .collect::<Vec<_>>()
+    }
+
+    /// What the local allocator decided: a line for each emitted local
+    /// and spill slot naming the values it holds (`v3.1` for the
+    /// second result of `v3`), then the locals spilling needs.
+    pub(crate) fn local_assignments(&self) -> Vec<String> {
+        let mut holds: PerEntity<Local, Vec<String>> = PerEntity::default();
+        for value in self.body.values.iter() {
+            let locals = &self.locals.values[value];
+            for (i, &local) in locals.iter().enumerate() {
+                holds[local].push(match locals.len() {
+                    1 => value.to_string(),
+                    _ => format!("{}.{}", value, i),
+                });
+            }
+        }
+        let mut lines = self
+            .locals
+            .locals
+            .entries()
+            .map(|(local, ty)| {
+                let at = match self.locals.spill_slots[local] {
+                    Some(offset) => format!("frame+{}", offset),
+                    None => format!("local {}", self.local_indices[local]),
+                };
+                format!("{}: {} = {}", at, ty, holds[local].join(", "))
+            })
+            .collect::<Vec<_>>();
+        // Spill slots go after the locals, in frame order.
+        lines.sort_by_key(|line| line.starts_with("frame+"));
+        if self.locals.frame_size > 0 {
+            lines.push(format!("local {}: i32 = frame pointer", self.frame_pointer));
+        }
+        for &(ty, index) in &self.spill_scratch {
+            lines.push(format!("local {}: {} = spill scratch", index, ty));
+        }
+        lines
+    }
+
+    fn encoder_locals(&self) -> Vec<(u32, wasm_encoder::ValType)> {
+        self.locals()
+            .into_iter()
+            .map(|ty| (1, wasm_encoder::ValType::from(ty)))
+            .collect()
+    }
+
+    /// Names for the emitted locals, given names for the original
+    /// function's locals. Each emitted local takes the name of the
+    /// first original local that one of its values came from.
+    pub fn local_names(&self, orig_names: &BTreeMap<u32, String>) -> BTreeMap<u32, String> {
+        let mut names: BTreeMap<u32, String> = BTreeMap::new();
+        let entry_params = self.body.blocks[self.body.entry]
+            .params
+            .iter()
+            .enumerate()
+            .map(|(i, &(_, value))| (value, Some(Local::new(i))));
+        let other_values = self
+            .body
+            .values
+            .iter()
+            .map(|value| (value, self.body.value_locals[value]));
+        for (value, orig_local) in entry_params.chain(other_values) {
+            let orig_name = match orig_local.and_then(|l| orig_names.get(&(l.index() as u32))) {
+                Some(name) => name,
+                None => continue,
+            };
+            if let Some(&local) = self.locals.values[value].first() {
+                if !self.locals.is_spilled(local) {
+                    names
+                        .entry(self.local_indices[local])
+                        .or_insert_with(|| orig_name.clone());
+                }
+            }
+        }
+        names
+    }
+
     pub fn compile(&self) -> Result<wasm_encoder::Function> {
-        let mut func = wasm_encoder::Function::new(
-            self.locals
-                .locals
-                .values()
-                .skip(self.body.blocks[self.body.entry].params.len())
-                .map(|&ty| (1, wasm_encoder::ValType::from(ty)))
-                .collect::<Vec<_>>(),
-        );
+        let mut func = wasm_encoder::Function::new(self.encoder_locals());
+        self.compile_into(&mut func)?;
+        log::debug!("Compiled to:\n{:?}\n", func);
+        Ok(func)
+    }
+
+    /// Like `compile()`, but also returns, for each lowered operator
+    /// that came from the original module, its offset within the
+    /// emitted body paired with its offset in the original module.
+    pub fn compile_with_offsets(&self) -> Result<(wasm_encoder::Function, Vec<(u32, u32)>)> {
+        let mut sink = OffsetTrackingSink {
+            func: wasm_encoder::Function::new(self.encoder_locals()),
+            offsets: vec![],
+        };
+        self.compile_into(&mut sink)?;
+        Ok((sink.func, sink.offsets))
+    }
 
+    /// Lower the function body's instructions (not including local
+    /// declarations; see `locals()`) into the given sink.
+    pub fn compile_into(&self, func: &mut impl FunctionSink) -> Result<()> {
+        if self.locals.frame_size > 0 {
+            self.lower_frame_alloc(func);
+        }
+
+        let mut frames = vec![];
         for block in &self.ctrl {
-            self.lower_block(block, &mut func);
+            self.lower_block(block, &mut frames, func);
         }
 
         // If the last block was a Block, Loop or If, then the type
@@ -74,33 +306,79 @@ impl<'a> WasmFuncBackend<'a> {
         }
         func.instruction(&wasm_encoder::Instruction::End);
 
-        log::debug!("Compiled to:\n{:?}\n", func);
-
-        Ok(func)
+        Ok(())
     }
 
-    fn lower_block(&self, block: &WasmBlock<'_>, func: &mut wasm_encoder::Function) {
+    /// Lower `block`, within `frames`: for each enclosing control
+    /// frame of the stackified body, innermost last, the number of
+    /// Wasm blocks it was lowered to.
+    fn lower_block(
+        &self,
+        block: &WasmBlock<'_>,
+        frames: &mut Vec<u32>,
+        func: &mut impl FunctionSink,
+    ) {
         match block {
             WasmBlock::Block { body, .. } => {
                 func.instruction(&wasm_encoder::Instruction::Block(
                     wasm_encoder::BlockType::Empty,
                 ));
-                for sub_block in &body[..] {
-                    self.lower_block(sub_block, func);
-                }
+                self.lower_blocks(body, 1, frames, func);
                 func.instruction(&wasm_encoder::Instruction::End);
             }
             WasmBlock::Loop { body, .. } => {
                 func.instruction(&wasm_encoder::Instruction::Loop(
                     wasm_encoder::BlockType::Empty,
                 ));
-                for sub_block in &body[..] {
-                    self.lower_block(sub_block, func);
-                }
+                self.lower_blocks(body, 1, frames, func);
                 func.instruction(&wasm_encoder::Instruction::End);
             }
             WasmBlock::Br { target } => {
-                func.instruction(&wasm_encoder::Instruction::Br(target.index()));
+                func.instruction(&wasm_encoder::Instruction::Br(label_depth(frames, *target)));
+            }
+            WasmBlock::If {
+                cond,
+                if_true,
+                if_false,
+            } if self.structuring == Structuring::Blocks => {
+                // block
+                //   block
+                //     <cond> br_if 0
+                //     <if_false> br 1
+                //   end
+                //   <if_true>
+                // end
+                //
+                // or, without an else, `block <cond> i32.eqz br_if 0
+                // <if_true> end`.
+                func.instruction(&wasm_encoder::Instruction::Block(
+                    wasm_encoder::BlockType::Empty,
+                ));
+                if if_false.is_empty() {
+                    self.lower_value(*cond, func);
+                    func.instruction(&wasm_encoder::Instruction::I32Eqz);
+                    func.instruction(&wasm_encoder::Instruction::BrIf(0));
+                } else {
+                    func.instruction(&wasm_encoder::Instruction::Block(
+                        wasm_encoder::BlockType::Empty,
+                    ));
+                    self.lower_value(*cond, func);
+                    func.instruction(&wasm_encoder::Instruction::BrIf(0));
+                    self.lower_blocks(if_false, 2, frames, func);
+                    if !matches!(
+                        if_false.last(),
+                        Some(
+                            WasmBlock::Br { .. }
+                                | WasmBlock::Return { .. }
+                                | WasmBlock::Unreachable
+                        )
+                    ) {
+                        func.instruction(&wasm_encoder::Instruction::Br(1));
+                    }
+                    func.instruction(&wasm_encoder::Instruction::End);
+                }
+                self.lower_blocks(if_true, 1, frames, func);
+                func.instruction(&wasm_encoder::Instruction::End);
             }
             WasmBlock::If {
                 cond,
@@ -111,14 +389,10 @@ impl<'a> WasmFuncBackend<'a> {
                 func.instruction(&wasm_encoder::Instruction::If(
                     wasm_encoder::BlockType::Empty,
                 ));
-                for sub_block in &if_true[..] {
-                    self.lower_block(sub_block, func);
-                }
+                self.lower_blocks(if_true, 1, frames, func);
                 if if_false.len() > 0 {
                     func.instruction(&wasm_encoder::Instruction::Else);
-                    for sub_block in &if_false[..] {
-                        self.lower_block(sub_block, func);
-                    }
+                    self.lower_blocks(if_false, 1, frames, func);
                 }
                 func.instruction(&wasm_encoder::Instruction::End);
             }
@@ -132,17 +406,17 @@ impl<'a> WasmFuncBackend<'a> {
                     Cow::Owned(
                         targets
                             .iter()
-                            .map(|label| label.index())
+                            .map(|&label| label_depth(frames, label))
                             .collect::<Vec<_>>(),
                     ),
-                    default.index(),
+                    label_depth(frames, *default),
                 ));
             }
             WasmBlock::Leaf { block } => {
                 for &inst in &self.body.blocks[*block].insts {
                     // If this value is "owned", do nothing: it will be lowered in
                     // the one place it's used.
-                    if self.trees.owner.contains_key(&inst) || self.trees.remat.contains(&inst) {
+                    if self.trees.is_inline(inst) {
                         continue;
                     }
                     if let &ValueDef::Operator(..) = &self.body.values[inst] {
@@ -169,6 +443,9 @@ impl<'a> WasmFuncBackend<'a> {
                 for &value in &values[..] {
                     self.lower_value(value, func);
                 }
+                if self.locals.frame_size > 0 {
+                    self.lower_frame_free(func);
+                }
                 func.instruction(&wasm_encoder::Instruction::Return);
             }
             WasmBlock::Unreachable => {
@@ -177,10 +454,26 @@ impl<'a> WasmFuncBackend<'a> {
         }
     }
 
-    fn lower_value(&self, value: Value, func: &mut wasm_encoder::Function) {
+    /// Lower `blocks`, in a control frame lowered to `depth` Wasm
+    /// blocks.
+    fn lower_blocks(
+        &self,
+        blocks: &[WasmBlock<'_>],
+        depth: u32,
+        frames: &mut Vec<u32>,
+        func: &mut impl FunctionSink,
+    ) {
+        frames.push(depth);
+        for block in blocks {
+            self.lower_block(block, frames, func);
+        }
+        frames.pop();
+    }
+
+    fn lower_value(&self, value: Value, func: &mut impl FunctionSink) {
         log::trace!("lower_value: value {}", value);
         let value = self.body.resolve_alias(value);
-        if self.trees.remat.contains(&value) {
+        if self.trees.remat[value] {
             self.lower_inst(value, /* root = */ false, func);
         } else {
             let local = match &self.body.values[value] {
@@ -190,11 +483,13 @@ impl<'a> WasmFuncBackend<'a> {
                 }
                 _ => unreachable!(),
             };
-            func.instruction(&wasm_encoder::Instruction::LocalGet(local.index() as u32));
+            func.enter_value(value);
+            self.lower_local_get(local, func);
+            func.leave_value();
         }
     }
 
-    fn lower_set_value(&self, value: Value, func: &mut wasm_encoder::Function) {
+    fn lower_set_value(&self, value: Value, func: &mut impl FunctionSink) {
         debug_assert_eq!(
             self.locals.values[value].len(),
             1,
@@ -202,34 +497,126 @@ impl<'a> WasmFuncBackend<'a> {
             value
         );
         let local = self.locals.values[value][0];
-        func.instruction(&wasm_encoder::Instruction::LocalSet(local.index() as u32));
+        func.enter_value(value);
+        self.lower_local_set(local, func);
+        func.leave_value();
+    }
+
+    fn lower_local_get(&self, local: Local, func: &mut impl FunctionSink) {
+        match self.locals.spill_slots[local] {
+            Some(offset) => {
+                let memarg = self.spill_memarg(local, offset);
+                func.instruction(&wasm_encoder::Instruction::LocalGet(self.frame_pointer));
+                func.instruction(&match self.locals.locals[local] {
+                    Type::I32 => wasm_encoder::Instruction::I32Load(memarg),
+                    Type::I64 => wasm_encoder::Instruction::I64Load(memarg),
+                    Type::F32 => wasm_encoder::Instruction::F32Load(memarg),
+                    Type::F64 => wasm_encoder::Instruction::F64Load(memarg),
+                    ty => unreachable!("Spilled local of type {}", ty),
+                });
+            }
+            None => {
+                func.instruction(&wasm_encoder::Instruction::LocalGet(
+                    self.local_indices[local],
+                ));
+            }
+        }
     }
 
-    fn lower_inst(&self, value: Value, root: bool, func: &mut wasm_encoder::Function) {
+    fn lower_local_set(&self, local: Local, func: &mut impl FunctionSink) {
+        match self.locals.spill_slots[local] {
+            Some(offset) => {
+                // The address must be below the value on the stack, so
+                // stash the value in a scratch local first.
+                let ty = self.locals.locals[local];
+                let memarg = self.spill_memarg(local, offset);
+                let scratch = self
+                    .spill_scratch
+                    .iter()
+                    .find(|&&(scratch_ty, _)| scratch_ty == ty)
+                    .unwrap()
+                    .1;
+                func.instruction(&wasm_encoder::Instruction::LocalSet(scratch));
+                func.instruction(&wasm_encoder::Instruction::LocalGet(self.frame_pointer));
+                func.instruction(&wasm_encoder::Instruction::LocalGet(scratch));
+                func.instruction(&match ty {
+                    Type::I32 => wasm_encoder::Instruction::I32Store(memarg),
+                    Type::I64 => wasm_encoder::Instruction::I64Store(memarg),
+                    Type::F32 => wasm_encoder::Instruction::F32Store(memarg),
+                    Type::F64 => wasm_encoder::Instruction::F64Store(memarg),
+                    ty => unreachable!("Spilled local of type {}", ty),
+                });
+            }
+            None => {
+                func.instruction(&wasm_encoder::Instruction::LocalSet(
+                    self.local_indices[local],
+                ));
+            }
+        }
+    }
+
+    fn spill_memarg(&self, local: Local, offset: u32) -> wasm_encoder::MemArg {
+        let align = match self.locals.locals[local] {
+            Type::I64 | Type::F64 => 3,
+            _ => 2,
+        };
+        wasm_encoder::MemArg {
+            offset: offset as u64,
+            align,
+            memory_index: self.spill_config.as_ref().unwrap().memory.index() as u32,
+        }
+    }
+
+    fn lower_frame_alloc(&self, func: &mut impl FunctionSink) {
+        let sp = self.spill_config.as_ref().unwrap().stack_pointer.index() as u32;
+        func.instruction(&wasm_encoder::Instruction::GlobalGet(sp));
+        func.instruction(&wasm_encoder::Instruction::I32Const(
+            self.locals.frame_size as i32,
+        ));
+        func.instruction(&wasm_encoder::Instruction::I32Sub);
+        func.instruction(&wasm_encoder::Instruction::LocalTee(self.frame_pointer));
+        func.instruction(&wasm_encoder::Instruction::GlobalSet(sp));
+    }
+
+    fn lower_frame_free(&self, func: &mut impl FunctionSink) {
+        let sp = self.spill_config.as_ref().unwrap().stack_pointer.index() as u32;
+        func.instruction(&wasm_encoder::Instruction::LocalGet(self.frame_pointer));
+        func.instruction(&wasm_encoder::Instruction::I32Const(
+            self.locals.frame_size as i32,
+        ));
+        func.instruction(&wasm_encoder::Instruction::I32Add);
+        func.instruction(&wasm_encoder::Instruction::GlobalSet(sp));
+    }
+
+    fn lower_inst(&self, value: Value, root: bool, func: &mut impl FunctionSink) {
         log::trace!("lower_inst: value {} root {}", value, root);
         match &self.body.values[value] {
             &ValueDef::Operator(ref op, args, tys) => {
+                func.enter_value(value);
                 for &arg in &self.body.arg_pool[args] {
                     let arg = self.body.resolve_alias(arg);
-                    if self.trees.owner.contains_key(&arg) || self.trees.remat.contains(&arg) {
+                    if self.trees.is_inline(arg) {
                         log::trace!(" -> arg {} is owned", arg);
                         self.lower_inst(arg, /* root = */ false, func);
                     } else {
                         self.lower_value(arg, func);
                     }
                 }
+                if let Some(offset) = self.body.source_offsets[value] {
+                    func.source_offset(offset);
+                }
                 self.lower_op(op, func);
                 if root {
-                    for &local in &self.locals.values[value] {
-                        func.instruction(
-                            &wasm_encoder::Instruction::LocalSet(local.index() as u32),
-                        );
+                    // The last result is on top of the stack.
+                    for &local in self.locals.values[value].iter().rev() {
+                        self.lower_local_set(local, func);
                     }
                     let leftovers = tys.len() - self.locals.values[value].len();
                     for _ in 0..leftovers {
                         func.instruction(&wasm_encoder::Instruction::Drop);
                     }
                 }
+                func.leave_value();
             }
             &ValueDef::PickOutput(..) => {
                 self.lower_value(value, func);
@@ -238,7 +625,7 @@ impl<'a> WasmFuncBackend<'a> {
         }
     }
 
-    fn lower_op(&self, op: &Operator, func: &mut wasm_encoder::Function) {
+    fn lower_op(&self, op: &Operator, func: &mut impl FunctionSink) {
         let inst = match op {
             Operator::Unreachable => Some(wasm_encoder::Instruction::Unreachable),
             Operator::Nop => None,
@@ -514,6 +901,215 @@ impl<'a> WasmFuncBackend<'a> {
             Operator::MemoryGrow { mem } => {
                 Some(wasm_encoder::Instruction::MemoryGrow(mem.index() as u32))
             }
+            Operator::MemoryAtomicNotify { memory } => Some(
+                wasm_encoder::Instruction::MemoryAtomicNotify(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::MemoryAtomicWait32 { memory } => Some(
+                wasm_encoder::Instruction::MemoryAtomicWait32(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::MemoryAtomicWait64 { memory } => Some(
+                wasm_encoder::Instruction::MemoryAtomicWait64(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::AtomicFence => Some(wasm_encoder::Instruction::AtomicFence),
+            Operator::I32AtomicLoad { memory } => Some(wasm_encoder::Instruction::I32AtomicLoad(
+                wasm_encoder::MemArg::from(*memory),
+            )),
+            Operator::I64AtomicLoad { memory } => Some(wasm_encoder::Instruction::I64AtomicLoad(
+                wasm_encoder::MemArg::from(*memory),
+            )),
+            Operator::I32AtomicLoad8U { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicLoad8U(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicLoad16U { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicLoad16U(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicLoad8U { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicLoad8U(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicLoad16U { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicLoad16U(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicLoad32U { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicLoad32U(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicStore { memory } => Some(wasm_encoder::Instruction::I32AtomicStore(
+                wasm_encoder::MemArg::from(*memory),
+            )),
+            Operator::I64AtomicStore { memory } => Some(wasm_encoder::Instruction::I64AtomicStore(
+                wasm_encoder::MemArg::from(*memory),
+            )),
+            Operator::I32AtomicStore8 { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicStore8(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicStore16 { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicStore16(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicStore8 { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicStore8(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicStore16 { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicStore16(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicStore32 { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicStore32(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmwAdd { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmwAdd(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmwAdd { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmwAdd(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw8AddU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw8AddU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw16AddU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw16AddU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw8AddU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw8AddU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw16AddU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw16AddU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw32AddU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw32AddU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmwSub { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmwSub(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmwSub { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmwSub(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw8SubU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw8SubU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw16SubU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw16SubU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw8SubU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw8SubU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw16SubU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw16SubU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw32SubU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw32SubU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmwAnd { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmwAnd(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmwAnd { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmwAnd(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw8AndU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw8AndU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw16AndU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw16AndU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw8AndU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw8AndU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw16AndU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw16AndU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw32AndU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw32AndU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmwOr { memory } => Some(wasm_encoder::Instruction::I32AtomicRmwOr(
+                wasm_encoder::MemArg::from(*memory),
+            )),
+            Operator::I64AtomicRmwOr { memory } => Some(wasm_encoder::Instruction::I64AtomicRmwOr(
+                wasm_encoder::MemArg::from(*memory),
+            )),
+            Operator::I32AtomicRmw8OrU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw8OrU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw16OrU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw16OrU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw8OrU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw8OrU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw16OrU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw16OrU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw32OrU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw32OrU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmwXor { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmwXor(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmwXor { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmwXor(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw8XorU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw8XorU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw16XorU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw16XorU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw8XorU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw8XorU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw16XorU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw16XorU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw32XorU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw32XorU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmwXchg { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmwXchg(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmwXchg { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmwXchg(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw8XchgU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw8XchgU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw16XchgU { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmw16XchgU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw8XchgU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw8XchgU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw16XchgU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw16XchgU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmw32XchgU { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmw32XchgU(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmwCmpxchg { memory } => Some(
+                wasm_encoder::Instruction::I32AtomicRmwCmpxchg(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I64AtomicRmwCmpxchg { memory } => Some(
+                wasm_encoder::Instruction::I64AtomicRmwCmpxchg(wasm_encoder::MemArg::from(*memory)),
+            ),
+            Operator::I32AtomicRmw8CmpxchgU { memory } => {
+                Some(wasm_encoder::Instruction::I32AtomicRmw8CmpxchgU(
+                    wasm_encoder::MemArg::from(*memory),
+                ))
+            }
+            Operator::I32AtomicRmw16CmpxchgU { memory } => {
+                Some(wasm_encoder::Instruction::I32AtomicRmw16CmpxchgU(
+                    wasm_encoder::MemArg::from(*memory),
+                ))
+            }
+            Operator::I64AtomicRmw8CmpxchgU { memory } => {
+                Some(wasm_encoder::Instruction::I64AtomicRmw8CmpxchgU(
+                    wasm_encoder::MemArg::from(*memory),
+                ))
+            }
+            Operator::I64AtomicRmw16CmpxchgU { memory } => {
+                Some(wasm_encoder::Instruction::I64AtomicRmw16CmpxchgU(
+                    wasm_encoder::MemArg::from(*memory),
+                ))
+            }
+            Operator::I64AtomicRmw32CmpxchgU { memory } => {
+                Some(wasm_encoder::Instruction::I64AtomicRmw32CmpxchgU(
+                    wasm_encoder::MemArg::from(*memory),
+                ))
+            }
         };
 
         if let Some(inst) = inst {
@@ -522,8 +1118,214 @@ impl<'a> WasmFuncBackend<'a> {
     }
 }
 
+/// Private helper to feed `wasm-encoder` sections to a `ModuleEncoder`.
+trait SectionSink {
+    fn emit_section(&mut self, section: &impl wasm_encoder::Section) -> Result<()>;
+}
+
+impl<E: ModuleEncoder> SectionSink for E {
+    fn emit_section(&mut self, section: &impl wasm_encoder::Section) -> Result<()> {
+        let mut buf = vec![];
+        section.encode(&mut buf);
+        self.section(section.id(), encoder::strip_size_prefix(&buf[..]))
+    }
+}
+
+/// Emits custom sections at their recorded places among the standard
+/// sections. As it is told of each standard section in turn, it also
+/// records on the timeline, if any, the time spent building it.
+struct CustomSectionEmitter<'m> {
+    sections: &'m [CustomSection],
+    emitted: Vec<bool>,
+    timeline: Option<&'m Timeline>,
+    /// When the section being built was started.
+    since: Duration,
+}
+
+impl<'m> CustomSectionEmitter<'m> {
+    fn new(sections: &'m [CustomSection], timeline: Option<&'m Timeline>) -> Self {
+        CustomSectionEmitter {
+            sections,
+            emitted: vec![false; sections.len()],
+            timeline,
+            since: timeline.map_or(Duration::ZERO, |timeline| timeline.now()),
+        }
+    }
+
+    /// Record the section built since the last one as `name`.
+    fn built(&mut self, name: &str) {
+        if let Some(timeline) = self.timeline {
+            timeline.record("emit", name, self.since, vec![]);
+            self.since = timeline.now();
+        }
+    }
+
+    fn section_name(id: u8) -> &'static str {
+        match id {
+            1 => "type section",
+            2 => "import section",
+            3 => "function section",
+            4 => "table section",
+            5 => "memory section",
+            6 => "global section",
+            7 => "export section",
+            8 => "start section",
+            9 => "element section",
+            10 => "code section",
+            11 => "data section",
+            12 => "data count section",
+            _ => "name section",
+        }
+    }
+
+    /// Position of a standard section in the order the binary format
+    /// requires (which is not the order of section IDs).
+    fn rank(id: u8) -> usize {
+        const ORDER: &[u8] = &[1, 2, 3, 4, 5, 13, 6, 7, 8, 9, 12, 10, 11];
+        ORDER
+            .iter()
+            .position(|&other| other == id)
+            .map(|pos| pos + 1)
+            .unwrap_or(ORDER.len() + 1)
+    }
+
+    fn placement_rank(placement: CustomSectionPlacement) -> usize {
+        match placement {
+            CustomSectionPlacement::Start => 0,
+            CustomSectionPlacement::After(id) => Self::rank(id),
+            CustomSectionPlacement::End => usize::MAX,
+        }
+    }
+
+    fn emit_where<E: ModuleEncoder, F: Fn(CustomSectionPlacement) -> bool>(
+        &mut self,
+        into_mod: &mut E,
+        pred: F,
+    ) -> Result<()> {
+        for (section, emitted) in self.sections.iter().zip(self.emitted.iter_mut()) {
+            if !*emitted && pred(section.placement) {
+                into_mod.emit_section(&wasm_encoder::CustomSection {
+                    name: &section.name[..],
+                    data: &section.data[..],
+                })?;
+                *emitted = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit the sections that belong before the standard section with
+    /// the given ID.
+    fn emit_before<E: ModuleEncoder>(&mut self, into_mod: &mut E, id: u8) -> Result<()> {
+        self.built(Self::section_name(id));
+        self.emit_unbuilt_before(into_mod, id)
+    }
+
+    /// Like `emit_before`, without recording the section as built: for
+    /// a section written out while it is built.
+    fn emit_unbuilt_before<E: ModuleEncoder>(&mut self, into_mod: &mut E, id: u8) -> Result<()> {
+        let rank = Self::rank(id);
+        self.emit_where(into_mod, |placement| Self::placement_rank(placement) < rank)
+    }
+
+    fn emit_rest<E: ModuleEncoder>(&mut self, into_mod: &mut E) -> Result<()> {
+        self.built("custom sections");
+        self.emit_where(into_mod, |_| true)
+    }
+}
+
+/// Number of function bodies compiled (in parallel) at once in
+/// streaming mode before being written out and dropped.
+const STREAMING_BATCH: usize = 256;
+
 pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
-    let mut into_mod = wasm_encoder::Module::new();
+    compile_with(module, BinaryEncoder::new())
+}
+
+/// Compile the module, handing its sections to the given encoder.
+pub fn compile_with<E: ModuleEncoder>(module: &Module<'_>, mut encoder: E) -> Result<E::Output> {
+    compile_sections(module, &mut encoder, None, false)?;
+    encoder.finish()
+}
+
+/// Compile the module and build a source map for its code. If `url`
+/// is given, a `sourceMappingURL` section pointing to it is appended.
+pub fn compile_with_source_map(
+    module: &Module<'_>,
+    orig_name: &str,
+    url: Option<&str>,
+) -> Result<(Vec<u8>, SourceMap)> {
+    let mut encoder = MeasuringEncoder::new();
+    let layout = compile_sections(module, &mut encoder, None, true)?;
+    let code_start = encoder.code_start.unwrap_or(0);
+    if let Some(url) = url {
+        let mut data = vec![];
+        wasm_encoder::Encode::encode(url, &mut data);
+        encoder.emit_section(&wasm_encoder::CustomSection {
+            name: "sourceMappingURL",
+            data: &data[..],
+        })?;
+    }
+    let bytes = encoder.finish()?;
+    let source_map = sourcemap::build(module, &layout, code_start, orig_name)?;
+    Ok((bytes, source_map))
+}
+
+/// Compile the module and measure where its bytes went; see
+/// `SizeProfile`.
+pub fn compile_with_size_profile(module: &Module<'_>) -> Result<(Vec<u8>, SizeProfile)> {
+    let mut encoder = MeasuringEncoder::new();
+    let layout = compile_sections(module, &mut encoder, None, true)?;
+    let sections = std::mem::take(&mut encoder.sections);
+    let bytes = encoder.finish()?;
+    let profile = size::build(module, &layout, sections, bytes.len() as u32);
+    Ok((bytes, profile))
+}
+
+/// Compile the module, writing the result to `out` section by section.
+///
+/// Function bodies are compiled in bounded-size batches, and each
+/// batch is written out to the code section, whose size is patched in
+/// once it ends, and then dropped. Past the module itself, peak memory
+/// is thus one batch of compiled bodies or the largest other section,
+/// rather than the whole output.
+pub fn compile_to_writer<W: Write + Seek>(module: &Module<'_>, out: &mut W) -> anyhow::Result<()> {
+    let mut encoder = SeekWriterEncoder::new(out);
+    compile_sections(module, &mut encoder, Some(STREAMING_BATCH), false)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Emit all sections of the module. If `track_layout` is set (or
+/// there is DWARF to rewrite), returns where each function body's
+/// code went.
+fn compile_sections<E: ModuleEncoder>(
+    module: &Module<'_>,
+    into_mod: &mut E,
+    batch_size: Option<usize>,
+    track_layout: bool,
+) -> anyhow::Result<CodeLayout> {
+    span!("emit", funcs = module.funcs.len());
+    let _span = module.timeline.as_ref().map(|timeline| {
+        timeline
+            .span("emit", "emit module")
+            .arg("funcs", module.funcs.len())
+    });
+    if let Some(allowed) = &module.codegen_options.features {
+        let (used, _) = module.used_features()?;
+        let disallowed = used
+            .into_iter()
+            .filter(|&feature| !allowed.contains(feature))
+            .collect::<Vec<_>>();
+        if !disallowed.is_empty() {
+            anyhow::bail!(WaffleError::unsupported(format!(
+                "Module uses {}, which the codegen options do not allow",
+                disallowed.join(", ")
+            )));
+        }
+    }
+    let mut custom_sections =
+        CustomSectionEmitter::new(&module.custom_sections[..], module.timeline.as_ref());
 
     let mut types = wasm_encoder::TypeSection::new();
     for sig_data in module.signatures.values() {
@@ -537,7 +1339,8 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
             .map(|&ty| wasm_encoder::ValType::from(ty));
         types.function(params, returns);
     }
-    into_mod.section(&types);
+    custom_sections.emit_before(into_mod, 1)?;
+    into_mod.emit_section(&types)?;
 
     let mut imports = wasm_encoder::ImportSection::new();
     let mut num_func_imports = 0;
@@ -577,7 +1380,7 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
                 let mem = &module.memories[mem];
                 wasm_encoder::EntityType::Memory(wasm_encoder::MemoryType {
                     memory64: false,
-                    shared: false,
+                    shared: mem.shared,
                     minimum: mem.initial_pages as u64,
                     maximum: mem.maximum_pages.map(|val| val as u64),
                 })
@@ -586,21 +1389,29 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
         imports.import(&import.module[..], &import.name[..], entity);
     }
 
-    into_mod.section(&imports);
+    custom_sections.emit_before(into_mod, 2)?;
+    into_mod.emit_section(&imports)?;
 
     let mut funcs = wasm_encoder::FunctionSection::new();
     for (func, func_decl) in module.funcs.entries().skip(num_func_imports) {
         match func_decl {
-            FuncDecl::Import(_, _) => anyhow::bail!("Import comes after func with body: {}", func),
+            FuncDecl::Import(_, _) => anyhow::bail!(WaffleError::internal(format!(
+                "Import comes after func with body: {}",
+                func
+            ))),
             FuncDecl::Lazy(sig, _, _)
             | FuncDecl::Body(sig, _, _)
             | FuncDecl::Compiled(sig, _, _) => {
                 funcs.function(sig.index() as u32);
             }
-            FuncDecl::None => panic!("FuncDecl::None at compilation time"),
+            FuncDecl::None => anyhow::bail!(WaffleError::internal(format!(
+                "{} has no declaration at compilation time",
+                func
+            ))),
         }
     }
-    into_mod.section(&funcs);
+    custom_sections.emit_before(into_mod, 3)?;
+    into_mod.emit_section(&funcs)?;
 
     let mut tables = wasm_encoder::TableSection::new();
     for table_data in module.tables.values().skip(num_table_imports) {
@@ -614,7 +1425,8 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
             maximum: table_data.max,
         });
     }
-    into_mod.section(&tables);
+    custom_sections.emit_before(into_mod, 4)?;
+    into_mod.emit_section(&tables)?;
 
     let mut memories = wasm_encoder::MemorySection::new();
     for mem_data in module.memories.values().skip(num_mem_imports) {
@@ -622,10 +1434,11 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
             minimum: mem_data.initial_pages as u64,
             maximum: mem_data.maximum_pages.map(|val| val as u64),
             memory64: false,
-            shared: false,
+            shared: mem_data.shared,
         });
     }
-    into_mod.section(&memories);
+    custom_sections.emit_before(into_mod, 5)?;
+    into_mod.emit_section(&memories)?;
 
     let mut globals = wasm_encoder::GlobalSection::new();
     for global_data in module.globals.values().skip(num_global_imports) {
@@ -634,10 +1447,11 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
                 val_type: wasm_encoder::ValType::from(global_data.ty),
                 mutable: global_data.mutable,
             },
-            &const_init(global_data.ty, global_data.value),
+            &const_init(global_data.ty, global_data.init),
         );
     }
-    into_mod.section(&globals);
+    custom_sections.emit_before(into_mod, 6)?;
+    into_mod.emit_section(&globals)?;
 
     let mut exports = wasm_encoder::ExportSection::new();
     for export in &module.exports {
@@ -672,16 +1486,59 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
             }
         }
     }
-    into_mod.section(&exports);
+    custom_sections.emit_before(into_mod, 7)?;
+    into_mod.emit_section(&exports)?;
 
     if let Some(start) = module.start_func {
         let start = wasm_encoder::StartSection {
             function_index: start.index() as u32,
         };
-        into_mod.section(&start);
+        custom_sections.emit_before(into_mod, 8)?;
+        into_mod.emit_section(&start)?;
     }
 
     let mut elem = wasm_encoder::ElementSection::new();
+    for segment in &module.elem_segments {
+        let funcs;
+        let exprs;
+        let elements = match &segment.items {
+            ElementItems::Functions(items) => {
+                funcs = items
+                    .iter()
+                    .map(|func| func.index() as u32)
+                    .collect::<Vec<_>>();
+                wasm_encoder::Elements::Functions(&funcs[..])
+            }
+            ElementItems::Expressions(items) => {
+                exprs = items
+                    .iter()
+                    .map(|item| match item {
+                        Some(func) => wasm_encoder::ConstExpr::ref_func(func.index() as u32),
+                        None => wasm_encoder::ConstExpr::ref_null(wasm_encoder::ValType::FuncRef),
+                    })
+                    .collect::<Vec<_>>();
+                wasm_encoder::Elements::Expressions(&exprs[..])
+            }
+        };
+        let ty = wasm_encoder::ValType::FuncRef;
+        match segment.kind {
+            ElementSegmentKind::Passive => {
+                elem.passive(ty, elements);
+            }
+            ElementSegmentKind::Declared => {
+                elem.declared(ty, elements);
+            }
+            ElementSegmentKind::Active { table, offset } => {
+                elem.active(
+                    Some(table.index() as u32),
+                    &offset_expr(offset),
+                    ty,
+                    elements,
+                );
+            }
+        }
+    }
+    // After `elem_segments`, so that those keep their indices.
     for (table, table_data) in module.tables.entries() {
         if let Some(elts) = &table_data.func_elements {
             for (i, &elt) in elts.iter().enumerate() {
@@ -696,79 +1553,372 @@ pub fn compile(module: &Module<'_>) -> anyhow::Result<Vec<u8>> {
             }
         }
     }
-    into_mod.section(&elem);
+    custom_sections.emit_before(into_mod, 9)?;
+    into_mod.emit_section(&elem)?;
+
+    // Only needed for `memory.init` and `data.drop`, which can only
+    // refer to passive segments in practice.
+    if module
+        .data_segments
+        .iter()
+        .any(|segment| segment.kind == DataSegmentKind::Passive)
+    {
+        custom_sections.emit_before(into_mod, 12)?;
+        into_mod.emit_section(&wasm_encoder::DataCountSection {
+            count: module.data_segments.len() as u32,
+        })?;
+    }
+
+    // Bodies kept verbatim only need checking for the segments they
+    // refer to if some segment has changed.
+    #[cfg(feature = "frontend")]
+    let segments_unchanged = crate::ir::SegmentFingerprints::of(module) == module.orig_segments;
 
-    let mut code = wasm_encoder::CodeSection::new();
+    // The code section is written out batch by batch when the encoder
+    // can take it in pieces, and otherwise assembled in `code`.
+    custom_sections.emit_unbuilt_before(into_mod, 10)?;
+    let streaming = into_mod.begin_section(10)?;
+    let mut code = vec![];
+    // The bytes of the section already written out.
+    let mut code_written = 0;
 
     enum FuncOrRawBytes<'a> {
         Raw(&'a [u8]),
         Func(Cow<'a, wasm_encoder::Function>),
     }
 
-    let bodies = module
+    let defined_funcs = module
         .funcs
         .entries()
         .skip(num_func_imports)
-        .collect::<Vec<_>>()
-        .par_iter()
-        .map(|(func, func_decl)| -> Result<_> {
-            match func_decl {
-                FuncDecl::Lazy(_, _name, reader) => {
-                    let data = &module.orig_bytes[reader.range()];
-                    Ok(FuncOrRawBytes::Raw(data))
-                }
-                FuncDecl::Compiled(_, _name, encoder) => {
-                    Ok(FuncOrRawBytes::Func(Cow::Borrowed(encoder)))
-                }
-                FuncDecl::Body(_, name, body) => {
+        .collect::<Vec<_>>();
+    let batch_size = batch_size.unwrap_or(defined_funcs.len()).max(1);
+    // Local and label names of defined functions, as emitted.
+    let mut func_local_names = vec![];
+    let mut label_names = vec![];
+    let keep_dwarf = !module.dwarf_sections.is_empty();
+    let track_layout = track_layout || keep_dwarf;
+    let code_offset = module.dwarf_sections.code_offset;
+    let mut code_layout = CodeLayout::default();
+    crate::leb128::write_u32(&mut code, defined_funcs.len() as u32);
+    for batch in defined_funcs.chunks(batch_size) {
+        #[cfg(feature = "parallel")]
+        let iter = batch.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let iter = batch.iter();
+        let bodies = iter
+            .map(|&(func, func_decl)| -> Result<_> {
+                let orig_local_names = module.names.locals.get(&func);
+                let options = &module.codegen_options;
+                let compile = |name: &str, body: &FunctionBody| -> Result<_> {
+                    if let Some(cached) = module.compile_cache.get(func) {
+                        if cached.spill_config == module.spill_config
+                            && &cached.codegen_options == options
+                            && cached.orig_local_names.as_ref() == orig_local_names
+                            && (cached.offsets.is_some() || !track_layout)
+                        {
+                            log::debug!("Reusing compiled {} \"{}\"", func, name);
+                            return Ok((
+                                FuncOrRawBytes::Func(Cow::Owned(cached.func.clone())),
+                                cached.local_names.clone(),
+                                cached.offsets.clone(),
+                            ));
+                        }
+                    }
                     log::debug!("Compiling {} \"{}\"", func, name);
-                    WasmFuncBackend::new(body)?
-                        .compile()
-                        .map(|func| FuncOrRawBytes::Func(Cow::Owned(func)))
+                    span!("compile", func = %func);
+                    let _span = module.func_span("compile", func);
+                    event!(
+                        func = %func,
+                        blocks = body.blocks.len(),
+                        values = body.values.len(),
+                        "compiling"
+                    );
+                    let mut rewritten;
+                    let body = match &options.determinism {
+                        Some(determinism) => {
+                            rewritten = body.clone();
+                            determinism::run_on_body(&mut rewritten, determinism);
+                            &rewritten
+                        }
+                        None => body,
+                    };
+                    let backend =
+                        WasmFuncBackend::with_options(body, module.spill_config.as_ref(), options)?;
+                    let local_names = orig_local_names.map(|names| backend.local_names(names));
+                    let (compiled, offsets) = if track_layout {
+                        let (compiled, offsets) = backend.compile_with_offsets()?;
+                        (compiled, Some(offsets))
+                    } else {
+                        (backend.compile()?, None)
+                    };
+                    if module.compile_cache.enabled {
+                        module.compile_cache.insert(
+                            func,
+                            CompiledBody {
+                                func: compiled.clone(),
+                                local_names: local_names.clone(),
+                                offsets: offsets.clone(),
+                                orig_local_names: orig_local_names.cloned(),
+                                spill_config: module.spill_config.clone(),
+                                codegen_options: options.clone(),
+                            },
+                        );
+                    }
+                    Ok((
+                        FuncOrRawBytes::Func(Cow::Owned(compiled)),
+                        local_names,
+                        offsets,
+                    ))
+                };
+                match func_decl {
+                    // Bodies are made deterministic as they are compiled,
+                    // so none can be copied verbatim.
+                    FuncDecl::Lazy(_, name, _) if options.determinism.is_some() => {
+                        compile(name, &module.clone_and_expand_body(func)?)
+                    }
+                    FuncDecl::Compiled(..) if options.determinism.is_some() => {
+                        anyhow::bail!("Cannot rewrite {}: it is already compiled", func)
+                    }
+                    FuncDecl::Lazy(_, _name, reader) => {
+                        #[cfg(feature = "frontend")]
+                        if !segments_unchanged {
+                            module.check_verbatim_segments(func, reader)?;
+                        }
+                        let data = &module.orig_bytes[reader.range()];
+                        Ok((FuncOrRawBytes::Raw(data), orig_local_names.cloned(), None))
+                    }
+                    FuncDecl::Compiled(sig, _name, encoder) => {
+                        // We no longer know where non-param locals went.
+                        let n_params = module.signatures[*sig].params.len() as u32;
+                        let param_names = orig_local_names.map(|names| {
+                            names
+                                .range(..n_params)
+                                .map(|(&idx, name)| (idx, name.clone()))
+                                .collect()
+                        });
+                        Ok((
+                            FuncOrRawBytes::Func(Cow::Borrowed(encoder)),
+                            param_names,
+                            None,
+                        ))
+                    }
+                    FuncDecl::Body(_, name, body) => compile(name, body),
+                    FuncDecl::Import(_, _) => unreachable!("Should have skipped imports"),
+                    FuncDecl::None => {
+                        anyhow::bail!(WaffleError::internal("No declaration at compilation time"))
+                    }
                 }
-                FuncDecl::Import(_, _) => unreachable!("Should have skipped imports"),
-                FuncDecl::None => panic!("FuncDecl::None at compilation time"),
-            }
-        })
-        .collect::<Result<Vec<_>>>()?;
+            })
+            .collect::<Vec<Result<_>>>()
+            .into_iter()
+            .zip(batch)
+            .map(|(body, &(func, _))| body.map_err(|e| locate(e, Some(func), None)))
+            .collect::<Result<Vec<_>>>()?;
 
-    for body in bodies {
-        match body {
-            FuncOrRawBytes::Raw(bytes) => {
-                code.raw(bytes);
+        for (&(func, func_decl), (body, local_names, offsets)) in batch.iter().zip(bodies) {
+            let body_len = match body {
+                FuncOrRawBytes::Raw(bytes) => {
+                    crate::leb128::write_u32(&mut code, bytes.len() as u32);
+                    code.extend_from_slice(bytes);
+                    if let Some(labels) = module.names.labels.get(&func) {
+                        label_names.push((func, labels.clone()));
+                    }
+                    bytes.len()
+                }
+                FuncOrRawBytes::Func(func) => {
+                    wasm_encoder::Encode::encode(&*func, &mut code);
+                    func.byte_len()
+                }
+            };
+            if let Some(local_names) = local_names {
+                func_local_names.push((func, local_names));
             }
-            FuncOrRawBytes::Func(func) => {
-                code.function(&*func);
+            if track_layout {
+                let end = (code_written + code.len()) as u32;
+                let new = end - body_len as u32..end;
+                code_layout.push_body(func, new.clone());
+                match (func_decl, offsets) {
+                    (FuncDecl::Lazy(_, _, reader), _) => {
+                        let old = reader.range();
+                        let old = old.start as u32 - code_offset..old.end as u32 - code_offset;
+                        code_layout.push(FuncLayout::verbatim(old, new));
+                    }
+                    (FuncDecl::Body(_, _, body), Some(offsets)) => {
+                        if let Some(old) = &body.source_range {
+                            let old = old.start - code_offset..old.end - code_offset;
+                            let ops = offsets
+                                .into_iter()
+                                .map(|(new_offset, old_offset)| {
+                                    (new.start + new_offset, old_offset - code_offset)
+                                })
+                                .collect();
+                            code_layout.push(FuncLayout::recompiled(old, new, ops));
+                        }
+                    }
+                    _ => {}
+                }
             }
         }
+        if streaming {
+            into_mod.section_part(&code[..])?;
+            code_written += code.len();
+            code.clear();
+        }
+    }
+    custom_sections.emit_before(into_mod, 10)?;
+    if streaming {
+        into_mod.end_section()?;
+    } else {
+        into_mod.section(10, &code[..])?;
     }
-    into_mod.section(&code);
 
     let mut data = wasm_encoder::DataSection::new();
-    for (mem, mem_data) in module.memories.entries() {
-        for segment in &mem_data.segments {
-            data.active(
-                mem.index() as u32,
-                &wasm_encoder::ConstExpr::i32_const(segment.offset as i32),
-                segment.data.iter().copied(),
-            );
+    for segment in &module.data_segments {
+        match segment.kind {
+            DataSegmentKind::Passive => {
+                data.passive(segment.data.iter().copied());
+            }
+            DataSegmentKind::Active { memory, offset } => {
+                data.active(
+                    memory.index() as u32,
+                    &offset_expr(offset),
+                    segment.data.iter().copied(),
+                );
+            }
         }
     }
-    into_mod.section(&data);
+    custom_sections.emit_before(into_mod, 11)?;
+    into_mod.emit_section(&data)?;
 
+    // Only names that are set, and that the name policy keeps, are
+    // emitted, and the section is left out if there are none (e.g.
+    // after `Module::strip`).
+    let policy = module.codegen_options.names;
+    let all_names = policy == NamePolicy::All;
     let mut names = wasm_encoder::NameSection::new();
+    let mut has_names = false;
+    if let Some(module_name) = module
+        .names
+        .module
+        .as_ref()
+        .filter(|_| policy != NamePolicy::None)
+    {
+        names.module(&module_name[..]);
+        has_names = true;
+    }
     let mut func_names = wasm_encoder::NameMap::new();
     for (func, decl) in module.funcs.entries() {
-        func_names.append(func.index() as u32, decl.name());
+        if !decl.name().is_empty() && policy != NamePolicy::None {
+            func_names.append(func.index() as u32, decl.name());
+        }
+    }
+    if !func_names.is_empty() {
+        names.functions(&func_names);
+        has_names = true;
+    }
+    let import_local_names = module
+        .names
+        .locals
+        .iter()
+        .filter(|(func, _)| func.index() < num_func_imports)
+        .map(|(&func, names)| (func, names.clone()));
+    let func_local_names = import_local_names
+        .chain(func_local_names)
+        .collect::<Vec<_>>();
+    if !func_local_names.is_empty() && all_names {
+        names.locals(&indirect_name_map(&func_local_names[..]));
+        has_names = true;
+    }
+    if !label_names.is_empty() && all_names {
+        names.labels(&indirect_name_map(&label_names[..]));
+        has_names = true;
+    }
+    for (map, add) in [
+        (
+            entity_name_map(&module.names.types),
+            wasm_encoder::NameSection::types as fn(&mut _, &_),
+        ),
+        (
+            entity_name_map(&module.names.tables),
+            wasm_encoder::NameSection::tables,
+        ),
+        (
+            entity_name_map(&module.names.memories),
+            wasm_encoder::NameSection::memories,
+        ),
+        (
+            entity_name_map(&module.names.globals),
+            wasm_encoder::NameSection::globals,
+        ),
+    ] {
+        if !map.is_empty() && all_names {
+            add(&mut names, &map);
+            has_names = true;
+        }
+    }
+    custom_sections.emit_before(into_mod, u8::MAX)?;
+    code_layout.finish();
+    if keep_dwarf {
+        match dwarf::rewrite(&module.dwarf_sections, &code_layout) {
+            Ok(sections) => {
+                for (name, data) in &sections {
+                    into_mod.emit_section(&wasm_encoder::CustomSection {
+                        name: &name[..],
+                        data: &data[..],
+                    })?;
+                }
+            }
+            Err(e) => module.warnings.add(format!(
+                "Dropping DWARF debug info that could not be rewritten: {}",
+                e
+            )),
+        }
+        custom_sections.built("DWARF sections");
     }
-    names.functions(&func_names);
-    into_mod.section(&names);
+    if has_names {
+        into_mod.emit_section(&names)?;
+    }
+    custom_sections.emit_rest(into_mod)?;
 
-    Ok(into_mod.finish())
+    Ok(code_layout)
 }
 
-fn const_init(ty: Type, value: Option<u64>) -> wasm_encoder::ConstExpr {
-    let bits = value.unwrap_or(0);
+fn indirect_name_map(names: &[(Func, BTreeMap<u32, String>)]) -> wasm_encoder::IndirectNameMap {
+    let mut map = wasm_encoder::IndirectNameMap::new();
+    for (func, names) in names {
+        let mut inner = wasm_encoder::NameMap::new();
+        for (&idx, name) in names {
+            inner.append(idx, &name[..]);
+        }
+        map.append(func.index() as u32, &inner);
+    }
+    map
+}
+
+fn entity_name_map<E: EntityRef>(names: &BTreeMap<E, String>) -> wasm_encoder::NameMap {
+    let mut map = wasm_encoder::NameMap::new();
+    for (entity, name) in names {
+        map.append(entity.index() as u32, &name[..]);
+    }
+    map
+}
+
+fn offset_expr(offset: SegmentOffset) -> wasm_encoder::ConstExpr {
+    match offset {
+        SegmentOffset::Const(offset) => wasm_encoder::ConstExpr::i32_const(offset as i32),
+        SegmentOffset::Global(global) => wasm_encoder::ConstExpr::global_get(global.index() as u32),
+    }
+}
+
+fn const_init(ty: Type, init: Option<GlobalInit>) -> wasm_encoder::ConstExpr {
+    let bits = match init {
+        Some(GlobalInit::GlobalGet(global)) => {
+            return wasm_encoder::ConstExpr::global_get(global.index() as u32)
+        }
+        Some(GlobalInit::Const(bits)) => bits,
+        None => 0,
+    };
     match ty {
         Type::I32 => wasm_encoder::ConstExpr::i32_const(bits as u32 as i32),
         Type::I64 => wasm_encoder::ConstExpr::i64_const(bits as i64),
@@ -0,0 +1,89 @@
+//! Options for compiling a module.
+
+use super::localify::AllocStrategy;
+use crate::passes::determinism::DeterminismOptions;
+use std::collections::BTreeSet;
+
+/// How the backend compiles a module; see `Module::codegen_options`.
+/// The defaults allow every feature and keep every name. For
+/// example:
+///
+/// ```ignore
+/// module.codegen_options = CodegenOptions::new()
+///     .features(["sign-ext", "mutable-globals"])
+///     .locals(AllocStrategy::Binpack)
+///     .names(NamePolicy::Functions);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CodegenOptions {
+    /// The features the output may use, named as in `DETECTED_FEATURES`.
+    /// Compiling a module that uses any other fails. `None` allows all.
+    pub features: Option<BTreeSet<String>>,
+    pub structuring: Structuring,
+    pub locals: AllocStrategy,
+    /// Make each body deterministic as the `determinism` pass would as
+    /// it is compiled, leaving the module itself as it is. Bodies that
+    /// would otherwise be copied verbatim are parsed and compiled too.
+    pub determinism: Option<DeterminismOptions>,
+    pub names: NamePolicy,
+}
+
+impl CodegenOptions {
+    pub fn new() -> CodegenOptions {
+        CodegenOptions::default()
+    }
+
+    pub fn features<I, S>(mut self, features: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.features = Some(features.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn structuring(mut self, structuring: Structuring) -> Self {
+        self.structuring = structuring;
+        self
+    }
+
+    pub fn locals(mut self, strategy: AllocStrategy) -> Self {
+        self.locals = strategy;
+        self
+    }
+
+    pub fn determinism(mut self, options: DeterminismOptions) -> Self {
+        self.determinism = Some(options);
+        self
+    }
+
+    pub fn names(mut self, policy: NamePolicy) -> Self {
+        self.names = policy;
+        self
+    }
+}
+
+/// How conditional branches become structured control flow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Structuring {
+    /// `if`, with an `else` where needed.
+    #[default]
+    IfElse,
+    /// `block`s exited with `br_if`, for consumers that handle only
+    /// blocks, loops and branches. Each `if` becomes a block, or two
+    /// with an `else`.
+    Blocks,
+}
+
+/// Which names go in the `name` section.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NamePolicy {
+    /// Every name the module has.
+    #[default]
+    All,
+    /// The module's and functions' names only, e.g. for symbolized
+    /// stack traces at a fraction of the size.
+    Functions,
+    /// None: the section is left out.
+    None,
+}
@@ -0,0 +1,263 @@
+//! Where the bytes of an emitted module go.
+
+use super::layout::{CodeLayout, FuncLayout};
+use crate::entity::EntityRef;
+use crate::ir::{Func, FuncDecl, Module};
+#[cfg(feature = "frontend")]
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::ops::Range;
+
+/// The size of one section of an emitted module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SectionSize {
+    pub id: u8,
+    /// The standard name of the section, or a custom section's name.
+    pub name: String,
+    /// Size of the section's contents, not counting its header.
+    pub size: u32,
+}
+
+/// The size of one emitted function body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuncSize {
+    pub func: Func,
+    pub name: String,
+    /// Size of the body, including local declarations but not its
+    /// size prefix.
+    pub size: u32,
+    /// The body's bytes broken down by the original function their
+    /// code came from, so that inlined or duplicated code is counted
+    /// against where it was written. Bytes that cannot be traced back
+    /// are counted against `func`. Sorted by function.
+    pub origins: Vec<(Func, u32)>,
+}
+
+/// Sizes of the sections and function bodies of an emitted module.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SizeProfile {
+    pub total: u32,
+    /// In emission order.
+    pub sections: Vec<SectionSize>,
+    /// In function index order.
+    pub funcs: Vec<FuncSize>,
+}
+
+pub(super) fn section_name(id: u8) -> &'static str {
+    match id {
+        0 => "custom",
+        1 => "type",
+        2 => "import",
+        3 => "function",
+        4 => "table",
+        5 => "memory",
+        6 => "global",
+        7 => "export",
+        8 => "start",
+        9 => "element",
+        10 => "code",
+        11 => "data",
+        12 => "datacount",
+        13 => "tag",
+        _ => "unknown",
+    }
+}
+
+impl SectionSize {
+    /// The sizes of the sections of the module `bytes`, in order.
+    #[cfg(feature = "frontend")]
+    pub fn of_module(bytes: &[u8]) -> Result<Vec<SectionSize>> {
+        let mut sections = vec![];
+        for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+            let payload = payload?;
+            let name = match &payload {
+                wasmparser::Payload::CustomSection(reader) => reader.name().to_owned(),
+                _ => match payload.as_section() {
+                    Some((id, _)) => section_name(id).to_owned(),
+                    None => continue,
+                },
+            };
+            if let Some((id, range)) = payload.as_section() {
+                sections.push(SectionSize {
+                    id,
+                    name,
+                    size: range.len() as u32,
+                });
+            }
+        }
+        Ok(sections)
+    }
+}
+
+impl SizeProfile {
+    /// Bytes of emitted code attributed to each original function,
+    /// summed over every body its code ended up in.
+    pub fn attributed(&self) -> BTreeMap<Func, u32> {
+        let mut sizes = BTreeMap::new();
+        for func in &self.funcs {
+            for &(origin, size) in &func.origins {
+                *sizes.entry(origin).or_insert(0) += size;
+            }
+        }
+        sizes
+    }
+
+    /// A human-readable report: sections, then the `top` largest
+    /// function bodies (all if `None`), then the `top` functions with
+    /// the most code attributed to them.
+    pub fn report(&self, top: Option<usize>) -> String {
+        let percent = |size: u32| 100.0 * size as f64 / self.total.max(1) as f64;
+        let top = top.unwrap_or(usize::MAX);
+        let names: HashMap<Func, &str> = self
+            .funcs
+            .iter()
+            .map(|func| (func.func, &func.name[..]))
+            .collect();
+        let mut out = String::new();
+
+        writeln!(out, "{:>10} {:>7}  section", "bytes", "%").unwrap();
+        for section in &self.sections {
+            writeln!(
+                out,
+                "{:>10} {:>6.2}%  {}",
+                section.size,
+                percent(section.size),
+                section.name
+            )
+            .unwrap();
+        }
+        writeln!(out, "{:>10} {:>6.2}%  (total)", self.total, 100.0).unwrap();
+
+        let mut funcs = self.funcs.iter().collect::<Vec<_>>();
+        funcs.sort_by_key(|func| (std::cmp::Reverse(func.size), func.func));
+        writeln!(out, "\n{:>10} {:>7}  function", "bytes", "%").unwrap();
+        for func in funcs.into_iter().take(top) {
+            writeln!(
+                out,
+                "{:>10} {:>6.2}%  {} {}",
+                func.size,
+                percent(func.size),
+                func.func,
+                func.name
+            )
+            .unwrap();
+        }
+
+        let mut attributed = self.attributed().into_iter().collect::<Vec<_>>();
+        attributed.sort_by_key(|&(func, size)| (std::cmp::Reverse(size), func));
+        writeln!(out, "\n{:>10} {:>7}  attributed to", "bytes", "%").unwrap();
+        for (func, size) in attributed.into_iter().take(top) {
+            writeln!(
+                out,
+                "{:>10} {:>6.2}%  {} {}",
+                size,
+                percent(size),
+                func,
+                names.get(&func).copied().unwrap_or("")
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+/// The original function whose body contains `addr`, given original
+/// body ranges sorted by start.
+fn origin_of(origins: &[(Range<u32>, Func)], addr: u32) -> Option<Func> {
+    let idx = origins.partition_point(|(range, _)| range.start <= addr);
+    let (range, func) = origins.get(idx.checked_sub(1)?)?;
+    if addr < range.end {
+        Some(*func)
+    } else {
+        None
+    }
+}
+
+/// Break the bytes of the body at `new`, emitted for `func`, down by
+/// original function.
+fn attribute(
+    func: Func,
+    new: &Range<u32>,
+    layout: Option<&FuncLayout>,
+    origins: &[(Range<u32>, Func)],
+) -> Vec<(Func, u32)> {
+    let mut sizes = BTreeMap::new();
+    match layout {
+        Some(FuncLayout { ops: Some(ops), .. }) => {
+            // Each operator's code runs up to the next traced
+            // operator's; code before the first is local declarations
+            // and frame setup.
+            let mut start = new.start;
+            let mut owner = func;
+            for &(offset, old) in &ops.emitted {
+                *sizes.entry(owner).or_insert(0) += offset - start;
+                start = offset;
+                owner = origin_of(origins, old).unwrap_or(func);
+            }
+            *sizes.entry(owner).or_insert(0) += new.end - start;
+        }
+        Some(FuncLayout { old, ops: None, .. }) => {
+            let owner = origin_of(origins, old.start).unwrap_or(func);
+            sizes.insert(owner, new.end - new.start);
+        }
+        None => {
+            sizes.insert(func, new.end - new.start);
+        }
+    }
+    sizes.retain(|_, size| *size > 0);
+    sizes.into_iter().collect()
+}
+
+/// Build the size profile of a compiled module, given the layout of
+/// its code and the emitted bytes.
+pub(crate) fn build(
+    module: &Module<'_>,
+    layout: &CodeLayout,
+    sections: Vec<SectionSize>,
+    total: u32,
+) -> SizeProfile {
+    // Where each function that still has its original code was in the
+    // original code section. A copy of a body keeps its source range,
+    // so the lowest-numbered function claims it.
+    let code_offset = module.dwarf_sections.code_offset;
+    let mut origins = module
+        .funcs
+        .entries()
+        .filter_map(|(func, decl)| {
+            let range = match decl {
+                FuncDecl::Lazy(_, _, body) => {
+                    let range = body.range();
+                    range.start as u32..range.end as u32
+                }
+                FuncDecl::Body(_, _, body) => body.source_range.clone()?,
+                _ => return None,
+            };
+            Some((range.start - code_offset..range.end - code_offset, func))
+        })
+        .collect::<Vec<_>>();
+    origins.sort_by_key(|(range, func)| (range.start, func.index()));
+    origins.dedup_by_key(|(range, _)| range.start);
+
+    let by_new = layout
+        .funcs()
+        .iter()
+        .map(|func| (func.new.start, func))
+        .collect::<HashMap<_, _>>();
+    let funcs = layout
+        .bodies()
+        .iter()
+        .map(|(func, new)| FuncSize {
+            func: *func,
+            name: module.funcs[*func].name().to_owned(),
+            size: new.end - new.start,
+            origins: attribute(*func, new, by_new.get(&new.start).copied(), &origins),
+        })
+        .collect();
+
+    SizeProfile {
+        total,
+        sections,
+        funcs,
+    }
+}
@@ -0,0 +1,183 @@
+//! Source maps from emitted code back to the original module.
+
+use super::layout::CodeLayout;
+use crate::entity::EntityRef;
+use crate::ir::{FuncDecl, Module, SourceLoc};
+use crate::json;
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// A source map (format revision 3), as consumed by browser devtools.
+///
+/// Generated positions are byte offsets into the emitted binary. The
+/// original positions are source-file locations taken from the input's
+/// DWARF if it was parsed with `FrontendOptions::debug`, and byte
+/// offsets into the original module otherwise.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    pub sources: Vec<String>,
+    /// Sorted by generated offset.
+    pub mappings: Vec<SourceMapping>,
+}
+
+/// One source map segment. Lines and columns are zero-based, as in
+/// the source map format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceMapping {
+    pub generated: u32,
+    pub source: u32,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl SourceMap {
+    /// The map's JSON encoding.
+    pub fn to_json(&self) -> String {
+        let sources = self
+            .sources
+            .iter()
+            .map(|source| json::string(source))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"version\":3,\"sources\":[{}],\"names\":[],\"mappings\":\"{}\"}}",
+            sources,
+            self.encode_mappings()
+        )
+    }
+
+    fn encode_mappings(&self) -> String {
+        // Wasm has no lines, so all segments are on the first line.
+        let mut out = String::new();
+        let mut last = SourceMapping {
+            generated: 0,
+            source: 0,
+            line: 0,
+            column: 0,
+        };
+        for (i, mapping) in self.mappings.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            vlq(&mut out, mapping.generated as i64 - last.generated as i64);
+            vlq(&mut out, mapping.source as i64 - last.source as i64);
+            vlq(&mut out, mapping.line as i64 - last.line as i64);
+            vlq(&mut out, mapping.column as i64 - last.column as i64);
+            last = *mapping;
+        }
+        out
+    }
+}
+
+fn vlq(out: &mut String, value: i64) {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut value = if value < 0 {
+        ((-value as u64) << 1) | 1
+    } else {
+        (value as u64) << 1
+    };
+    loop {
+        let mut digit = (value & 0x1f) as usize;
+        value >>= 5;
+        if value != 0 {
+            digit |= 0x20;
+        }
+        out.push(BASE64[digit] as char);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+/// Build the source map for a compiled module, given the layout of
+/// its code and the offset of the emitted code section's contents.
+pub(crate) fn build(
+    module: &Module<'_>,
+    layout: &CodeLayout,
+    code_start: u32,
+    orig_name: &str,
+) -> Result<SourceMap> {
+    let code_offset = module.dwarf_sections.code_offset;
+    let use_dwarf = !module.debug_map.tuples.is_empty();
+    let sources = if use_dwarf {
+        module.debug.source_files.values().cloned().collect()
+    } else {
+        vec![orig_name.to_owned()]
+    };
+
+    // Original position of the operator at a given (code-relative)
+    // address in the original module.
+    let resolve = |addr: u32| -> Option<(u32, u32, u32)> {
+        if !use_dwarf {
+            return Some((0, 0, addr + code_offset));
+        }
+        let tuples = &module.debug_map.tuples;
+        let idx = tuples.partition_point(|&(start, _, _)| start <= addr);
+        let (start, len, loc) = *tuples.get(idx.checked_sub(1)?)?;
+        if addr >= start + len || loc == SourceLoc::invalid() {
+            return None;
+        }
+        let data = &module.debug.source_locs[loc];
+        Some((
+            data.file.index() as u32,
+            data.line.checked_sub(1)?,
+            data.col.saturating_sub(1),
+        ))
+    };
+
+    // Bodies that were copied verbatim, by original start address.
+    let verbatim = module
+        .funcs
+        .values()
+        .filter_map(|decl| match decl {
+            FuncDecl::Lazy(_, _, body) => Some((body.range().start as u32 - code_offset, body)),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut mappings = vec![];
+    for func in layout.funcs() {
+        let ops = match &func.ops {
+            Some(ops) => ops.emitted.clone(),
+            None => match verbatim.get(&func.old.start) {
+                #[cfg(not(feature = "frontend"))]
+                Some(body) => body.unreachable(),
+                #[cfg(feature = "frontend")]
+                Some(body) => {
+                    let mut ops = vec![];
+                    for op in body
+                        .reader()
+                        .get_operators_reader()?
+                        .into_iter_with_offsets()
+                    {
+                        let (_, offset) = op?;
+                        let old = offset as u32 - code_offset;
+                        ops.push((old - func.old.start + func.new.start, old));
+                    }
+                    ops
+                }
+                None => vec![(func.new.start, func.old.start)],
+            },
+        };
+        let mut last = None;
+        for (new, old) in ops {
+            let pos = match resolve(old) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            if last == Some(pos) {
+                continue;
+            }
+            last = Some(pos);
+            mappings.push(SourceMapping {
+                generated: code_start + new,
+                source: pos.0,
+                line: pos.1,
+                column: pos.2,
+            });
+        }
+    }
+    mappings.sort_by_key(|mapping| mapping.generated);
+
+    Ok(SourceMap { sources, mappings })
+}
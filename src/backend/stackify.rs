@@ -11,6 +11,7 @@
 
 use crate::cfg::CFGInfo;
 use crate::entity::EntityRef;
+use crate::errors::WaffleError;
 use crate::ir::{Block, BlockTarget, FunctionBody, Terminator, Type, Value};
 use std::collections::HashSet;
 use std::convert::TryFrom;
@@ -139,7 +140,7 @@ impl<'a, 'b> Context<'a, 'b> {
         let mut merge_nodes = HashSet::new();
 
         for (block_rpo, &block) in cfg.rpo.entries() {
-            for &succ in &body.blocks[block].succs {
+            for &succ in body.succs(block) {
                 log::trace!(
                     "block {} ({}) rpo {} has succ {} ({})",
                     block,
@@ -152,13 +153,10 @@ impl<'a, 'b> Context<'a, 'b> {
                 log::trace!(" -> succ rpo {}", succ_rpo);
                 if succ_rpo <= block_rpo {
                     if !cfg.dominates(succ, block) {
-                        anyhow::bail!(
+                        anyhow::bail!(WaffleError::internal(format!(
                             "Irreducible control flow: edge from {} ({}) to {} ({})",
-                            block,
-                            body.blocks[block].desc,
-                            succ,
-                            body.blocks[succ].desc
-                        );
+                            block, body.blocks[block].desc, succ, body.blocks[succ].desc
+                        )));
                     }
                     // Backward branch.
                     loop_headers.insert(succ);
@@ -1,10 +1,9 @@
 //! Treeification: placing some values "under" others if only used
 //! once, to generate more AST-like Wasm code.
 
-use crate::entity::EntityRef;
+use crate::entity::{EntityRef, PerEntity};
 use crate::ir::{FunctionBody, Value, ValueDef};
 use crate::Operator;
-use fxhash::{FxHashMap as HashMap, FxHashSet as HashSet};
 use std::convert::TryFrom;
 
 /// One "argument slot" of an operator defining a value.
@@ -15,12 +14,9 @@ pub struct ValueArg(Value, u16);
 pub struct Trees {
     /// Is a value placed "under" the given arg slot of the given
     /// other value?
-    pub owner: HashMap<Value, ValueArg>,
-    /// For a given value that is defined by an operator, which
-    /// Values, if any, live at each slot?
-    pub owned: HashMap<ValueArg, Value>,
+    pub owner: PerEntity<Value, Option<ValueArg>>,
     /// Values that are regenerated every time they are used.
-    pub remat: HashSet<Value>,
+    pub remat: PerEntity<Value, bool>,
 }
 
 fn is_remat(op: &Operator) -> bool {
@@ -36,10 +32,9 @@ fn is_remat(op: &Operator) -> bool {
 
 impl Trees {
     pub fn compute(body: &FunctionBody) -> Trees {
-        let mut owner = HashMap::default();
-        let mut owned = HashMap::default();
-        let mut remat = HashSet::default();
-        let mut multi_use = HashSet::default();
+        let mut owner: PerEntity<Value, Option<ValueArg>> = PerEntity::default();
+        let mut remat = PerEntity::default();
+        let mut multi_use = PerEntity::default();
 
         for (value, def) in body.values.entries() {
             match def {
@@ -52,7 +47,7 @@ impl Trees {
                     // If this is an always-rematerialized operator,
                     // mark it as such and continue.
                     if is_remat(&op) {
-                        remat.insert(value);
+                        remat[value] = true;
                         continue;
                     }
 
@@ -64,16 +59,13 @@ impl Trees {
                     // `multi_use`.
                     for (i, &arg) in body.arg_pool[args].iter().enumerate() {
                         let arg = body.resolve_alias(arg);
-                        if multi_use.contains(&arg) {
+                        if multi_use[arg] {
                             continue;
-                        } else if let Some(old_owner) = owner.remove(&arg) {
-                            owned.remove(&old_owner);
-                            multi_use.insert(arg);
+                        } else if owner[arg].take().is_some() {
+                            multi_use[arg] = true;
                         } else if Self::is_movable(body, arg) {
                             let pos = u16::try_from(i).unwrap();
-                            let value_arg = ValueArg(value, pos);
-                            owner.insert(arg, value_arg);
-                            owned.insert(value_arg, arg);
+                            owner[arg] = Some(ValueArg(value, pos));
                         }
                     }
                 }
@@ -90,17 +82,18 @@ impl Trees {
         for block in body.blocks.values() {
             block.terminator.visit_uses(|u| {
                 let u = body.resolve_alias(u);
-                if let Some(old_owner) = owner.remove(&u) {
-                    owned.remove(&old_owner);
-                }
+                owner[u] = None;
             });
         }
 
-        Trees {
-            owner,
-            owned,
-            remat,
-        }
+        Trees { owner, remat }
+    }
+
+    /// Whether `value` is lowered where it is used rather than where
+    /// it is defined: under the one operator using it, or everywhere
+    /// it is used if it is rematerialized.
+    pub fn is_inline(&self, value: Value) -> bool {
+        self.owner[value].is_some() || self.remat[value]
     }
 
     fn is_single_output_op(body: &FunctionBody, value: Value) -> Option<Operator> {
@@ -0,0 +1,213 @@
+//! WAT output and disassembly with each instruction of a recompiled
+//! body commented with the IR value it came from.
+
+use super::{compile, FunctionSink, WasmFuncBackend};
+use crate::entity::EntityRef;
+use crate::ir::{Func, FuncDecl, FunctionBody, Module, Value};
+use crate::passes::determinism;
+use anyhow::Result;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+/// Records, for each instruction of a body, the innermost value being
+/// lowered when it was emitted.
+#[derive(Default)]
+struct ValueSink {
+    stack: Vec<Value>,
+    insts: Vec<Option<Value>>,
+}
+
+impl FunctionSink for ValueSink {
+    fn instruction(&mut self, _inst: &wasm_encoder::Instruction<'_>) {
+        self.insts.push(self.stack.last().copied());
+    }
+
+    fn enter_value(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn leave_value(&mut self) {
+        self.stack.pop();
+    }
+}
+
+/// A body as the backend compiles it: the value behind each
+/// instruction, and the backend's local assignments.
+struct Lowered {
+    insts: Vec<Option<Value>>,
+    locals: Vec<String>,
+}
+
+/// Lower `func` as the backend compiles it, or return `None` if its
+/// body is copied as it is.
+fn lower(module: &Module<'_>, func: Func) -> Result<Option<Lowered>> {
+    let options = &module.codegen_options;
+    let body: Cow<'_, FunctionBody> = match &module.funcs[func] {
+        FuncDecl::Body(_, _, body) => Cow::Borrowed(body),
+        FuncDecl::Lazy(..) if options.determinism.is_some() => {
+            Cow::Owned(module.clone_and_expand_body(func)?)
+        }
+        _ => return Ok(None),
+    };
+    let body = match &options.determinism {
+        Some(determinism) => {
+            let mut body = body.into_owned();
+            determinism::run_on_body(&mut body, determinism);
+            Cow::Owned(body)
+        }
+        None => body,
+    };
+    let backend = WasmFuncBackend::with_options(&body, module.spill_config.as_ref(), options)?;
+    let mut sink = ValueSink::default();
+    backend.compile_into(&mut sink)?;
+    Ok(Some(Lowered {
+        insts: sink.insts,
+        locals: backend.local_assignments(),
+    }))
+}
+
+/// The offset of each instruction of `body`, and the offset of its end.
+fn inst_offsets(body: &wasmparser::FunctionBody<'_>) -> Result<(Vec<usize>, usize)> {
+    let mut reader = body.get_operators_reader()?;
+    let mut offsets = vec![];
+    while !reader.eof() {
+        offsets.push(reader.original_position());
+        reader.read()?;
+    }
+    Ok((offsets, reader.original_position()))
+}
+
+/// Print `bytes` as WAT, returning each line with the offset it was
+/// printed at, if any.
+fn print_with_offsets(bytes: &[u8]) -> Result<Vec<(Option<usize>, String)>> {
+    let mut printer = wasmprinter::Printer::new();
+    printer.print_offsets(true);
+    let text = printer.print(bytes)?;
+    // Every line but the first starts with its offset, as `(;@1f  ;)`,
+    // or padding where it has none.
+    Ok(text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| match line.strip_prefix("(;@") {
+            Some(rest) if i > 0 => {
+                let end = rest.find(";)").unwrap_or(rest.len());
+                let offset = usize::from_str_radix(rest[..end].trim_end(), 16).ok();
+                (offset, rest[(end + 2).min(rest.len())..].to_owned())
+            }
+            _ if i > 0 => (None, line.get(11..).unwrap_or("").to_owned()),
+            _ => (None, line.to_owned()),
+        })
+        .collect())
+}
+
+/// Compile `module` and print it as WAT, with each instruction of a
+/// recompiled body followed by a `;; vN` comment naming the value it
+/// computes, or loads or stores to a local. Bodies copied from the
+/// original module are printed as they are.
+pub fn compile_to_annotated_wat(module: &Module<'_>) -> Result<String> {
+    let bytes = compile(module)?;
+
+    // The value behind the instruction at each offset of the output.
+    let mut values: HashMap<usize, Value> = HashMap::new();
+    let mut func = num_imported(module);
+    for payload in wasmparser::Parser::new(0).parse_all(&bytes[..]) {
+        if let wasmparser::Payload::CodeSectionEntry(body) = payload? {
+            if let Some(lowered) = lower(module, Func::new(func))? {
+                let (offsets, _) = inst_offsets(&body)?;
+                debug_assert_eq!(offsets.len(), lowered.insts.len());
+                for (offset, value) in offsets.into_iter().zip(lowered.insts) {
+                    if let Some(value) = value {
+                        values.insert(offset, value);
+                    }
+                }
+            }
+            func += 1;
+        }
+    }
+
+    let mut out = String::new();
+    for (offset, line) in print_with_offsets(&bytes[..])? {
+        match offset.and_then(|offset| values.get(&offset)) {
+            Some(value) => writeln!(out, "{}  ;; {}", line, value).unwrap(),
+            None => writeln!(out, "{}", line).unwrap(),
+        }
+    }
+    Ok(out)
+}
+
+fn num_imported(module: &Module<'_>) -> usize {
+    module
+        .funcs
+        .values()
+        .take_while(|decl| matches!(decl, FuncDecl::Import(..)))
+        .count()
+}
+
+/// Disassemble `funcs` as `module` compiles them, objdump-style: for
+/// each, the backend's local assignments, then each instruction with
+/// its offset in the output, its bytes, its text, and the value it
+/// computes, or loads or stores to a local.
+pub fn disassemble(module: &Module<'_>, funcs: &[Func]) -> Result<String> {
+    let bytes = compile(module)?;
+    let bodies = wasmparser::Parser::new(0)
+        .parse_all(&bytes[..])
+        .filter_map(|payload| match payload {
+            Ok(wasmparser::Payload::CodeSectionEntry(body)) => Some(Ok(body)),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<wasmparser::Result<Vec<_>>>()?;
+    let text = print_with_offsets(&bytes[..])?
+        .into_iter()
+        .filter_map(|(offset, line)| Some((offset?, line)))
+        .collect::<HashMap<_, _>>();
+
+    let mut out = String::new();
+    for &func in funcs {
+        let decl = &module.funcs[func];
+        writeln!(out, "{} \"{}\": {}", func, decl.name(), decl.sig()).unwrap();
+        let body = match func.index().checked_sub(num_imported(module)) {
+            Some(index) => &bodies[index],
+            None => {
+                out.push_str("  # imported\n");
+                continue;
+            }
+        };
+        let lowered = lower(module, func)?;
+        match &lowered {
+            Some(lowered) => {
+                out.push_str("  locals:\n");
+                for line in &lowered.locals {
+                    writeln!(out, "    {}", line).unwrap();
+                }
+            }
+            None => out.push_str("  # copied from the input\n"),
+        }
+
+        let (offsets, end) = inst_offsets(body)?;
+        // Indented as printed, relative to the body.
+        let indent = offsets
+            .first()
+            .and_then(|offset| text.get(offset))
+            .map_or(0, |line| line.len() - line.trim_start().len());
+        for (i, &offset) in offsets.iter().enumerate() {
+            let next = offsets.get(i + 1).copied().unwrap_or(end);
+            let inst = bytes[offset..next]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            // The body's final `end` is left implicit in WAT.
+            let line = text.get(&offset).map_or("end", |line| {
+                line.get(indent..).unwrap_or(line.trim_start())
+            });
+            let mut line = format!("  {:06x}: {:<20} {}", offset, inst, line);
+            if let Some(Some(value)) = lowered.as_ref().map(|lowered| lowered.insts[i]) {
+                write!(line, "  ;; {}", value).unwrap();
+            }
+            writeln!(out, "{}", line.trim_end()).unwrap();
+        }
+    }
+    Ok(out)
+}
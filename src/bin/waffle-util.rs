@@ -5,7 +5,7 @@ use log::debug;
 use std::path::PathBuf;
 use structopt::StructOpt;
 use waffle::InterpContext;
-use waffle::{entity::EntityRef, FrontendOptions, Func, Module};
+use waffle::{entity::EntityRef, FrontendOptions, Func, Module, StripOptions};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "waffle-util", about = "WAFFLE utility.")]
@@ -53,6 +53,33 @@ enum Command {
         input: PathBuf,
         #[structopt(help = "Wasm file to produce", short = "o")]
         output: PathBuf,
+        #[structopt(help = "Source map to produce", long = "source-map")]
+        source_map: Option<PathBuf>,
+    },
+    #[structopt(name = "strip", about = "Remove debug info, names and custom sections")]
+    Strip {
+        #[structopt(help = "Wasm file to parse", short = "i")]
+        input: PathBuf,
+        #[structopt(help = "Wasm file to produce", short = "o")]
+        output: PathBuf,
+        #[structopt(help = "Keep DWARF debug info", long = "keep-dwarf")]
+        keep_dwarf: bool,
+        #[structopt(help = "Keep the name section", long = "keep-names")]
+        keep_names: bool,
+        #[structopt(help = "Keep the producers section", long = "keep-producers")]
+        keep_producers: bool,
+        #[structopt(
+            help = "Also remove custom sections matching this pattern (`*` matches anything)",
+            long = "remove-section"
+        )]
+        remove_sections: Vec<String>,
+    },
+    #[structopt(name = "size", about = "Report where the bytes of the emitted Wasm go")]
+    Size {
+        #[structopt(help = "Wasm file to parse", short = "i")]
+        input: PathBuf,
+        #[structopt(help = "Number of functions to list", long = "top")]
+        top: Option<usize>,
     },
     #[structopt(name = "interp", about = "Interpret Waffle IR from Wasm")]
     Interp {
@@ -105,14 +132,55 @@ fn main() -> Result<()> {
                     .display_verbose("", Some(&module))
             );
         }
-        Command::RoundTrip { input, output } => {
+        Command::RoundTrip {
+            input,
+            output,
+            source_map,
+        } => {
             let bytes = std::fs::read(input)?;
             debug!("Loaded {} bytes of Wasm data", bytes.len());
             let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
             apply_options(&opts, &mut module)?;
-            let produced = module.to_wasm_bytes()?;
+            module.add_waffle_producer()?;
+            let produced = match source_map {
+                Some(source_map) => {
+                    let url = source_map.file_name().and_then(|name| name.to_str());
+                    let (produced, map) =
+                        module.to_wasm_bytes_with_source_map(&input.to_string_lossy(), url)?;
+                    std::fs::write(source_map, map.to_json())?;
+                    produced
+                }
+                None => module.to_wasm_bytes()?,
+            };
             std::fs::write(output, &produced[..])?;
         }
+        Command::Strip {
+            input,
+            output,
+            keep_dwarf,
+            keep_names,
+            keep_producers,
+            remove_sections,
+        } => {
+            let bytes = std::fs::read(input)?;
+            debug!("Loaded {} bytes of Wasm data", bytes.len());
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            module.strip(&StripOptions {
+                dwarf: !keep_dwarf,
+                names: !keep_names,
+                producers: !keep_producers,
+                custom: remove_sections.clone(),
+            });
+            std::fs::write(output, &module.to_wasm_bytes()?[..])?;
+        }
+        Command::Size { input, top } => {
+            let bytes = std::fs::read(input)?;
+            debug!("Loaded {} bytes of Wasm data", bytes.len());
+            let mut module = Module::from_wasm_bytes(&bytes[..], &options)?;
+            apply_options(&opts, &mut module)?;
+            let (_, profile) = module.to_wasm_bytes_with_size_profile()?;
+            print!("{}", profile.report(*top));
+        }
         Command::Interp { input } => {
             let bytes = std::fs::read(input)?;
             debug!("Loaded {} bytes of Wasm data", bytes.len());
@@ -0,0 +1,978 @@
+//! The `waffle` command-line tool: inspect and transform Wasm modules
+//! through WAFFLE's IR.
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use structopt::StructOpt;
+use waffle::analysis::{CallGraph, DiffLine, ModuleDiff};
+use waffle::generate::{generate, GenConfig, GenFeatures};
+use waffle::mutate::Mutator;
+use waffle::passes::call_profile::CallProfileMap;
+use waffle::passes::inline;
+use waffle::passes::manager::{PassManager, Statistics};
+use waffle::passes::obfuscate::{self, ObfuscateOptions, Obfuscation};
+use waffle::passes::watermark;
+use waffle::report::Report;
+use waffle::{
+    entity::EntityRef, FrontendOptions, Func, FuncDecl, Module, SectionSize, SizeProfile, Timeline,
+    ValueDef,
+};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "waffle",
+    about = "Inspect and transform Wasm through WAFFLE's IR."
+)]
+struct Options {
+    #[structopt(
+        help = "Parse debug info from the input",
+        short = "g",
+        long = "debug-info",
+        global = true
+    )]
+    debug_info: bool,
+
+    #[structopt(
+        help = "Write a trace of where the time went, for chrome://tracing or Perfetto, to this file",
+        long = "trace",
+        global = true
+    )]
+    trace: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(
+        name = "print-ir",
+        about = "Lift Wasm and print the IR of each function"
+    )]
+    PrintIr {
+        #[structopt(help = "Wasm file to lift")]
+        wasm: PathBuf,
+        #[structopt(help = "Print only the function with this index", long = "func")]
+        func: Option<usize>,
+        #[structopt(
+            help = "Also print operand types, block params and aliases",
+            short,
+            long
+        )]
+        verbose: bool,
+    },
+    #[structopt(
+        name = "roundtrip",
+        about = "Lift Wasm, optionally run passes, compile it back and validate the result"
+    )]
+    RoundTrip {
+        #[structopt(help = "Wasm file to lift")]
+        input: PathBuf,
+        #[structopt(help = "Wasm file to produce", short = "o", long = "output")]
+        output: PathBuf,
+        #[structopt(
+            help = "Passes to run, comma-separated, e.g. `optimize` or `dce,gvn`",
+            long = "passes"
+        )]
+        passes: Option<String>,
+        #[structopt(
+            help = "Write sizes, pass statistics and warnings as JSON to this file",
+            long = "report"
+        )]
+        report: Option<PathBuf>,
+    },
+    #[structopt(name = "opt", about = "Optimize Wasm with a pipeline of passes")]
+    Opt {
+        #[structopt(help = "Wasm file to optimize")]
+        input: PathBuf,
+        #[structopt(help = "Wasm file to produce", short = "o", long = "output")]
+        output: PathBuf,
+        #[structopt(
+            help = "Passes to run, comma-separated: those of `PassManager::from_pipeline`, \
+                    and `inline` to inline small functions into their callers",
+            long = "passes",
+            default_value = "optimize"
+        )]
+        passes: String,
+        #[structopt(
+            help = "Inline functions of at most this many instructions",
+            long = "inline-threshold",
+            default_value = "32"
+        )]
+        inline_threshold: usize,
+        #[structopt(help = "Print what each pass did", long = "stats")]
+        stats: bool,
+        #[structopt(
+            help = "Keep functions that use unsupported features as they are",
+            long = "skip-unsupported"
+        )]
+        skip_unsupported: bool,
+        #[structopt(
+            help = "Write sizes, pass statistics and warnings as JSON to this file",
+            long = "report"
+        )]
+        report: Option<PathBuf>,
+    },
+    #[structopt(name = "stats", about = "Summarize the contents of a Wasm module")]
+    Stats {
+        #[structopt(help = "Wasm file to summarize")]
+        wasm: PathBuf,
+        #[structopt(
+            help = "Number of operators and functions to list",
+            long = "top",
+            default_value = "20"
+        )]
+        top: usize,
+        #[structopt(
+            help = "Also compile the module, without writing it, and report on the output",
+            long = "emit"
+        )]
+        emit: bool,
+    },
+    #[structopt(name = "diff", about = "Compare the functions of two Wasm modules")]
+    Diff {
+        #[structopt(help = "Old Wasm file")]
+        old: PathBuf,
+        #[structopt(help = "New Wasm file")]
+        new: PathBuf,
+        #[structopt(help = "Show how each changed function changed", short, long)]
+        verbose: bool,
+    },
+    #[structopt(
+        name = "dot",
+        about = "Print the CFG of a function as a Graphviz graph"
+    )]
+    Dot {
+        #[structopt(help = "Wasm file to lift")]
+        wasm: PathBuf,
+        #[structopt(help = "Index of the function to print", long = "func")]
+        func: usize,
+        #[structopt(help = "Run these passes first, comma-separated", long = "passes")]
+        passes: Option<String>,
+    },
+    #[structopt(
+        name = "mutate",
+        about = "Apply random mutations to the IR, e.g. to fuzz other engines"
+    )]
+    Mutate {
+        #[structopt(help = "Wasm file to mutate")]
+        input: PathBuf,
+        #[structopt(help = "Wasm file to produce", short = "o", long = "output")]
+        output: PathBuf,
+        #[structopt(
+            help = "Seed of the random choices",
+            long = "seed",
+            default_value = "0"
+        )]
+        seed: u64,
+        #[structopt(
+            help = "Number of mutations to apply",
+            long = "count",
+            default_value = "1"
+        )]
+        count: usize,
+        #[structopt(
+            help = "Apply only mutations that preserve the module's behavior",
+            long = "preserving"
+        )]
+        preserving: bool,
+    },
+    #[structopt(
+        name = "generate",
+        about = "Generate a random valid module, e.g. to benchmark or fuzz with"
+    )]
+    Generate {
+        #[structopt(help = "Wasm file to produce", short = "o", long = "output")]
+        output: PathBuf,
+        #[structopt(
+            help = "Seed of the random choices",
+            long = "seed",
+            default_value = "0"
+        )]
+        seed: u64,
+        #[structopt(help = "Number of functions", long = "funcs", default_value = "8")]
+        funcs: usize,
+        #[structopt(
+            help = "How deeply loops nest",
+            long = "max-loop-depth",
+            default_value = "2"
+        )]
+        max_loop_depth: usize,
+        #[structopt(
+            help = "Percentage of instructions that access memory",
+            long = "memory-ops",
+            default_value = "10"
+        )]
+        memory_ops: u32,
+        #[structopt(
+            help = "Features to use, comma-separated, out of floats, sign-ext, sat-float-to-int and multi-value; all by default",
+            long = "features"
+        )]
+        features: Option<String>,
+    },
+    #[structopt(
+        name = "reduce",
+        about = "Shrink a module while a command still finds it interesting"
+    )]
+    Reduce {
+        #[structopt(help = "Wasm file to reduce")]
+        input: PathBuf,
+        #[structopt(help = "Wasm file to produce", short = "o", long = "output")]
+        output: PathBuf,
+        #[structopt(
+            help = "Shell command run on each candidate, as $1; exit status 0 means interesting",
+            long = "command"
+        )]
+        command: String,
+    },
+    #[structopt(
+        name = "obfuscate",
+        about = "Flatten control flow and insert opaque predicates"
+    )]
+    Obfuscate {
+        #[structopt(help = "Wasm file to obfuscate")]
+        input: PathBuf,
+        #[structopt(help = "Wasm file to produce", short = "o", long = "output")]
+        output: PathBuf,
+        #[structopt(
+            help = "Seed of the random choices",
+            long = "seed",
+            default_value = "0"
+        )]
+        seed: u64,
+        #[structopt(help = "Do not flatten control flow", long = "no-flatten")]
+        no_flatten: bool,
+        #[structopt(
+            help = "Percentage of blocks to guard with an opaque predicate",
+            long = "opaque-predicates",
+            default_value = "50"
+        )]
+        opaque_predicates: u32,
+        #[structopt(
+            help = "Obfuscate only the functions with these indices; all by default",
+            long = "func"
+        )]
+        funcs: Vec<usize>,
+    },
+    #[structopt(
+        name = "watermark",
+        about = "Embed a keyed watermark in a module's code and a custom section"
+    )]
+    Watermark {
+        #[structopt(help = "Wasm file to watermark")]
+        input: PathBuf,
+        #[structopt(help = "Wasm file to produce", short = "o", long = "output")]
+        output: PathBuf,
+        #[structopt(help = "The watermark", long = "mark")]
+        mark: String,
+        #[structopt(help = "Key to embed it with", long = "key")]
+        key: u64,
+    },
+    #[structopt(
+        name = "verify-watermark",
+        about = "Check whether a module carries a watermark"
+    )]
+    VerifyWatermark {
+        #[structopt(help = "Wasm file to check")]
+        wasm: PathBuf,
+        #[structopt(help = "The watermark", long = "mark")]
+        mark: String,
+        #[structopt(help = "Key it was embedded with", long = "key")]
+        key: u64,
+    },
+    #[structopt(
+        name = "disasm",
+        about = "Disassemble compiled functions, annotated with the IR values and locals"
+    )]
+    Disasm {
+        #[structopt(help = "Wasm file to lift")]
+        wasm: PathBuf,
+        #[structopt(help = "Disassemble only the function with this index", long = "func")]
+        func: Option<usize>,
+        #[structopt(help = "Run these passes first, comma-separated", long = "passes")]
+        passes: Option<String>,
+    },
+    #[structopt(
+        name = "callgraph",
+        about = "Print the call graph of a module as a Graphviz graph or JSON"
+    )]
+    CallGraph {
+        #[structopt(help = "Wasm file to lift")]
+        wasm: PathBuf,
+        #[structopt(help = "Print JSON rather than a Graphviz graph", long = "json")]
+        json: bool,
+        #[structopt(
+            help = "The module as instrumented by the call-profile pass",
+            long = "profile",
+            requires = "counters"
+        )]
+        profile: Option<PathBuf>,
+        #[structopt(
+            help = "A dump of its counters, to annotate edges with call counts",
+            long = "counters",
+            requires = "profile"
+        )]
+        counters: Option<PathBuf>,
+    },
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("Cannot read {}", path.display()))
+}
+
+/// Read a module, binary or WAT.
+fn read_module(path: &Path) -> Result<Vec<u8>> {
+    let bytes = read(path)?;
+    #[cfg(feature = "wat")]
+    let bytes = Module::wat_to_wasm(&bytes)
+        .with_context(|| format!("Cannot parse {}", path.display()))?
+        .into_owned();
+    #[cfg(not(feature = "wat"))]
+    if !bytes.starts_with(b"\0asm") {
+        bail!(
+            "{} is not a binary module; WAT input needs the `wat` feature",
+            path.display()
+        );
+    }
+    Ok(bytes)
+}
+
+/// Validate `produced`, compiled from `module`, and write it to
+/// `output`: as WAT annotated with the IR values if its extension is
+/// `.wat`.
+fn write_output(module: &Module, produced: &[u8], output: &Path) -> Result<()> {
+    validate_output(module, produced)?;
+    let text;
+    let contents = if matches!(output.extension(), Some(ext) if ext == "wat") {
+        text = annotated_wat(module)?;
+        text.as_bytes()
+    } else {
+        produced
+    };
+    std::fs::write(output, contents).with_context(|| format!("Cannot write {}", output.display()))
+}
+
+/// Compile `module`, and measure where its bytes went if `profile`.
+fn compile(module: &Module, profile: bool) -> Result<(Vec<u8>, Option<SizeProfile>)> {
+    if profile {
+        let (bytes, profile) = module.to_wasm_bytes_with_size_profile()?;
+        Ok((bytes, Some(profile)))
+    } else {
+        Ok((module.to_wasm_bytes()?, None))
+    }
+}
+
+/// Write the report on a run that started at `start`, turned `input`
+/// into a module with the size profile `profile` and ran passes with
+/// `stats`, to `path`.
+fn write_report(
+    module: &Module,
+    input: &[u8],
+    profile: &SizeProfile,
+    stats: &[Statistics],
+    start: Instant,
+    path: &Path,
+) -> Result<()> {
+    let mut report = Report::new(module, input, Some(profile))?;
+    for stats in stats {
+        report.add_stats(stats);
+    }
+    report.time = Some(start.elapsed());
+    std::fs::write(path, report.to_json())
+        .with_context(|| format!("Cannot write {}", path.display()))
+}
+
+#[cfg(feature = "wat")]
+fn annotated_wat(module: &Module) -> Result<String> {
+    module.to_annotated_wat()
+}
+
+#[cfg(not(feature = "wat"))]
+fn annotated_wat(_module: &Module) -> Result<String> {
+    bail!("WAT output needs the `wat` feature")
+}
+
+#[cfg(feature = "wat")]
+fn disassemble(module: &Module, funcs: &[Func]) -> Result<String> {
+    module.disassemble(funcs)
+}
+
+#[cfg(not(feature = "wat"))]
+fn disassemble(_module: &Module, _funcs: &[Func]) -> Result<String> {
+    bail!("Disassembly needs the `wat` feature")
+}
+
+/// Parse `bytes`, read from `path`; function bodies are left to be
+/// lifted.
+fn parse<'a>(path: &Path, bytes: &'a [u8], options: &FrontendOptions) -> Result<Module<'a>> {
+    Module::from_wasm_bytes(bytes, options)
+        .with_context(|| format!("Cannot parse {}", path.display()))
+}
+
+/// `func`'s name and signature, followed by its IR if it has a body.
+fn describe_func(module: &Module, func: Func, verbose: bool) -> String {
+    let decl = &module.funcs[func];
+    let header = format!("{} \"{}\": {}", func, decl.name(), decl.sig());
+    match decl {
+        FuncDecl::Body(_, _, body) if verbose => {
+            format!("{}\n{}", header, body.display_verbose("  ", Some(module)))
+        }
+        FuncDecl::Body(_, _, body) => format!("{}\n{}", header, body.display("  ", Some(module))),
+        FuncDecl::Import(..) => format!("{}\n  # imported\n", header),
+        // Bodies that failed to lift and were skipped stay lazy.
+        FuncDecl::Lazy(..) => format!("{}\n  # not lifted\n", header),
+        FuncDecl::Compiled(..) => format!("{}\n  # already compiled\n", header),
+        FuncDecl::None => format!("{}\n  # none\n", header),
+    }
+}
+
+/// Validate the emitted module `bytes`, made from `module`. Errors in
+/// function bodies are reported with the IR they were compiled from,
+/// and validation carries on with the next body.
+fn validate_output(module: &Module, bytes: &[u8]) -> Result<()> {
+    let mut validator = wasmparser::Validator::new_with_features(wasmparser::WasmFeatures {
+        threads: true,
+        ..Default::default()
+    });
+    let mut failed = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+        let payload = payload?;
+        let (func, body) = match validator
+            .payload(&payload)
+            .context("Output is not valid Wasm")?
+        {
+            wasmparser::ValidPayload::Func(func, body) => (func, body),
+            _ => continue,
+        };
+        let mut func = func.into_validator(Default::default());
+        if let Err(err) = func.validate(&body) {
+            let id = Func::new(func.index() as usize);
+            eprintln!("error: {}\n{}", err, describe_func(module, id, false));
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{} function bodies in the output are not valid Wasm",
+            failed
+        );
+    }
+    Ok(())
+}
+
+/// Print the sizes of the sections of `bytes`, from which `module` was
+/// parsed, and what its function bodies contain.
+fn print_stats(module: &Module, bytes: &[u8], top: usize) -> Result<()> {
+    let imported = module
+        .funcs
+        .values()
+        .filter(|decl| matches!(decl, FuncDecl::Import(..)))
+        .count();
+    println!(
+        "{} functions: {} imported, {} defined",
+        module.funcs.len(),
+        imported,
+        module.funcs.len() - imported
+    );
+
+    println!("\n{:>10}  section", "bytes");
+    for section in SectionSize::of_module(bytes)? {
+        println!("{:>10}  {}", section.size, section.name);
+    }
+    println!("{:>10}  (total)", bytes.len());
+
+    let mut ops: HashMap<String, usize> = HashMap::new();
+    let (mut blocks, mut insts, mut locals) = (0, 0, 0);
+    let mut most_locals = None;
+    for (func, decl) in module.funcs.entries() {
+        let body = match decl.body() {
+            Some(body) => body,
+            None => continue,
+        };
+        blocks += body.blocks.len();
+        for block in body.blocks.values() {
+            insts += block.insts.len();
+            for &inst in &block.insts {
+                if let ValueDef::Operator(op, ..) = &body.values[inst] {
+                    // Leave out immediates, as in `i32const<16>`.
+                    let name = op.to_string();
+                    let name = name.split('<').next().unwrap();
+                    *ops.entry(name.to_owned()).or_default() += 1;
+                }
+            }
+        }
+        let declared = body.locals.len() - body.n_params;
+        locals += declared;
+        if !matches!(most_locals, Some((_, most)) if most >= declared) {
+            most_locals = Some((func, declared));
+        }
+    }
+    println!(
+        "\n{} blocks, {} instructions, {} locals declared",
+        blocks, insts, locals
+    );
+    if let Some((func, declared)) = most_locals {
+        println!(
+            "most locals: {} in {} \"{}\"",
+            declared,
+            func,
+            module.funcs[func].name()
+        );
+    }
+
+    let mut ops = ops.into_iter().collect::<Vec<_>>();
+    ops.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    println!("\n{:>10} {:>7}  operator", "count", "%");
+    for (name, count) in ops.into_iter().take(top) {
+        println!(
+            "{:>10} {:>6.2}%  {}",
+            count,
+            100.0 * count as f64 / insts.max(1) as f64,
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Print the changed lines of a function's listing, with a few lines
+/// of context around each run of changes.
+fn print_diff_lines(lines: &[DiffLine]) {
+    const CONTEXT: usize = 2;
+    let changed = |i: usize| !matches!(lines.get(i), Some(DiffLine::Same(_)) | None);
+    let mut skipped = false;
+    for (i, line) in lines.iter().enumerate() {
+        let near = (i.saturating_sub(CONTEXT)..=i + CONTEXT).any(changed);
+        match line {
+            DiffLine::Same(_) if !near => {
+                skipped = true;
+                continue;
+            }
+            _ if skipped => {
+                println!("    ...");
+                skipped = false;
+            }
+            _ => {}
+        }
+        match line {
+            DiffLine::Same(text) => println!("     {}", text),
+            DiffLine::Removed(text) => println!("   - {}", text),
+            DiffLine::Added(text) => println!("   + {}", text),
+        }
+    }
+}
+
+/// The number of locals the function bodies of `bytes` declare.
+fn count_locals(bytes: &[u8]) -> Result<u64> {
+    let mut locals = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+        if let wasmparser::Payload::CodeSectionEntry(body) = payload? {
+            let mut reader = body.get_locals_reader()?;
+            for _ in 0..reader.get_count() {
+                locals += reader.read()?.0 as u64;
+            }
+        }
+    }
+    Ok(locals)
+}
+
+fn main() -> Result<()> {
+    let opts = Options::from_args();
+    let _ = env_logger::try_init();
+
+    let frontend = FrontendOptions {
+        debug: opts.debug_info,
+        timeline: opts.trace.as_ref().map(|_| Timeline::new()),
+    };
+    let result = run(&opts.command, &frontend);
+    if let (Some(path), Some(timeline)) = (&opts.trace, &frontend.timeline) {
+        std::fs::write(path, timeline.to_json())
+            .with_context(|| format!("Cannot write {}", path.display()))?;
+    }
+    result
+}
+
+fn run(command: &Command, frontend: &FrontendOptions) -> Result<()> {
+    match command {
+        Command::PrintIr {
+            wasm,
+            func,
+            verbose,
+        } => {
+            let bytes = read_module(wasm)?;
+            let mut module = parse(wasm, &bytes, frontend)?;
+            match *func {
+                Some(index) => {
+                    if index >= module.funcs.len() {
+                        bail!(
+                            "No function {}: the module has {}",
+                            index,
+                            module.funcs.len()
+                        );
+                    }
+                    let func = Func::new(index);
+                    module.expand_func(func)?;
+                    print!("{}", describe_func(&module, func, *verbose));
+                }
+                None => {
+                    module.expand_all_funcs()?;
+                    for func in module.funcs.iter() {
+                        print!("{}", describe_func(&module, func, *verbose));
+                    }
+                }
+            }
+        }
+        Command::Opt {
+            input,
+            output,
+            passes,
+            inline_threshold,
+            stats,
+            skip_unsupported,
+            report,
+        } => {
+            let start = Instant::now();
+            let bytes = read_module(input)?;
+            let mut module = parse(input, &bytes, frontend)?;
+            module.set_skip_unsupported(*skip_unsupported);
+            let mut all_stats = vec![];
+            // Runs of body passes go to a pass manager, between the
+            // module-wide inlining steps.
+            let names = passes.split(',').map(str::trim).collect::<Vec<_>>();
+            for (i, run) in names.split(|&name| name == "inline").enumerate() {
+                if i > 0 {
+                    let inlined = inline::run(&mut module, *inline_threshold)?;
+                    if *stats {
+                        println!("inline: {} calls inlined", inlined);
+                    }
+                }
+                let manager = PassManager::from_pipeline(&run.join(","))?;
+                let pass_stats = manager.run(&mut module)?;
+                if *stats && !pass_stats.passes.is_empty() {
+                    println!("{}", pass_stats);
+                }
+                all_stats.push(pass_stats);
+            }
+            let (produced, profile) = compile(&module, report.is_some())?;
+            write_output(&module, &produced, output)?;
+            if let (Some(path), Some(profile)) = (report, &profile) {
+                write_report(&module, &bytes, profile, &all_stats, start, path)?;
+            }
+        }
+        Command::Stats { wasm, top, emit } => {
+            let bytes = read_module(wasm)?;
+            let mut module = parse(wasm, &bytes, frontend)?;
+            module.expand_all_funcs()?;
+            print_stats(&module, &bytes, *top)?;
+            if *emit {
+                let (produced, profile) = module.to_wasm_bytes_with_size_profile()?;
+                println!(
+                    "\nemitted {} bytes ({:+} from the input), {} locals",
+                    produced.len(),
+                    produced.len() as isize - bytes.len() as isize,
+                    count_locals(&produced)?
+                );
+                print!("{}", profile.report(Some(*top)));
+            }
+        }
+        Command::Diff { old, new, verbose } => {
+            let (old_bytes, new_bytes) = (read_module(old)?, read_module(new)?);
+            let old = parse(old, &old_bytes, frontend)?;
+            let new = parse(new, &new_bytes, frontend)?;
+            let diff = ModuleDiff::compute(&old, &new)?;
+            for change in &diff.changed {
+                println!(
+                    "changed {} -> {} \"{}\": +{} -{} lines",
+                    change.old,
+                    change.new,
+                    new.funcs[change.new].name(),
+                    change.lines_added(),
+                    change.lines_removed()
+                );
+                if *verbose {
+                    print_diff_lines(&change.lines);
+                }
+            }
+            for &func in &diff.removed {
+                println!("removed {} \"{}\"", func, old.funcs[func].name());
+            }
+            for &func in &diff.added {
+                println!("added {} \"{}\"", func, new.funcs[func].name());
+            }
+            let moved = diff
+                .unchanged
+                .iter()
+                .filter(|&&(old_func, new_func)| {
+                    old_func != new_func || old.funcs[old_func].name() != new.funcs[new_func].name()
+                })
+                .count();
+            println!(
+                "{} changed, {} removed, {} added, {} unchanged ({} moved or renamed)",
+                diff.changed.len(),
+                diff.removed.len(),
+                diff.added.len(),
+                diff.unchanged.len(),
+                moved
+            );
+        }
+        Command::Dot { wasm, func, passes } => {
+            let bytes = read_module(wasm)?;
+            let mut module = parse(wasm, &bytes, frontend)?;
+            if *func >= module.funcs.len() {
+                bail!(
+                    "No function {}: the module has {}",
+                    func,
+                    module.funcs.len()
+                );
+            }
+            let body = match module.func_body(Func::new(*func))? {
+                Some(body) => body,
+                None => bail!("{} has no body", Func::new(*func)),
+            };
+            if let Some(passes) = passes {
+                PassManager::from_pipeline(passes)?.run_on_body(body)?;
+            }
+            print!("{}", body.to_dot());
+        }
+        Command::Mutate {
+            input,
+            output,
+            seed,
+            count,
+            preserving,
+        } => {
+            let bytes = read_module(input)?;
+            let mut module = parse(input, &bytes, frontend)?;
+            let mut mutator = Mutator::new(*seed);
+            if *preserving {
+                mutator = mutator.preserving();
+            }
+            for (func, mutation) in mutator.mutate(&mut module, *count)? {
+                println!("{} \"{}\": {}", func, module.funcs[func].name(), mutation);
+            }
+            let produced = module.to_wasm_bytes()?;
+            write_output(&module, &produced, output)?;
+        }
+        Command::Generate {
+            output,
+            seed,
+            funcs,
+            max_loop_depth,
+            memory_ops,
+            features,
+        } => {
+            let mut config = GenConfig {
+                funcs: *funcs,
+                max_loop_depth: *max_loop_depth,
+                memory_ops: *memory_ops,
+                ..GenConfig::default()
+            };
+            if let Some(features) = features {
+                config.features = GenFeatures {
+                    floats: false,
+                    sign_ext: false,
+                    sat_float_to_int: false,
+                    multi_value: false,
+                };
+                for feature in features.split(',').filter(|f| !f.is_empty()) {
+                    match feature {
+                        "floats" => config.features.floats = true,
+                        "sign-ext" => config.features.sign_ext = true,
+                        "sat-float-to-int" => config.features.sat_float_to_int = true,
+                        "multi-value" => config.features.multi_value = true,
+                        _ => bail!("Unknown feature: {}", feature),
+                    }
+                }
+            }
+            let module = generate(&config, *seed);
+            let produced = module.to_wasm_bytes()?;
+            write_output(&module, &produced, output)?;
+        }
+        Command::Reduce {
+            input,
+            output,
+            command,
+        } => {
+            let bytes = read_module(input)?;
+            let module = parse(input, &bytes, frontend)?;
+            let candidate =
+                std::env::temp_dir().join(format!("waffle-reduce-{}.wasm", std::process::id()));
+            let reduced = waffle::reduce::reduce(module, |bytes| {
+                std::fs::write(&candidate, bytes)?;
+                let status = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .arg("sh")
+                    .arg(&candidate)
+                    .stdout(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::null())
+                    .status()
+                    .with_context(|| format!("Failed to run {:?}", command))?;
+                Ok(status.success())
+            });
+            let _ = std::fs::remove_file(&candidate);
+            let reduced = reduced?;
+            println!(
+                "{} -> {} bytes after {} tests",
+                bytes.len(),
+                reduced.bytes.len(),
+                reduced.tests
+            );
+            write_output(&reduced.module, &reduced.bytes, output)?;
+        }
+        Command::Obfuscate {
+            input,
+            output,
+            seed,
+            no_flatten,
+            opaque_predicates,
+            funcs,
+        } => {
+            let bytes = read_module(input)?;
+            let mut module = parse(input, &bytes, frontend)?;
+            let obfuscation = Obfuscation {
+                flatten: !no_flatten,
+                opaque_predicates: *opaque_predicates,
+            };
+            let mut options = ObfuscateOptions {
+                default: obfuscation,
+                seed: *seed,
+                ..ObfuscateOptions::default()
+            };
+            if !funcs.is_empty() {
+                options.default = Obfuscation::default();
+                for &index in funcs {
+                    if index >= module.funcs.len() {
+                        bail!(
+                            "No function {}: the module has {}",
+                            index,
+                            module.funcs.len()
+                        );
+                    }
+                    options.funcs.insert(Func::new(index), obfuscation);
+                }
+            }
+            obfuscate::run(&mut module, &options)?;
+            let produced = module.to_wasm_bytes()?;
+            write_output(&module, &produced, output)?;
+        }
+        Command::Watermark {
+            input,
+            output,
+            mark,
+            key,
+        } => {
+            let bytes = read_module(input)?;
+            let mut module = parse(input, &bytes, frontend)?;
+            let sites = watermark::embed(&mut module, mark.as_bytes(), *key)?;
+            println!("{} instructions carry the watermark", sites);
+            let produced = module.to_wasm_bytes()?;
+            write_output(&module, &produced, output)?;
+        }
+        Command::VerifyWatermark { wasm, mark, key } => {
+            let bytes = read_module(wasm)?;
+            let module = parse(wasm, &bytes, frontend)?;
+            let verification = watermark::verify(&module, mark.as_bytes(), *key)?;
+            println!(
+                "section: {}",
+                if verification.section {
+                    "found"
+                } else {
+                    "not found"
+                }
+            );
+            println!(
+                "code: {} of {} instructions match",
+                verification.matching, verification.sites
+            );
+            if !verification.found() {
+                bail!("The watermark was not found");
+            }
+            println!("The watermark was found");
+        }
+        Command::Disasm { wasm, func, passes } => {
+            let bytes = read_module(wasm)?;
+            let mut module = parse(wasm, &bytes, frontend)?;
+            let funcs = match *func {
+                Some(index) if index >= module.funcs.len() => bail!(
+                    "No function {}: the module has {}",
+                    index,
+                    module.funcs.len()
+                ),
+                Some(index) => {
+                    module.expand_func(Func::new(index))?;
+                    vec![Func::new(index)]
+                }
+                None => {
+                    module.expand_all_funcs()?;
+                    module.funcs.iter().collect()
+                }
+            };
+            if let Some(passes) = passes {
+                PassManager::from_pipeline(passes)?.run(&mut module)?;
+            }
+            print!("{}", disassemble(&module, &funcs)?);
+        }
+        Command::CallGraph {
+            wasm,
+            json,
+            profile,
+            counters,
+        } => {
+            let bytes = read_module(wasm)?;
+            let module = parse(wasm, &bytes, frontend)?;
+            let edges = match (profile, counters) {
+                (Some(profile), Some(counters)) => {
+                    let instrumented = read_module(profile)?;
+                    let instrumented = parse(profile, &instrumented, frontend)?;
+                    let map = match instrumented.custom_section(CallProfileMap::SECTION_NAME) {
+                        Some(section) => CallProfileMap::parse(&section.data)?,
+                        None => bail!(
+                            "{} has no {} section",
+                            profile.display(),
+                            CallProfileMap::SECTION_NAME
+                        ),
+                    };
+                    Some(map.decode(&read(counters)?)?)
+                }
+                _ => None,
+            };
+            let graph = CallGraph::compute(&module)?;
+            if *json {
+                println!("{}", graph.to_json(&module, edges.as_deref()));
+            } else {
+                print!("{}", graph.to_dot(&module, edges.as_deref()));
+            }
+        }
+        Command::RoundTrip {
+            input,
+            output,
+            passes,
+            report,
+        } => {
+            let start = Instant::now();
+            let bytes = read_module(input)?;
+            let mut module = parse(input, &bytes, frontend)?;
+            module.expand_all_funcs()?;
+            for (func, decl) in module.funcs.entries() {
+                if let Some(body) = decl.body() {
+                    if let Err(err) = body.validate() {
+                        bail!("{:#}\n{}", err, describe_func(&module, func, true));
+                    }
+                }
+            }
+            let mut all_stats = vec![];
+            if let Some(passes) = passes {
+                all_stats.push(PassManager::from_pipeline(passes)?.run(&mut module)?);
+            }
+            let (produced, profile) = compile(&module, report.is_some())?;
+            write_output(&module, &produced, output)?;
+            if let (Some(path), Some(profile)) = (report, &profile) {
+                write_report(&module, &bytes, profile, &all_stats, start, path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
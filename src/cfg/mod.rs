@@ -6,6 +6,7 @@
 use crate::declare_entity;
 use crate::entity::{EntityRef, EntityVec, PerEntity};
 use crate::ir::{Block, FunctionBody, Terminator, Value, ValueDef};
+use crate::prelude::*;
 use smallvec::SmallVec;
 
 pub mod domtree;
@@ -77,10 +78,9 @@ impl CFGInfo {
             preds.dedup();
         }
 
-        let postorder = postorder::calculate(f.entry, |block| &f.blocks[block].succs[..]);
+        let postorder = postorder::calculate(f.entry, |block| f.succs(block));
 
-        let domtree =
-            domtree::calculate(|block| &f.blocks[block].preds[..], &postorder[..], f.entry);
+        let domtree = domtree::calculate(|block| f.preds(block), &postorder[..], f.entry);
 
         let mut domtree_children: PerEntity<Block, DomtreeChildren> = PerEntity::default();
         for block in f.blocks.iter().rev() {
@@ -5,6 +5,7 @@
 
 use crate::entity::PerEntity;
 use crate::ir::Block;
+use crate::prelude::*;
 use smallvec::{smallvec, SmallVec};
 
 pub fn calculate<'a, SuccFn: Fn(Block) -> &'a [Block]>(
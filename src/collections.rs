@@ -0,0 +1,82 @@
+//! Hash maps and sets: those of `std` and `fxhash`, or without `std`,
+//! `hashbrown`'s, with the same Fx hash for the deterministic ones.
+
+#[cfg(feature = "std")]
+pub(crate) use fxhash::{hash64, FxHashMap};
+#[cfg(feature = "std")]
+pub(crate) use std::collections::{hash_map, HashMap, HashSet};
+
+#[cfg(not(feature = "std"))]
+pub(crate) use fx::{hash64, FxHashMap};
+#[cfg(not(feature = "std"))]
+pub(crate) use hashbrown::{hash_map, HashMap, HashSet};
+
+/// `fxhash`'s 64-bit hash, which that crate only provides with `std`.
+#[cfg(not(feature = "std"))]
+mod fx {
+    use core::convert::TryInto;
+    use core::hash::{BuildHasherDefault, Hash, Hasher};
+
+    pub type FxHashMap<K, V> = hashbrown::HashMap<K, V, BuildHasherDefault<FxHasher>>;
+
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+    #[derive(Default)]
+    pub struct FxHasher {
+        hash: u64,
+    }
+
+    impl FxHasher {
+        fn add(&mut self, word: u64) {
+            self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+        }
+    }
+
+    impl Hasher for FxHasher {
+        fn write(&mut self, mut bytes: &[u8]) {
+            while bytes.len() >= 8 {
+                let (word, rest) = bytes.split_at(8);
+                self.add(u64::from_ne_bytes(word.try_into().unwrap()));
+                bytes = rest;
+            }
+            if bytes.len() >= 4 {
+                let (word, rest) = bytes.split_at(4);
+                self.add(u32::from_ne_bytes(word.try_into().unwrap()) as u64);
+                bytes = rest;
+            }
+            for &byte in bytes {
+                self.add(byte as u64);
+            }
+        }
+
+        fn write_u8(&mut self, i: u8) {
+            self.add(i as u64);
+        }
+
+        fn write_u16(&mut self, i: u16) {
+            self.add(i as u64);
+        }
+
+        fn write_u32(&mut self, i: u32) {
+            self.add(i as u64);
+        }
+
+        fn write_u64(&mut self, i: u64) {
+            self.add(i);
+        }
+
+        fn write_usize(&mut self, i: usize) {
+            self.add(i as u64);
+        }
+
+        fn finish(&self) -> u64 {
+            self.hash
+        }
+    }
+
+    pub(crate) fn hash64<T: Hash + ?Sized>(value: &T) -> u64 {
+        let mut hasher = FxHasher::default();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+}
@@ -0,0 +1,265 @@
+//! Differential execution of modules under wasmtime, to check that
+//! what waffle writes behaves like what it read.
+
+use crate::interp::diff::{same_value, Rng};
+use crate::{ConstVal, FrontendOptions, Module, Type};
+use anyhow::Result;
+use wasmtime::{Engine, ExternType, Func, Instance, Store, Trap, Val, ValType};
+
+/// How `engine_diff` runs the modules.
+#[derive(Clone, Debug)]
+pub struct EngineDiffOptions {
+    /// The number of calls of each exported function, each with new
+    /// arguments and in new instances.
+    pub runs: usize,
+    /// The seed the arguments are generated from.
+    pub seed: u64,
+    /// The fuel of each instantiation and call of the original module.
+    /// Calls that run out of it are not compared. The other module gets
+    /// ten times as much, as its code may be longer.
+    pub fuel: u64,
+    /// Whether `engine_diff_roundtrip` also optimizes the bodies.
+    pub optimize: bool,
+}
+
+impl Default for EngineDiffOptions {
+    fn default() -> Self {
+        EngineDiffOptions {
+            runs: 10,
+            seed: 0x5eed,
+            fuel: 100_000,
+            optimize: true,
+        }
+    }
+}
+
+/// Parse `bytes` with waffle, expand (and optionally optimize) all
+/// bodies, write the module back out and compare the two with
+/// `engine_diff`.
+pub fn engine_diff_roundtrip(bytes: &[u8], options: &EngineDiffOptions) -> Result<usize> {
+    let mut module = Module::from_wasm_bytes(bytes, &FrontendOptions::default())?;
+    module.expand_all_funcs()?;
+    if options.optimize {
+        module.per_func_body(|body| body.optimize());
+    }
+    let roundtrip = module.to_wasm_bytes()?;
+    engine_diff(bytes, &roundtrip[..], options)
+}
+
+/// Instantiate `orig` and `other` and call each function `orig` exports
+/// with generated arguments in both, and bail on the first call whose
+/// results, traps, or exported globals or memories afterwards differ.
+/// Imported functions trap; calls reaching them are not compared, and
+/// other kinds of imports are not supported. Traps are compared by
+/// their occurrence only, and NaNs compare equal to each other. Returns
+/// the number of calls that were compared.
+pub fn engine_diff(orig: &[u8], other: &[u8], options: &EngineDiffOptions) -> Result<usize> {
+    let mut config = wasmtime::Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+    let orig = wasmtime::Module::new(&engine, orig)?;
+    let other = wasmtime::Module::new(&engine, other)?;
+    let other_fuel = options.fuel.saturating_mul(10);
+
+    let mut funcs = vec![];
+    for export in orig.exports() {
+        if let ExternType::Func(ty) = export.ty() {
+            let params = ty.params().map(param_type).collect::<Option<Vec<_>>>();
+            if let Some(params) = params {
+                funcs.push((export.name().to_owned(), params));
+            }
+        }
+    }
+
+    // Instantiation alone, which runs the start function.
+    let expected = run(&orig, options.fuel, None)?;
+    let actual = run(&other, other_fuel, None)?;
+    if !compare(&expected, &actual, "instantiation")? {
+        return Ok(0);
+    }
+    let mut compared = 1;
+    if !matches!(expected.outcome, Outcome::Returned(_)) {
+        return Ok(compared);
+    }
+
+    let mut rng = Rng::new(options.seed);
+    for (name, params) in &funcs {
+        for _ in 0..options.runs {
+            let args = params
+                .iter()
+                .map(|&ty| rng.value(ty))
+                .collect::<Result<Vec<_>>>()?;
+            let expected = run(&orig, options.fuel, Some((name, &args[..])))?;
+            let actual = run(&other, other_fuel, Some((name, &args[..])))?;
+            let call = format!("{} called with {:?}", name, args);
+            if compare(&expected, &actual, &call)? {
+                compared += 1;
+            }
+        }
+    }
+    Ok(compared)
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Returned(Vec<Val>),
+    Trapped,
+    OutOfFuel,
+    /// An imported function was called.
+    Import,
+}
+
+/// The outcome of an instantiation or call, and the exported globals
+/// and memories afterwards.
+struct Run {
+    outcome: Outcome,
+    globals: Vec<(String, Val)>,
+    memories: Vec<(String, Vec<u8>)>,
+}
+
+fn param_type(ty: ValType) -> Option<Type> {
+    match ty {
+        ValType::I32 => Some(Type::I32),
+        ValType::I64 => Some(Type::I64),
+        ValType::F32 => Some(Type::F32),
+        ValType::F64 => Some(Type::F64),
+        _ => None,
+    }
+}
+
+fn to_val(value: ConstVal) -> Val {
+    match value {
+        ConstVal::I32(x) => Val::I32(x as i32),
+        ConstVal::I64(x) => Val::I64(x as i64),
+        ConstVal::F32(x) => Val::F32(x),
+        ConstVal::F64(x) => Val::F64(x),
+        ConstVal::None => unreachable!(),
+    }
+}
+
+fn outcome(err: &anyhow::Error) -> Outcome {
+    match err.downcast_ref::<Trap>() {
+        Some(Trap::OutOfFuel) => Outcome::OutOfFuel,
+        Some(_) => Outcome::Trapped,
+        None => Outcome::Import,
+    }
+}
+
+/// Instantiate `module` in a new store with `fuel`, then make `call`.
+fn run(module: &wasmtime::Module, fuel: u64, call: Option<(&str, &[ConstVal])>) -> Result<Run> {
+    let mut store = Store::new(module.engine(), ());
+    store.add_fuel(fuel)?;
+    let mut imports = vec![];
+    for import in module.imports() {
+        match import.ty() {
+            ExternType::Func(ty) => {
+                let func = Func::new(&mut store, ty, |_, _, _| {
+                    anyhow::bail!("Call to an imported function")
+                });
+                imports.push(func.into());
+            }
+            _ => anyhow::bail!("Unsupported import: {}.{}", import.module(), import.name()),
+        }
+    }
+    let instance = match Instance::new(&mut store, module, &imports[..]) {
+        Ok(instance) => instance,
+        Err(err) => {
+            return Ok(Run {
+                outcome: outcome(&err),
+                globals: vec![],
+                memories: vec![],
+            })
+        }
+    };
+
+    let outcome = match call {
+        None => Outcome::Returned(vec![]),
+        Some((name, args)) => {
+            let func = instance.get_func(&mut store, name).unwrap();
+            let args = args.iter().map(|&arg| to_val(arg)).collect::<Vec<_>>();
+            let mut results = vec![Val::I32(0); func.ty(&store).results().len()];
+            match func.call(&mut store, &args[..], &mut results[..]) {
+                Ok(()) => Outcome::Returned(results),
+                Err(err) => outcome(&err),
+            }
+        }
+    };
+
+    let mut globals = vec![];
+    let mut memories = vec![];
+    for export in module.exports() {
+        let name = export.name();
+        match export.ty() {
+            ExternType::Global(_) => {
+                let global = instance.get_global(&mut store, name).unwrap();
+                globals.push((name.to_owned(), global.get(&mut store)));
+            }
+            ExternType::Memory(_) => {
+                let memory = instance.get_memory(&mut store, name).unwrap();
+                memories.push((name.to_owned(), memory.data(&store).to_vec()));
+            }
+            _ => {}
+        }
+    }
+    Ok(Run {
+        outcome,
+        globals,
+        memories,
+    })
+}
+
+fn same_val(a: &Val, b: &Val) -> bool {
+    match (a, b) {
+        (Val::I32(a), Val::I32(b)) => a == b,
+        (Val::I64(a), Val::I64(b)) => a == b,
+        (&Val::F32(a), &Val::F32(b)) => same_value(ConstVal::F32(a), ConstVal::F32(b)),
+        (&Val::F64(a), &Val::F64(b)) => same_value(ConstVal::F64(a), ConstVal::F64(b)),
+        (Val::V128(a), Val::V128(b)) => a == b,
+        (Val::FuncRef(a), Val::FuncRef(b)) => a.is_none() == b.is_none(),
+        (Val::ExternRef(a), Val::ExternRef(b)) => a.is_none() == b.is_none(),
+        _ => false,
+    }
+}
+
+/// Compare the runs of `what`, bailing if they differ. Returns whether
+/// they were compared.
+fn compare(expected: &Run, actual: &Run, what: &str) -> Result<bool> {
+    let same = match (&expected.outcome, &actual.outcome) {
+        (Outcome::OutOfFuel, _) | (Outcome::Import, _) | (_, Outcome::Import) => return Ok(false),
+        (Outcome::Returned(a), Outcome::Returned(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_val(a, b))
+        }
+        (Outcome::Trapped, Outcome::Trapped) => true,
+        _ => false,
+    };
+    if !same {
+        anyhow::bail!(
+            "{} gives {:?} originally and {:?} after",
+            what,
+            expected.outcome,
+            actual.outcome
+        );
+    }
+    if expected.globals.len() != actual.globals.len()
+        || expected.memories.len() != actual.memories.len()
+    {
+        anyhow::bail!("The modules export different globals or memories");
+    }
+    for ((name, a), (_, b)) in expected.globals.iter().zip(actual.globals.iter()) {
+        if !same_val(a, b) {
+            anyhow::bail!(
+                "{} leaves global {} as {:?} originally and {:?} after",
+                what,
+                name,
+                a,
+                b
+            );
+        }
+    }
+    for ((name, a), (_, b)) in expected.memories.iter().zip(actual.memories.iter()) {
+        if a != b {
+            anyhow::bail!("{} leaves different contents in memory {}", what, name);
+        }
+    }
+    Ok(true)
+}
@@ -1,10 +1,11 @@
 //! Type-safe indices and indexed containers.
 
-use std::default::Default;
-use std::fmt::Debug;
-use std::hash::Hash;
-use std::marker::PhantomData;
-use std::ops::{Index, IndexMut};
+use crate::prelude::*;
+use core::default::Default;
+use core::fmt::Debug;
+use core::hash::Hash;
+use core::marker::PhantomData;
+use core::ops::{Index, IndexMut};
 
 pub trait EntityRef: Clone + Copy + PartialEq + Eq + PartialOrd + Ord + Hash {
     fn new(value: usize) -> Self;
@@ -27,7 +28,7 @@ macro_rules! declare_entity {
 
         impl $crate::entity::EntityRef for $name {
             fn new(value: usize) -> Self {
-                use std::convert::TryFrom;
+                use core::convert::TryFrom;
                 let value = u32::try_from(value).unwrap();
                 debug_assert!(value != u32::MAX);
                 Self(value)
@@ -48,25 +49,25 @@ macro_rules! declare_entity {
             }
         }
 
-        impl std::convert::From<u32> for $name {
+        impl core::convert::From<u32> for $name {
             fn from(val: u32) -> Self {
                 <Self as $crate::entity::EntityRef>::new(val as usize)
             }
         }
 
-        impl std::default::Default for $name {
+        impl core::default::Default for $name {
             fn default() -> Self {
                 <Self as $crate::entity::EntityRef>::invalid()
             }
         }
 
-        impl std::fmt::Debug for $name {
-            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        impl core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
                 write!(f, "{}{}", $prefix, self.0)
             }
         }
-        impl std::fmt::Display for $name {
-            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        impl core::fmt::Display for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
                 write!(f, "{}{}", $prefix, self.0)
             }
         }
@@ -76,7 +77,7 @@ macro_rules! declare_entity {
 #[derive(Clone, Debug)]
 pub struct EntityVec<Idx: EntityRef, T: Clone + Debug>(Vec<T>, PhantomData<Idx>);
 
-impl<Idx: EntityRef, T: Clone + Debug> std::default::Default for EntityVec<Idx, T> {
+impl<Idx: EntityRef, T: Clone + Debug> core::default::Default for EntityVec<Idx, T> {
     fn default() -> Self {
         Self(vec![], PhantomData)
     }
@@ -99,6 +100,10 @@ impl<Idx: EntityRef, T: Clone + Debug> EntityVec<Idx, T> {
         self.0.len()
     }
 
+    pub fn reserve(&mut self, additional: usize) {
+        self.0.reserve(additional);
+    }
+
     pub fn iter(&self) -> impl DoubleEndedIterator<Item = Idx> {
         (0..self.0.len()).map(|index| Idx::new(index))
     }
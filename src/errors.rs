@@ -1,16 +1,137 @@
 //! Error types.
 
-#[derive(Clone, Debug)]
-pub enum FrontendError {
-    UnsupportedFeature(String),
-    TooLarge(String),
-    Internal(String),
+use crate::ir::Func;
+use crate::prelude::*;
+
+/// An error reading, transforming or writing a module. Errors are
+/// returned as `anyhow::Error`s, from which this can be recovered with
+/// `downcast_ref`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WaffleError {
+    /// The input uses a feature that is not supported, or exceeds one
+    /// of the limits on tables.
+    UnsupportedFeature {
+        message: String,
+        func: Option<Func>,
+        offset: Option<usize>,
+    },
+    /// The input is not a valid module.
+    MalformedInput {
+        message: String,
+        func: Option<Func>,
+        offset: Option<usize>,
+    },
+    /// An invariant of the IR does not hold: a bug in waffle or in a
+    /// transform.
+    Internal {
+        message: String,
+        func: Option<Func>,
+        offset: Option<usize>,
+    },
+}
+
+impl WaffleError {
+    pub fn unsupported<S: Into<String>>(message: S) -> WaffleError {
+        WaffleError::UnsupportedFeature {
+            message: message.into(),
+            func: None,
+            offset: None,
+        }
+    }
+
+    pub fn malformed<S: Into<String>>(message: S) -> WaffleError {
+        WaffleError::MalformedInput {
+            message: message.into(),
+            func: None,
+            offset: None,
+        }
+    }
+
+    pub fn internal<S: Into<String>>(message: S) -> WaffleError {
+        WaffleError::Internal {
+            message: message.into(),
+            func: None,
+            offset: None,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            WaffleError::UnsupportedFeature { message, .. }
+            | WaffleError::MalformedInput { message, .. }
+            | WaffleError::Internal { message, .. } => message,
+        }
+    }
+
+    /// The function the error is in, if it is in one.
+    pub fn func(&self) -> Option<Func> {
+        self.location().0
+    }
+
+    /// The offset in the input the error is at, if known.
+    pub fn offset(&self) -> Option<usize> {
+        self.location().1
+    }
+
+    fn location(&self) -> (Option<Func>, Option<usize>) {
+        match self {
+            WaffleError::UnsupportedFeature { func, offset, .. }
+            | WaffleError::MalformedInput { func, offset, .. }
+            | WaffleError::Internal { func, offset, .. } => (*func, *offset),
+        }
+    }
+
+    fn location_mut(&mut self) -> (&mut Option<Func>, &mut Option<usize>) {
+        match self {
+            WaffleError::UnsupportedFeature { func, offset, .. }
+            | WaffleError::MalformedInput { func, offset, .. }
+            | WaffleError::Internal { func, offset, .. } => (func, offset),
+        }
+    }
 }
 
-impl std::fmt::Display for FrontendError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        std::fmt::Debug::fmt(self, f)
+impl core::fmt::Display for WaffleError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            WaffleError::UnsupportedFeature { .. } => write!(f, "unsupported feature")?,
+            WaffleError::MalformedInput { .. } => write!(f, "malformed input")?,
+            WaffleError::Internal { .. } => write!(f, "internal error")?,
+        }
+        if let Some(func) = self.func() {
+            write!(f, " in {}", func)?;
+        }
+        if let Some(offset) = self.offset() {
+            write!(f, " at offset {:#x}", offset)?;
+        }
+        write!(f, ": {}", self.message())
     }
 }
 
-impl std::error::Error for FrontendError {}
+impl core::error::Error for WaffleError {}
+
+/// `err` with `func` and `offset` as its location where it has none.
+/// Errors from the parser become malformed input; others are left as
+/// they are.
+pub(crate) fn locate(
+    err: anyhow::Error,
+    func: Option<Func>,
+    offset: Option<usize>,
+) -> anyhow::Error {
+    #[cfg(feature = "frontend")]
+    let err = match err.downcast::<wasmparser::BinaryReaderError>() {
+        Ok(reader_err) => {
+            let mut located = WaffleError::malformed(reader_err.message());
+            *located.location_mut().1 = Some(reader_err.offset());
+            return locate(located.into(), func, offset);
+        }
+        Err(err) => err,
+    };
+    let mut err = match err.downcast::<WaffleError>() {
+        Ok(err) => err,
+        Err(err) => return err,
+    };
+    let (err_func, err_offset) = err.location_mut();
+    *err_func = err_func.or(func);
+    *err_offset = err_offset.or(offset);
+    err.into()
+}
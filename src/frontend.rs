@@ -3,11 +3,12 @@
 #![allow(dead_code)]
 
 use crate::entity::EntityRef;
-use crate::errors::FrontendError;
+use crate::errors::{locate, WaffleError};
 use crate::ir::*;
 use crate::op_traits::{op_inputs, op_outputs};
-use crate::ops::Operator;
+use crate::ops::{EntityUse, Operator};
 use crate::pool::ListRef;
+use crate::Timeline;
 use addr2line::gimli;
 use anyhow::{bail, Result};
 use fxhash::{FxHashMap, FxHashSet};
@@ -17,9 +18,12 @@ use wasmparser::{
     BlockType, DataKind, ExternalKind, Name, NameSectionReader, Parser, Payload, TypeRef,
 };
 
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct FrontendOptions {
     pub debug: bool,
+    /// Record parsing, and what is then done to the module, on this
+    /// timeline; see `Module::timeline`.
+    pub timeline: Option<Timeline>,
 }
 
 pub fn wasm_to_ir<'a>(bytes: &'a [u8], options: &FrontendOptions) -> Result<Module<'a>> {
@@ -29,20 +33,36 @@ pub fn wasm_to_ir<'a>(bytes: &'a [u8], options: &FrontendOptions) -> Result<Modu
     let mut dwarf = gimli::Dwarf::default();
     let mut extra_sections = ExtraSections::default();
     for payload in parser.parse_all(bytes) {
-        let payload = payload?;
+        let payload = payload.map_err(|e| locate(e.into(), None, None))?;
+        match payload.as_section() {
+            Some((id, _)) if id != 0 => extra_sections.order.last_section_id = Some(id),
+            _ => {}
+        }
+        if let Payload::CustomSection(reader) = &payload {
+            if reader.name().starts_with(".debug_") {
+                module
+                    .dwarf_sections
+                    .sections
+                    .insert(reader.name().to_owned(), reader.data().to_vec());
+            }
+        }
         handle_payload(
             &mut module,
             payload,
             &mut next_func,
             &mut dwarf,
             &mut extra_sections,
-        )?;
+        )
+        .map_err(|e| locate(e, None, None))?;
     }
     dwarf.locations =
         gimli::LocationLists::new(extra_sections.debug_loc, extra_sections.debug_loclists);
     dwarf.ranges =
         gimli::RangeLists::new(extra_sections.debug_ranges, extra_sections.debug_rnglists);
 
+    module.dwarf_sections.code_offset = extra_sections.code_offset;
+    module.orig_segments = SegmentFingerprints::of(&module);
+
     if options.debug {
         let debug_map = DebugMap::from_dwarf(dwarf, &mut module.debug, extra_sections.code_offset)?;
         module.debug_map = debug_map;
@@ -51,30 +71,118 @@ pub fn wasm_to_ir<'a>(bytes: &'a [u8], options: &FrontendOptions) -> Result<Modu
     Ok(module)
 }
 
-fn parse_init_expr<'a>(init_expr: &wasmparser::ConstExpr<'a>) -> Result<Option<u64>> {
-    let operators = init_expr
-        .get_operators_reader()
-        .into_iter()
-        .collect::<Result<Vec<wasmparser::Operator>, _>>()?;
-    if operators.len() == 1 && matches!(&operators[0], &wasmparser::Operator::End) {
+/// The operators of a constant expression, without its final `end`.
+fn const_expr_ops(expr: &wasmparser::ConstExpr<'_>) -> Result<Vec<Operator>> {
+    let mut ops = vec![];
+    for op in expr.get_operators_reader() {
+        match op? {
+            wasmparser::Operator::End => break,
+            op => ops.push(Operator::try_from(&op).map_err(|_| {
+                WaffleError::unsupported(format!(
+                    "Unsupported operator in constant expression: {:?}",
+                    op
+                ))
+            })?),
+        }
+    }
+    Ok(ops)
+}
+
+/// Parse a global initializer, which may read the globals before it.
+/// An empty expression gives `None`.
+fn parse_init_expr(
+    module: &Module<'_>,
+    init_expr: &wasmparser::ConstExpr<'_>,
+) -> Result<Option<u64>> {
+    let ops = const_expr_ops(init_expr)?;
+    if ops.is_empty() {
         return Ok(None);
     }
-    if operators.len() != 2 || !matches!(&operators[1], &wasmparser::Operator::End) {
-        bail!(FrontendError::UnsupportedFeature(format!(
-            "Unsupported operator seq in base-address expr: {:?}",
-            operators
+    match module.eval_const_expr(&ops[..]) {
+        Ok(Some(value)) => Ok(Some(value)),
+        Ok(None) => bail!(WaffleError::unsupported(format!(
+            "Initializer reads an imported global: {:?}",
+            ops
+        ))),
+        Err(e) => bail!(WaffleError::unsupported(e.to_string())),
+    }
+}
+
+/// Parse the initializer of a defined global, which may also be the
+/// value of an imported global.
+fn parse_global_init(
+    module: &Module<'_>,
+    init_expr: &wasmparser::ConstExpr<'_>,
+) -> Result<Option<GlobalInit>> {
+    let ops = const_expr_ops(init_expr)?;
+    if let &[Operator::GlobalGet { global_index }] = &ops[..] {
+        if module.global_value(global_index).is_none() {
+            return Ok(Some(GlobalInit::GlobalGet(global_index)));
+        }
+    }
+    Ok(parse_init_expr(module, init_expr)?.map(GlobalInit::Const))
+}
+
+/// Parse the offset of an active segment, which may also be read from
+/// a global.
+fn parse_offset_expr(
+    module: &Module<'_>,
+    offset_expr: &wasmparser::ConstExpr<'_>,
+) -> Result<SegmentOffset> {
+    let ops = const_expr_ops(offset_expr)?;
+    if let &[Operator::GlobalGet { global_index }] = &ops[..] {
+        return Ok(SegmentOffset::Global(global_index));
+    }
+    Ok(SegmentOffset::Const(
+        parse_init_expr(module, offset_expr)?.unwrap_or(0) as u32 as usize,
+    ))
+}
+
+/// Tables are kept as a list of their elements, so limit their size.
+fn check_table_size(size: usize) -> Result<()> {
+    const MAX_TABLE: usize = 100_000;
+    if size > MAX_TABLE {
+        bail!(WaffleError::unsupported(format!(
+            "Too many table elements: {:?}",
+            size
         )));
     }
-    Ok(match &operators[0] {
-        &wasmparser::Operator::I32Const { value } => Some(value as u64),
-        &wasmparser::Operator::I64Const { value } => Some(value as u64),
-        &wasmparser::Operator::F32Const { value } => Some(value.bits() as u64),
-        &wasmparser::Operator::F64Const { value } => Some(value.bits()),
-        op => anyhow::bail!(FrontendError::UnsupportedFeature(format!(
-            "Unsupported data segment base-address operator: {:?}",
-            op
+    Ok(())
+}
+
+/// The table, offset and functions of a segment that
+/// `TableData::func_elements` can hold: an active one at a constant
+/// offset, written with function indices.
+fn table_contents(segment: &ElementSegment) -> Option<(Table, usize, &[Func])> {
+    match segment {
+        ElementSegment {
+            kind:
+                ElementSegmentKind::Active {
+                    table,
+                    offset: SegmentOffset::Const(offset),
+                },
+            items: ElementItems::Functions(funcs),
+        } => Some((*table, *offset, &funcs[..])),
+        _ => None,
+    }
+}
+
+/// Parse an element expression: `ref.func` or `ref.null func`.
+fn parse_elem_expr(expr: &wasmparser::ConstExpr<'_>) -> Result<Option<Func>> {
+    let operators = expr
+        .get_operators_reader()
+        .into_iter()
+        .collect::<Result<Vec<wasmparser::Operator>, _>>()?;
+    match &operators[..] {
+        [wasmparser::Operator::RefFunc { function_index }, wasmparser::Operator::End] => {
+            Ok(Some(Func::from(*function_index)))
+        }
+        [wasmparser::Operator::RefNull { .. }, wasmparser::Operator::End] => Ok(None),
+        _ => bail!(WaffleError::unsupported(format!(
+            "Unsupported element expression: {:?}",
+            operators
         ))),
-    })
+    }
 }
 
 #[derive(Default)]
@@ -84,6 +192,16 @@ struct ExtraSections<'a> {
     debug_ranges: gimli::DebugRanges<gimli::EndianSlice<'a, gimli::LittleEndian>>,
     debug_rnglists: gimli::DebugRngLists<gimli::EndianSlice<'a, gimli::LittleEndian>>,
     code_offset: u32,
+    order: SectionOrder,
+}
+
+/// Where the sections seen so far put the next custom section.
+#[derive(Default)]
+pub(crate) struct SectionOrder {
+    /// ID of the most recent standard (non-custom) section.
+    pub(crate) last_section_id: Option<u8>,
+    /// Whether the `name` section has been seen.
+    pub(crate) seen_name_section: bool,
 }
 
 fn handle_payload<'a>(
@@ -94,6 +212,35 @@ fn handle_payload<'a>(
     extra_sections: &mut ExtraSections<'a>,
 ) -> Result<()> {
     trace!("Wasm parser item: {:?}", payload);
+    match payload {
+        Payload::CodeSectionStart { range, .. } => {
+            extra_sections.code_offset = range.start as u32;
+        }
+        Payload::CodeSectionEntry(body) => {
+            let func_idx = Func::new(*next_func);
+            *next_func += 1;
+
+            let sig = module.funcs[func_idx].sig();
+            let name = module.funcs[func_idx].name().to_owned();
+            module.funcs[func_idx] = FuncDecl::Lazy(sig, name, RawBody::new(body));
+        }
+        Payload::CustomSection(reader) if reader.name().starts_with(".debug_") => {
+            handle_debug_section(&reader, dwarf, extra_sections);
+        }
+        payload => handle_section(module, payload, next_func, &mut extra_sections.order)?,
+    }
+    Ok(())
+}
+
+/// Read a section that describes the module, rather than code or its
+/// debug info, into `module`. `next_func` counts the imported
+/// functions.
+pub(crate) fn handle_section(
+    module: &mut Module<'_>,
+    payload: Payload<'_>,
+    next_func: &mut usize,
+    order: &mut SectionOrder,
+) -> Result<()> {
     match payload {
         Payload::TypeSection(reader) => {
             for ty in reader {
@@ -120,25 +267,30 @@ fn handle_payload<'a>(
                         let ty = ty.content_type.into();
                         let global = module.globals.push(GlobalData {
                             ty,
-                            value: None,
+                            init: None,
                             mutable,
                         });
                         ImportKind::Global(global)
                     }
                     TypeRef::Table(ty) => {
-                        let table = module.frontend_add_table(ty.element_type.into(), None);
+                        check_table_size(ty.initial as usize)?;
+                        let table = module.frontend_add_table(
+                            ty.element_type.into(),
+                            ty.initial,
+                            ty.maximum,
+                        );
                         ImportKind::Table(table)
                     }
                     TypeRef::Memory(mem) => {
                         let mem = module.memories.push(MemoryData {
                             initial_pages: mem.initial as usize,
                             maximum_pages: mem.maximum.map(|max| max as usize),
-                            segments: vec![],
+                            shared: mem.shared,
                         });
                         ImportKind::Memory(mem)
                     }
                     t => {
-                        bail!(FrontendError::UnsupportedFeature(format!(
+                        bail!(WaffleError::unsupported(format!(
                             "Unknown import type: {:?}",
                             t
                         )));
@@ -156,18 +308,15 @@ fn handle_payload<'a>(
                 let global = global?;
                 let mutable = global.ty.mutable;
                 let ty = global.ty.content_type.into();
-                let init_expr = parse_init_expr(&global.init_expr)?;
-                module.globals.push(GlobalData {
-                    ty,
-                    value: init_expr,
-                    mutable,
-                });
+                let init = parse_global_init(module, &global.init_expr)?;
+                module.globals.push(GlobalData { ty, init, mutable });
             }
         }
         Payload::TableSection(reader) => {
             for table in reader {
                 let table = table?;
-                module.frontend_add_table(table.element_type.into(), table.maximum);
+                check_table_size(table.initial as usize)?;
+                module.frontend_add_table(table.element_type.into(), table.initial, table.maximum);
             }
         }
         Payload::FunctionSection(reader) => {
@@ -180,17 +329,6 @@ fn handle_payload<'a>(
                 ));
             }
         }
-        Payload::CodeSectionStart { range, .. } => {
-            extra_sections.code_offset = range.start as u32;
-        }
-        Payload::CodeSectionEntry(body) => {
-            let func_idx = Func::new(*next_func);
-            *next_func += 1;
-
-            let sig = module.funcs[func_idx].sig();
-            let name = module.funcs[func_idx].name().to_owned();
-            module.funcs[func_idx] = FuncDecl::Lazy(sig, name, body);
-        }
         Payload::ExportSection(reader) => {
             for export in reader {
                 let export = export?;
@@ -213,160 +351,255 @@ fn handle_payload<'a>(
                 module.memories.push(MemoryData {
                     initial_pages: memory.initial as usize,
                     maximum_pages: memory.maximum.map(|max| max as usize),
-                    segments: vec![],
+                    shared: memory.shared,
                 });
             }
         }
         Payload::DataSection(reader) => {
             for segment in reader {
                 let segment = segment?;
-                match &segment.kind {
-                    DataKind::Passive => {}
+                let kind = match &segment.kind {
+                    DataKind::Passive => DataSegmentKind::Passive,
                     DataKind::Active {
                         memory_index,
                         offset_expr,
-                    } => {
-                        let data = segment.data.to_vec();
-                        let memory = Memory::from(*memory_index);
-                        let offset = parse_init_expr(offset_expr)?.unwrap_or(0) as usize;
-                        module.memories[memory]
-                            .segments
-                            .push(MemorySegment { offset, data });
-                    }
-                }
+                    } => DataSegmentKind::Active {
+                        memory: Memory::from(*memory_index),
+                        offset: parse_offset_expr(module, offset_expr)?,
+                    },
+                };
+                module.data_segments.push(DataSegment {
+                    kind,
+                    data: segment.data.to_vec(),
+                });
             }
         }
         Payload::CustomSection(reader) if reader.name() == "name" => {
+            order.seen_name_section = true;
             let name_reader = NameSectionReader::new(reader.data(), reader.data_offset())?;
             for subsection in name_reader {
                 let subsection = subsection?;
                 match subsection {
+                    Name::Module { name, .. } => {
+                        module.names.module = Some(name.to_owned());
+                    }
                     Name::Function(names) => {
                         for name in names {
                             let name = name?;
                             module.funcs[Func::new(name.index as usize)].set_name(name.name);
                         }
                     }
+                    Name::Local(names) => {
+                        for func_names in names {
+                            let func_names = func_names?;
+                            let map = module
+                                .names
+                                .locals
+                                .entry(Func::new(func_names.index as usize))
+                                .or_default();
+                            for name in func_names.names {
+                                let name = name?;
+                                map.insert(name.index, name.name.to_owned());
+                            }
+                        }
+                    }
+                    Name::Label(names) => {
+                        for func_names in names {
+                            let func_names = func_names?;
+                            let map = module
+                                .names
+                                .labels
+                                .entry(Func::new(func_names.index as usize))
+                                .or_default();
+                            for name in func_names.names {
+                                let name = name?;
+                                map.insert(name.index, name.name.to_owned());
+                            }
+                        }
+                    }
+                    Name::Type(names) => {
+                        for name in names {
+                            let name = name?;
+                            module
+                                .names
+                                .types
+                                .insert(Signature::new(name.index as usize), name.name.to_owned());
+                        }
+                    }
+                    Name::Table(names) => {
+                        for name in names {
+                            let name = name?;
+                            module
+                                .names
+                                .tables
+                                .insert(Table::new(name.index as usize), name.name.to_owned());
+                        }
+                    }
+                    Name::Memory(names) => {
+                        for name in names {
+                            let name = name?;
+                            module
+                                .names
+                                .memories
+                                .insert(Memory::new(name.index as usize), name.name.to_owned());
+                        }
+                    }
+                    Name::Global(names) => {
+                        for name in names {
+                            let name = name?;
+                            module
+                                .names
+                                .globals
+                                .insert(Global::new(name.index as usize), name.name.to_owned());
+                        }
+                    }
                     _ => {}
                 }
             }
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_info" => {
+        Payload::CustomSection(reader) => {
+            module.custom_sections.push(CustomSection {
+                name: reader.name().to_owned(),
+                data: reader.data().to_vec(),
+                placement: match order.last_section_id {
+                    _ if order.seen_name_section => CustomSectionPlacement::End,
+                    Some(id) => CustomSectionPlacement::After(id),
+                    None => CustomSectionPlacement::Start,
+                },
+            });
+        }
+        Payload::Version { .. } => {}
+        Payload::ElementSection(reader) => {
+            let mut segments = vec![];
+            for element in reader {
+                let element = element?;
+                if element.ty != wasmparser::ValType::FuncRef {
+                    bail!(WaffleError::unsupported(format!(
+                        "Unsupported table type: {:?}",
+                        element.ty
+                    )));
+                }
+                let mut funcs = vec![];
+                let mut exprs = vec![];
+                for item in element.items.get_items_reader()? {
+                    match item? {
+                        wasmparser::ElementItem::Func(func_idx) => funcs.push(Func::from(func_idx)),
+                        wasmparser::ElementItem::Expr(expr) => exprs.push(parse_elem_expr(&expr)?),
+                    }
+                }
+                let items = if exprs.is_empty() {
+                    ElementItems::Functions(funcs)
+                } else {
+                    ElementItems::Expressions(exprs)
+                };
+                let kind = match &element.kind {
+                    wasmparser::ElementKind::Passive => ElementSegmentKind::Passive,
+                    wasmparser::ElementKind::Declared => ElementSegmentKind::Declared,
+                    wasmparser::ElementKind::Active {
+                        table_index,
+                        offset_expr,
+                    } => ElementSegmentKind::Active {
+                        table: Table::from(*table_index),
+                        offset: parse_offset_expr(module, offset_expr)?,
+                    },
+                };
+                segments.push(ElementSegment { kind, items });
+            }
+            // Segments are referred to by index, and the table contents
+            // are written after `elem_segments`, so only the segments
+            // after the last one kept can go into the table contents.
+            let kept = segments
+                .iter()
+                .rposition(|segment| table_contents(segment).is_none())
+                .map_or(0, |last| last + 1);
+            for segment in &segments[kept..] {
+                let (table, offset, funcs) = table_contents(segment).unwrap();
+                let table_items = module.tables[table].func_elements.as_mut().unwrap();
+                let new_size = offset.checked_add(funcs.len()).ok_or_else(|| {
+                    WaffleError::unsupported(format!(
+                        "Overflowing element offset + length: {} + {}",
+                        offset,
+                        funcs.len()
+                    ))
+                })?;
+                if new_size > table_items.len() {
+                    check_table_size(new_size)?;
+                    table_items.resize(new_size, Func::invalid());
+                }
+                table_items[offset..new_size].copy_from_slice(funcs);
+            }
+            segments.truncate(kept);
+            module.elem_segments.extend(segments);
+        }
+        Payload::End(_) => {}
+        Payload::StartSection { func, .. } => {
+            module.start_func = Some(Func::from(func));
+        }
+        payload => {
+            module
+                .warnings
+                .add(format!("Skipping section: {:?}", payload));
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a DWARF section for `DebugMap`.
+fn handle_debug_section<'a>(
+    reader: &wasmparser::CustomSectionReader<'a>,
+    dwarf: &mut gimli::Dwarf<gimli::EndianSlice<'a, gimli::LittleEndian>>,
+    extra_sections: &mut ExtraSections<'a>,
+) {
+    match reader.name() {
+        ".debug_info" => {
             dwarf.debug_info = gimli::DebugInfo::new(reader.data(), gimli::LittleEndian);
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_abbrev" => {
+        ".debug_abbrev" => {
             dwarf.debug_abbrev = gimli::DebugAbbrev::new(reader.data(), gimli::LittleEndian);
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_addr" => {
+        ".debug_addr" => {
             dwarf.debug_addr =
                 gimli::DebugAddr::from(gimli::EndianSlice::new(reader.data(), gimli::LittleEndian));
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_aranges" => {
+        ".debug_aranges" => {
             dwarf.debug_aranges = gimli::DebugAranges::new(reader.data(), gimli::LittleEndian);
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_line" => {
+        ".debug_line" => {
             dwarf.debug_line = gimli::DebugLine::new(reader.data(), gimli::LittleEndian);
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_line_str" => {
+        ".debug_line_str" => {
             dwarf.debug_line_str = gimli::DebugLineStr::new(reader.data(), gimli::LittleEndian);
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_str" => {
+        ".debug_str" => {
             dwarf.debug_str = gimli::DebugStr::new(reader.data(), gimli::LittleEndian);
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_str_offsets" => {
+        ".debug_str_offsets" => {
             dwarf.debug_str_offsets = gimli::DebugStrOffsets::from(gimli::EndianSlice::new(
                 reader.data(),
                 gimli::LittleEndian,
             ));
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_types" => {
+        ".debug_types" => {
             dwarf.debug_types = gimli::DebugTypes::new(reader.data(), gimli::LittleEndian);
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_loc" => {
+        ".debug_loc" => {
             extra_sections.debug_loc = gimli::DebugLoc::new(reader.data(), gimli::LittleEndian);
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_loclists" => {
+        ".debug_loclists" => {
             extra_sections.debug_loclists =
                 gimli::DebugLocLists::new(reader.data(), gimli::LittleEndian);
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_ranges" => {
+        ".debug_ranges" => {
             extra_sections.debug_ranges =
                 gimli::DebugRanges::new(reader.data(), gimli::LittleEndian);
         }
-        Payload::CustomSection(reader) if reader.name() == ".debug_rnglists" => {
+        ".debug_rnglists" => {
             extra_sections.debug_rnglists =
                 gimli::DebugRngLists::new(reader.data(), gimli::LittleEndian);
         }
-        Payload::CustomSection(_) => {}
-        Payload::Version { .. } => {}
-        Payload::ElementSection(reader) => {
-            for element in reader {
-                let element = element?;
-                if element.ty != wasmparser::ValType::FuncRef {
-                    bail!(FrontendError::UnsupportedFeature(format!(
-                        "Unsupported table type: {:?}",
-                        element.ty
-                    )));
-                }
-                match &element.kind {
-                    wasmparser::ElementKind::Passive => {}
-                    wasmparser::ElementKind::Declared => {}
-                    wasmparser::ElementKind::Active {
-                        table_index,
-                        offset_expr,
-                    } => {
-                        let table = Table::from(*table_index);
-                        let offset = parse_init_expr(&offset_expr)?.unwrap_or(0) as usize;
-                        let items = element
-                            .items
-                            .get_items_reader()?
-                            .into_iter()
-                            .collect::<Result<Vec<_>, _>>()?;
-                        let mut funcs = vec![];
-                        for item in items {
-                            let func = match item {
-                                wasmparser::ElementItem::Func(func_idx) => Func::from(func_idx),
-                                _ => bail!(FrontendError::UnsupportedFeature(format!(
-                                    "Unsupported element item: {:?}",
-                                    item
-                                ))),
-                            };
-                            funcs.push(func);
-                        }
-
-                        let table_items = module.tables[table].func_elements.as_mut().unwrap();
-                        let new_size = offset.checked_add(funcs.len()).ok_or_else(|| {
-                            FrontendError::TooLarge(format!(
-                                "Overflowing element offset + length: {} + {}",
-                                offset,
-                                funcs.len()
-                            ))
-                        })?;
-                        if new_size > table_items.len() {
-                            static MAX_TABLE: usize = 100_000;
-                            if new_size > MAX_TABLE {
-                                bail!(FrontendError::TooLarge(format!(
-                                    "Too many table elements: {:?}",
-                                    new_size
-                                )));
-                            }
-                            table_items.resize(new_size, Func::invalid());
-                        }
-                        table_items[offset..new_size].copy_from_slice(&funcs[..]);
-                    }
-                }
-            }
-        }
-        Payload::End(_) => {}
-        Payload::StartSection { func, .. } => {
-            module.start_func = Some(Func::from(func));
-        }
-        payload => {
-            log::warn!("Skipping section: {:?}", payload);
-        }
+        _ => {}
     }
-
-    Ok(())
 }
 
 struct DebugLocReader<'a> {
@@ -422,8 +655,14 @@ pub(crate) fn parse_body<'a>(
     body: &mut wasmparser::FunctionBody,
 ) -> Result<FunctionBody> {
     let mut ret: FunctionBody = FunctionBody::default();
+    // Operators take about two bytes each; sizing the value tables for
+    // that up front saves growing them while parsing.
+    let estimate = body.range().len() / 2;
+    ret.values.reserve(estimate);
+    ret.arg_pool.reserve(estimate);
 
     let mut debug_locs = DebugLocReader::new(module, body.range().start as u32);
+    ret.source_range = Some(body.range().start as u32..body.range().end as u32);
 
     for &param in &module.signatures[my_sig].params[..] {
         ret.locals.push(param.into());
@@ -473,11 +712,13 @@ pub(crate) fn parse_body<'a>(
     for item in ops.into_iter_with_offsets() {
         let (op, offset) = item?;
         let loc = debug_locs.get_loc(offset);
+        builder.cur_offset = Some(offset as u32);
         if builder.reachable {
-            builder.handle_op(op, loc)?;
+            builder.handle_op(op, loc)
         } else {
-            builder.handle_op_unreachable(op)?;
+            builder.handle_op_unreachable(op)
         }
+        .map_err(|e| locate(e, None, Some(offset)))?;
     }
 
     if builder.reachable {
@@ -705,8 +946,10 @@ impl LocalTracker {
             value
         );
         let mut results: Vec<Value> = vec![];
-        let preds = body.blocks[block].preds.clone();
-        for pred in preds {
+        // `block` is sealed, so its preds don't change while we look
+        // for the value in them.
+        for i in 0..body.preds(block).len() {
+            let pred = body.preds(block)[i];
             let pred_value = self.get_in_block(body, pred, local);
             log::trace!(
                 "compute_blockparam: block {} local {} value {:?}: pred {} -> {:?}",
@@ -745,14 +988,9 @@ impl LocalTracker {
                 value,
             );
             body.replace_placeholder_with_blockparam(block, value);
-            for (i, (&pred, result)) in body.blocks[block]
-                .preds
-                .clone()
-                .iter()
-                .zip(results.into_iter())
-                .enumerate()
-            {
-                let index = body.blocks[block].pos_in_pred_succ[i];
+            for (i, result) in results.into_iter().enumerate() {
+                let pred = body.preds(block)[i];
+                let index = body.pos_in_pred_succ(block)[i];
                 body.blocks[pred].terminator.update_target(index, |target| {
                     log::trace!(
                         "compute_blockparam: block {} local {} value {:?}: in pred {}, adding branch arg {:?}",
@@ -779,6 +1017,8 @@ struct FunctionBodyBuilder<'a, 'b> {
     reachable: bool,
     ctrl_stack: Vec<Frame>,
     op_stack: Vec<(Type, Value)>,
+    /// Offset in the module of the operator being translated.
+    cur_offset: Option<u32>,
 }
 
 #[derive(Clone, Debug)]
@@ -896,6 +1136,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             cur_block: Block::new(0),
             reachable: true,
             locals: LocalTracker::default(),
+            cur_offset: None,
         };
 
         // Push initial implicit Block.
@@ -912,30 +1153,50 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
         ret
     }
 
-    fn pop_n(&mut self, n: usize) -> Vec<Value> {
+    /// The top `n` operands, which must belong to the innermost block.
+    fn peek_n(&self, n: usize) -> Result<&[(Type, Value)]> {
+        let floor = self.ctrl_stack.last().map_or(0, Frame::start_depth);
+        if self.op_stack.len() < floor + n {
+            bail!(WaffleError::malformed(format!(
+                "Operand stack underflow: {} operands needed, {} available",
+                n,
+                self.op_stack.len() - floor.min(self.op_stack.len())
+            )));
+        }
+        Ok(&self.op_stack[self.op_stack.len() - n..])
+    }
+
+    fn pop_n(&mut self, n: usize) -> Result<Vec<Value>> {
         assert!(self.reachable);
-        let new_top = self.op_stack.len() - n;
-        let ret = self.op_stack[new_top..]
+        let ret = self
+            .peek_n(n)?
             .iter()
             .map(|(_ty, value)| *value)
             .collect::<Vec<_>>();
-        self.op_stack.truncate(new_top);
-        ret
+        self.op_stack.truncate(self.op_stack.len() - n);
+        Ok(ret)
     }
 
-    fn pop_1(&mut self) -> Value {
+    fn pop_1(&mut self) -> Result<Value> {
         assert!(self.reachable);
-        self.op_stack.pop().unwrap().1
+        self.peek_n(1)?;
+        Ok(self.op_stack.pop().unwrap().1)
     }
 
-    fn block_results(&mut self, tys: &[Type], start_depth: usize, at_block: Block) -> Vec<Value> {
+    fn block_results(
+        &mut self,
+        tys: &[Type],
+        start_depth: usize,
+        at_block: Block,
+    ) -> Result<Vec<Value>> {
         if self.op_stack.len() < start_depth + tys.len() {
-            tys.iter()
+            Ok(tys
+                .iter()
                 .map(|&ty| {
                     self.locals
                         .create_default_value(&mut self.body, ty, at_block)
                 })
-                .collect()
+                .collect())
         } else {
             self.pop_n(tys.len())
         }
@@ -959,21 +1220,21 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             }
 
             wasmparser::Operator::LocalGet { local_index } => {
-                let local_index = Local::from(*local_index);
+                let local_index = self.local(*local_index)?;
                 let ty = self.body.locals[local_index];
                 let value = self.locals.get(&mut self.body, local_index);
                 self.op_stack.push((ty, value));
             }
 
             wasmparser::Operator::LocalSet { local_index } => {
-                let local_index = Local::from(*local_index);
-                let (_, value) = self.op_stack.pop().unwrap();
+                let local_index = self.local(*local_index)?;
+                let value = self.pop_1()?;
                 self.locals.set(local_index, value);
             }
 
             wasmparser::Operator::LocalTee { local_index } => {
-                let local_index = Local::from(*local_index);
-                let (_ty, value) = *self.op_stack.last().unwrap();
+                let local_index = self.local(*local_index)?;
+                let (_ty, value) = self.peek_n(1)?[0];
                 self.locals.set(local_index, value);
             }
 
@@ -1008,6 +1269,73 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             | wasmparser::Operator::I64Store32 { .. }
             | wasmparser::Operator::MemorySize { .. }
             | wasmparser::Operator::MemoryGrow { .. }
+            | wasmparser::Operator::MemoryAtomicNotify { .. }
+            | wasmparser::Operator::MemoryAtomicWait32 { .. }
+            | wasmparser::Operator::MemoryAtomicWait64 { .. }
+            | wasmparser::Operator::AtomicFence
+            | wasmparser::Operator::I32AtomicLoad { .. }
+            | wasmparser::Operator::I64AtomicLoad { .. }
+            | wasmparser::Operator::I32AtomicLoad8U { .. }
+            | wasmparser::Operator::I32AtomicLoad16U { .. }
+            | wasmparser::Operator::I64AtomicLoad8U { .. }
+            | wasmparser::Operator::I64AtomicLoad16U { .. }
+            | wasmparser::Operator::I64AtomicLoad32U { .. }
+            | wasmparser::Operator::I32AtomicStore { .. }
+            | wasmparser::Operator::I64AtomicStore { .. }
+            | wasmparser::Operator::I32AtomicStore8 { .. }
+            | wasmparser::Operator::I32AtomicStore16 { .. }
+            | wasmparser::Operator::I64AtomicStore8 { .. }
+            | wasmparser::Operator::I64AtomicStore16 { .. }
+            | wasmparser::Operator::I64AtomicStore32 { .. }
+            | wasmparser::Operator::I32AtomicRmwAdd { .. }
+            | wasmparser::Operator::I64AtomicRmwAdd { .. }
+            | wasmparser::Operator::I32AtomicRmw8AddU { .. }
+            | wasmparser::Operator::I32AtomicRmw16AddU { .. }
+            | wasmparser::Operator::I64AtomicRmw8AddU { .. }
+            | wasmparser::Operator::I64AtomicRmw16AddU { .. }
+            | wasmparser::Operator::I64AtomicRmw32AddU { .. }
+            | wasmparser::Operator::I32AtomicRmwSub { .. }
+            | wasmparser::Operator::I64AtomicRmwSub { .. }
+            | wasmparser::Operator::I32AtomicRmw8SubU { .. }
+            | wasmparser::Operator::I32AtomicRmw16SubU { .. }
+            | wasmparser::Operator::I64AtomicRmw8SubU { .. }
+            | wasmparser::Operator::I64AtomicRmw16SubU { .. }
+            | wasmparser::Operator::I64AtomicRmw32SubU { .. }
+            | wasmparser::Operator::I32AtomicRmwAnd { .. }
+            | wasmparser::Operator::I64AtomicRmwAnd { .. }
+            | wasmparser::Operator::I32AtomicRmw8AndU { .. }
+            | wasmparser::Operator::I32AtomicRmw16AndU { .. }
+            | wasmparser::Operator::I64AtomicRmw8AndU { .. }
+            | wasmparser::Operator::I64AtomicRmw16AndU { .. }
+            | wasmparser::Operator::I64AtomicRmw32AndU { .. }
+            | wasmparser::Operator::I32AtomicRmwOr { .. }
+            | wasmparser::Operator::I64AtomicRmwOr { .. }
+            | wasmparser::Operator::I32AtomicRmw8OrU { .. }
+            | wasmparser::Operator::I32AtomicRmw16OrU { .. }
+            | wasmparser::Operator::I64AtomicRmw8OrU { .. }
+            | wasmparser::Operator::I64AtomicRmw16OrU { .. }
+            | wasmparser::Operator::I64AtomicRmw32OrU { .. }
+            | wasmparser::Operator::I32AtomicRmwXor { .. }
+            | wasmparser::Operator::I64AtomicRmwXor { .. }
+            | wasmparser::Operator::I32AtomicRmw8XorU { .. }
+            | wasmparser::Operator::I32AtomicRmw16XorU { .. }
+            | wasmparser::Operator::I64AtomicRmw8XorU { .. }
+            | wasmparser::Operator::I64AtomicRmw16XorU { .. }
+            | wasmparser::Operator::I64AtomicRmw32XorU { .. }
+            | wasmparser::Operator::I32AtomicRmwXchg { .. }
+            | wasmparser::Operator::I64AtomicRmwXchg { .. }
+            | wasmparser::Operator::I32AtomicRmw8XchgU { .. }
+            | wasmparser::Operator::I32AtomicRmw16XchgU { .. }
+            | wasmparser::Operator::I64AtomicRmw8XchgU { .. }
+            | wasmparser::Operator::I64AtomicRmw16XchgU { .. }
+            | wasmparser::Operator::I64AtomicRmw32XchgU { .. }
+            | wasmparser::Operator::I32AtomicRmwCmpxchg { .. }
+            | wasmparser::Operator::I64AtomicRmwCmpxchg { .. }
+            | wasmparser::Operator::I32AtomicRmw8CmpxchgU { .. }
+            | wasmparser::Operator::I32AtomicRmw16CmpxchgU { .. }
+            | wasmparser::Operator::I64AtomicRmw8CmpxchgU { .. }
+            | wasmparser::Operator::I64AtomicRmw16CmpxchgU { .. }
+            | wasmparser::Operator::I64AtomicRmw32CmpxchgU { .. }
             | wasmparser::Operator::I32Const { .. }
             | wasmparser::Operator::I64Const { .. }
             | wasmparser::Operator::F32Const { .. }
@@ -1158,18 +1486,18 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             wasmparser::Operator::Nop => {}
 
             wasmparser::Operator::Drop => {
-                let _ = self.pop_1();
+                let _ = self.pop_1()?;
             }
 
             wasmparser::Operator::Br { relative_depth }
             | wasmparser::Operator::BrIf { relative_depth } => {
                 let cond = match &op {
                     wasmparser::Operator::Br { .. } => None,
-                    wasmparser::Operator::BrIf { .. } => Some(self.pop_1()),
+                    wasmparser::Operator::BrIf { .. } => Some(self.pop_1()?),
                     _ => unreachable!(),
                 };
                 // Get the frame we're branching to.
-                let frame = self.relative_frame(*relative_depth);
+                let frame = self.relative_frame(*relative_depth)?;
                 frame.set_reachable();
                 let frame = frame.clone();
                 log::trace!("Br/BrIf: dest frame {:?}", frame);
@@ -1177,7 +1505,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                 match cond {
                     None => {
                         // Get the args off the stack unconditionally.
-                        let args = self.pop_n(frame.br_args().len());
+                        let args = self.pop_n(frame.br_args().len())?;
                         self.emit_branch(frame.br_target(), &args[..]);
                         self.locals.finish_block(self.reachable);
                         self.reachable = false;
@@ -1185,7 +1513,8 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                     Some(cond) => {
                         let cont = self.body.add_block();
                         // Get the args off the stack but leave for the fallthrough.
-                        let args = self.op_stack[self.op_stack.len() - frame.br_args().len()..]
+                        let args = self
+                            .peek_n(frame.br_args().len())?
                             .iter()
                             .map(|(_ty, value)| *value)
                             .collect::<Vec<_>>();
@@ -1200,23 +1529,27 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
 
             wasmparser::Operator::BrTable { targets } => {
                 // Get the selector index.
-                let index = self.pop_1();
+                let index = self.pop_1()?;
                 // Get the signature of the default frame; this tells
-                // us the signature of all frames (since wasmparser
-                // validates the input for us). Pop that many args.
-                let default_frame = self.relative_frame(targets.default());
+                // us the signature of all frames, which must match.
+                // Pop that many args.
+                let default_frame = self.relative_frame(targets.default())?;
                 default_frame.set_reachable();
                 let default_term_target = default_frame.br_target();
                 let arg_len = default_frame.br_args().len();
-                let args = self.pop_n(arg_len);
+                let args = self.pop_n(arg_len)?;
                 // Generate a branch terminator with the same args for
                 // every branch target.
                 let mut term_targets = vec![];
                 for target in targets.targets() {
                     let target = target?;
-                    let frame = self.relative_frame(target);
+                    let frame = self.relative_frame(target)?;
                     frame.set_reachable();
-                    assert_eq!(frame.br_args().len(), args.len());
+                    if frame.br_args().len() != args.len() {
+                        bail!(WaffleError::malformed(
+                            "br_table targets take different numbers of values"
+                        ));
+                    }
                     let block = frame.br_target();
                     term_targets.push(block);
                 }
@@ -1226,12 +1559,12 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             }
 
             wasmparser::Operator::Return => {
-                let retvals = self.pop_n(self.module.signatures[self.my_sig].returns.len());
+                let retvals = self.pop_n(self.module.signatures[self.my_sig].returns.len())?;
                 self.emit_ret(&retvals[..]);
                 self.reachable = false;
             }
 
-            _ => bail!(FrontendError::UnsupportedFeature(format!(
+            _ => bail!(WaffleError::unsupported(format!(
                 "Unsupported operator: {:?}",
                 op
             ))),
@@ -1267,7 +1600,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                     None => {
                         if self.reachable {
                             let retvals =
-                                self.pop_n(self.module.signatures[self.my_sig].returns.len());
+                                self.pop_n(self.module.signatures[self.my_sig].returns.len())?;
                             self.emit_ret(&retvals[..]);
                         } else {
                             self.emit_unreachable();
@@ -1290,7 +1623,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                         let was_reachable = self.reachable;
                         if self.reachable {
                             let result_values =
-                                self.block_results(&results[..], *start_depth, self.cur_block);
+                                self.block_results(&results[..], *start_depth, self.cur_block)?;
                             self.emit_branch(*out, &result_values[..]);
                         }
                         self.op_stack.truncate(*start_depth);
@@ -1328,7 +1661,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                         let was_reachable = self.reachable;
                         if self.reachable {
                             let result_values =
-                                self.block_results(&results[..], *start_depth, self.cur_block);
+                                self.block_results(&results[..], *start_depth, self.cur_block)?;
                             self.emit_branch(*out, &result_values[..]);
                         }
                         assert!(self.op_stack.len() >= *start_depth);
@@ -1338,7 +1671,11 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                             // branch in the else-block. If the if-block-type
                             // has results, they must be exactly the params.
                             let else_result_values = param_values;
-                            assert_eq!(else_result_values.len(), results.len());
+                            if else_result_values.len() != results.len() {
+                                bail!(WaffleError::malformed(
+                                    "If without Else must give back its params"
+                                ));
+                            }
                             let else_result_values = else_result_values
                                 .iter()
                                 .map(|(_ty, value)| *value)
@@ -1370,7 +1707,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                         let was_reachable = self.reachable;
                         if self.reachable {
                             let result_values =
-                                self.block_results(&results[..], *start_depth, self.cur_block);
+                                self.block_results(&results[..], *start_depth, self.cur_block)?;
                             self.emit_branch(*out, &result_values[..]);
                         }
                         self.op_stack.truncate(*start_depth);
@@ -1385,11 +1722,11 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             }
 
             wasmparser::Operator::Block { blockty } => {
-                let (params, results) = self.block_params_and_results(*blockty);
+                let (params, results) = self.block_params_and_results(*blockty)?;
                 let out = self.body.add_block();
                 self.add_block_params(out, &results[..]);
                 let start_depth = if self.reachable {
-                    self.op_stack.len() - params.len()
+                    self.op_stack.len() - self.peek_n(params.len())?.len()
                 } else {
                     self.op_stack.len()
                 };
@@ -1403,11 +1740,11 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             }
 
             wasmparser::Operator::Loop { blockty } => {
-                let (params, results) = self.block_params_and_results(*blockty);
+                let (params, results) = self.block_params_and_results(*blockty)?;
                 let header = self.body.add_block();
                 self.add_block_params(header, &params[..]);
                 let initial_args = if self.reachable {
-                    self.pop_n(params.len())
+                    self.pop_n(params.len())?
                 } else {
                     vec![Value::invalid(); params.len()]
                 };
@@ -1429,14 +1766,14 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             }
 
             wasmparser::Operator::If { blockty } => {
-                let (params, results) = self.block_params_and_results(*blockty);
+                let (params, results) = self.block_params_and_results(*blockty)?;
                 let if_true = self.body.add_block();
                 let if_false = self.body.add_block();
                 let join = self.body.add_block();
                 self.add_block_params(join, &results[..]);
                 let (cond, param_values) = if self.reachable {
-                    let cond = self.pop_1();
-                    let param_values = self.op_stack[self.op_stack.len() - params.len()..].to_vec();
+                    let cond = self.pop_1()?;
+                    let param_values = self.peek_n(params.len())?.to_vec();
                     (cond, param_values)
                 } else {
                     (
@@ -1468,7 +1805,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             }
 
             wasmparser::Operator::Else => {
-                if let Frame::If {
+                if let Some(Frame::If {
                     start_depth,
                     out,
                     el,
@@ -1477,11 +1814,11 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                     results,
                     head_reachable,
                     merge_reachable,
-                } = self.ctrl_stack.pop().unwrap()
+                }) = self.ctrl_stack.pop()
                 {
                     if self.reachable {
                         let if_results =
-                            self.block_results(&results[..], start_depth, self.cur_block);
+                            self.block_results(&results[..], start_depth, self.cur_block)?;
                         self.emit_branch(out, &if_results[..]);
                     }
                     self.op_stack.truncate(start_depth);
@@ -1498,9 +1835,9 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
                     self.locals.start_block(el);
                     self.reachable = head_reachable;
                 } else {
-                    bail!(FrontendError::Internal(format!(
+                    bail!(WaffleError::malformed(
                         "Else without If on top of frame stack"
-                    )));
+                    ));
                 }
             }
 
@@ -1517,23 +1854,43 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
         }
     }
 
-    fn block_params_and_results(&self, ty: BlockType) -> (Vec<Type>, Vec<Type>) {
-        match ty {
+    fn block_params_and_results(&self, ty: BlockType) -> Result<(Vec<Type>, Vec<Type>)> {
+        Ok(match ty {
             BlockType::Empty => (vec![], vec![]),
             BlockType::Type(ret_ty) => (vec![], vec![ret_ty.into()]),
             BlockType::FuncType(sig_idx) => {
-                let sig = &self.module.signatures[Signature::from(sig_idx)];
+                let sig = self
+                    .module
+                    .signatures
+                    .get(Signature::from(sig_idx))
+                    .ok_or_else(|| {
+                        WaffleError::malformed(format!("No such block type: {}", sig_idx))
+                    })?;
                 (
                     Vec::from(sig.params.clone()),
                     Vec::from(sig.returns.clone()),
                 )
             }
-        }
+        })
     }
 
-    fn relative_frame(&mut self, relative_depth: u32) -> &mut Frame {
-        let index = self.ctrl_stack.len() - 1 - relative_depth as usize;
-        &mut self.ctrl_stack[index]
+    fn relative_frame(&mut self, relative_depth: u32) -> Result<&mut Frame> {
+        let index = self
+            .ctrl_stack
+            .len()
+            .checked_sub(1 + relative_depth as usize)
+            .ok_or_else(|| {
+                WaffleError::malformed(format!("No enclosing block at depth {}", relative_depth))
+            })?;
+        Ok(&mut self.ctrl_stack[index])
+    }
+
+    fn local(&self, local_index: u32) -> Result<Local> {
+        let local = Local::from(local_index);
+        match self.body.locals.get(local) {
+            Some(_) => Ok(local),
+            None => bail!(WaffleError::malformed(format!("No such local: {}", local))),
+        }
     }
 
     fn emit_branch(&mut self, target: Block, args: &[Value]) {
@@ -1647,6 +2004,28 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
         }
     }
 
+    /// Check that the entities `op` refers to exist, before looking
+    /// up their types.
+    fn check_entities(&self, op: &Operator) -> Result<()> {
+        let exists = match op.entity_use() {
+            Some(EntityUse::Func(func)) => self.module.funcs.get(func).is_some(),
+            Some(EntityUse::Global(global)) => self.module.globals.get(global).is_some(),
+            Some(EntityUse::Table(table)) => self.module.tables.get(table).is_some(),
+            Some(EntityUse::Memory(memory)) => self.module.memories.get(memory).is_some(),
+            None => true,
+        };
+        let sig_exists = match op {
+            Operator::CallIndirect { sig_index, .. } => {
+                self.module.signatures.get(*sig_index).is_some()
+            }
+            _ => true,
+        };
+        if !exists || !sig_exists {
+            bail!(WaffleError::malformed(format!("No such entity: {}", op)));
+        }
+        Ok(())
+    }
+
     fn emit_unreachable(&mut self) {
         log::trace!(
             "emit_unreachable: cur_block {} reachable {}",
@@ -1674,6 +2053,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
     }
 
     fn emit(&mut self, op: Operator, loc: SourceLoc) -> Result<()> {
+        self.check_entities(&op)?;
         let inputs = op_inputs(self.module, &self.op_stack[..], &op)?;
         let outputs = op_outputs(self.module, &self.op_stack[..], &op)?;
 
@@ -1686,21 +2066,27 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
 
         let n_outputs = outputs.len();
 
+        let operands = self.peek_n(inputs.len())?;
+        if let Some((&(ty, _), &expected)) = operands
+            .iter()
+            .zip(inputs.iter())
+            .find(|((ty, _), expected)| ty != *expected)
+        {
+            bail!(WaffleError::malformed(format!(
+                "Type mismatch: expected {}, found {}",
+                expected, ty
+            )));
+        }
         let input_operands = self.body.arg_pool.allocate(inputs.len(), Value::invalid());
         let args = &mut self.body.arg_pool[input_operands];
-        for (i, &input) in inputs.into_iter().enumerate().rev() {
-            let (stack_top_ty, stack_top) = self.op_stack.pop().unwrap();
-            assert_eq!(stack_top_ty, input);
+        for (i, _) in inputs.into_iter().enumerate().rev() {
+            let (_, stack_top) = self.op_stack.pop().unwrap();
             args[i] = stack_top;
         }
         log::trace!(" -> operands: {:?}", input_operands);
         log::trace!(" -> ty {:?}", outputs);
 
-        let outputs_list = if n_outputs == 1 {
-            self.body.single_type_list(outputs[0])
-        } else {
-            self.body.type_pool.from_iter(outputs.iter().cloned())
-        };
+        let outputs_list = self.body.type_list(&outputs[..]);
 
         let value = self
             .body
@@ -1711,6 +2097,7 @@ impl<'a, 'b> FunctionBodyBuilder<'a, 'b> {
             self.body.append_to_block(self.cur_block, value);
         }
         self.body.source_locs[value] = loc;
+        self.body.source_offsets[value] = self.cur_offset;
 
         if n_outputs == 1 {
             let output_ty = outputs[0];
@@ -1,6 +1,11 @@
 //! Fuzzing-specific utilities.
 
+use crate::entity::EntityRef;
+use crate::gen;
+use crate::ir::*;
+use crate::{ConstVal, InterpResult, Operator};
 use libfuzzer_sys::arbitrary;
+use std::collections::BTreeSet;
 
 pub fn reject(bytes: &[u8]) -> bool {
     let parser = wasmparser::Parser::new(0);
@@ -109,3 +114,229 @@ impl wasm_smith::Config for Config {
         1
     }
 }
+
+/// A module with one function, `func`, whose body is generated IR
+/// rather than parsed from Wasm, and arguments to call it with. The
+/// body is valid, but may loop forever or trap when run.
+#[derive(Debug)]
+pub struct IrModule {
+    pub module: Module<'static>,
+    pub func: Func,
+    pub args: Vec<ConstVal>,
+}
+
+impl<'a> arbitrary::Arbitrary<'a> for IrModule {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let mut module = Module::with_orig_bytes(&[]);
+        let params = arbitrary_types(u, 4)?;
+        let returns = arbitrary_types(u, 2)?;
+        let sig = module.find_or_add_signature(SignatureData {
+            params: params.clone(),
+            returns,
+        });
+        let body = arbitrary_body(u, &module, sig)?;
+        let func = module.add_function(sig, "run", body);
+        module.exports.push(Export {
+            name: "run".to_owned(),
+            kind: ExportKind::Func(func),
+        });
+        let args = params
+            .iter()
+            .map(|&ty| arbitrary_const(u, ty))
+            .collect::<arbitrary::Result<_>>()?;
+        Ok(IrModule { module, func, args })
+    }
+}
+
+fn arbitrary_types(
+    u: &mut arbitrary::Unstructured<'_>,
+    max: usize,
+) -> arbitrary::Result<Vec<Type>> {
+    let len = u.int_in_range(0..=max)?;
+    (0..len).map(|_| u.choose(gen::TYPES).copied()).collect()
+}
+
+fn arbitrary_const(u: &mut arbitrary::Unstructured<'_>, ty: Type) -> arbitrary::Result<ConstVal> {
+    Ok(match ty {
+        Type::I32 => ConstVal::I32(u.arbitrary()?),
+        Type::I64 => ConstVal::I64(u.arbitrary()?),
+        _ => unreachable!(),
+    })
+}
+
+/// Generate a valid body with signature `sig`: a CFG of up to eight
+/// blocks, with blockparams and arbitrary forward edges. Each block
+/// uses only its own params and instructions, so every use is
+/// dominated by its definition however the blocks are connected.
+///
+/// The backend only handles reducible control flow, so a block
+/// branches back only to blocks that dominate it. As all other edges go
+/// forward, a block's dominators are known once all blocks before it
+/// are generated, and back edges to dominators do not change them.
+pub fn arbitrary_body(
+    u: &mut arbitrary::Unstructured<'_>,
+    module: &Module,
+    sig: Signature,
+) -> arbitrary::Result<FunctionBody> {
+    let mut body = FunctionBody::new(module, sig);
+    let num_blocks = u.int_in_range(1..=8)?;
+    let mut blocks = vec![body.entry];
+    for _ in 1..num_blocks {
+        let block = body.add_block();
+        for ty in arbitrary_types(u, 3)? {
+            body.add_blockparam(block, ty);
+        }
+        blocks.push(block);
+    }
+
+    let mut preds: Vec<Vec<usize>> = vec![vec![]; num_blocks];
+    // Empty for blocks not reachable by forward edges.
+    let mut doms: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); num_blocks];
+    for (i, &block) in blocks.iter().enumerate() {
+        let mut reachable = preds[i].iter().filter(|&&pred| !doms[pred].is_empty());
+        doms[i] = match reachable.next() {
+            _ if i == 0 => BTreeSet::new(),
+            Some(&first) => reachable.fold(doms[first].clone(), |doms_i, &pred| {
+                doms_i.intersection(&doms[pred]).copied().collect()
+            }),
+            None => BTreeSet::new(),
+        };
+        if i == 0 || !doms[i].is_empty() {
+            doms[i].insert(i);
+        }
+        let succs = (0..num_blocks)
+            .filter(|&j| j > i || doms[i].contains(&j))
+            .map(|j| blocks[j])
+            .collect::<Vec<_>>();
+
+        let mut avail: Vec<(Type, Value)> = body.blocks[block].params.clone();
+        for _ in 0..u.int_in_range(0..=16)? {
+            let value = if u.ratio(1, 8)? {
+                let ty = u.choose(gen::TYPES).copied()?;
+                let cond = pick(u, &mut body, block, &mut avail, Type::I32)?;
+                let a = pick(u, &mut body, block, &mut avail, ty)?;
+                let b = pick(u, &mut body, block, &mut avail, ty)?;
+                let args = body.arg_pool.triple(a, b, cond);
+                let tys = body.single_type_list(ty);
+                (ty, ValueDef::Operator(Operator::Select, args, tys))
+            } else {
+                let &(op, arg_tys, ty) = u.choose(gen::OPS)?;
+                let mut args = vec![];
+                for &arg_ty in arg_tys {
+                    args.push(pick(u, &mut body, block, &mut avail, arg_ty)?);
+                }
+                let args = body.arg_pool.from_iter(args.into_iter());
+                let tys = body.single_type_list(ty);
+                (ty, ValueDef::Operator(op, args, tys))
+            };
+            let (ty, def) = value;
+            let value = body.add_value(def);
+            body.append_to_block(block, value);
+            avail.push((ty, value));
+        }
+
+        let terminator = match u.int_in_range(0..=9)? {
+            0..=1 => {
+                let mut values = vec![];
+                for ty in body.rets.clone() {
+                    values.push(pick(u, &mut body, block, &mut avail, ty)?);
+                }
+                Terminator::Return { values }
+            }
+            2 => Terminator::Unreachable,
+            3..=5 => Terminator::Br {
+                target: arbitrary_target(u, &mut body, block, &mut avail, &succs)?,
+            },
+            6..=8 => Terminator::CondBr {
+                cond: pick(u, &mut body, block, &mut avail, Type::I32)?,
+                if_true: arbitrary_target(u, &mut body, block, &mut avail, &succs)?,
+                if_false: arbitrary_target(u, &mut body, block, &mut avail, &succs)?,
+            },
+            _ => {
+                let value = pick(u, &mut body, block, &mut avail, Type::I32)?;
+                let mut targets = vec![];
+                for _ in 0..u.int_in_range(0..=3)? {
+                    targets.push(arbitrary_target(u, &mut body, block, &mut avail, &succs)?);
+                }
+                let default = arbitrary_target(u, &mut body, block, &mut avail, &succs)?;
+                Terminator::Select {
+                    value,
+                    targets,
+                    default,
+                }
+            }
+        };
+        terminator.visit_successors(|succ| {
+            if succ.index() > i {
+                preds[succ.index()].push(i);
+            }
+        });
+        body.set_terminator(block, terminator);
+    }
+    Ok(body)
+}
+
+/// A value of type `ty` available in `block`, or a new constant if
+/// there is none (or, sometimes, anyway).
+fn pick(
+    u: &mut arbitrary::Unstructured<'_>,
+    body: &mut FunctionBody,
+    block: Block,
+    avail: &mut Vec<(Type, Value)>,
+    ty: Type,
+) -> arbitrary::Result<Value> {
+    let candidates = avail
+        .iter()
+        .filter(|&&(avail_ty, _)| avail_ty == ty)
+        .map(|&(_, value)| value)
+        .collect::<Vec<_>>();
+    if !candidates.is_empty() && !u.ratio(1, 8)? {
+        return u.choose(&candidates).copied();
+    }
+    let value = gen::add_const(body, block, ty, u.arbitrary()?);
+    avail.push((ty, value));
+    Ok(value)
+}
+
+fn arbitrary_target(
+    u: &mut arbitrary::Unstructured<'_>,
+    body: &mut FunctionBody,
+    block: Block,
+    avail: &mut Vec<(Type, Value)>,
+    targets: &[Block],
+) -> arbitrary::Result<BlockTarget> {
+    let target = u.choose(targets).copied()?;
+    let mut args = vec![];
+    for ty in body.blocks[target]
+        .params
+        .iter()
+        .map(|&(ty, _)| ty)
+        .collect::<Vec<_>>()
+    {
+        args.push(pick(u, body, block, avail, ty)?);
+    }
+    Ok(BlockTarget {
+        block: target,
+        args,
+    })
+}
+
+/// The outcome of running a function, for comparison between runs of
+/// differently compiled versions of it: results are compared exactly,
+/// while traps are only compared as traps, as their locations differ.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Returned(Vec<ConstVal>),
+    Trapped,
+}
+
+impl Outcome {
+    /// The outcome of `result`, or `None` if the run did not finish.
+    pub fn of(result: InterpResult) -> Option<Outcome> {
+        match result {
+            InterpResult::Ok(values) => Some(Outcome::Returned(values.into_vec())),
+            InterpResult::Trap(..) => Some(Outcome::Trapped),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! Pieces shared by the generators of random bodies and modules for
+//! fuzzing, property testing and benchmarking, and by the mutator.
+
+use crate::ir::{Block, BlockTarget, FunctionBody, Terminator, Type, Value, ValueDef};
+use crate::prelude::*;
+use crate::Operator;
+
+/// The types of the values generated bodies compute.
+pub(crate) const TYPES: &[Type] = &[Type::I32, Type::I64];
+
+/// Operators the generated bodies use, with their argument and result
+/// types. `select` is handled separately, being polymorphic.
+pub(crate) const OPS: &[(Operator, &[Type], Type)] = &[
+    (Operator::I32Add, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32Sub, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32Mul, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32DivU, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32RemU, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32And, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32Or, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32Xor, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32Shl, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32ShrS, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32ShrU, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32Rotl, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32Eq, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32LtS, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32LtU, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32GtU, &[Type::I32, Type::I32], Type::I32),
+    (Operator::I32Eqz, &[Type::I32], Type::I32),
+    (Operator::I32Clz, &[Type::I32], Type::I32),
+    (Operator::I32Popcnt, &[Type::I32], Type::I32),
+    (Operator::I32WrapI64, &[Type::I64], Type::I32),
+    (Operator::I64Add, &[Type::I64, Type::I64], Type::I64),
+    (Operator::I64Sub, &[Type::I64, Type::I64], Type::I64),
+    (Operator::I64Mul, &[Type::I64, Type::I64], Type::I64),
+    (Operator::I64DivU, &[Type::I64, Type::I64], Type::I64),
+    (Operator::I64And, &[Type::I64, Type::I64], Type::I64),
+    (Operator::I64Xor, &[Type::I64, Type::I64], Type::I64),
+    (Operator::I64ShrU, &[Type::I64, Type::I64], Type::I64),
+    (Operator::I64Eq, &[Type::I64, Type::I64], Type::I32),
+    (Operator::I64LtS, &[Type::I64, Type::I64], Type::I32),
+    (Operator::I64Eqz, &[Type::I64], Type::I32),
+    (Operator::I64ExtendI32S, &[Type::I32], Type::I64),
+    (Operator::I64ExtendI32U, &[Type::I32], Type::I64),
+];
+
+/// Append a constant of type `ty`, with the low bits of `bits`, to
+/// `block`.
+pub(crate) fn add_const(body: &mut FunctionBody, block: Block, ty: Type, bits: u64) -> Value {
+    let op = match ty {
+        Type::I32 => Operator::I32Const { value: bits as u32 },
+        Type::I64 => Operator::I64Const { value: bits },
+        Type::F32 => Operator::F32Const { value: bits as u32 },
+        Type::F64 => Operator::F64Const { value: bits },
+        _ => unreachable!(),
+    };
+    let args = body.arg_pool.from_iter(core::iter::empty());
+    let tys = body.single_type_list(ty);
+    let value = body.add_value(ValueDef::Operator(op, args, tys));
+    body.append_to_block(block, value);
+    value
+}
+
+/// At most this many of the values available at the end of a block
+/// are passed on to the next.
+const MAX_CARRIED: usize = 8;
+
+/// A body being built from structured regions (straight-line code,
+/// ifs and counted loops), so that its control flow is reducible and
+/// it always terminates.
+pub(crate) struct Regions {
+    pub(crate) body: FunctionBody,
+    pub(crate) block: Block,
+    /// The values available in `block`: its params and instructions.
+    /// Each block uses only these, so the uses are dominated by their
+    /// definitions however the blocks nest.
+    pub(crate) values: Vec<(Type, Value)>,
+    /// The trip counters of the enclosing loops, passed along to every
+    /// block but not otherwise used.
+    counters: Vec<Value>,
+}
+
+/// An if between `Regions::begin_if` and `Regions::end_if`.
+pub(crate) struct IfRegion {
+    tys: Vec<Type>,
+    if_false: Block,
+    merge: Block,
+}
+
+/// A loop between `Regions::begin_loop` and `Regions::end_loop`.
+pub(crate) struct LoopRegion {
+    tys: Vec<Type>,
+    header: Block,
+    exit: Block,
+}
+
+impl Regions {
+    pub(crate) fn new(body: FunctionBody) -> Regions {
+        Regions {
+            block: body.entry,
+            values: body.blocks[body.entry].params.clone(),
+            counters: vec![],
+            body,
+        }
+    }
+
+    /// Append `def`, of type `ty`, to the current block.
+    pub(crate) fn push(&mut self, ty: Type, def: ValueDef) -> Value {
+        let value = self.body.add_value(def);
+        self.body.append_to_block(self.block, value);
+        self.values.push((ty, value));
+        value
+    }
+
+    /// Append a constant of type `ty` to the current block.
+    pub(crate) fn constant(&mut self, ty: Type, bits: u64) -> Value {
+        let value = add_const(&mut self.body, self.block, ty, bits);
+        self.values.push((ty, value));
+        value
+    }
+
+    /// The available values of type `ty`.
+    pub(crate) fn candidates(&self, ty: Type) -> Vec<Value> {
+        self.values
+            .iter()
+            .filter(|&&(value_ty, _)| value_ty == ty)
+            .map(|&(_, value)| value)
+            .collect()
+    }
+
+    /// Branch on `cond`, continuing in the true arm.
+    pub(crate) fn begin_if(&mut self, cond: Value) -> IfRegion {
+        let tys = self.carried_types();
+        let args = self.jump_args(&tys);
+        let if_true = self.new_block(&tys, self.counters.len());
+        let if_false = self.new_block(&tys, self.counters.len());
+        let merge = self.new_block(&tys, self.counters.len());
+        self.terminate(Terminator::CondBr {
+            cond,
+            if_true: BlockTarget {
+                block: if_true,
+                args: args.clone(),
+            },
+            if_false: BlockTarget {
+                block: if_false,
+                args,
+            },
+        });
+        self.switch_to(if_true, tys.len());
+        IfRegion {
+            tys,
+            if_false,
+            merge,
+        }
+    }
+
+    /// End the true arm of `region`, continuing in the false arm.
+    pub(crate) fn else_(&mut self, region: &IfRegion) {
+        self.jump(region.merge, &region.tys);
+        self.switch_to(region.if_false, region.tys.len());
+    }
+
+    /// End the false arm of `region`, continuing after it.
+    pub(crate) fn end_if(&mut self, region: IfRegion) {
+        self.jump(region.merge, &region.tys);
+        self.switch_to(region.merge, region.tys.len());
+    }
+
+    /// Start a loop that runs `trips` times, at least once, continuing
+    /// in its body.
+    pub(crate) fn begin_loop(&mut self, trips: u32) -> LoopRegion {
+        let tys = self.carried_types();
+        let trips = add_const(&mut self.body, self.block, Type::I32, trips.max(1) as u64);
+        let mut args = self.jump_args(&tys);
+        args.push(trips);
+        let header = self.new_block(&tys, self.counters.len() + 1);
+        let exit = self.new_block(&tys, self.counters.len());
+        self.terminate(Terminator::Br {
+            target: BlockTarget {
+                block: header,
+                args,
+            },
+        });
+        self.switch_to(header, tys.len());
+        LoopRegion { tys, header, exit }
+    }
+
+    /// End the body of `region`, continuing after the loop.
+    pub(crate) fn end_loop(&mut self, region: LoopRegion) {
+        let counter = self.counters.pop().unwrap();
+        let one = add_const(&mut self.body, self.block, Type::I32, 1);
+        let args = self.body.arg_pool.double(counter, one);
+        let tys_i32 = self.body.single_type_list(Type::I32);
+        let next = self
+            .body
+            .add_value(ValueDef::Operator(Operator::I32Sub, args, tys_i32));
+        self.body.append_to_block(self.block, next);
+        let exit_args = self.jump_args(&region.tys);
+        let mut header_args = exit_args.clone();
+        header_args.push(next);
+        self.terminate(Terminator::CondBr {
+            cond: next,
+            if_true: BlockTarget {
+                block: region.header,
+                args: header_args,
+            },
+            if_false: BlockTarget {
+                block: region.exit,
+                args: exit_args,
+            },
+        });
+        self.switch_to(region.exit, region.tys.len());
+    }
+
+    /// Return values of types `results` from the current block, and
+    /// take the body.
+    pub(crate) fn finish(mut self, results: &[Type]) -> FunctionBody {
+        let values = self.carry(results);
+        self.terminate(Terminator::Return { values });
+        self.body
+    }
+
+    fn carried_types(&self) -> Vec<Type> {
+        let start = self.values.len().saturating_sub(MAX_CARRIED);
+        self.values[start..].iter().map(|&(ty, _)| ty).collect()
+    }
+
+    /// Values of types `tys`, the latest available of each type that
+    /// is not already taken, or zeroes.
+    fn carry(&mut self, tys: &[Type]) -> Vec<Value> {
+        let mut taken = vec![false; self.values.len()];
+        let mut carried = vec![];
+        for &ty in tys {
+            let latest = (0..self.values.len())
+                .rev()
+                .find(|&i| !taken[i] && self.values[i].0 == ty);
+            carried.push(match latest {
+                Some(i) => {
+                    taken[i] = true;
+                    self.values[i].1
+                }
+                None => add_const(&mut self.body, self.block, ty, 0),
+            });
+        }
+        carried
+    }
+
+    /// The arguments of a branch to a block made with `new_block(tys,
+    /// self.counters.len())`.
+    fn jump_args(&mut self, tys: &[Type]) -> Vec<Value> {
+        let mut args = self.carry(tys);
+        args.extend(self.counters.iter().copied());
+        args
+    }
+
+    fn jump(&mut self, block: Block, tys: &[Type]) {
+        let args = self.jump_args(tys);
+        self.terminate(Terminator::Br {
+            target: BlockTarget { block, args },
+        });
+    }
+
+    fn new_block(&mut self, tys: &[Type], num_counters: usize) -> Block {
+        let block = self.body.add_block();
+        for &ty in tys {
+            self.body.add_blockparam(block, ty);
+        }
+        for _ in 0..num_counters {
+            self.body.add_blockparam(block, Type::I32);
+        }
+        block
+    }
+
+    /// Continue in `block`, whose first `num_values` params are values
+    /// and the rest counters.
+    fn switch_to(&mut self, block: Block, num_values: usize) {
+        let params = &self.body.blocks[block].params;
+        self.block = block;
+        self.values = params[..num_values].to_vec();
+        self.counters = params[num_values..]
+            .iter()
+            .map(|&(_, value)| value)
+            .collect();
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        self.body.set_terminator(self.block, terminator);
+    }
+}
@@ -0,0 +1,432 @@
+//! Generating random valid modules from a seed, with a chosen size and
+//! shape: to benchmark waffle itself, or to fuzz engines without a
+//! corpus.
+//!
+//! Bodies are built from structured regions (straight-line code,
+//! sequences, ifs and counted loops), and functions call only
+//! functions of a lower call depth, so every function terminates when
+//! run, though it may trap. Loads and stores use addresses masked to
+//! the memory's single page, so those never trap.
+//!
+//! ```ignore
+//! let module = generate(&GenConfig { funcs: 100, ..GenConfig::default() }, seed);
+//! ```
+
+use crate::entity::EntityRef;
+use crate::gen::{self, Regions};
+use crate::ir::*;
+use crate::mutate::{MutationRng, SeededRng};
+use crate::prelude::*;
+use crate::{MemoryArg, Operator};
+
+/// Proposals beyond the MVP that generated modules may use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GenFeatures {
+    /// `f32` and `f64` values and operators.
+    pub floats: bool,
+    /// `i32.extend8_s` and the like.
+    pub sign_ext: bool,
+    /// Non-trapping float-to-int conversions; only with `floats`.
+    pub sat_float_to_int: bool,
+    /// Functions with more than one result.
+    pub multi_value: bool,
+}
+
+impl GenFeatures {
+    /// Only what the MVP has, floats included.
+    pub fn mvp() -> GenFeatures {
+        GenFeatures {
+            floats: true,
+            sign_ext: false,
+            sat_float_to_int: false,
+            multi_value: false,
+        }
+    }
+}
+
+impl Default for GenFeatures {
+    fn default() -> Self {
+        GenFeatures {
+            floats: true,
+            sign_ext: true,
+            sat_float_to_int: true,
+            multi_value: true,
+        }
+    }
+}
+
+/// The size and shape of generated modules.
+#[derive(Clone, Debug)]
+pub struct GenConfig {
+    /// Functions, all of them exported.
+    pub funcs: usize,
+    pub max_params: usize,
+    /// At most one without `features.multi_value`.
+    pub max_results: usize,
+    /// How deeply regions (sequences, ifs and loops) nest.
+    pub max_depth: usize,
+    /// How deeply loops nest, at most `max_depth`.
+    pub max_loop_depth: usize,
+    /// Regions in each sequence.
+    pub max_seq_len: usize,
+    /// Instructions in each straight-line run.
+    pub max_insts: usize,
+    /// Iterations of each loop; every loop runs at least once.
+    pub max_trip_count: u32,
+    /// The percentage of instructions that are loads or stores. With
+    /// zero, the module has no memory.
+    pub memory_ops: u32,
+    /// The percentage of instructions that are calls.
+    pub calls: u32,
+    /// How deeply calls nest: functions of depth zero call nothing,
+    /// and the others call functions of lower depths.
+    pub max_call_depth: usize,
+    /// Mutable globals, which instructions read and write.
+    pub globals: usize,
+    pub features: GenFeatures,
+}
+
+impl Default for GenConfig {
+    fn default() -> Self {
+        GenConfig {
+            funcs: 8,
+            max_params: 4,
+            max_results: 2,
+            max_depth: 4,
+            max_loop_depth: 2,
+            max_seq_len: 3,
+            max_insts: 8,
+            max_trip_count: 4,
+            memory_ops: 10,
+            calls: 5,
+            max_call_depth: 3,
+            globals: 2,
+            features: GenFeatures::default(),
+        }
+    }
+}
+
+/// Float operators, with their argument and result types.
+const FLOAT_OPS: &[(Operator, &[Type], Type)] = &[
+    (Operator::F32Add, &[Type::F32, Type::F32], Type::F32),
+    (Operator::F32Sub, &[Type::F32, Type::F32], Type::F32),
+    (Operator::F32Mul, &[Type::F32, Type::F32], Type::F32),
+    (Operator::F32Div, &[Type::F32, Type::F32], Type::F32),
+    (Operator::F32Min, &[Type::F32, Type::F32], Type::F32),
+    (Operator::F32Copysign, &[Type::F32, Type::F32], Type::F32),
+    (Operator::F32Abs, &[Type::F32], Type::F32),
+    (Operator::F32Sqrt, &[Type::F32], Type::F32),
+    (Operator::F32Floor, &[Type::F32], Type::F32),
+    (Operator::F32Lt, &[Type::F32, Type::F32], Type::I32),
+    (Operator::F32Eq, &[Type::F32, Type::F32], Type::I32),
+    (Operator::F64Add, &[Type::F64, Type::F64], Type::F64),
+    (Operator::F64Sub, &[Type::F64, Type::F64], Type::F64),
+    (Operator::F64Mul, &[Type::F64, Type::F64], Type::F64),
+    (Operator::F64Div, &[Type::F64, Type::F64], Type::F64),
+    (Operator::F64Max, &[Type::F64, Type::F64], Type::F64),
+    (Operator::F64Neg, &[Type::F64], Type::F64),
+    (Operator::F64Nearest, &[Type::F64], Type::F64),
+    (Operator::F64Le, &[Type::F64, Type::F64], Type::I32),
+    (Operator::F64Ne, &[Type::F64, Type::F64], Type::I32),
+    (Operator::F32ConvertI32S, &[Type::I32], Type::F32),
+    (Operator::F32ConvertI64U, &[Type::I64], Type::F32),
+    (Operator::F64ConvertI32U, &[Type::I32], Type::F64),
+    (Operator::F64ConvertI64S, &[Type::I64], Type::F64),
+    (Operator::F32DemoteF64, &[Type::F64], Type::F32),
+    (Operator::F64PromoteF32, &[Type::F32], Type::F64),
+    (Operator::F32ReinterpretI32, &[Type::I32], Type::F32),
+    (Operator::I64ReinterpretF64, &[Type::F64], Type::I64),
+];
+
+const SIGN_EXT_OPS: &[(Operator, &[Type], Type)] = &[
+    (Operator::I32Extend8S, &[Type::I32], Type::I32),
+    (Operator::I32Extend16S, &[Type::I32], Type::I32),
+    (Operator::I64Extend8S, &[Type::I64], Type::I64),
+    (Operator::I64Extend16S, &[Type::I64], Type::I64),
+    (Operator::I64Extend32S, &[Type::I64], Type::I64),
+];
+
+const SAT_OPS: &[(Operator, &[Type], Type)] = &[
+    (Operator::I32TruncSatF32S, &[Type::F32], Type::I32),
+    (Operator::I32TruncSatF64U, &[Type::F64], Type::I32),
+    (Operator::I64TruncSatF32U, &[Type::F32], Type::I64),
+    (Operator::I64TruncSatF64S, &[Type::F64], Type::I64),
+];
+
+/// Addresses are masked with this, which keeps accesses of up to eight
+/// bytes in the first page.
+const ADDRESS_MASK: u64 = 0xfff8;
+
+/// Generate a module of the size and shape `config` asks for. The same
+/// seed and config give the same module.
+pub fn generate(config: &GenConfig, seed: u64) -> Module<'static> {
+    let mut module = Module::empty();
+    let mut rng = SeededRng::new(seed);
+    let features = config.features;
+
+    let mut types = gen::TYPES.to_vec();
+    let mut ops = gen::OPS.to_vec();
+    if features.floats {
+        types.extend([Type::F32, Type::F64]);
+        ops.extend_from_slice(FLOAT_OPS);
+        if features.sat_float_to_int {
+            ops.extend_from_slice(SAT_OPS);
+        }
+    }
+    if features.sign_ext {
+        ops.extend_from_slice(SIGN_EXT_OPS);
+    }
+
+    let memory = if config.memory_ops > 0 {
+        let memory = module.memories.push(MemoryData {
+            initial_pages: 1,
+            maximum_pages: Some(1),
+            shared: false,
+        });
+        module.exports.push(Export {
+            name: "memory".to_owned(),
+            kind: ExportKind::Memory(memory),
+        });
+        Some(memory)
+    } else {
+        None
+    };
+    let globals = (0..config.globals)
+        .map(|_| {
+            let ty = gen::TYPES[rng.below(gen::TYPES.len())];
+            let global = module.globals.push(GlobalData {
+                ty,
+                init: Some(GlobalInit::Const(rng.next_u64())),
+                mutable: true,
+            });
+            (global, ty)
+        })
+        .collect::<Vec<_>>();
+
+    let max_results = if features.multi_value {
+        config.max_results
+    } else {
+        config.max_results.min(1)
+    };
+    let mut depths = vec![];
+    for i in 0..config.funcs {
+        let params = (0..rng.below(config.max_params + 1))
+            .map(|_| types[rng.below(types.len())])
+            .collect::<Vec<_>>();
+        let returns = (0..rng.below(max_results + 1))
+            .map(|_| types[rng.below(types.len())])
+            .collect::<Vec<_>>();
+        let sig = module.find_or_add_signature(SignatureData { params, returns });
+
+        // Callees are earlier functions of lower depths.
+        let depth = match depths.iter().max() {
+            Some(&max) => rng.below(config.max_call_depth.min(max + 1) + 1),
+            None => 0,
+        };
+        let callees = depths
+            .iter()
+            .enumerate()
+            .filter(|&(_, &callee)| callee < depth)
+            .map(|(callee, _)| {
+                let sig = module.funcs[Func::new(callee)].sig();
+                (Func::new(callee), module.signatures[sig].clone())
+            })
+            .collect();
+        depths.push(depth);
+
+        let mut builder = BodyBuilder {
+            config,
+            rng: &mut rng,
+            regions: Regions::new(FunctionBody::new(&module, sig)),
+            ops: &ops,
+            memory,
+            globals: &globals,
+            callees,
+        };
+        builder.region(
+            config.max_depth,
+            config.max_loop_depth.min(config.max_depth),
+        );
+        let results = module.signatures[sig].returns.clone();
+        let body = builder.regions.finish(&results);
+
+        let name = format!("f{}", i);
+        let func = module.add_function(sig, &name, body);
+        module.exports.push(Export {
+            name,
+            kind: ExportKind::Func(func),
+        });
+    }
+    module
+}
+
+struct BodyBuilder<'a> {
+    config: &'a GenConfig,
+    rng: &'a mut SeededRng,
+    regions: Regions,
+    ops: &'a [(Operator, &'static [Type], Type)],
+    memory: Option<Memory>,
+    globals: &'a [(Global, Type)],
+    callees: Vec<(Func, SignatureData)>,
+}
+
+impl<'a> BodyBuilder<'a> {
+    fn region(&mut self, depth: usize, loop_depth: usize) {
+        let kinds = match (depth, loop_depth) {
+            (0, _) => 1,
+            (_, 0) => 3,
+            _ => 4,
+        };
+        match self.rng.below(kinds) {
+            0 => {
+                for _ in 0..self.rng.below(self.config.max_insts + 1) {
+                    self.inst();
+                }
+            }
+            1 => {
+                for _ in 0..self.rng.below(self.config.max_seq_len) + 1 {
+                    self.region(depth - 1, loop_depth);
+                }
+            }
+            2 => {
+                let cond = self.pick(Type::I32);
+                let region = self.regions.begin_if(cond);
+                self.region(depth - 1, loop_depth);
+                self.regions.else_(&region);
+                self.region(depth - 1, loop_depth);
+                self.regions.end_if(region);
+            }
+            _ => {
+                let trips = self.rng.below(self.config.max_trip_count.max(1) as usize) + 1;
+                let region = self.regions.begin_loop(trips as u32);
+                self.region(depth - 1, loop_depth - 1);
+                self.regions.end_loop(region);
+            }
+        }
+    }
+
+    fn inst(&mut self) {
+        let roll = self.rng.below(100) as u32;
+        match self.memory {
+            Some(memory) if roll < self.config.memory_ops => return self.memory_op(memory),
+            _ => {}
+        }
+        if roll >= 100u32.saturating_sub(self.config.calls) && !self.callees.is_empty() {
+            return self.call();
+        }
+        if !self.globals.is_empty() && self.rng.below(20) == 0 {
+            return self.global_op();
+        }
+        let (op, arg_tys, ty) = self.ops[self.rng.below(self.ops.len())];
+        let args = arg_tys
+            .iter()
+            .map(|&arg_ty| self.pick(arg_ty))
+            .collect::<Vec<_>>();
+        self.push(ty, op, &args);
+    }
+
+    fn memory_op(&mut self, memory: Memory) {
+        let value = self.pick(Type::I32);
+        let mask = self.regions.constant(Type::I32, ADDRESS_MASK);
+        let addr = self.push(Type::I32, Operator::I32And, &[value, mask]);
+        let arg = |align| MemoryArg {
+            align,
+            offset: 0,
+            memory,
+        };
+        let floats = self.config.features.floats;
+        if self.rng.below(2) == 0 {
+            let (op, ty) = match self.rng.below(if floats { 7 } else { 5 }) {
+                0 => (Operator::I32Load { memory: arg(2) }, Type::I32),
+                1 => (Operator::I64Load { memory: arg(3) }, Type::I64),
+                2 => (Operator::I32Load8U { memory: arg(0) }, Type::I32),
+                3 => (Operator::I32Load16S { memory: arg(1) }, Type::I32),
+                4 => (Operator::I64Load32U { memory: arg(2) }, Type::I64),
+                5 => (Operator::F32Load { memory: arg(2) }, Type::F32),
+                _ => (Operator::F64Load { memory: arg(3) }, Type::F64),
+            };
+            self.push(ty, op, &[addr]);
+        } else {
+            let (op, ty) = match self.rng.below(if floats { 6 } else { 4 }) {
+                0 => (Operator::I32Store { memory: arg(2) }, Type::I32),
+                1 => (Operator::I64Store { memory: arg(3) }, Type::I64),
+                2 => (Operator::I32Store8 { memory: arg(0) }, Type::I32),
+                3 => (Operator::I64Store16 { memory: arg(1) }, Type::I64),
+                4 => (Operator::F32Store { memory: arg(2) }, Type::F32),
+                _ => (Operator::F64Store { memory: arg(3) }, Type::F64),
+            };
+            let value = self.pick(ty);
+            self.effect(op, &[addr, value]);
+        }
+    }
+
+    fn global_op(&mut self) {
+        let (global_index, ty) = self.globals[self.rng.below(self.globals.len())];
+        if self.rng.below(2) == 0 {
+            self.push(ty, Operator::GlobalGet { global_index }, &[]);
+        } else {
+            let value = self.pick(ty);
+            self.effect(Operator::GlobalSet { global_index }, &[value]);
+        }
+    }
+
+    fn call(&mut self) {
+        let (function_index, sig) = self.callees[self.rng.below(self.callees.len())].clone();
+        let args = sig
+            .params
+            .iter()
+            .map(|&ty| self.pick(ty))
+            .collect::<Vec<_>>();
+        let op = Operator::Call { function_index };
+        match &sig.returns[..] {
+            [] => self.effect(op, &args),
+            &[ty] => {
+                self.push(ty, op, &args);
+            }
+            returns => {
+                let args = self.regions.body.arg_pool.from_iter(args.into_iter());
+                let tys = self.regions.body.type_list(returns);
+                let call = self
+                    .regions
+                    .body
+                    .add_value(ValueDef::Operator(op, args, tys));
+                let block = self.regions.block;
+                self.regions.body.append_to_block(block, call);
+                for (i, &ty) in returns.iter().enumerate() {
+                    self.regions
+                        .push(ty, ValueDef::PickOutput(call, i as u32, ty));
+                }
+            }
+        }
+    }
+
+    /// Append `op`, with one result of type `ty`.
+    fn push(&mut self, ty: Type, op: Operator, args: &[Value]) -> Value {
+        let args = self.regions.body.arg_pool.from_iter(args.iter().copied());
+        let tys = self.regions.body.single_type_list(ty);
+        self.regions.push(ty, ValueDef::Operator(op, args, tys))
+    }
+
+    /// Append `op`, which has no results.
+    fn effect(&mut self, op: Operator, args: &[Value]) {
+        let args = self.regions.body.arg_pool.from_iter(args.iter().copied());
+        let tys = self.regions.body.type_list(&[]);
+        let value = self
+            .regions
+            .body
+            .add_value(ValueDef::Operator(op, args, tys));
+        let block = self.regions.block;
+        self.regions.body.append_to_block(block, value);
+    }
+
+    /// A value of type `ty` available in the current block, or a new
+    /// constant if there is none (or, sometimes, anyway).
+    fn pick(&mut self, ty: Type) -> Value {
+        let candidates = self.regions.candidates(ty);
+        if candidates.is_empty() || self.rng.below(8) == 0 {
+            let bits = self.rng.next_u64();
+            return self.regions.constant(ty, bits);
+        }
+        candidates[self.rng.below(candidates.len())]
+    }
+}
@@ -3,21 +3,39 @@
 use crate::entity::{EntityRef, PerEntity};
 use crate::ir::*;
 use crate::ops::Operator;
+use crate::passes::memtrace::atomic_access;
+use crate::prelude::*;
 use smallvec::{smallvec, SmallVec};
 
-use std::collections::HashMap;
+use crate::collections::HashMap;
 
+pub(crate) mod diff;
+mod float;
+#[cfg(feature = "std")]
 mod wasi;
 
+pub use diff::{differential_test, DiffOptions};
+
 const WASM_PAGE: usize = 0x1_0000; // 64KiB
 const MAX_PAGES: usize = 2048; // 2048 * 64KiB = 128MiB
 
+/// Runs a call to an imported function, given the memories and the
+/// arguments.
+pub type ImportHandler =
+    Box<dyn FnMut(&mut PerEntity<Memory, InterpMemory>, &[ConstVal]) -> InterpResult + Send>;
+
 pub struct InterpContext {
     pub memories: PerEntity<Memory, InterpMemory>,
     pub tables: PerEntity<Table, InterpTable>,
     pub globals: PerEntity<Global, ConstVal>,
     pub fuel: u64,
     pub trace_handler: Option<Box<dyn Fn(usize, Vec<ConstVal>) -> bool + Send>>,
+    /// Handlers for calls to imports, by module and name. They take
+    /// precedence over WASI.
+    pub import_handlers: HashMap<(String, String), ImportHandler>,
+    /// Whether calls to imports are run as the WASI functions of the
+    /// same name, where supported. WASI needs `std`.
+    pub wasi: bool,
 }
 
 type MultiVal = SmallVec<[ConstVal; 2]>;
@@ -29,6 +47,8 @@ pub enum InterpResult {
     Trap(Func, Block, u32),
     OutOfFuel,
     TraceHandlerQuit,
+    /// A call to an imported function that the interpreter cannot run.
+    UnsupportedImport(Func),
 }
 
 impl InterpResult {
@@ -44,48 +64,87 @@ impl InterpContext {
     pub fn new(module: &Module<'_>) -> anyhow::Result<Self> {
         let mut memories = PerEntity::default();
         for (memory, data) in module.memories.entries() {
-            let mut interp_mem = InterpMemory {
+            memories[memory] = InterpMemory {
                 data: vec![0; data.initial_pages * WASM_PAGE],
                 max_pages: data.maximum_pages.unwrap_or(MAX_PAGES),
+                shared: data.shared,
             };
-            for segment in &data.segments {
-                let end = match segment.offset.checked_add(segment.data.len()) {
-                    Some(end) => end,
-                    None => anyhow::bail!("Data segment offset + length overflows"),
-                };
-                if end > interp_mem.data.len() {
-                    anyhow::bail!("Data segment out of bounds");
-                }
-                interp_mem.data[segment.offset..end].copy_from_slice(&segment.data[..]);
+        }
+        for segment in &module.data_segments {
+            let (memory, offset) = match segment.kind {
+                DataSegmentKind::Active { memory, offset } => (memory, offset),
+                DataSegmentKind::Passive => continue,
+            };
+            let offset = module.segment_offset(offset).unwrap_or(0);
+            let interp_mem = &mut memories[memory];
+            let end = match offset.checked_add(segment.data.len()) {
+                Some(end) => end,
+                None => anyhow::bail!("Data segment offset + length overflows"),
+            };
+            if end > interp_mem.data.len() {
+                anyhow::bail!("Data segment out of bounds");
             }
-            memories[memory] = interp_mem;
+            interp_mem.data[offset..end].copy_from_slice(&segment.data[..]);
         }
 
         let mut tables = PerEntity::default();
         for (table, data) in module.tables.entries() {
+            let len = data.func_elements.as_ref().map_or(0, |elts| elts.len());
             let interp_table = InterpTable {
-                elements: data.func_elements.clone().unwrap_or(vec![]),
+                elements: vec![Func::invalid(); len],
             };
             tables[table] = interp_table;
         }
 
         let mut globals = PerEntity::default();
         for (global, data) in module.globals.entries() {
+            let value = module.global_value(global).unwrap_or(0);
             globals[global] = match data.ty {
-                Type::I32 => ConstVal::I32(data.value.unwrap_or(0) as u32),
-                Type::I64 => ConstVal::I64(data.value.unwrap_or(0)),
-                Type::F32 => ConstVal::F32(data.value.unwrap_or(0) as u32),
-                Type::F64 => ConstVal::F64(data.value.unwrap_or(0)),
+                Type::I32 => ConstVal::I32(value as u32),
+                Type::I64 => ConstVal::I64(value),
+                Type::F32 => ConstVal::F32(value as u32),
+                Type::F64 => ConstVal::F64(value),
                 _ => unimplemented!(),
             };
         }
 
+        for segment in &module.elem_segments {
+            let (table, offset) = match segment.kind {
+                ElementSegmentKind::Active { table, offset } => (table, offset),
+                ElementSegmentKind::Passive | ElementSegmentKind::Declared => continue,
+            };
+            let offset = module.segment_offset(offset).unwrap_or(0);
+            let items: Vec<Func> = match &segment.items {
+                ElementItems::Functions(funcs) => funcs.clone(),
+                ElementItems::Expressions(exprs) => exprs
+                    .iter()
+                    .map(|func| func.unwrap_or(Func::invalid()))
+                    .collect(),
+            };
+            let elements = &mut tables[table].elements;
+            let end = match offset.checked_add(items.len()) {
+                Some(end) if end <= elements.len() => end,
+                _ => anyhow::bail!("Element segment out of bounds"),
+            };
+            elements[offset..end].copy_from_slice(&items[..]);
+        }
+        // The tables' contents are written after `elem_segments`.
+        for (table, data) in module.tables.entries() {
+            for (i, &func) in data.func_elements.iter().flatten().enumerate() {
+                if func.is_valid() {
+                    tables[table].elements[i] = func;
+                }
+            }
+        }
+
         Ok(InterpContext {
             memories,
             tables,
             globals,
             fuel: u64::MAX,
             trace_handler: None,
+            import_handlers: HashMap::new(),
+            wasi: true,
         })
     }
 
@@ -96,7 +155,7 @@ impl InterpContext {
             FuncDecl::Import(..) => {
                 let import = &module.imports[func.index()];
                 assert_eq!(import.kind, ImportKind::Func(func));
-                return self.call_import(&import.name[..], args);
+                return self.call_import(func, import, args);
             }
             FuncDecl::Body(_, _, body) => body,
             FuncDecl::None => panic!("FuncDecl::None in call()"),
@@ -279,11 +338,31 @@ impl InterpContext {
         }
     }
 
-    fn call_import(&mut self, name: &str, args: &[ConstVal]) -> InterpResult {
-        if let Some(ret) = wasi::call_wasi(&mut self.memories[Memory::from(0)], name, args) {
-            return ret;
+    /// Run calls to the import `module`.`name` with `handler`.
+    pub fn set_import_handler<F>(&mut self, module: &str, name: &str, handler: F)
+    where
+        F: FnMut(&mut PerEntity<Memory, InterpMemory>, &[ConstVal]) -> InterpResult
+            + Send
+            + 'static,
+    {
+        self.import_handlers
+            .insert((module.to_owned(), name.to_owned()), Box::new(handler));
+    }
+
+    fn call_import(&mut self, func: Func, import: &Import, args: &[ConstVal]) -> InterpResult {
+        let key = (import.module.clone(), import.name.clone());
+        if let Some(handler) = self.import_handlers.get_mut(&key) {
+            return handler(&mut self.memories, args);
+        }
+        let name = &import.name[..];
+        #[cfg(feature = "std")]
+        if self.wasi {
+            if let Some(ret) = wasi::call_wasi(&mut self.memories[Memory::from(0)], name, args) {
+                return ret;
+            }
         }
-        panic!("Unknown import: {} with args: {:?}", name, args);
+        log::trace!("Unsupported import: {} with args: {:?}", name, args);
+        InterpResult::UnsupportedImport(func)
     }
 }
 
@@ -323,6 +402,8 @@ impl InterpStackFrame {
 pub struct InterpMemory {
     pub data: Vec<u8>,
     pub max_pages: usize,
+    /// Whether `memory.atomic.wait` may wait on it.
+    pub shared: bool,
 }
 
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
@@ -619,40 +700,19 @@ pub fn const_eval(
             Some(ConstVal::F32((-f32::from_bits(*a)).to_bits()))
         }
         (Operator::F32Ceil, [ConstVal::F32(a)]) => {
-            Some(ConstVal::F32(f32::from_bits(*a).ceil().to_bits()))
-        }
-        (Operator::F32Floor, [ConstVal::F32(a)]) => {
-            Some(ConstVal::F32(f32::from_bits(*a).floor().to_bits()))
-        }
-        (Operator::F32Trunc, [ConstVal::F32(a)]) => {
-            Some(ConstVal::F32(f32::from_bits(*a).trunc().to_bits()))
-        }
-        (Operator::F32Nearest, [ConstVal::F32(a)]) => {
-            // See
-            // https://github.com/paritytech/wasmi/blob/43ce25d47e26498b9372369345e75dc9632eca8f/crates/core/src/value.rs#L662
-            // for the origin of this algorithm.
-            //
-            // When https://github.com/rust-lang/rust/pull/95317 is
-            // resolved and the resulting API is stable, we can switch
-            // to that instead.
-            let a = f32::from_bits(*a);
-            let round = a.round();
-            let nearest = if a.fract().abs() != 0.5 {
-                round
-            } else {
-                let rem = round % 2.0;
-                if rem == 1.0 {
-                    a.floor()
-                } else if rem == -1.0 {
-                    a.ceil()
-                } else {
-                    round
-                }
-            };
-            Some(ConstVal::F32(nearest.to_bits()))
+            Some(ConstVal::F32(float::f32_ceil(f32::from_bits(*a)).to_bits()))
         }
+        (Operator::F32Floor, [ConstVal::F32(a)]) => Some(ConstVal::F32(
+            float::f32_floor(f32::from_bits(*a)).to_bits(),
+        )),
+        (Operator::F32Trunc, [ConstVal::F32(a)]) => Some(ConstVal::F32(
+            float::f32_trunc(f32::from_bits(*a)).to_bits(),
+        )),
+        (Operator::F32Nearest, [ConstVal::F32(a)]) => Some(ConstVal::F32(
+            float::f32_nearest(f32::from_bits(*a)).to_bits(),
+        )),
         (Operator::F32Sqrt, [ConstVal::F32(a)]) => {
-            Some(ConstVal::F32(f32::from_bits(*a).sqrt().to_bits()))
+            Some(ConstVal::F32(float::f32_sqrt(f32::from_bits(*a)).to_bits()))
         }
         (Operator::F32Add, [ConstVal::F32(a), ConstVal::F32(b)]) => Some(ConstVal::F32(
             (f32::from_bits(*a) + f32::from_bits(*b)).to_bits(),
@@ -683,33 +743,19 @@ pub fn const_eval(
             Some(ConstVal::F64((-f64::from_bits(*a)).to_bits()))
         }
         (Operator::F64Ceil, [ConstVal::F64(a)]) => {
-            Some(ConstVal::F64(f64::from_bits(*a).ceil().to_bits()))
-        }
-        (Operator::F64Floor, [ConstVal::F64(a)]) => {
-            Some(ConstVal::F64(f64::from_bits(*a).floor().to_bits()))
-        }
-        (Operator::F64Trunc, [ConstVal::F64(a)]) => {
-            Some(ConstVal::F64(f64::from_bits(*a).trunc().to_bits()))
-        }
-        (Operator::F64Nearest, [ConstVal::F64(a)]) => {
-            let a = f64::from_bits(*a);
-            let round = a.round();
-            let nearest = if a.fract().abs() != 0.5 {
-                round
-            } else {
-                let rem = round % 2.0;
-                if rem == 1.0 {
-                    a.floor()
-                } else if rem == -1.0 {
-                    a.ceil()
-                } else {
-                    round
-                }
-            };
-            Some(ConstVal::F64(nearest.to_bits()))
+            Some(ConstVal::F64(float::f64_ceil(f64::from_bits(*a)).to_bits()))
         }
+        (Operator::F64Floor, [ConstVal::F64(a)]) => Some(ConstVal::F64(
+            float::f64_floor(f64::from_bits(*a)).to_bits(),
+        )),
+        (Operator::F64Trunc, [ConstVal::F64(a)]) => Some(ConstVal::F64(
+            float::f64_trunc(f64::from_bits(*a)).to_bits(),
+        )),
+        (Operator::F64Nearest, [ConstVal::F64(a)]) => Some(ConstVal::F64(
+            float::f64_nearest(f64::from_bits(*a)).to_bits(),
+        )),
         (Operator::F64Sqrt, [ConstVal::F64(a)]) => {
-            Some(ConstVal::F64(f64::from_bits(*a).sqrt().to_bits()))
+            Some(ConstVal::F64(float::f64_sqrt(f64::from_bits(*a)).to_bits()))
         }
         (Operator::F64Add, [ConstVal::F64(a), ConstVal::F64(b)]) => Some(ConstVal::F64(
             (f64::from_bits(*a) + f64::from_bits(*b)).to_bits(),
@@ -1171,6 +1217,8 @@ pub fn const_eval(
                 write_u64(&mut global.memories[memory.memory], addr, *data);
                 Some(ConstVal::None)
             }),
+        (op, args) if op.is_atomic() => ctx.and_then(|global| atomic_eval(op, args, global)),
+
         (_, args) if args.iter().any(|&arg| arg == ConstVal::None) => None,
         (op, args) => unimplemented!(
             "Undefined operator or arg combination: {:?}, {:?}",
@@ -1180,29 +1228,200 @@ pub fn const_eval(
     }
 }
 
+/// Evaluate an atomic operator. With a single thread, atomic accesses
+/// act as plain ones that also trap unless aligned, no one waits to be
+/// notified, and a wait that would block times out at once; without a
+/// timeout it would block forever, which is reported as a trap.
+fn atomic_eval(op: &Operator, args: &[ConstVal], global: &mut InterpContext) -> Option<ConstVal> {
+    let (memory, size) = match atomic_access(op) {
+        Some(access) => access,
+        None => return Some(ConstVal::None),
+    };
+    let addr = args[0].as_u32()?.checked_add(memory.offset)?;
+    let mem = &mut global.memories[memory.memory];
+    if addr % size != 0 || addr.checked_add(size)? > mem.data.len() as u32 {
+        return None;
+    }
+    let value = |arg: &ConstVal| match *arg {
+        ConstVal::I32(value) => Some(value as u64),
+        ConstVal::I64(value) => Some(value),
+        _ => None,
+    };
+    let wide = matches!(
+        op,
+        Operator::I64AtomicLoad { .. }
+            | Operator::I64AtomicLoad8U { .. }
+            | Operator::I64AtomicLoad16U { .. }
+            | Operator::I64AtomicLoad32U { .. }
+            | Operator::I64AtomicStore { .. }
+            | Operator::I64AtomicStore8 { .. }
+            | Operator::I64AtomicStore16 { .. }
+            | Operator::I64AtomicStore32 { .. }
+            | Operator::I64AtomicRmwAdd { .. }
+            | Operator::I64AtomicRmw8AddU { .. }
+            | Operator::I64AtomicRmw16AddU { .. }
+            | Operator::I64AtomicRmw32AddU { .. }
+            | Operator::I64AtomicRmwSub { .. }
+            | Operator::I64AtomicRmw8SubU { .. }
+            | Operator::I64AtomicRmw16SubU { .. }
+            | Operator::I64AtomicRmw32SubU { .. }
+            | Operator::I64AtomicRmwAnd { .. }
+            | Operator::I64AtomicRmw8AndU { .. }
+            | Operator::I64AtomicRmw16AndU { .. }
+            | Operator::I64AtomicRmw32AndU { .. }
+            | Operator::I64AtomicRmwOr { .. }
+            | Operator::I64AtomicRmw8OrU { .. }
+            | Operator::I64AtomicRmw16OrU { .. }
+            | Operator::I64AtomicRmw32OrU { .. }
+            | Operator::I64AtomicRmwXor { .. }
+            | Operator::I64AtomicRmw8XorU { .. }
+            | Operator::I64AtomicRmw16XorU { .. }
+            | Operator::I64AtomicRmw32XorU { .. }
+            | Operator::I64AtomicRmwXchg { .. }
+            | Operator::I64AtomicRmw8XchgU { .. }
+            | Operator::I64AtomicRmw16XchgU { .. }
+            | Operator::I64AtomicRmw32XchgU { .. }
+            | Operator::I64AtomicRmwCmpxchg { .. }
+            | Operator::I64AtomicRmw8CmpxchgU { .. }
+            | Operator::I64AtomicRmw16CmpxchgU { .. }
+            | Operator::I64AtomicRmw32CmpxchgU { .. }
+    );
+    let loaded = |value: u64| {
+        if wide {
+            ConstVal::I64(value)
+        } else {
+            ConstVal::I32(value as u32)
+        }
+    };
+    let mask = u64::MAX >> (64 - 8 * size);
+    let old = read_sized(mem, addr, size);
+    let update: fn(u64, u64) -> u64 = match op {
+        Operator::MemoryAtomicNotify { .. } => return Some(ConstVal::I32(0)),
+        Operator::MemoryAtomicWait32 { .. } | Operator::MemoryAtomicWait64 { .. } => {
+            if !mem.shared {
+                return None;
+            }
+            if old != value(&args[1])? & mask {
+                return Some(ConstVal::I32(1));
+            }
+            return match args[2] {
+                ConstVal::I64(timeout) if (timeout as i64) >= 0 => Some(ConstVal::I32(2)),
+                _ => None,
+            };
+        }
+        Operator::I32AtomicLoad { .. }
+        | Operator::I64AtomicLoad { .. }
+        | Operator::I32AtomicLoad8U { .. }
+        | Operator::I32AtomicLoad16U { .. }
+        | Operator::I64AtomicLoad8U { .. }
+        | Operator::I64AtomicLoad16U { .. }
+        | Operator::I64AtomicLoad32U { .. } => return Some(loaded(old)),
+        Operator::I32AtomicStore { .. }
+        | Operator::I64AtomicStore { .. }
+        | Operator::I32AtomicStore8 { .. }
+        | Operator::I32AtomicStore16 { .. }
+        | Operator::I64AtomicStore8 { .. }
+        | Operator::I64AtomicStore16 { .. }
+        | Operator::I64AtomicStore32 { .. } => {
+            write_sized(mem, addr, size, value(&args[1])?);
+            return Some(ConstVal::None);
+        }
+        Operator::I32AtomicRmwCmpxchg { .. }
+        | Operator::I64AtomicRmwCmpxchg { .. }
+        | Operator::I32AtomicRmw8CmpxchgU { .. }
+        | Operator::I32AtomicRmw16CmpxchgU { .. }
+        | Operator::I64AtomicRmw8CmpxchgU { .. }
+        | Operator::I64AtomicRmw16CmpxchgU { .. }
+        | Operator::I64AtomicRmw32CmpxchgU { .. } => {
+            if old == value(&args[1])? & mask {
+                write_sized(mem, addr, size, value(&args[2])?);
+            }
+            return Some(loaded(old));
+        }
+        Operator::I32AtomicRmwAdd { .. }
+        | Operator::I64AtomicRmwAdd { .. }
+        | Operator::I32AtomicRmw8AddU { .. }
+        | Operator::I32AtomicRmw16AddU { .. }
+        | Operator::I64AtomicRmw8AddU { .. }
+        | Operator::I64AtomicRmw16AddU { .. }
+        | Operator::I64AtomicRmw32AddU { .. } => u64::wrapping_add,
+        Operator::I32AtomicRmwSub { .. }
+        | Operator::I64AtomicRmwSub { .. }
+        | Operator::I32AtomicRmw8SubU { .. }
+        | Operator::I32AtomicRmw16SubU { .. }
+        | Operator::I64AtomicRmw8SubU { .. }
+        | Operator::I64AtomicRmw16SubU { .. }
+        | Operator::I64AtomicRmw32SubU { .. } => u64::wrapping_sub,
+        Operator::I32AtomicRmwAnd { .. }
+        | Operator::I64AtomicRmwAnd { .. }
+        | Operator::I32AtomicRmw8AndU { .. }
+        | Operator::I32AtomicRmw16AndU { .. }
+        | Operator::I64AtomicRmw8AndU { .. }
+        | Operator::I64AtomicRmw16AndU { .. }
+        | Operator::I64AtomicRmw32AndU { .. } => |old, value| old & value,
+        Operator::I32AtomicRmwOr { .. }
+        | Operator::I64AtomicRmwOr { .. }
+        | Operator::I32AtomicRmw8OrU { .. }
+        | Operator::I32AtomicRmw16OrU { .. }
+        | Operator::I64AtomicRmw8OrU { .. }
+        | Operator::I64AtomicRmw16OrU { .. }
+        | Operator::I64AtomicRmw32OrU { .. } => |old, value| old | value,
+        Operator::I32AtomicRmwXor { .. }
+        | Operator::I64AtomicRmwXor { .. }
+        | Operator::I32AtomicRmw8XorU { .. }
+        | Operator::I32AtomicRmw16XorU { .. }
+        | Operator::I64AtomicRmw8XorU { .. }
+        | Operator::I64AtomicRmw16XorU { .. }
+        | Operator::I64AtomicRmw32XorU { .. } => |old, value| old ^ value,
+        _ => |_, value| value,
+    };
+    write_sized(mem, addr, size, update(old, value(&args[1])?));
+    Some(loaded(old))
+}
+
 pub(crate) fn read_u8(mem: &InterpMemory, addr: u32) -> u8 {
     let addr = addr as usize;
     mem.data[addr]
 }
 
 pub(crate) fn read_u16(mem: &InterpMemory, addr: u32) -> u16 {
-    use std::convert::TryInto;
+    use core::convert::TryInto;
     let addr = addr as usize;
     u16::from_le_bytes(mem.data[addr..(addr + 2)].try_into().unwrap())
 }
 
 pub(crate) fn read_u32(mem: &InterpMemory, addr: u32) -> u32 {
-    use std::convert::TryInto;
+    use core::convert::TryInto;
     let addr = addr as usize;
     u32::from_le_bytes(mem.data[addr..(addr + 4)].try_into().unwrap())
 }
 
 pub(crate) fn read_u64(mem: &InterpMemory, addr: u32) -> u64 {
-    use std::convert::TryInto;
+    use core::convert::TryInto;
     let addr = addr as usize;
     u64::from_le_bytes(mem.data[addr..(addr + 8)].try_into().unwrap())
 }
 
+/// The `size`-byte little-endian value at `addr`, zero-extended.
+fn read_sized(mem: &InterpMemory, addr: u32, size: u32) -> u64 {
+    match size {
+        1 => read_u8(mem, addr) as u64,
+        2 => read_u16(mem, addr) as u64,
+        4 => read_u32(mem, addr) as u64,
+        _ => read_u64(mem, addr),
+    }
+}
+
+/// Store the low `size` bytes of `data` at `addr`.
+fn write_sized(mem: &mut InterpMemory, addr: u32, size: u32, data: u64) {
+    match size {
+        1 => write_u8(mem, addr, data as u8),
+        2 => write_u16(mem, addr, data as u16),
+        4 => write_u32(mem, addr, data as u32),
+        _ => write_u64(mem, addr, data),
+    }
+}
+
 pub(crate) fn write_u8(mem: &mut InterpMemory, addr: u32, data: u8) {
     let addr = addr as usize;
     mem.data[addr] = data;
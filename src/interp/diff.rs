@@ -0,0 +1,193 @@
+//! Differential testing of transforms by running IR before and after.
+
+use crate::interp::{ConstVal, InterpContext, InterpResult};
+use crate::ir::*;
+use crate::prelude::*;
+use anyhow::Result;
+
+/// How `differential_test` runs a function.
+pub struct DiffOptions {
+    /// The number of calls, each with new arguments.
+    pub runs: usize,
+    /// The seed the arguments are generated from.
+    pub seed: u64,
+    /// The fuel of each call. Calls that run out of fuel on either
+    /// side are not compared.
+    pub fuel: u64,
+    /// Called on each new context before it runs, e.g. to set import
+    /// handlers.
+    pub setup: Box<dyn Fn(&mut InterpContext)>,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            runs: 100,
+            seed: 0x5eed,
+            fuel: 100_000,
+            setup: Box::new(|_| {}),
+        }
+    }
+}
+
+/// Call `func` in `before` and in `after` (typically `before` with a
+/// transform applied, or re-parsed from its Wasm to also test the
+/// backend) with the same generated arguments, each call in a new
+/// context, and bail on the first call whose results, traps, globals or
+/// memories differ. Traps are compared by their occurrence only, and
+/// NaNs compare equal to each other. Returns the number of calls that
+/// were compared: those that neither ran out of fuel nor called an
+/// unsupported import.
+pub fn differential_test(
+    before: &Module<'_>,
+    after: &Module<'_>,
+    func: Func,
+    options: &DiffOptions,
+) -> Result<usize> {
+    let mut before = before.clone();
+    before.expand_all_funcs()?;
+    let mut after = after.clone();
+    after.expand_all_funcs()?;
+    let sig = before.funcs[func].sig();
+    if after.funcs[func].sig() != sig {
+        anyhow::bail!("{} has different signatures before and after", func);
+    }
+    let params = before.signatures[sig].params.clone();
+
+    let mut rng = Rng::new(options.seed);
+    let mut compared = 0;
+    for _ in 0..options.runs {
+        let args = params
+            .iter()
+            .map(|&ty| rng.value(ty))
+            .collect::<Result<Vec<_>>>()?;
+        let run = |module: &Module<'_>| -> Result<(InterpResult, InterpContext)> {
+            let mut ctx = InterpContext::new(module)?;
+            ctx.fuel = options.fuel;
+            (options.setup)(&mut ctx);
+            let result = ctx.call(module, func, &args[..]);
+            Ok((result, ctx))
+        };
+        let (result_before, ctx_before) = run(&before)?;
+        let (result_after, ctx_after) = run(&after)?;
+
+        let same = match (&result_before, &result_after) {
+            (InterpResult::OutOfFuel, _)
+            | (_, InterpResult::OutOfFuel)
+            | (InterpResult::UnsupportedImport(_), _)
+            | (_, InterpResult::UnsupportedImport(_)) => continue,
+            (InterpResult::Ok(a), InterpResult::Ok(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(&a, &b)| same_value(a, b))
+            }
+            (InterpResult::Trap(..), InterpResult::Trap(..))
+            | (InterpResult::Exit, InterpResult::Exit)
+            | (InterpResult::TraceHandlerQuit, InterpResult::TraceHandlerQuit) => true,
+            _ => false,
+        };
+        if !same {
+            anyhow::bail!(
+                "{} called with {:?} gives {:?} before and {:?} after",
+                func,
+                args,
+                result_before,
+                result_after
+            );
+        }
+        for global in before.globals.iter() {
+            let value = ctx_before.globals[global];
+            if !same_value(value, ctx_after.globals[global]) {
+                anyhow::bail!(
+                    "{} called with {:?} leaves {} as {:?} before and {:?} after",
+                    func,
+                    args,
+                    global,
+                    value,
+                    ctx_after.globals[global]
+                );
+            }
+        }
+        for memory in before.memories.iter() {
+            if ctx_before.memories[memory] != ctx_after.memories[memory] {
+                anyhow::bail!(
+                    "{} called with {:?} leaves different contents in {}",
+                    func,
+                    args,
+                    memory
+                );
+            }
+        }
+        compared += 1;
+    }
+    Ok(compared)
+}
+
+pub(crate) fn same_value(a: ConstVal, b: ConstVal) -> bool {
+    match (a, b) {
+        (ConstVal::F32(a), ConstVal::F32(b)) => {
+            a == b || (f32::from_bits(a).is_nan() && f32::from_bits(b).is_nan())
+        }
+        (ConstVal::F64(a), ConstVal::F64(b)) => {
+            a == b || (f64::from_bits(a).is_nan() && f64::from_bits(b).is_nan())
+        }
+        _ => a == b,
+    }
+}
+
+/// An xorshift generator of arguments, half of them edge cases.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng {
+        Rng(seed | 1)
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub(crate) fn pick(&mut self, edges: &[u64]) -> u64 {
+        let bits = self.next();
+        if bits & 1 == 0 {
+            edges[(bits >> 1) as usize % edges.len()]
+        } else {
+            self.next()
+        }
+    }
+
+    pub(crate) fn value(&mut self, ty: Type) -> Result<ConstVal> {
+        Ok(match ty {
+            Type::I32 => {
+                ConstVal::I32(self.pick(&[0, 1, 2, 0xffff_ffff, 0x8000_0000, 0x7fff_ffff]) as u32)
+            }
+            Type::I64 => ConstVal::I64(self.pick(&[
+                0,
+                1,
+                2,
+                u64::MAX,
+                1 << 63,
+                i64::MAX as u64,
+                0xffff_ffff,
+            ])),
+            Type::F32 => ConstVal::F32(self.pick(&[
+                0.0f32.to_bits() as u64,
+                (-0.0f32).to_bits() as u64,
+                1.0f32.to_bits() as u64,
+                f32::NAN.to_bits() as u64,
+                f32::INFINITY.to_bits() as u64,
+                f32::NEG_INFINITY.to_bits() as u64,
+            ]) as u32),
+            Type::F64 => ConstVal::F64(self.pick(&[
+                0.0f64.to_bits(),
+                (-0.0f64).to_bits(),
+                1.0f64.to_bits(),
+                f64::NAN.to_bits(),
+                f64::INFINITY.to_bits(),
+                f64::NEG_INFINITY.to_bits(),
+            ])),
+            _ => anyhow::bail!("Cannot generate arguments of type {}", ty),
+        })
+    }
+}
@@ -0,0 +1,152 @@
+//! Float rounding and square roots. These come from `std`; without it,
+//! they are computed here from the bits. `f32`s go through `f64`, which
+//! is exact for rounding, and for square roots rounds correctly since
+//! `f64` has more than twice the precision.
+
+#[cfg(feature = "std")]
+pub(crate) fn f32_trunc(x: f32) -> f32 {
+    x.trunc()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn f32_floor(x: f32) -> f32 {
+    x.floor()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn f32_ceil(x: f32) -> f32 {
+    x.ceil()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn f32_sqrt(x: f32) -> f32 {
+    x.sqrt()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn f64_trunc(x: f64) -> f64 {
+    x.trunc()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn f64_floor(x: f64) -> f64 {
+    x.floor()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn f64_ceil(x: f64) -> f64 {
+    x.ceil()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn f64_sqrt(x: f64) -> f64 {
+    x.sqrt()
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn f32_trunc(x: f32) -> f32 {
+    f64_trunc(x as f64) as f32
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn f32_floor(x: f32) -> f32 {
+    f64_floor(x as f64) as f32
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn f32_ceil(x: f32) -> f32 {
+    f64_ceil(x as f64) as f32
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn f32_sqrt(x: f32) -> f32 {
+    f64_sqrt(x as f64) as f32
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn f64_trunc(x: f64) -> f64 {
+    let bits = x.to_bits();
+    let exp = ((bits >> 52) & 0x7ff) as i32 - 1023;
+    if exp >= 52 {
+        // Already integral, infinite or NaN.
+        x
+    } else if exp < 0 {
+        f64::from_bits(bits & (1 << 63))
+    } else {
+        f64::from_bits(bits & !((1 << (52 - exp)) - 1))
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn f64_floor(x: f64) -> f64 {
+    let t = f64_trunc(x);
+    if t > x {
+        t - 1.0
+    } else {
+        t
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn f64_ceil(x: f64) -> f64 {
+    let t = f64_trunc(x);
+    if t < x {
+        t + 1.0
+    } else {
+        t
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn f64_sqrt(x: f64) -> f64 {
+    if x.is_nan() || x == 0.0 || x == f64::INFINITY {
+        return x;
+    }
+    if x < 0.0 {
+        return f64::NAN;
+    }
+    // `x` is `mant * 2^exp`, with `mant` normalized to 53 bits.
+    let bits = x.to_bits();
+    let (mut mant, mut exp) = match (bits >> 52) as i32 {
+        0 => {
+            let shift = (bits.leading_zeros() - 11) as i32;
+            (bits << shift, -1074 - shift)
+        }
+        biased => ((bits & ((1 << 52) - 1)) | (1 << 52), biased - 1075),
+    };
+    if exp % 2 != 0 {
+        mant <<= 1;
+        exp -= 1;
+    }
+    // The root of `mant * 2^56` has 55 bits: 53, a rounding bit, and
+    // one that is sticky with the remainder.
+    let n = (mant as u128) << 56;
+    let root = n.isqrt();
+    let sticky = (root & 1) != 0 || root * root != n;
+    let mut q = (root >> 2) as u64;
+    if (root & 2) != 0 && (sticky || (q & 1) != 0) {
+        q += 1;
+    }
+    let mut biased = (exp / 2 + 1049) as u64;
+    if q == 1 << 53 {
+        q >>= 1;
+        biased += 1;
+    }
+    f64::from_bits((biased << 52) | (q & ((1 << 52) - 1)))
+}
+
+/// Round to the nearest integer, with ties to even.
+pub(crate) fn f32_nearest(x: f32) -> f32 {
+    f64_nearest(x as f64) as f32
+}
+
+/// Round to the nearest integer, with ties to even.
+pub(crate) fn f64_nearest(x: f64) -> f64 {
+    let t = f64_trunc(x);
+    let diff = (x - t).abs();
+    if diff > 0.5 || (diff == 0.5 && f64_trunc(t * 0.5) != t * 0.5) {
+        t + f64::copysign(1.0, x)
+    } else {
+        t
+    }
+}
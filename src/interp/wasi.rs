@@ -48,7 +48,7 @@ pub fn call_wasi(mem: &mut InterpMemory, name: &str, args: &[ConstVal]) -> Optio
                     let base = read_u32(mem, iov_entry) as usize;
                     let len = read_u32(mem, iov_entry + 4) as usize;
                     let data = &mem.data[base..(base + len)];
-                    print!("{}", std::str::from_utf8(data).unwrap());
+                    print!("{}", core::str::from_utf8(data).unwrap());
                     written += len;
                 }
                 write_u32(mem, p_nwritten, written as u32);
@@ -11,6 +11,7 @@ pub enum Type {
     V128,
     FuncRef,
 }
+#[cfg(feature = "frontend")]
 impl From<wasmparser::ValType> for Type {
     fn from(ty: wasmparser::ValType) -> Self {
         match ty {
@@ -25,8 +26,8 @@ impl From<wasmparser::ValType> for Type {
     }
 }
 
-impl std::fmt::Display for Type {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for Type {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         let s = match self {
             Type::I32 => "i32",
             Type::I64 => "i64",
@@ -39,6 +40,7 @@ impl std::fmt::Display for Type {
     }
 }
 
+#[cfg(feature = "backend")]
 impl From<Type> for wasm_encoder::ValType {
     fn from(ty: Type) -> wasm_encoder::ValType {
         match ty {
@@ -63,6 +65,12 @@ declare_entity!(Value, "v");
 
 mod module;
 pub use module::*;
+mod edit;
+pub use edit::*;
+mod link;
+pub use link::*;
+mod split;
+pub use split::*;
 mod func;
 pub use func::*;
 mod value;
@@ -71,3 +79,11 @@ mod display;
 pub use display::*;
 mod debug;
 pub use debug::*;
+mod producers;
+pub use producers::*;
+mod const_expr;
+pub use const_expr::*;
+mod dylink;
+pub use dylink::*;
+mod target_features;
+pub use target_features::*;
@@ -0,0 +1,121 @@
+//! Evaluation of constant expressions: global initializers and
+//! segment offsets.
+
+use super::{
+    ElementItems, ElementSegmentKind, Func, Global, GlobalInit, Module, SegmentOffset, Table,
+};
+use crate::entity::EntityRef;
+use crate::ops::Operator;
+use crate::prelude::*;
+use anyhow::Result;
+
+/// Evaluate the constant expression `ops` (without its final `end`) to
+/// the bits of its value, as in `GlobalInit::Const`. Besides constants
+/// it may read globals, whose values `global` gives, and use the
+/// extended-const `add`, `sub` and `mul` operators. Returns `None` if
+/// it reads a global whose value is not known, and fails on operators
+/// not allowed in constant expressions.
+pub fn eval_const_expr<F: Fn(Global) -> Option<u64>>(
+    ops: &[Operator],
+    global: F,
+) -> Result<Option<u64>> {
+    let mut stack: Vec<Option<u64>> = vec![];
+    for op in ops {
+        let value = match op {
+            &Operator::I32Const { value } => Some(value as u64),
+            &Operator::I64Const { value } => Some(value),
+            &Operator::F32Const { value } => Some(value as u64),
+            &Operator::F64Const { value } => Some(value),
+            &Operator::GlobalGet { global_index } => global(global_index),
+            Operator::I32Add
+            | Operator::I32Sub
+            | Operator::I32Mul
+            | Operator::I64Add
+            | Operator::I64Sub
+            | Operator::I64Mul => {
+                let (b, a) = match (stack.pop(), stack.pop()) {
+                    (Some(b), Some(a)) => (b, a),
+                    _ => anyhow::bail!("Stack underflow in constant expression at {}", op),
+                };
+                a.zip(b).map(|(a, b)| match op {
+                    Operator::I32Add => (a as u32).wrapping_add(b as u32) as u64,
+                    Operator::I32Sub => (a as u32).wrapping_sub(b as u32) as u64,
+                    Operator::I32Mul => (a as u32).wrapping_mul(b as u32) as u64,
+                    Operator::I64Add => a.wrapping_add(b),
+                    Operator::I64Sub => a.wrapping_sub(b),
+                    _ => a.wrapping_mul(b),
+                })
+            }
+            op => anyhow::bail!("Unsupported operator in constant expression: {}", op),
+        };
+        stack.push(value);
+    }
+    match &stack[..] {
+        &[value] => Ok(value),
+        _ => anyhow::bail!("Constant expression leaves {} values", stack.len()),
+    }
+}
+
+impl<'a> Module<'a> {
+    /// The initial value of `global`, if known: imported globals, and
+    /// those initialized from them, have none.
+    pub fn global_value(&self, global: Global) -> Option<u64> {
+        match self.globals.get(global)?.init? {
+            GlobalInit::Const(bits) => Some(bits),
+            GlobalInit::GlobalGet(_) => None,
+        }
+    }
+
+    /// Evaluate the constant expression `ops` with the initial values
+    /// of this module's globals; see `eval_const_expr`.
+    pub fn eval_const_expr(&self, ops: &[Operator]) -> Result<Option<u64>> {
+        eval_const_expr(ops, |global| self.global_value(global))
+    }
+
+    /// The offset an active segment is placed at, if known: an offset
+    /// read from an imported global is known only once instantiated.
+    pub fn segment_offset(&self, offset: SegmentOffset) -> Option<usize> {
+        match offset {
+            SegmentOffset::Const(offset) => Some(offset),
+            SegmentOffset::Global(global) => Some(self.global_value(global)? as u32 as usize),
+        }
+    }
+
+    /// The functions in `table` once instantiated: its active segments
+    /// in `elem_segments`, then its `func_elements` written over them,
+    /// with `Func::invalid()` in empty slots. `None` if a segment's
+    /// offset is not known or the segment doesn't fit.
+    pub fn table_contents(&self, table: Table) -> Option<Vec<Func>> {
+        let len = self.tables[table].func_elements.as_ref()?.len();
+        let mut contents = vec![Func::invalid(); len];
+        for segment in &self.elem_segments {
+            let offset = match segment.kind {
+                ElementSegmentKind::Active { table: t, offset } if t == table => {
+                    self.segment_offset(offset)?
+                }
+                _ => continue,
+            };
+            let items: Vec<Func> = match &segment.items {
+                ElementItems::Functions(funcs) => funcs.clone(),
+                ElementItems::Expressions(exprs) => exprs
+                    .iter()
+                    .map(|func| func.unwrap_or(Func::invalid()))
+                    .collect(),
+            };
+            contents
+                .get_mut(offset..offset.checked_add(items.len())?)?
+                .copy_from_slice(&items);
+        }
+        for (slot, &func) in self.tables[table]
+            .func_elements
+            .iter()
+            .flatten()
+            .enumerate()
+        {
+            if func.is_valid() {
+                contents[slot] = func;
+            }
+        }
+        Some(contents)
+    }
+}
@@ -1,10 +1,13 @@
 //! Debug info (currently, source-location maps).
 
+use crate::collections::hash_map::Entry as HashEntry;
+use crate::collections::HashMap;
 use crate::declare_entity;
 use crate::entity::EntityVec;
+use crate::prelude::*;
+#[cfg(feature = "frontend")]
 use addr2line::gimli;
-use std::collections::hash_map::Entry as HashEntry;
-use std::collections::HashMap;
+use alloc::collections::BTreeMap;
 
 declare_entity!(SourceFile, "file");
 declare_entity!(SourceLoc, "loc");
@@ -56,6 +59,7 @@ pub struct DebugMap {
 }
 
 impl DebugMap {
+    #[cfg(feature = "frontend")]
     pub(crate) fn from_dwarf<R: gimli::Reader>(
         dwarf: gimli::Dwarf<R>,
         debug: &mut Debug,
@@ -88,3 +92,25 @@ impl DebugMap {
         })
     }
 }
+
+/// The original module's DWARF (`.debug_*`) sections, kept so that
+/// the backend can rewrite their code addresses to match the emitted
+/// code section.
+#[derive(Clone, Debug, Default)]
+pub struct DwarfSections {
+    /// Offset of the original code section's contents relative to the
+    /// Wasm file start. DWARF code addresses are relative to it.
+    pub code_offset: u32,
+    /// Section contents, keyed by section name.
+    pub sections: BTreeMap<String, Vec<u8>>,
+}
+
+impl DwarfSections {
+    pub fn is_empty(&self) -> bool {
+        self.sections.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.sections.clear();
+    }
+}
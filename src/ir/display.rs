@@ -1,9 +1,15 @@
 //! Displaying IR.
 
-use super::{FuncDecl, FunctionBody, Module, SourceLoc, ValueDef};
+use super::{
+    BlockTarget, DataSegmentKind, ElementSegmentKind, FuncDecl, FunctionBody, Module, SourceLoc,
+    Terminator, ValueDef,
+};
+use crate::cfg::CFGInfo;
+use crate::collections::HashMap;
 use crate::entity::EntityRef;
-use std::collections::HashMap;
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use crate::prelude::*;
+use core::fmt::Write as _;
+use core::fmt::{Display, Formatter, Result as FmtResult};
 
 pub struct FunctionBodyDisplay<'a> {
     pub(crate) body: &'a FunctionBody,
@@ -104,8 +110,7 @@ impl<'a> Display for FunctionBodyDisplay<'a> {
                 f,
                 "{}    # preds: {}",
                 self.indent,
-                block
-                    .preds
+                self.body.edge_pool[block.preds]
                     .iter()
                     .map(|pred| format!("{} ({})", pred, self.body.blocks[*pred].desc))
                     .collect::<Vec<_>>()
@@ -115,13 +120,15 @@ impl<'a> Display for FunctionBodyDisplay<'a> {
                 f,
                 "{}    # succs: {}",
                 self.indent,
-                block
-                    .succs
+                self.body.edge_pool[block.succs]
                     .iter()
                     .map(|succ| format!("{} ({})", succ, self.body.blocks[*succ].desc))
                     .collect::<Vec<_>>()
                     .join(", ")
             )?;
+            if let Some(frequency) = block.frequency {
+                writeln!(f, "{}    # frequency: {}", self.indent, frequency)?;
+            }
             for (_, param) in &block.params {
                 if let Some(local) = self.body.value_locals[*param] {
                     writeln!(f, "{}    # {}: {}", self.indent, param, local)?;
@@ -215,11 +222,10 @@ impl<'a> Display for ModuleDisplay<'a> {
             writeln!(f, "  {}: {}", sig, sig_str)?;
         }
         for (global, global_data) in self.module.globals.entries() {
-            writeln!(
-                f,
-                "  {}: {:?} # {}",
-                global, global_data.value, global_data.ty
-            )?;
+            match &global_data.init {
+                Some(init) => writeln!(f, "  {}: {} # {}", global, init, global_data.ty)?,
+                None => writeln!(f, "  {}: import # {}", global, global_data.ty)?,
+            }
         }
         for (table, table_data) in self.module.tables.entries() {
             writeln!(f, "  {}: {}", table, table_data.ty)?;
@@ -229,20 +235,52 @@ impl<'a> Display for ModuleDisplay<'a> {
                 }
             }
         }
+        for (i, seg) in self.module.elem_segments.iter().enumerate() {
+            let funcs = seg
+                .items
+                .funcs()
+                .map(|func| func.to_string())
+                .collect::<Vec<_>>();
+            match seg.kind {
+                ElementSegmentKind::Active { table, offset } => writeln!(
+                    f,
+                    "  elem{}: {} offset {}: [{}]",
+                    i,
+                    table,
+                    offset,
+                    funcs.join(", ")
+                )?,
+                ElementSegmentKind::Passive => {
+                    writeln!(f, "  elem{}: passive: [{}]", i, funcs.join(", "))?
+                }
+                ElementSegmentKind::Declared => {
+                    writeln!(f, "  elem{}: declared: [{}]", i, funcs.join(", "))?
+                }
+            }
+        }
         for (memory, memory_data) in self.module.memories.entries() {
             writeln!(
                 f,
-                "  {}: initial {} max {:?}",
-                memory, memory_data.initial_pages, memory_data.maximum_pages
+                "  {}: initial {} max {:?}{}",
+                memory,
+                memory_data.initial_pages,
+                memory_data.maximum_pages,
+                if memory_data.shared { " shared" } else { "" }
             )?;
-            for seg in &memory_data.segments {
-                writeln!(
+        }
+        for (i, seg) in self.module.data_segments.iter().enumerate() {
+            match seg.kind {
+                DataSegmentKind::Active { memory, offset } => writeln!(
                     f,
-                    "    {} offset {}: # {} bytes",
+                    "  data{}: {} offset {}: # {} bytes",
+                    i,
                     memory,
-                    seg.offset,
+                    offset,
                     seg.data.len()
-                )?;
+                )?,
+                DataSegmentKind::Passive => {
+                    writeln!(f, "  data{}: passive: # {} bytes", i, seg.data.len())?
+                }
             }
         }
         for import in &self.module.imports {
@@ -264,7 +302,7 @@ impl<'a> Display for ModuleDisplay<'a> {
                         func,
                         name,
                         sig,
-                        sig_strs.get(&sig).unwrap()
+                        sig_strs.get(sig).unwrap()
                     )?;
                     writeln!(f, "{}", body.display("    ", Some(self.module)))?;
                 }
@@ -275,7 +313,7 @@ impl<'a> Display for ModuleDisplay<'a> {
                         func,
                         name,
                         sig,
-                        sig_strs.get(&sig).unwrap()
+                        sig_strs.get(sig).unwrap()
                     )?;
                     writeln!(f, "  # raw bytes (length {})", reader.range().len())?;
                 }
@@ -286,7 +324,7 @@ impl<'a> Display for ModuleDisplay<'a> {
                         func,
                         name,
                         sig,
-                        sig_strs.get(&sig).unwrap()
+                        sig_strs.get(sig).unwrap()
                     )?;
                     writeln!(f, "  # already compiled")?;
                 }
@@ -297,7 +335,7 @@ impl<'a> Display for ModuleDisplay<'a> {
                         func,
                         name,
                         sig,
-                        sig_strs.get(&sig).unwrap()
+                        sig_strs.get(sig).unwrap()
                     )?;
                 }
                 FuncDecl::None => {
@@ -319,3 +357,139 @@ impl<'a> Display for ModuleDisplay<'a> {
         Ok(())
     }
 }
+
+/// Instructions shown in each block of `FunctionBody::to_dot`; the
+/// rest are counted.
+const DOT_MAX_INSTS: usize = 24;
+
+/// Escape `text` for a Graphviz string, ending it with a
+/// left-justified line break.
+fn dot_line(out: &mut String, text: &str) {
+    for c in text.trim_end().chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out.push_str("\\l");
+}
+
+pub(crate) fn body_to_dot(body: &FunctionBody) -> String {
+    let cfg = CFGInfo::new(body);
+    let mut out = String::new();
+    writeln!(out, "digraph {{").unwrap();
+    writeln!(out, "  node [shape=box, fontname=\"monospace\"];").unwrap();
+    for (block, def) in body.blocks.entries() {
+        let params = def
+            .params
+            .iter()
+            .map(|(ty, val)| format!("{}: {}", val, ty))
+            .collect::<Vec<_>>();
+        let mut label = String::new();
+        dot_line(
+            &mut label,
+            &format!("{}({}): {}", block, params.join(", "), def.desc),
+        );
+        if let Some(frequency) = def.frequency {
+            dot_line(&mut label, &format!("# frequency: {}", frequency));
+        }
+        let insts = def
+            .insts
+            .iter()
+            .filter(|&&inst| !matches!(body.values[inst], ValueDef::Alias(_)))
+            .collect::<Vec<_>>();
+        for &&inst in insts.iter().take(DOT_MAX_INSTS) {
+            let text = match &body.values[inst] {
+                ValueDef::Operator(op, args, _) => {
+                    let args = body.arg_pool[*args]
+                        .iter()
+                        .map(|&v| format!("{}", body.resolve_alias(v)))
+                        .collect::<Vec<_>>();
+                    format!("{} = {} {}", inst, op, args.join(", "))
+                }
+                ValueDef::PickOutput(val, idx, _) => format!("{} = {}.{}", inst, val, idx),
+                ValueDef::Trace(id, args) => {
+                    let args = body.arg_pool[*args]
+                        .iter()
+                        .map(|&v| format!("{}", body.resolve_alias(v)))
+                        .collect::<Vec<_>>();
+                    format!("trace {}, {}", id, args.join(", "))
+                }
+                def => format!("{} = {:?}", inst, def),
+            };
+            dot_line(&mut label, &text);
+        }
+        if insts.len() > DOT_MAX_INSTS {
+            dot_line(
+                &mut label,
+                &format!("... ({} more)", insts.len() - DOT_MAX_INSTS),
+            );
+        }
+        let terminator = match &def.terminator {
+            Terminator::Br { .. } => "br".to_owned(),
+            Terminator::CondBr { cond, .. } => format!("if {}", cond),
+            Terminator::Select { value, .. } => format!("select {}", value),
+            terminator => format!("{}", terminator),
+        };
+        dot_line(&mut label, &terminator);
+
+        let mut style = vec![];
+        if block == body.entry {
+            style.push("bold");
+        }
+        if cfg.rpo_pos[block].is_none() {
+            style.push("dashed");
+        }
+        writeln!(
+            out,
+            "  {} [label=\"{}\", style=\"{}\"];",
+            block,
+            label,
+            style.join(",")
+        )
+        .unwrap();
+
+        let mut edge = |target: &BlockTarget, name: String| {
+            let args = target
+                .args
+                .iter()
+                .map(|arg| format!("{}", arg))
+                .collect::<Vec<_>>();
+            let mut label = String::new();
+            match (&name[..], args.is_empty()) {
+                ("", true) => {}
+                (_, true) => dot_line(&mut label, &name),
+                _ => dot_line(&mut label, &format!("{}({})", name, args.join(", "))),
+            }
+            writeln!(
+                out,
+                "  {} -> {} [label=\"{}\"];",
+                block, target.block, label
+            )
+            .unwrap();
+        };
+        match &def.terminator {
+            Terminator::Br { target } => edge(target, String::new()),
+            Terminator::CondBr {
+                if_true, if_false, ..
+            } => {
+                edge(if_true, "true".to_owned());
+                edge(if_false, "false".to_owned());
+            }
+            Terminator::Select {
+                targets, default, ..
+            } => {
+                for (i, target) in targets.iter().enumerate() {
+                    edge(target, i.to_string());
+                }
+                edge(default, "default".to_owned());
+            }
+            _ => {}
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
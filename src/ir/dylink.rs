@@ -0,0 +1,232 @@
+//! The `dylink.0` custom section: the requirements of a shared library
+//! (a module that shares its memory and table with others, as with
+//! Emscripten's dynamic linking).
+
+use super::{CustomSection, CustomSectionPlacement, Module};
+use crate::leb128::{write_u32, Reader};
+use crate::prelude::*;
+use anyhow::Result;
+
+const MEM_INFO: u8 = 1;
+const NEEDED: u8 = 2;
+const EXPORT_INFO: u8 = 3;
+const IMPORT_INFO: u8 = 4;
+const RUNTIME_PATH: u8 = 5;
+
+/// The space a shared library needs in the memory and table it is
+/// loaded into. The library's data and table elements are placed at
+/// the `__memory_base` and `__table_base` it imports.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DylinkMemInfo {
+    pub memory_size: u32,
+    /// Log2 of the required alignment of `__memory_base`.
+    pub memory_alignment: u32,
+    pub table_size: u32,
+    /// Log2 of the required alignment of `__table_base`.
+    pub table_alignment: u32,
+}
+
+/// The parsed contents of a `dylink.0` section.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Dylink {
+    pub mem_info: Option<DylinkMemInfo>,
+    /// Shared libraries this one depends on.
+    pub needed: Vec<String>,
+    /// Symbol flags of exports, by export name.
+    pub export_info: Vec<(String, u32)>,
+    /// Symbol flags of imports, by module and name.
+    pub import_info: Vec<(String, String, u32)>,
+    pub runtime_path: Vec<String>,
+    /// Subsections waffle does not know, by ID, kept as they are.
+    pub other: Vec<(u8, Vec<u8>)>,
+}
+
+impl Dylink {
+    pub const SECTION_NAME: &'static str = "dylink.0";
+
+    pub fn parse(data: &[u8]) -> Result<Dylink> {
+        let mut dylink = Dylink::default();
+        let mut reader = Reader::new(data);
+        while !reader.eof() {
+            let id = reader.read_u8()?;
+            let len = reader.read_var_u32()? as usize;
+            let payload = reader.read_bytes(len)?;
+            let mut sub = Reader::new(payload);
+            match id {
+                MEM_INFO => {
+                    dylink.mem_info = Some(DylinkMemInfo {
+                        memory_size: sub.read_var_u32()?,
+                        memory_alignment: sub.read_var_u32()?,
+                        table_size: sub.read_var_u32()?,
+                        table_alignment: sub.read_var_u32()?,
+                    });
+                }
+                NEEDED | RUNTIME_PATH => {
+                    let mut names = vec![];
+                    for _ in 0..sub.read_var_u32()? {
+                        names.push(sub.read_string()?.to_owned());
+                    }
+                    if id == NEEDED {
+                        dylink.needed = names;
+                    } else {
+                        dylink.runtime_path = names;
+                    }
+                }
+                EXPORT_INFO => {
+                    for _ in 0..sub.read_var_u32()? {
+                        let name = sub.read_string()?.to_owned();
+                        dylink.export_info.push((name, sub.read_var_u32()?));
+                    }
+                }
+                IMPORT_INFO => {
+                    for _ in 0..sub.read_var_u32()? {
+                        let module = sub.read_string()?.to_owned();
+                        let name = sub.read_string()?.to_owned();
+                        dylink.import_info.push((module, name, sub.read_var_u32()?));
+                    }
+                }
+                _ => {
+                    dylink.other.push((id, payload.to_vec()));
+                    continue;
+                }
+            }
+            if !sub.eof() {
+                anyhow::bail!("Trailing bytes in dylink.0 subsection {}", id);
+            }
+        }
+        Ok(dylink)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        fn string(out: &mut Vec<u8>, s: &str) {
+            write_u32(out, s.len() as u32);
+            out.extend_from_slice(s.as_bytes());
+        }
+        fn subsection(out: &mut Vec<u8>, id: u8, payload: &[u8]) {
+            out.push(id);
+            write_u32(out, payload.len() as u32);
+            out.extend_from_slice(payload);
+        }
+        let mut out = vec![];
+        if let Some(info) = &self.mem_info {
+            let mut payload = vec![];
+            write_u32(&mut payload, info.memory_size);
+            write_u32(&mut payload, info.memory_alignment);
+            write_u32(&mut payload, info.table_size);
+            write_u32(&mut payload, info.table_alignment);
+            subsection(&mut out, MEM_INFO, &payload);
+        }
+        for (id, names) in [(NEEDED, &self.needed), (RUNTIME_PATH, &self.runtime_path)] {
+            if !names.is_empty() {
+                let mut payload = vec![];
+                write_u32(&mut payload, names.len() as u32);
+                for name in names {
+                    string(&mut payload, name);
+                }
+                subsection(&mut out, id, &payload);
+            }
+        }
+        if !self.export_info.is_empty() {
+            let mut payload = vec![];
+            write_u32(&mut payload, self.export_info.len() as u32);
+            for (name, flags) in &self.export_info {
+                string(&mut payload, name);
+                write_u32(&mut payload, *flags);
+            }
+            subsection(&mut out, EXPORT_INFO, &payload);
+        }
+        if !self.import_info.is_empty() {
+            let mut payload = vec![];
+            write_u32(&mut payload, self.import_info.len() as u32);
+            for (module, name, flags) in &self.import_info {
+                string(&mut payload, module);
+                string(&mut payload, name);
+                write_u32(&mut payload, *flags);
+            }
+            subsection(&mut out, IMPORT_INFO, &payload);
+        }
+        for (id, payload) in &self.other {
+            subsection(&mut out, *id, payload);
+        }
+        out
+    }
+
+    /// Reserve `size` bytes of the library's memory, aligned to
+    /// `1 << alignment`, returning their offset from `__memory_base`.
+    pub fn reserve_memory(&mut self, size: u32, alignment: u32) -> Result<u32> {
+        let info = self.mem_info.get_or_insert_with(DylinkMemInfo::default);
+        let offset = align_to(info.memory_size, alignment)?;
+        info.memory_size = offset
+            .checked_add(size)
+            .ok_or_else(|| anyhow::anyhow!("Shared library memory size overflows"))?;
+        info.memory_alignment = info.memory_alignment.max(alignment);
+        Ok(offset)
+    }
+
+    /// Reserve `size` slots of the library's table, returning their
+    /// offset from `__table_base`.
+    pub fn reserve_table(&mut self, size: u32) -> Result<u32> {
+        let info = self.mem_info.get_or_insert_with(DylinkMemInfo::default);
+        let offset = info.table_size;
+        info.table_size = offset
+            .checked_add(size)
+            .ok_or_else(|| anyhow::anyhow!("Shared library table size overflows"))?;
+        Ok(offset)
+    }
+}
+
+fn align_to(value: u32, alignment: u32) -> Result<u32> {
+    if alignment >= 32 {
+        anyhow::bail!("Invalid alignment: 2^{}", alignment);
+    }
+    let mask = (1u32 << alignment) - 1;
+    value
+        .checked_add(mask)
+        .map(|value| value & !mask)
+        .ok_or_else(|| anyhow::anyhow!("Shared library memory size overflows"))
+}
+
+impl<'a> Module<'a> {
+    /// The module's `dylink.0` section, if it is a shared library.
+    pub fn dylink(&self) -> Result<Option<Dylink>> {
+        self.custom_section(Dylink::SECTION_NAME)
+            .map(|section| Dylink::parse(&section.data[..]))
+            .transpose()
+    }
+
+    /// Replace the module's `dylink.0` section, or add one. The
+    /// section must come first in the module.
+    pub fn set_dylink(&mut self, dylink: &Dylink) {
+        let data = dylink.encode();
+        match self.custom_section_mut(Dylink::SECTION_NAME) {
+            Some(section) => section.data = data,
+            None => self.custom_sections.insert(
+                0,
+                CustomSection {
+                    name: Dylink::SECTION_NAME.to_owned(),
+                    data,
+                    placement: CustomSectionPlacement::Start,
+                },
+            ),
+        }
+    }
+
+    /// Drop the `dylink.0` symbol flags of imports and exports that no
+    /// longer exist.
+    pub(crate) fn sync_dylink(&mut self) -> Result<()> {
+        let mut dylink = match self.dylink()? {
+            Some(dylink) => dylink,
+            None => return Ok(()),
+        };
+        dylink.import_info.retain(|(module, name, _)| {
+            self.imports
+                .iter()
+                .any(|import| import.module == *module && import.name == *name)
+        });
+        dylink
+            .export_info
+            .retain(|(name, _)| self.exports.iter().any(|export| export.name == *name));
+        self.set_dylink(&dylink);
+        Ok(())
+    }
+}
@@ -0,0 +1,1184 @@
+//! Adding and removing module-level entities, with index remapping.
+
+use super::{
+    DataSegment, DataSegmentKind, ElementSegment, ElementSegmentKind, ExportKind, Func, FuncDecl,
+    FunctionBody, Global, GlobalData, GlobalInit, Import, ImportKind, Memory, Module,
+    SegmentOffset, Signature, SignatureData, Table, TableData, Terminator, Type, ValueDef,
+};
+use crate::analysis::Reachability;
+use crate::entity::{EntityRef, EntityVec};
+use crate::ops::EntityUse;
+use crate::pool::ListRef;
+use crate::prelude::*;
+use crate::Operator;
+use alloc::collections::BTreeSet;
+use anyhow::Result;
+#[cfg(feature = "frontend")]
+use core::convert::TryFrom;
+use core::fmt::Debug;
+
+/// How an index space was renumbered when entities were added or
+/// removed.
+#[derive(Clone, Debug)]
+pub struct IndexMapping<Idx: EntityRef + Debug> {
+    new_index: EntityVec<Idx, Option<Idx>>,
+}
+
+pub type FuncMapping = IndexMapping<Func>;
+pub type GlobalMapping = IndexMapping<Global>;
+pub type MemoryMapping = IndexMapping<Memory>;
+pub type TableMapping = IndexMapping<Table>;
+
+impl<Idx: EntityRef + Debug> IndexMapping<Idx> {
+    /// The new index of the entity that had index `old`, or `None` if
+    /// it was removed.
+    pub fn get(&self, old: Idx) -> Option<Idx> {
+        self.new_index.get(old).copied().flatten()
+    }
+
+    /// Whether any entity that was kept changed its index.
+    pub fn moves_any(&self) -> bool {
+        self.new_index
+            .entries()
+            .any(|(old, &new)| new.is_some_and(|new| new != old))
+    }
+
+    fn inserting(len: usize, at: Idx) -> Self {
+        let new_index = (0..len)
+            .map(|i| Some(Idx::new(if i < at.index() { i } else { i + 1 })))
+            .collect::<Vec<_>>();
+        IndexMapping {
+            new_index: new_index.into(),
+        }
+    }
+
+    fn removing(len: usize, at: Idx) -> Self {
+        let new_index = (0..len)
+            .map(|i| match i.cmp(&at.index()) {
+                core::cmp::Ordering::Less => Some(Idx::new(i)),
+                core::cmp::Ordering::Equal => None,
+                core::cmp::Ordering::Greater => Some(Idx::new(i - 1)),
+            })
+            .collect::<Vec<_>>();
+        IndexMapping {
+            new_index: new_index.into(),
+        }
+    }
+
+    fn retaining(keep: &[bool]) -> Self {
+        let mut next = 0;
+        let new_index = keep
+            .iter()
+            .map(|&keep| {
+                keep.then(|| {
+                    next += 1;
+                    Idx::new(next - 1)
+                })
+            })
+            .collect::<Vec<_>>();
+        IndexMapping {
+            new_index: new_index.into(),
+        }
+    }
+
+    /// `order` lists the old indices in their new order.
+    fn reordering(order: &[Idx]) -> Self {
+        let mut new_index = vec![None; order.len()];
+        for (new, &old) in order.iter().enumerate() {
+            new_index[old.index()] = Some(Idx::new(new));
+        }
+        IndexMapping {
+            new_index: new_index.into(),
+        }
+    }
+
+    fn moving(len: usize, from: Idx, to: Idx) -> Self {
+        let (from, to) = (from.index(), to.index());
+        let new_index = (0..len)
+            .map(|i| {
+                Some(Idx::new(if i == from {
+                    to
+                } else if from < i && i <= to {
+                    i - 1
+                } else if to <= i && i < from {
+                    i + 1
+                } else {
+                    i
+                }))
+            })
+            .collect::<Vec<_>>();
+        IndexMapping {
+            new_index: new_index.into(),
+        }
+    }
+}
+
+/// How all of a module's index spaces were renumbered.
+#[derive(Clone, Debug)]
+pub struct EntityMappings {
+    pub funcs: FuncMapping,
+    pub globals: GlobalMapping,
+    pub tables: TableMapping,
+    pub memories: MemoryMapping,
+}
+
+/// Keep the entities whose entry in `keep` is set.
+fn retain_entities<Idx: EntityRef, T: Clone + Debug>(
+    entities: EntityVec<Idx, T>,
+    keep: &[bool],
+) -> EntityVec<Idx, T> {
+    entities
+        .into_vec()
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, &keep)| keep)
+        .map(|(entity, _)| entity)
+        .collect::<Vec<_>>()
+        .into()
+}
+
+/// The kinds of segment that bodies refer to by index.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SegmentOp {
+    /// `memory.init` and `data.drop`.
+    Data,
+    /// `table.init` and `elem.drop`.
+    Elem,
+}
+
+/// A renumbering of one of the module's index spaces.
+#[derive(Clone, Copy)]
+enum AnyMapping<'m> {
+    Func(&'m FuncMapping),
+    Global(&'m GlobalMapping),
+    Memory(&'m MemoryMapping),
+    Table(&'m TableMapping),
+}
+
+impl<'m> AnyMapping<'m> {
+    fn moves_any(self) -> bool {
+        match self {
+            AnyMapping::Func(m) => m.moves_any(),
+            AnyMapping::Global(m) => m.moves_any(),
+            AnyMapping::Memory(m) => m.moves_any(),
+            AnyMapping::Table(m) => m.moves_any(),
+        }
+    }
+
+    fn map(self, entity: EntityUse) -> EntityUse {
+        match (self, entity) {
+            (AnyMapping::Func(m), EntityUse::Func(f)) => EntityUse::Func(m.get(f).unwrap_or(f)),
+            (AnyMapping::Global(m), EntityUse::Global(g)) => {
+                EntityUse::Global(m.get(g).unwrap_or(g))
+            }
+            (AnyMapping::Memory(m), EntityUse::Memory(mem)) => {
+                EntityUse::Memory(m.get(mem).unwrap_or(mem))
+            }
+            (AnyMapping::Table(m), EntityUse::Table(t)) => EntityUse::Table(m.get(t).unwrap_or(t)),
+            _ => entity,
+        }
+    }
+
+    fn moves(self, entity: EntityUse) -> bool {
+        self.map(entity) != entity
+    }
+
+    fn removes(self, entity: EntityUse) -> bool {
+        match (self, entity) {
+            (AnyMapping::Func(m), EntityUse::Func(f)) => m.get(f).is_none(),
+            (AnyMapping::Global(m), EntityUse::Global(g)) => m.get(g).is_none(),
+            (AnyMapping::Memory(m), EntityUse::Memory(mem)) => m.get(mem).is_none(),
+            (AnyMapping::Table(m), EntityUse::Table(t)) => m.get(t).is_none(),
+            _ => false,
+        }
+    }
+
+    fn func(self, func: &mut Func) {
+        if let EntityUse::Func(new) = self.map(EntityUse::Func(*func)) {
+            *func = new;
+        }
+    }
+
+    fn global(self, global: &mut Global) {
+        if let EntityUse::Global(new) = self.map(EntityUse::Global(*global)) {
+            *global = new;
+        }
+    }
+
+    fn memory(self, memory: &mut Memory) {
+        if let EntityUse::Memory(new) = self.map(EntityUse::Memory(*memory)) {
+            *memory = new;
+        }
+    }
+
+    fn table(self, table: &mut Table) {
+        if let EntityUse::Table(new) = self.map(EntityUse::Table(*table)) {
+            *table = new;
+        }
+    }
+}
+
+/// The operators in a function's body, other than control flow.
+fn body_ops(decl: &FuncDecl<'_>) -> Result<Vec<Operator>> {
+    let mut ops = vec![];
+    match decl {
+        #[cfg(feature = "frontend")]
+        FuncDecl::Lazy(_, _, body) => {
+            for op in body.reader().get_operators_reader()? {
+                if let Ok(op) = Operator::try_from(&op?) {
+                    ops.push(op);
+                }
+            }
+        }
+        #[cfg(not(feature = "frontend"))]
+        FuncDecl::Lazy(_, _, body) => body.unreachable(),
+        FuncDecl::Body(_, _, body) => {
+            for def in body.values.values() {
+                if let ValueDef::Operator(op, ..) = def {
+                    ops.push(*op);
+                }
+            }
+        }
+        FuncDecl::Compiled(..) => {
+            anyhow::bail!("Cannot inspect the body of an already-compiled function")
+        }
+        FuncDecl::Import(..) | FuncDecl::None => {}
+    }
+    Ok(ops)
+}
+
+/// Whether each of the first `len` entities is in `set`.
+fn reached<Idx: EntityRef + Ord>(len: usize, set: &BTreeSet<Idx>) -> Vec<bool> {
+    (0..len)
+        .map(|index| set.contains(&Idx::new(index)))
+        .collect()
+}
+
+/// Module-level entities referred to from a function's body.
+pub(crate) fn body_uses(decl: &FuncDecl<'_>) -> Result<Vec<EntityUse>> {
+    #[cfg(feature = "frontend")]
+    if let FuncDecl::Lazy(_, _, body) = decl {
+        let mut uses = vec![];
+        for op in body.reader().get_operators_reader()? {
+            let op = op?;
+            match Operator::try_from(&op) {
+                Ok(op) => uses.extend(op.entity_use()),
+                Err(()) => uses.extend(raw_entity_uses(&op)),
+            }
+        }
+        return Ok(uses);
+    }
+    Ok(body_ops(decl)?
+        .iter()
+        .filter_map(|op| op.entity_use())
+        .collect())
+}
+
+/// Entities referred to by operators the IR can't represent. Bodies
+/// using them can't be expanded, so they must not be renumbered.
+#[cfg(feature = "frontend")]
+fn raw_entity_uses(op: &wasmparser::Operator<'_>) -> Vec<EntityUse> {
+    use wasmparser::Operator as Op;
+    match *op {
+        Op::RefFunc { function_index } | Op::ReturnCall { function_index } => {
+            vec![EntityUse::Func(Func::from(function_index))]
+        }
+        Op::MemoryInit { mem, .. } | Op::MemoryFill { mem } => {
+            vec![EntityUse::Memory(Memory::from(mem))]
+        }
+        Op::MemoryCopy { dst_mem, src_mem } => vec![
+            EntityUse::Memory(Memory::from(dst_mem)),
+            EntityUse::Memory(Memory::from(src_mem)),
+        ],
+        Op::TableInit { table, .. }
+        | Op::TableFill { table }
+        | Op::ReturnCallIndirect {
+            table_index: table, ..
+        } => vec![EntityUse::Table(Table::from(table))],
+        Op::TableCopy {
+            dst_table,
+            src_table,
+        } => vec![
+            EntityUse::Table(Table::from(dst_table)),
+            EntityUse::Table(Table::from(src_table)),
+        ],
+        _ => vec![],
+    }
+}
+
+impl<'a> Module<'a> {
+    /// Add a function with a body, returning its index. Defined
+    /// functions come after all imports, so no other function is
+    /// renumbered.
+    pub fn add_function(&mut self, sig: Signature, name: &str, body: FunctionBody) -> Func {
+        self.funcs.push(FuncDecl::Body(sig, name.to_owned(), body))
+    }
+
+    /// Add an imported function. Imported functions come before all
+    /// defined functions, so the defined functions are renumbered and
+    /// all references to them are rewritten. Returns the new function
+    /// and the renumbering.
+    pub fn add_func_import(
+        &mut self,
+        module: &str,
+        name: &str,
+        sig: Signature,
+    ) -> Result<(Func, FuncMapping)> {
+        let num_imports = self
+            .funcs
+            .values()
+            .take_while(|decl| matches!(decl, FuncDecl::Import(..)))
+            .count();
+        let func = Func::new(num_imports);
+        let mapping = FuncMapping::inserting(self.funcs.len(), func);
+        self.apply_mapping(AnyMapping::Func(&mapping))?;
+
+        let mut funcs = core::mem::take(&mut self.funcs).into_vec();
+        funcs.insert(num_imports, FuncDecl::Import(sig, name.to_owned()));
+        self.funcs = funcs.into();
+        self.imports.push(Import {
+            module: module.to_owned(),
+            name: name.to_owned(),
+            kind: ImportKind::Func(func),
+        });
+        Ok((func, mapping))
+    }
+
+    /// Remove a function, renumbering the functions after it and
+    /// rewriting all references to them. Fails without modifying
+    /// anything if the function is still called, exported, used as
+    /// the start function or placed in a table.
+    pub fn remove_function(&mut self, func: Func) -> Result<FuncMapping> {
+        if self.funcs.get(func).is_none() {
+            anyhow::bail!("No such function: {}", func);
+        }
+        if self
+            .exports
+            .iter()
+            .any(|export| matches!(export.kind, ExportKind::Func(f) if f == func))
+        {
+            anyhow::bail!("Cannot remove exported function {}", func);
+        }
+        if self.start_func == Some(func) {
+            anyhow::bail!("Cannot remove start function {}", func);
+        }
+        for (table, data) in self.tables.entries() {
+            if let Some(elts) = &data.func_elements {
+                if elts.contains(&func) {
+                    anyhow::bail!("Cannot remove function {} used in {}", func, table);
+                }
+            }
+        }
+        if let Some(i) = self
+            .elem_segments
+            .iter()
+            .position(|segment| segment.items.funcs().any(|f| f == func))
+        {
+            anyhow::bail!(
+                "Cannot remove function {} used in element segment {}",
+                func,
+                i
+            );
+        }
+        for (caller, decl) in self.funcs.entries() {
+            if caller != func && body_uses(decl)?.contains(&EntityUse::Func(func)) {
+                anyhow::bail!("Cannot remove function {} called from {}", func, caller);
+            }
+        }
+
+        let mapping = FuncMapping::removing(self.funcs.len(), func);
+        self.apply_mapping(AnyMapping::Func(&mapping))?;
+
+        let mut funcs = core::mem::take(&mut self.funcs).into_vec();
+        funcs.remove(func.index());
+        self.funcs = funcs.into();
+        Ok(mapping)
+    }
+
+    /// Move the given defined functions, in that order, in front of
+    /// the other defined functions, which keep their relative order.
+    /// This is the order of their bodies in the code section. All
+    /// references to the moved functions are rewritten. Returns the
+    /// renumbering.
+    pub fn reorder_functions(&mut self, order: &[Func]) -> Result<FuncMapping> {
+        let num_imports = self
+            .funcs
+            .values()
+            .take_while(|decl| matches!(decl, FuncDecl::Import(..)))
+            .count();
+        let mut listed = vec![false; self.funcs.len()];
+        for &func in order {
+            if func.index() >= self.funcs.len() {
+                anyhow::bail!("No such function: {}", func);
+            }
+            if func.index() < num_imports {
+                anyhow::bail!("Cannot reorder imported function {}", func);
+            }
+            if core::mem::replace(&mut listed[func.index()], true) {
+                anyhow::bail!("Function {} is listed twice", func);
+            }
+        }
+        let new_order = (0..num_imports)
+            .map(Func::new)
+            .chain(order.iter().copied())
+            .chain(
+                (num_imports..self.funcs.len())
+                    .filter(|&i| !listed[i])
+                    .map(Func::new),
+            )
+            .collect::<Vec<_>>();
+        let mapping = FuncMapping::reordering(&new_order);
+        self.apply_mapping(AnyMapping::Func(&mapping))?;
+
+        let mut funcs = core::mem::take(&mut self.funcs)
+            .into_vec()
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        self.funcs = new_order
+            .iter()
+            .map(|func| funcs[func.index()].take().unwrap())
+            .collect::<Vec<_>>()
+            .into();
+        Ok(mapping)
+    }
+
+    /// Add a global with the given initializer, returning its index.
+    /// Defined globals come after all imports, so no other global is
+    /// renumbered. Fails if the initializer reads a global that is not
+    /// an immutable import of the same type.
+    pub fn add_global(&mut self, ty: Type, mutable: bool, init: GlobalInit) -> Result<Global> {
+        if let GlobalInit::GlobalGet(global) = init {
+            match self.globals.get(global) {
+                None => anyhow::bail!("No such global: {}", global),
+                Some(data) if data.init.is_some() => {
+                    anyhow::bail!("Initializer reads defined global {}", global)
+                }
+                Some(data) if data.mutable || data.ty != ty => {
+                    anyhow::bail!("Initializer reads mismatched global {}", global)
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(self.globals.push(GlobalData {
+            ty,
+            init: Some(init),
+            mutable,
+        }))
+    }
+
+    /// Add an imported global. Imported globals come before all
+    /// defined globals, so the defined globals are renumbered and all
+    /// references to them are rewritten. Returns the new global and
+    /// the renumbering.
+    pub fn add_global_import(
+        &mut self,
+        module: &str,
+        name: &str,
+        ty: Type,
+        mutable: bool,
+    ) -> Result<(Global, GlobalMapping)> {
+        let num_imports = self
+            .imports
+            .iter()
+            .filter(|import| matches!(import.kind, ImportKind::Global(_)))
+            .count();
+        let global = Global::new(num_imports);
+        let mapping = GlobalMapping::inserting(self.globals.len(), global);
+        self.apply_mapping(AnyMapping::Global(&mapping))?;
+
+        let mut globals = core::mem::take(&mut self.globals).into_vec();
+        globals.insert(
+            num_imports,
+            GlobalData {
+                ty,
+                init: None,
+                mutable,
+            },
+        );
+        self.globals = globals.into();
+        self.imports.push(Import {
+            module: module.to_owned(),
+            name: name.to_owned(),
+            kind: ImportKind::Global(global),
+        });
+        Ok((global, mapping))
+    }
+
+    /// Remove a global, renumbering the globals after it and rewriting
+    /// all references to them. Fails without modifying anything if
+    /// the global is still used or exported.
+    pub fn remove_global(&mut self, global: Global) -> Result<GlobalMapping> {
+        if self.globals.get(global).is_none() {
+            anyhow::bail!("No such global: {}", global);
+        }
+        if self
+            .exports
+            .iter()
+            .any(|export| matches!(export.kind, ExportKind::Global(g) if g == global))
+        {
+            anyhow::bail!("Cannot remove exported global {}", global);
+        }
+        if self
+            .spill_config
+            .as_ref()
+            .is_some_and(|config| config.stack_pointer == global)
+        {
+            anyhow::bail!("Cannot remove spill stack pointer {}", global);
+        }
+        if self.data_segments.iter().any(|segment| {
+            matches!(segment.kind, DataSegmentKind::Active { offset: SegmentOffset::Global(g), .. } if g == global)
+        }) {
+            anyhow::bail!("Cannot remove global {} used as a segment offset", global);
+        }
+        if self.elem_segments.iter().any(|segment| {
+            matches!(segment.kind, ElementSegmentKind::Active { offset: SegmentOffset::Global(g), .. } if g == global)
+        }) {
+            anyhow::bail!("Cannot remove global {} used as a segment offset", global);
+        }
+        if let Some(user) = self
+            .globals
+            .entries()
+            .find(|(_, data)| data.init == Some(GlobalInit::GlobalGet(global)))
+            .map(|(user, _)| user)
+        {
+            anyhow::bail!("Cannot remove global {} read by {}", global, user);
+        }
+        for (func, decl) in self.funcs.entries() {
+            if body_uses(decl)?.contains(&EntityUse::Global(global)) {
+                anyhow::bail!("Cannot remove global {} used in {}", global, func);
+            }
+        }
+
+        let mapping = GlobalMapping::removing(self.globals.len(), global);
+        self.apply_mapping(AnyMapping::Global(&mapping))?;
+
+        let mut globals = core::mem::take(&mut self.globals).into_vec();
+        globals.remove(global.index());
+        self.globals = globals.into();
+        Ok(mapping)
+    }
+
+    /// Change whether a global is mutable. Fails if the global is
+    /// imported (its type must match the import), or if it is made
+    /// immutable while some function still sets it.
+    pub fn set_global_mutable(&mut self, global: Global, mutable: bool) -> Result<()> {
+        if self.globals.get(global).is_none() {
+            anyhow::bail!("No such global: {}", global);
+        }
+        if self
+            .imports
+            .iter()
+            .any(|import| import.kind == ImportKind::Global(global))
+        {
+            anyhow::bail!("Cannot change the type of imported global {}", global);
+        }
+        if !mutable {
+            for (func, decl) in self.funcs.entries() {
+                let sets = body_ops(decl)?.into_iter().any(|op| {
+                    matches!(op, Operator::GlobalSet { global_index } if global_index == global)
+                });
+                if sets {
+                    anyhow::bail!("Cannot make global {} immutable: set in {}", global, func);
+                }
+            }
+        }
+        self.globals[global].mutable = mutable;
+        Ok(())
+    }
+
+    /// Change a memory's limits, in pages. Fails if the limits are
+    /// inconsistent or out of range.
+    pub fn set_memory_limits(
+        &mut self,
+        memory: Memory,
+        initial_pages: usize,
+        maximum_pages: Option<usize>,
+    ) -> Result<()> {
+        const MAX_PAGES: usize = 1 << 16;
+        let data = match self.memories.get_mut(memory) {
+            Some(data) => data,
+            None => anyhow::bail!("No such memory: {}", memory),
+        };
+        if initial_pages > MAX_PAGES || maximum_pages.is_some_and(|max| max > MAX_PAGES) {
+            anyhow::bail!("Memory size out of range for {}", memory);
+        }
+        if maximum_pages.is_some_and(|max| max < initial_pages) {
+            anyhow::bail!("Maximum size of {} is below its initial size", memory);
+        }
+        if data.shared && maximum_pages.is_none() {
+            anyhow::bail!("Shared memory {} needs a maximum size", memory);
+        }
+        data.initial_pages = initial_pages;
+        data.maximum_pages = maximum_pages;
+        Ok(())
+    }
+
+    /// Change whether a memory is shared. Shared memories must have a
+    /// maximum size.
+    pub fn set_memory_shared(&mut self, memory: Memory, shared: bool) -> Result<()> {
+        let data = match self.memories.get_mut(memory) {
+            Some(data) => data,
+            None => anyhow::bail!("No such memory: {}", memory),
+        };
+        if shared && data.maximum_pages.is_none() {
+            anyhow::bail!("Shared memory {} needs a maximum size", memory);
+        }
+        data.shared = shared;
+        Ok(())
+    }
+
+    /// Turn an imported memory into one defined by the module, with
+    /// the same limits. Defined memories come after all imports, so
+    /// the memory becomes the first defined one and the memories in
+    /// between are renumbered.
+    pub fn define_imported_memory(&mut self, memory: Memory) -> Result<MemoryMapping> {
+        let import = self
+            .imports
+            .iter()
+            .position(|import| import.kind == ImportKind::Memory(memory))
+            .ok_or_else(|| anyhow::anyhow!("Memory {} is not imported", memory))?;
+        let num_imports = self.num_memory_imports();
+        let mapping =
+            MemoryMapping::moving(self.memories.len(), memory, Memory::new(num_imports - 1));
+        self.apply_mapping(AnyMapping::Memory(&mapping))?;
+
+        self.imports.remove(import);
+        self.move_memory(memory, Memory::new(num_imports - 1));
+        Ok(mapping)
+    }
+
+    /// Turn a memory defined by the module into an import with the
+    /// given name. Imported memories come before all defined ones, so
+    /// the memory becomes the last imported one and the memories in
+    /// between are renumbered. Its data segments are kept.
+    pub fn import_memory(
+        &mut self,
+        memory: Memory,
+        module: &str,
+        name: &str,
+    ) -> Result<MemoryMapping> {
+        let num_imports = self.num_memory_imports();
+        if self.memories.get(memory).is_none() || memory.index() < num_imports {
+            anyhow::bail!("Memory {} is not defined by the module", memory);
+        }
+        let new_memory = Memory::new(num_imports);
+        let mapping = MemoryMapping::moving(self.memories.len(), memory, new_memory);
+        self.apply_mapping(AnyMapping::Memory(&mapping))?;
+
+        self.move_memory(memory, new_memory);
+        self.imports.push(Import {
+            module: module.to_owned(),
+            name: name.to_owned(),
+            kind: ImportKind::Memory(new_memory),
+        });
+        Ok(mapping)
+    }
+
+    fn num_memory_imports(&self) -> usize {
+        self.imports
+            .iter()
+            .filter(|import| matches!(import.kind, ImportKind::Memory(_)))
+            .count()
+    }
+
+    fn move_memory(&mut self, from: Memory, to: Memory) {
+        let mut memories = core::mem::take(&mut self.memories).into_vec();
+        let data = memories.remove(from.index());
+        memories.insert(to.index(), data);
+        self.memories = memories.into();
+    }
+
+    /// Add a table with the given limits, and no elements, returning
+    /// its index. Defined tables come after all imports, so no other
+    /// table is renumbered.
+    pub fn add_table(&mut self, ty: Type, initial: u32, max: Option<u32>) -> Table {
+        let func_elements = if ty == Type::FuncRef {
+            Some(vec![Func::invalid(); initial as usize])
+        } else {
+            None
+        };
+        self.tables.push(TableData {
+            ty,
+            max,
+            func_elements,
+        })
+    }
+
+    /// Add an imported table. Imported tables come before all defined
+    /// tables, so the defined tables are renumbered and all references
+    /// to them are rewritten. Returns the new table and the
+    /// renumbering.
+    pub fn add_table_import(
+        &mut self,
+        module: &str,
+        name: &str,
+        ty: Type,
+        initial: u32,
+        max: Option<u32>,
+    ) -> Result<(Table, TableMapping)> {
+        let num_imports = self
+            .imports
+            .iter()
+            .filter(|import| matches!(import.kind, ImportKind::Table(_)))
+            .count();
+        let table = Table::new(num_imports);
+        let mapping = TableMapping::inserting(self.tables.len(), table);
+        self.apply_mapping(AnyMapping::Table(&mapping))?;
+
+        let func_elements = if ty == Type::FuncRef {
+            Some(vec![Func::invalid(); initial as usize])
+        } else {
+            None
+        };
+        let mut tables = core::mem::take(&mut self.tables).into_vec();
+        tables.insert(
+            num_imports,
+            TableData {
+                ty,
+                max,
+                func_elements,
+            },
+        );
+        self.tables = tables.into();
+        self.imports.push(Import {
+            module: module.to_owned(),
+            name: name.to_owned(),
+            kind: ImportKind::Table(table),
+        });
+        Ok((table, mapping))
+    }
+
+    /// Remove a table, renumbering the tables after it and rewriting
+    /// all references to them. Fails without modifying anything if the
+    /// table is still used or exported.
+    pub fn remove_table(&mut self, table: Table) -> Result<TableMapping> {
+        if self.tables.get(table).is_none() {
+            anyhow::bail!("No such table: {}", table);
+        }
+        if self
+            .exports
+            .iter()
+            .any(|export| matches!(export.kind, ExportKind::Table(t) if t == table))
+        {
+            anyhow::bail!("Cannot remove exported table {}", table);
+        }
+        for (func, decl) in self.funcs.entries() {
+            if body_uses(decl)?.contains(&EntityUse::Table(table)) {
+                anyhow::bail!("Cannot remove table {} used in {}", table, func);
+            }
+        }
+
+        if self.elem_segments.iter().any(|segment| {
+            matches!(segment.kind, ElementSegmentKind::Active { table: t, .. } if t == table)
+        }) {
+            anyhow::bail!("Cannot remove table {} used by element segments", table);
+        }
+
+        let mapping = TableMapping::removing(self.tables.len(), table);
+        self.apply_mapping(AnyMapping::Table(&mapping))?;
+
+        let mut tables = core::mem::take(&mut self.tables).into_vec();
+        tables.remove(table.index());
+        self.tables = tables.into();
+        Ok(mapping)
+    }
+
+    /// Append functions to a `funcref` table, growing it (and its
+    /// minimum size). Returns the index of the first new element.
+    pub fn append_table_elements(&mut self, table: Table, funcs: &[Func]) -> Result<u32> {
+        if let Some(&func) = funcs.iter().find(|&&func| self.funcs.get(func).is_none()) {
+            anyhow::bail!("No such function: {}", func);
+        }
+        let data = match self.tables.get_mut(table) {
+            Some(data) => data,
+            None => anyhow::bail!("No such table: {}", table),
+        };
+        let elts = match &mut data.func_elements {
+            Some(elts) => elts,
+            None => anyhow::bail!("Table {} does not hold functions", table),
+        };
+        let start = elts.len();
+        if data
+            .max
+            .is_some_and(|max| (start + funcs.len()) as u64 > max as u64)
+        {
+            anyhow::bail!("Table {} would exceed its maximum size", table);
+        }
+        elts.extend_from_slice(funcs);
+        Ok(start as u32)
+    }
+
+    /// Change a `funcref` table's limits. Growing the table adds null
+    /// elements; it can't be shrunk past its last non-null element.
+    pub fn set_table_limits(&mut self, table: Table, initial: u32, max: Option<u32>) -> Result<()> {
+        let data = match self.tables.get_mut(table) {
+            Some(data) => data,
+            None => anyhow::bail!("No such table: {}", table),
+        };
+        let elts = match &mut data.func_elements {
+            Some(elts) => elts,
+            None => anyhow::bail!("Table {} does not hold functions", table),
+        };
+        if max.is_some_and(|max| max < initial) {
+            anyhow::bail!("Maximum size of {} is below its initial size", table);
+        }
+        let used = elts
+            .iter()
+            .rposition(|func| func.is_valid())
+            .map_or(0, |last| last + 1);
+        if (initial as usize) < used {
+            anyhow::bail!("Cannot shrink {} below its last element", table);
+        }
+        elts.resize(initial as usize, Func::invalid());
+        data.max = max;
+        Ok(())
+    }
+
+    /// Append a data segment, returning its index.
+    pub fn add_data_segment(&mut self, segment: DataSegment) -> Result<u32> {
+        if let DataSegmentKind::Active { memory, offset } = segment.kind {
+            if self.memories.get(memory).is_none() {
+                anyhow::bail!("No such memory: {}", memory);
+            }
+            if let SegmentOffset::Global(global) = offset {
+                if self.globals.get(global).is_none() {
+                    anyhow::bail!("No such global: {}", global);
+                }
+            }
+        }
+        self.data_segments.push(segment);
+        Ok(self.data_segments.len() as u32 - 1)
+    }
+
+    /// Remove a data segment, returning it. The segments after it are
+    /// renumbered, so this fails if any function body refers to data
+    /// segments by index (with `memory.init` or `data.drop`); waffle
+    /// can only keep such bodies verbatim.
+    pub fn remove_data_segment(&mut self, index: u32) -> Result<DataSegment> {
+        if index as usize >= self.data_segments.len() {
+            anyhow::bail!("No such data segment: {}", index);
+        }
+        if let Some(func) = self.func_with_segment_op(SegmentOp::Data)? {
+            anyhow::bail!("Cannot renumber data segments: {} may refer to them", func);
+        }
+        Ok(self.data_segments.remove(index as usize))
+    }
+
+    /// Append an element segment, returning its index in
+    /// `elem_segments`.
+    pub fn add_elem_segment(&mut self, segment: ElementSegment) -> Result<u32> {
+        if let ElementSegmentKind::Active { table, offset } = segment.kind {
+            match self.tables.get(table) {
+                Some(data) if data.ty == Type::FuncRef => {}
+                Some(_) => anyhow::bail!("Table {} does not hold functions", table),
+                None => anyhow::bail!("No such table: {}", table),
+            }
+            if let SegmentOffset::Global(global) = offset {
+                if self.globals.get(global).is_none() {
+                    anyhow::bail!("No such global: {}", global);
+                }
+            }
+        }
+        if let Some(func) = segment
+            .items
+            .funcs()
+            .find(|&func| self.funcs.get(func).is_none())
+        {
+            anyhow::bail!("No such function: {}", func);
+        }
+        self.elem_segments.push(segment);
+        Ok(self.elem_segments.len() as u32 - 1)
+    }
+
+    /// Remove an element segment from `elem_segments`, returning it.
+    /// Like `remove_data_segment`, this fails if any function body
+    /// refers to element segments by index (with `table.init` or
+    /// `elem.drop`).
+    pub fn remove_elem_segment(&mut self, index: u32) -> Result<ElementSegment> {
+        if index as usize >= self.elem_segments.len() {
+            anyhow::bail!("No such element segment: {}", index);
+        }
+        if let Some(func) = self.func_with_segment_op(SegmentOp::Elem)? {
+            anyhow::bail!(
+                "Cannot renumber element segments: {} may refer to them",
+                func
+            );
+        }
+        Ok(self.elem_segments.remove(index as usize))
+    }
+
+    /// The first function whose body may contain an operator that the
+    /// IR can't represent and that refers to segments of kind `kind`.
+    #[cfg_attr(not(feature = "frontend"), allow(unused_variables))]
+    fn func_with_segment_op(&self, kind: SegmentOp) -> Result<Option<Func>> {
+        for (func, decl) in self.funcs.entries() {
+            match decl {
+                #[cfg(feature = "frontend")]
+                FuncDecl::Lazy(_, _, body) => {
+                    use wasmparser::Operator as Op;
+                    for op in body.reader().get_operators_reader()? {
+                        let op_kind = match op? {
+                            Op::MemoryInit { .. } | Op::DataDrop { .. } => SegmentOp::Data,
+                            Op::TableInit { .. } | Op::ElemDrop { .. } => SegmentOp::Elem,
+                            _ => continue,
+                        };
+                        if op_kind == kind {
+                            return Ok(Some(func));
+                        }
+                    }
+                }
+                FuncDecl::Compiled(..) => return Ok(Some(func)),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// The signature with the given parameters and results, adding it
+    /// if the module has none yet.
+    pub fn find_or_add_signature(&mut self, data: SignatureData) -> Signature {
+        let existing = self
+            .signatures
+            .entries()
+            .find(|(_, existing)| **existing == data)
+            .map(|(sig, _)| sig);
+        existing.unwrap_or_else(|| self.signatures.push(data))
+    }
+
+    /// Set the function that runs when the module is instantiated. It
+    /// must take no parameters and return nothing.
+    pub fn set_start(&mut self, func: Func) -> Result<()> {
+        let decl = match self.funcs.get(func) {
+            Some(decl) => decl,
+            None => anyhow::bail!("No such function: {}", func),
+        };
+        let sig = &self.signatures[decl.sig()];
+        if !sig.params.is_empty() || !sig.returns.is_empty() {
+            anyhow::bail!("Start function {} must not take or return values", func);
+        }
+        self.start_func = Some(func);
+        Ok(())
+    }
+
+    /// Make `func` run when the module is instantiated, before any
+    /// existing start function. If there is one already, a new start
+    /// function is added that calls `func` and then the old one.
+    /// Returns the new start function.
+    pub fn wrap_start(&mut self, func: Func) -> Result<Func> {
+        let old = match self.start_func {
+            Some(old) => old,
+            None => {
+                self.set_start(func)?;
+                return Ok(func);
+            }
+        };
+        let sig = match self.funcs.get(func) {
+            Some(decl) => decl.sig(),
+            None => anyhow::bail!("No such function: {}", func),
+        };
+        if sig != self.funcs[old].sig() {
+            anyhow::bail!("Start function {} must not take or return values", func);
+        }
+
+        let start = self.add_call_sequence(sig, "start", &[func, old]);
+        self.start_func = Some(start);
+        Ok(start)
+    }
+
+    /// Add a function of signature `sig`, which must take no
+    /// parameters and return nothing, that calls each of `callees` in
+    /// turn.
+    pub(crate) fn add_call_sequence(
+        &mut self,
+        sig: Signature,
+        name: &str,
+        callees: &[Func],
+    ) -> Func {
+        let mut body = FunctionBody::new(self, sig);
+        for &callee in callees {
+            let call = body.add_value(ValueDef::Operator(
+                Operator::Call {
+                    function_index: callee,
+                },
+                ListRef::default(),
+                ListRef::default(),
+            ));
+            body.append_to_block(body.entry, call);
+        }
+        body.set_terminator(body.entry, Terminator::Return { values: vec![] });
+        self.add_function(sig, name, body)
+    }
+
+    /// Keep only the exports named in `names`, then remove everything
+    /// that is no longer reachable (see `remove_unreachable`). Fails
+    /// without modifying anything if one of the names isn't exported.
+    pub fn retain_exports(&mut self, names: &[&str]) -> Result<EntityMappings> {
+        if let Some(name) = names
+            .iter()
+            .find(|&&name| !self.exports.iter().any(|export| export.name == name))
+        {
+            anyhow::bail!("No such export: {}", name);
+        }
+        let exports = self.exports.clone();
+        self.exports
+            .retain(|export| names.contains(&&export.name[..]));
+        let result = self.remove_unreachable();
+        if result.is_err() {
+            self.exports = exports;
+        }
+        result
+    }
+
+    /// Remove the functions, globals, tables and memories that can't
+    /// be reached from the exports, the start function, the data and
+    /// element segments or the spill configuration, together with
+    /// their imports (and their `dylink.0` symbol flags). A function
+    /// reaches whatever its body uses, and a table reaches its
+    /// contents. Fails without modifying anything if a reachable
+    /// function is already compiled.
+    pub fn remove_unreachable(&mut self) -> Result<EntityMappings> {
+        let reachable = Reachability::of_module(self)?;
+        let funcs = reached(self.funcs.len(), &reachable.funcs);
+        let globals = reached(self.globals.len(), &reachable.globals);
+        let tables = reached(self.tables.len(), &reachable.tables);
+        let memories = reached(self.memories.len(), &reachable.memories);
+
+        // Work on a copy so that a failure leaves the module as it was.
+        let mut module = self.clone();
+        for (func, decl) in module.funcs.entries_mut() {
+            if !funcs[func.index()] {
+                *decl = FuncDecl::None;
+            }
+        }
+        let mappings = EntityMappings {
+            funcs: FuncMapping::retaining(&funcs),
+            globals: GlobalMapping::retaining(&globals),
+            tables: TableMapping::retaining(&tables),
+            memories: MemoryMapping::retaining(&memories),
+        };
+        module.apply_mapping(AnyMapping::Func(&mappings.funcs))?;
+        module.funcs = retain_entities(module.funcs, &funcs);
+        module.apply_mapping(AnyMapping::Global(&mappings.globals))?;
+        module.globals = retain_entities(module.globals, &globals);
+        module.apply_mapping(AnyMapping::Table(&mappings.tables))?;
+        module.tables = retain_entities(module.tables, &tables);
+        module.apply_mapping(AnyMapping::Memory(&mappings.memories))?;
+        module.memories = retain_entities(module.memories, &memories);
+        module.sync_dylink()?;
+        *self = module;
+        Ok(mappings)
+    }
+
+    /// Rewrite all references to entities according to `mapping`, and
+    /// drop the imports of removed ones, but leave the renumbered
+    /// entities themselves in place.
+    /// Bodies that would be emitted verbatim but refer to a renumbered
+    /// entity are expanded first. Fails without modifying anything if
+    /// some reference can't be rewritten.
+    fn apply_mapping(&mut self, mapping: AnyMapping<'_>) -> Result<()> {
+        #[cfg(feature = "backend")]
+        if mapping.moves_any() {
+            self.compile_cache.clear();
+        }
+        let mut expanded = vec![];
+        for (func, decl) in self.funcs.entries() {
+            match decl {
+                FuncDecl::Lazy(..)
+                    if body_uses(decl)?
+                        .into_iter()
+                        .any(|entity| mapping.moves(entity)) =>
+                {
+                    expanded.push((func, self.clone_and_expand_body(func)?));
+                }
+                FuncDecl::Compiled(..) if mapping.moves_any() => {
+                    anyhow::bail!("Cannot renumber entities: {} is already compiled", func);
+                }
+                _ => {}
+            }
+        }
+
+        for (func, body) in expanded {
+            self.replace_body(func, body);
+        }
+        for decl in self.funcs.values_mut() {
+            if let FuncDecl::Body(_, _, body) = decl {
+                for def in body.values.values_mut() {
+                    if let ValueDef::Operator(op, ..) = def {
+                        op.map_entity_use(|entity| mapping.map(entity));
+                    }
+                }
+            }
+        }
+        self.imports.retain(|import| {
+            let entity = match import.kind {
+                ImportKind::Func(func) => EntityUse::Func(func),
+                ImportKind::Global(global) => EntityUse::Global(global),
+                ImportKind::Table(table) => EntityUse::Table(table),
+                ImportKind::Memory(memory) => EntityUse::Memory(memory),
+            };
+            !mapping.removes(entity)
+        });
+        for import in &mut self.imports {
+            match &mut import.kind {
+                ImportKind::Func(func) => mapping.func(func),
+                ImportKind::Global(global) => mapping.global(global),
+                ImportKind::Memory(memory) => mapping.memory(memory),
+                ImportKind::Table(table) => mapping.table(table),
+            }
+        }
+        for export in &mut self.exports {
+            match &mut export.kind {
+                ExportKind::Func(func) => mapping.func(func),
+                ExportKind::Global(global) => mapping.global(global),
+                ExportKind::Memory(memory) => mapping.memory(memory),
+                ExportKind::Table(table) => mapping.table(table),
+            }
+        }
+        if let Some(func) = &mut self.start_func {
+            mapping.func(func);
+        }
+        for table in self.tables.values_mut() {
+            for func in table.func_elements.iter_mut().flatten() {
+                if func.is_valid() {
+                    mapping.func(func);
+                }
+            }
+        }
+        for data in self.globals.values_mut() {
+            if let Some(GlobalInit::GlobalGet(global)) = &mut data.init {
+                mapping.global(global);
+            }
+        }
+        if let Some(config) = &mut self.spill_config {
+            mapping.global(&mut config.stack_pointer);
+            mapping.memory(&mut config.memory);
+        }
+        for segment in &mut self.data_segments {
+            if let DataSegmentKind::Active { memory, offset } = &mut segment.kind {
+                mapping.memory(memory);
+                if let SegmentOffset::Global(global) = offset {
+                    mapping.global(global);
+                }
+            }
+        }
+        for segment in &mut self.elem_segments {
+            if let ElementSegmentKind::Active { table, offset } = &mut segment.kind {
+                mapping.table(table);
+                if let SegmentOffset::Global(global) = offset {
+                    mapping.global(global);
+                }
+            }
+            for func in segment.items.funcs_mut() {
+                mapping.func(func);
+            }
+        }
+        match mapping {
+            AnyMapping::Func(m) => self.names.remap_funcs(|func| m.get(func)),
+            AnyMapping::Global(m) => self.names.remap_globals(|global| m.get(global)),
+            AnyMapping::Memory(m) => self.names.remap_memories(|memory| m.get(memory)),
+            AnyMapping::Table(m) => self.names.remap_tables(|table| m.get(table)),
+        }
+        Ok(())
+    }
+}
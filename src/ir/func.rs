@@ -1,13 +1,16 @@
 use super::{Block, FunctionBodyDisplay, Local, Module, Signature, Type, Value, ValueDef};
+#[cfg(feature = "backend")]
 use crate::backend::WasmFuncBackend;
 use crate::cfg::CFGInfo;
+use crate::collections::{FxHashMap, HashSet};
 use crate::entity::{EntityRef, EntityVec, PerEntity};
+#[cfg(feature = "frontend")]
 use crate::frontend::parse_body;
 use crate::ir::SourceLoc;
 use crate::pool::{ListPool, ListRef};
+use crate::prelude::*;
 use anyhow::Result;
-use fxhash::FxHashMap;
-use std::collections::HashSet;
+use core::ops::Range;
 
 /// A declaration of a function: there is one `FuncDecl` per `Func`
 /// index.
@@ -16,16 +19,70 @@ pub enum FuncDecl<'a> {
     /// An imported function.
     Import(Signature, String),
     /// An un-expanded body that can be lazily expanded if needed.
-    Lazy(Signature, String, wasmparser::FunctionBody<'a>),
+    Lazy(Signature, String, RawBody<'a>),
     /// A modified or new function body that requires compilation.
     Body(Signature, String, FunctionBody),
     /// A compiled function body (was IR, has been collapsed back to bytecode).
-    Compiled(Signature, String, wasm_encoder::Function),
+    Compiled(Signature, String, CompiledBody),
     /// A placeholder.
     #[default]
     None,
 }
 
+/// The bytecode of a compiled body.
+#[cfg(feature = "backend")]
+pub type CompiledBody = wasm_encoder::Function;
+
+/// Only the backend compiles bodies, so without it there are none.
+#[cfg(not(feature = "backend"))]
+#[derive(Clone, Debug)]
+pub enum CompiledBody {}
+
+/// A function body as it is in the original module's bytes.
+#[derive(Clone, Debug)]
+pub struct RawBody<'a>(RawBodyInner<'a>);
+
+#[cfg(feature = "frontend")]
+type RawBodyInner<'a> = wasmparser::FunctionBody<'a>;
+
+/// Only the frontend makes raw bodies, so without it there are none.
+#[cfg(not(feature = "frontend"))]
+#[derive(Clone, Debug)]
+enum RawBodyInner<'a> {
+    #[allow(dead_code)]
+    Never(core::convert::Infallible, core::marker::PhantomData<&'a ()>),
+}
+
+impl<'a> RawBody<'a> {
+    #[cfg(feature = "frontend")]
+    pub(crate) fn new(reader: wasmparser::FunctionBody<'a>) -> RawBody<'a> {
+        RawBody(reader)
+    }
+
+    #[cfg(feature = "frontend")]
+    pub(crate) fn reader(&self) -> &wasmparser::FunctionBody<'a> {
+        &self.0
+    }
+
+    /// Only the frontend makes raw bodies, so without it code holding
+    /// one can't be reached.
+    #[cfg(not(feature = "frontend"))]
+    pub(crate) fn unreachable(&self) -> ! {
+        match self.0 {
+            RawBodyInner::Never(never, _) => match never {},
+        }
+    }
+
+    /// The range of the body in the original module's bytes, after its
+    /// size prefix.
+    pub fn range(&self) -> Range<usize> {
+        #[cfg(feature = "frontend")]
+        return self.0.range();
+        #[cfg(not(feature = "frontend"))]
+        self.unreachable()
+    }
+}
+
 impl<'a> FuncDecl<'a> {
     pub fn sig(&self) -> Signature {
         match self {
@@ -37,10 +94,14 @@ impl<'a> FuncDecl<'a> {
         }
     }
 
+    /// Parse a lazy body. Only the frontend makes lazy bodies, so
+    /// without it this does nothing.
+    #[cfg_attr(not(feature = "frontend"), allow(unused_variables))]
     pub fn parse(&mut self, module: &Module) -> Result<()> {
         match self {
+            #[cfg(feature = "frontend")]
             FuncDecl::Lazy(sig, name, body) => {
-                let body = parse_body(module, *sig, body)?;
+                let body = parse_body(module, *sig, &mut body.reader().clone())?;
                 *self = FuncDecl::Body(*sig, name.clone(), body);
                 Ok(())
             }
@@ -80,6 +141,16 @@ impl<'a> FuncDecl<'a> {
         }
     }
 
+    /// The range in the original module's bytes of a body that has
+    /// not been parsed yet (after its size prefix), or `None` for
+    /// other kinds of declaration.
+    pub fn code_range(&self) -> Option<Range<usize>> {
+        match self {
+            FuncDecl::Lazy(_, _, reader) => Some(reader.range()),
+            _ => None,
+        }
+    }
+
     pub fn name(&self) -> &str {
         match self {
             FuncDecl::Body(_, name, _)
@@ -130,14 +201,42 @@ pub struct FunctionBody {
     pub type_pool: ListPool<Type>,
     /// Deduplication for type-lists of single types.
     pub single_type_dedup: FxHashMap<Type, ListRef<Type>>,
+    /// Deduplication for type-lists of several types.
+    pub type_list_dedup: FxHashMap<Vec<Type>, ListRef<Type>>,
     /// Pool of values for ValueDefs' arg lists.
     pub arg_pool: ListPool<Value>,
+    /// Pool of blocks for BlockDefs' successor and predecessor lists.
+    pub edge_pool: ListPool<Block>,
+    /// Pool of indices for BlockDefs' edge positions.
+    pub edge_pos_pool: ListPool<usize>,
     /// Blocks in which values are computed. Each may be `Block::invalid()` if not placed.
     pub value_blocks: PerEntity<Value, Block>,
     /// Wasm locals that values correspond to, if any.
     pub value_locals: PerEntity<Value, Option<Local>>,
     /// Debug source locations of each value.
     pub source_locs: PerEntity<Value, SourceLoc>,
+    /// Offset in the original module's bytes of the operator that
+    /// produced each value, if it was parsed from one.
+    pub source_offsets: PerEntity<Value, Option<u32>>,
+    /// Extent of the body (after its size prefix) in the original
+    /// module's bytes, if it was parsed from one.
+    pub source_range: Option<Range<u32>>,
+    /// Hints to the backend's local allocator, if any.
+    pub alloc_hints: PerEntity<Value, Option<AllocHint>>,
+}
+
+/// A hint attached to a value that the backend's local allocator
+/// respects when assigning Wasm locals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AllocHint {
+    /// Prefer to share a local with the given value, if their
+    /// liveranges do not overlap. This is only a preference: if the
+    /// other value's local is not available, any local may be used.
+    ShareWith(Value),
+    /// Place the value in a fresh local that is shared with no other
+    /// value. Code that is patched after compilation can rely on such
+    /// a local holding only this value.
+    Fresh,
 }
 
 impl FunctionBody {
@@ -163,10 +262,16 @@ impl FunctionBody {
             values,
             type_pool: ListPool::default(),
             arg_pool: ListPool::default(),
+            edge_pool: ListPool::default(),
+            edge_pos_pool: ListPool::default(),
             single_type_dedup: FxHashMap::default(),
+            type_list_dedup: FxHashMap::default(),
             value_blocks,
             value_locals: PerEntity::default(),
             source_locs: PerEntity::default(),
+            source_offsets: PerEntity::default(),
+            source_range: None,
+            alloc_hints: PerEntity::default(),
         }
     }
 
@@ -197,20 +302,60 @@ impl FunctionBody {
             .or_insert_with(|| type_pool.single(ty))
     }
 
+    /// The type-list `tys`, shared with every other use of the same
+    /// list in this body.
+    pub fn type_list(&mut self, tys: &[Type]) -> ListRef<Type> {
+        match tys {
+            [] => ListRef::default(),
+            &[ty] => self.single_type_list(ty),
+            _ => {
+                let type_pool = &mut self.type_pool;
+                *self
+                    .type_list_dedup
+                    .entry(tys.to_vec())
+                    .or_insert_with(|| type_pool.from_iter(tys.iter().copied()))
+            }
+        }
+    }
+
+    /// The successors of `block`.
+    pub fn succs(&self, block: Block) -> &[Block] {
+        &self.edge_pool[self.blocks[block].succs]
+    }
+
+    /// The predecessors of `block`.
+    pub fn preds(&self, block: Block) -> &[Block] {
+        &self.edge_pool[self.blocks[block].preds]
+    }
+
+    /// For each successor of `block`, the index of `block` in its
+    /// predecessors.
+    pub fn pos_in_succ_pred(&self, block: Block) -> &[usize] {
+        &self.edge_pos_pool[self.blocks[block].pos_in_succ_pred]
+    }
+
+    /// For each predecessor of `block`, the index of `block` in its
+    /// successors.
+    pub fn pos_in_pred_succ(&self, block: Block) -> &[usize] {
+        &self.edge_pos_pool[self.blocks[block].pos_in_pred_succ]
+    }
+
     pub fn add_edge(&mut self, from: Block, to: Block) {
         let succ_pos = self.blocks[from].succs.len();
         let pred_pos = self.blocks[to].preds.len();
-        self.blocks[from].succs.push(to);
-        self.blocks[to].preds.push(from);
-        self.blocks[from].pos_in_succ_pred.push(pred_pos);
-        self.blocks[to].pos_in_pred_succ.push(succ_pos);
+        self.edge_pool.push(&mut self.blocks[from].succs, to);
+        self.edge_pool.push(&mut self.blocks[to].preds, from);
+        self.edge_pos_pool
+            .push(&mut self.blocks[from].pos_in_succ_pred, pred_pos);
+        self.edge_pos_pool
+            .push(&mut self.blocks[to].pos_in_pred_succ, succ_pos);
         log::trace!("add_edge: from {} to {}", from, to);
     }
 
     pub fn split_edge(&mut self, from: Block, to: Block, succ_idx: usize) -> Block {
-        assert_eq!(self.blocks[from].succs[succ_idx], to);
-        let pred_idx = self.blocks[from].pos_in_succ_pred[succ_idx];
-        assert_eq!(self.blocks[to].preds[pred_idx], from);
+        assert_eq!(self.succs(from)[succ_idx], to);
+        let pred_idx = self.pos_in_succ_pred(from)[succ_idx];
+        assert_eq!(self.preds(to)[pred_idx], from);
 
         // Create the block itself.
         let edge_block = self.add_block();
@@ -236,20 +381,51 @@ impl FunctionBody {
             .update_target(succ_idx, |target| target.block = edge_block);
 
         // Fill in succ/pred links on edge block.
-        self.blocks[edge_block].succs.push(to);
-        self.blocks[edge_block].pos_in_succ_pred.push(pred_idx);
-        self.blocks[edge_block].preds.push(from);
-        self.blocks[edge_block].pos_in_pred_succ.push(succ_idx);
+        let edge = &mut self.blocks[edge_block];
+        self.edge_pool.push(&mut edge.succs, to);
+        self.edge_pos_pool
+            .push(&mut edge.pos_in_succ_pred, pred_idx);
+        self.edge_pool.push(&mut edge.preds, from);
+        self.edge_pos_pool
+            .push(&mut edge.pos_in_pred_succ, succ_idx);
 
         // Update `succs` in `from`, `preds` in `to`.
-        self.blocks[from].succs[succ_idx] = edge_block;
-        self.blocks[from].pos_in_succ_pred[succ_idx] = 0;
-        self.blocks[to].preds[pred_idx] = edge_block;
-        self.blocks[to].pos_in_pred_succ[pred_idx] = 0;
+        let (from, to) = (&self.blocks[from], &self.blocks[to]);
+        self.edge_pool[from.succs][succ_idx] = edge_block;
+        self.edge_pos_pool[from.pos_in_succ_pred][succ_idx] = 0;
+        self.edge_pool[to.preds][pred_idx] = edge_block;
+        self.edge_pos_pool[to.pos_in_pred_succ][pred_idx] = 0;
 
         edge_block
     }
 
+    /// Split `block` before its `at`th instruction: the instructions
+    /// from there on and the terminator move to a new block, which is
+    /// returned. `block` is left without a terminator.
+    pub fn split_block(&mut self, block: Block, at: usize) -> Block {
+        let new_block = self.add_block();
+        let insts = self.blocks[block].insts.split_off(at);
+        for &inst in &insts {
+            self.value_blocks[inst] = new_block;
+        }
+        self.blocks[new_block].insts = insts;
+        self.blocks[new_block].terminator = core::mem::take(&mut self.blocks[block].terminator);
+
+        // The new block takes over the successor edges.
+        let succs = core::mem::take(&mut self.blocks[block].succs);
+        let pos_in_succ_pred = core::mem::take(&mut self.blocks[block].pos_in_succ_pred);
+        for i in 0..succs.len() {
+            let succ = self.edge_pool[succs][i];
+            let pred_idx = self.edge_pos_pool[pos_in_succ_pred][i];
+            let preds = self.blocks[succ].preds;
+            self.edge_pool[preds][pred_idx] = new_block;
+        }
+        self.blocks[new_block].succs = succs;
+        self.blocks[new_block].pos_in_succ_pred = pos_in_succ_pred;
+
+        new_block
+    }
+
     pub fn recompute_edges(&mut self) {
         for block in self.blocks.values_mut() {
             block.preds.clear();
@@ -257,6 +433,8 @@ impl FunctionBody {
             block.pos_in_succ_pred.clear();
             block.pos_in_pred_succ.clear();
         }
+        self.edge_pool.clear();
+        self.edge_pos_pool.clear();
 
         for block in 0..self.blocks.len() {
             let block = Block::new(block);
@@ -323,6 +501,10 @@ impl FunctionBody {
         self.values[value] = ValueDef::BlockParam(block, index as u32, ty);
     }
 
+    pub fn set_alloc_hint(&mut self, value: Value, hint: AllocHint) {
+        self.alloc_hints[value] = Some(hint);
+    }
+
     pub fn mark_value_as_local(&mut self, value: Value, local: Local) {
         self.value_locals[value] = Some(local);
     }
@@ -382,6 +564,13 @@ impl FunctionBody {
         }
     }
 
+    /// A Graphviz graph of the blocks, listing their instructions (up
+    /// to a limit), with edges labelled by branch arguments. The entry
+    /// block is drawn bold and unreachable blocks dashed.
+    pub fn to_dot(&self) -> String {
+        super::display::body_to_dot(self)
+    }
+
     pub fn validate(&self) -> anyhow::Result<()> {
         // Verify that every block's succs are accurate.
         for (block, block_def) in self.blocks.entries() {
@@ -389,12 +578,12 @@ impl FunctionBody {
             block_def
                 .terminator
                 .visit_successors(|succ| actual_succs.push(succ));
-            if &actual_succs[..] != &block_def.succs[..] {
+            if &actual_succs[..] != self.succs(block) {
                 anyhow::bail!(
                     "Incorrect successors on {}: actual {:?}, stored {:?}",
                     block,
                     actual_succs,
-                    block_def.succs
+                    self.succs(block)
                 );
             }
         }
@@ -475,6 +664,7 @@ impl FunctionBody {
         Ok(())
     }
 
+    #[cfg(feature = "backend")]
     pub fn compile(&self) -> Result<wasm_encoder::Function> {
         let backend = WasmFuncBackend::new(self)?;
         backend.compile()
@@ -487,18 +677,23 @@ pub struct BlockDef {
     pub insts: Vec<Value>,
     /// Terminator: branch or return.
     pub terminator: Terminator,
-    /// Successor blocks.
-    pub succs: Vec<Block>,
-    /// For each successor block, our index in its `preds` array.
-    pub pos_in_succ_pred: Vec<usize>,
-    /// Predecessor blocks.
-    pub preds: Vec<Block>,
-    /// For each predecessor block, our index in its `succs` array.
-    pub pos_in_pred_succ: Vec<usize>,
+    /// Successor blocks, in the body's `edge_pool`.
+    pub succs: ListRef<Block>,
+    /// For each successor block, our index in its `preds` array, in
+    /// the body's `edge_pos_pool`.
+    pub pos_in_succ_pred: ListRef<usize>,
+    /// Predecessor blocks, in the body's `edge_pool`.
+    pub preds: ListRef<Block>,
+    /// For each predecessor block, our index in its `succs` array, in
+    /// the body's `edge_pos_pool`.
+    pub pos_in_pred_succ: ListRef<usize>,
     /// Type and Value for each blockparam.
     pub params: Vec<(Type, Value)>,
     /// Descriptive name for the block, if any.
     pub desc: String,
+    /// How many times the block ran in a profile attached with
+    /// `BranchProfile::apply`, if any.
+    pub frequency: Option<u64>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -507,8 +702,8 @@ pub struct BlockTarget {
     pub args: Vec<Value>,
 }
 
-impl std::fmt::Display for BlockTarget {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for BlockTarget {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         let args = self
             .args
             .iter()
@@ -540,14 +735,14 @@ pub enum Terminator {
     None,
 }
 
-impl std::default::Default for Terminator {
+impl core::default::Default for Terminator {
     fn default() -> Self {
         Terminator::None
     }
 }
 
-impl std::fmt::Display for Terminator {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for Terminator {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
             Terminator::None => write!(f, "no_terminator")?,
             Terminator::Br { target } => write!(f, "br {}", target)?,
@@ -0,0 +1,555 @@
+//! Static linking: merging several modules into one.
+
+use super::{
+    DataSegmentKind, ElementSegmentKind, Export, ExportKind, Func, FuncDecl, FunctionBody, Global,
+    GlobalInit, Import, ImportKind, Memory, Module, Producers, SegmentOffset, Signature,
+    SignatureData, SourceLoc, Table, TableData, Value, ValueDef,
+};
+use crate::collections::{HashMap, HashSet};
+use crate::entity::{EntityRef, EntityVec, PerEntity};
+use crate::ops::EntityUse;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// An import that none of the linked modules provides.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnresolvedImport {
+    /// The linked module that imports it.
+    pub importer: String,
+    pub module: String,
+    pub name: String,
+}
+
+/// The result of linking modules together.
+#[derive(Clone, Debug)]
+pub struct Linked {
+    pub module: Module<'static>,
+    /// The imports left in the merged module, once for each linked
+    /// module that imports them. An import that was re-exported is
+    /// reported for the module that imported it first.
+    pub unresolved: Vec<UnresolvedImport>,
+}
+
+fn import_entity(kind: &ImportKind) -> EntityUse {
+    match *kind {
+        ImportKind::Func(func) => EntityUse::Func(func),
+        ImportKind::Global(global) => EntityUse::Global(global),
+        ImportKind::Table(table) => EntityUse::Table(table),
+        ImportKind::Memory(memory) => EntityUse::Memory(memory),
+    }
+}
+
+pub(super) fn export_entity(kind: &ExportKind) -> EntityUse {
+    match *kind {
+        ExportKind::Func(func) => EntityUse::Func(func),
+        ExportKind::Global(global) => EntityUse::Global(global),
+        ExportKind::Table(table) => EntityUse::Table(table),
+        ExportKind::Memory(memory) => EntityUse::Memory(memory),
+    }
+}
+
+/// Where one module's entities went in the merged module.
+#[derive(Default)]
+pub(super) struct Remap {
+    pub(super) entities: HashMap<EntityUse, EntityUse>,
+}
+
+impl Remap {
+    pub(super) fn entity(&self, entity: EntityUse) -> EntityUse {
+        self.entities[&entity]
+    }
+
+    pub(super) fn func(&self, func: Func) -> Func {
+        match self.entity(EntityUse::Func(func)) {
+            EntityUse::Func(func) => func,
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn global(&self, global: Global) -> Global {
+        match self.entity(EntityUse::Global(global)) {
+            EntityUse::Global(global) => global,
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn table(&self, table: Table) -> Table {
+        match self.entity(EntityUse::Table(table)) {
+            EntityUse::Table(table) => table,
+            _ => unreachable!(),
+        }
+    }
+
+    pub(super) fn memory(&self, memory: Memory) -> Memory {
+        match self.entity(EntityUse::Memory(memory)) {
+            EntityUse::Memory(memory) => memory,
+            _ => unreachable!(),
+        }
+    }
+
+    fn offset(&self, offset: SegmentOffset) -> SegmentOffset {
+        match offset {
+            SegmentOffset::Global(global) => SegmentOffset::Global(self.global(global)),
+            offset => offset,
+        }
+    }
+}
+
+struct Linker<'m, 'a> {
+    names: &'m [String],
+    modules: &'m [Module<'a>],
+    imports: Vec<HashMap<EntityUse, &'m Import>>,
+    exports: Vec<HashMap<&'m str, EntityUse>>,
+}
+
+impl<'m, 'a> Linker<'m, 'a> {
+    fn new(names: &'m [String], modules: &'m [Module<'a>]) -> Self {
+        Linker {
+            names,
+            modules,
+            imports: modules
+                .iter()
+                .map(|module| {
+                    module
+                        .imports
+                        .iter()
+                        .map(|import| (import_entity(&import.kind), import))
+                        .collect()
+                })
+                .collect(),
+            exports: modules
+                .iter()
+                .map(|module| {
+                    module
+                        .exports
+                        .iter()
+                        .map(|export| (&export.name[..], export_entity(&export.kind)))
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    fn is_import(&self, module: usize, entity: EntityUse) -> bool {
+        self.imports[module].contains_key(&entity)
+    }
+
+    /// Whether entity `a` of module `i` can stand for entity `b` of
+    /// module `j`.
+    fn same_type(&self, (i, a): (usize, EntityUse), (j, b): (usize, EntityUse)) -> bool {
+        let (m, n) = (&self.modules[i], &self.modules[j]);
+        match (a, b) {
+            (EntityUse::Func(a), EntityUse::Func(b)) => {
+                m.signatures[m.funcs[a].sig()] == n.signatures[n.funcs[b].sig()]
+            }
+            (EntityUse::Global(a), EntityUse::Global(b)) => {
+                let (a, b) = (&m.globals[a], &n.globals[b]);
+                a.ty == b.ty && a.mutable == b.mutable
+            }
+            (EntityUse::Table(a), EntityUse::Table(b)) => m.tables[a].ty == n.tables[b].ty,
+            (EntityUse::Memory(a), EntityUse::Memory(b)) => {
+                m.memories[a].shared == n.memories[b].shared
+            }
+            _ => false,
+        }
+    }
+
+    /// Follow imports of `entity` of module `i` through the linked
+    /// modules' exports, returning the module and entity they end at:
+    /// either a definition or an unresolved import.
+    fn resolve(&self, i: usize, entity: EntityUse) -> Result<(usize, EntityUse)> {
+        let (mut i, mut entity) = (i, entity);
+        let limit: usize = self.imports.iter().map(|imports| imports.len()).sum();
+        for _ in 0..=limit {
+            let import = match self.imports[i].get(&entity) {
+                Some(import) => import,
+                None => return Ok((i, entity)),
+            };
+            let j = match self.names.iter().position(|name| *name == import.module) {
+                Some(j) => j,
+                None => return Ok((i, entity)),
+            };
+            let exported = match self.exports[j].get(&import.name[..]) {
+                Some(&exported) => exported,
+                None => return Ok((i, entity)),
+            };
+            if !self.same_type((i, entity), (j, exported)) {
+                anyhow::bail!(
+                    "Import {}.{} of module {} does not match the type of the export",
+                    import.module,
+                    import.name,
+                    self.names[i]
+                );
+            }
+            i = j;
+            entity = exported;
+        }
+        anyhow::bail!("Import cycle through module {}", self.names[i])
+    }
+}
+
+/// A copy of a table's type and limits, with no elements set.
+pub(super) fn blank_table(data: &TableData) -> TableData {
+    TableData {
+        ty: data.ty,
+        max: data.max,
+        func_elements: data
+            .func_elements
+            .as_ref()
+            .map(|elts| vec![Func::invalid(); elts.len()]),
+    }
+}
+
+/// Add an import of `entity` of `module`, which is imported as
+/// `import`, to `out`.
+fn add_import(
+    out: &mut Module<'static>,
+    module: &Module<'_>,
+    sigs: &EntityVec<Signature, Signature>,
+    entity: EntityUse,
+    import: &Import,
+) -> EntityUse {
+    let (new, kind) = match entity {
+        EntityUse::Func(func) => {
+            let decl = &module.funcs[func];
+            let func = out
+                .funcs
+                .push(FuncDecl::Import(sigs[decl.sig()], decl.name().to_owned()));
+            (EntityUse::Func(func), ImportKind::Func(func))
+        }
+        EntityUse::Global(global) => {
+            let global = out.globals.push(module.globals[global].clone());
+            (EntityUse::Global(global), ImportKind::Global(global))
+        }
+        EntityUse::Table(table) => {
+            let table = out.tables.push(blank_table(&module.tables[table]));
+            (EntityUse::Table(table), ImportKind::Table(table))
+        }
+        EntityUse::Memory(memory) => {
+            let memory = out.memories.push(module.memories[memory].clone());
+            (EntityUse::Memory(memory), ImportKind::Memory(memory))
+        }
+    };
+    out.imports.push(Import {
+        module: import.module.clone(),
+        name: import.name.clone(),
+        kind,
+    });
+    new
+}
+
+/// Rewrite a body moved from `debug`'s module into the module with
+/// debug info `out`.
+pub(super) fn remap_body(
+    body: &mut FunctionBody,
+    remap: &Remap,
+    sigs: &EntityVec<Signature, Signature>,
+    debug: &super::Debug,
+    out: &mut super::Debug,
+) {
+    for value in 0..body.values.len() {
+        let value = Value::new(value);
+        if let ValueDef::Operator(op, ..) = &mut body.values[value] {
+            op.map_entity_use(|entity| remap.entity(entity));
+            if let Operator::CallIndirect { sig_index, .. } = op {
+                *sig_index = sigs[*sig_index];
+            }
+        }
+        let loc = body.source_locs[value];
+        if loc != SourceLoc::invalid() {
+            let data = debug.source_locs[loc];
+            let file = out.intern_file(&debug.source_files[data.file]);
+            body.source_locs[value] = out.intern_loc(file, data.line, data.col);
+        }
+    }
+    // Offsets into the original modules can't be told apart anymore.
+    body.source_offsets = PerEntity::default();
+    body.source_range = None;
+}
+
+/// Link `modules`, each given with the name the others import it by,
+/// into one module.
+///
+/// An import is resolved if it names one of the modules and one of its
+/// exports; it must then have the same type, and references to it are
+/// replaced by references to the exported entity (following that
+/// module's own imports if it re-exports one). The remaining imports
+/// are kept, with identical ones merged, and reported. The merged
+/// module keeps all exports, so their names must not clash (see
+/// `Module::rename_exports`). Start functions run in the order the
+/// modules are given, and tables and memories are initialized as if
+/// the modules were instantiated in that order.
+///
+/// All function bodies are expanded, and none may be kept as its
+/// original bytes (see `Module::set_skip_unsupported`). DWARF sections
+/// can't be merged, so they are dropped; source locations are kept.
+pub fn link(modules: Vec<(String, Module<'_>)>) -> Result<Linked> {
+    let mut names: Vec<String> = vec![];
+    let mut inputs = vec![];
+    for (name, mut module) in modules {
+        if names.contains(&name) {
+            anyhow::bail!("Module name {} is used twice", name);
+        }
+        module.expand_all_funcs()?;
+        if let Some((func, _)) = module
+            .funcs
+            .entries()
+            .find(|(_, decl)| matches!(decl, FuncDecl::Compiled(..)))
+        {
+            anyhow::bail!("Cannot link {}: {} is already compiled", name, func);
+        }
+        // Their indices would need rewriting.
+        if let Some(&func) = module.skipped_funcs().keys().next() {
+            anyhow::bail!(
+                "Cannot link {}: {} is kept as its original bytes",
+                name,
+                func
+            );
+        }
+        names.push(name);
+        inputs.push(module);
+    }
+
+    let mut out = Module::with_orig_bytes(&[]);
+    let sigs: Vec<EntityVec<Signature, Signature>> = inputs
+        .iter()
+        .map(|module| {
+            module
+                .signatures
+                .values()
+                .map(|data| out.find_or_add_signature(data.clone()))
+                .collect::<Vec<_>>()
+                .into()
+        })
+        .collect();
+    let mut remaps: Vec<Remap> = inputs.iter().map(|_| Remap::default()).collect();
+    let mut unresolved = vec![];
+
+    {
+        let linker = Linker::new(&names[..], &inputs[..]);
+
+        // Unresolved imports come first in each index space.
+        let mut merged: HashMap<(&str, &str, core::mem::Discriminant<EntityUse>), _> =
+            HashMap::new();
+        let mut resolved = vec![];
+        for (i, module) in inputs.iter().enumerate() {
+            for import in &module.imports {
+                let entity = import_entity(&import.kind);
+                let (j, target) = linker.resolve(i, entity)?;
+                let target_import = match linker.imports[j].get(&target) {
+                    Some(&target_import) => target_import,
+                    None => {
+                        resolved.push((i, entity, j, target));
+                        continue;
+                    }
+                };
+                let key = (
+                    &target_import.module[..],
+                    &target_import.name[..],
+                    core::mem::discriminant(&target),
+                );
+                let new = match merged.get(&key) {
+                    Some(&(first, new)) => {
+                        if !linker.same_type(first, (j, target)) {
+                            anyhow::bail!(
+                                "Modules import {}.{} with different types",
+                                target_import.module,
+                                target_import.name
+                            );
+                        }
+                        new
+                    }
+                    None => {
+                        let new = add_import(&mut out, &inputs[j], &sigs[j], target, target_import);
+                        merged.insert(key, ((j, target), new));
+                        new
+                    }
+                };
+                remaps[i].entities.insert(entity, new);
+                let report = UnresolvedImport {
+                    importer: names[j].clone(),
+                    module: target_import.module.clone(),
+                    name: target_import.name.clone(),
+                };
+                if !unresolved.contains(&report) {
+                    unresolved.push(report);
+                }
+            }
+        }
+
+        // Then each module's definitions, in order.
+        for (i, module) in inputs.iter().enumerate() {
+            let remap = &mut remaps[i].entities;
+            for func in module.funcs.iter() {
+                if !linker.is_import(i, EntityUse::Func(func)) {
+                    let new = out.funcs.push(FuncDecl::None);
+                    remap.insert(EntityUse::Func(func), EntityUse::Func(new));
+                }
+            }
+            for (global, data) in module.globals.entries() {
+                if !linker.is_import(i, EntityUse::Global(global)) {
+                    let new = out.globals.push(data.clone());
+                    remap.insert(EntityUse::Global(global), EntityUse::Global(new));
+                }
+            }
+            for (table, data) in module.tables.entries() {
+                if !linker.is_import(i, EntityUse::Table(table)) {
+                    let new = out.tables.push(blank_table(data));
+                    remap.insert(EntityUse::Table(table), EntityUse::Table(new));
+                }
+            }
+            for (memory, data) in module.memories.entries() {
+                if !linker.is_import(i, EntityUse::Memory(memory)) {
+                    let new = out.memories.push(data.clone());
+                    remap.insert(EntityUse::Memory(memory), EntityUse::Memory(new));
+                }
+            }
+        }
+
+        for (i, entity, j, target) in resolved {
+            let new = remaps[j].entity(target);
+            remaps[i].entities.insert(entity, new);
+        }
+    }
+
+    let mut export_names = HashSet::new();
+    let mut starts = vec![];
+    let mut producers: Option<Producers> = None;
+    for (i, module) in inputs.into_iter().enumerate() {
+        let (remap, sigs) = (&remaps[i], &sigs[i]);
+
+        for (func, decl) in module.funcs.into_vec().into_iter().enumerate() {
+            if let FuncDecl::Body(sig, name, mut body) = decl {
+                remap_body(&mut body, remap, sigs, &module.debug, &mut out.debug);
+                let new = remap.func(Func::new(func));
+                out.funcs[new] = FuncDecl::Body(sigs[sig], name, body);
+            }
+        }
+
+        for (global, data) in module.globals.entries() {
+            if let Some(GlobalInit::GlobalGet(import)) = data.init {
+                out.globals[remap.global(global)].init =
+                    Some(GlobalInit::GlobalGet(remap.global(import)));
+            }
+        }
+
+        // Each module's table contents are applied over the previous
+        // ones', as on instantiation.
+        for (table, data) in module.tables.entries() {
+            let elts = match &data.func_elements {
+                Some(elts) => elts,
+                None => continue,
+            };
+            let out_elts = out.tables[remap.table(table)]
+                .func_elements
+                .get_or_insert_with(Vec::new);
+            for (index, &func) in elts.iter().enumerate() {
+                if func.is_valid() {
+                    if index >= out_elts.len() {
+                        out_elts.resize(index + 1, Func::invalid());
+                    }
+                    out_elts[index] = remap.func(func);
+                }
+            }
+        }
+
+        for mut segment in module.data_segments {
+            if let DataSegmentKind::Active { memory, offset } = &mut segment.kind {
+                *memory = remap.memory(*memory);
+                *offset = remap.offset(*offset);
+            }
+            out.data_segments.push(segment);
+        }
+        for mut segment in module.elem_segments {
+            if let ElementSegmentKind::Active { table, offset } = &mut segment.kind {
+                *table = remap.table(*table);
+                *offset = remap.offset(*offset);
+            }
+            for func in segment.items.funcs_mut() {
+                *func = remap.func(*func);
+            }
+            out.elem_segments.push(segment);
+        }
+
+        for export in module.exports {
+            if !export_names.insert(export.name.clone()) {
+                anyhow::bail!("Export {} is defined by more than one module", export.name);
+            }
+            let kind = match export.kind {
+                ExportKind::Func(func) => ExportKind::Func(remap.func(func)),
+                ExportKind::Global(global) => ExportKind::Global(remap.global(global)),
+                ExportKind::Table(table) => ExportKind::Table(remap.table(table)),
+                ExportKind::Memory(memory) => ExportKind::Memory(remap.memory(memory)),
+            };
+            out.exports.push(Export {
+                name: export.name,
+                kind,
+            });
+        }
+
+        starts.extend(module.start_func.map(|func| remap.func(func)));
+        if let (None, Some(config)) = (&out.spill_config, &module.spill_config) {
+            let mut config = config.clone();
+            config.stack_pointer = remap.global(config.stack_pointer);
+            config.memory = remap.memory(config.memory);
+            out.spill_config = Some(config);
+        }
+
+        for (func, names) in module.names.locals {
+            out.names.locals.insert(remap.func(func), names);
+        }
+        for (func, names) in module.names.labels {
+            out.names.labels.insert(remap.func(func), names);
+        }
+        for (sig, name) in module.names.types {
+            out.names.types.entry(sigs[sig]).or_insert(name);
+        }
+        for (table, name) in module.names.tables {
+            out.names.tables.entry(remap.table(table)).or_insert(name);
+        }
+        for (memory, name) in module.names.memories {
+            out.names
+                .memories
+                .entry(remap.memory(memory))
+                .or_insert(name);
+        }
+        for (global, name) in module.names.globals {
+            out.names
+                .globals
+                .entry(remap.global(global))
+                .or_insert(name);
+        }
+
+        for section in module.custom_sections {
+            if section.name == Producers::SECTION_NAME {
+                let theirs = Producers::parse(&section.data[..])?;
+                producers
+                    .get_or_insert_with(Producers::default)
+                    .merge(&theirs);
+            } else {
+                out.custom_sections.push(section);
+            }
+        }
+    }
+
+    match starts[..] {
+        [] => {}
+        [start] => out.start_func = Some(start),
+        _ => {
+            let sig = out.find_or_add_signature(SignatureData {
+                params: vec![],
+                returns: vec![],
+            });
+            out.start_func = Some(out.add_call_sequence(sig, "start", &starts));
+        }
+    }
+    if let Some(producers) = producers {
+        out.set_producers(&producers);
+    }
+
+    Ok(Linked {
+        module: out,
+        unresolved,
+    })
+}
@@ -1,9 +1,23 @@
+#[cfg(feature = "frontend")]
+use super::RawBody;
 use super::{Func, FuncDecl, Global, Memory, ModuleDisplay, Signature, Table, Type};
+#[cfg(feature = "backend")]
+use crate::backend;
+use crate::collections::{HashMap, HashSet};
 use crate::entity::{EntityRef, EntityVec};
-use crate::ir::{Debug, DebugMap, FunctionBody};
-use crate::{backend, frontend};
+use crate::errors::{locate, WaffleError};
+#[cfg(feature = "frontend")]
+use crate::frontend;
+use crate::ir::{Debug, DebugMap, DwarfSections, FunctionBody, Producers};
+use crate::prelude::*;
+use crate::sync::Mutex;
+use crate::{Timeline, TimelineSpan};
+use alloc::collections::BTreeMap;
 use anyhow::Result;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
+#[cfg(feature = "frontend")]
 pub use crate::frontend::FrontendOptions;
 
 #[derive(Clone, Debug)]
@@ -16,9 +30,226 @@ pub struct Module<'a> {
     pub imports: Vec<Import>,
     pub exports: Vec<Export>,
     pub memories: EntityVec<Memory, MemoryData>,
+    /// Data segments, in order (their index is what `memory.init` and
+    /// `data.drop` refer to).
+    pub data_segments: Vec<DataSegment>,
+    /// Element segments other than the tables' contents, in order and
+    /// at their original indices. They are emitted before the tables'
+    /// contents, which are written over them.
+    pub elem_segments: Vec<ElementSegment>,
     pub start_func: Option<Func>,
     pub debug: Debug,
     pub debug_map: DebugMap,
+    /// DWARF sections, re-emitted with code addresses remapped.
+    pub dwarf_sections: DwarfSections,
+    /// Contents of the `name` section, other than function names
+    /// (which are kept in each `FuncDecl`).
+    pub names: Names,
+    /// Custom sections other than those waffle interprets itself
+    /// (`name` and DWARF `.debug_*`), in their original order.
+    pub custom_sections: Vec<CustomSection>,
+    /// Where the backend may spill values when a function needs more
+    /// locals than engines accept. If `None`, functions are emitted
+    /// with as many locals as they need.
+    pub spill_config: Option<SpillConfig>,
+    /// How the backend compiles the module.
+    #[cfg(feature = "backend")]
+    pub codegen_options: backend::CodegenOptions,
+    /// Compiled bodies kept for reuse once `set_incremental` is on.
+    #[cfg(feature = "backend")]
+    pub(crate) compile_cache: backend::CompileCache,
+    /// Whether bodies using unsupported features are left unparsed
+    /// rather than failing expansion; see `set_skip_unsupported`.
+    pub(crate) skip_unsupported: bool,
+    /// The bodies left unparsed, and why.
+    pub(crate) skipped: BTreeMap<Func, WaffleError>,
+    /// The segments the module was parsed with, which bodies kept as
+    /// their original bytes refer to by index.
+    pub(crate) orig_segments: SegmentFingerprints,
+    /// Where lifting, the pass manager and the backend record what
+    /// they spend their time on, if anywhere. It is the one in the
+    /// `FrontendOptions` the module was parsed with.
+    pub timeline: Option<Timeline>,
+    pub(crate) warnings: Warnings,
+}
+
+/// The warnings given while parsing, expanding and compiling a module,
+/// each once, in the order they were first given.
+#[derive(Debug, Default)]
+pub(crate) struct Warnings(Mutex<Vec<String>>);
+
+impl Clone for Warnings {
+    fn clone(&self) -> Self {
+        Warnings(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+impl Warnings {
+    /// Log `warning`, and keep it unless it was given before.
+    pub(crate) fn add(&self, warning: String) {
+        log::warn!("{}", warning);
+        let mut warnings = self.0.lock().unwrap();
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+}
+
+/// Hashes of a module's element and data segments, by index.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct SegmentFingerprints {
+    elem: Vec<u64>,
+    data: Vec<u64>,
+}
+
+impl SegmentFingerprints {
+    pub(crate) fn of(module: &Module<'_>) -> SegmentFingerprints {
+        SegmentFingerprints {
+            elem: module
+                .elem_segments
+                .iter()
+                .map(crate::collections::hash64)
+                .collect(),
+            data: module
+                .data_segments
+                .iter()
+                .map(crate::collections::hash64)
+                .collect(),
+        }
+    }
+}
+
+/// Names from the `name` section, keyed by IR entity. Local and label
+/// names are keyed by their index in the original function body.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Names {
+    pub module: Option<String>,
+    pub locals: BTreeMap<Func, BTreeMap<u32, String>>,
+    /// Label names. These are only meaningful for function bodies that
+    /// are emitted unchanged, and are dropped for recompiled ones.
+    pub labels: BTreeMap<Func, BTreeMap<u32, String>>,
+    pub types: BTreeMap<Signature, String>,
+    pub tables: BTreeMap<Table, String>,
+    pub memories: BTreeMap<Memory, String>,
+    pub globals: BTreeMap<Global, String>,
+}
+
+fn remap_keys<K: Ord + Copy, V, F: Fn(K) -> Option<K>>(map: &mut BTreeMap<K, V>, f: F) {
+    *map = core::mem::take(map)
+        .into_iter()
+        .filter_map(|(k, v)| f(k).map(|k| (k, v)))
+        .collect();
+}
+
+impl Names {
+    /// Rekey names after functions have been renumbered; `f` maps each
+    /// old index to its new one, or `None` if it was removed.
+    pub fn remap_funcs<F: Fn(Func) -> Option<Func>>(&mut self, f: F) {
+        remap_keys(&mut self.locals, &f);
+        remap_keys(&mut self.labels, &f);
+    }
+
+    pub fn remap_globals<F: Fn(Global) -> Option<Global>>(&mut self, f: F) {
+        remap_keys(&mut self.globals, f);
+    }
+
+    pub fn remap_tables<F: Fn(Table) -> Option<Table>>(&mut self, f: F) {
+        remap_keys(&mut self.tables, f);
+    }
+
+    pub fn remap_memories<F: Fn(Memory) -> Option<Memory>>(&mut self, f: F) {
+        remap_keys(&mut self.memories, f);
+    }
+
+    pub fn remap_signatures<F: Fn(Signature) -> Option<Signature>>(&mut self, f: F) {
+        remap_keys(&mut self.types, f);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomSection {
+    pub name: String,
+    pub data: Vec<u8>,
+    pub placement: CustomSectionPlacement,
+}
+
+/// Where a custom section is emitted relative to the standard
+/// sections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CustomSectionPlacement {
+    /// Before all standard sections.
+    Start,
+    /// After the standard section with the given section ID (and any
+    /// custom sections placed earlier in the list). If that section
+    /// is not emitted, the section goes where it would have been.
+    After(u8),
+    /// At the end of the module, after the `name` section.
+    End,
+}
+
+impl CustomSection {
+    /// A new custom section, to be placed at the end of the module.
+    pub fn new(name: &str, data: Vec<u8>) -> CustomSection {
+        CustomSection {
+            name: name.to_owned(),
+            data,
+            placement: CustomSectionPlacement::End,
+        }
+    }
+}
+
+/// What `Module::strip` removes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StripOptions {
+    /// DWARF sections, and the source locations taken from them.
+    pub dwarf: bool,
+    /// The `name` section, including function names.
+    pub names: bool,
+    /// The `producers` section.
+    pub producers: bool,
+    /// Other custom sections, by name. In a pattern, `*` matches any
+    /// run of characters.
+    pub custom: Vec<String>,
+}
+
+/// Whether `name` matches `pattern`, in which `*` matches any run of
+/// characters.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            name.starts_with(prefix)
+                && (prefix.len()..=name.len())
+                    .any(|i| name.is_char_boundary(i) && matches_pattern(rest, &name[i..]))
+        }
+    }
+}
+
+/// Configuration for spilling values to a shadow stack in linear
+/// memory when a function would otherwise have too many locals.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpillConfig {
+    /// A mutable `i32` global holding the shadow stack pointer. The
+    /// stack grows downward and the pointer is kept 16-byte aligned.
+    pub stack_pointer: Global,
+    /// The memory in which the shadow stack lives.
+    pub memory: Memory,
+    /// The maximum number of locals (including params) a function may
+    /// use before its least-frequently-accessed values are spilled.
+    pub max_locals: usize,
+}
+
+impl SpillConfig {
+    /// The smallest local-count limit among common engines.
+    pub const DEFAULT_MAX_LOCALS: usize = 50_000;
+
+    pub fn new(stack_pointer: Global, memory: Memory) -> SpillConfig {
+        SpillConfig {
+            stack_pointer,
+            memory,
+            max_locals: Self::DEFAULT_MAX_LOCALS,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -31,15 +262,104 @@ pub struct SignatureData {
 pub struct MemoryData {
     pub initial_pages: usize,
     pub maximum_pages: Option<usize>,
-    pub segments: Vec<MemorySegment>,
+    /// Whether the memory is shared between threads.
+    pub shared: bool,
 }
 
+/// A data segment. Active segments are copied into memory when the
+/// module is instantiated; passive ones only by `memory.init`.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct MemorySegment {
-    pub offset: usize,
+pub struct DataSegment {
+    pub kind: DataSegmentKind,
     pub data: Vec<u8>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DataSegmentKind {
+    Passive,
+    Active {
+        memory: Memory,
+        offset: SegmentOffset,
+    },
+}
+
+/// An element segment that is not part of a table's contents in
+/// `TableData::func_elements`: a passive or declared segment, or an
+/// active one that the table contents can't express (because its
+/// offset is a global, or it is written with expressions), or that
+/// comes before one of those, as the table contents are written last.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ElementSegment {
+    pub kind: ElementSegmentKind,
+    pub items: ElementItems,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ElementSegmentKind {
+    Passive,
+    /// Only declares functions for use by `ref.func`.
+    Declared,
+    Active {
+        table: Table,
+        offset: SegmentOffset,
+    },
+}
+
+/// The items of an element segment, in either of the binary
+/// format's encodings.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ElementItems {
+    Functions(Vec<Func>),
+    /// `ref.func` (`Some`) and `ref.null func` (`None`) expressions.
+    Expressions(Vec<Option<Func>>),
+}
+
+impl ElementItems {
+    pub fn len(&self) -> usize {
+        match self {
+            ElementItems::Functions(funcs) => funcs.len(),
+            ElementItems::Expressions(exprs) => exprs.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The functions referred to, in order.
+    pub fn funcs(&self) -> impl Iterator<Item = Func> + '_ {
+        let (funcs, exprs) = match self {
+            ElementItems::Functions(funcs) => (&funcs[..], &[][..]),
+            ElementItems::Expressions(exprs) => (&[][..], &exprs[..]),
+        };
+        funcs.iter().copied().chain(exprs.iter().flatten().copied())
+    }
+
+    pub fn funcs_mut(&mut self) -> Box<dyn Iterator<Item = &mut Func> + '_> {
+        match self {
+            ElementItems::Functions(funcs) => Box::new(funcs.iter_mut()),
+            ElementItems::Expressions(exprs) => Box::new(exprs.iter_mut().flatten()),
+        }
+    }
+}
+
+/// Where an active segment is placed: a constant offset, or the value
+/// of an (immutable, imported) global.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SegmentOffset {
+    Const(usize),
+    Global(Global),
+}
+
+impl core::fmt::Display for SegmentOffset {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            SegmentOffset::Const(offset) => write!(f, "{}", offset),
+            SegmentOffset::Global(global) => write!(f, "{}", global),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TableData {
     pub ty: Type,
@@ -50,10 +370,31 @@ pub struct TableData {
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct GlobalData {
     pub ty: Type,
-    pub value: Option<u64>,
+    /// How a defined global is initialized; `None` for imported
+    /// globals.
+    pub init: Option<GlobalInit>,
     pub mutable: bool,
 }
 
+/// The initializer of a defined global.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum GlobalInit {
+    /// A constant, as the bits of its value.
+    Const(u64),
+    /// The value of an imported global, known only once instantiated.
+    GlobalGet(Global),
+}
+
+impl core::fmt::Display for GlobalInit {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            GlobalInit::Const(bits) => write!(f, "{}", bits),
+            GlobalInit::GlobalGet(global) => write!(f, "global.get {}", global),
+        }
+    }
+}
+
+#[cfg(feature = "frontend")]
 impl From<&wasmparser::FuncType> for SignatureData {
     fn from(fty: &wasmparser::FuncType) -> Self {
         Self {
@@ -70,6 +411,7 @@ impl From<&wasmparser::FuncType> for SignatureData {
         }
     }
 }
+#[cfg(feature = "frontend")]
 impl From<wasmparser::FuncType> for SignatureData {
     fn from(fty: wasmparser::FuncType) -> Self {
         (&fty).into()
@@ -91,8 +433,8 @@ pub enum ImportKind {
     Memory(Memory),
 }
 
-impl std::fmt::Display for ImportKind {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for ImportKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
             ImportKind::Table(table) => write!(f, "{}", table)?,
             ImportKind::Func(func) => write!(f, "{}", func)?,
@@ -117,8 +459,8 @@ pub enum ExportKind {
     Memory(Memory),
 }
 
-impl std::fmt::Display for ExportKind {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for ExportKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
             ExportKind::Table(table) => write!(f, "{}", table)?,
             ExportKind::Func(func) => write!(f, "{}", func)?,
@@ -130,6 +472,11 @@ impl std::fmt::Display for ExportKind {
 }
 
 impl<'a> Module<'a> {
+    /// A module with nothing in it, to be built up through the API.
+    pub fn empty() -> Module<'static> {
+        Module::with_orig_bytes(&[])
+    }
+
     pub(crate) fn with_orig_bytes(orig_bytes: &'a [u8]) -> Module<'a> {
         Module {
             orig_bytes,
@@ -140,9 +487,24 @@ impl<'a> Module<'a> {
             imports: vec![],
             exports: vec![],
             memories: EntityVec::default(),
+            data_segments: vec![],
+            elem_segments: vec![],
             start_func: None,
             debug: Debug::default(),
             debug_map: DebugMap::default(),
+            dwarf_sections: DwarfSections::default(),
+            names: Names::default(),
+            custom_sections: vec![],
+            spill_config: None,
+            #[cfg(feature = "backend")]
+            codegen_options: backend::CodegenOptions::default(),
+            #[cfg(feature = "backend")]
+            compile_cache: backend::CompileCache::default(),
+            skip_unsupported: false,
+            skipped: BTreeMap::new(),
+            orig_segments: SegmentFingerprints::default(),
+            timeline: None,
+            warnings: Warnings::default(),
         }
     }
 
@@ -162,17 +524,32 @@ impl<'a> Module<'a> {
             imports: self.imports,
             exports: self.exports,
             memories: self.memories,
+            data_segments: self.data_segments,
+            elem_segments: self.elem_segments,
             start_func: self.start_func,
             debug: self.debug,
             debug_map: self.debug_map,
+            dwarf_sections: self.dwarf_sections,
+            names: self.names,
+            custom_sections: self.custom_sections,
+            spill_config: self.spill_config,
+            #[cfg(feature = "backend")]
+            codegen_options: self.codegen_options,
+            #[cfg(feature = "backend")]
+            compile_cache: self.compile_cache,
+            skip_unsupported: self.skip_unsupported,
+            skipped: self.skipped,
+            orig_segments: self.orig_segments,
+            timeline: self.timeline,
+            warnings: self.warnings,
         }
     }
 }
 
 impl<'a> Module<'a> {
-    pub(crate) fn frontend_add_table(&mut self, ty: Type, max: Option<u32>) -> Table {
+    pub(crate) fn frontend_add_table(&mut self, ty: Type, initial: u32, max: Option<u32>) -> Table {
         let func_elements = if ty == Type::FuncRef {
-            Some(vec![])
+            Some(vec![Func::invalid(); initial as usize])
         } else {
             None
         };
@@ -183,35 +560,387 @@ impl<'a> Module<'a> {
         })
     }
 
+    /// Parse a module. Function bodies are not parsed into IR here:
+    /// each is kept as a `FuncDecl::Lazy` referring to its bytes in
+    /// `bytes`, and parsed by `expand_func` (or `func_body`) when needed.
+    /// Bodies that are never expanded are written back out unchanged.
+    #[cfg(feature = "frontend")]
     pub fn from_wasm_bytes(bytes: &'a [u8], options: &FrontendOptions) -> Result<Self> {
-        frontend::wasm_to_ir(bytes, options)
+        span!("parse_module", bytes = bytes.len());
+        let _span = options.timeline.as_ref().map(|timeline| {
+            timeline
+                .span("parse", "parse module")
+                .arg("bytes", bytes.len())
+        });
+        let mut module = frontend::wasm_to_ir(bytes, options)?;
+        module.timeline = options.timeline.clone();
+        Ok(module)
     }
 
+    /// Translate WAT text to a binary module for `from_wasm_bytes`.
+    /// Input that is a binary module already is returned as it is.
+    #[cfg(feature = "wat")]
+    pub fn wat_to_wasm(input: &[u8]) -> Result<alloc::borrow::Cow<'_, [u8]>> {
+        Ok(wat::parse_bytes(input)?)
+    }
+
+    /// The first custom section with the given name, if any.
+    pub fn custom_section(&self, name: &str) -> Option<&CustomSection> {
+        self.custom_sections
+            .iter()
+            .find(|section| section.name == name)
+    }
+
+    pub fn custom_section_mut(&mut self, name: &str) -> Option<&mut CustomSection> {
+        self.custom_sections
+            .iter_mut()
+            .find(|section| section.name == name)
+    }
+
+    /// Append a custom section, to be emitted at the end of the module.
+    pub fn add_custom_section(&mut self, name: &str, data: Vec<u8>) {
+        self.custom_sections.push(CustomSection::new(name, data));
+    }
+
+    /// Remove all custom sections with the given name, returning them.
+    pub fn remove_custom_sections(&mut self, name: &str) -> Vec<CustomSection> {
+        let (removed, kept) = core::mem::take(&mut self.custom_sections)
+            .into_iter()
+            .partition(|section| section.name == name);
+        self.custom_sections = kept;
+        removed
+    }
+
+    /// Remove debug info, names and custom sections, as selected by
+    /// `options`.
+    pub fn strip(&mut self, options: &StripOptions) {
+        if options.dwarf {
+            self.dwarf_sections.clear();
+            self.debug = Debug::default();
+            self.debug_map = DebugMap::default();
+            self.per_func_body(|body| body.source_locs = Default::default());
+        }
+        if options.names {
+            self.names = Names::default();
+            for decl in self.funcs.values_mut() {
+                if let FuncDecl::None = decl {
+                    continue;
+                }
+                decl.set_name("");
+            }
+        }
+        self.custom_sections.retain(|section| {
+            let strip = (options.producers && section.name == Producers::SECTION_NAME)
+                || options
+                    .custom
+                    .iter()
+                    .any(|pattern| matches_pattern(pattern, &section.name));
+            !strip
+        });
+    }
+
+    /// Rename imports in bulk. `f` returns the new `(module, name)`
+    /// for an import, or `None` to leave it unchanged. Fails without
+    /// modifying anything if a renamed import would collide with
+    /// another import.
+    pub fn rename_imports<F: FnMut(&Import) -> Option<(String, String)>>(
+        &mut self,
+        mut f: F,
+    ) -> Result<()> {
+        let renamed = self.imports.iter().map(&mut f).collect::<Vec<_>>();
+        let mut seen = HashMap::new();
+        for (i, (import, new_name)) in self.imports.iter().zip(&renamed).enumerate() {
+            let key = match new_name {
+                Some((module, name)) => (&module[..], &name[..]),
+                None => (&import.module[..], &import.name[..]),
+            };
+            if let Some(j) = seen.insert(key, i) {
+                // Duplicates already present in the input are allowed.
+                if renamed[i].is_some() || renamed[j].is_some() {
+                    anyhow::bail!("Duplicate import after renaming: {}.{}", key.0, key.1);
+                }
+            }
+        }
+        // Without `std`, `hashbrown`'s maps hold their borrows until
+        // dropped.
+        drop(seen);
+        let mut dylink = self.dylink()?;
+        for (import, new_name) in self.imports.iter_mut().zip(renamed) {
+            if let Some((module, name)) = new_name {
+                for info in dylink.iter_mut().flat_map(|dylink| &mut dylink.import_info) {
+                    if info.0 == import.module && info.1 == import.name {
+                        info.0 = module.clone();
+                        info.1 = name.clone();
+                    }
+                }
+                import.module = module;
+                import.name = name;
+            }
+        }
+        if let Some(dylink) = dylink {
+            self.set_dylink(&dylink);
+        }
+        Ok(())
+    }
+
+    /// Rename exports in bulk. `f` returns the new name for an export,
+    /// or `None` to leave it unchanged. Fails without modifying
+    /// anything if two exports would end up with the same name.
+    pub fn rename_exports<F: FnMut(&Export) -> Option<String>>(&mut self, mut f: F) -> Result<()> {
+        let renamed = self.exports.iter().map(&mut f).collect::<Vec<_>>();
+        let mut seen = HashSet::new();
+        for (export, new_name) in self.exports.iter().zip(&renamed) {
+            let name = new_name.as_ref().unwrap_or(&export.name);
+            if !seen.insert(&name[..]) {
+                anyhow::bail!("Duplicate export name after renaming: {}", name);
+            }
+        }
+        drop(seen);
+        let mut dylink = self.dylink()?;
+        for (export, new_name) in self.exports.iter_mut().zip(renamed) {
+            if let Some(name) = new_name {
+                for info in dylink.iter_mut().flat_map(|dylink| &mut dylink.export_info) {
+                    if info.0 == export.name {
+                        info.0 = name.clone();
+                    }
+                }
+                export.name = name;
+            }
+        }
+        if let Some(dylink) = dylink {
+            self.set_dylink(&dylink);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "backend")]
     pub fn to_wasm_bytes(&self) -> Result<Vec<u8>> {
         backend::compile(self)
     }
 
-    pub fn per_func_body<F: Fn(&mut FunctionBody)>(&mut self, f: F) {
-        for func_decl in self.funcs.values_mut() {
+    /// Compile the module along with a source map from the emitted
+    /// code back to the original; see `SourceMap`. `orig_name` names
+    /// the original module in the map when there is no DWARF to map
+    /// through. If `url` is given, a `sourceMappingURL` section
+    /// pointing to the map is added.
+    #[cfg(feature = "backend")]
+    pub fn to_wasm_bytes_with_source_map(
+        &self,
+        orig_name: &str,
+        url: Option<&str>,
+    ) -> Result<(Vec<u8>, backend::SourceMap)> {
+        backend::compile_with_source_map(self, orig_name, url)
+    }
+
+    /// Compile the module and measure the size of each section and
+    /// function body, attributing code back to the original function
+    /// it came from; see `SizeProfile`.
+    #[cfg(feature = "backend")]
+    pub fn to_wasm_bytes_with_size_profile(&self) -> Result<(Vec<u8>, backend::SizeProfile)> {
+        backend::compile_with_size_profile(self)
+    }
+
+    /// Compile the module and print it as WAT, with each instruction
+    /// compiled from IR commented with the value it came from.
+    #[cfg(feature = "wat")]
+    pub fn to_annotated_wat(&self) -> Result<String> {
+        backend::compile_to_annotated_wat(self)
+    }
+
+    /// Disassemble `funcs` as they compile, objdump-style: the locals
+    /// and spill slots the backend gave each value, then each emitted
+    /// instruction with its offset, bytes, text and value.
+    #[cfg(feature = "wat")]
+    pub fn disassemble(&self, funcs: &[Func]) -> Result<String> {
+        backend::disassemble(self, funcs)
+    }
+
+    /// Compile the module, handing the encoded sections to `encoder`
+    /// (e.g. a `BinaryEncoder`, a `WriterEncoder`, or a user-provided
+    /// implementation) and returning its output.
+    #[cfg(feature = "backend")]
+    pub fn to_encoder<E: backend::ModuleEncoder>(&self, encoder: E) -> Result<E::Output> {
+        backend::compile_with(self, encoder)
+    }
+
+    /// Compile the module and write it to `out`, emitting each
+    /// section as it is completed and compiling function bodies in
+    /// bounded-size batches, each written out as it is done. Suitable
+    /// for very large modules. `out` is sought back into to patch in
+    /// the code section's size.
+    #[cfg(feature = "backend")]
+    pub fn to_wasm_writer<W: std::io::Write + std::io::Seek>(&self, out: &mut W) -> Result<()> {
+        backend::compile_to_writer(self, out)
+    }
+
+    /// Apply `f` to every function body that has been expanded. With
+    /// the `parallel` feature, the bodies are processed on rayon's
+    /// thread pool.
+    pub fn per_func_body<F: Fn(&mut FunctionBody) + Sync>(&mut self, f: F) {
+        #[cfg(feature = "backend")]
+        self.compile_cache.clear();
+        let f = |func_decl: &mut FuncDecl<'a>| {
             if let Some(body) = func_decl.body_mut() {
                 f(body);
             }
+        };
+        #[cfg(feature = "parallel")]
+        {
+            let mut funcs = core::mem::take(&mut self.funcs).into_vec();
+            funcs.par_iter_mut().for_each(f);
+            self.funcs = funcs.into();
+        }
+        #[cfg(not(feature = "parallel"))]
+        self.funcs.values_mut().for_each(f);
+    }
+
+    /// Keep the compiled code of each body when the module is
+    /// serialized, and reuse it the next time for the bodies that have
+    /// not been marked dirty since. Turning it off drops the kept code.
+    #[cfg(feature = "backend")]
+    pub fn set_incremental(&mut self, incremental: bool) {
+        self.compile_cache.enabled = incremental;
+        if !incremental {
+            self.compile_cache.clear();
+        }
+    }
+
+    /// Note that the body of `func` may have changed, so that the next
+    /// serialization compiles it again. `expand_func`, `func_body`,
+    /// `per_func_body` and `replace_body` mark the bodies they give
+    /// access to, and renumbering entities marks all of them; only
+    /// changes made through `funcs` directly need this.
+    #[cfg_attr(not(feature = "backend"), allow(unused_variables))]
+    pub fn mark_dirty(&mut self, func: Func) {
+        #[cfg(feature = "backend")]
+        self.compile_cache.remove(func);
+    }
+
+    /// When a body uses a feature the IR can't represent, keep it as a
+    /// `FuncDecl::Lazy` when it is expanded instead of failing, and
+    /// record why in `skipped_funcs`. Passes leave such bodies alone,
+    /// and they are written back out as their original bytes; that
+    /// fails if an element or data segment they refer to by index has
+    /// changed. Modules with such bodies can't be linked, and the
+    /// functions can't be split out.
+    pub fn set_skip_unsupported(&mut self, skip: bool) {
+        self.skip_unsupported = skip;
+    }
+
+    /// The bodies kept as their original bytes because they use
+    /// unsupported features, with the error each gave.
+    pub fn skipped_funcs(&self) -> &BTreeMap<Func, WaffleError> {
+        &self.skipped
+    }
+
+    /// Fail if `body`, the original bytes of `func`, refers by index
+    /// to an element or data segment (with `table.init`, `elem.drop`,
+    /// `memory.init` or `data.drop`) that is no longer the one it was
+    /// parsed with, so that it can't be written back out verbatim.
+    #[cfg(feature = "frontend")]
+    pub(crate) fn check_verbatim_segments(&self, func: Func, body: &RawBody<'_>) -> Result<()> {
+        use wasmparser::Operator as Op;
+        for op in body.reader().get_operators_reader()? {
+            let (kind, index, now, orig) = match op? {
+                Op::TableInit { elem_index, .. } | Op::ElemDrop { elem_index } => (
+                    "element",
+                    elem_index,
+                    self.elem_segments
+                        .get(elem_index as usize)
+                        .map(crate::collections::hash64),
+                    self.orig_segments.elem.get(elem_index as usize),
+                ),
+                Op::MemoryInit { data_index, .. } | Op::DataDrop { data_index } => (
+                    "data",
+                    data_index,
+                    self.data_segments
+                        .get(data_index as usize)
+                        .map(crate::collections::hash64),
+                    self.orig_segments.data.get(data_index as usize),
+                ),
+                _ => continue,
+            };
+            if now.is_none() || now.as_ref() != orig {
+                anyhow::bail!(
+                    "Cannot keep {} as its original bytes: {} segment {} has changed",
+                    func,
+                    kind,
+                    index
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The warnings given so far while parsing, expanding and compiling
+    /// the module, each once.
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.0.lock().unwrap().clone()
+    }
+
+    /// Record that `id` failed to parse with `err`, if it is to be
+    /// skipped; otherwise, fail with `err`.
+    fn skip_or_fail(&mut self, id: Func, err: anyhow::Error) -> Result<()> {
+        let err = locate(err, Some(id), None);
+        match err.downcast_ref::<WaffleError>() {
+            Some(err @ WaffleError::UnsupportedFeature { .. }) if self.skip_unsupported => {
+                self.warnings
+                    .add(format!("Keeping the original body: {}", err));
+                self.skipped.insert(id, err.clone());
+                Ok(())
+            }
+            _ => Err(err),
         }
     }
 
+    /// A span of the timeline, if there is one, for work on `func`.
+    pub(crate) fn func_span(&self, category: &'static str, func: Func) -> Option<TimelineSpan<'_>> {
+        let timeline = self.timeline.as_ref()?;
+        let span = timeline.span(category, &func.to_string());
+        Some(match self.funcs[func].name() {
+            "" => span,
+            name => span.arg("name", name),
+        })
+    }
+
+    /// A copy of `id`'s declaration, with its body parsed if it is lazy.
+    fn parse_func(&self, id: Func) -> Result<FuncDecl<'a>> {
+        span!("lift", func = %id);
+        let _span = self.func_span("lift", id);
+        // This is cheap for lazy bodies (a slice copy).
+        let mut func = self.funcs[id].clone();
+        func.parse(self)?;
+        event!(
+            func = %id,
+            blocks = func.body().map_or(0, |body| body.blocks.len()),
+            values = func.body().map_or(0, |body| body.values.len()),
+            "lifted"
+        );
+        Ok(func)
+    }
+
     pub fn expand_func<'b>(&'b mut self, id: Func) -> Result<&'b mut FuncDecl<'a>> {
+        self.mark_dirty(id);
         if let FuncDecl::Lazy(..) = self.funcs[id] {
-            // End the borrow. This is cheap (a slice copy).
-            let mut func = self.funcs[id].clone();
-            func.parse(self)?;
-            self.funcs[id] = func;
+            if !self.skipped.contains_key(&id) {
+                match self.parse_func(id) {
+                    Ok(func) => self.funcs[id] = func,
+                    Err(err) => self.skip_or_fail(id, err)?,
+                }
+            }
         }
         Ok(&mut self.funcs[id])
     }
 
+    /// The body of `func`, parsing it first if it has not been yet.
+    /// No other body is parsed. Returns `None` for imported and
+    /// already-compiled functions.
+    pub fn func_body(&mut self, id: Func) -> Result<Option<&mut FunctionBody>> {
+        Ok(self.expand_func(id)?.body_mut())
+    }
+
     pub fn clone_and_expand_body(&self, id: Func) -> Result<FunctionBody> {
-        let mut body = self.funcs[id].clone();
-        body.parse(self)?;
+        let body = self.parse_func(id).map_err(|e| locate(e, Some(id), None))?;
         Ok(match body {
             FuncDecl::Body(_, _, body) => body,
             _ => unreachable!(),
@@ -219,12 +948,40 @@ impl<'a> Module<'a> {
     }
 
     pub fn replace_body(&mut self, id: Func, body: FunctionBody) {
+        self.mark_dirty(id);
         let sig = self.funcs[id].sig();
         let name = self.funcs[id].name().to_owned();
         self.funcs[id] = FuncDecl::Body(sig, name, body);
     }
 
+    /// Expand every lazy function body. With the `parallel` feature,
+    /// the bodies are parsed on rayon's thread pool; either way, if
+    /// some fail to parse, the error is the first one's, and the bodies
+    /// before it are expanded.
     pub fn expand_all_funcs(&mut self) -> Result<()> {
+        #[cfg(feature = "parallel")]
+        {
+            let lazy = self
+                .funcs
+                .entries()
+                .filter(|(id, func_decl)| {
+                    matches!(func_decl, FuncDecl::Lazy(..)) && !self.skipped.contains_key(id)
+                })
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>();
+            let module = &*self;
+            let parsed = lazy
+                .par_iter()
+                .map(|&id| module.parse_func(id))
+                .collect::<Vec<_>>();
+            for (id, func) in lazy.into_iter().zip(parsed) {
+                match func {
+                    Ok(func) => self.funcs[id] = func,
+                    Err(err) => self.skip_or_fail(id, err)?,
+                }
+            }
+        }
+        #[cfg(not(feature = "parallel"))]
         for id in 0..self.funcs.len() {
             let id = Func::new(id);
             self.expand_func(id)?;
@@ -0,0 +1,106 @@
+//! The `producers` custom section: which tools produced a module.
+
+use super::{CustomSection, Module};
+use crate::leb128::{write_str, write_u32, Reader};
+use crate::prelude::*;
+use anyhow::Result;
+
+/// The parsed contents of a `producers` section: a list of fields (by
+/// convention `language`, `processed-by` and `sdk`), each with a list
+/// of `(name, version)` pairs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Producers {
+    pub fields: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl Producers {
+    pub const SECTION_NAME: &'static str = "producers";
+
+    pub fn parse(data: &[u8]) -> Result<Producers> {
+        let mut reader = Reader::new(data);
+        let mut fields = vec![];
+        for _ in 0..reader.read_var_u32()? {
+            let field = reader.read_string()?.to_owned();
+            let mut values = vec![];
+            for _ in 0..reader.read_var_u32()? {
+                let name = reader.read_string()?.to_owned();
+                values.push((name, reader.read_string()?.to_owned()));
+            }
+            fields.push((field, values));
+        }
+        if !reader.eof() {
+            anyhow::bail!("Trailing bytes in producers section");
+        }
+        Ok(Producers { fields })
+    }
+
+    /// Add a tool to a field. Per the tool conventions, each name is
+    /// listed at most once per field; if the name is already present,
+    /// its version is replaced.
+    pub fn add(&mut self, field: &str, name: &str, version: &str) {
+        let values = match self.fields.iter().position(|(f, _)| f == field) {
+            Some(idx) => &mut self.fields[idx].1,
+            None => {
+                self.fields.push((field.to_owned(), vec![]));
+                &mut self.fields.last_mut().unwrap().1
+            }
+        };
+        match values.iter_mut().find(|(n, _)| n == name) {
+            Some(existing) => existing.1 = version.to_owned(),
+            None => values.push((name.to_owned(), version.to_owned())),
+        }
+    }
+
+    /// Merge another producers section into this one.
+    pub fn merge(&mut self, other: &Producers) {
+        for (field, values) in &other.fields {
+            for (name, version) in values {
+                self.add(field, name, version);
+            }
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_u32(&mut out, self.fields.len() as u32);
+        for (field, values) in &self.fields {
+            write_str(&mut out, field);
+            write_u32(&mut out, values.len() as u32);
+            for (name, version) in values {
+                write_str(&mut out, name);
+                write_str(&mut out, version);
+            }
+        }
+        out
+    }
+}
+
+impl<'a> Module<'a> {
+    /// The module's `producers` section, if it has one.
+    pub fn producers(&self) -> Result<Option<Producers>> {
+        self.custom_section(Producers::SECTION_NAME)
+            .map(|section| Producers::parse(&section.data[..]))
+            .transpose()
+    }
+
+    /// Replace the module's `producers` section, keeping its original
+    /// position if it had one.
+    pub fn set_producers(&mut self, producers: &Producers) {
+        let data = producers.encode();
+        match self.custom_section_mut(Producers::SECTION_NAME) {
+            Some(section) => section.data = data,
+            None => self
+                .custom_sections
+                .push(CustomSection::new(Producers::SECTION_NAME, data)),
+        }
+    }
+
+    /// Record waffle in the `processed-by` field of the `producers`
+    /// section, creating the section if needed.
+    pub fn add_waffle_producer(&mut self) -> Result<()> {
+        let mut producers = self.producers()?.unwrap_or_default();
+        producers.add("processed-by", "waffle", env!("CARGO_PKG_VERSION"));
+        self.set_producers(&producers);
+        Ok(())
+    }
+}
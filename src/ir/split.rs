@@ -0,0 +1,263 @@
+//! Splitting a module into a primary module and secondary modules
+//! that can be loaded later.
+
+use super::link::{blank_table, export_entity, remap_body, Remap};
+use super::{
+    ElementItems, ElementSegment, ElementSegmentKind, Export, ExportKind, Func, FuncDecl,
+    FunctionBody, Import, ImportKind, Module, SegmentOffset, Signature, Table, Terminator, Type,
+    Value, ValueDef,
+};
+use crate::collections::{HashMap, HashSet};
+use crate::entity::EntityVec;
+use crate::ops::EntityUse;
+use crate::pool::ListRef;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// The name under which the primary module exports the table of
+/// split-out functions.
+pub const SPLIT_TABLE_EXPORT: &str = "__waffle_split_table";
+
+/// The result of splitting a module.
+#[derive(Clone, Debug)]
+pub struct Split<'a> {
+    pub primary: Module<'a>,
+    /// One module for each part, in order. Each imports what it needs
+    /// from the primary module.
+    pub secondaries: Vec<Module<'static>>,
+}
+
+/// A body that calls the function in slot `slot` of `table` with the
+/// same arguments, and returns its results.
+fn trampoline(module: &Module<'_>, sig: Signature, table: Table, slot: u32) -> FunctionBody {
+    let mut body = FunctionBody::new(module, sig);
+    let mut args: Vec<Value> = body.blocks[body.entry]
+        .params
+        .iter()
+        .map(|&(_, param)| param)
+        .collect();
+    let types = body.single_type_list(Type::I32);
+    let index = body.add_value(ValueDef::Operator(
+        Operator::I32Const { value: slot },
+        ListRef::default(),
+        types,
+    ));
+    body.append_to_block(body.entry, index);
+    args.push(index);
+
+    let rets = module.signatures[sig].returns.clone();
+    let args = body.arg_pool.from_iter(args.into_iter());
+    let types = body.type_list(&rets[..]);
+    let call = body.add_value(ValueDef::Operator(
+        Operator::CallIndirect {
+            sig_index: sig,
+            table_index: table,
+        },
+        args,
+        types,
+    ));
+    body.append_to_block(body.entry, call);
+    let values = if rets.len() == 1 {
+        vec![call]
+    } else {
+        let mut values = vec![];
+        for (i, &ty) in rets.iter().enumerate() {
+            let pick = body.add_value(ValueDef::PickOutput(call, i as u32, ty));
+            body.append_to_block(body.entry, pick);
+            values.push(pick);
+        }
+        values
+    };
+    body.set_terminator(body.entry, Terminator::Return { values });
+    body
+}
+
+impl<'a> Module<'a> {
+    /// The name of an export of `entity`, adding one if there is none.
+    fn export_name(&mut self, entity: EntityUse) -> String {
+        let kind = match entity {
+            EntityUse::Func(func) => ExportKind::Func(func),
+            EntityUse::Global(global) => ExportKind::Global(global),
+            EntityUse::Table(table) => ExportKind::Table(table),
+            EntityUse::Memory(memory) => ExportKind::Memory(memory),
+        };
+        if let Some(export) = self
+            .exports
+            .iter()
+            .find(|export| export_entity(&export.kind) == entity)
+        {
+            return export.name.clone();
+        }
+        let name = format!("__waffle_split_{}", kind);
+        self.exports.push(Export {
+            name: name.clone(),
+            kind,
+        });
+        name
+    }
+
+    /// Split the module for lazy loading: move the functions in each
+    /// of `parts` to a secondary module of their own.
+    ///
+    /// The primary module gets a new table, exported as
+    /// `SPLIT_TABLE_EXPORT`, with a slot for each moved function, and
+    /// each moved function is replaced by a trampoline that calls
+    /// through its slot, so all references to it stay valid. A
+    /// secondary module imports whatever its functions use from the
+    /// primary module (as `primary_name`, exporting it from there if
+    /// needed) and fills its functions' slots when instantiated.
+    /// Calling a moved function before its secondary module has been
+    /// instantiated traps.
+    pub fn split(mut self, primary_name: &str, parts: &[Vec<Func>]) -> Result<Split<'a>> {
+        let mut slots = HashMap::new();
+        for &func in parts.iter().flatten() {
+            match self.funcs.get(func) {
+                Some(FuncDecl::Import(..)) => {
+                    anyhow::bail!("Cannot move imported function {}", func)
+                }
+                Some(_) => {}
+                None => anyhow::bail!("No such function: {}", func),
+            }
+            if self.start_func == Some(func) {
+                anyhow::bail!("Cannot move start function {}", func);
+            }
+            let slot = slots.len() as u32;
+            if slots.insert(func, slot).is_some() {
+                anyhow::bail!("Function {} is in more than one part", func);
+            }
+            match self.expand_func(func)? {
+                FuncDecl::Compiled(..) => {
+                    anyhow::bail!("Cannot move {}: it is already compiled", func)
+                }
+                FuncDecl::Lazy(..) => {
+                    anyhow::bail!("Cannot move {}: it is kept as its original bytes", func)
+                }
+                _ => {}
+            }
+        }
+
+        let n = slots.len() as u32;
+        let table = self.add_table(Type::FuncRef, n, Some(n));
+        self.exports.push(Export {
+            name: SPLIT_TABLE_EXPORT.to_owned(),
+            kind: ExportKind::Table(table),
+        });
+
+        let mut secondaries = vec![];
+        for part in parts {
+            let mut bodies = vec![];
+            for &func in part {
+                let sig = self.funcs[func].sig();
+                let name = self.funcs[func].name().to_owned();
+                let body = trampoline(&self, sig, table, slots[&func]);
+                let body = match core::mem::replace(
+                    &mut self.funcs[func],
+                    FuncDecl::Body(sig, name.clone(), body),
+                ) {
+                    FuncDecl::Body(_, _, body) => body,
+                    _ => unreachable!(),
+                };
+                bodies.push((sig, name, body));
+            }
+
+            // Everything the moved bodies use from the primary module.
+            let moved: HashSet<Func> = part.iter().copied().collect();
+            let mut uses = vec![EntityUse::Table(table)];
+            if let Some(config) = &self.spill_config {
+                uses.push(EntityUse::Global(config.stack_pointer));
+                uses.push(EntityUse::Memory(config.memory));
+            }
+            let mut seen: HashSet<EntityUse> = uses.iter().copied().collect();
+            for (_, _, body) in &bodies {
+                for def in body.values.values() {
+                    if let ValueDef::Operator(op, ..) = def {
+                        match op.entity_use() {
+                            Some(EntityUse::Func(func)) if moved.contains(&func) => {}
+                            Some(entity) if seen.insert(entity) => uses.push(entity),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+
+            let mut out = Module::with_orig_bytes(&[]);
+            out.signatures = self.signatures.clone();
+            let mut remap = Remap::default();
+            for entity in uses {
+                let name = self.export_name(entity);
+                let (new, kind) = match entity {
+                    EntityUse::Func(func) => {
+                        let decl = &self.funcs[func];
+                        let new = out
+                            .funcs
+                            .push(FuncDecl::Import(decl.sig(), decl.name().to_owned()));
+                        (EntityUse::Func(new), ImportKind::Func(new))
+                    }
+                    EntityUse::Global(global) => {
+                        let mut data = self.globals[global].clone();
+                        data.init = None;
+                        let new = out.globals.push(data);
+                        (EntityUse::Global(new), ImportKind::Global(new))
+                    }
+                    EntityUse::Table(table) => {
+                        let new = out.tables.push(blank_table(&self.tables[table]));
+                        (EntityUse::Table(new), ImportKind::Table(new))
+                    }
+                    EntityUse::Memory(memory) => {
+                        let new = out.memories.push(self.memories[memory].clone());
+                        (EntityUse::Memory(new), ImportKind::Memory(new))
+                    }
+                };
+                remap.entities.insert(entity, new);
+                out.imports.push(Import {
+                    module: primary_name.to_owned(),
+                    name,
+                    kind,
+                });
+            }
+            for &func in part {
+                let new = out.funcs.push(FuncDecl::None);
+                remap
+                    .entities
+                    .insert(EntityUse::Func(func), EntityUse::Func(new));
+            }
+
+            let sigs: EntityVec<Signature, Signature> =
+                self.signatures.iter().collect::<Vec<_>>().into();
+            let mut funcs = vec![];
+            for (&func, (sig, name, mut body)) in part.iter().zip(bodies) {
+                remap_body(&mut body, &remap, &sigs, &self.debug, &mut out.debug);
+                let new = remap.func(func);
+                out.funcs[new] = FuncDecl::Body(sig, name, body);
+                funcs.push(new);
+                if let Some(locals) = self.names.locals.remove(&func) {
+                    out.names.locals.insert(new, locals);
+                }
+                self.names.labels.remove(&func);
+            }
+            if let Some(config) = &self.spill_config {
+                let mut config = config.clone();
+                config.stack_pointer = remap.global(config.stack_pointer);
+                config.memory = remap.memory(config.memory);
+                out.spill_config = Some(config);
+            }
+
+            if let Some(&first) = part.first() {
+                out.elem_segments.push(ElementSegment {
+                    kind: ElementSegmentKind::Active {
+                        table: remap.table(table),
+                        offset: SegmentOffset::Const(slots[&first] as usize),
+                    },
+                    items: ElementItems::Functions(funcs),
+                });
+            }
+            secondaries.push(out);
+        }
+
+        Ok(Split {
+            primary: self,
+            secondaries,
+        })
+    }
+}
@@ -0,0 +1,316 @@
+//! The `target_features` custom section: which Wasm features a module
+//! was compiled with, as recorded by LLVM for the linker.
+
+use super::{
+    DataSegmentKind, ElementItems, ElementSegmentKind, ExportKind, FuncDecl, Global, ImportKind,
+    Module, Type, ValueDef,
+};
+use crate::leb128::{write_u32, Reader};
+use crate::prelude::*;
+use crate::Operator;
+use alloc::collections::BTreeSet;
+use anyhow::Result;
+
+/// How a feature is listed in a `target_features` section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FeaturePrefix {
+    /// `+`: the module uses the feature.
+    Used,
+    /// `-`: the module must not be linked with modules that use it.
+    Disallowed,
+    /// `=`: every module it is linked with must use it.
+    Required,
+}
+
+impl FeaturePrefix {
+    fn byte(self) -> u8 {
+        match self {
+            FeaturePrefix::Used => b'+',
+            FeaturePrefix::Disallowed => b'-',
+            FeaturePrefix::Required => b'=',
+        }
+    }
+}
+
+/// The parsed contents of a `target_features` section.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TargetFeatures {
+    pub features: Vec<(FeaturePrefix, String)>,
+}
+
+/// The features that `Module::update_target_features` detects.
+pub const DETECTED_FEATURES: &[&str] = &[
+    "atomics",
+    "bulk-memory",
+    "multimemory",
+    "multivalue",
+    "mutable-globals",
+    "nontrapping-fptoint",
+    "reference-types",
+    "sign-ext",
+    "simd128",
+    "tail-call",
+];
+
+impl TargetFeatures {
+    pub const SECTION_NAME: &'static str = "target_features";
+
+    pub fn parse(data: &[u8]) -> Result<TargetFeatures> {
+        let mut reader = Reader::new(data);
+        let mut features = vec![];
+        for _ in 0..reader.read_var_u32()? {
+            let prefix = match reader.read_u8()? {
+                b'+' => FeaturePrefix::Used,
+                b'-' => FeaturePrefix::Disallowed,
+                b'=' => FeaturePrefix::Required,
+                byte => anyhow::bail!("Invalid target feature prefix: {:#x}", byte),
+            };
+            features.push((prefix, reader.read_string()?.to_owned()));
+        }
+        if !reader.eof() {
+            anyhow::bail!("Trailing bytes in target_features section");
+        }
+        Ok(TargetFeatures { features })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_u32(&mut out, self.features.len() as u32);
+        for (prefix, name) in &self.features {
+            out.push(prefix.byte());
+            write_u32(&mut out, name.len() as u32);
+            out.extend_from_slice(name.as_bytes());
+        }
+        out
+    }
+
+    /// How `name` is listed, if it is.
+    pub fn get(&self, name: &str) -> Option<FeaturePrefix> {
+        self.features
+            .iter()
+            .find(|(_, feature)| feature == name)
+            .map(|&(prefix, _)| prefix)
+    }
+
+    /// List `name` with the given prefix, replacing any existing entry.
+    pub fn set(&mut self, name: &str, prefix: FeaturePrefix) {
+        match self
+            .features
+            .iter_mut()
+            .find(|(_, feature)| feature == name)
+        {
+            Some(entry) => entry.0 = prefix,
+            None => self.features.push((prefix, name.to_owned())),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.features.retain(|(_, feature)| feature != name);
+    }
+}
+
+/// Features used by an IR operator.
+fn op_feature(op: &Operator) -> Option<&'static str> {
+    match op {
+        Operator::I32Extend8S
+        | Operator::I32Extend16S
+        | Operator::I64Extend8S
+        | Operator::I64Extend16S
+        | Operator::I64Extend32S => Some("sign-ext"),
+        Operator::I32TruncSatF32S
+        | Operator::I32TruncSatF32U
+        | Operator::I32TruncSatF64S
+        | Operator::I32TruncSatF64U
+        | Operator::I64TruncSatF32S
+        | Operator::I64TruncSatF32U
+        | Operator::I64TruncSatF64S
+        | Operator::I64TruncSatF64U => Some("nontrapping-fptoint"),
+        Operator::TableGet { .. }
+        | Operator::TableSet { .. }
+        | Operator::TableGrow { .. }
+        | Operator::TableSize { .. } => Some("reference-types"),
+        _ => None,
+    }
+}
+
+/// Features used by an operator of a body that is emitted verbatim,
+/// given the bytes at its offset. Most are told apart by their opcode
+/// (and the prefixed opcode's sub-opcode).
+#[cfg(feature = "frontend")]
+fn raw_op_features(
+    op: &wasmparser::Operator<'_>,
+    bytes: &[u8],
+    features: &mut BTreeSet<&'static str>,
+) {
+    use wasmparser::{BlockType, Operator as Op};
+    match op {
+        Op::Block {
+            blockty: BlockType::FuncType(_),
+        }
+        | Op::Loop {
+            blockty: BlockType::FuncType(_),
+        }
+        | Op::If {
+            blockty: BlockType::FuncType(_),
+        } => {
+            features.insert("multivalue");
+        }
+        Op::CallIndirect { table_index, .. } if *table_index != 0 => {
+            features.insert("reference-types");
+        }
+        _ => {}
+    }
+    let feature = match (bytes.first(), bytes.get(1)) {
+        (Some(0xc0..=0xc4), _) => "sign-ext",
+        (Some(0x12..=0x13), _) => "tail-call",
+        (Some(0x1c), _) | (Some(0x25..=0x26), _) | (Some(0xd0..=0xd2), _) => "reference-types",
+        (Some(0xfc), Some(0..=7)) => "nontrapping-fptoint",
+        (Some(0xfc), Some(8..=14)) => "bulk-memory",
+        (Some(0xfc), Some(15..=17)) => "reference-types",
+        (Some(0xfd), _) => "simd128",
+        (Some(0xfe), _) => "atomics",
+        _ => return,
+    };
+    features.insert(feature);
+}
+
+impl<'a> Module<'a> {
+    /// The module's `target_features` section, if it has one.
+    pub fn target_features(&self) -> Result<Option<TargetFeatures>> {
+        self.custom_section(TargetFeatures::SECTION_NAME)
+            .map(|section| TargetFeatures::parse(&section.data[..]))
+            .transpose()
+    }
+
+    /// Replace the module's `target_features` section, keeping its
+    /// original position if it had one.
+    pub fn set_target_features(&mut self, features: &TargetFeatures) {
+        let data = features.encode();
+        match self.custom_section_mut(TargetFeatures::SECTION_NAME) {
+            Some(section) => section.data = data,
+            None => self.add_custom_section(TargetFeatures::SECTION_NAME, data),
+        }
+    }
+
+    /// Which of `DETECTED_FEATURES` the module uses. Also returns
+    /// whether the answer is complete: already-compiled bodies can't
+    /// be inspected.
+    pub fn used_features(&self) -> Result<(BTreeSet<&'static str>, bool)> {
+        let mut features = BTreeSet::new();
+        let mut complete = true;
+
+        if self.signatures.values().any(|sig| sig.returns.len() > 1) {
+            features.insert("multivalue");
+        }
+        let mutable = |global: Global| self.globals[global].mutable;
+        if self
+            .imports
+            .iter()
+            .any(|import| matches!(import.kind, ImportKind::Global(g) if mutable(g)))
+            || self
+                .exports
+                .iter()
+                .any(|export| matches!(export.kind, ExportKind::Global(g) if mutable(g)))
+        {
+            features.insert("mutable-globals");
+        }
+        if self.memories.len() > 1 {
+            features.insert("multimemory");
+        }
+        if self.memories.values().any(|memory| memory.shared) {
+            features.insert("atomics");
+        }
+        if self.tables.len() > 1
+            || self
+                .globals
+                .values()
+                .any(|global| global.ty == Type::FuncRef)
+        {
+            features.insert("reference-types");
+        }
+        if self.globals.values().any(|global| global.ty == Type::V128) {
+            features.insert("simd128");
+        }
+        if self
+            .data_segments
+            .iter()
+            .any(|segment| matches!(segment.kind, DataSegmentKind::Passive))
+        {
+            features.insert("bulk-memory");
+        }
+        for segment in &self.elem_segments {
+            if !matches!(segment.kind, ElementSegmentKind::Active { .. }) {
+                features.insert("bulk-memory");
+            }
+            if let ElementItems::Expressions(_) = segment.items {
+                features.insert("reference-types");
+            }
+        }
+
+        for decl in self.funcs.values() {
+            match decl {
+                FuncDecl::Body(_, _, body) => {
+                    if body.locals.values().any(|&ty| ty == Type::V128) {
+                        features.insert("simd128");
+                    }
+                    for def in body.values.values() {
+                        match def {
+                            ValueDef::Operator(op, ..) => features.extend(op_feature(op)),
+                            ValueDef::BlockParam(_, _, Type::V128) => {
+                                features.insert("simd128");
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                #[cfg(not(feature = "frontend"))]
+                FuncDecl::Lazy(_, _, body) => body.unreachable(),
+                #[cfg(feature = "frontend")]
+                FuncDecl::Lazy(_, _, body) => {
+                    for local in body.reader().get_locals_reader()? {
+                        if let (_, wasmparser::ValType::V128) = local? {
+                            features.insert("simd128");
+                        }
+                    }
+                    for op in body
+                        .reader()
+                        .get_operators_reader()?
+                        .into_iter_with_offsets()
+                    {
+                        let (op, offset) = op?;
+                        raw_op_features(&op, &self.orig_bytes[offset..], &mut features);
+                    }
+                }
+                FuncDecl::Compiled(..) => complete = false,
+                FuncDecl::Import(..) | FuncDecl::None => {}
+            }
+        }
+        Ok((features, complete))
+    }
+
+    /// Bring the `target_features` section, if there is one, up to
+    /// date with the features the module uses: detected features are
+    /// listed as used (replacing a `-` entry), and `+` entries for
+    /// features in `DETECTED_FEATURES` that are no longer used are
+    /// removed, unless some body can't be inspected.
+    pub fn update_target_features(&mut self) -> Result<()> {
+        let mut section = match self.target_features()? {
+            Some(section) => section,
+            None => return Ok(()),
+        };
+        let (used, complete) = self.used_features()?;
+        for &feature in DETECTED_FEATURES {
+            match section.get(feature) {
+                Some(FeaturePrefix::Used) if complete && !used.contains(feature) => {
+                    section.remove(feature)
+                }
+                None | Some(FeaturePrefix::Disallowed) if used.contains(feature) => {
+                    section.set(feature, FeaturePrefix::Used)
+                }
+                _ => {}
+            }
+        }
+        self.set_target_features(&section);
+        Ok(())
+    }
+}
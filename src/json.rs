@@ -0,0 +1,18 @@
+//! Writing JSON by hand, for the few outputs that need it.
+
+use crate::prelude::*;
+
+/// `s` as a JSON string literal.
+pub(crate) fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,111 @@
+//! The unsigned LEB128 and string encodings of the binary format, for
+//! the custom sections and profiles waffle reads and writes itself.
+
+use crate::prelude::*;
+use anyhow::{bail, Result};
+use core::convert::TryFrom;
+
+/// Append `value` as an unsigned LEB128.
+pub(crate) fn write_u32(out: &mut Vec<u8>, value: u32) {
+    write_u64(out, value.into());
+}
+
+pub(crate) fn write_u64(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// `value` as an unsigned LEB128 padded to five bytes, the most a `u32`
+/// takes, so that room for it can be left before it is known.
+pub(crate) fn padded_u32(value: u32) -> [u8; 5] {
+    let mut bytes = [0; 5];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = ((value >> (7 * i)) & 0x7f) as u8;
+        if i < 4 {
+            *byte |= 0x80;
+        }
+    }
+    bytes
+}
+
+/// Append `s`, prefixed with its length.
+pub(crate) fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Reads what the `write_*` functions write.
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    pub(crate) fn eof(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8> {
+        match self.data.get(self.pos) {
+            Some(&byte) => {
+                self.pos += 1;
+                Ok(byte)
+            }
+            None => bail!("Unexpected end of data at offset {}", self.pos),
+        }
+    }
+
+    pub(crate) fn read_var_u64(&mut self) -> Result<u64> {
+        let start = self.pos;
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            if shift == 63 && byte > 1 {
+                break;
+            }
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("Invalid LEB128 at offset {}", start)
+    }
+
+    pub(crate) fn read_var_u32(&mut self) -> Result<u32> {
+        let start = self.pos;
+        match u32::try_from(self.read_var_u64()?) {
+            Ok(value) => Ok(value),
+            Err(_) => bail!("Invalid LEB128 at offset {}", start),
+        }
+    }
+
+    pub(crate) fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        match self.data.get(self.pos..).and_then(|rest| rest.get(..len)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => bail!("Unexpected end of data at offset {}", self.pos),
+        }
+    }
+
+    pub(crate) fn read_string(&mut self) -> Result<&'a str> {
+        let len = self.read_var_u32()? as usize;
+        let start = self.pos;
+        match core::str::from_utf8(self.read_bytes(len)?) {
+            Ok(s) => Ok(s),
+            Err(_) => bail!("Invalid UTF-8 at offset {}", start),
+        }
+    }
+}
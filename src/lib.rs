@@ -1,28 +1,78 @@
 //! WAFFLE Wasm analysis framework.
+//!
+//! Without the `std` feature, the crate is `no_std` and needs only
+//! `alloc`. The parser, encoder and rayon, behind `frontend`, `backend`
+//! and `parallel`, need `std` and imply it; the IR, analyses, passes and
+//! interpreter do not. Without it, hash maps are `hashbrown`'s, the
+//! warnings and `Timeline` sit behind a `RefCell` rather than a `Mutex`,
+//! and pass timings are zero.
 
+#![cfg_attr(not(feature = "std"), no_std)]
 #![allow(dead_code)]
 
+#[macro_use]
+extern crate alloc;
+
 // Re-export wasmparser for easier use of the right version by our embedders.
+#[cfg(feature = "frontend")]
 pub use wasmparser;
 
+#[macro_use]
+mod spans;
+
+pub mod analysis;
+#[cfg(feature = "backend")]
 mod backend;
 pub mod cfg;
+mod collections;
 pub mod entity;
 mod errors;
+#[cfg(feature = "frontend")]
 mod frontend;
 mod ir;
+mod json;
+mod leb128;
 mod op_traits;
+mod op_visitor;
 mod ops;
 pub mod passes;
 pub mod pool;
+mod prelude;
 mod scoped_map;
+#[cfg(all(feature = "frontend", feature = "backend"))]
+pub mod stream;
+mod sync;
+mod timeline;
 
+#[cfg(feature = "wat")]
+pub use backend::WatEncoder;
+#[cfg(feature = "backend")]
+pub use backend::{
+    AllocStrategy, BinaryEncoder, CodegenOptions, FuncSize, FunctionSink, ModuleEncoder,
+    NamePolicy, SectionSize, SizeProfile, SourceMap, SourceMapping, Structuring, WriterEncoder,
+};
 pub use errors::*;
 pub use ir::*;
+pub use op_visitor::OperatorVisitor;
 pub use ops::{Ieee32, Ieee64, MemoryArg, Operator};
+pub use timeline::{Timeline, TimelineSpan, TraceEvent};
 
 mod interp;
 pub use interp::*;
 
+pub mod generate;
+pub mod mutate;
+#[cfg(all(feature = "frontend", feature = "backend"))]
+pub mod reduce;
+#[cfg(all(feature = "frontend", feature = "backend"))]
+pub mod report;
+
 #[cfg(feature = "fuzzing")]
 pub mod fuzzing;
+
+mod gen;
+#[cfg(feature = "proptest")]
+pub mod strategies;
+
+#[cfg(feature = "differential")]
+pub mod differential;
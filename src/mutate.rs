@@ -0,0 +1,509 @@
+//! Mutating modules at the IR level, e.g. to fuzz other Wasm engines
+//! with inputs derived from a corpus.
+//!
+//! A `Mutator` applies randomly chosen `Mutation`s to randomly chosen
+//! places in function bodies. Every mutation keeps the body valid;
+//! some also keep its behavior the same, so that an engine given the
+//! original and the mutated module can be checked to agree on them.
+
+use crate::gen;
+use crate::interp::diff::Rng;
+use crate::ir::*;
+use crate::pool::ListRef;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// Where a `Mutator` draws its random choices from.
+pub trait MutationRng {
+    fn next_u64(&mut self) -> u64;
+
+    /// A number below `n`, which must not be zero.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// The built-in generator: the same seed gives the same mutations.
+pub struct SeededRng(Rng);
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        // An odd multiple of an odd number, so that seeds below 2^63
+        // give distinct, and from the start unrelated, sequences.
+        SeededRng(Rng::new(
+            (seed << 1 | 1).wrapping_mul(0x9e37_79b9_7f4a_7c15),
+        ))
+    }
+}
+
+impl MutationRng for SeededRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0.next()
+    }
+}
+
+/// One kind of change to a function body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mutation {
+    /// Swap the operands of a commutative integer operator.
+    SwapOperands,
+    /// Pass an integer operand through an identity, e.g. `x + 0`.
+    AddIdentity,
+    /// Split a block in two, joined by a branch.
+    SplitBlock,
+    /// Negate a conditional branch's condition and swap its targets.
+    InvertBranch,
+    /// Replace an operator with another of the same type, e.g.
+    /// `i32.add` with `i32.sub`.
+    ReplaceOperator,
+    /// Change the value of a constant.
+    ChangeConstant,
+    /// Replace an operand with another value of its type defined
+    /// before it in its block.
+    ReplaceOperand,
+    /// Swap a conditional branch's targets.
+    SwapBranchTargets,
+}
+
+impl Mutation {
+    pub const ALL: &'static [Mutation] = &[
+        Mutation::SwapOperands,
+        Mutation::AddIdentity,
+        Mutation::SplitBlock,
+        Mutation::InvertBranch,
+        Mutation::ReplaceOperator,
+        Mutation::ChangeConstant,
+        Mutation::ReplaceOperand,
+        Mutation::SwapBranchTargets,
+    ];
+
+    /// Whether the mutated body always behaves as the original does.
+    pub fn preserves_semantics(self) -> bool {
+        matches!(
+            self,
+            Mutation::SwapOperands
+                | Mutation::AddIdentity
+                | Mutation::SplitBlock
+                | Mutation::InvertBranch
+        )
+    }
+}
+
+impl core::fmt::Display for Mutation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let name = match self {
+            Mutation::SwapOperands => "swap-operands",
+            Mutation::AddIdentity => "add-identity",
+            Mutation::SplitBlock => "split-block",
+            Mutation::InvertBranch => "invert-branch",
+            Mutation::ReplaceOperator => "replace-operator",
+            Mutation::ChangeConstant => "change-constant",
+            Mutation::ReplaceOperand => "replace-operand",
+            Mutation::SwapBranchTargets => "swap-branch-targets",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Applies random mutations. For example, to make ten
+/// behavior-preserving changes:
+///
+/// ```ignore
+/// let mut mutator = Mutator::new(seed).preserving();
+/// let applied = mutator.mutate(&mut module, 10)?;
+/// ```
+pub struct Mutator<R: MutationRng = SeededRng> {
+    rng: R,
+    /// The mutations to choose from: all of them by default.
+    pub mutations: Vec<Mutation>,
+}
+
+impl Mutator<SeededRng> {
+    pub fn new(seed: u64) -> Mutator<SeededRng> {
+        Mutator::with_rng(SeededRng::new(seed))
+    }
+}
+
+impl<R: MutationRng> Mutator<R> {
+    pub fn with_rng(rng: R) -> Mutator<R> {
+        Mutator {
+            rng,
+            mutations: Mutation::ALL.to_vec(),
+        }
+    }
+
+    /// Choose only mutations that preserve semantics.
+    pub fn preserving(mut self) -> Self {
+        self.mutations
+            .retain(|mutation| mutation.preserves_semantics());
+        self
+    }
+
+    /// Apply one mutation to `body`, trying each kind, from a random
+    /// one on, until one applies. Returns it, or `None` if none can.
+    pub fn mutate_body(&mut self, body: &mut FunctionBody) -> Option<Mutation> {
+        if self.mutations.is_empty() {
+            return None;
+        }
+        let start = self.rng.below(self.mutations.len());
+        for i in 0..self.mutations.len() {
+            let mutation = self.mutations[(start + i) % self.mutations.len()];
+            if self.apply(mutation, body) {
+                log::trace!("mutate: applied {}", mutation);
+                return Some(mutation);
+            }
+        }
+        None
+    }
+
+    /// Apply `count` mutations, each to a random defined function of
+    /// `module`, parsing bodies as needed. Returns the mutations
+    /// applied: fewer if no body can be mutated.
+    pub fn mutate(
+        &mut self,
+        module: &mut Module<'_>,
+        count: usize,
+    ) -> Result<Vec<(Func, Mutation)>> {
+        let mut funcs = module
+            .funcs
+            .entries()
+            .filter(|(_, decl)| matches!(decl, FuncDecl::Body(..) | FuncDecl::Lazy(..)))
+            .map(|(func, _)| func)
+            .collect::<Vec<_>>();
+        let mut applied = vec![];
+        while applied.len() < count && !funcs.is_empty() {
+            let index = self.rng.below(funcs.len());
+            let func = funcs[index];
+            let mutation = match module.func_body(func)? {
+                Some(body) => self.mutate_body(body),
+                None => None,
+            };
+            match mutation {
+                Some(mutation) => applied.push((func, mutation)),
+                // Nothing applies to this body, nor will it later.
+                None => {
+                    funcs.swap_remove(index);
+                }
+            }
+        }
+        Ok(applied)
+    }
+
+    fn pick<T: Copy>(&mut self, candidates: &[T]) -> Option<T> {
+        match candidates.len() {
+            0 => None,
+            n => Some(candidates[self.rng.below(n)]),
+        }
+    }
+
+    fn apply(&mut self, mutation: Mutation, body: &mut FunctionBody) -> bool {
+        match mutation {
+            Mutation::SwapOperands => {
+                let insts = placed_insts(body, |body, inst| match &body.values[inst] {
+                    ValueDef::Operator(op, _, _) => is_commutative(op),
+                    _ => false,
+                });
+                let inst = match self.pick(&insts) {
+                    Some((_, _, inst)) => inst,
+                    None => return false,
+                };
+                let args = own_args(body, inst);
+                body.arg_pool[args].swap(0, 1);
+                true
+            }
+            Mutation::AddIdentity => {
+                let uses = integer_uses(body);
+                let (block, pos, inst, arg) = match self.pick(&uses) {
+                    Some(found) => found,
+                    None => return false,
+                };
+                let value = operand(body, inst, arg);
+                let ty = int_type(body, value).unwrap();
+                let (op, identity) = match (ty, self.rng.below(3)) {
+                    (Type::I32, 0) => (Operator::I32Add, 0),
+                    (Type::I32, 1) => (Operator::I32Xor, 0),
+                    (Type::I32, _) => (Operator::I32Mul, 1),
+                    (_, 0) => (Operator::I64Add, 0),
+                    (_, 1) => (Operator::I64Xor, 0),
+                    (_, _) => (Operator::I64Mul, 1),
+                };
+                let constant = insert_const(body, block, pos, ty, identity);
+                let args = body.arg_pool.double(value, constant);
+                let tys = body.single_type_list(ty);
+                let new = insert(body, block, pos + 1, ValueDef::Operator(op, args, tys));
+                set_operand(body, inst, arg, new);
+                true
+            }
+            Mutation::SplitBlock => {
+                let blocks = body.blocks.iter().collect::<Vec<_>>();
+                let block = match self.pick(&blocks) {
+                    Some(block) => block,
+                    None => return false,
+                };
+                let at = self.rng.below(body.blocks[block].insts.len() + 1);
+                let new = body.split_block(block, at);
+                body.set_terminator(
+                    block,
+                    Terminator::Br {
+                        target: BlockTarget {
+                            block: new,
+                            args: vec![],
+                        },
+                    },
+                );
+                true
+            }
+            Mutation::InvertBranch | Mutation::SwapBranchTargets => {
+                let branches = body
+                    .blocks
+                    .entries()
+                    .filter(|(_, def)| match &def.terminator {
+                        Terminator::CondBr {
+                            if_true, if_false, ..
+                        } => if_true != if_false,
+                        _ => false,
+                    })
+                    .map(|(block, _)| block)
+                    .collect::<Vec<_>>();
+                let block = match self.pick(&branches) {
+                    Some(block) => block,
+                    None => return false,
+                };
+                let negated = match (mutation, &body.blocks[block].terminator) {
+                    (Mutation::InvertBranch, &Terminator::CondBr { cond, .. }) => {
+                        let args = body.arg_pool.single(cond);
+                        let tys = body.single_type_list(Type::I32);
+                        let def = ValueDef::Operator(Operator::I32Eqz, args, tys);
+                        let pos = body.blocks[block].insts.len();
+                        Some(insert(body, block, pos, def))
+                    }
+                    _ => None,
+                };
+                if let Terminator::CondBr {
+                    cond,
+                    if_true,
+                    if_false,
+                } = &mut body.blocks[block].terminator
+                {
+                    core::mem::swap(if_true, if_false);
+                    if let Some(negated) = negated {
+                        *cond = negated;
+                    }
+                }
+                body.recompute_edges();
+                true
+            }
+            Mutation::ReplaceOperator => {
+                let insts = placed_insts(body, |body, inst| match &body.values[inst] {
+                    ValueDef::Operator(op, _, _) => same_type_ops(op).len() > 1,
+                    _ => false,
+                });
+                let inst = match self.pick(&insts) {
+                    Some((_, _, inst)) => inst,
+                    None => return false,
+                };
+                if let ValueDef::Operator(op, _, _) = &mut body.values[inst] {
+                    let others = same_type_ops(op)
+                        .into_iter()
+                        .filter(|other| other != op)
+                        .collect::<Vec<_>>();
+                    *op = others[self.rng.below(others.len())];
+                }
+                true
+            }
+            Mutation::ChangeConstant => {
+                let insts = placed_insts(body, |body, inst| {
+                    matches!(
+                        &body.values[inst],
+                        ValueDef::Operator(
+                            Operator::I32Const { .. }
+                                | Operator::I64Const { .. }
+                                | Operator::F32Const { .. }
+                                | Operator::F64Const { .. },
+                            _,
+                            _
+                        )
+                    )
+                });
+                let inst = match self.pick(&insts) {
+                    Some((_, _, inst)) => inst,
+                    None => return false,
+                };
+                // Flip one bit, or replace the value altogether; either
+                // way it changes.
+                let bits = match self.rng.below(2) {
+                    0 => 1 << self.rng.below(64),
+                    _ => self.rng.next_u64() | 1,
+                };
+                if let ValueDef::Operator(op, _, _) = &mut body.values[inst] {
+                    match op {
+                        Operator::I32Const { value } | Operator::F32Const { value } => {
+                            *value ^= (bits as u32).max(1)
+                        }
+                        Operator::I64Const { value } | Operator::F64Const { value } => {
+                            *value ^= bits
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                true
+            }
+            Mutation::ReplaceOperand => {
+                let mut candidates = vec![];
+                for (block, pos, inst) in placed_insts(body, |_, _| true) {
+                    let args = match &body.values[inst] {
+                        ValueDef::Operator(_, args, _) => &body.arg_pool[*args],
+                        _ => continue,
+                    };
+                    for (arg, &value) in args.iter().enumerate() {
+                        let value = body.resolve_alias(value);
+                        let ty = match single_type(body, value) {
+                            Some(ty) => ty,
+                            None => continue,
+                        };
+                        let defs = body.blocks[block].params.iter().map(|&(_, param)| param);
+                        let earlier = body.blocks[block].insts[..pos].iter().copied();
+                        for other in defs.chain(earlier) {
+                            if other != value && single_type(body, other) == Some(ty) {
+                                candidates.push((inst, arg, other));
+                            }
+                        }
+                    }
+                }
+                let (inst, arg, other) = match self.pick(&candidates) {
+                    Some(found) => found,
+                    None => return false,
+                };
+                set_operand(body, inst, arg, other);
+                true
+            }
+        }
+    }
+}
+
+/// The instructions placed in blocks that satisfy `filter`, with their
+/// block and position in it.
+fn placed_insts(
+    body: &FunctionBody,
+    filter: impl Fn(&FunctionBody, Value) -> bool,
+) -> Vec<(Block, usize, Value)> {
+    body.blocks
+        .entries()
+        .flat_map(|(block, def)| {
+            def.insts
+                .iter()
+                .enumerate()
+                .map(move |(pos, &inst)| (block, pos, inst))
+        })
+        .filter(|&(_, _, inst)| filter(body, inst))
+        .collect()
+}
+
+/// Each integer operand of a placed operator: its block, position,
+/// instruction and operand index.
+fn integer_uses(body: &FunctionBody) -> Vec<(Block, usize, Value, usize)> {
+    let mut uses = vec![];
+    for (block, pos, inst) in placed_insts(body, |_, _| true) {
+        if let ValueDef::Operator(_, args, _) = &body.values[inst] {
+            for (arg, &value) in body.arg_pool[*args].iter().enumerate() {
+                if int_type(body, value).is_some() {
+                    uses.push((block, pos, inst, arg));
+                }
+            }
+        }
+    }
+    uses
+}
+
+/// The type of `value`, if it is a single value.
+fn single_type(body: &FunctionBody, value: Value) -> Option<Type> {
+    match &body.values[value] {
+        &ValueDef::BlockParam(_, _, ty) | &ValueDef::PickOutput(_, _, ty) => Some(ty),
+        ValueDef::Operator(_, _, tys) if tys.len() == 1 => Some(body.type_pool[*tys][0]),
+        ValueDef::Alias(to) => single_type(body, *to),
+        _ => None,
+    }
+}
+
+fn int_type(body: &FunctionBody, value: Value) -> Option<Type> {
+    single_type(body, value).filter(|ty| matches!(ty, Type::I32 | Type::I64))
+}
+
+fn operand(body: &FunctionBody, inst: Value, arg: usize) -> Value {
+    match &body.values[inst] {
+        ValueDef::Operator(_, args, _) => body.arg_pool[*args][arg],
+        _ => unreachable!(),
+    }
+}
+
+/// Give `inst` its own copy of its operand list, which it may share
+/// with other instructions, and return it.
+fn own_args(body: &mut FunctionBody, inst: Value) -> ListRef<Value> {
+    let args = match &body.values[inst] {
+        ValueDef::Operator(_, args, _) => *args,
+        _ => unreachable!(),
+    };
+    let copy = body.arg_pool.deep_clone(args);
+    if let ValueDef::Operator(_, args, _) = &mut body.values[inst] {
+        *args = copy;
+    }
+    copy
+}
+
+fn set_operand(body: &mut FunctionBody, inst: Value, arg: usize, value: Value) {
+    let args = own_args(body, inst);
+    body.arg_pool[args][arg] = value;
+}
+
+/// Add `def` to `block` as its `pos`th instruction.
+fn insert(body: &mut FunctionBody, block: Block, pos: usize, def: ValueDef) -> Value {
+    let value = body.add_value(def);
+    body.blocks[block].insts.insert(pos, value);
+    body.value_blocks[value] = block;
+    value
+}
+
+fn insert_const(body: &mut FunctionBody, block: Block, pos: usize, ty: Type, bits: u64) -> Value {
+    let op = match ty {
+        Type::I32 => Operator::I32Const { value: bits as u32 },
+        _ => Operator::I64Const { value: bits },
+    };
+    let args = body.arg_pool.from_iter(core::iter::empty());
+    let tys = body.single_type_list(ty);
+    insert(body, block, pos, ValueDef::Operator(op, args, tys))
+}
+
+fn is_commutative(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::I32Add
+            | Operator::I32Mul
+            | Operator::I32And
+            | Operator::I32Or
+            | Operator::I32Xor
+            | Operator::I32Eq
+            | Operator::I32Ne
+            | Operator::I64Add
+            | Operator::I64Mul
+            | Operator::I64And
+            | Operator::I64Or
+            | Operator::I64Xor
+            | Operator::I64Eq
+            | Operator::I64Ne
+    )
+}
+
+/// The operators of the generators' set with the same operand and
+/// result types as `op`, including `op`; empty if it is not in the set.
+fn same_type_ops(op: &Operator) -> Vec<Operator> {
+    let (args, result) = match gen::OPS.iter().find(|(other, _, _)| other == op) {
+        Some((_, args, result)) => (*args, *result),
+        None => return vec![],
+    };
+    gen::OPS
+        .iter()
+        .filter(|(_, other_args, other_result)| *other_args == args && *other_result == result)
+        .map(|&(op, _, _)| op)
+        .collect()
+}
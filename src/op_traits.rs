@@ -1,9 +1,11 @@
 //! Metadata on operators.
 
+use crate::errors::WaffleError;
 use crate::ir::{Module, Type, Value};
+use crate::prelude::*;
 use crate::Operator;
+use alloc::borrow::Cow;
 use anyhow::Result;
-use std::borrow::Cow;
 
 pub fn op_inputs(
     module: &Module,
@@ -24,7 +26,7 @@ pub fn op_inputs(
         }
 
         &Operator::Select => {
-            let val_ty = op_stack[op_stack.len() - 2].0;
+            let val_ty = select_ty(op_stack)?;
             Ok(vec![val_ty, val_ty, Type::I32].into())
         }
         &Operator::TypedSelect { ty } => Ok(vec![ty, ty, Type::I32].into()),
@@ -221,6 +223,80 @@ pub fn op_inputs(
         Operator::TableSize { .. } => Ok(Cow::Borrowed(&[])),
         Operator::MemorySize { .. } => Ok(Cow::Borrowed(&[])),
         Operator::MemoryGrow { .. } => Ok(Cow::Borrowed(&[Type::I32])),
+
+        Operator::MemoryAtomicNotify { .. }
+        | Operator::I32AtomicStore { .. }
+        | Operator::I32AtomicStore8 { .. }
+        | Operator::I32AtomicStore16 { .. }
+        | Operator::I32AtomicRmwAdd { .. }
+        | Operator::I32AtomicRmw8AddU { .. }
+        | Operator::I32AtomicRmw16AddU { .. }
+        | Operator::I32AtomicRmwSub { .. }
+        | Operator::I32AtomicRmw8SubU { .. }
+        | Operator::I32AtomicRmw16SubU { .. }
+        | Operator::I32AtomicRmwAnd { .. }
+        | Operator::I32AtomicRmw8AndU { .. }
+        | Operator::I32AtomicRmw16AndU { .. }
+        | Operator::I32AtomicRmwOr { .. }
+        | Operator::I32AtomicRmw8OrU { .. }
+        | Operator::I32AtomicRmw16OrU { .. }
+        | Operator::I32AtomicRmwXor { .. }
+        | Operator::I32AtomicRmw8XorU { .. }
+        | Operator::I32AtomicRmw16XorU { .. }
+        | Operator::I32AtomicRmwXchg { .. }
+        | Operator::I32AtomicRmw8XchgU { .. }
+        | Operator::I32AtomicRmw16XchgU { .. } => Ok(Cow::Borrowed(&[Type::I32, Type::I32])),
+        Operator::MemoryAtomicWait32 { .. } => {
+            Ok(Cow::Borrowed(&[Type::I32, Type::I32, Type::I64]))
+        }
+        Operator::MemoryAtomicWait64 { .. }
+        | Operator::I64AtomicRmwCmpxchg { .. }
+        | Operator::I64AtomicRmw8CmpxchgU { .. }
+        | Operator::I64AtomicRmw16CmpxchgU { .. }
+        | Operator::I64AtomicRmw32CmpxchgU { .. } => {
+            Ok(Cow::Borrowed(&[Type::I32, Type::I64, Type::I64]))
+        }
+        Operator::AtomicFence => Ok(Cow::Borrowed(&[])),
+        Operator::I32AtomicLoad { .. }
+        | Operator::I64AtomicLoad { .. }
+        | Operator::I32AtomicLoad8U { .. }
+        | Operator::I32AtomicLoad16U { .. }
+        | Operator::I64AtomicLoad8U { .. }
+        | Operator::I64AtomicLoad16U { .. }
+        | Operator::I64AtomicLoad32U { .. } => Ok(Cow::Borrowed(&[Type::I32])),
+        Operator::I64AtomicStore { .. }
+        | Operator::I64AtomicStore8 { .. }
+        | Operator::I64AtomicStore16 { .. }
+        | Operator::I64AtomicStore32 { .. }
+        | Operator::I64AtomicRmwAdd { .. }
+        | Operator::I64AtomicRmw8AddU { .. }
+        | Operator::I64AtomicRmw16AddU { .. }
+        | Operator::I64AtomicRmw32AddU { .. }
+        | Operator::I64AtomicRmwSub { .. }
+        | Operator::I64AtomicRmw8SubU { .. }
+        | Operator::I64AtomicRmw16SubU { .. }
+        | Operator::I64AtomicRmw32SubU { .. }
+        | Operator::I64AtomicRmwAnd { .. }
+        | Operator::I64AtomicRmw8AndU { .. }
+        | Operator::I64AtomicRmw16AndU { .. }
+        | Operator::I64AtomicRmw32AndU { .. }
+        | Operator::I64AtomicRmwOr { .. }
+        | Operator::I64AtomicRmw8OrU { .. }
+        | Operator::I64AtomicRmw16OrU { .. }
+        | Operator::I64AtomicRmw32OrU { .. }
+        | Operator::I64AtomicRmwXor { .. }
+        | Operator::I64AtomicRmw8XorU { .. }
+        | Operator::I64AtomicRmw16XorU { .. }
+        | Operator::I64AtomicRmw32XorU { .. }
+        | Operator::I64AtomicRmwXchg { .. }
+        | Operator::I64AtomicRmw8XchgU { .. }
+        | Operator::I64AtomicRmw16XchgU { .. }
+        | Operator::I64AtomicRmw32XchgU { .. } => Ok(Cow::Borrowed(&[Type::I32, Type::I64])),
+        Operator::I32AtomicRmwCmpxchg { .. }
+        | Operator::I32AtomicRmw8CmpxchgU { .. }
+        | Operator::I32AtomicRmw16CmpxchgU { .. } => {
+            Ok(Cow::Borrowed(&[Type::I32, Type::I32, Type::I32]))
+        }
     }
 }
 
@@ -241,7 +317,7 @@ pub fn op_outputs(
         }
 
         &Operator::Select => {
-            let val_ty = op_stack[op_stack.len() - 2].0;
+            let val_ty = select_ty(op_stack)?;
             Ok(vec![val_ty].into())
         }
         &Operator::TypedSelect { ty } => Ok(vec![ty].into()),
@@ -425,6 +501,74 @@ pub fn op_outputs(
         Operator::TableSize { .. } => Ok(Cow::Borrowed(&[Type::I32])),
         Operator::MemorySize { .. } => Ok(Cow::Borrowed(&[Type::I32])),
         Operator::MemoryGrow { .. } => Ok(Cow::Borrowed(&[Type::I32])),
+
+        Operator::MemoryAtomicNotify { .. }
+        | Operator::MemoryAtomicWait32 { .. }
+        | Operator::MemoryAtomicWait64 { .. }
+        | Operator::I32AtomicLoad { .. }
+        | Operator::I32AtomicLoad8U { .. }
+        | Operator::I32AtomicLoad16U { .. }
+        | Operator::I32AtomicRmwAdd { .. }
+        | Operator::I32AtomicRmw8AddU { .. }
+        | Operator::I32AtomicRmw16AddU { .. }
+        | Operator::I32AtomicRmwSub { .. }
+        | Operator::I32AtomicRmw8SubU { .. }
+        | Operator::I32AtomicRmw16SubU { .. }
+        | Operator::I32AtomicRmwAnd { .. }
+        | Operator::I32AtomicRmw8AndU { .. }
+        | Operator::I32AtomicRmw16AndU { .. }
+        | Operator::I32AtomicRmwOr { .. }
+        | Operator::I32AtomicRmw8OrU { .. }
+        | Operator::I32AtomicRmw16OrU { .. }
+        | Operator::I32AtomicRmwXor { .. }
+        | Operator::I32AtomicRmw8XorU { .. }
+        | Operator::I32AtomicRmw16XorU { .. }
+        | Operator::I32AtomicRmwXchg { .. }
+        | Operator::I32AtomicRmw8XchgU { .. }
+        | Operator::I32AtomicRmw16XchgU { .. }
+        | Operator::I32AtomicRmwCmpxchg { .. }
+        | Operator::I32AtomicRmw8CmpxchgU { .. }
+        | Operator::I32AtomicRmw16CmpxchgU { .. } => Ok(Cow::Borrowed(&[Type::I32])),
+        Operator::AtomicFence
+        | Operator::I32AtomicStore { .. }
+        | Operator::I64AtomicStore { .. }
+        | Operator::I32AtomicStore8 { .. }
+        | Operator::I32AtomicStore16 { .. }
+        | Operator::I64AtomicStore8 { .. }
+        | Operator::I64AtomicStore16 { .. }
+        | Operator::I64AtomicStore32 { .. } => Ok(Cow::Borrowed(&[])),
+        Operator::I64AtomicLoad { .. }
+        | Operator::I64AtomicLoad8U { .. }
+        | Operator::I64AtomicLoad16U { .. }
+        | Operator::I64AtomicLoad32U { .. }
+        | Operator::I64AtomicRmwAdd { .. }
+        | Operator::I64AtomicRmw8AddU { .. }
+        | Operator::I64AtomicRmw16AddU { .. }
+        | Operator::I64AtomicRmw32AddU { .. }
+        | Operator::I64AtomicRmwSub { .. }
+        | Operator::I64AtomicRmw8SubU { .. }
+        | Operator::I64AtomicRmw16SubU { .. }
+        | Operator::I64AtomicRmw32SubU { .. }
+        | Operator::I64AtomicRmwAnd { .. }
+        | Operator::I64AtomicRmw8AndU { .. }
+        | Operator::I64AtomicRmw16AndU { .. }
+        | Operator::I64AtomicRmw32AndU { .. }
+        | Operator::I64AtomicRmwOr { .. }
+        | Operator::I64AtomicRmw8OrU { .. }
+        | Operator::I64AtomicRmw16OrU { .. }
+        | Operator::I64AtomicRmw32OrU { .. }
+        | Operator::I64AtomicRmwXor { .. }
+        | Operator::I64AtomicRmw8XorU { .. }
+        | Operator::I64AtomicRmw16XorU { .. }
+        | Operator::I64AtomicRmw32XorU { .. }
+        | Operator::I64AtomicRmwXchg { .. }
+        | Operator::I64AtomicRmw8XchgU { .. }
+        | Operator::I64AtomicRmw16XchgU { .. }
+        | Operator::I64AtomicRmw32XchgU { .. }
+        | Operator::I64AtomicRmwCmpxchg { .. }
+        | Operator::I64AtomicRmw8CmpxchgU { .. }
+        | Operator::I64AtomicRmw16CmpxchgU { .. }
+        | Operator::I64AtomicRmw32CmpxchgU { .. } => Ok(Cow::Borrowed(&[Type::I64])),
     }
 }
 
@@ -635,6 +779,78 @@ impl Operator {
             Operator::TableSize { .. } => &[ReadTable],
             Operator::MemorySize { .. } => &[ReadMem],
             Operator::MemoryGrow { .. } => &[WriteMem, Trap],
+
+            // Atomics order the accesses of other threads, so they
+            // read and write all of memory as far as any analysis is
+            // concerned: nothing that accesses memory moves across
+            // them, and none is removed or merged with another.
+            Operator::AtomicFence => &[ReadMem, WriteMem],
+            Operator::MemoryAtomicNotify { .. }
+            | Operator::MemoryAtomicWait32 { .. }
+            | Operator::MemoryAtomicWait64 { .. }
+            | Operator::I32AtomicLoad { .. }
+            | Operator::I64AtomicLoad { .. }
+            | Operator::I32AtomicLoad8U { .. }
+            | Operator::I32AtomicLoad16U { .. }
+            | Operator::I64AtomicLoad8U { .. }
+            | Operator::I64AtomicLoad16U { .. }
+            | Operator::I64AtomicLoad32U { .. }
+            | Operator::I32AtomicStore { .. }
+            | Operator::I64AtomicStore { .. }
+            | Operator::I32AtomicStore8 { .. }
+            | Operator::I32AtomicStore16 { .. }
+            | Operator::I64AtomicStore8 { .. }
+            | Operator::I64AtomicStore16 { .. }
+            | Operator::I64AtomicStore32 { .. }
+            | Operator::I32AtomicRmwAdd { .. }
+            | Operator::I64AtomicRmwAdd { .. }
+            | Operator::I32AtomicRmw8AddU { .. }
+            | Operator::I32AtomicRmw16AddU { .. }
+            | Operator::I64AtomicRmw8AddU { .. }
+            | Operator::I64AtomicRmw16AddU { .. }
+            | Operator::I64AtomicRmw32AddU { .. }
+            | Operator::I32AtomicRmwSub { .. }
+            | Operator::I64AtomicRmwSub { .. }
+            | Operator::I32AtomicRmw8SubU { .. }
+            | Operator::I32AtomicRmw16SubU { .. }
+            | Operator::I64AtomicRmw8SubU { .. }
+            | Operator::I64AtomicRmw16SubU { .. }
+            | Operator::I64AtomicRmw32SubU { .. }
+            | Operator::I32AtomicRmwAnd { .. }
+            | Operator::I64AtomicRmwAnd { .. }
+            | Operator::I32AtomicRmw8AndU { .. }
+            | Operator::I32AtomicRmw16AndU { .. }
+            | Operator::I64AtomicRmw8AndU { .. }
+            | Operator::I64AtomicRmw16AndU { .. }
+            | Operator::I64AtomicRmw32AndU { .. }
+            | Operator::I32AtomicRmwOr { .. }
+            | Operator::I64AtomicRmwOr { .. }
+            | Operator::I32AtomicRmw8OrU { .. }
+            | Operator::I32AtomicRmw16OrU { .. }
+            | Operator::I64AtomicRmw8OrU { .. }
+            | Operator::I64AtomicRmw16OrU { .. }
+            | Operator::I64AtomicRmw32OrU { .. }
+            | Operator::I32AtomicRmwXor { .. }
+            | Operator::I64AtomicRmwXor { .. }
+            | Operator::I32AtomicRmw8XorU { .. }
+            | Operator::I32AtomicRmw16XorU { .. }
+            | Operator::I64AtomicRmw8XorU { .. }
+            | Operator::I64AtomicRmw16XorU { .. }
+            | Operator::I64AtomicRmw32XorU { .. }
+            | Operator::I32AtomicRmwXchg { .. }
+            | Operator::I64AtomicRmwXchg { .. }
+            | Operator::I32AtomicRmw8XchgU { .. }
+            | Operator::I32AtomicRmw16XchgU { .. }
+            | Operator::I64AtomicRmw8XchgU { .. }
+            | Operator::I64AtomicRmw16XchgU { .. }
+            | Operator::I64AtomicRmw32XchgU { .. }
+            | Operator::I32AtomicRmwCmpxchg { .. }
+            | Operator::I64AtomicRmwCmpxchg { .. }
+            | Operator::I32AtomicRmw8CmpxchgU { .. }
+            | Operator::I32AtomicRmw16CmpxchgU { .. }
+            | Operator::I64AtomicRmw8CmpxchgU { .. }
+            | Operator::I64AtomicRmw16CmpxchgU { .. }
+            | Operator::I64AtomicRmw32CmpxchgU { .. } => &[Trap, ReadMem, WriteMem],
         }
     }
 
@@ -649,6 +865,81 @@ impl Operator {
         }
     }
 
+    /// Whether the operator is an atomic access, a wait or notify, or
+    /// a fence: one that orders memory accesses across threads.
+    pub fn is_atomic(&self) -> bool {
+        matches!(
+            self,
+            Operator::MemoryAtomicNotify { .. }
+                | Operator::MemoryAtomicWait32 { .. }
+                | Operator::MemoryAtomicWait64 { .. }
+                | Operator::AtomicFence
+                | Operator::I32AtomicLoad { .. }
+                | Operator::I64AtomicLoad { .. }
+                | Operator::I32AtomicLoad8U { .. }
+                | Operator::I32AtomicLoad16U { .. }
+                | Operator::I64AtomicLoad8U { .. }
+                | Operator::I64AtomicLoad16U { .. }
+                | Operator::I64AtomicLoad32U { .. }
+                | Operator::I32AtomicStore { .. }
+                | Operator::I64AtomicStore { .. }
+                | Operator::I32AtomicStore8 { .. }
+                | Operator::I32AtomicStore16 { .. }
+                | Operator::I64AtomicStore8 { .. }
+                | Operator::I64AtomicStore16 { .. }
+                | Operator::I64AtomicStore32 { .. }
+                | Operator::I32AtomicRmwAdd { .. }
+                | Operator::I64AtomicRmwAdd { .. }
+                | Operator::I32AtomicRmw8AddU { .. }
+                | Operator::I32AtomicRmw16AddU { .. }
+                | Operator::I64AtomicRmw8AddU { .. }
+                | Operator::I64AtomicRmw16AddU { .. }
+                | Operator::I64AtomicRmw32AddU { .. }
+                | Operator::I32AtomicRmwSub { .. }
+                | Operator::I64AtomicRmwSub { .. }
+                | Operator::I32AtomicRmw8SubU { .. }
+                | Operator::I32AtomicRmw16SubU { .. }
+                | Operator::I64AtomicRmw8SubU { .. }
+                | Operator::I64AtomicRmw16SubU { .. }
+                | Operator::I64AtomicRmw32SubU { .. }
+                | Operator::I32AtomicRmwAnd { .. }
+                | Operator::I64AtomicRmwAnd { .. }
+                | Operator::I32AtomicRmw8AndU { .. }
+                | Operator::I32AtomicRmw16AndU { .. }
+                | Operator::I64AtomicRmw8AndU { .. }
+                | Operator::I64AtomicRmw16AndU { .. }
+                | Operator::I64AtomicRmw32AndU { .. }
+                | Operator::I32AtomicRmwOr { .. }
+                | Operator::I64AtomicRmwOr { .. }
+                | Operator::I32AtomicRmw8OrU { .. }
+                | Operator::I32AtomicRmw16OrU { .. }
+                | Operator::I64AtomicRmw8OrU { .. }
+                | Operator::I64AtomicRmw16OrU { .. }
+                | Operator::I64AtomicRmw32OrU { .. }
+                | Operator::I32AtomicRmwXor { .. }
+                | Operator::I64AtomicRmwXor { .. }
+                | Operator::I32AtomicRmw8XorU { .. }
+                | Operator::I32AtomicRmw16XorU { .. }
+                | Operator::I64AtomicRmw8XorU { .. }
+                | Operator::I64AtomicRmw16XorU { .. }
+                | Operator::I64AtomicRmw32XorU { .. }
+                | Operator::I32AtomicRmwXchg { .. }
+                | Operator::I64AtomicRmwXchg { .. }
+                | Operator::I32AtomicRmw8XchgU { .. }
+                | Operator::I32AtomicRmw16XchgU { .. }
+                | Operator::I64AtomicRmw8XchgU { .. }
+                | Operator::I64AtomicRmw16XchgU { .. }
+                | Operator::I64AtomicRmw32XchgU { .. }
+                | Operator::I32AtomicRmwCmpxchg { .. }
+                | Operator::I64AtomicRmwCmpxchg { .. }
+                | Operator::I32AtomicRmw8CmpxchgU { .. }
+                | Operator::I32AtomicRmw16CmpxchgU { .. }
+                | Operator::I64AtomicRmw8CmpxchgU { .. }
+                | Operator::I64AtomicRmw16CmpxchgU { .. }
+                | Operator::I64AtomicRmw32CmpxchgU { .. }
+        )
+    }
+
     pub fn accesses_memory(&self) -> bool {
         self.effects().iter().any(|e| match e {
             SideEffect::ReadMem | SideEffect::WriteMem => true,
@@ -657,8 +948,8 @@ impl Operator {
     }
 }
 
-impl std::fmt::Display for Operator {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for Operator {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         match self {
             &Operator::Unreachable => write!(f, "unreachable")?,
             &Operator::Nop => write!(f, "nop")?,
@@ -853,6 +1144,100 @@ impl std::fmt::Display for Operator {
             Operator::TableSize { table_index, .. } => write!(f, "table_size<{}>", table_index)?,
             Operator::MemorySize { mem } => write!(f, "memory_size<{}>", mem)?,
             Operator::MemoryGrow { mem } => write!(f, "memory_grow<{}>", mem)?,
+
+            Operator::MemoryAtomicNotify { memory } => {
+                write!(f, "memory_atomic_notify<{}>", memory)?
+            }
+            Operator::MemoryAtomicWait32 { memory } => {
+                write!(f, "memory_atomic_wait32<{}>", memory)?
+            }
+            Operator::MemoryAtomicWait64 { memory } => {
+                write!(f, "memory_atomic_wait64<{}>", memory)?
+            }
+            Operator::AtomicFence => write!(f, "atomic_fence")?,
+            Operator::I32AtomicLoad { memory } => write!(f, "i32atomicload<{}>", memory)?,
+            Operator::I64AtomicLoad { memory } => write!(f, "i64atomicload<{}>", memory)?,
+            Operator::I32AtomicLoad8U { memory } => write!(f, "i32atomicload8u<{}>", memory)?,
+            Operator::I32AtomicLoad16U { memory } => write!(f, "i32atomicload16u<{}>", memory)?,
+            Operator::I64AtomicLoad8U { memory } => write!(f, "i64atomicload8u<{}>", memory)?,
+            Operator::I64AtomicLoad16U { memory } => write!(f, "i64atomicload16u<{}>", memory)?,
+            Operator::I64AtomicLoad32U { memory } => write!(f, "i64atomicload32u<{}>", memory)?,
+            Operator::I32AtomicStore { memory } => write!(f, "i32atomicstore<{}>", memory)?,
+            Operator::I64AtomicStore { memory } => write!(f, "i64atomicstore<{}>", memory)?,
+            Operator::I32AtomicStore8 { memory } => write!(f, "i32atomicstore8<{}>", memory)?,
+            Operator::I32AtomicStore16 { memory } => write!(f, "i32atomicstore16<{}>", memory)?,
+            Operator::I64AtomicStore8 { memory } => write!(f, "i64atomicstore8<{}>", memory)?,
+            Operator::I64AtomicStore16 { memory } => write!(f, "i64atomicstore16<{}>", memory)?,
+            Operator::I64AtomicStore32 { memory } => write!(f, "i64atomicstore32<{}>", memory)?,
+            Operator::I32AtomicRmwAdd { memory } => write!(f, "i32atomicrmwadd<{}>", memory)?,
+            Operator::I64AtomicRmwAdd { memory } => write!(f, "i64atomicrmwadd<{}>", memory)?,
+            Operator::I32AtomicRmw8AddU { memory } => write!(f, "i32atomicrmw8addu<{}>", memory)?,
+            Operator::I32AtomicRmw16AddU { memory } => write!(f, "i32atomicrmw16addu<{}>", memory)?,
+            Operator::I64AtomicRmw8AddU { memory } => write!(f, "i64atomicrmw8addu<{}>", memory)?,
+            Operator::I64AtomicRmw16AddU { memory } => write!(f, "i64atomicrmw16addu<{}>", memory)?,
+            Operator::I64AtomicRmw32AddU { memory } => write!(f, "i64atomicrmw32addu<{}>", memory)?,
+            Operator::I32AtomicRmwSub { memory } => write!(f, "i32atomicrmwsub<{}>", memory)?,
+            Operator::I64AtomicRmwSub { memory } => write!(f, "i64atomicrmwsub<{}>", memory)?,
+            Operator::I32AtomicRmw8SubU { memory } => write!(f, "i32atomicrmw8subu<{}>", memory)?,
+            Operator::I32AtomicRmw16SubU { memory } => write!(f, "i32atomicrmw16subu<{}>", memory)?,
+            Operator::I64AtomicRmw8SubU { memory } => write!(f, "i64atomicrmw8subu<{}>", memory)?,
+            Operator::I64AtomicRmw16SubU { memory } => write!(f, "i64atomicrmw16subu<{}>", memory)?,
+            Operator::I64AtomicRmw32SubU { memory } => write!(f, "i64atomicrmw32subu<{}>", memory)?,
+            Operator::I32AtomicRmwAnd { memory } => write!(f, "i32atomicrmwand<{}>", memory)?,
+            Operator::I64AtomicRmwAnd { memory } => write!(f, "i64atomicrmwand<{}>", memory)?,
+            Operator::I32AtomicRmw8AndU { memory } => write!(f, "i32atomicrmw8andu<{}>", memory)?,
+            Operator::I32AtomicRmw16AndU { memory } => write!(f, "i32atomicrmw16andu<{}>", memory)?,
+            Operator::I64AtomicRmw8AndU { memory } => write!(f, "i64atomicrmw8andu<{}>", memory)?,
+            Operator::I64AtomicRmw16AndU { memory } => write!(f, "i64atomicrmw16andu<{}>", memory)?,
+            Operator::I64AtomicRmw32AndU { memory } => write!(f, "i64atomicrmw32andu<{}>", memory)?,
+            Operator::I32AtomicRmwOr { memory } => write!(f, "i32atomicrmwor<{}>", memory)?,
+            Operator::I64AtomicRmwOr { memory } => write!(f, "i64atomicrmwor<{}>", memory)?,
+            Operator::I32AtomicRmw8OrU { memory } => write!(f, "i32atomicrmw8oru<{}>", memory)?,
+            Operator::I32AtomicRmw16OrU { memory } => write!(f, "i32atomicrmw16oru<{}>", memory)?,
+            Operator::I64AtomicRmw8OrU { memory } => write!(f, "i64atomicrmw8oru<{}>", memory)?,
+            Operator::I64AtomicRmw16OrU { memory } => write!(f, "i64atomicrmw16oru<{}>", memory)?,
+            Operator::I64AtomicRmw32OrU { memory } => write!(f, "i64atomicrmw32oru<{}>", memory)?,
+            Operator::I32AtomicRmwXor { memory } => write!(f, "i32atomicrmwxor<{}>", memory)?,
+            Operator::I64AtomicRmwXor { memory } => write!(f, "i64atomicrmwxor<{}>", memory)?,
+            Operator::I32AtomicRmw8XorU { memory } => write!(f, "i32atomicrmw8xoru<{}>", memory)?,
+            Operator::I32AtomicRmw16XorU { memory } => write!(f, "i32atomicrmw16xoru<{}>", memory)?,
+            Operator::I64AtomicRmw8XorU { memory } => write!(f, "i64atomicrmw8xoru<{}>", memory)?,
+            Operator::I64AtomicRmw16XorU { memory } => write!(f, "i64atomicrmw16xoru<{}>", memory)?,
+            Operator::I64AtomicRmw32XorU { memory } => write!(f, "i64atomicrmw32xoru<{}>", memory)?,
+            Operator::I32AtomicRmwXchg { memory } => write!(f, "i32atomicrmwxchg<{}>", memory)?,
+            Operator::I64AtomicRmwXchg { memory } => write!(f, "i64atomicrmwxchg<{}>", memory)?,
+            Operator::I32AtomicRmw8XchgU { memory } => write!(f, "i32atomicrmw8xchgu<{}>", memory)?,
+            Operator::I32AtomicRmw16XchgU { memory } => {
+                write!(f, "i32atomicrmw16xchgu<{}>", memory)?
+            }
+            Operator::I64AtomicRmw8XchgU { memory } => write!(f, "i64atomicrmw8xchgu<{}>", memory)?,
+            Operator::I64AtomicRmw16XchgU { memory } => {
+                write!(f, "i64atomicrmw16xchgu<{}>", memory)?
+            }
+            Operator::I64AtomicRmw32XchgU { memory } => {
+                write!(f, "i64atomicrmw32xchgu<{}>", memory)?
+            }
+            Operator::I32AtomicRmwCmpxchg { memory } => {
+                write!(f, "i32atomicrmwcmpxchg<{}>", memory)?
+            }
+            Operator::I64AtomicRmwCmpxchg { memory } => {
+                write!(f, "i64atomicrmwcmpxchg<{}>", memory)?
+            }
+            Operator::I32AtomicRmw8CmpxchgU { memory } => {
+                write!(f, "i32atomicrmw8cmpxchgu<{}>", memory)?
+            }
+            Operator::I32AtomicRmw16CmpxchgU { memory } => {
+                write!(f, "i32atomicrmw16cmpxchgu<{}>", memory)?
+            }
+            Operator::I64AtomicRmw8CmpxchgU { memory } => {
+                write!(f, "i64atomicrmw8cmpxchgu<{}>", memory)?
+            }
+            Operator::I64AtomicRmw16CmpxchgU { memory } => {
+                write!(f, "i64atomicrmw16cmpxchgu<{}>", memory)?
+            }
+            Operator::I64AtomicRmw32CmpxchgU { memory } => {
+                write!(f, "i64atomicrmw32cmpxchgu<{}>", memory)?
+            }
         }
 
         Ok(())
@@ -868,3 +1253,11 @@ pub fn op_rematerialize(op: &Operator) -> bool {
         _ => false,
     }
 }
+
+/// The type an untyped `select` picks from: that of its first operand.
+fn select_ty(op_stack: &[(Type, Value)]) -> Result<Type> {
+    match op_stack.len().checked_sub(2) {
+        Some(index) => Ok(op_stack[index].0),
+        None => anyhow::bail!(WaffleError::malformed("Operand stack underflow at select")),
+    }
+}
@@ -0,0 +1,305 @@
+//! A visitor over operators, with a method per operator.
+//!
+//! `Operator` is `#[non_exhaustive]`, so matches on it outside waffle
+//! need a wildcard arm, which silently catches operators added later.
+//! Implementing `OperatorVisitor` instead does not compile until every
+//! operator is handled:
+//!
+//! ```ignore
+//! struct Name;
+//!
+//! impl OperatorVisitor for Name {
+//!     type Output = &'static str;
+//!     fn visit_unreachable(&mut self) -> &'static str {
+//!         "unreachable"
+//!     }
+//!     fn visit_call(&mut self, _function_index: Func) -> &'static str {
+//!         "call"
+//!     }
+//!     // ...
+//! }
+//!
+//! let name = op.visit(&mut Name);
+//! ```
+
+/// Invoke `$m` with every operator, one per line, as
+///
+/// ```ignore
+/// Variant { field: Type, .. } => visit_method
+/// ```
+///
+/// with the fields left out for operators that have none, e.g. to
+/// implement `OperatorVisitor` with a macro.
+#[macro_export]
+macro_rules! for_each_operator {
+    ($m:ident) => {
+        $m! {
+            Unreachable => visit_unreachable
+            Nop => visit_nop
+            Call { function_index: $crate::Func } => visit_call
+            CallIndirect { sig_index: $crate::Signature, table_index: $crate::Table } => visit_call_indirect
+            Select => visit_select
+            TypedSelect { ty: $crate::Type } => visit_typed_select
+            GlobalGet { global_index: $crate::Global } => visit_global_get
+            GlobalSet { global_index: $crate::Global } => visit_global_set
+            I32Load { memory: $crate::MemoryArg } => visit_i32_load
+            I64Load { memory: $crate::MemoryArg } => visit_i64_load
+            F32Load { memory: $crate::MemoryArg } => visit_f32_load
+            F64Load { memory: $crate::MemoryArg } => visit_f64_load
+            I32Load8S { memory: $crate::MemoryArg } => visit_i32_load8_s
+            I32Load8U { memory: $crate::MemoryArg } => visit_i32_load8_u
+            I32Load16S { memory: $crate::MemoryArg } => visit_i32_load16_s
+            I32Load16U { memory: $crate::MemoryArg } => visit_i32_load16_u
+            I64Load8S { memory: $crate::MemoryArg } => visit_i64_load8_s
+            I64Load8U { memory: $crate::MemoryArg } => visit_i64_load8_u
+            I64Load16S { memory: $crate::MemoryArg } => visit_i64_load16_s
+            I64Load16U { memory: $crate::MemoryArg } => visit_i64_load16_u
+            I64Load32S { memory: $crate::MemoryArg } => visit_i64_load32_s
+            I64Load32U { memory: $crate::MemoryArg } => visit_i64_load32_u
+            I32Store { memory: $crate::MemoryArg } => visit_i32_store
+            I64Store { memory: $crate::MemoryArg } => visit_i64_store
+            F32Store { memory: $crate::MemoryArg } => visit_f32_store
+            F64Store { memory: $crate::MemoryArg } => visit_f64_store
+            I32Store8 { memory: $crate::MemoryArg } => visit_i32_store8
+            I32Store16 { memory: $crate::MemoryArg } => visit_i32_store16
+            I64Store8 { memory: $crate::MemoryArg } => visit_i64_store8
+            I64Store16 { memory: $crate::MemoryArg } => visit_i64_store16
+            I64Store32 { memory: $crate::MemoryArg } => visit_i64_store32
+            I32Const { value: u32 } => visit_i32_const
+            I64Const { value: u64 } => visit_i64_const
+            F32Const { value: u32 } => visit_f32_const
+            F64Const { value: u64 } => visit_f64_const
+            I32Eqz => visit_i32_eqz
+            I32Eq => visit_i32_eq
+            I32Ne => visit_i32_ne
+            I32LtS => visit_i32_lt_s
+            I32LtU => visit_i32_lt_u
+            I32GtS => visit_i32_gt_s
+            I32GtU => visit_i32_gt_u
+            I32LeS => visit_i32_le_s
+            I32LeU => visit_i32_le_u
+            I32GeS => visit_i32_ge_s
+            I32GeU => visit_i32_ge_u
+            I64Eqz => visit_i64_eqz
+            I64Eq => visit_i64_eq
+            I64Ne => visit_i64_ne
+            I64LtS => visit_i64_lt_s
+            I64LtU => visit_i64_lt_u
+            I64GtU => visit_i64_gt_u
+            I64GtS => visit_i64_gt_s
+            I64LeS => visit_i64_le_s
+            I64LeU => visit_i64_le_u
+            I64GeS => visit_i64_ge_s
+            I64GeU => visit_i64_ge_u
+            F32Eq => visit_f32_eq
+            F32Ne => visit_f32_ne
+            F32Lt => visit_f32_lt
+            F32Gt => visit_f32_gt
+            F32Le => visit_f32_le
+            F32Ge => visit_f32_ge
+            F64Eq => visit_f64_eq
+            F64Ne => visit_f64_ne
+            F64Lt => visit_f64_lt
+            F64Gt => visit_f64_gt
+            F64Le => visit_f64_le
+            F64Ge => visit_f64_ge
+            I32Clz => visit_i32_clz
+            I32Ctz => visit_i32_ctz
+            I32Popcnt => visit_i32_popcnt
+            I32Add => visit_i32_add
+            I32Sub => visit_i32_sub
+            I32Mul => visit_i32_mul
+            I32DivS => visit_i32_div_s
+            I32DivU => visit_i32_div_u
+            I32RemS => visit_i32_rem_s
+            I32RemU => visit_i32_rem_u
+            I32And => visit_i32_and
+            I32Or => visit_i32_or
+            I32Xor => visit_i32_xor
+            I32Shl => visit_i32_shl
+            I32ShrS => visit_i32_shr_s
+            I32ShrU => visit_i32_shr_u
+            I32Rotl => visit_i32_rotl
+            I32Rotr => visit_i32_rotr
+            I64Clz => visit_i64_clz
+            I64Ctz => visit_i64_ctz
+            I64Popcnt => visit_i64_popcnt
+            I64Add => visit_i64_add
+            I64Sub => visit_i64_sub
+            I64Mul => visit_i64_mul
+            I64DivS => visit_i64_div_s
+            I64DivU => visit_i64_div_u
+            I64RemS => visit_i64_rem_s
+            I64RemU => visit_i64_rem_u
+            I64And => visit_i64_and
+            I64Or => visit_i64_or
+            I64Xor => visit_i64_xor
+            I64Shl => visit_i64_shl
+            I64ShrS => visit_i64_shr_s
+            I64ShrU => visit_i64_shr_u
+            I64Rotl => visit_i64_rotl
+            I64Rotr => visit_i64_rotr
+            F32Abs => visit_f32_abs
+            F32Neg => visit_f32_neg
+            F32Ceil => visit_f32_ceil
+            F32Floor => visit_f32_floor
+            F32Trunc => visit_f32_trunc
+            F32Nearest => visit_f32_nearest
+            F32Sqrt => visit_f32_sqrt
+            F32Add => visit_f32_add
+            F32Sub => visit_f32_sub
+            F32Mul => visit_f32_mul
+            F32Div => visit_f32_div
+            F32Min => visit_f32_min
+            F32Max => visit_f32_max
+            F32Copysign => visit_f32_copysign
+            F64Abs => visit_f64_abs
+            F64Neg => visit_f64_neg
+            F64Ceil => visit_f64_ceil
+            F64Floor => visit_f64_floor
+            F64Trunc => visit_f64_trunc
+            F64Nearest => visit_f64_nearest
+            F64Sqrt => visit_f64_sqrt
+            F64Add => visit_f64_add
+            F64Sub => visit_f64_sub
+            F64Mul => visit_f64_mul
+            F64Div => visit_f64_div
+            F64Min => visit_f64_min
+            F64Max => visit_f64_max
+            F64Copysign => visit_f64_copysign
+            I32WrapI64 => visit_i32_wrap_i64
+            I32TruncF32S => visit_i32_trunc_f32_s
+            I32TruncF32U => visit_i32_trunc_f32_u
+            I32TruncF64S => visit_i32_trunc_f64_s
+            I32TruncF64U => visit_i32_trunc_f64_u
+            I64ExtendI32S => visit_i64_extend_i32_s
+            I64ExtendI32U => visit_i64_extend_i32_u
+            I64TruncF32S => visit_i64_trunc_f32_s
+            I64TruncF32U => visit_i64_trunc_f32_u
+            I64TruncF64S => visit_i64_trunc_f64_s
+            I64TruncF64U => visit_i64_trunc_f64_u
+            F32ConvertI32S => visit_f32_convert_i32_s
+            F32ConvertI32U => visit_f32_convert_i32_u
+            F32ConvertI64S => visit_f32_convert_i64_s
+            F32ConvertI64U => visit_f32_convert_i64_u
+            F32DemoteF64 => visit_f32_demote_f64
+            F64ConvertI32S => visit_f64_convert_i32_s
+            F64ConvertI32U => visit_f64_convert_i32_u
+            F64ConvertI64S => visit_f64_convert_i64_s
+            F64ConvertI64U => visit_f64_convert_i64_u
+            F64PromoteF32 => visit_f64_promote_f32
+            I32Extend8S => visit_i32_extend8_s
+            I32Extend16S => visit_i32_extend16_s
+            I64Extend8S => visit_i64_extend8_s
+            I64Extend16S => visit_i64_extend16_s
+            I64Extend32S => visit_i64_extend32_s
+            I32TruncSatF32S => visit_i32_trunc_sat_f32_s
+            I32TruncSatF32U => visit_i32_trunc_sat_f32_u
+            I32TruncSatF64S => visit_i32_trunc_sat_f64_s
+            I32TruncSatF64U => visit_i32_trunc_sat_f64_u
+            I64TruncSatF32S => visit_i64_trunc_sat_f32_s
+            I64TruncSatF32U => visit_i64_trunc_sat_f32_u
+            I64TruncSatF64S => visit_i64_trunc_sat_f64_s
+            I64TruncSatF64U => visit_i64_trunc_sat_f64_u
+            F32ReinterpretI32 => visit_f32_reinterpret_i32
+            F64ReinterpretI64 => visit_f64_reinterpret_i64
+            I32ReinterpretF32 => visit_i32_reinterpret_f32
+            I64ReinterpretF64 => visit_i64_reinterpret_f64
+            TableGet { table_index: $crate::Table } => visit_table_get
+            TableSet { table_index: $crate::Table } => visit_table_set
+            TableGrow { table_index: $crate::Table } => visit_table_grow
+            TableSize { table_index: $crate::Table } => visit_table_size
+            MemorySize { mem: $crate::Memory } => visit_memory_size
+            MemoryGrow { mem: $crate::Memory } => visit_memory_grow
+            MemoryAtomicNotify { memory: $crate::MemoryArg } => visit_memory_atomic_notify
+            MemoryAtomicWait32 { memory: $crate::MemoryArg } => visit_memory_atomic_wait32
+            MemoryAtomicWait64 { memory: $crate::MemoryArg } => visit_memory_atomic_wait64
+            AtomicFence => visit_atomic_fence
+            I32AtomicLoad { memory: $crate::MemoryArg } => visit_i32_atomic_load
+            I64AtomicLoad { memory: $crate::MemoryArg } => visit_i64_atomic_load
+            I32AtomicLoad8U { memory: $crate::MemoryArg } => visit_i32_atomic_load8_u
+            I32AtomicLoad16U { memory: $crate::MemoryArg } => visit_i32_atomic_load16_u
+            I64AtomicLoad8U { memory: $crate::MemoryArg } => visit_i64_atomic_load8_u
+            I64AtomicLoad16U { memory: $crate::MemoryArg } => visit_i64_atomic_load16_u
+            I64AtomicLoad32U { memory: $crate::MemoryArg } => visit_i64_atomic_load32_u
+            I32AtomicStore { memory: $crate::MemoryArg } => visit_i32_atomic_store
+            I64AtomicStore { memory: $crate::MemoryArg } => visit_i64_atomic_store
+            I32AtomicStore8 { memory: $crate::MemoryArg } => visit_i32_atomic_store8
+            I32AtomicStore16 { memory: $crate::MemoryArg } => visit_i32_atomic_store16
+            I64AtomicStore8 { memory: $crate::MemoryArg } => visit_i64_atomic_store8
+            I64AtomicStore16 { memory: $crate::MemoryArg } => visit_i64_atomic_store16
+            I64AtomicStore32 { memory: $crate::MemoryArg } => visit_i64_atomic_store32
+            I32AtomicRmwAdd { memory: $crate::MemoryArg } => visit_i32_atomic_rmw_add
+            I64AtomicRmwAdd { memory: $crate::MemoryArg } => visit_i64_atomic_rmw_add
+            I32AtomicRmw8AddU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw8_add_u
+            I32AtomicRmw16AddU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw16_add_u
+            I64AtomicRmw8AddU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw8_add_u
+            I64AtomicRmw16AddU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw16_add_u
+            I64AtomicRmw32AddU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw32_add_u
+            I32AtomicRmwSub { memory: $crate::MemoryArg } => visit_i32_atomic_rmw_sub
+            I64AtomicRmwSub { memory: $crate::MemoryArg } => visit_i64_atomic_rmw_sub
+            I32AtomicRmw8SubU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw8_sub_u
+            I32AtomicRmw16SubU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw16_sub_u
+            I64AtomicRmw8SubU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw8_sub_u
+            I64AtomicRmw16SubU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw16_sub_u
+            I64AtomicRmw32SubU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw32_sub_u
+            I32AtomicRmwAnd { memory: $crate::MemoryArg } => visit_i32_atomic_rmw_and
+            I64AtomicRmwAnd { memory: $crate::MemoryArg } => visit_i64_atomic_rmw_and
+            I32AtomicRmw8AndU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw8_and_u
+            I32AtomicRmw16AndU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw16_and_u
+            I64AtomicRmw8AndU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw8_and_u
+            I64AtomicRmw16AndU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw16_and_u
+            I64AtomicRmw32AndU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw32_and_u
+            I32AtomicRmwOr { memory: $crate::MemoryArg } => visit_i32_atomic_rmw_or
+            I64AtomicRmwOr { memory: $crate::MemoryArg } => visit_i64_atomic_rmw_or
+            I32AtomicRmw8OrU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw8_or_u
+            I32AtomicRmw16OrU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw16_or_u
+            I64AtomicRmw8OrU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw8_or_u
+            I64AtomicRmw16OrU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw16_or_u
+            I64AtomicRmw32OrU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw32_or_u
+            I32AtomicRmwXor { memory: $crate::MemoryArg } => visit_i32_atomic_rmw_xor
+            I64AtomicRmwXor { memory: $crate::MemoryArg } => visit_i64_atomic_rmw_xor
+            I32AtomicRmw8XorU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw8_xor_u
+            I32AtomicRmw16XorU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw16_xor_u
+            I64AtomicRmw8XorU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw8_xor_u
+            I64AtomicRmw16XorU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw16_xor_u
+            I64AtomicRmw32XorU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw32_xor_u
+            I32AtomicRmwXchg { memory: $crate::MemoryArg } => visit_i32_atomic_rmw_xchg
+            I64AtomicRmwXchg { memory: $crate::MemoryArg } => visit_i64_atomic_rmw_xchg
+            I32AtomicRmw8XchgU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw8_xchg_u
+            I32AtomicRmw16XchgU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw16_xchg_u
+            I64AtomicRmw8XchgU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw8_xchg_u
+            I64AtomicRmw16XchgU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw16_xchg_u
+            I64AtomicRmw32XchgU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw32_xchg_u
+            I32AtomicRmwCmpxchg { memory: $crate::MemoryArg } => visit_i32_atomic_rmw_cmpxchg
+            I64AtomicRmwCmpxchg { memory: $crate::MemoryArg } => visit_i64_atomic_rmw_cmpxchg
+            I32AtomicRmw8CmpxchgU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw8_cmpxchg_u
+            I32AtomicRmw16CmpxchgU { memory: $crate::MemoryArg } => visit_i32_atomic_rmw16_cmpxchg_u
+            I64AtomicRmw8CmpxchgU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw8_cmpxchg_u
+            I64AtomicRmw16CmpxchgU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw16_cmpxchg_u
+            I64AtomicRmw32CmpxchgU { memory: $crate::MemoryArg } => visit_i64_atomic_rmw32_cmpxchg_u
+        }
+    };
+}
+
+macro_rules! define_visitor {
+    ($($op:ident $({ $($field:ident: $ty:ty),* })? => $visit:ident)*) => {
+        /// A method for each operator, given its fields, called by
+        /// `Operator::visit`.
+        pub trait OperatorVisitor {
+            type Output;
+            $(fn $visit(&mut self $($(, $field: $ty)*)?) -> Self::Output;)*
+        }
+
+        impl crate::Operator {
+            /// Call the method of `visitor` for this operator.
+            pub fn visit<V: OperatorVisitor + ?Sized>(&self, visitor: &mut V) -> V::Output {
+                match *self {
+                    $(crate::Operator::$op $({ $($field),* })? => visitor.$visit($($($field),*)?),)*
+                }
+            }
+        }
+    };
+}
+
+for_each_operator!(define_visitor);
@@ -1,8 +1,44 @@
 //! Operators.
 
-use crate::{entity::EntityRef, Func, Global, Memory, Signature, Table, Type};
-use std::convert::TryFrom;
-pub use wasmparser::{Ieee32, Ieee64};
+#[cfg(feature = "backend")]
+use crate::entity::EntityRef;
+use crate::{Func, Global, Memory, Signature, Table, Type};
+#[cfg(feature = "frontend")]
+use core::convert::TryFrom;
+
+/// The bits of a 32-bit float.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Ieee32(pub u32);
+
+impl Ieee32 {
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+#[cfg(feature = "frontend")]
+impl From<wasmparser::Ieee32> for Ieee32 {
+    fn from(value: wasmparser::Ieee32) -> Ieee32 {
+        Ieee32(value.bits())
+    }
+}
+
+/// The bits of a 64-bit float.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Ieee64(pub u64);
+
+impl Ieee64 {
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(feature = "frontend")]
+impl From<wasmparser::Ieee64> for Ieee64 {
+    fn from(value: wasmparser::Ieee64) -> Ieee64 {
+        Ieee64(value.bits())
+    }
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct MemoryArg {
@@ -11,8 +47,8 @@ pub struct MemoryArg {
     pub memory: Memory,
 }
 
-impl std::fmt::Display for MemoryArg {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for MemoryArg {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(
             f,
             "{}, align={}, offset={}",
@@ -21,7 +57,10 @@ impl std::fmt::Display for MemoryArg {
     }
 }
 
+/// An operator of the IR. New operators may be added; see
+/// `OperatorVisitor` for handling every one.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum Operator {
     Unreachable,
     Nop,
@@ -295,14 +334,458 @@ pub enum Operator {
     MemoryGrow {
         mem: Memory,
     },
+
+    MemoryAtomicNotify {
+        memory: MemoryArg,
+    },
+    MemoryAtomicWait32 {
+        memory: MemoryArg,
+    },
+    MemoryAtomicWait64 {
+        memory: MemoryArg,
+    },
+    AtomicFence,
+    I32AtomicLoad {
+        memory: MemoryArg,
+    },
+    I64AtomicLoad {
+        memory: MemoryArg,
+    },
+    I32AtomicLoad8U {
+        memory: MemoryArg,
+    },
+    I32AtomicLoad16U {
+        memory: MemoryArg,
+    },
+    I64AtomicLoad8U {
+        memory: MemoryArg,
+    },
+    I64AtomicLoad16U {
+        memory: MemoryArg,
+    },
+    I64AtomicLoad32U {
+        memory: MemoryArg,
+    },
+    I32AtomicStore {
+        memory: MemoryArg,
+    },
+    I64AtomicStore {
+        memory: MemoryArg,
+    },
+    I32AtomicStore8 {
+        memory: MemoryArg,
+    },
+    I32AtomicStore16 {
+        memory: MemoryArg,
+    },
+    I64AtomicStore8 {
+        memory: MemoryArg,
+    },
+    I64AtomicStore16 {
+        memory: MemoryArg,
+    },
+    I64AtomicStore32 {
+        memory: MemoryArg,
+    },
+    I32AtomicRmwAdd {
+        memory: MemoryArg,
+    },
+    I64AtomicRmwAdd {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw8AddU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw16AddU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw8AddU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw16AddU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw32AddU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmwSub {
+        memory: MemoryArg,
+    },
+    I64AtomicRmwSub {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw8SubU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw16SubU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw8SubU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw16SubU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw32SubU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmwAnd {
+        memory: MemoryArg,
+    },
+    I64AtomicRmwAnd {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw8AndU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw16AndU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw8AndU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw16AndU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw32AndU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmwOr {
+        memory: MemoryArg,
+    },
+    I64AtomicRmwOr {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw8OrU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw16OrU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw8OrU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw16OrU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw32OrU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmwXor {
+        memory: MemoryArg,
+    },
+    I64AtomicRmwXor {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw8XorU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw16XorU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw8XorU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw16XorU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw32XorU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmwXchg {
+        memory: MemoryArg,
+    },
+    I64AtomicRmwXchg {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw8XchgU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw16XchgU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw8XchgU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw16XchgU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw32XchgU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmwCmpxchg {
+        memory: MemoryArg,
+    },
+    I64AtomicRmwCmpxchg {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw8CmpxchgU {
+        memory: MemoryArg,
+    },
+    I32AtomicRmw16CmpxchgU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw8CmpxchgU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw16CmpxchgU {
+        memory: MemoryArg,
+    },
+    I64AtomicRmw32CmpxchgU {
+        memory: MemoryArg,
+    },
 }
 
 #[test]
 fn op_size() {
-    assert_eq!(std::mem::size_of::<Operator>(), 16);
+    assert_eq!(core::mem::size_of::<Operator>(), 16);
+}
+
+/// A module-level entity referred to by an operator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum EntityUse {
+    Func(Func),
+    Global(Global),
+    Table(Table),
+    Memory(Memory),
 }
 
-impl<'a, 'b> std::convert::TryFrom<&'b wasmparser::Operator<'a>> for Operator {
+impl Operator {
+    /// The module-level entity this operator refers to, if any.
+    pub(crate) fn entity_use(&self) -> Option<EntityUse> {
+        let mut used = None;
+        let mut op = *self;
+        op.map_entity_use(|entity| {
+            used = Some(entity);
+            entity
+        });
+        used
+    }
+
+    /// Replace the module-level entity this operator refers to, if
+    /// any, with `f`'s result (which must be of the same kind).
+    pub(crate) fn map_entity_use<F: FnOnce(EntityUse) -> EntityUse>(&mut self, f: F) {
+        match self {
+            Operator::Call { function_index } => {
+                if let EntityUse::Func(new) = f(EntityUse::Func(*function_index)) {
+                    *function_index = new;
+                }
+            }
+            Operator::GlobalGet { global_index } | Operator::GlobalSet { global_index } => {
+                if let EntityUse::Global(new) = f(EntityUse::Global(*global_index)) {
+                    *global_index = new;
+                }
+            }
+            Operator::CallIndirect { table_index, .. }
+            | Operator::TableGet { table_index }
+            | Operator::TableSet { table_index }
+            | Operator::TableGrow { table_index }
+            | Operator::TableSize { table_index } => {
+                if let EntityUse::Table(new) = f(EntityUse::Table(*table_index)) {
+                    *table_index = new;
+                }
+            }
+            Operator::I32Load { memory }
+            | Operator::I64Load { memory }
+            | Operator::F32Load { memory }
+            | Operator::F64Load { memory }
+            | Operator::I32Load8S { memory }
+            | Operator::I32Load8U { memory }
+            | Operator::I32Load16S { memory }
+            | Operator::I32Load16U { memory }
+            | Operator::I64Load8S { memory }
+            | Operator::I64Load8U { memory }
+            | Operator::I64Load16S { memory }
+            | Operator::I64Load16U { memory }
+            | Operator::I64Load32S { memory }
+            | Operator::I64Load32U { memory }
+            | Operator::I32Store { memory }
+            | Operator::I64Store { memory }
+            | Operator::F32Store { memory }
+            | Operator::F64Store { memory }
+            | Operator::I32Store8 { memory }
+            | Operator::I32Store16 { memory }
+            | Operator::I64Store8 { memory }
+            | Operator::I64Store16 { memory }
+            | Operator::I64Store32 { memory }
+            | Operator::MemoryAtomicNotify { memory }
+            | Operator::MemoryAtomicWait32 { memory }
+            | Operator::MemoryAtomicWait64 { memory }
+            | Operator::I32AtomicLoad { memory }
+            | Operator::I64AtomicLoad { memory }
+            | Operator::I32AtomicLoad8U { memory }
+            | Operator::I32AtomicLoad16U { memory }
+            | Operator::I64AtomicLoad8U { memory }
+            | Operator::I64AtomicLoad16U { memory }
+            | Operator::I64AtomicLoad32U { memory }
+            | Operator::I32AtomicStore { memory }
+            | Operator::I64AtomicStore { memory }
+            | Operator::I32AtomicStore8 { memory }
+            | Operator::I32AtomicStore16 { memory }
+            | Operator::I64AtomicStore8 { memory }
+            | Operator::I64AtomicStore16 { memory }
+            | Operator::I64AtomicStore32 { memory }
+            | Operator::I32AtomicRmwAdd { memory }
+            | Operator::I64AtomicRmwAdd { memory }
+            | Operator::I32AtomicRmw8AddU { memory }
+            | Operator::I32AtomicRmw16AddU { memory }
+            | Operator::I64AtomicRmw8AddU { memory }
+            | Operator::I64AtomicRmw16AddU { memory }
+            | Operator::I64AtomicRmw32AddU { memory }
+            | Operator::I32AtomicRmwSub { memory }
+            | Operator::I64AtomicRmwSub { memory }
+            | Operator::I32AtomicRmw8SubU { memory }
+            | Operator::I32AtomicRmw16SubU { memory }
+            | Operator::I64AtomicRmw8SubU { memory }
+            | Operator::I64AtomicRmw16SubU { memory }
+            | Operator::I64AtomicRmw32SubU { memory }
+            | Operator::I32AtomicRmwAnd { memory }
+            | Operator::I64AtomicRmwAnd { memory }
+            | Operator::I32AtomicRmw8AndU { memory }
+            | Operator::I32AtomicRmw16AndU { memory }
+            | Operator::I64AtomicRmw8AndU { memory }
+            | Operator::I64AtomicRmw16AndU { memory }
+            | Operator::I64AtomicRmw32AndU { memory }
+            | Operator::I32AtomicRmwOr { memory }
+            | Operator::I64AtomicRmwOr { memory }
+            | Operator::I32AtomicRmw8OrU { memory }
+            | Operator::I32AtomicRmw16OrU { memory }
+            | Operator::I64AtomicRmw8OrU { memory }
+            | Operator::I64AtomicRmw16OrU { memory }
+            | Operator::I64AtomicRmw32OrU { memory }
+            | Operator::I32AtomicRmwXor { memory }
+            | Operator::I64AtomicRmwXor { memory }
+            | Operator::I32AtomicRmw8XorU { memory }
+            | Operator::I32AtomicRmw16XorU { memory }
+            | Operator::I64AtomicRmw8XorU { memory }
+            | Operator::I64AtomicRmw16XorU { memory }
+            | Operator::I64AtomicRmw32XorU { memory }
+            | Operator::I32AtomicRmwXchg { memory }
+            | Operator::I64AtomicRmwXchg { memory }
+            | Operator::I32AtomicRmw8XchgU { memory }
+            | Operator::I32AtomicRmw16XchgU { memory }
+            | Operator::I64AtomicRmw8XchgU { memory }
+            | Operator::I64AtomicRmw16XchgU { memory }
+            | Operator::I64AtomicRmw32XchgU { memory }
+            | Operator::I32AtomicRmwCmpxchg { memory }
+            | Operator::I64AtomicRmwCmpxchg { memory }
+            | Operator::I32AtomicRmw8CmpxchgU { memory }
+            | Operator::I32AtomicRmw16CmpxchgU { memory }
+            | Operator::I64AtomicRmw8CmpxchgU { memory }
+            | Operator::I64AtomicRmw16CmpxchgU { memory }
+            | Operator::I64AtomicRmw32CmpxchgU { memory } => {
+                if let EntityUse::Memory(new) = f(EntityUse::Memory(memory.memory)) {
+                    memory.memory = new;
+                }
+            }
+            Operator::MemorySize { mem } | Operator::MemoryGrow { mem } => {
+                if let EntityUse::Memory(new) = f(EntityUse::Memory(*mem)) {
+                    *mem = new;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The memory argument of a load or store.
+    pub(crate) fn memory_arg_mut(&mut self) -> Option<&mut MemoryArg> {
+        match self {
+            Operator::I32Load { memory }
+            | Operator::I64Load { memory }
+            | Operator::F32Load { memory }
+            | Operator::F64Load { memory }
+            | Operator::I32Load8S { memory }
+            | Operator::I32Load8U { memory }
+            | Operator::I32Load16S { memory }
+            | Operator::I32Load16U { memory }
+            | Operator::I64Load8S { memory }
+            | Operator::I64Load8U { memory }
+            | Operator::I64Load16S { memory }
+            | Operator::I64Load16U { memory }
+            | Operator::I64Load32S { memory }
+            | Operator::I64Load32U { memory }
+            | Operator::I32Store { memory }
+            | Operator::I64Store { memory }
+            | Operator::F32Store { memory }
+            | Operator::F64Store { memory }
+            | Operator::I32Store8 { memory }
+            | Operator::I32Store16 { memory }
+            | Operator::I64Store8 { memory }
+            | Operator::I64Store16 { memory }
+            | Operator::I64Store32 { memory }
+            | Operator::MemoryAtomicNotify { memory }
+            | Operator::MemoryAtomicWait32 { memory }
+            | Operator::MemoryAtomicWait64 { memory }
+            | Operator::I32AtomicLoad { memory }
+            | Operator::I64AtomicLoad { memory }
+            | Operator::I32AtomicLoad8U { memory }
+            | Operator::I32AtomicLoad16U { memory }
+            | Operator::I64AtomicLoad8U { memory }
+            | Operator::I64AtomicLoad16U { memory }
+            | Operator::I64AtomicLoad32U { memory }
+            | Operator::I32AtomicStore { memory }
+            | Operator::I64AtomicStore { memory }
+            | Operator::I32AtomicStore8 { memory }
+            | Operator::I32AtomicStore16 { memory }
+            | Operator::I64AtomicStore8 { memory }
+            | Operator::I64AtomicStore16 { memory }
+            | Operator::I64AtomicStore32 { memory }
+            | Operator::I32AtomicRmwAdd { memory }
+            | Operator::I64AtomicRmwAdd { memory }
+            | Operator::I32AtomicRmw8AddU { memory }
+            | Operator::I32AtomicRmw16AddU { memory }
+            | Operator::I64AtomicRmw8AddU { memory }
+            | Operator::I64AtomicRmw16AddU { memory }
+            | Operator::I64AtomicRmw32AddU { memory }
+            | Operator::I32AtomicRmwSub { memory }
+            | Operator::I64AtomicRmwSub { memory }
+            | Operator::I32AtomicRmw8SubU { memory }
+            | Operator::I32AtomicRmw16SubU { memory }
+            | Operator::I64AtomicRmw8SubU { memory }
+            | Operator::I64AtomicRmw16SubU { memory }
+            | Operator::I64AtomicRmw32SubU { memory }
+            | Operator::I32AtomicRmwAnd { memory }
+            | Operator::I64AtomicRmwAnd { memory }
+            | Operator::I32AtomicRmw8AndU { memory }
+            | Operator::I32AtomicRmw16AndU { memory }
+            | Operator::I64AtomicRmw8AndU { memory }
+            | Operator::I64AtomicRmw16AndU { memory }
+            | Operator::I64AtomicRmw32AndU { memory }
+            | Operator::I32AtomicRmwOr { memory }
+            | Operator::I64AtomicRmwOr { memory }
+            | Operator::I32AtomicRmw8OrU { memory }
+            | Operator::I32AtomicRmw16OrU { memory }
+            | Operator::I64AtomicRmw8OrU { memory }
+            | Operator::I64AtomicRmw16OrU { memory }
+            | Operator::I64AtomicRmw32OrU { memory }
+            | Operator::I32AtomicRmwXor { memory }
+            | Operator::I64AtomicRmwXor { memory }
+            | Operator::I32AtomicRmw8XorU { memory }
+            | Operator::I32AtomicRmw16XorU { memory }
+            | Operator::I64AtomicRmw8XorU { memory }
+            | Operator::I64AtomicRmw16XorU { memory }
+            | Operator::I64AtomicRmw32XorU { memory }
+            | Operator::I32AtomicRmwXchg { memory }
+            | Operator::I64AtomicRmwXchg { memory }
+            | Operator::I32AtomicRmw8XchgU { memory }
+            | Operator::I32AtomicRmw16XchgU { memory }
+            | Operator::I64AtomicRmw8XchgU { memory }
+            | Operator::I64AtomicRmw16XchgU { memory }
+            | Operator::I64AtomicRmw32XchgU { memory }
+            | Operator::I32AtomicRmwCmpxchg { memory }
+            | Operator::I64AtomicRmwCmpxchg { memory }
+            | Operator::I32AtomicRmw8CmpxchgU { memory }
+            | Operator::I32AtomicRmw16CmpxchgU { memory }
+            | Operator::I64AtomicRmw8CmpxchgU { memory }
+            | Operator::I64AtomicRmw16CmpxchgU { memory }
+            | Operator::I64AtomicRmw32CmpxchgU { memory } => Some(memory),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "frontend")]
+impl<'a, 'b> core::convert::TryFrom<&'b wasmparser::Operator<'a>> for Operator {
     type Error = ();
 
     fn try_from(op: &'b wasmparser::Operator<'a>) -> Result<Operator, Self::Error> {
@@ -568,12 +1051,288 @@ impl<'a, 'b> std::convert::TryFrom<&'b wasmparser::Operator<'a>> for Operator {
             &wasmparser::Operator::MemoryGrow { mem, .. } => Ok(Operator::MemoryGrow {
                 mem: Memory::from(mem),
             }),
+            &wasmparser::Operator::MemoryAtomicNotify { memarg } => {
+                Ok(Operator::MemoryAtomicNotify {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::MemoryAtomicWait32 { memarg } => {
+                Ok(Operator::MemoryAtomicWait32 {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::MemoryAtomicWait64 { memarg } => {
+                Ok(Operator::MemoryAtomicWait64 {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::AtomicFence => Ok(Operator::AtomicFence),
+            &wasmparser::Operator::I32AtomicLoad { memarg } => Ok(Operator::I32AtomicLoad {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicLoad { memarg } => Ok(Operator::I64AtomicLoad {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicLoad8U { memarg } => Ok(Operator::I32AtomicLoad8U {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicLoad16U { memarg } => Ok(Operator::I32AtomicLoad16U {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicLoad8U { memarg } => Ok(Operator::I64AtomicLoad8U {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicLoad16U { memarg } => Ok(Operator::I64AtomicLoad16U {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicLoad32U { memarg } => Ok(Operator::I64AtomicLoad32U {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicStore { memarg } => Ok(Operator::I32AtomicStore {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicStore { memarg } => Ok(Operator::I64AtomicStore {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicStore8 { memarg } => Ok(Operator::I32AtomicStore8 {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicStore16 { memarg } => Ok(Operator::I32AtomicStore16 {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicStore8 { memarg } => Ok(Operator::I64AtomicStore8 {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicStore16 { memarg } => Ok(Operator::I64AtomicStore16 {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicStore32 { memarg } => Ok(Operator::I64AtomicStore32 {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicRmwAdd { memarg } => Ok(Operator::I32AtomicRmwAdd {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicRmwAdd { memarg } => Ok(Operator::I64AtomicRmwAdd {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicRmw8AddU { memarg } => {
+                Ok(Operator::I32AtomicRmw8AddU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmw16AddU { memarg } => {
+                Ok(Operator::I32AtomicRmw16AddU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw8AddU { memarg } => {
+                Ok(Operator::I64AtomicRmw8AddU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw16AddU { memarg } => {
+                Ok(Operator::I64AtomicRmw16AddU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw32AddU { memarg } => {
+                Ok(Operator::I64AtomicRmw32AddU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmwSub { memarg } => Ok(Operator::I32AtomicRmwSub {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicRmwSub { memarg } => Ok(Operator::I64AtomicRmwSub {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicRmw8SubU { memarg } => {
+                Ok(Operator::I32AtomicRmw8SubU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmw16SubU { memarg } => {
+                Ok(Operator::I32AtomicRmw16SubU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw8SubU { memarg } => {
+                Ok(Operator::I64AtomicRmw8SubU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw16SubU { memarg } => {
+                Ok(Operator::I64AtomicRmw16SubU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw32SubU { memarg } => {
+                Ok(Operator::I64AtomicRmw32SubU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmwAnd { memarg } => Ok(Operator::I32AtomicRmwAnd {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicRmwAnd { memarg } => Ok(Operator::I64AtomicRmwAnd {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicRmw8AndU { memarg } => {
+                Ok(Operator::I32AtomicRmw8AndU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmw16AndU { memarg } => {
+                Ok(Operator::I32AtomicRmw16AndU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw8AndU { memarg } => {
+                Ok(Operator::I64AtomicRmw8AndU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw16AndU { memarg } => {
+                Ok(Operator::I64AtomicRmw16AndU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw32AndU { memarg } => {
+                Ok(Operator::I64AtomicRmw32AndU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmwOr { memarg } => Ok(Operator::I32AtomicRmwOr {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicRmwOr { memarg } => Ok(Operator::I64AtomicRmwOr {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicRmw8OrU { memarg } => Ok(Operator::I32AtomicRmw8OrU {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicRmw16OrU { memarg } => {
+                Ok(Operator::I32AtomicRmw16OrU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw8OrU { memarg } => Ok(Operator::I64AtomicRmw8OrU {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicRmw16OrU { memarg } => {
+                Ok(Operator::I64AtomicRmw16OrU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw32OrU { memarg } => {
+                Ok(Operator::I64AtomicRmw32OrU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmwXor { memarg } => Ok(Operator::I32AtomicRmwXor {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicRmwXor { memarg } => Ok(Operator::I64AtomicRmwXor {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicRmw8XorU { memarg } => {
+                Ok(Operator::I32AtomicRmw8XorU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmw16XorU { memarg } => {
+                Ok(Operator::I32AtomicRmw16XorU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw8XorU { memarg } => {
+                Ok(Operator::I64AtomicRmw8XorU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw16XorU { memarg } => {
+                Ok(Operator::I64AtomicRmw16XorU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw32XorU { memarg } => {
+                Ok(Operator::I64AtomicRmw32XorU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmwXchg { memarg } => Ok(Operator::I32AtomicRmwXchg {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I64AtomicRmwXchg { memarg } => Ok(Operator::I64AtomicRmwXchg {
+                memory: memarg.into(),
+            }),
+            &wasmparser::Operator::I32AtomicRmw8XchgU { memarg } => {
+                Ok(Operator::I32AtomicRmw8XchgU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmw16XchgU { memarg } => {
+                Ok(Operator::I32AtomicRmw16XchgU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw8XchgU { memarg } => {
+                Ok(Operator::I64AtomicRmw8XchgU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw16XchgU { memarg } => {
+                Ok(Operator::I64AtomicRmw16XchgU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw32XchgU { memarg } => {
+                Ok(Operator::I64AtomicRmw32XchgU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmwCmpxchg { memarg } => {
+                Ok(Operator::I32AtomicRmwCmpxchg {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmwCmpxchg { memarg } => {
+                Ok(Operator::I64AtomicRmwCmpxchg {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmw8CmpxchgU { memarg } => {
+                Ok(Operator::I32AtomicRmw8CmpxchgU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I32AtomicRmw16CmpxchgU { memarg } => {
+                Ok(Operator::I32AtomicRmw16CmpxchgU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw8CmpxchgU { memarg } => {
+                Ok(Operator::I64AtomicRmw8CmpxchgU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw16CmpxchgU { memarg } => {
+                Ok(Operator::I64AtomicRmw16CmpxchgU {
+                    memory: memarg.into(),
+                })
+            }
+            &wasmparser::Operator::I64AtomicRmw32CmpxchgU { memarg } => {
+                Ok(Operator::I64AtomicRmw32CmpxchgU {
+                    memory: memarg.into(),
+                })
+            }
             _ => Err(()),
         }
     }
 }
 
-impl std::convert::From<wasmparser::MemArg> for MemoryArg {
+#[cfg(feature = "frontend")]
+impl core::convert::From<wasmparser::MemArg> for MemoryArg {
     fn from(value: wasmparser::MemArg) -> MemoryArg {
         MemoryArg {
             align: value.align as u32,
@@ -583,7 +1342,8 @@ impl std::convert::From<wasmparser::MemArg> for MemoryArg {
     }
 }
 
-impl std::convert::From<MemoryArg> for wasm_encoder::MemArg {
+#[cfg(feature = "backend")]
+impl core::convert::From<MemoryArg> for wasm_encoder::MemArg {
     fn from(value: MemoryArg) -> wasm_encoder::MemArg {
         wasm_encoder::MemArg {
             offset: value.offset as u64,
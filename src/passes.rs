@@ -1,10 +1,35 @@
 //! Passes.
 
+pub mod asyncify;
 pub mod basic_opt;
+pub mod branch_profile;
+pub mod call_profile;
+pub mod cfi;
+pub mod const_globals;
+pub mod coverage;
+pub mod dce;
+pub mod determinism;
 pub mod dom_pass;
 pub mod empty_blocks;
+pub mod hooks;
+pub mod hotpatch;
+pub mod inline;
+pub mod intercept;
+pub mod load_store;
+pub mod manager;
 pub mod maxssa;
+pub mod memtrace;
+pub mod metering;
+pub mod obfuscate;
+pub mod pgo;
+pub mod preinit;
 pub mod remove_phis;
 pub mod resolve_aliases;
+pub mod sandbox;
+pub mod specialize;
 pub mod ssa;
+pub mod stack_guard;
+pub mod stack_promote;
 pub mod trace;
+pub mod traps;
+pub mod watermark;
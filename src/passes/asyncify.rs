@@ -0,0 +1,728 @@
+//! Asyncify: let functions unwind their stack into memory and rewind
+//! it later, so that a synchronous-looking import can be implemented
+//! with an asynchronous host API.
+//!
+//! The protocol is that of Binaryen's Asyncify pass. The module
+//! exports `asyncify_start_unwind(data)`, `asyncify_stop_unwind()`,
+//! `asyncify_start_rewind(data)`, `asyncify_stop_rewind()` and
+//! `asyncify_get_state()`, where `data` points to a pair of `i32`s:
+//! the current position in a buffer for saved frames and the end of
+//! that buffer. To pause, an import calls `asyncify_start_unwind` and
+//! returns; every instrumented function on the stack then saves its
+//! live values and returns, until control reaches the embedder, which
+//! calls `asyncify_stop_unwind`. To resume, the embedder calls
+//! `asyncify_start_rewind` and calls the same export again; the
+//! functions restore their frames and call down to the import again,
+//! which calls `asyncify_stop_rewind` and returns its result.
+
+use super::hooks::{insert, push_op};
+use crate::cfg::CFGInfo;
+use crate::collections::{HashMap, HashSet};
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::ops::MemoryArg;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+const STATE_NORMAL: u32 = 0;
+const STATE_UNWINDING: u32 = 1;
+const STATE_REWINDING: u32 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsyncifyOptions {
+    /// The imports that may unwind, by module and name; all imported
+    /// functions if `None`.
+    pub imports: Option<Vec<(String, String)>>,
+    /// Whether an indirect call may unwind.
+    pub indirect_calls: bool,
+    /// The memory holding the frame buffer.
+    pub memory: Memory,
+}
+
+impl Default for AsyncifyOptions {
+    fn default() -> Self {
+        AsyncifyOptions {
+            imports: None,
+            indirect_calls: true,
+            memory: Memory::new(0),
+        }
+    }
+}
+
+/// The globals the instrumentation uses.
+#[derive(Clone, Copy, Debug)]
+struct Globals {
+    state: Global,
+    data: Global,
+    memory: Memory,
+}
+
+impl Globals {
+    fn memarg(&self, offset: u32) -> MemoryArg {
+        MemoryArg {
+            align: 0,
+            offset,
+            memory: self.memory,
+        }
+    }
+}
+
+fn slot_size(ty: Type) -> Result<u32> {
+    match ty {
+        Type::I32 | Type::F32 => Ok(4),
+        Type::I64 | Type::F64 => Ok(8),
+        _ => anyhow::bail!("Cannot save a {} value across an unwind", ty),
+    }
+}
+
+fn load_op(ty: Type, memory: MemoryArg) -> Operator {
+    match ty {
+        Type::I32 => Operator::I32Load { memory },
+        Type::I64 => Operator::I64Load { memory },
+        Type::F32 => Operator::F32Load { memory },
+        Type::F64 => Operator::F64Load { memory },
+        _ => unreachable!(),
+    }
+}
+
+fn store_op(ty: Type, memory: MemoryArg) -> Operator {
+    match ty {
+        Type::I32 => Operator::I32Store { memory },
+        Type::I64 => Operator::I64Store { memory },
+        Type::F32 => Operator::F32Store { memory },
+        Type::F64 => Operator::F64Store { memory },
+        _ => unreachable!(),
+    }
+}
+
+fn zero_op(ty: Type) -> Result<Operator> {
+    Ok(match ty {
+        Type::I32 => Operator::I32Const { value: 0 },
+        Type::I64 => Operator::I64Const { value: 0 },
+        Type::F32 => Operator::F32Const { value: 0 },
+        Type::F64 => Operator::F64Const { value: 0 },
+        _ => anyhow::bail!("Cannot return a dummy {} value while unwinding", ty),
+    })
+}
+
+fn i32_const(body: &mut FunctionBody, code: &mut Vec<Value>, value: u32) -> Value {
+    push_op(
+        body,
+        code,
+        Operator::I32Const { value },
+        &[],
+        Some(Type::I32),
+    )
+}
+
+fn jump(block: Block, args: Vec<Value>) -> BlockTarget {
+    BlockTarget { block, args }
+}
+
+/// A call that may unwind, after instrumentation: the block holding
+/// just the call, whose parameters are the values saved in its frame.
+struct CallSite {
+    block: Block,
+    types: Vec<Type>,
+}
+
+/// The frame layout for values of the given types: each value's
+/// offset and the total size, not counting the call-site index that
+/// follows the values.
+fn frame_layout(types: &[Type]) -> Result<(Vec<u32>, u32)> {
+    let mut offsets = vec![];
+    let mut size = 0;
+    for &ty in types {
+        offsets.push(size);
+        size += slot_size(ty)?;
+    }
+    Ok((offsets, size))
+}
+
+fn instrument(
+    body: &mut FunctionBody,
+    may_unwind: &dyn Fn(&Operator) -> bool,
+    globals: Globals,
+) -> Result<()> {
+    // In maximal SSA, the only values used across blocks are block
+    // parameters, so the values live across a call are all found in
+    // its block.
+    body.convert_to_max_ssa(None);
+
+    let mut sites = vec![];
+    for block in body.blocks.iter().collect::<Vec<_>>() {
+        let mut block = block;
+        while let Some(at) = body.blocks[block].insts.iter().position(
+            |&inst| matches!(&body.values[inst], ValueDef::Operator(op, ..) if may_unwind(op)),
+        ) {
+            block = split_at_call(body, block, at, globals, &mut sites)?;
+        }
+    }
+    if !sites.is_empty() {
+        add_rewind_paths(body, &sites, globals)?;
+    }
+    Ok(())
+}
+
+/// Split `block` around the call at `at`: the code before the call
+/// branches to a block holding just the call, which takes every value
+/// used from then on as a parameter, so that rewinding can enter it
+/// with restored values. After the call, that block either saves them
+/// and returns, if unwinding, or continues with the rest of the code
+/// in a new block, which is returned.
+fn split_at_call(
+    body: &mut FunctionBody,
+    block: Block,
+    at: usize,
+    globals: Globals,
+    sites: &mut Vec<CallSite>,
+) -> Result<Block> {
+    let call = body.blocks[block].insts[at];
+    let mut defined_after = HashSet::new();
+    let mut live = vec![];
+    let mut seen = HashSet::new();
+    {
+        let mut add_use = |value: Value, defined_after: &HashSet<Value>| {
+            let value = body.resolve_alias(value);
+            if !defined_after.contains(&value) && seen.insert(value) {
+                live.push(value);
+            }
+        };
+        body.values[call].visit_uses(&body.arg_pool, |value| add_use(value, &defined_after));
+        defined_after.insert(call);
+        for &inst in &body.blocks[block].insts[at + 1..] {
+            body.values[inst].visit_uses(&body.arg_pool, |value| add_use(value, &defined_after));
+            defined_after.insert(inst);
+        }
+        body.blocks[block]
+            .terminator
+            .visit_uses(|value| add_use(value, &defined_after));
+    }
+    let types = live
+        .iter()
+        .map(|&value| {
+            body.values[value]
+                .ty(&body.type_pool)
+                .ok_or_else(|| anyhow::anyhow!("Value {} has no single type", value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let rest = body.split_block(block, at + 1);
+    let call_block = body.split_block(block, at);
+    let mut renamed = HashMap::new();
+    for (&value, &ty) in live.iter().zip(types.iter()) {
+        renamed.insert(value, body.add_blockparam(call_block, ty));
+    }
+    let rename = |value: &mut Value| {
+        if let Some(&new) = renamed.get(value) {
+            *value = new;
+        }
+    };
+    // Aliases were resolved when collecting the live values, so
+    // resolve them when renaming too.
+    let insts = body.blocks[rest].insts.clone();
+    let mut arg_pool = core::mem::take(&mut body.arg_pool);
+    for inst in core::iter::once(call).chain(insts) {
+        let mut def = core::mem::replace(&mut body.values[inst], ValueDef::None);
+        def.update_uses(&mut arg_pool, |value| {
+            *value = body.resolve_alias(*value);
+            rename(value);
+        });
+        body.values[inst] = def;
+    }
+    body.arg_pool = arg_pool;
+    let mut terminator = core::mem::take(&mut body.blocks[rest].terminator);
+    terminator.update_uses(|value| {
+        *value = body.resolve_alias(*value);
+        rename(value);
+    });
+    body.blocks[rest].terminator = terminator;
+    body.set_terminator(
+        block,
+        Terminator::Br {
+            target: jump(call_block, live.clone()),
+        },
+    );
+
+    let mut code = vec![];
+    let state = Operator::GlobalGet {
+        global_index: globals.state,
+    };
+    let state = push_op(body, &mut code, state, &[], Some(Type::I32));
+    let unwinding = i32_const(body, &mut code, STATE_UNWINDING);
+    let cond = push_op(
+        body,
+        &mut code,
+        Operator::I32Eq,
+        &[state, unwinding],
+        Some(Type::I32),
+    );
+    let len = body.blocks[call_block].insts.len();
+    insert(body, call_block, len, &code);
+
+    let params = body.blocks[call_block]
+        .params
+        .iter()
+        .map(|&(_, param)| param)
+        .collect::<Vec<_>>();
+    let save = save_frame(body, &params, &types, sites.len() as u32, globals)?;
+    body.set_terminator(
+        call_block,
+        Terminator::CondBr {
+            cond,
+            if_true: jump(save, vec![]),
+            if_false: jump(rest, vec![]),
+        },
+    );
+    sites.push(CallSite {
+        block: call_block,
+        types,
+    });
+    Ok(rest)
+}
+
+/// A block that pushes `values` and the call-site index onto the
+/// frame buffer, trapping if it is full, and then returns dummy
+/// values.
+fn save_frame(
+    body: &mut FunctionBody,
+    values: &[Value],
+    types: &[Type],
+    index: u32,
+    globals: Globals,
+) -> Result<Block> {
+    let (offsets, size) = frame_layout(types)?;
+    let save = body.add_block();
+    let mut code = vec![];
+    let data = Operator::GlobalGet {
+        global_index: globals.data,
+    };
+    let data = push_op(body, &mut code, data, &[], Some(Type::I32));
+    let pos = Operator::I32Load {
+        memory: globals.memarg(0),
+    };
+    let pos = push_op(body, &mut code, pos, &[data], Some(Type::I32));
+    let end = Operator::I32Load {
+        memory: globals.memarg(4),
+    };
+    let end = push_op(body, &mut code, end, &[data], Some(Type::I32));
+    let frame_size = i32_const(body, &mut code, size + 4);
+    let new_pos = push_op(
+        body,
+        &mut code,
+        Operator::I32Add,
+        &[pos, frame_size],
+        Some(Type::I32),
+    );
+    let full = push_op(
+        body,
+        &mut code,
+        Operator::I32GtU,
+        &[new_pos, end],
+        Some(Type::I32),
+    );
+    insert(body, save, 0, &code);
+
+    let trap = body.add_block();
+    body.set_terminator(trap, Terminator::Unreachable);
+    let store = body.add_block();
+    body.set_terminator(
+        save,
+        Terminator::CondBr {
+            cond: full,
+            if_true: jump(trap, vec![]),
+            if_false: jump(store, vec![]),
+        },
+    );
+
+    let mut code = vec![];
+    for ((&value, &ty), &offset) in values.iter().zip(types).zip(&offsets) {
+        let op = store_op(ty, globals.memarg(offset));
+        push_op(body, &mut code, op, &[pos, value], None);
+    }
+    let index = i32_const(body, &mut code, index);
+    let op = Operator::I32Store {
+        memory: globals.memarg(size),
+    };
+    push_op(body, &mut code, op, &[pos, index], None);
+    let op = Operator::I32Store {
+        memory: globals.memarg(0),
+    };
+    push_op(body, &mut code, op, &[data, new_pos], None);
+    let mut values = vec![];
+    for ty in body.rets.clone() {
+        values.push(push_op(body, &mut code, zero_op(ty)?, &[], Some(ty)));
+    }
+    insert(body, store, 0, &code);
+    body.set_terminator(store, Terminator::Return { values });
+    Ok(save)
+}
+
+/// The headers of the loops containing each block, outermost first.
+fn enclosing_loops(body: &FunctionBody) -> HashMap<Block, Vec<Block>> {
+    let cfg = CFGInfo::new(body);
+    let mut loops: HashMap<Block, Vec<Block>> = HashMap::new();
+    for &header in cfg.rpo.values() {
+        let mut members = HashSet::new();
+        members.insert(header);
+        let mut stack = cfg.preds[header]
+            .iter()
+            .copied()
+            .filter(|&pred| cfg.dominates(header, pred))
+            .collect::<Vec<_>>();
+        if stack.is_empty() {
+            continue;
+        }
+        while let Some(block) = stack.pop() {
+            if members.insert(block) {
+                stack.extend(cfg.preds[block].iter().copied());
+            }
+        }
+        // Headers are visited in reverse postorder, so outer loops
+        // come first.
+        for block in members {
+            loops.entry(block).or_default().push(header);
+        }
+    }
+    loops
+}
+
+/// Make the body able to resume at any of `sites` when entered while
+/// rewinding. To keep the control flow reducible, the path to a site
+/// enters each loop around it through the loop's header: a new entry
+/// block and the headers of those loops check whether the module is
+/// rewinding, and if so look at the index of the call site to resume
+/// at and branch to the next header, or to a block that pops the
+/// frame and enters the site's block with the saved values. Loop
+/// headers are entered with zeros, which are never used on that path.
+fn add_rewind_paths(body: &mut FunctionBody, sites: &[CallSite], globals: Globals) -> Result<()> {
+    let loops = enclosing_loops(body);
+
+    let old_entry = body.entry;
+    let entry = body.add_block();
+    let params = body.blocks[old_entry]
+        .params
+        .iter()
+        .map(|&(ty, _)| ty)
+        .collect::<Vec<_>>();
+    let args = params
+        .into_iter()
+        .map(|ty| body.add_blockparam(entry, ty))
+        .collect::<Vec<_>>();
+    body.entry = entry;
+
+    let trap = body.add_block();
+    body.set_terminator(trap, Terminator::Unreachable);
+
+    // For each place that checks for rewinding (`None` for the entry),
+    // where to go next for each call site.
+    let mut routes: HashMap<Option<Block>, Vec<BlockTarget>> = HashMap::new();
+    let mut checks = vec![None];
+    for (index, site) in sites.iter().enumerate() {
+        let headers = loops.get(&site.block).cloned().unwrap_or_default();
+        let mut from = None;
+        for &header in &headers {
+            let enter = body.add_block();
+            let mut code = vec![];
+            let mut args = vec![];
+            for (ty, _) in body.blocks[header].params.clone() {
+                args.push(push_op(body, &mut code, zero_op(ty)?, &[], Some(ty)));
+            }
+            insert(body, enter, 0, &code);
+            body.set_terminator(
+                enter,
+                Terminator::Br {
+                    target: jump(header, args),
+                },
+            );
+            add_route(
+                &mut routes,
+                &mut checks,
+                from,
+                index,
+                sites.len(),
+                enter,
+                trap,
+            );
+            from = Some(header);
+        }
+        let restore = restore_frame(body, site, globals)?;
+        add_route(
+            &mut routes,
+            &mut checks,
+            from,
+            index,
+            sites.len(),
+            restore,
+            trap,
+        );
+    }
+
+    for check in checks {
+        let (block, rest) = match check {
+            Some(header) => (header, body.split_block(header, 0)),
+            None => (entry, old_entry),
+        };
+        let mut code = vec![];
+        let state = Operator::GlobalGet {
+            global_index: globals.state,
+        };
+        let state = push_op(body, &mut code, state, &[], Some(Type::I32));
+        let rewinding = i32_const(body, &mut code, STATE_REWINDING);
+        let cond = push_op(
+            body,
+            &mut code,
+            Operator::I32Eq,
+            &[state, rewinding],
+            Some(Type::I32),
+        );
+        insert(body, block, 0, &code);
+
+        // The index of the call site to resume at is the last word of
+        // the innermost saved frame.
+        let dispatch = body.add_block();
+        let mut code = vec![];
+        let data = Operator::GlobalGet {
+            global_index: globals.data,
+        };
+        let data = push_op(body, &mut code, data, &[], Some(Type::I32));
+        let pos = Operator::I32Load {
+            memory: globals.memarg(0),
+        };
+        let pos = push_op(body, &mut code, pos, &[data], Some(Type::I32));
+        let four = i32_const(body, &mut code, 4);
+        let at = push_op(
+            body,
+            &mut code,
+            Operator::I32Sub,
+            &[pos, four],
+            Some(Type::I32),
+        );
+        let index = Operator::I32Load {
+            memory: globals.memarg(0),
+        };
+        let index = push_op(body, &mut code, index, &[at], Some(Type::I32));
+        insert(body, dispatch, 0, &code);
+        body.set_terminator(
+            dispatch,
+            Terminator::Select {
+                value: index,
+                targets: routes.remove(&check).unwrap(),
+                default: jump(trap, vec![]),
+            },
+        );
+
+        let args = match check {
+            Some(_) => vec![],
+            None => args.clone(),
+        };
+        body.set_terminator(
+            block,
+            Terminator::CondBr {
+                cond,
+                if_true: jump(dispatch, vec![]),
+                if_false: jump(rest, args),
+            },
+        );
+    }
+    Ok(())
+}
+
+fn add_route(
+    routes: &mut HashMap<Option<Block>, Vec<BlockTarget>>,
+    checks: &mut Vec<Option<Block>>,
+    from: Option<Block>,
+    index: usize,
+    num_sites: usize,
+    to: Block,
+    trap: Block,
+) {
+    let targets = routes.entry(from).or_insert_with(|| {
+        if from.is_some() {
+            checks.push(from);
+        }
+        vec![jump(trap, vec![]); num_sites]
+    });
+    targets[index] = jump(to, vec![]);
+}
+
+/// A block that pops the frame of `site` and enters its block with
+/// the saved values.
+fn restore_frame(body: &mut FunctionBody, site: &CallSite, globals: Globals) -> Result<Block> {
+    let (offsets, size) = frame_layout(&site.types)?;
+    let restore = body.add_block();
+    let mut code = vec![];
+    let data = Operator::GlobalGet {
+        global_index: globals.data,
+    };
+    let data = push_op(body, &mut code, data, &[], Some(Type::I32));
+    let pos = Operator::I32Load {
+        memory: globals.memarg(0),
+    };
+    let pos = push_op(body, &mut code, pos, &[data], Some(Type::I32));
+    let frame_size = i32_const(body, &mut code, size + 4);
+    let pos = push_op(
+        body,
+        &mut code,
+        Operator::I32Sub,
+        &[pos, frame_size],
+        Some(Type::I32),
+    );
+    let op = Operator::I32Store {
+        memory: globals.memarg(0),
+    };
+    push_op(body, &mut code, op, &[data, pos], None);
+    let mut values = vec![];
+    for (&ty, &offset) in site.types.iter().zip(&offsets) {
+        let op = load_op(ty, globals.memarg(offset));
+        values.push(push_op(body, &mut code, op, &[pos], Some(ty)));
+    }
+    insert(body, restore, 0, &code);
+    body.set_terminator(
+        restore,
+        Terminator::Br {
+            target: jump(site.block, values),
+        },
+    );
+    Ok(restore)
+}
+
+/// Add the exported control functions.
+fn add_exports(module: &mut Module<'_>, globals: Globals) {
+    let start_sig = module.find_or_add_signature(SignatureData {
+        params: vec![Type::I32],
+        returns: vec![],
+    });
+    let stop_sig = module.find_or_add_signature(SignatureData {
+        params: vec![],
+        returns: vec![],
+    });
+    let get_sig = module.find_or_add_signature(SignatureData {
+        params: vec![],
+        returns: vec![Type::I32],
+    });
+    let set = |global_index| Operator::GlobalSet { global_index };
+    let funcs = [
+        ("asyncify_start_unwind", start_sig, Some(STATE_UNWINDING)),
+        ("asyncify_stop_unwind", stop_sig, Some(STATE_NORMAL)),
+        ("asyncify_start_rewind", start_sig, Some(STATE_REWINDING)),
+        ("asyncify_stop_rewind", stop_sig, Some(STATE_NORMAL)),
+        ("asyncify_get_state", get_sig, None),
+    ];
+    for (name, sig, state) in funcs {
+        let mut body = FunctionBody::new(module, sig);
+        let mut code = vec![];
+        let mut returns = vec![];
+        match state {
+            Some(state) => {
+                let state = i32_const(&mut body, &mut code, state);
+                push_op(&mut body, &mut code, set(globals.state), &[state], None);
+                if let Some(&(_, data)) = body.blocks[body.entry].params.first() {
+                    push_op(&mut body, &mut code, set(globals.data), &[data], None);
+                }
+            }
+            None => {
+                let get = Operator::GlobalGet {
+                    global_index: globals.state,
+                };
+                returns.push(push_op(&mut body, &mut code, get, &[], Some(Type::I32)));
+            }
+        }
+        let entry = body.entry;
+        insert(&mut body, entry, 0, &code);
+        body.set_terminator(entry, Terminator::Return { values: returns });
+        let func = module.add_function(sig, name, body);
+        module.exports.push(Export {
+            name: name.to_owned(),
+            kind: ExportKind::Func(func),
+        });
+    }
+}
+
+/// Make every function that may (transitively) call an unwinding
+/// import able to unwind and rewind, and add the control exports.
+pub fn run(module: &mut Module<'_>, options: &AsyncifyOptions) -> Result<()> {
+    if module.memories.get(options.memory).is_none() {
+        anyhow::bail!("No such memory: {}", options.memory);
+    }
+    module.expand_all_funcs()?;
+
+    let mut unwinds = HashSet::new();
+    for import in &module.imports {
+        if let ImportKind::Func(func) = import.kind {
+            let listed = match &options.imports {
+                Some(imports) => imports
+                    .iter()
+                    .any(|(module, name)| *module == import.module && *name == import.name),
+                None => true,
+            };
+            if listed {
+                unwinds.insert(func);
+            }
+        }
+    }
+
+    // Find the functions that may unwind: those that call one that
+    // may, until nothing changes.
+    let mut calls = HashMap::new();
+    for (func, decl) in module.funcs.entries() {
+        match decl {
+            FuncDecl::Body(_, _, body) => {
+                let mut callees = HashSet::new();
+                let mut indirect = false;
+                for def in body.values.values() {
+                    match def {
+                        ValueDef::Operator(Operator::Call { function_index }, ..) => {
+                            callees.insert(*function_index);
+                        }
+                        ValueDef::Operator(Operator::CallIndirect { .. }, ..) => indirect = true,
+                        _ => {}
+                    }
+                }
+                calls.insert(func, (callees, indirect && options.indirect_calls));
+            }
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot instrument {}: it is already compiled", func)
+            }
+            _ => {}
+        }
+    }
+    let mut instrumented: HashSet<Func> = HashSet::new();
+    loop {
+        let before = instrumented.len();
+        for (&func, (callees, indirect)) in &calls {
+            if *indirect
+                || callees
+                    .iter()
+                    .any(|callee| unwinds.contains(callee) || instrumented.contains(callee))
+            {
+                instrumented.insert(func);
+            }
+        }
+        if instrumented.len() == before {
+            break;
+        }
+    }
+    unwinds.extend(instrumented.iter().copied());
+
+    let globals = Globals {
+        state: module.add_global(Type::I32, true, GlobalInit::Const(0))?,
+        data: module.add_global(Type::I32, true, GlobalInit::Const(0))?,
+        memory: options.memory,
+    };
+    let may_unwind = |op: &Operator| match op {
+        Operator::Call { function_index } => unwinds.contains(function_index),
+        Operator::CallIndirect { .. } => options.indirect_calls,
+        _ => false,
+    };
+    let mut instrumented = instrumented.into_iter().collect::<Vec<_>>();
+    instrumented.sort_by_key(|func| func.index());
+    for func in instrumented {
+        if let FuncDecl::Body(_, _, body) = &mut module.funcs[func] {
+            instrument(body, &may_unwind, globals)?;
+        }
+    }
+    add_exports(module, globals);
+    Ok(())
+}
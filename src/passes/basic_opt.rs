@@ -5,6 +5,7 @@ use crate::interp::{const_eval, ConstVal};
 use crate::ir::*;
 use crate::passes::dom_pass::{dom_pass, DomtreePass};
 use crate::pool::ListRef;
+use crate::prelude::*;
 use crate::scoped_map::ScopedMap;
 use crate::Operator;
 
@@ -0,0 +1,473 @@
+//! Branch and block frequency profiling pass.
+
+use super::hooks::{insert, push_op};
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::*;
+use crate::leb128::{write_u32, write_u64, Reader};
+use crate::ops::MemoryArg;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+use core::convert::TryInto;
+
+/// The counters of one conditional branch: one per target, in the
+/// order `if_true`, `if_false` for a `CondBr`, and the targets and
+/// then the default for a `Select`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BranchCounters {
+    pub block: Block,
+    /// Byte offset of the first counter from the start of the region.
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// The counters of one function: how often it was entered, and how
+/// often each of its conditional branches went each way.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuncCounters {
+    pub func: Func,
+    /// Byte offset of the entry counter from the start of the region.
+    pub entry: u32,
+    pub branches: Vec<BranchCounters>,
+}
+
+/// Where the counters of an instrumented module are. Counters are
+/// little-endian wrapping `u64`s, placed in a zeroed data segment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BranchProfileMap {
+    pub memory: Memory,
+    pub base: u32,
+    pub funcs: Vec<FuncCounters>,
+    /// The size in bytes of the counter region.
+    pub size: u32,
+}
+
+impl BranchProfileMap {
+    pub const SECTION_NAME: &'static str = "waffle.branchprofile";
+
+    /// The section encoding: the memory index, base and region size,
+    /// then a vector of functions, each with its index, entry counter
+    /// offset and a vector of branches, each with its block index,
+    /// counter offset and number of counters.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_u32(&mut out, self.memory.index() as u32);
+        write_u32(&mut out, self.base);
+        write_u32(&mut out, self.size);
+        write_u32(&mut out, self.funcs.len() as u32);
+        for func in &self.funcs {
+            write_u32(&mut out, func.func.index() as u32);
+            write_u32(&mut out, func.entry);
+            write_u32(&mut out, func.branches.len() as u32);
+            for branch in &func.branches {
+                write_u32(&mut out, branch.block.index() as u32);
+                write_u32(&mut out, branch.offset);
+                write_u32(&mut out, branch.len);
+            }
+        }
+        out
+    }
+
+    pub fn parse(data: &[u8]) -> Result<BranchProfileMap> {
+        let mut reader = Reader::new(data);
+        let memory = Memory::new(reader.read_var_u32()? as usize);
+        let base = reader.read_var_u32()?;
+        let size = reader.read_var_u32()?;
+        let mut funcs = vec![];
+        for _ in 0..reader.read_var_u32()? {
+            let func = Func::new(reader.read_var_u32()? as usize);
+            let entry = reader.read_var_u32()?;
+            let mut branches = vec![];
+            for _ in 0..reader.read_var_u32()? {
+                branches.push(BranchCounters {
+                    block: Block::new(reader.read_var_u32()? as usize),
+                    offset: reader.read_var_u32()?,
+                    len: reader.read_var_u32()?,
+                });
+            }
+            funcs.push(FuncCounters {
+                func,
+                entry,
+                branches,
+            });
+        }
+        if !reader.eof() {
+            anyhow::bail!("Trailing bytes in branch profile section");
+        }
+        Ok(BranchProfileMap {
+            memory,
+            base,
+            funcs,
+            size,
+        })
+    }
+
+    /// Turn a dump of the counter region (`size` bytes from `base`)
+    /// into a profile.
+    pub fn decode(&self, dump: &[u8]) -> Result<BranchProfile> {
+        if dump.len() < self.size as usize {
+            anyhow::bail!(
+                "Expected {} bytes of counters, got {}",
+                self.size,
+                dump.len()
+            );
+        }
+        let count = |offset: u32| {
+            let at = offset as usize;
+            u64::from_le_bytes(dump[at..at + 8].try_into().unwrap())
+        };
+        let funcs = self
+            .funcs
+            .iter()
+            .map(|func| FuncProfile {
+                func: func.func,
+                entries: count(func.entry),
+                branches: func
+                    .branches
+                    .iter()
+                    .map(|branch| BranchCounts {
+                        block: branch.block,
+                        counts: (0..branch.len)
+                            .map(|i| count(branch.offset + i * 8))
+                            .collect(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(BranchProfile { funcs })
+    }
+}
+
+/// How often a conditional branch went to each of its targets, in the
+/// order of `BranchCounters`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BranchCounts {
+    pub block: Block,
+    pub counts: Vec<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuncProfile {
+    pub func: Func,
+    pub entries: u64,
+    pub branches: Vec<BranchCounts>,
+}
+
+impl FuncProfile {
+    pub fn branch(&self, block: Block) -> Option<&BranchCounts> {
+        self.branches.iter().find(|branch| branch.block == block)
+    }
+
+    /// How often each block of `body`, the body the profile was taken
+    /// of, ran. Unconditional branches pass on the frequency of their
+    /// block. A cycle of unconditional branches that ran has no
+    /// meaningful frequency (the program never left it), and its
+    /// blocks get whatever the bounded propagation reached.
+    pub fn block_frequencies(&self, body: &FunctionBody) -> Result<PerEntity<Block, u64>> {
+        for branch in &self.branches {
+            let expected = match body.blocks.get(branch.block).map(|def| &def.terminator) {
+                Some(Terminator::CondBr { .. }) => 2,
+                Some(Terminator::Select { targets, .. }) => targets.len() + 1,
+                _ => anyhow::bail!(
+                    "Profile of {} does not match: {} has no conditional branch",
+                    self.func,
+                    branch.block
+                ),
+            };
+            if branch.counts.len() != expected {
+                anyhow::bail!(
+                    "Profile of {} does not match: {} has {} targets, not {}",
+                    self.func,
+                    branch.block,
+                    expected,
+                    branch.counts.len()
+                );
+            }
+        }
+
+        let mut freqs: PerEntity<Block, u64> = PerEntity::default();
+        for _ in 0..=body.blocks.len() {
+            let mut next: PerEntity<Block, u64> = PerEntity::default();
+            next[body.entry] = self.entries;
+            let mut add =
+                |block: Block, count: u64| next[block] = next[block].saturating_add(count);
+            for (block, def) in body.blocks.entries() {
+                match &def.terminator {
+                    Terminator::Br { target } => add(target.block, freqs[block]),
+                    Terminator::CondBr {
+                        if_true, if_false, ..
+                    } => {
+                        let counts = self.branch(block).map(|branch| &branch.counts[..]);
+                        let counts = counts.unwrap_or(&[0, 0]);
+                        add(if_true.block, counts[0]);
+                        add(if_false.block, counts[1]);
+                    }
+                    Terminator::Select {
+                        targets, default, ..
+                    } => {
+                        let counts = self.branch(block).map(|branch| &branch.counts[..]);
+                        for (i, target) in targets.iter().chain(Some(default)).enumerate() {
+                            add(target.block, counts.map(|counts| counts[i]).unwrap_or(0));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            let done = body.blocks.iter().all(|block| next[block] == freqs[block]);
+            freqs = next;
+            if done {
+                break;
+            }
+        }
+        Ok(freqs)
+    }
+}
+
+/// A profile of a module's branches, independent of where the
+/// counters were placed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BranchProfile {
+    pub funcs: Vec<FuncProfile>,
+}
+
+impl BranchProfile {
+    pub const MAGIC: &'static [u8; 4] = b"WBPF";
+    pub const VERSION: u32 = 1;
+
+    /// The file encoding: `MAGIC`, the version, then a vector of
+    /// functions, each with its index, entry count and a vector of
+    /// branches, each with its block index and a vector of counts.
+    /// Counts are unsigned LEB128 `u64`s.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Self::MAGIC.to_vec();
+        write_u32(&mut out, Self::VERSION);
+        write_u32(&mut out, self.funcs.len() as u32);
+        for func in &self.funcs {
+            write_u32(&mut out, func.func.index() as u32);
+            write_u64(&mut out, func.entries);
+            write_u32(&mut out, func.branches.len() as u32);
+            for branch in &func.branches {
+                write_u32(&mut out, branch.block.index() as u32);
+                write_u32(&mut out, branch.counts.len() as u32);
+                for count in &branch.counts {
+                    write_u64(&mut out, *count);
+                }
+            }
+        }
+        out
+    }
+
+    pub fn parse(data: &[u8]) -> Result<BranchProfile> {
+        if !data.starts_with(Self::MAGIC) {
+            anyhow::bail!("Not a branch profile");
+        }
+        let mut reader = Reader::new(&data[Self::MAGIC.len()..]);
+        let version = reader.read_var_u32()?;
+        if version != Self::VERSION {
+            anyhow::bail!("Unsupported branch profile version: {}", version);
+        }
+        let mut funcs = vec![];
+        for _ in 0..reader.read_var_u32()? {
+            let func = Func::new(reader.read_var_u32()? as usize);
+            let entries = reader.read_var_u64()?;
+            let mut branches = vec![];
+            for _ in 0..reader.read_var_u32()? {
+                let block = Block::new(reader.read_var_u32()? as usize);
+                let counts = (0..reader.read_var_u32()?)
+                    .map(|_| reader.read_var_u64())
+                    .collect::<core::result::Result<_, _>>()?;
+                branches.push(BranchCounts { block, counts });
+            }
+            funcs.push(FuncProfile {
+                func,
+                entries,
+                branches,
+            });
+        }
+        if !reader.eof() {
+            anyhow::bail!("Trailing bytes in branch profile");
+        }
+        Ok(BranchProfile { funcs })
+    }
+
+    pub fn func(&self, func: Func) -> Option<&FuncProfile> {
+        self.funcs.iter().find(|profile| profile.func == func)
+    }
+
+    /// Set the `frequency` of every block of each profiled function.
+    /// The module must be the one that was instrumented, before
+    /// instrumentation, parsed the same way, so that its blocks
+    /// match. Bodies that have not been parsed yet are expanded;
+    /// already-compiled bodies cannot be annotated.
+    pub fn apply(&self, module: &mut Module<'_>) -> Result<()> {
+        for profile in &self.funcs {
+            if profile.func.index() >= module.funcs.len() {
+                anyhow::bail!("No such function: {}", profile.func);
+            }
+            let body = match module.expand_func(profile.func)? {
+                FuncDecl::Body(_, _, body) => body,
+                FuncDecl::Compiled(..) => {
+                    anyhow::bail!("Cannot annotate {}: it is already compiled", profile.func)
+                }
+                _ => anyhow::bail!("Profiled function {} has no body", profile.func),
+            };
+            let freqs = profile.block_frequencies(body)?;
+            for block in body.blocks.iter().collect::<Vec<_>>() {
+                body.blocks[block].frequency = Some(freqs[block]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Code incrementing the counter at `offset` plus `address` (`None`
+/// for zero).
+fn count(
+    body: &mut FunctionBody,
+    code: &mut Vec<Value>,
+    memory: Memory,
+    offset: u32,
+    address: Option<Value>,
+) {
+    let memory = MemoryArg {
+        align: 3,
+        offset,
+        memory,
+    };
+    let address = address.unwrap_or_else(|| {
+        let zero = Operator::I32Const { value: 0 };
+        push_op(body, code, zero, &[], Some(Type::I32))
+    });
+    let load = Operator::I64Load { memory };
+    let count = push_op(body, code, load, &[address], Some(Type::I64));
+    let one = Operator::I64Const { value: 1 };
+    let one = push_op(body, code, one, &[], Some(Type::I64));
+    let count = push_op(body, code, Operator::I64Add, &[count, one], Some(Type::I64));
+    let store = Operator::I64Store { memory };
+    push_op(body, code, store, &[address, count], None);
+}
+
+/// Count how often every defined function is entered and every
+/// conditional branch in it goes each way, into counters at `base` in
+/// `memory`, added as a zeroed data segment, and describe them in a
+/// `waffle.branchprofile` section. No blocks are added, so the
+/// profile applies to the uninstrumented module. Bodies that have not
+/// been parsed yet are expanded; already-compiled bodies cannot be
+/// instrumented. Returns the map.
+pub fn run(module: &mut Module<'_>, memory: Memory, base: u32) -> Result<BranchProfileMap> {
+    if module.memories.get(memory).is_none() {
+        anyhow::bail!("No such memory: {}", memory);
+    }
+
+    let mut map = BranchProfileMap {
+        memory,
+        base,
+        funcs: vec![],
+        size: 0,
+    };
+    let mut alloc = |len: u32| {
+        let offset = map.size;
+        map.size = len
+            .checked_mul(8)
+            .and_then(|len| map.size.checked_add(len))
+            .filter(|size| base.checked_add(*size).is_some())
+            .ok_or_else(|| anyhow::anyhow!("Branch counters overflow memory"))?;
+        Ok::<_, anyhow::Error>(base + offset)
+    };
+    let mut funcs = vec![];
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot instrument {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        let entry = alloc(1)?;
+        let mut code = vec![];
+        count(body, &mut code, memory, entry, None);
+        insert(body, body.entry, 0, &code);
+
+        let mut branches = vec![];
+        for block in body.blocks.iter().collect::<Vec<_>>() {
+            let mut code = vec![];
+            let i32_const = |body: &mut FunctionBody, code: &mut Vec<Value>, value: u32| {
+                let op = Operator::I32Const { value };
+                push_op(body, code, op, &[], Some(Type::I32))
+            };
+            let (len, slot) = match &body.blocks[block].terminator {
+                &Terminator::CondBr { cond, .. } => {
+                    let zero = i32_const(body, &mut code, 0);
+                    let eight = i32_const(body, &mut code, 8);
+                    let slot = push_op(
+                        body,
+                        &mut code,
+                        Operator::Select,
+                        &[zero, eight, cond],
+                        Some(Type::I32),
+                    );
+                    (2, slot)
+                }
+                Terminator::Select { value, targets, .. } => {
+                    // Out-of-range values go to the default, the last
+                    // counter.
+                    let (value, len) = (*value, targets.len() as u32);
+                    let last = i32_const(body, &mut code, len);
+                    let in_range = push_op(
+                        body,
+                        &mut code,
+                        Operator::I32LtU,
+                        &[value, last],
+                        Some(Type::I32),
+                    );
+                    let index = push_op(
+                        body,
+                        &mut code,
+                        Operator::Select,
+                        &[value, last, in_range],
+                        Some(Type::I32),
+                    );
+                    let eight = i32_const(body, &mut code, 8);
+                    let slot = push_op(
+                        body,
+                        &mut code,
+                        Operator::I32Mul,
+                        &[index, eight],
+                        Some(Type::I32),
+                    );
+                    (len + 1, slot)
+                }
+                _ => continue,
+            };
+            let offset = alloc(len)?;
+            count(body, &mut code, memory, offset, Some(slot));
+            let at = body.blocks[block].insts.len();
+            insert(body, block, at, &code);
+            branches.push(BranchCounters {
+                block,
+                offset: offset - base,
+                len,
+            });
+        }
+        funcs.push(FuncCounters {
+            func,
+            entry: entry - base,
+            branches,
+        });
+    }
+    map.funcs = funcs;
+
+    module.add_data_segment(DataSegment {
+        kind: DataSegmentKind::Active {
+            memory,
+            offset: SegmentOffset::Const(base as usize),
+        },
+        data: vec![0; map.size as usize],
+    })?;
+    let data = map.encode();
+    match module.custom_section_mut(BranchProfileMap::SECTION_NAME) {
+        Some(section) => section.data = data,
+        None => module.add_custom_section(BranchProfileMap::SECTION_NAME, data),
+    }
+    Ok(map)
+}
@@ -0,0 +1,313 @@
+//! Call-graph edge profiling pass.
+
+use super::hooks::push_op;
+use crate::collections::HashMap;
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::leb128::{write_u32, Reader};
+use crate::ops::MemoryArg;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+use core::convert::TryInto;
+
+/// What a group of counters counts calls to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CallTarget {
+    /// Direct calls of a function: one counter.
+    Func(Func),
+    /// Indirect calls through a table: one counter per slot the table
+    /// had at instrumentation time, and one for all later slots.
+    Table(Table),
+}
+
+/// A group of counters for calls from one function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallCounters {
+    pub caller: Func,
+    pub target: CallTarget,
+    /// Byte offset of the first counter from the start of the region.
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// Where the counters of an instrumented module are. Counters are
+/// little-endian wrapping `u64`s, placed in a zeroed data segment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallProfileMap {
+    pub memory: Memory,
+    pub base: u32,
+    pub counters: Vec<CallCounters>,
+}
+
+/// The callee of a profiled call edge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Callee {
+    Func(Func),
+    /// A call through `table`; `slot` is `None` for slots past the
+    /// table's size at instrumentation time.
+    TableSlot {
+        table: Table,
+        slot: Option<u32>,
+    },
+}
+
+/// How often one call edge was taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallEdgeCount {
+    pub caller: Func,
+    pub callee: Callee,
+    pub count: u64,
+}
+
+impl CallProfileMap {
+    pub const SECTION_NAME: &'static str = "waffle.callprofile";
+
+    /// The size in bytes of the counter region.
+    pub fn size(&self) -> u32 {
+        self.counters
+            .last()
+            .map(|counters| counters.offset + counters.len * 8)
+            .unwrap_or(0)
+    }
+
+    /// The section encoding: the memory index and base, then a vector
+    /// of counter groups, each with its caller, a target kind (0 for a
+    /// function, 1 for a table) and index, offset and length.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_u32(&mut out, self.memory.index() as u32);
+        write_u32(&mut out, self.base);
+        write_u32(&mut out, self.counters.len() as u32);
+        for counters in &self.counters {
+            write_u32(&mut out, counters.caller.index() as u32);
+            let (kind, index) = match counters.target {
+                CallTarget::Func(func) => (0, func.index()),
+                CallTarget::Table(table) => (1, table.index()),
+            };
+            out.push(kind);
+            write_u32(&mut out, index as u32);
+            write_u32(&mut out, counters.offset);
+            write_u32(&mut out, counters.len);
+        }
+        out
+    }
+
+    pub fn parse(data: &[u8]) -> Result<CallProfileMap> {
+        let mut reader = Reader::new(data);
+        let memory = Memory::new(reader.read_var_u32()? as usize);
+        let base = reader.read_var_u32()?;
+        let mut counters = vec![];
+        for _ in 0..reader.read_var_u32()? {
+            let caller = Func::new(reader.read_var_u32()? as usize);
+            let target = match (reader.read_u8()?, reader.read_var_u32()? as usize) {
+                (0, index) => CallTarget::Func(Func::new(index)),
+                (1, index) => CallTarget::Table(Table::new(index)),
+                (kind, _) => anyhow::bail!("Invalid call target kind: {}", kind),
+            };
+            counters.push(CallCounters {
+                caller,
+                target,
+                offset: reader.read_var_u32()?,
+                len: reader.read_var_u32()?,
+            });
+        }
+        if !reader.eof() {
+            anyhow::bail!("Trailing bytes in call profile section");
+        }
+        Ok(CallProfileMap {
+            memory,
+            base,
+            counters,
+        })
+    }
+
+    /// Turn a dump of the counter region (`size()` bytes from `base`)
+    /// into the edges that were taken, most frequent first.
+    pub fn decode(&self, dump: &[u8]) -> Result<Vec<CallEdgeCount>> {
+        if dump.len() < self.size() as usize {
+            anyhow::bail!(
+                "Expected {} bytes of counters, got {}",
+                self.size(),
+                dump.len()
+            );
+        }
+        let mut edges = vec![];
+        for counters in &self.counters {
+            for i in 0..counters.len {
+                let at = (counters.offset + i * 8) as usize;
+                let count = u64::from_le_bytes(dump[at..at + 8].try_into().unwrap());
+                if count == 0 {
+                    continue;
+                }
+                let callee = match counters.target {
+                    CallTarget::Func(func) => Callee::Func(func),
+                    CallTarget::Table(table) => Callee::TableSlot {
+                        table,
+                        slot: Some(i).filter(|&slot| slot + 1 < counters.len),
+                    },
+                };
+                edges.push(CallEdgeCount {
+                    caller: counters.caller,
+                    callee,
+                    count,
+                });
+            }
+        }
+        edges.sort_by_key(|edge| core::cmp::Reverse(edge.count));
+        Ok(edges)
+    }
+}
+
+/// Count the calls along every call edge of every defined function
+/// into counters at `base` in `memory`, added as a zeroed data
+/// segment, and describe them in a `waffle.callprofile` section. A
+/// call is counted when it is made, even if the callee then traps.
+/// Bodies that have not been parsed yet are expanded;
+/// already-compiled bodies cannot be instrumented. Returns the map.
+pub fn run(module: &mut Module<'_>, memory: Memory, base: u32) -> Result<CallProfileMap> {
+    if module.memories.get(memory).is_none() {
+        anyhow::bail!("No such memory: {}", memory);
+    }
+    let slots: HashMap<Table, u32> = module
+        .tables
+        .entries()
+        .map(|(table, data)| {
+            let len = data.func_elements.as_ref().map(|elems| elems.len());
+            (table, len.unwrap_or(0) as u32)
+        })
+        .collect();
+
+    let mut map = CallProfileMap {
+        memory,
+        base,
+        counters: vec![],
+    };
+    let mut size = 0u32;
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot instrument {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        let mut groups: HashMap<CallTarget, u32> = HashMap::new();
+        for block in body.blocks.iter().collect::<Vec<_>>() {
+            let insts = core::mem::take(&mut body.blocks[block].insts);
+            let mut new_insts = vec![];
+            for &inst in &insts {
+                let (target, args) = match &body.values[inst] {
+                    ValueDef::Operator(Operator::Call { function_index }, args, _) => {
+                        (CallTarget::Func(*function_index), *args)
+                    }
+                    ValueDef::Operator(Operator::CallIndirect { table_index, .. }, args, _) => {
+                        (CallTarget::Table(*table_index), *args)
+                    }
+                    _ => {
+                        new_insts.push(inst);
+                        continue;
+                    }
+                };
+                let offset = match groups.get(&target) {
+                    Some(&offset) => offset,
+                    None => {
+                        let len = match target {
+                            CallTarget::Func(_) => 1,
+                            CallTarget::Table(table) => slots[&table] + 1,
+                        };
+                        let offset = size;
+                        size = len
+                            .checked_mul(8)
+                            .and_then(|len| size.checked_add(len))
+                            .filter(|size| base.checked_add(*size).is_some())
+                            .ok_or_else(|| anyhow::anyhow!("Call counters overflow memory"))?;
+                        map.counters.push(CallCounters {
+                            caller: func,
+                            target,
+                            offset,
+                            len,
+                        });
+                        groups.insert(target, offset);
+                        offset
+                    }
+                };
+
+                let memory = MemoryArg {
+                    align: 3,
+                    offset: base + offset,
+                    memory,
+                };
+                let address = match target {
+                    CallTarget::Func(_) => {
+                        let zero = Operator::I32Const { value: 0 };
+                        push_op(body, &mut new_insts, zero, &[], Some(Type::I32))
+                    }
+                    CallTarget::Table(table) => {
+                        // Slots past the end share the last counter.
+                        let index = *body.arg_pool[args].last().unwrap();
+                        let last = Operator::I32Const {
+                            value: slots[&table],
+                        };
+                        let last = push_op(body, &mut new_insts, last, &[], Some(Type::I32));
+                        let in_range = push_op(
+                            body,
+                            &mut new_insts,
+                            Operator::I32LtU,
+                            &[index, last],
+                            Some(Type::I32),
+                        );
+                        let slot = push_op(
+                            body,
+                            &mut new_insts,
+                            Operator::Select,
+                            &[index, last, in_range],
+                            Some(Type::I32),
+                        );
+                        let eight = Operator::I32Const { value: 8 };
+                        let eight = push_op(body, &mut new_insts, eight, &[], Some(Type::I32));
+                        push_op(
+                            body,
+                            &mut new_insts,
+                            Operator::I32Mul,
+                            &[slot, eight],
+                            Some(Type::I32),
+                        )
+                    }
+                };
+                let load = Operator::I64Load { memory };
+                let count = push_op(body, &mut new_insts, load, &[address], Some(Type::I64));
+                let one = Operator::I64Const { value: 1 };
+                let one = push_op(body, &mut new_insts, one, &[], Some(Type::I64));
+                let count = push_op(
+                    body,
+                    &mut new_insts,
+                    Operator::I64Add,
+                    &[count, one],
+                    Some(Type::I64),
+                );
+                let store = Operator::I64Store { memory };
+                push_op(body, &mut new_insts, store, &[address, count], None);
+                new_insts.push(inst);
+            }
+            for &inst in &new_insts {
+                body.value_blocks[inst] = block;
+            }
+            body.blocks[block].insts = new_insts;
+        }
+    }
+
+    module.add_data_segment(DataSegment {
+        kind: DataSegmentKind::Active {
+            memory,
+            offset: SegmentOffset::Const(base as usize),
+        },
+        data: vec![0; size as usize],
+    })?;
+    let data = map.encode();
+    match module.custom_section_mut(CallProfileMap::SECTION_NAME) {
+        Some(section) => section.data = data,
+        None => module.add_custom_section(CallProfileMap::SECTION_NAME, data),
+    }
+    Ok(map)
+}
@@ -0,0 +1,244 @@
+//! Control-flow integrity for indirect calls.
+
+use super::hooks::{insert, push_op};
+use crate::collections::HashMap;
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::ops::MemoryArg;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// The tags of one table's slots: little-endian `u32`s at `offset`
+/// from the base of the tag region, one per slot the table had at
+/// instrumentation time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CfiTable {
+    pub table: Table,
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// Where the tags of an instrumented module are.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CfiMap {
+    pub memory: Memory,
+    pub base: u32,
+    pub tables: Vec<CfiTable>,
+    /// The tag of each signature. Signatures with the same types share
+    /// a tag; 0 marks an empty slot and is never a signature's tag.
+    pub tags: HashMap<Signature, u32>,
+}
+
+impl CfiMap {
+    /// The size in bytes of the tag region.
+    pub fn size(&self) -> u32 {
+        self.tables
+            .last()
+            .map(|table| table.offset + table.len.max(1) * 4)
+            .unwrap_or(0)
+    }
+}
+
+/// Tag every table slot with the signature of the function placed
+/// there by the module's element segments, in a data segment at
+/// `base` in `memory`, and make every `call_indirect` in a defined
+/// function trap unless the slot's tag is that of the call's
+/// signature. Unlike the engine's own signature check, this ignores
+/// whatever `table.set`, `table.grow` or the embedder later put in
+/// the table: calls through slots that were empty or past the end of
+/// the table at instrumentation time trap. The tags are only as safe
+/// as the memory they are in. Bodies that have not been parsed yet
+/// are expanded; already-compiled bodies cannot be instrumented.
+/// Returns the map.
+pub fn run(module: &mut Module<'_>, memory: Memory, base: u32) -> Result<CfiMap> {
+    if module.memories.get(memory).is_none() {
+        anyhow::bail!("No such memory: {}", memory);
+    }
+
+    let mut canonical: HashMap<SignatureData, u32> = HashMap::new();
+    let mut tags = HashMap::new();
+    for (sig, data) in module.signatures.entries() {
+        let next = canonical.len() as u32 + 1;
+        tags.insert(sig, *canonical.entry(data.clone()).or_insert(next));
+    }
+
+    let mut map = CfiMap {
+        memory,
+        base,
+        tables: vec![],
+        tags,
+    };
+    let mut data = vec![];
+    for (table, table_data) in module.tables.entries() {
+        if table_data.ty != Type::FuncRef {
+            continue;
+        }
+        let elements = match module.table_contents(table) {
+            Some(elements) => elements,
+            None => anyhow::bail!(
+                "Cannot tag the slots of {}: an element segment is at an unknown offset or out of bounds",
+                table
+            ),
+        };
+        map.tables.push(CfiTable {
+            table,
+            offset: data.len() as u32,
+            len: elements.len() as u32,
+        });
+        for &func in &elements {
+            let tag = if func.is_valid() {
+                map.tags[&module.funcs[func].sig()]
+            } else {
+                0
+            };
+            data.extend_from_slice(&tag.to_le_bytes());
+        }
+        // Keep a slot's worth of zeros even for an empty table, so
+        // that the (discarded) tag load of an out-of-range call stays
+        // in bounds.
+        if elements.is_empty() {
+            data.extend_from_slice(&[0; 4]);
+        }
+    }
+    if base.checked_add(data.len() as u32).is_none() {
+        anyhow::bail!("CFI tags overflow memory");
+    }
+    let tables: HashMap<Table, CfiTable> = map
+        .tables
+        .iter()
+        .map(|table| (table.table, *table))
+        .collect();
+
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot instrument {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        for block in body.blocks.iter().collect::<Vec<_>>() {
+            let mut block = block;
+            let mut at = 0;
+            while at < body.blocks[block].insts.len() {
+                let inst = body.blocks[block].insts[at];
+                let (sig, table, index) = match &body.values[inst] {
+                    ValueDef::Operator(
+                        Operator::CallIndirect {
+                            sig_index,
+                            table_index,
+                        },
+                        args,
+                        _,
+                    ) => (
+                        *sig_index,
+                        *table_index,
+                        *body.arg_pool[*args].last().unwrap(),
+                    ),
+                    _ => {
+                        at += 1;
+                        continue;
+                    }
+                };
+                block = check_call(body, block, at, index, tables[&table], map.tags[&sig], &map);
+                at = 1;
+            }
+        }
+    }
+
+    module.add_data_segment(DataSegment {
+        kind: DataSegmentKind::Active {
+            memory,
+            offset: SegmentOffset::Const(base as usize),
+        },
+        data,
+    })?;
+    Ok(map)
+}
+
+/// Split `block` before the call at `at` and check the tag of slot
+/// `index` against `tag`, trapping on a mismatch. Returns the block
+/// that now starts with the call.
+fn check_call(
+    body: &mut FunctionBody,
+    block: Block,
+    at: usize,
+    index: Value,
+    table: CfiTable,
+    tag: u32,
+    map: &CfiMap,
+) -> Block {
+    let cont = body.split_block(block, at);
+    let mut code = vec![];
+    let len = Operator::I32Const { value: table.len };
+    let len = push_op(body, &mut code, len, &[], Some(Type::I32));
+    let in_range = push_op(
+        body,
+        &mut code,
+        Operator::I32LtU,
+        &[index, len],
+        Some(Type::I32),
+    );
+    let zero = Operator::I32Const { value: 0 };
+    let zero = push_op(body, &mut code, zero, &[], Some(Type::I32));
+    let slot = push_op(
+        body,
+        &mut code,
+        Operator::Select,
+        &[index, zero, in_range],
+        Some(Type::I32),
+    );
+    let four = Operator::I32Const { value: 4 };
+    let four = push_op(body, &mut code, four, &[], Some(Type::I32));
+    let address = push_op(
+        body,
+        &mut code,
+        Operator::I32Mul,
+        &[slot, four],
+        Some(Type::I32),
+    );
+    let load = Operator::I32Load {
+        memory: MemoryArg {
+            align: 2,
+            offset: map.base + table.offset,
+            memory: map.memory,
+        },
+    };
+    let found = push_op(body, &mut code, load, &[address], Some(Type::I32));
+    let expected = Operator::I32Const { value: tag };
+    let expected = push_op(body, &mut code, expected, &[], Some(Type::I32));
+    let matches = push_op(
+        body,
+        &mut code,
+        Operator::I32Eq,
+        &[found, expected],
+        Some(Type::I32),
+    );
+    let ok = push_op(
+        body,
+        &mut code,
+        Operator::I32And,
+        &[in_range, matches],
+        Some(Type::I32),
+    );
+    insert(body, block, at, &code);
+
+    let trap = body.add_block();
+    body.set_terminator(trap, Terminator::Unreachable);
+    body.set_terminator(
+        block,
+        Terminator::CondBr {
+            cond: ok,
+            if_true: BlockTarget {
+                block: cont,
+                args: vec![],
+            },
+            if_false: BlockTarget {
+                block: trap,
+                args: vec![],
+            },
+        },
+    );
+    cont
+}
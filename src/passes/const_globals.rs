@@ -0,0 +1,60 @@
+//! Propagation of effectively-constant globals.
+
+use crate::analysis::globals::ConstantGlobals;
+use crate::ir::*;
+use crate::pool::ListRef;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// Replace every read of a global that `ConstantGlobals` shows to be
+/// effectively constant with its value, so that `optimize` can fold
+/// what depends on it. The globals and their writes are kept. Bodies
+/// that have not been parsed yet are expanded; already-compiled bodies
+/// cannot be optimized. Returns the number of reads replaced.
+pub fn run(module: &mut Module<'_>) -> Result<usize> {
+    let globals = ConstantGlobals::compute(module)?;
+    let mut replaced = 0;
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot optimize {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        for block in body.blocks.iter().collect::<Vec<_>>() {
+            for i in 0..body.blocks[block].insts.len() {
+                let inst = body.blocks[block].insts[i];
+                let constant = match body.values[inst] {
+                    ValueDef::Operator(Operator::GlobalGet { global_index }, ..) => {
+                        match globals.get(global_index) {
+                            Some(constant) => *constant,
+                            None => continue,
+                        }
+                    }
+                    _ => continue,
+                };
+                let op = match constant.ty {
+                    Type::I32 => Operator::I32Const {
+                        value: constant.bits as u32,
+                    },
+                    Type::I64 => Operator::I64Const {
+                        value: constant.bits,
+                    },
+                    Type::F32 => Operator::F32Const {
+                        value: constant.bits as u32,
+                    },
+                    Type::F64 => Operator::F64Const {
+                        value: constant.bits,
+                    },
+                    _ => continue,
+                };
+                let ty = body.single_type_list(constant.ty);
+                body.values[inst] = ValueDef::Operator(op, ListRef::default(), ty);
+                replaced += 1;
+            }
+        }
+    }
+    Ok(replaced)
+}
@@ -0,0 +1,246 @@
+//! Basic-block code coverage instrumentation pass.
+
+use super::hooks::{import_hook, insert, push_op, HookImport};
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::leb128::{write_str, write_u32, Reader};
+use crate::ops::MemoryArg;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+use core::convert::TryInto;
+
+/// Where block execution counts go.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CoverageCounters {
+    /// An array of wrapping `u32` counters, one per block in counter
+    /// order, at `base` in `memory`. The region must be reserved by
+    /// the embedder (see `CoverageMap::counters_size`) and should
+    /// start out zeroed.
+    Memory { memory: Memory, base: u32 },
+    /// An imported function, called with the counter index on every
+    /// block entry.
+    Import(HookImport),
+}
+
+/// One instrumented block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoverageBlock {
+    pub block: Block,
+    /// Offset in the original module of the block's first operator,
+    /// if it was parsed from there.
+    pub offset: Option<u32>,
+}
+
+/// One instrumented function. Its blocks have consecutive counter
+/// indices starting at `first_counter`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoverageFunc {
+    pub func: Func,
+    pub name: String,
+    pub first_counter: u32,
+    pub blocks: Vec<CoverageBlock>,
+}
+
+/// The mapping from counter indices back to functions and blocks,
+/// as stored in the `waffle.coverage` custom section.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CoverageMap {
+    pub funcs: Vec<CoverageFunc>,
+}
+
+impl CoverageMap {
+    pub const SECTION_NAME: &'static str = "waffle.coverage";
+
+    /// The number of counters.
+    pub fn num_counters(&self) -> u32 {
+        self.funcs
+            .last()
+            .map(|func| func.first_counter + func.blocks.len() as u32)
+            .unwrap_or(0)
+    }
+
+    /// The size in bytes of the counter array in memory.
+    pub fn counters_size(&self) -> u32 {
+        self.num_counters() * 4
+    }
+
+    /// The section encoding: a vector of functions, each with its
+    /// index, name, first counter index, and a vector of blocks, each
+    /// with its index and original offset plus one (0 if none).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_u32(&mut out, self.funcs.len() as u32);
+        for func in &self.funcs {
+            write_u32(&mut out, func.func.index() as u32);
+            write_str(&mut out, &func.name);
+            write_u32(&mut out, func.first_counter);
+            write_u32(&mut out, func.blocks.len() as u32);
+            for block in &func.blocks {
+                write_u32(&mut out, block.block.index() as u32);
+                write_u32(&mut out, block.offset.map(|offset| offset + 1).unwrap_or(0));
+            }
+        }
+        out
+    }
+
+    pub fn parse(data: &[u8]) -> Result<CoverageMap> {
+        let mut reader = Reader::new(data);
+        let mut funcs = vec![];
+        for _ in 0..reader.read_var_u32()? {
+            let func = Func::new(reader.read_var_u32()? as usize);
+            let name = reader.read_string()?.to_owned();
+            let first_counter = reader.read_var_u32()?;
+            let mut blocks = vec![];
+            for _ in 0..reader.read_var_u32()? {
+                let block = Block::new(reader.read_var_u32()? as usize);
+                let offset = reader.read_var_u32()?.checked_sub(1);
+                blocks.push(CoverageBlock { block, offset });
+            }
+            funcs.push(CoverageFunc {
+                func,
+                name,
+                first_counter,
+                blocks,
+            });
+        }
+        if !reader.eof() {
+            anyhow::bail!("Trailing bytes in coverage section");
+        }
+        Ok(CoverageMap { funcs })
+    }
+
+    /// Pair each block with its count, given the counter array (as
+    /// little-endian `u32`s, e.g. dumped from memory).
+    pub fn decode<'a>(
+        &'a self,
+        counters: &'a [u8],
+    ) -> Result<impl Iterator<Item = (&'a CoverageFunc, CoverageBlock, u32)> + 'a> {
+        if counters.len() < self.counters_size() as usize {
+            anyhow::bail!(
+                "Expected {} bytes of counters, got {}",
+                self.counters_size(),
+                counters.len()
+            );
+        }
+        Ok(self.funcs.iter().flat_map(move |func| {
+            func.blocks.iter().enumerate().map(move |(i, &block)| {
+                let at = (func.first_counter as usize + i) * 4;
+                let count = u32::from_le_bytes(counters[at..at + 4].try_into().unwrap());
+                (func, block, count)
+            })
+        }))
+    }
+}
+
+/// Count block executions in every defined function, and add a
+/// `waffle.coverage` section describing the counters. Bodies that
+/// have not been parsed yet are expanded; already-compiled bodies
+/// cannot be instrumented. Returns the mapping.
+pub fn run(module: &mut Module<'_>, counters: &CoverageCounters) -> Result<CoverageMap> {
+    enum Sink {
+        Call(Func),
+        Memory(Memory, u32),
+    }
+    let sink = match counters {
+        CoverageCounters::Import(hook) => {
+            let sig = SignatureData {
+                params: vec![Type::I32],
+                returns: vec![],
+            };
+            Sink::Call(import_hook(module, hook, sig)?.0)
+        }
+        &CoverageCounters::Memory { memory, base } => {
+            if module.memories.get(memory).is_none() {
+                anyhow::bail!("No such memory: {}", memory);
+            }
+            Sink::Memory(memory, base)
+        }
+    };
+
+    let mut map = CoverageMap::default();
+    let mut next_counter = 0u32;
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let name = module.funcs[func].name().to_owned();
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot instrument {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        let mut entry = CoverageFunc {
+            func,
+            name,
+            first_counter: next_counter,
+            blocks: vec![],
+        };
+        for block in body.blocks.iter().collect::<Vec<_>>() {
+            let offset = body.blocks[block]
+                .insts
+                .iter()
+                .find_map(|&inst| body.source_offsets[inst]);
+            entry.blocks.push(CoverageBlock { block, offset });
+
+            let mut code = vec![];
+            match sink {
+                Sink::Call(hook) => {
+                    let index = Operator::I32Const {
+                        value: next_counter,
+                    };
+                    let index = push_op(body, &mut code, index, &[], Some(Type::I32));
+                    push_op(
+                        body,
+                        &mut code,
+                        Operator::Call {
+                            function_index: hook,
+                        },
+                        &[index],
+                        None,
+                    );
+                }
+                Sink::Memory(memory, base) => {
+                    let offset = next_counter
+                        .checked_mul(4)
+                        .and_then(|offset| offset.checked_add(base))
+                        .ok_or_else(|| anyhow::anyhow!("Coverage counters overflow memory"))?;
+                    let memory = MemoryArg {
+                        align: 2,
+                        offset,
+                        memory,
+                    };
+                    let zero = Operator::I32Const { value: 0 };
+                    let zero = push_op(body, &mut code, zero, &[], Some(Type::I32));
+                    let count = Operator::I32Load { memory };
+                    let count = push_op(body, &mut code, count, &[zero], Some(Type::I32));
+                    let one = Operator::I32Const { value: 1 };
+                    let one = push_op(body, &mut code, one, &[], Some(Type::I32));
+                    let count = push_op(
+                        body,
+                        &mut code,
+                        Operator::I32Add,
+                        &[count, one],
+                        Some(Type::I32),
+                    );
+                    push_op(
+                        body,
+                        &mut code,
+                        Operator::I32Store { memory },
+                        &[zero, count],
+                        None,
+                    );
+                }
+            }
+            insert(body, block, 0, &code);
+            next_counter += 1;
+        }
+        map.funcs.push(entry);
+    }
+
+    let data = map.encode();
+    match module.custom_section_mut(CoverageMap::SECTION_NAME) {
+        Some(section) => section.data = data,
+        None => module.add_custom_section(CoverageMap::SECTION_NAME, data),
+    }
+    Ok(map)
+}
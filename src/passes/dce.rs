@@ -0,0 +1,52 @@
+//! Dead-code elimination: removing pure instructions whose results are
+//! never used.
+
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::*;
+use crate::prelude::*;
+
+/// Whether `value` may be removed when nothing uses it. Operators with
+/// side effects, traps included, and traces always stay.
+fn removable(body: &FunctionBody, value: Value) -> bool {
+    match &body.values[value] {
+        ValueDef::Operator(op, ..) => op.is_pure(),
+        ValueDef::PickOutput(..) | ValueDef::Alias(..) => true,
+        _ => false,
+    }
+}
+
+/// Remove the unused pure instructions of `body`, and those used only
+/// by them. Returns how many were removed.
+pub fn run(body: &mut FunctionBody) -> usize {
+    let mut live: PerEntity<Value, bool> = PerEntity::default();
+    let mut worklist = vec![];
+    for block in body.blocks.values() {
+        for &inst in &block.insts {
+            if !removable(body, inst) {
+                worklist.push(inst);
+            }
+        }
+        block.terminator.visit_uses(|value| worklist.push(value));
+    }
+    while let Some(value) = worklist.pop() {
+        if live[value] {
+            continue;
+        }
+        live[value] = true;
+        body.values[value].visit_uses(&body.arg_pool, |used| worklist.push(used));
+    }
+
+    let mut removed = 0;
+    for block in 0..body.blocks.len() {
+        let block = Block::new(block);
+        let insts = core::mem::take(&mut body.blocks[block].insts);
+        let (kept, dead): (Vec<_>, Vec<_>) = insts.into_iter().partition(|&inst| live[inst]);
+        for &inst in &dead {
+            body.value_blocks[inst] = Block::invalid();
+        }
+        removed += dead.len();
+        body.blocks[block].insts = kept;
+    }
+    log::trace!("dce: removed {} instructions", removed);
+    removed
+}
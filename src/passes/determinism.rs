@@ -0,0 +1,239 @@
+//! Deterministic execution pass: remove the behavior that Wasm leaves
+//! up to the engine.
+//!
+//! The remaining nondeterminism in core Wasm is in the bits of NaNs
+//! produced by float operations, and in whether `memory.grow`
+//! succeeds. Relaxed SIMD is the other source, but waffle does not
+//! support SIMD at all, so modules using it cannot be loaded.
+
+use super::hooks::push_op;
+use crate::ir::*;
+use crate::pool::ListRef;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+const CANONICAL_NAN_F32: u32 = 0x7fc0_0000;
+const CANONICAL_NAN_F64: u64 = 0x7ff8_0000_0000_0000;
+
+/// What `memory.grow` does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryGrowPolicy {
+    /// Leave it to the engine.
+    Allow,
+    /// Always fail, returning -1.
+    Deny,
+    /// Fail if the memory would grow past this many pages, regardless
+    /// of whether the engine could grow it further (it may still fail
+    /// below the limit if the engine runs out of memory).
+    Limit(u32),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeterminismOptions {
+    /// Replace every NaN produced by a float operation with the
+    /// canonical (positive, quiet, zero-payload) NaN.
+    pub canonicalize_nans: bool,
+    pub memory_grow: MemoryGrowPolicy,
+}
+
+impl Default for DeterminismOptions {
+    fn default() -> Self {
+        DeterminismOptions {
+            canonicalize_nans: true,
+            memory_grow: MemoryGrowPolicy::Allow,
+        }
+    }
+}
+
+/// The type of the result of `op` if it is a float operation whose
+/// NaN results are not fully specified. Sign operations (`abs`, `neg`
+/// and `copysign`), reinterpretations, loads and constants are exact.
+fn nan_producing(op: &Operator) -> Option<Type> {
+    match op {
+        Operator::F32Ceil
+        | Operator::F32Floor
+        | Operator::F32Trunc
+        | Operator::F32Nearest
+        | Operator::F32Sqrt
+        | Operator::F32Add
+        | Operator::F32Sub
+        | Operator::F32Mul
+        | Operator::F32Div
+        | Operator::F32Min
+        | Operator::F32Max
+        | Operator::F32DemoteF64 => Some(Type::F32),
+        Operator::F64Ceil
+        | Operator::F64Floor
+        | Operator::F64Trunc
+        | Operator::F64Nearest
+        | Operator::F64Sqrt
+        | Operator::F64Add
+        | Operator::F64Sub
+        | Operator::F64Mul
+        | Operator::F64Div
+        | Operator::F64Min
+        | Operator::F64Max
+        | Operator::F64PromoteF32 => Some(Type::F64),
+        _ => None,
+    }
+}
+
+/// Make the defined functions behave the same on every engine, as far
+/// as `options` asks. Bodies that have not been parsed yet are
+/// expanded; already-compiled bodies cannot be rewritten.
+pub fn run(module: &mut Module<'_>, options: &DeterminismOptions) -> Result<()> {
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot rewrite {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        run_on_body(body, options);
+    }
+    Ok(())
+}
+
+/// Make `body` behave the same on every engine, as far as `options`
+/// asks.
+pub fn run_on_body(body: &mut FunctionBody, options: &DeterminismOptions) {
+    for block in body.blocks.iter().collect::<Vec<_>>() {
+        let insts = core::mem::take(&mut body.blocks[block].insts);
+        let mut new_insts = vec![];
+        for inst in insts {
+            let (op, args, tys) = match &body.values[inst] {
+                ValueDef::Operator(op, args, tys) => (*op, *args, *tys),
+                _ => {
+                    new_insts.push(inst);
+                    continue;
+                }
+            };
+            match (op, nan_producing(&op)) {
+                (_, Some(ty)) if options.canonicalize_nans => {
+                    // Move the operation to a new value, and make
+                    // the original one select the canonical NaN
+                    // instead of any NaN it produces.
+                    let result = body.add_value(ValueDef::Operator(op, args, tys));
+                    body.source_offsets[result] = body.source_offsets[inst];
+                    new_insts.push(result);
+                    let (nan, ne) = match ty {
+                        Type::F32 => (
+                            Operator::F32Const {
+                                value: CANONICAL_NAN_F32,
+                            },
+                            Operator::F32Ne,
+                        ),
+                        _ => (
+                            Operator::F64Const {
+                                value: CANONICAL_NAN_F64,
+                            },
+                            Operator::F64Ne,
+                        ),
+                    };
+                    let nan = push_op(body, &mut new_insts, nan, &[], Some(ty));
+                    let is_nan =
+                        push_op(body, &mut new_insts, ne, &[result, result], Some(Type::I32));
+                    let args = body
+                        .arg_pool
+                        .from_iter([nan, result, is_nan].iter().copied());
+                    body.values[inst] = ValueDef::Operator(Operator::Select, args, tys);
+                }
+                (Operator::MemoryGrow { mem }, _) => match options.memory_grow {
+                    MemoryGrowPolicy::Allow => {}
+                    MemoryGrowPolicy::Deny => {
+                        let op = Operator::I32Const { value: u32::MAX };
+                        body.values[inst] = ValueDef::Operator(op, ListRef::default(), tys);
+                    }
+                    MemoryGrowPolicy::Limit(max_pages) => {
+                        limit_grow(body, &mut new_insts, inst, mem, max_pages, args, tys)
+                    }
+                },
+                _ => {}
+            }
+            new_insts.push(inst);
+        }
+        for &inst in &new_insts {
+            body.value_blocks[inst] = block;
+        }
+        body.blocks[block].insts = new_insts;
+    }
+}
+
+/// Make the `memory.grow` at `inst` grow by zero pages and return -1
+/// instead if it would exceed the limit. The original size is
+/// compared as an `i64` so that the sum cannot wrap.
+fn limit_grow(
+    body: &mut FunctionBody,
+    code: &mut Vec<Value>,
+    inst: Value,
+    mem: Memory,
+    max_pages: u32,
+    args: ListRef<Value>,
+    tys: ListRef<Type>,
+) {
+    let delta = body.arg_pool[args][0];
+    let size = push_op(
+        body,
+        code,
+        Operator::MemorySize { mem },
+        &[],
+        Some(Type::I32),
+    );
+    let size = push_op(
+        body,
+        code,
+        Operator::I64ExtendI32U,
+        &[size],
+        Some(Type::I64),
+    );
+    let wide_delta = push_op(
+        body,
+        code,
+        Operator::I64ExtendI32U,
+        &[delta],
+        Some(Type::I64),
+    );
+    let new_size = push_op(
+        body,
+        code,
+        Operator::I64Add,
+        &[size, wide_delta],
+        Some(Type::I64),
+    );
+    let max = Operator::I64Const {
+        value: max_pages as u64,
+    };
+    let max = push_op(body, code, max, &[], Some(Type::I64));
+    let over = push_op(
+        body,
+        code,
+        Operator::I64GtU,
+        &[new_size, max],
+        Some(Type::I32),
+    );
+    let zero = push_op(
+        body,
+        code,
+        Operator::I32Const { value: 0 },
+        &[],
+        Some(Type::I32),
+    );
+    let delta = push_op(
+        body,
+        code,
+        Operator::Select,
+        &[zero, delta, over],
+        Some(Type::I32),
+    );
+    let grow = Operator::MemoryGrow { mem };
+    let grown = push_op(body, code, grow, &[delta], Some(Type::I32));
+    body.source_offsets[grown] = body.source_offsets[inst];
+    let failed = Operator::I32Const { value: u32::MAX };
+    let failed = push_op(body, code, failed, &[], Some(Type::I32));
+    let args = body
+        .arg_pool
+        .from_iter([failed, grown, over].iter().copied());
+    body.values[inst] = ValueDef::Operator(Operator::Select, args, tys);
+}
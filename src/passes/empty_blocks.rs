@@ -2,6 +2,7 @@
 
 use crate::entity::EntityRef;
 use crate::ir::{Block, BlockTarget, FunctionBody, Terminator};
+use crate::prelude::*;
 
 /// Determines whether a block (i) has no blockparams, and (ii) is
 /// solely a jump to another block. We can remove these blocks.
@@ -0,0 +1,198 @@
+//! Function entry/exit hook insertion pass.
+
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::pool::ListRef;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// An imported function to call as a hook.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HookImport {
+    pub module: String,
+    pub name: String,
+}
+
+/// The hooks to insert. Each takes the index of the instrumented
+/// function (as numbered in the instrumented module) as its only
+/// argument and returns nothing.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hooks {
+    /// Called on entry to every defined function.
+    pub entry: Option<HookImport>,
+    /// Called just before every return from a defined function, after
+    /// its return values have been computed.
+    pub exit: Option<HookImport>,
+}
+
+/// Find or add a function import of `hook` with signature `sig`,
+/// returning the function. Adding an import renumbers the defined
+/// functions; the renumbering is returned too if that happened.
+pub(crate) fn import_hook(
+    module: &mut Module<'_>,
+    hook: &HookImport,
+    sig: SignatureData,
+) -> Result<(Func, Option<FuncMapping>)> {
+    let sig = module.find_or_add_signature(sig);
+    let existing = module.imports.iter().find_map(|import| match import.kind {
+        ImportKind::Func(func) if import.module == hook.module && import.name == hook.name => {
+            Some(func)
+        }
+        _ => None,
+    });
+    match existing {
+        Some(func) if module.funcs[func].sig() == sig => Ok((func, None)),
+        Some(func) => anyhow::bail!(
+            "Hook {}.{} is already imported as {} with a different signature",
+            hook.module,
+            hook.name,
+            func
+        ),
+        None => {
+            let (func, mapping) = module.add_func_import(&hook.module, &hook.name, sig)?;
+            Ok((func, Some(mapping)))
+        }
+    }
+}
+
+/// Add an operator to `code`.
+pub(crate) fn push_op(
+    body: &mut FunctionBody,
+    code: &mut Vec<Value>,
+    op: Operator,
+    args: &[Value],
+    ty: Option<Type>,
+) -> Value {
+    let args = body.arg_pool.from_iter(args.iter().copied());
+    let tys = match ty {
+        Some(ty) => body.single_type_list(ty),
+        None => ListRef::default(),
+    };
+    let value = body.add_value(ValueDef::Operator(op, args, tys));
+    code.push(value);
+    value
+}
+
+/// Add a call with any number of results to `code`, returning the
+/// results.
+pub(crate) fn push_call(
+    body: &mut FunctionBody,
+    code: &mut Vec<Value>,
+    func: Func,
+    args: &[Value],
+    returns: &[Type],
+) -> Vec<Value> {
+    let op = Operator::Call {
+        function_index: func,
+    };
+    if returns.len() <= 1 {
+        let value = push_op(body, code, op, args, returns.first().copied());
+        return if returns.is_empty() {
+            vec![]
+        } else {
+            vec![value]
+        };
+    }
+    let args = body.arg_pool.from_iter(args.iter().copied());
+    let tys = body.type_list(returns);
+    let call = body.add_value(ValueDef::Operator(op, args, tys));
+    code.push(call);
+    returns
+        .iter()
+        .enumerate()
+        .map(|(i, &ty)| {
+            let pick = body.add_value(ValueDef::PickOutput(call, i as u32, ty));
+            code.push(pick);
+            pick
+        })
+        .collect()
+}
+
+/// Put `code` into `block` before its `at`th instruction.
+pub(crate) fn insert(body: &mut FunctionBody, block: Block, at: usize, code: &[Value]) {
+    for &value in code {
+        body.value_blocks[value] = block;
+    }
+    body.blocks[block]
+        .insts
+        .splice(at..at, code.iter().copied());
+}
+
+/// Append `call hook(index)` to the instructions of `block`, or put
+/// it first if `at_start` is set.
+pub(crate) fn call_with_index(
+    body: &mut FunctionBody,
+    block: Block,
+    hook: Func,
+    index: u32,
+    at_start: bool,
+) {
+    let ty = body.single_type_list(Type::I32);
+    let index = body.add_value(ValueDef::Operator(
+        Operator::I32Const { value: index },
+        ListRef::default(),
+        ty,
+    ));
+    let args = body.arg_pool.single(index);
+    let call = body.add_value(ValueDef::Operator(
+        Operator::Call {
+            function_index: hook,
+        },
+        args,
+        ListRef::default(),
+    ));
+    let insts = &mut body.blocks[block].insts;
+    if at_start {
+        insts.splice(0..0, [index, call]);
+    } else {
+        insts.extend([index, call]);
+    }
+    body.value_blocks[index] = block;
+    body.value_blocks[call] = block;
+}
+
+/// Insert calls to `hooks` into every defined function. Bodies that
+/// have not been parsed yet are expanded; already-compiled bodies
+/// cannot be instrumented. The IR has no tail calls, so every way out
+/// of a function other than a trap goes through a return.
+pub fn run(module: &mut Module<'_>, hooks: &Hooks) -> Result<()> {
+    let sig = SignatureData {
+        params: vec![Type::I32],
+        returns: vec![],
+    };
+    let entry = match &hooks.entry {
+        Some(hook) => Some(import_hook(module, hook, sig.clone())?.0),
+        None => None,
+    };
+    let exit = match &hooks.exit {
+        Some(hook) => Some(import_hook(module, hook, sig)?.0),
+        None => None,
+    };
+
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot instrument {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        let index = func.index() as u32;
+        if let Some(hook) = entry {
+            call_with_index(body, body.entry, hook, index, true);
+        }
+        if let Some(hook) = exit {
+            let returns = body
+                .blocks
+                .entries()
+                .filter(|(_, data)| matches!(data.terminator, Terminator::Return { .. }))
+                .map(|(block, _)| block)
+                .collect::<Vec<_>>();
+            for block in returns {
+                call_with_index(body, block, hook, index, false);
+            }
+        }
+    }
+    Ok(())
+}
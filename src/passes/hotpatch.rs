@@ -0,0 +1,230 @@
+//! Hot-patch scaffolding: route direct calls through table slots that
+//! a running engine can overwrite.
+
+use super::hooks::push_op;
+use crate::collections::HashMap;
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::leb128::{write_u32, Reader};
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+use core::ops::Range;
+
+/// The name under which a table added by the hot-patch pass is
+/// exported.
+pub const PATCH_TABLE_EXPORT: &str = "__waffle_patch_table";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HotPatchOptions {
+    /// The functions whose direct calls are routed through the table.
+    pub funcs: Vec<Func>,
+    /// A defined `funcref` table to add the slots to. If `None`, a
+    /// table is added and exported as `PATCH_TABLE_EXPORT`.
+    pub table: Option<Table>,
+    /// The number of empty slots to reserve after the patch slots, for
+    /// new functions that patched code may call indirectly.
+    pub spare_slots: u32,
+}
+
+/// A function whose calls go through a table slot, initially holding
+/// the function itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PatchPoint {
+    pub func: Func,
+    pub slot: u32,
+    /// The number of call sites routed through the slot.
+    pub calls: u32,
+}
+
+/// The patch points of an instrumented module, as stored in the
+/// `waffle.hotpatch` custom section.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HotPatchMap {
+    pub table: Table,
+    pub points: Vec<PatchPoint>,
+    pub spare_slots: Range<u32>,
+}
+
+impl HotPatchMap {
+    pub const SECTION_NAME: &'static str = "waffle.hotpatch";
+
+    /// The section encoding: the table index, a vector of patch
+    /// points, each with its function index, slot and number of
+    /// calls, and the first spare slot and the number of spare slots.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_u32(&mut out, self.table.index() as u32);
+        write_u32(&mut out, self.points.len() as u32);
+        for point in &self.points {
+            write_u32(&mut out, point.func.index() as u32);
+            write_u32(&mut out, point.slot);
+            write_u32(&mut out, point.calls);
+        }
+        write_u32(&mut out, self.spare_slots.start);
+        write_u32(&mut out, self.spare_slots.len() as u32);
+        out
+    }
+
+    pub fn parse(data: &[u8]) -> Result<HotPatchMap> {
+        let mut reader = Reader::new(data);
+        let table = Table::new(reader.read_var_u32()? as usize);
+        let mut points = vec![];
+        for _ in 0..reader.read_var_u32()? {
+            points.push(PatchPoint {
+                func: Func::new(reader.read_var_u32()? as usize),
+                slot: reader.read_var_u32()?,
+                calls: reader.read_var_u32()?,
+            });
+        }
+        let start = reader.read_var_u32()?;
+        let end = start
+            .checked_add(reader.read_var_u32()?)
+            .ok_or_else(|| anyhow::anyhow!("Spare slots overflow"))?;
+        if !reader.eof() {
+            anyhow::bail!("Trailing bytes in hot-patch section");
+        }
+        Ok(HotPatchMap {
+            table,
+            points,
+            spare_slots: start..end,
+        })
+    }
+
+    pub fn point(&self, func: Func) -> Option<&PatchPoint> {
+        self.points.iter().find(|point| point.func == func)
+    }
+}
+
+/// Give each of `options.funcs` a slot at the end of the table holding
+/// it, and turn every direct call of it in a defined function into an
+/// indirect call through that slot, so that replacing the slot's
+/// contents (e.g. with `Table.set` from the embedder) patches all of
+/// its callers. Exports, `ref.func` and table elements still refer to
+/// the original function. Bodies that have not been parsed yet are
+/// expanded; already-compiled bodies cannot be instrumented. Adds a
+/// `waffle.hotpatch` section and returns the map.
+pub fn run(module: &mut Module<'_>, options: &HotPatchOptions) -> Result<HotPatchMap> {
+    let table = match options.table {
+        Some(table) => {
+            let imported = module
+                .imports
+                .iter()
+                .any(|import| import.kind == ImportKind::Table(table));
+            match module.tables.get(table) {
+                Some(data) if data.ty == Type::FuncRef && !imported => {}
+                Some(_) => anyhow::bail!("Table {} must be a defined funcref table", table),
+                None => anyhow::bail!("No such table: {}", table),
+            }
+            table
+        }
+        None => {
+            let table = module.add_table(Type::FuncRef, 0, None);
+            module.exports.push(Export {
+                name: PATCH_TABLE_EXPORT.to_owned(),
+                kind: ExportKind::Table(table),
+            });
+            table
+        }
+    };
+
+    let mut map = HotPatchMap {
+        table,
+        points: vec![],
+        spare_slots: 0..0,
+    };
+    let mut slots = HashMap::new();
+    {
+        let elements = module.tables[table]
+            .func_elements
+            .get_or_insert_with(Vec::new);
+        for &func in &options.funcs {
+            if func.index() >= module.funcs.len() {
+                anyhow::bail!("No such function: {}", func);
+            }
+            if slots.contains_key(&func) {
+                continue;
+            }
+            let slot = elements.len() as u32;
+            elements.push(func);
+            slots.insert(func, map.points.len());
+            map.points.push(PatchPoint {
+                func,
+                slot,
+                calls: 0,
+            });
+        }
+        let start = elements.len() as u32;
+        elements.resize(
+            start as usize + options.spare_slots as usize,
+            Func::invalid(),
+        );
+        map.spare_slots = start..elements.len() as u32;
+        if let Some(max) = module.tables[table].max {
+            if max < map.spare_slots.end {
+                anyhow::bail!(
+                    "Table {} needs {} slots but has a maximum of {}",
+                    table,
+                    map.spare_slots.end,
+                    max
+                );
+            }
+        }
+    }
+
+    let sigs: HashMap<Func, Signature> = map
+        .points
+        .iter()
+        .map(|point| (point.func, module.funcs[point.func].sig()))
+        .collect();
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot instrument {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        for block in body.blocks.iter().collect::<Vec<_>>() {
+            let insts = core::mem::take(&mut body.blocks[block].insts);
+            let mut new_insts = vec![];
+            for inst in insts {
+                let (callee, args, tys) = match &body.values[inst] {
+                    ValueDef::Operator(Operator::Call { function_index }, args, tys)
+                        if slots.contains_key(function_index) =>
+                    {
+                        (*function_index, *args, *tys)
+                    }
+                    _ => {
+                        new_insts.push(inst);
+                        continue;
+                    }
+                };
+                let point = &mut map.points[slots[&callee]];
+                point.calls += 1;
+                let slot = Operator::I32Const { value: point.slot };
+                let slot = push_op(body, &mut new_insts, slot, &[], Some(Type::I32));
+                let mut new_args = body.arg_pool[args].to_vec();
+                new_args.push(slot);
+                let new_args = body.arg_pool.from_iter(new_args.into_iter());
+                let op = Operator::CallIndirect {
+                    sig_index: sigs[&callee],
+                    table_index: table,
+                };
+                body.values[inst] = ValueDef::Operator(op, new_args, tys);
+                new_insts.push(inst);
+            }
+            for &inst in &new_insts {
+                body.value_blocks[inst] = block;
+            }
+            body.blocks[block].insts = new_insts;
+        }
+    }
+
+    let data = map.encode();
+    match module.custom_section_mut(HotPatchMap::SECTION_NAME) {
+        Some(section) => section.data = data,
+        None => module.add_custom_section(HotPatchMap::SECTION_NAME, data),
+    }
+    Ok(map)
+}
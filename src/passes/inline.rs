@@ -0,0 +1,204 @@
+//! Inlining of direct calls.
+
+use crate::collections::hash_map::{Entry, HashMap};
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// The number of instructions in a body, as a measure of how much
+/// inlining it costs.
+pub fn body_size(body: &FunctionBody) -> usize {
+    body.blocks.values().map(|block| block.insts.len()).sum()
+}
+
+/// Inline every direct call to a function of at most `max_size`
+/// instructions, other than recursive calls, into its caller. Callees
+/// are inlined as they are when their caller is reached, so a call
+/// inlined into a callee earlier on may be inlined again. Bodies that
+/// have not been parsed yet are expanded; already-compiled ones are
+/// neither rewritten nor inlined. Returns the number of calls inlined.
+pub fn run(module: &mut Module<'_>, max_size: usize) -> Result<usize> {
+    let mut inlined = 0;
+    for caller in module.funcs.iter().collect::<Vec<_>>() {
+        let calls = match module.expand_func(caller)? {
+            FuncDecl::Body(_, _, body) => direct_calls(body),
+            _ => continue,
+        };
+        let mut callees: HashMap<Func, Option<FunctionBody>> = HashMap::new();
+        let mut sites = vec![];
+        for (call, callee) in calls {
+            if callee == caller || callee.index() >= module.funcs.len() {
+                continue;
+            }
+            let callee_body = match callees.entry(callee) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(match module.expand_func(callee)? {
+                    FuncDecl::Body(_, _, body) if body_size(body) <= max_size => Some(body.clone()),
+                    _ => None,
+                }),
+            };
+            if callee_body.is_some() {
+                sites.push((call, callee));
+            }
+        }
+        let body = module.funcs[caller].body_mut().unwrap();
+        for (call, callee) in sites {
+            inline_call(body, call, callees[&callee].as_ref().unwrap())?;
+            inlined += 1;
+        }
+    }
+    Ok(inlined)
+}
+
+/// The direct calls placed in `body`, with the functions they call.
+fn direct_calls(body: &FunctionBody) -> Vec<(Value, Func)> {
+    body.blocks
+        .values()
+        .flat_map(|block| block.insts.iter().copied())
+        .filter_map(|inst| match body.values[inst] {
+            ValueDef::Operator(Operator::Call { function_index }, ..) => {
+                Some((inst, function_index))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Replace the direct call `call` in `body` with a copy of `callee`,
+/// the body of the called function. The call's block is split after
+/// the call, and returns from the copy branch to the second half with
+/// the results as block parameters. Returns the blocks the callee's
+/// blocks were copied to, indexed by callee block. The new blocks have
+/// no frequency; the second half of the split block keeps the
+/// original one.
+pub fn inline_call(
+    body: &mut FunctionBody,
+    call: Value,
+    callee: &FunctionBody,
+) -> Result<Vec<Block>> {
+    let block = body.value_blocks[call];
+    let at = block
+        .is_valid()
+        .then(|| {
+            body.blocks[block]
+                .insts
+                .iter()
+                .position(|&inst| inst == call)
+        })
+        .flatten()
+        .ok_or_else(|| anyhow::anyhow!("{} is not placed in a block", call))?;
+    let args = match &body.values[call] {
+        ValueDef::Operator(Operator::Call { .. }, args, _) => body.arg_pool[*args].to_vec(),
+        _ => anyhow::bail!("{} is not a direct call", call),
+    };
+    if args.len() != callee.blocks[callee.entry].params.len() {
+        anyhow::bail!("Call {} does not match the callee's parameters", call);
+    }
+
+    let cont = body.split_block(block, at + 1);
+    body.blocks[block].insts.pop();
+    body.blocks[cont].frequency = body.blocks[block].frequency;
+    let results = callee
+        .rets
+        .iter()
+        .map(|&ty| body.add_blockparam(cont, ty))
+        .collect::<Vec<_>>();
+    for value in 0..body.values.len() {
+        let value = Value::new(value);
+        if let ValueDef::PickOutput(from, index, _) = body.values[value] {
+            if from == call {
+                body.values[value] = ValueDef::Alias(results[index as usize]);
+            }
+        }
+    }
+    // A call with other than one result stays defined, but unplaced.
+    if let [result] = results[..] {
+        body.values[call] = ValueDef::Alias(result);
+    }
+    body.value_blocks[call] = Block::invalid();
+
+    // Copy the callee, first allocating all blocks and values so that
+    // definitions can refer to them in any order.
+    let blocks = callee
+        .blocks
+        .iter()
+        .map(|_| body.add_block())
+        .collect::<Vec<_>>();
+    let values = callee
+        .values
+        .iter()
+        .map(|_| body.add_value(ValueDef::None))
+        .collect::<Vec<_>>();
+    let value = |value: Value| {
+        if value.is_valid() {
+            values[value.index()]
+        } else {
+            value
+        }
+    };
+    for (old, def) in callee.values.entries() {
+        let def = match def {
+            &ValueDef::BlockParam(block, index, ty) => {
+                ValueDef::BlockParam(blocks[block.index()], index, ty)
+            }
+            ValueDef::Operator(op, args, tys) => {
+                let args = callee.arg_pool[*args].iter().map(|&arg| value(arg));
+                let args = body.arg_pool.from_iter(args);
+                let tys = body.type_list(&callee.type_pool[*tys]);
+                ValueDef::Operator(*op, args, tys)
+            }
+            &ValueDef::PickOutput(from, index, ty) => ValueDef::PickOutput(value(from), index, ty),
+            &ValueDef::Alias(to) => ValueDef::Alias(value(to)),
+            &ValueDef::Placeholder(ty) => ValueDef::Placeholder(ty),
+            ValueDef::Trace(id, args) => {
+                let args = callee.arg_pool[*args].iter().map(|&arg| value(arg));
+                ValueDef::Trace(*id, body.arg_pool.from_iter(args))
+            }
+            ValueDef::None => ValueDef::None,
+        };
+        let new = value(old);
+        body.values[new] = def;
+        body.source_offsets[new] = callee.source_offsets[old];
+    }
+    for (old, def) in callee.blocks.entries() {
+        let new = blocks[old.index()];
+        body.blocks[new].desc = def.desc.clone();
+        body.blocks[new].params = def
+            .params
+            .iter()
+            .map(|&(ty, param)| (ty, value(param)))
+            .collect();
+        body.blocks[new].insts = def.insts.iter().map(|&inst| value(inst)).collect();
+        for i in 0..def.params.len() {
+            let param = body.blocks[new].params[i].1;
+            body.value_blocks[param] = new;
+        }
+        for i in 0..def.insts.len() {
+            let inst = body.blocks[new].insts[i];
+            body.value_blocks[inst] = new;
+        }
+        let mut terminator = def.terminator.clone();
+        terminator.update_uses(|use_| *use_ = value(*use_));
+        terminator.update_targets(|target| target.block = blocks[target.block.index()]);
+        if let Terminator::Return { values } = terminator {
+            terminator = Terminator::Br {
+                target: BlockTarget {
+                    block: cont,
+                    args: values,
+                },
+            };
+        }
+        body.blocks[new].terminator = terminator;
+    }
+
+    body.blocks[block].terminator = Terminator::Br {
+        target: BlockTarget {
+            block: blocks[callee.entry.index()],
+            args,
+        },
+    };
+    body.recompute_edges();
+    Ok(blocks)
+}
@@ -0,0 +1,135 @@
+//! Import call interception: wrap imports in shims that call hooks.
+
+use super::hooks::{import_hook, insert, push_call, HookImport};
+use crate::collections::HashMap;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// How to intercept calls to one imported function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Intercept {
+    /// The intercepted import.
+    pub module: String,
+    pub name: String,
+    /// Called with the arguments before each call. If `rewrite_args`
+    /// is set, it returns the arguments to pass on instead (so it has
+    /// the import's parameters as its results); otherwise it returns
+    /// nothing.
+    pub before: Option<HookImport>,
+    pub rewrite_args: bool,
+    /// Called with the results after each call that returns.
+    pub after: Option<HookImport>,
+}
+
+/// Add a shim for each intercepted import that calls its hooks around
+/// a call of the import, and make every direct call of the import in
+/// a defined function call the shim instead. The imports themselves
+/// are left as they are, as are exports, `ref.func` and table
+/// elements referring to them, so indirect calls are not intercepted.
+/// Bodies that have not been parsed yet are expanded; already-compiled
+/// bodies cannot be rewritten. Returns the shim of each intercepted
+/// import.
+pub fn run(module: &mut Module<'_>, intercepts: &[Intercept]) -> Result<HashMap<Func, Func>> {
+    let mut targets = vec![];
+    for intercept in intercepts {
+        let import = module
+            .imports
+            .iter()
+            .find_map(|import| match import.kind {
+                ImportKind::Func(func)
+                    if import.module == intercept.module && import.name == intercept.name =>
+                {
+                    Some(func)
+                }
+                _ => None,
+            })
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No such function import: {}.{}",
+                    intercept.module,
+                    intercept.name
+                )
+            })?;
+        let sig = module.signatures[module.funcs[import].sig()].clone();
+        let before = match &intercept.before {
+            Some(hook) => {
+                let hook_sig = SignatureData {
+                    params: sig.params.clone(),
+                    returns: if intercept.rewrite_args {
+                        sig.params.clone()
+                    } else {
+                        vec![]
+                    },
+                };
+                Some(import_hook(module, hook, hook_sig)?.0)
+            }
+            None => None,
+        };
+        let after = match &intercept.after {
+            Some(hook) => {
+                let hook_sig = SignatureData {
+                    params: sig.returns.clone(),
+                    returns: vec![],
+                };
+                Some(import_hook(module, hook, hook_sig)?.0)
+            }
+            None => None,
+        };
+        targets.push((import, sig, before, after, intercept.rewrite_args));
+    }
+
+    let mut shims = HashMap::new();
+    for (import, sig, before, after, rewrite_args) in targets {
+        if shims.contains_key(&import) {
+            anyhow::bail!("{} is intercepted twice", module.funcs[import].name());
+        }
+        let sig_index = module.funcs[import].sig();
+        let mut body = FunctionBody::new(module, sig_index);
+        let entry = body.entry;
+        let mut args = body.blocks[entry]
+            .params
+            .iter()
+            .map(|&(_, param)| param)
+            .collect::<Vec<_>>();
+        let mut code = vec![];
+        if let Some(before) = before {
+            let returns = if rewrite_args { &sig.params[..] } else { &[] };
+            let rewritten = push_call(&mut body, &mut code, before, &args, returns);
+            if rewrite_args {
+                args = rewritten;
+            }
+        }
+        let results = push_call(&mut body, &mut code, import, &args, &sig.returns);
+        if let Some(after) = after {
+            push_call(&mut body, &mut code, after, &results, &[]);
+        }
+        insert(&mut body, entry, 0, &code);
+        body.set_terminator(entry, Terminator::Return { values: results });
+        let name = format!("{}$shim", module.funcs[import].name());
+        let shim = module.add_function(sig_index, &name, body);
+        shims.insert(import, shim);
+    }
+
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        if shims.values().any(|&shim| shim == func) {
+            continue;
+        }
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot rewrite {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        for def in body.values.values_mut() {
+            if let ValueDef::Operator(Operator::Call { function_index }, ..) = def {
+                if let Some(&shim) = shims.get(function_index) {
+                    *function_index = shim;
+                }
+            }
+        }
+    }
+    Ok(shims)
+}
@@ -0,0 +1,86 @@
+//! Redundant-load elimination and store-to-load forwarding.
+
+use super::memtrace::access;
+use crate::analysis::alias::AliasAnalysis;
+use crate::analysis::memory_ssa::{MemoryAccess, MemoryAccessDef, MemorySsa};
+use crate::cfg::CFGInfo;
+use crate::collections::HashMap;
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+
+/// The load that reads back what the whole-value store `op` stores.
+fn load_of(op: &Operator) -> Option<Operator> {
+    match *op {
+        Operator::I32Store { memory } => Some(Operator::I32Load { memory }),
+        Operator::I64Store { memory } => Some(Operator::I64Load { memory }),
+        Operator::F32Store { memory } => Some(Operator::F32Load { memory }),
+        Operator::F64Store { memory } => Some(Operator::F64Load { memory }),
+        _ => None,
+    }
+}
+
+/// How many states `MemorySsa::clobbering` walks up from a load
+/// before giving up.
+const WALK_LIMIT: usize = 100;
+
+/// Replace loads by values known to be in memory, given the
+/// shadow-stack pointer if the module has one: what a dominating store
+/// of a whole value of the loaded type stored there, or what a
+/// dominating load with the same operator read there, when nothing
+/// that may alias the location (see `AliasAnalysis`) may have written
+/// it since. Calls and other operators that write memory may write
+/// any location except those in a private stack frame. Returns the
+/// number of loads removed.
+pub fn run(body: &mut FunctionBody, cfg: &CFGInfo, sp: Option<Global>) -> usize {
+    let aliases = AliasAnalysis::compute(body, cfg, sp);
+    let ssa = MemorySsa::compute(body, cfg);
+    let mut removed = 0;
+    // The loads seen so far, by the state they are clobbered by.
+    let mut loads: HashMap<MemoryAccess, Vec<Value>> = HashMap::new();
+    for &block in cfg.rpo.values() {
+        let insts = core::mem::take(&mut body.blocks[block].insts);
+        let mut new_insts = Vec::with_capacity(insts.len());
+        for inst in insts {
+            let op = match &body.values[inst] {
+                ValueDef::Operator(op, ..) if matches!(access(op), Some((_, _, false))) => *op,
+                _ => {
+                    new_insts.push(inst);
+                    continue;
+                }
+            };
+            let clobber = ssa.clobbering(body, &aliases, inst, WALK_LIMIT).unwrap();
+            let stored = match ssa.access(clobber) {
+                MemoryAccessDef::Def { inst: store, .. } => match body.values[*store] {
+                    ValueDef::Operator(store_op, args, _)
+                        if load_of(&store_op) == Some(op) && aliases.must_alias(*store, inst) =>
+                    {
+                        Some(body.arg_pool[args][1])
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            let earlier = || {
+                loads.get(&clobber).and_then(|earlier| {
+                    earlier.iter().copied().find(|&earlier| {
+                        matches!(body.values[earlier], ValueDef::Operator(earlier_op, ..) if earlier_op == op)
+                            && cfg.dominates(body.value_blocks[earlier], block)
+                            && aliases.must_alias(earlier, inst)
+                    })
+                })
+            };
+            if let Some(value) = stored.or_else(earlier) {
+                body.values[inst] = ValueDef::Alias(value);
+                body.value_blocks[inst] = Block::invalid();
+                removed += 1;
+                continue;
+            }
+            loads.entry(clobber).or_default().push(inst);
+            new_insts.push(inst);
+        }
+        body.blocks[block].insts = new_insts;
+    }
+    removed
+}
@@ -0,0 +1,530 @@
+//! Running a sequence of passes over bodies, with the analyses they
+//! use kept between passes until a pass clobbers them.
+
+use crate::analysis::{Liveness, Loops};
+use crate::cfg::CFGInfo;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Timeline;
+use anyhow::Result;
+use core::time::Duration;
+#[cfg(all(
+    feature = "std",
+    not(all(target_arch = "wasm32", target_os = "unknown"))
+))]
+use std::time::Instant;
+
+/// An analysis the pass manager keeps between passes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Analysis {
+    /// The CFG and dominator tree.
+    Cfg,
+    Liveness,
+    Loops,
+}
+
+impl Analysis {
+    /// The analyses computed from this one, out of date whenever it is.
+    fn dependents(self) -> &'static [Analysis] {
+        match self {
+            Analysis::Cfg => &[Analysis::Liveness, Analysis::Loops],
+            Analysis::Liveness | Analysis::Loops => &[],
+        }
+    }
+}
+
+/// The analyses of one body that are up to date.
+#[derive(Clone, Debug, Default)]
+pub struct Analyses {
+    cfg: Option<CFGInfo>,
+    liveness: Option<Liveness>,
+    loops: Option<Loops>,
+    /// The number of times an analysis was computed.
+    computed: usize,
+}
+
+impl Analyses {
+    /// Compute `analysis` of `body` if it is not up to date.
+    pub fn compute(&mut self, body: &FunctionBody, analysis: Analysis) {
+        match analysis {
+            Analysis::Cfg => {
+                if self.cfg.is_none() {
+                    self.cfg = Some(CFGInfo::new(body));
+                    self.computed += 1;
+                }
+            }
+            Analysis::Liveness => {
+                if self.liveness.is_none() {
+                    self.compute(body, Analysis::Cfg);
+                    self.liveness = Some(Liveness::compute(body, self.cfg()));
+                    self.computed += 1;
+                }
+            }
+            Analysis::Loops => {
+                if self.loops.is_none() {
+                    self.compute(body, Analysis::Cfg);
+                    self.loops = Some(Loops::compute(body, self.cfg()));
+                    self.computed += 1;
+                }
+            }
+        }
+    }
+
+    /// Drop `analysis`, and the analyses computed from it.
+    pub fn invalidate(&mut self, analysis: Analysis) {
+        match analysis {
+            Analysis::Cfg => self.cfg = None,
+            Analysis::Liveness => self.liveness = None,
+            Analysis::Loops => self.loops = None,
+        }
+        for &dependent in analysis.dependents() {
+            self.invalidate(dependent);
+        }
+    }
+
+    /// The CFG. Panics unless it was required.
+    pub fn cfg(&self) -> &CFGInfo {
+        self.cfg.as_ref().expect("CFG not computed")
+    }
+
+    /// The liveness. Panics unless it was required.
+    pub fn liveness(&self) -> &Liveness {
+        self.liveness.as_ref().expect("liveness not computed")
+    }
+
+    /// The loops. Panics unless they were required.
+    pub fn loops(&self) -> &Loops {
+        self.loops.as_ref().expect("loops not computed")
+    }
+
+    /// The number of times an analysis was computed so far.
+    pub fn computed(&self) -> usize {
+        self.computed
+    }
+}
+
+/// A pass over one body.
+pub trait Pass {
+    fn name(&self) -> &str;
+    /// The analyses `run` uses. They are up to date when it runs.
+    fn requires(&self) -> &[Analysis] {
+        &[]
+    }
+    /// The analyses `run` may make out of date. Clobbering an analysis
+    /// also clobbers those computed from it.
+    fn clobbers(&self) -> &[Analysis];
+    fn run(&self, body: &mut FunctionBody, analyses: &Analyses) -> Result<()>;
+}
+
+/// `remove_phis::run`.
+pub struct RemovePhis;
+
+impl Pass for RemovePhis {
+    fn name(&self) -> &str {
+        "remove-phis"
+    }
+    fn requires(&self) -> &[Analysis] {
+        &[Analysis::Cfg]
+    }
+    fn clobbers(&self) -> &[Analysis] {
+        &[Analysis::Liveness, Analysis::Loops]
+    }
+    fn run(&self, body: &mut FunctionBody, analyses: &Analyses) -> Result<()> {
+        super::remove_phis::run(body, analyses.cfg());
+        Ok(())
+    }
+}
+
+/// `basic_opt::gvn`.
+pub struct Gvn;
+
+impl Pass for Gvn {
+    fn name(&self) -> &str {
+        "gvn"
+    }
+    fn requires(&self) -> &[Analysis] {
+        &[Analysis::Cfg]
+    }
+    fn clobbers(&self) -> &[Analysis] {
+        &[Analysis::Liveness, Analysis::Loops]
+    }
+    fn run(&self, body: &mut FunctionBody, analyses: &Analyses) -> Result<()> {
+        super::basic_opt::gvn(body, analyses.cfg());
+        Ok(())
+    }
+}
+
+/// `empty_blocks::run`.
+pub struct EmptyBlocks;
+
+impl Pass for EmptyBlocks {
+    fn name(&self) -> &str {
+        "empty-blocks"
+    }
+    fn clobbers(&self) -> &[Analysis] {
+        &[Analysis::Cfg]
+    }
+    fn run(&self, body: &mut FunctionBody, _: &Analyses) -> Result<()> {
+        super::empty_blocks::run(body);
+        Ok(())
+    }
+}
+
+/// `resolve_aliases::run`. The analyses all see through aliases, so it
+/// clobbers none of them.
+pub struct ResolveAliases;
+
+impl Pass for ResolveAliases {
+    fn name(&self) -> &str {
+        "resolve-aliases"
+    }
+    fn clobbers(&self) -> &[Analysis] {
+        &[]
+    }
+    fn run(&self, body: &mut FunctionBody, _: &Analyses) -> Result<()> {
+        super::resolve_aliases::run(body);
+        Ok(())
+    }
+}
+
+/// `dce::run`.
+pub struct Dce;
+
+impl Pass for Dce {
+    fn name(&self) -> &str {
+        "dce"
+    }
+    fn clobbers(&self) -> &[Analysis] {
+        &[Analysis::Liveness]
+    }
+    fn run(&self, body: &mut FunctionBody, _: &Analyses) -> Result<()> {
+        super::dce::run(body);
+        Ok(())
+    }
+}
+
+/// `maxssa::run`, cutting at every block.
+pub struct MaxSsa;
+
+impl Pass for MaxSsa {
+    fn name(&self) -> &str {
+        "maxssa"
+    }
+    fn requires(&self) -> &[Analysis] {
+        &[Analysis::Cfg]
+    }
+    fn clobbers(&self) -> &[Analysis] {
+        &[Analysis::Liveness, Analysis::Loops]
+    }
+    fn run(&self, body: &mut FunctionBody, analyses: &Analyses) -> Result<()> {
+        super::maxssa::run(body, None, analyses.cfg());
+        Ok(())
+    }
+}
+
+/// `load_store::run`, with `sp` as the stack pointer if any.
+pub struct LoadStore {
+    pub sp: Option<Global>,
+}
+
+impl Pass for LoadStore {
+    fn name(&self) -> &str {
+        "load-store"
+    }
+    fn requires(&self) -> &[Analysis] {
+        &[Analysis::Cfg]
+    }
+    fn clobbers(&self) -> &[Analysis] {
+        &[Analysis::Liveness, Analysis::Loops]
+    }
+    fn run(&self, body: &mut FunctionBody, analyses: &Analyses) -> Result<()> {
+        super::load_store::run(body, analyses.cfg(), self.sp);
+        Ok(())
+    }
+}
+
+/// Passes to run in order over each body.
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassManager {
+    pub fn new() -> PassManager {
+        PassManager::default()
+    }
+
+    /// The passes of `FunctionBody::optimize`.
+    pub fn optimize() -> PassManager {
+        let mut manager = PassManager::new();
+        manager
+            .add(RemovePhis)
+            .add(Gvn)
+            .add(RemovePhis)
+            .add(EmptyBlocks);
+        manager
+    }
+
+    /// The passes named, comma-separated, in `pipeline`: those of this
+    /// module by the names they report (`load-store` without a stack
+    /// pointer), and `optimize` for the passes of `optimize()`.
+    pub fn from_pipeline(pipeline: &str) -> Result<PassManager> {
+        let mut manager = PassManager::new();
+        for name in pipeline
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match name {
+                "optimize" => {
+                    manager.passes.extend(PassManager::optimize().passes);
+                }
+                "remove-phis" => {
+                    manager.add(RemovePhis);
+                }
+                "gvn" => {
+                    manager.add(Gvn);
+                }
+                "empty-blocks" => {
+                    manager.add(EmptyBlocks);
+                }
+                "resolve-aliases" => {
+                    manager.add(ResolveAliases);
+                }
+                "dce" => {
+                    manager.add(Dce);
+                }
+                "maxssa" => {
+                    manager.add(MaxSsa);
+                }
+                "load-store" => {
+                    manager.add(LoadStore { sp: None });
+                }
+                _ => anyhow::bail!("Unknown pass: {}", name),
+            }
+        }
+        Ok(manager)
+    }
+
+    /// Run `pass` after the passes added so far.
+    pub fn add<P: Pass + 'static>(&mut self, pass: P) -> &mut PassManager {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Run the passes over `body`, computing each analysis only when a
+    /// pass requires it and no earlier pass has computed it since it
+    /// was last clobbered. Returns the analyses still up to date.
+    pub fn run_on_body(&self, body: &mut FunctionBody) -> Result<Analyses> {
+        self.run_on_body_with_stats(body, &mut Statistics::default())
+    }
+
+    /// `run_on_body`, adding what each pass did to `stats`.
+    pub fn run_on_body_with_stats(
+        &self,
+        body: &mut FunctionBody,
+        stats: &mut Statistics,
+    ) -> Result<Analyses> {
+        self.run_on_func(body, None, stats)
+    }
+
+    /// `run_on_body_with_stats`, recording each pass on `timeline` as
+    /// run on the given function, if any.
+    fn run_on_func(
+        &self,
+        body: &mut FunctionBody,
+        timeline: Option<(&Timeline, Func)>,
+        stats: &mut Statistics,
+    ) -> Result<Analyses> {
+        if stats.passes.is_empty() {
+            stats.passes = self
+                .passes
+                .iter()
+                .map(|pass| PassStats {
+                    name: pass.name().to_owned(),
+                    ..PassStats::default()
+                })
+                .collect();
+        }
+        let mut analyses = Analyses::default();
+        for (pass, pass_stats) in self.passes.iter().zip(stats.passes.iter_mut()) {
+            span!("pass", name = pass.name());
+            let _span = timeline
+                .map(|(timeline, func)| timeline.span("pass", pass.name()).arg("func", func));
+            let ((), time) = timed(|| {
+                for &analysis in pass.requires() {
+                    analyses.compute(body, analysis);
+                }
+            });
+            stats.analysis_time += time;
+
+            log::debug!("pass manager: running {}", pass.name());
+            let (insts, params) = (live_insts(body), block_params(body));
+            let (result, time) = timed(|| pass.run(body, &analyses));
+            result?;
+            event!(
+                insts_before = insts,
+                insts_after = live_insts(body),
+                "ran {}",
+                pass.name()
+            );
+            pass_stats.time += time;
+            pass_stats.runs += 1;
+            pass_stats.insts_before += insts;
+            pass_stats.insts_after += live_insts(body);
+            pass_stats.params_before += params;
+            pass_stats.params_after += block_params(body);
+
+            for &analysis in pass.clobbers() {
+                analyses.invalidate(analysis);
+            }
+        }
+        log::debug!("pass manager: computed {} analyses", analyses.computed());
+        stats.analyses_computed += analyses.computed();
+        Ok(analyses)
+    }
+
+    /// Run the passes over each body of `module`. Bodies that have not
+    /// been parsed yet are expanded; already-compiled bodies cannot be
+    /// rewritten. Returns what each pass did over all bodies.
+    pub fn run(&self, module: &mut Module<'_>) -> Result<Statistics> {
+        let mut stats = Statistics::default();
+        for func in module.funcs.iter().collect::<Vec<_>>() {
+            span!("run_passes", func = %func);
+            let timeline = module.timeline.clone();
+            match module.expand_func(func)? {
+                FuncDecl::Body(_, _, body) => {
+                    let timeline = timeline.as_ref().map(|timeline| (timeline, func));
+                    self.run_on_func(body, timeline, &mut stats)?;
+                }
+                FuncDecl::Compiled(..) => {
+                    anyhow::bail!("Cannot rewrite {}: it is already compiled", func)
+                }
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// Run `f`, measuring how long it takes. There is no clock without
+/// `std`, or on `wasm32-unknown-unknown`, where `Instant::now` panics,
+/// so there the time is always zero.
+#[cfg(all(
+    feature = "std",
+    not(all(target_arch = "wasm32", target_os = "unknown"))
+))]
+fn timed<T, F: FnOnce() -> T>(f: F) -> (T, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+#[cfg(not(all(
+    feature = "std",
+    not(all(target_arch = "wasm32", target_os = "unknown"))
+)))]
+fn timed<T, F: FnOnce() -> T>(f: F) -> (T, Duration) {
+    (f(), Duration::ZERO)
+}
+
+/// Instructions other than aliases, which passes leave in place of the
+/// instructions they remove.
+fn live_insts(body: &FunctionBody) -> usize {
+    body.blocks
+        .values()
+        .flat_map(|block| block.insts.iter())
+        .filter(|&&inst| !matches!(body.values[inst], ValueDef::Alias(_)))
+        .count()
+}
+
+fn block_params(body: &FunctionBody) -> usize {
+    body.blocks.values().map(|block| block.params.len()).sum()
+}
+
+/// What one pass of a pass manager did, summed over the bodies it ran
+/// on.
+#[derive(Clone, Debug, Default)]
+pub struct PassStats {
+    pub name: String,
+    /// The number of bodies it ran on.
+    pub runs: usize,
+    /// The time spent in the pass itself, not in its analyses.
+    pub time: Duration,
+    /// Instructions before and after the pass.
+    pub insts_before: usize,
+    pub insts_after: usize,
+    /// Block parameters before and after the pass. Each takes a local
+    /// in the output, unless it shares one with its arguments.
+    pub params_before: usize,
+    pub params_after: usize,
+}
+
+impl PassStats {
+    /// The number of instructions the pass removed; negative if it
+    /// added instructions.
+    pub fn insts_removed(&self) -> isize {
+        self.insts_before as isize - self.insts_after as isize
+    }
+
+    /// The number of block parameters the pass removed; negative if it
+    /// added parameters.
+    pub fn params_removed(&self) -> isize {
+        self.params_before as isize - self.params_after as isize
+    }
+}
+
+/// What the passes of a pass manager did, in pipeline order. Printed,
+/// it is a table with one pass per row.
+#[derive(Clone, Debug, Default)]
+pub struct Statistics {
+    pub passes: Vec<PassStats>,
+    /// The time spent computing analyses.
+    pub analysis_time: Duration,
+    /// The number of times an analysis was computed.
+    pub analyses_computed: usize,
+}
+
+impl Statistics {
+    /// The time spent in passes and analyses.
+    pub fn total_time(&self) -> Duration {
+        self.passes.iter().map(|pass| pass.time).sum::<Duration>() + self.analysis_time
+    }
+}
+
+impl core::fmt::Display for Statistics {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(
+            f,
+            "{:<20} {:>8} {:>12} {:>12} {:>10} {:>12} {:>10}",
+            "pass", "runs", "time (ms)", "insts after", "removed", "params after", "removed"
+        )?;
+        for pass in &self.passes {
+            writeln!(
+                f,
+                "{:<20} {:>8} {:>12.3} {:>12} {:>10} {:>12} {:>10}",
+                pass.name,
+                pass.runs,
+                pass.time.as_secs_f64() * 1000.0,
+                pass.insts_after,
+                pass.insts_removed(),
+                pass.params_after,
+                pass.params_removed()
+            )?;
+        }
+        writeln!(
+            f,
+            "{:<20} {:>8} {:>12.3}",
+            "(analyses)",
+            self.analyses_computed,
+            self.analysis_time.as_secs_f64() * 1000.0
+        )?;
+        write!(
+            f,
+            "{:<20} {:>8} {:>12.3}",
+            "total",
+            "",
+            self.total_time().as_secs_f64() * 1000.0
+        )
+    }
+}
@@ -5,9 +5,11 @@
 //! mutating the CFG (all possible blockparams are already there!).
 
 use crate::cfg::CFGInfo;
+use crate::collections::{HashMap, HashSet};
 use crate::entity::PerEntity;
 use crate::ir::{Block, FunctionBody, Value, ValueDef};
-use std::collections::{BTreeSet, HashMap, HashSet};
+use crate::prelude::*;
+use alloc::collections::BTreeSet;
 
 pub fn run(body: &mut FunctionBody, cut_blocks: Option<HashSet<Block>>, cfg: &CFGInfo) {
     MaxSSAPass::new(cut_blocks).run(body, cfg);
@@ -90,7 +92,7 @@ impl MaxSSAPass {
         for i in 0..body.blocks[block].preds.len() {
             // Don't borrow for whole loop while iterating (`body` is
             // taken as mut by recursion, but we don't add preds).
-            let pred = body.blocks[block].preds[i];
+            let pred = body.preds(block)[i];
             self.visit_use(body, cfg, pred, value);
         }
 
@@ -98,8 +100,7 @@ impl MaxSSAPass {
         // cut-block, rewrite the blockparam to an alias instead.
         if !self.is_cut_block(block) {
             if let Some(pred_value) = iter_all_same(
-                body.blocks[block]
-                    .preds
+                body.preds(block)
                     .iter()
                     .map(|&pred| *self.value_map.get(&(pred, value)).unwrap_or(&value))
                     .filter(|&val| val != blockparam),
@@ -145,7 +146,7 @@ impl MaxSSAPass {
 
         for i in 0..body.blocks[block].insts.len() {
             let inst = body.blocks[block].insts[i];
-            let mut def = std::mem::take(&mut body.values[inst]);
+            let mut def = core::mem::take(&mut body.values[inst]);
             match &mut def {
                 ValueDef::Operator(_, args, _) | ValueDef::Trace(_, args) => {
                     for i in 0..args.len() {
@@ -165,7 +166,7 @@ impl MaxSSAPass {
             }
             body.values[inst] = def;
         }
-        let mut term = std::mem::take(&mut body.blocks[block].terminator);
+        let mut term = core::mem::take(&mut body.blocks[block].terminator);
         term.update_uses(|u| {
             *u = resolve(body, *u);
         });
@@ -0,0 +1,373 @@
+//! Memory access tracing pass.
+
+use super::hooks::{import_hook, insert, push_op, HookImport};
+use crate::collections::HashSet;
+use crate::ir::*;
+use crate::ops::MemoryArg;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+use core::ops::Range;
+
+/// What to trace. The tracer is an imported function taking
+/// `(address: i64, size: i32, is_store: i32, value: i64)`: the
+/// effective address (base plus offset, without wrapping), the access
+/// size in bytes, 1 for stores and 0 for loads, and the loaded or
+/// stored value, zero-extended or reinterpreted to 64 bits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemTraceOptions {
+    pub tracer: HookImport,
+    /// Only instrument these functions, numbered as before
+    /// instrumentation; all defined functions if `None`.
+    pub funcs: Option<HashSet<Func>>,
+    /// Only trace accesses whose effective address is in this range.
+    pub addresses: Option<Range<u64>>,
+}
+
+/// The memory argument, size in bytes, and whether `op` is a store,
+/// for loads and stores.
+pub(crate) fn access(op: &Operator) -> Option<(MemoryArg, u32, bool)> {
+    Some(match *op {
+        Operator::I32Load8S { memory }
+        | Operator::I32Load8U { memory }
+        | Operator::I64Load8S { memory }
+        | Operator::I64Load8U { memory } => (memory, 1, false),
+        Operator::I32Load16S { memory }
+        | Operator::I32Load16U { memory }
+        | Operator::I64Load16S { memory }
+        | Operator::I64Load16U { memory } => (memory, 2, false),
+        Operator::I32Load { memory }
+        | Operator::F32Load { memory }
+        | Operator::I64Load32S { memory }
+        | Operator::I64Load32U { memory } => (memory, 4, false),
+        Operator::I64Load { memory } | Operator::F64Load { memory } => (memory, 8, false),
+        Operator::I32Store8 { memory } | Operator::I64Store8 { memory } => (memory, 1, true),
+        Operator::I32Store16 { memory } | Operator::I64Store16 { memory } => (memory, 2, true),
+        Operator::I32Store { memory }
+        | Operator::F32Store { memory }
+        | Operator::I64Store32 { memory } => (memory, 4, true),
+        Operator::I64Store { memory } | Operator::F64Store { memory } => (memory, 8, true),
+        _ => return None,
+    })
+}
+
+/// The memory argument and size in bytes of an atomic access, which
+/// `access` leaves out: it must stay in order with every other
+/// access, and trap unless its address is aligned to its size.
+pub(crate) fn atomic_access(op: &Operator) -> Option<(MemoryArg, u32)> {
+    Some(match *op {
+        Operator::I32AtomicLoad8U { memory }
+        | Operator::I64AtomicLoad8U { memory }
+        | Operator::I32AtomicStore8 { memory }
+        | Operator::I64AtomicStore8 { memory }
+        | Operator::I32AtomicRmw8AddU { memory }
+        | Operator::I64AtomicRmw8AddU { memory }
+        | Operator::I32AtomicRmw8SubU { memory }
+        | Operator::I64AtomicRmw8SubU { memory }
+        | Operator::I32AtomicRmw8AndU { memory }
+        | Operator::I64AtomicRmw8AndU { memory }
+        | Operator::I32AtomicRmw8OrU { memory }
+        | Operator::I64AtomicRmw8OrU { memory }
+        | Operator::I32AtomicRmw8XorU { memory }
+        | Operator::I64AtomicRmw8XorU { memory }
+        | Operator::I32AtomicRmw8XchgU { memory }
+        | Operator::I64AtomicRmw8XchgU { memory }
+        | Operator::I32AtomicRmw8CmpxchgU { memory }
+        | Operator::I64AtomicRmw8CmpxchgU { memory } => (memory, 1),
+        Operator::I32AtomicLoad16U { memory }
+        | Operator::I64AtomicLoad16U { memory }
+        | Operator::I32AtomicStore16 { memory }
+        | Operator::I64AtomicStore16 { memory }
+        | Operator::I32AtomicRmw16AddU { memory }
+        | Operator::I64AtomicRmw16AddU { memory }
+        | Operator::I32AtomicRmw16SubU { memory }
+        | Operator::I64AtomicRmw16SubU { memory }
+        | Operator::I32AtomicRmw16AndU { memory }
+        | Operator::I64AtomicRmw16AndU { memory }
+        | Operator::I32AtomicRmw16OrU { memory }
+        | Operator::I64AtomicRmw16OrU { memory }
+        | Operator::I32AtomicRmw16XorU { memory }
+        | Operator::I64AtomicRmw16XorU { memory }
+        | Operator::I32AtomicRmw16XchgU { memory }
+        | Operator::I64AtomicRmw16XchgU { memory }
+        | Operator::I32AtomicRmw16CmpxchgU { memory }
+        | Operator::I64AtomicRmw16CmpxchgU { memory } => (memory, 2),
+        Operator::MemoryAtomicNotify { memory }
+        | Operator::MemoryAtomicWait32 { memory }
+        | Operator::I32AtomicLoad { memory }
+        | Operator::I64AtomicLoad32U { memory }
+        | Operator::I32AtomicStore { memory }
+        | Operator::I64AtomicStore32 { memory }
+        | Operator::I32AtomicRmwAdd { memory }
+        | Operator::I64AtomicRmw32AddU { memory }
+        | Operator::I32AtomicRmwSub { memory }
+        | Operator::I64AtomicRmw32SubU { memory }
+        | Operator::I32AtomicRmwAnd { memory }
+        | Operator::I64AtomicRmw32AndU { memory }
+        | Operator::I32AtomicRmwOr { memory }
+        | Operator::I64AtomicRmw32OrU { memory }
+        | Operator::I32AtomicRmwXor { memory }
+        | Operator::I64AtomicRmw32XorU { memory }
+        | Operator::I32AtomicRmwXchg { memory }
+        | Operator::I64AtomicRmw32XchgU { memory }
+        | Operator::I32AtomicRmwCmpxchg { memory }
+        | Operator::I64AtomicRmw32CmpxchgU { memory } => (memory, 4),
+        Operator::MemoryAtomicWait64 { memory }
+        | Operator::I64AtomicLoad { memory }
+        | Operator::I64AtomicStore { memory }
+        | Operator::I64AtomicRmwAdd { memory }
+        | Operator::I64AtomicRmwSub { memory }
+        | Operator::I64AtomicRmwAnd { memory }
+        | Operator::I64AtomicRmwOr { memory }
+        | Operator::I64AtomicRmwXor { memory }
+        | Operator::I64AtomicRmwXchg { memory }
+        | Operator::I64AtomicRmwCmpxchg { memory } => (memory, 8),
+        _ => return None,
+    })
+}
+
+/// The type of the value loaded or stored by `op`.
+fn value_type(op: &Operator) -> Type {
+    match op {
+        Operator::I64Load { .. }
+        | Operator::I64Load8S { .. }
+        | Operator::I64Load8U { .. }
+        | Operator::I64Load16S { .. }
+        | Operator::I64Load16U { .. }
+        | Operator::I64Load32S { .. }
+        | Operator::I64Load32U { .. }
+        | Operator::I64Store { .. }
+        | Operator::I64Store8 { .. }
+        | Operator::I64Store16 { .. }
+        | Operator::I64Store32 { .. } => Type::I64,
+        Operator::F32Load { .. } | Operator::F32Store { .. } => Type::F32,
+        Operator::F64Load { .. } | Operator::F64Store { .. } => Type::F64,
+        _ => Type::I32,
+    }
+}
+
+fn instrument(body: &mut FunctionBody, tracer: Func, addresses: Option<&Range<u64>>) {
+    let mut worklist = body.blocks.iter().collect::<Vec<_>>();
+    while let Some(block) = worklist.pop() {
+        let mut i = 0;
+        while i < body.blocks[block].insts.len() {
+            let inst = body.blocks[block].insts[i];
+            i += 1;
+            let (op, args) = match &body.values[inst] {
+                ValueDef::Operator(op, args, _) => (*op, *args),
+                _ => continue,
+            };
+            let (memarg, size, is_store) = match access(&op) {
+                Some(access) => access,
+                None => continue,
+            };
+            let base = body.arg_pool[args][0];
+            let value = if is_store {
+                body.arg_pool[args][1]
+            } else {
+                inst
+            };
+
+            // The tracer is called only once the access has succeeded,
+            // so a trapping access traps at the same point as before
+            // and is not traced. Computing its arguments cannot trap.
+            let mut code = vec![];
+            let mut addr = push_op(
+                body,
+                &mut code,
+                Operator::I64ExtendI32U,
+                &[base],
+                Some(Type::I64),
+            );
+            if memarg.offset != 0 {
+                let offset = push_op(
+                    body,
+                    &mut code,
+                    Operator::I64Const {
+                        value: memarg.offset as u64,
+                    },
+                    &[],
+                    Some(Type::I64),
+                );
+                addr = push_op(
+                    body,
+                    &mut code,
+                    Operator::I64Add,
+                    &[addr, offset],
+                    Some(Type::I64),
+                );
+            }
+
+            let (trace_block, at) = match addresses {
+                None => (block, i),
+                Some(range) => {
+                    let start = push_op(
+                        body,
+                        &mut code,
+                        Operator::I64Const { value: range.start },
+                        &[],
+                        Some(Type::I64),
+                    );
+                    let end = push_op(
+                        body,
+                        &mut code,
+                        Operator::I64Const { value: range.end },
+                        &[],
+                        Some(Type::I64),
+                    );
+                    let above = push_op(
+                        body,
+                        &mut code,
+                        Operator::I64GeU,
+                        &[addr, start],
+                        Some(Type::I32),
+                    );
+                    let below = push_op(
+                        body,
+                        &mut code,
+                        Operator::I64LtU,
+                        &[addr, end],
+                        Some(Type::I32),
+                    );
+                    let cond = push_op(
+                        body,
+                        &mut code,
+                        Operator::I32And,
+                        &[above, below],
+                        Some(Type::I32),
+                    );
+                    insert(body, block, i, &code);
+                    let cont = body.split_block(block, i + code.len());
+                    code.clear();
+                    let trace = body.add_block();
+                    body.set_terminator(
+                        trace,
+                        Terminator::Br {
+                            target: BlockTarget {
+                                block: cont,
+                                args: vec![],
+                            },
+                        },
+                    );
+                    body.set_terminator(
+                        block,
+                        Terminator::CondBr {
+                            cond,
+                            if_true: BlockTarget {
+                                block: trace,
+                                args: vec![],
+                            },
+                            if_false: BlockTarget {
+                                block: cont,
+                                args: vec![],
+                            },
+                        },
+                    );
+                    worklist.push(cont);
+                    (trace, 0)
+                }
+            };
+
+            let value = match value_type(&op) {
+                Type::I32 => push_op(
+                    body,
+                    &mut code,
+                    Operator::I64ExtendI32U,
+                    &[value],
+                    Some(Type::I64),
+                ),
+                Type::F32 => {
+                    let bits = push_op(
+                        body,
+                        &mut code,
+                        Operator::I32ReinterpretF32,
+                        &[value],
+                        Some(Type::I32),
+                    );
+                    push_op(
+                        body,
+                        &mut code,
+                        Operator::I64ExtendI32U,
+                        &[bits],
+                        Some(Type::I64),
+                    )
+                }
+                Type::F64 => push_op(
+                    body,
+                    &mut code,
+                    Operator::I64ReinterpretF64,
+                    &[value],
+                    Some(Type::I64),
+                ),
+                _ => value,
+            };
+            let size = push_op(
+                body,
+                &mut code,
+                Operator::I32Const { value: size },
+                &[],
+                Some(Type::I32),
+            );
+            let is_store = push_op(
+                body,
+                &mut code,
+                Operator::I32Const {
+                    value: is_store as u32,
+                },
+                &[],
+                Some(Type::I32),
+            );
+            push_op(
+                body,
+                &mut code,
+                Operator::Call {
+                    function_index: tracer,
+                },
+                &[addr, size, is_store, value],
+                None,
+            );
+            insert(body, trace_block, at, &code);
+            if trace_block != block {
+                // The rest of the block is in the continuation.
+                break;
+            }
+            i += code.len();
+        }
+    }
+}
+
+/// Insert a call to the tracer after every load and store in the
+/// selected functions. Bodies that have not been parsed yet are
+/// expanded; already-compiled bodies cannot be instrumented.
+pub fn run(module: &mut Module<'_>, options: &MemTraceOptions) -> Result<()> {
+    let mut selected = module
+        .funcs
+        .entries()
+        .filter(|(func, decl)| {
+            let wanted = match &options.funcs {
+                Some(funcs) => funcs.contains(func),
+                None => true,
+            };
+            wanted && !matches!(decl, FuncDecl::Import(..) | FuncDecl::None)
+        })
+        .map(|(func, _)| func)
+        .collect::<Vec<_>>();
+    let sig = SignatureData {
+        params: vec![Type::I64, Type::I32, Type::I32, Type::I64],
+        returns: vec![],
+    };
+    let (tracer, mapping) = import_hook(module, &options.tracer, sig)?;
+    if let Some(mapping) = mapping {
+        for func in &mut selected {
+            *func = mapping.get(*func).unwrap();
+        }
+    }
+
+    for func in selected {
+        match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => instrument(body, tracer, options.addresses.as_ref()),
+            _ => anyhow::bail!("Cannot instrument {}: it is already compiled", func),
+        }
+    }
+    Ok(())
+}
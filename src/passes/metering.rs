@@ -0,0 +1,179 @@
+//! Fuel metering pass.
+
+use super::hooks::{import_hook, insert, push_op, HookImport};
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// The name under which a fuel global added by the metering pass is
+/// exported, so that the embedder can refill and inspect it.
+pub const FUEL_EXPORT: &str = "__waffle_fuel";
+
+/// What happens when a block costs more fuel than is left.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OutOfFuel {
+    Trap,
+    /// Call an imported function taking and returning nothing, which
+    /// should add fuel or trap; the block's cost is charged after it
+    /// returns.
+    Call(HookImport),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MeteringOptions {
+    /// A mutable `i64` global holding the remaining fuel. If `None`,
+    /// one is added with `initial_fuel` and exported as `FUEL_EXPORT`.
+    pub fuel: Option<Global>,
+    pub initial_fuel: u64,
+    pub out_of_fuel: OutOfFuel,
+}
+
+/// The cost used by `run` if none is given: one unit per operator.
+pub fn default_cost(_: &Operator) -> u64 {
+    1
+}
+
+/// Charge fuel on entry to every block of every defined function: the
+/// sum of `cost` over the block's operators, plus one for its
+/// terminator. Every loop iteration enters a block, so back-edges are
+/// metered too. Bodies that have not been parsed yet are expanded;
+/// already-compiled bodies cannot be instrumented. Returns the fuel
+/// global.
+pub fn run<C: Fn(&Operator) -> u64>(
+    module: &mut Module<'_>,
+    options: &MeteringOptions,
+    cost: C,
+) -> Result<Global> {
+    let hook = match &options.out_of_fuel {
+        OutOfFuel::Trap => None,
+        OutOfFuel::Call(hook) => {
+            let sig = SignatureData {
+                params: vec![],
+                returns: vec![],
+            };
+            Some(import_hook(module, hook, sig)?.0)
+        }
+    };
+    let fuel = match options.fuel {
+        Some(fuel) => {
+            match module.globals.get(fuel) {
+                Some(data) if data.ty == Type::I64 && data.mutable => {}
+                Some(_) => anyhow::bail!("Fuel global {} must be a mutable i64", fuel),
+                None => anyhow::bail!("No such global: {}", fuel),
+            }
+            fuel
+        }
+        None => {
+            let fuel =
+                module.add_global(Type::I64, true, GlobalInit::Const(options.initial_fuel))?;
+            module.exports.push(Export {
+                name: FUEL_EXPORT.to_owned(),
+                kind: ExportKind::Global(fuel),
+            });
+            fuel
+        }
+    };
+
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot instrument {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        for block in body.blocks.iter().collect::<Vec<_>>() {
+            let block_cost = body.blocks[block]
+                .insts
+                .iter()
+                .map(|&inst| match &body.values[inst] {
+                    ValueDef::Operator(op, ..) => cost(op),
+                    _ => 0,
+                })
+                .fold(1u64, u64::saturating_add);
+            meter_block(body, block, fuel, block_cost, hook);
+        }
+    }
+    Ok(fuel)
+}
+
+/// Split `block` into a check of the remaining fuel against `cost`
+/// and a continuation that charges it, with the out-of-fuel handling
+/// in between.
+fn meter_block(body: &mut FunctionBody, block: Block, fuel: Global, cost: u64, hook: Option<Func>) {
+    let cont = body.split_block(block, 0);
+    let mut code = vec![];
+    let get = Operator::GlobalGet { global_index: fuel };
+    let left = push_op(body, &mut code, get, &[], Some(Type::I64));
+    let cost_value = push_op(
+        body,
+        &mut code,
+        Operator::I64Const { value: cost },
+        &[],
+        Some(Type::I64),
+    );
+    let cond = push_op(
+        body,
+        &mut code,
+        Operator::I64LtU,
+        &[left, cost_value],
+        Some(Type::I32),
+    );
+    insert(body, block, 0, &code);
+
+    let exhausted = body.add_block();
+    let to_cont = BlockTarget {
+        block: cont,
+        args: vec![],
+    };
+    match hook {
+        Some(hook) => {
+            let mut code = vec![];
+            let call = Operator::Call {
+                function_index: hook,
+            };
+            push_op(body, &mut code, call, &[], None);
+            insert(body, exhausted, 0, &code);
+            body.set_terminator(
+                exhausted,
+                Terminator::Br {
+                    target: to_cont.clone(),
+                },
+            );
+        }
+        None => body.set_terminator(exhausted, Terminator::Unreachable),
+    }
+    body.set_terminator(
+        block,
+        Terminator::CondBr {
+            cond,
+            if_true: BlockTarget {
+                block: exhausted,
+                args: vec![],
+            },
+            if_false: to_cont,
+        },
+    );
+
+    let mut code = vec![];
+    let get = Operator::GlobalGet { global_index: fuel };
+    let left = push_op(body, &mut code, get, &[], Some(Type::I64));
+    let cost_value = push_op(
+        body,
+        &mut code,
+        Operator::I64Const { value: cost },
+        &[],
+        Some(Type::I64),
+    );
+    let left = push_op(
+        body,
+        &mut code,
+        Operator::I64Sub,
+        &[left, cost_value],
+        Some(Type::I64),
+    );
+    let set = Operator::GlobalSet { global_index: fuel };
+    push_op(body, &mut code, set, &[left], None);
+    insert(body, cont, 0, &code);
+}
@@ -0,0 +1,412 @@
+//! Obfuscation pass: control-flow flattening and opaque predicates.
+
+use super::hooks::{insert, push_op};
+use crate::cfg::CFGInfo;
+use crate::collections::HashMap;
+use crate::entity::EntityRef;
+use crate::gen::add_const;
+use crate::ir::*;
+use crate::mutate::{MutationRng, SeededRng};
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// How to obfuscate one function.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Obfuscation {
+    /// Replace the function's control flow with a dispatch loop: every
+    /// block is entered from one dispatcher, which branches on a state
+    /// number set by the block's predecessor.
+    pub flatten: bool,
+    /// Guard about this percentage of blocks with a predicate that is
+    /// always true but not obviously so, whose other arm branches to
+    /// elsewhere in the function.
+    pub opaque_predicates: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ObfuscateOptions {
+    /// What to do to functions not in `funcs`.
+    pub default: Obfuscation,
+    pub funcs: HashMap<Func, Obfuscation>,
+    /// Seeds the choice of blocks, predicates and state numbers.
+    pub seed: u64,
+}
+
+/// The types the dispatcher can carry between blocks: those it can
+/// make up a value of for the blocks that do not use a slot.
+const SLOT_TYPES: [Type; 4] = [Type::I32, Type::I64, Type::F32, Type::F64];
+
+fn slot_kind(ty: Type) -> Option<usize> {
+    SLOT_TYPES.iter().position(|&slot_ty| slot_ty == ty)
+}
+
+/// Obfuscate every defined function as `options` says. Bodies that
+/// have not been parsed yet are expanded; already-compiled bodies
+/// cannot be obfuscated.
+pub fn run(module: &mut Module<'_>, options: &ObfuscateOptions) -> Result<()> {
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let obfuscation = options.funcs.get(&func).copied().unwrap_or(options.default);
+        if obfuscation == Obfuscation::default()
+            || matches!(module.funcs[func], FuncDecl::Import(..) | FuncDecl::None)
+        {
+            continue;
+        }
+        let mut rng = SeededRng::new(options.seed ^ ((func.index() as u64) << 32));
+        match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => {
+                run_on_body(body, &obfuscation, &mut rng);
+            }
+            _ => anyhow::bail!("Cannot obfuscate {}: it is already compiled", func),
+        }
+    }
+    Ok(())
+}
+
+/// Obfuscate `body`: first insert opaque predicates, then flatten,
+/// so that the predicates' bogus edges go through the dispatcher too.
+/// Returns whether the body was flattened, which it is not if it
+/// carries vector or reference values between blocks.
+pub fn run_on_body<R: MutationRng>(
+    body: &mut FunctionBody,
+    obfuscation: &Obfuscation,
+    rng: &mut R,
+) -> bool {
+    if *obfuscation == Obfuscation::default() {
+        return false;
+    }
+    // With every value passed explicitly between blocks, blocks can
+    // be given new predecessors without breaking dominance.
+    body.convert_to_max_ssa(None);
+    if obfuscation.opaque_predicates > 0 {
+        insert_opaque_predicates(body, obfuscation.opaque_predicates, rng);
+        if obfuscation.flatten {
+            // The predicates' arms use values from the guarded block.
+            body.convert_to_max_ssa(None);
+        }
+    }
+    obfuscation.flatten && flatten(body, rng)
+}
+
+/// The values defined in `block` that have a single type.
+fn typed_values(body: &FunctionBody, block: Block) -> Vec<(Type, Value)> {
+    let mut values = body.blocks[block].params.clone();
+    for &inst in &body.blocks[block].insts {
+        if let Some(ty) = body.values[inst].ty(&body.type_pool) {
+            values.push((ty, inst));
+        }
+    }
+    values
+}
+
+/// Make up a value of type `ty` at the end of `block`: one of
+/// `values` if there is one of that type, or else a constant.
+fn any_value<R: MutationRng>(
+    body: &mut FunctionBody,
+    block: Block,
+    values: &[(Type, Value)],
+    ty: Type,
+    rng: &mut R,
+) -> Option<Value> {
+    let candidates = values
+        .iter()
+        .filter(|&&(value_ty, _)| value_ty == ty)
+        .map(|&(_, value)| value)
+        .collect::<Vec<_>>();
+    if !candidates.is_empty() && rng.below(2) == 0 {
+        return Some(candidates[rng.below(candidates.len())]);
+    }
+    slot_kind(ty)?;
+    Some(add_const(body, block, ty, rng.next_u64()))
+}
+
+/// Insert opaque predicates before the terminators of about `percent`
+/// percent of the blocks that compute an `i32`. The predicate's other
+/// arm goes to a bogus block that branches, with made-up arguments,
+/// to the block itself, one of its dominators or one of its
+/// successors, so that the control flow stays reducible.
+pub fn insert_opaque_predicates<R: MutationRng>(
+    body: &mut FunctionBody,
+    percent: u32,
+    rng: &mut R,
+) {
+    let cfg = CFGInfo::new(body);
+    for &block in cfg.rpo.values() {
+        if rng.below(100) >= percent as usize {
+            continue;
+        }
+        let values = typed_values(body, block);
+        let ints = values
+            .iter()
+            .filter(|&&(ty, _)| ty == Type::I32)
+            .map(|&(_, value)| value)
+            .collect::<Vec<_>>();
+        if ints.is_empty() {
+            continue;
+        }
+        let x = ints[rng.below(ints.len())];
+
+        let mut targets = vec![];
+        let mut dom = block;
+        while dom.is_valid() && dom != body.entry {
+            targets.push(dom);
+            dom = cfg.domtree[dom];
+        }
+        body.blocks[block]
+            .terminator
+            .visit_successors(|succ| targets.push(succ));
+
+        let bogus = body.add_block();
+        body.blocks[bogus].terminator = Terminator::Unreachable;
+        if !targets.is_empty() {
+            let target = targets[rng.below(targets.len())];
+            let args = body.blocks[target]
+                .params
+                .clone()
+                .into_iter()
+                .map(|(ty, _)| any_value(body, bogus, &values, ty, rng))
+                .collect::<Option<Vec<_>>>();
+            if let Some(args) = args {
+                body.blocks[bogus].terminator = Terminator::Br {
+                    target: BlockTarget {
+                        block: target,
+                        args,
+                    },
+                };
+            }
+        }
+
+        // Each predicate holds for every `x`, also modulo 2^32: the
+        // product of consecutive numbers is even, a square is 0 or 1
+        // modulo 4, and setting the low bit gives a nonzero number.
+        let mut code = vec![];
+        let one = push_op(
+            body,
+            &mut code,
+            Operator::I32Const { value: 1 },
+            &[],
+            Some(Type::I32),
+        );
+        let (cond, holds) = match rng.below(3) {
+            0 => {
+                let next = push_op(
+                    body,
+                    &mut code,
+                    Operator::I32Add,
+                    &[x, one],
+                    Some(Type::I32),
+                );
+                let product = push_op(
+                    body,
+                    &mut code,
+                    Operator::I32Mul,
+                    &[x, next],
+                    Some(Type::I32),
+                );
+                let odd = push_op(
+                    body,
+                    &mut code,
+                    Operator::I32And,
+                    &[product, one],
+                    Some(Type::I32),
+                );
+                (odd, false)
+            }
+            1 => {
+                let two = push_op(
+                    body,
+                    &mut code,
+                    Operator::I32Const { value: 2 },
+                    &[],
+                    Some(Type::I32),
+                );
+                let square = push_op(body, &mut code, Operator::I32Mul, &[x, x], Some(Type::I32));
+                let bit = push_op(
+                    body,
+                    &mut code,
+                    Operator::I32And,
+                    &[square, two],
+                    Some(Type::I32),
+                );
+                (bit, false)
+            }
+            _ => {
+                let set = push_op(body, &mut code, Operator::I32Or, &[x, one], Some(Type::I32));
+                (set, true)
+            }
+        };
+        let at = body.blocks[block].insts.len();
+        insert(body, block, at, &code);
+
+        let rest = body.add_block();
+        body.blocks[rest].terminator = core::mem::take(&mut body.blocks[block].terminator);
+        let (if_true, if_false) = if holds { (rest, bogus) } else { (bogus, rest) };
+        body.blocks[block].terminator = Terminator::CondBr {
+            cond,
+            if_true: BlockTarget {
+                block: if_true,
+                args: vec![],
+            },
+            if_false: BlockTarget {
+                block: if_false,
+                args: vec![],
+            },
+        };
+    }
+    body.recompute_edges();
+}
+
+/// Flatten `body`, which must be in maximal SSA, into a dispatch
+/// loop: a new entry branches to a dispatcher, which selects on a
+/// state number the original block to run, and every branch between
+/// original blocks instead sets the state and branches back to the
+/// dispatcher. Block arguments are carried in the dispatcher's
+/// parameters, shared between blocks by type. States are numbered in
+/// a random order. Returns `false`, and leaves `body` as it is, if a
+/// block has a parameter the dispatcher cannot carry.
+pub fn flatten<R: MutationRng>(body: &mut FunctionBody, rng: &mut R) -> bool {
+    let cfg = CFGInfo::new(body);
+    let blocks = cfg.rpo.values().copied().collect::<Vec<_>>();
+
+    // The number of dispatcher parameters of each type, after the
+    // state, and the parameter each block parameter is carried in.
+    let mut counts = [0; SLOT_TYPES.len()];
+    for &block in &blocks {
+        let mut used = [0; SLOT_TYPES.len()];
+        for &(ty, _) in &body.blocks[block].params {
+            let kind = match slot_kind(ty) {
+                Some(kind) => kind,
+                None => return false,
+            };
+            used[kind] += 1;
+            counts[kind] = counts[kind].max(used[kind]);
+        }
+    }
+    let mut offsets = [1; SLOT_TYPES.len()];
+    for kind in 1..SLOT_TYPES.len() {
+        offsets[kind] = offsets[kind - 1] + counts[kind - 1];
+    }
+    let slots = blocks
+        .iter()
+        .map(|&block| {
+            let mut used = [0; SLOT_TYPES.len()];
+            let block_slots = body.blocks[block]
+                .params
+                .iter()
+                .map(|&(ty, _)| {
+                    let kind = slot_kind(ty).unwrap();
+                    used[kind] += 1;
+                    offsets[kind] + used[kind] - 1
+                })
+                .collect::<Vec<_>>();
+            (block, block_slots)
+        })
+        .collect::<HashMap<_, _>>();
+    let num_params = offsets[SLOT_TYPES.len() - 1] + counts[SLOT_TYPES.len() - 1];
+
+    let mut states = (0..blocks.len() as u32).collect::<Vec<_>>();
+    for i in (1..states.len()).rev() {
+        states.swap(i, rng.below(i + 1));
+    }
+    let states = blocks
+        .iter()
+        .copied()
+        .zip(states)
+        .collect::<HashMap<_, _>>();
+
+    let dispatcher = body.add_block();
+    let state = body.add_blockparam(dispatcher, Type::I32);
+    let mut params = vec![state];
+    for (kind, &count) in counts.iter().enumerate() {
+        for _ in 0..count {
+            params.push(body.add_blockparam(dispatcher, SLOT_TYPES[kind]));
+        }
+    }
+
+    // A branch to `target` from the end of `from`.
+    let mut jump = |body: &mut FunctionBody, from: Block, target: &BlockTarget| {
+        let state = add_const(body, from, Type::I32, states[&target.block] as u64);
+        let mut args = vec![state];
+        let mut carried = vec![None; num_params];
+        for (&slot, &arg) in slots[&target.block].iter().zip(&target.args) {
+            carried[slot] = Some(arg);
+        }
+        // Slots the target does not use get one made-up value per type.
+        let mut fillers = [Some(state), None, None, None];
+        for (slot, arg) in carried.into_iter().enumerate().skip(1) {
+            let arg = arg.unwrap_or_else(|| {
+                let ty = body.blocks[dispatcher].params[slot].0;
+                *fillers[slot_kind(ty).unwrap()]
+                    .get_or_insert_with(|| add_const(body, from, ty, rng.next_u64()))
+            });
+            args.push(arg);
+        }
+        BlockTarget {
+            block: dispatcher,
+            args,
+        }
+    };
+
+    for &block in &blocks {
+        let terminator = core::mem::take(&mut body.blocks[block].terminator);
+        body.blocks[block].terminator = match terminator {
+            Terminator::Br { target } => Terminator::Br {
+                target: jump(body, block, &target),
+            },
+            mut terminator => {
+                terminator.update_targets(|target| {
+                    let edge = body.add_block();
+                    let target = core::mem::replace(
+                        target,
+                        BlockTarget {
+                            block: edge,
+                            args: vec![],
+                        },
+                    );
+                    let target = jump(body, edge, &target);
+                    body.blocks[edge].terminator = Terminator::Br { target };
+                });
+                terminator
+            }
+        };
+    }
+
+    let entry = body.add_block();
+    let entry_params = body.blocks[body.entry]
+        .params
+        .iter()
+        .map(|&(ty, _)| ty)
+        .collect::<Vec<_>>();
+    let args = entry_params
+        .into_iter()
+        .map(|ty| body.add_blockparam(entry, ty))
+        .collect();
+    let target = BlockTarget {
+        block: body.entry,
+        args,
+    };
+    body.blocks[entry].terminator = Terminator::Br {
+        target: jump(body, entry, &target),
+    };
+    body.entry = entry;
+
+    let trap = body.add_block();
+    body.blocks[trap].terminator = Terminator::Unreachable;
+    let mut targets = vec![None; blocks.len()];
+    for &block in &blocks {
+        targets[states[&block] as usize] = Some(BlockTarget {
+            block,
+            args: slots[&block].iter().map(|&slot| params[slot]).collect(),
+        });
+    }
+    body.blocks[dispatcher].terminator = Terminator::Select {
+        value: state,
+        targets: targets.into_iter().map(Option::unwrap).collect(),
+        default: BlockTarget {
+            block: trap,
+            args: vec![],
+        },
+    };
+    body.recompute_edges();
+    true
+}
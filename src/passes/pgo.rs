@@ -0,0 +1,206 @@
+//! Profile-guided optimization: inlining, block layout and function
+//! ordering driven by branch and call profiles.
+
+use super::branch_profile::{BranchProfile, FuncProfile};
+use super::call_profile::{CallEdgeCount, Callee};
+use super::hooks::push_op;
+use super::inline::{body_size, inline_call};
+use crate::collections::HashMap;
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PgoOptions {
+    /// Inline direct calls along call edges taken at least this many
+    /// times. Call sites in blocks that the branch profile shows never
+    /// ran are left alone.
+    pub inline_min_count: u64,
+    /// Only inline callees with at most this many instructions.
+    pub inline_max_size: usize,
+    /// Make the more frequent target of each conditional branch the
+    /// fallthrough (`if`) arm.
+    pub layout: bool,
+    /// Order the defined functions by decreasing frequency, so that
+    /// hot code is contiguous in the code section.
+    pub order_funcs: bool,
+}
+
+impl Default for PgoOptions {
+    fn default() -> Self {
+        PgoOptions {
+            inline_min_count: 1000,
+            inline_max_size: 64,
+            layout: true,
+            order_funcs: true,
+        }
+    }
+}
+
+/// What the optimizations did.
+#[derive(Clone, Debug, Default)]
+pub struct PgoStats {
+    /// Call sites inlined.
+    pub inlined: usize,
+    /// Conditional branches inverted to fall through to the hotter
+    /// target.
+    pub inverted: usize,
+    /// The renumbering from ordering functions, if they were ordered.
+    pub mapping: Option<FuncMapping>,
+}
+
+/// Optimize `module` with the profiles taken from an instrumented
+/// build of it: `branches` from `branch_profile`, whose block
+/// frequencies are attached first, and `calls` from `call_profile`.
+/// Functions are ordered last, so the profiles refer to the original
+/// function indices throughout. Bodies that have not been parsed yet
+/// are expanded; already-compiled bodies cannot be optimized.
+pub fn run(
+    module: &mut Module<'_>,
+    branches: Option<&BranchProfile>,
+    calls: &[CallEdgeCount],
+    options: &PgoOptions,
+) -> Result<PgoStats> {
+    let mut stats = PgoStats::default();
+    if let Some(branches) = branches {
+        branches.apply(module)?;
+        // Before inlining, which splits blocks, so that the branches
+        // are where the profile says; inlined copies of a callee get
+        // its layout.
+        if options.layout {
+            for profile in &branches.funcs {
+                module.mark_dirty(profile.func);
+                if let Some(body) = module.funcs[profile.func].body_mut() {
+                    stats.inverted += layout(body, profile);
+                }
+            }
+        }
+    }
+
+    let mut edges = calls
+        .iter()
+        .filter_map(|edge| match edge.callee {
+            Callee::Func(callee) if edge.count >= options.inline_min_count => {
+                Some((edge.caller, callee, edge.count))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    edges.sort_by_key(|&(_, _, count)| core::cmp::Reverse(count));
+    for (caller, callee, _) in edges {
+        stats.inlined += inline_edge(module, caller, callee, options)?;
+    }
+
+    if options.order_funcs {
+        // Prefer each function's entry count, and fall back to the
+        // calls made to it.
+        let mut frequency: HashMap<Func, u64> = HashMap::new();
+        for edge in calls {
+            if let Callee::Func(callee) = edge.callee {
+                let count = frequency.entry(callee).or_default();
+                *count = count.saturating_add(edge.count);
+            }
+        }
+        for profile in branches.iter().flat_map(|branches| &branches.funcs) {
+            frequency.insert(profile.func, profile.entries);
+        }
+        let mut hot = frequency
+            .into_iter()
+            .filter(|&(func, count)| {
+                count > 0 && !matches!(module.funcs.get(func), None | Some(FuncDecl::Import(..)))
+            })
+            .collect::<Vec<_>>();
+        hot.sort_by_key(|&(func, count)| (core::cmp::Reverse(count), func));
+        let order = hot.into_iter().map(|(func, _)| func).collect::<Vec<_>>();
+        stats.mapping = Some(module.reorder_functions(&order)?);
+    }
+    Ok(stats)
+}
+
+/// Inline the calls of `callee` in `caller`, returning how many were
+/// inlined.
+fn inline_edge(
+    module: &mut Module<'_>,
+    caller: Func,
+    callee: Func,
+    options: &PgoOptions,
+) -> Result<usize> {
+    if caller == callee || callee.index() >= module.funcs.len() {
+        return Ok(0);
+    }
+    let callee_body = match module.expand_func(callee)? {
+        FuncDecl::Body(_, _, body) if body_size(body) <= options.inline_max_size => body.clone(),
+        FuncDecl::Compiled(..) => {
+            anyhow::bail!("Cannot inline {}: it is already compiled", callee)
+        }
+        _ => return Ok(0),
+    };
+    let body = match module.expand_func(caller)? {
+        FuncDecl::Body(_, _, body) => body,
+        FuncDecl::Compiled(..) => {
+            anyhow::bail!("Cannot optimize {}: it is already compiled", caller)
+        }
+        _ => return Ok(0),
+    };
+
+    let sites = body
+        .blocks
+        .values()
+        .filter(|block| block.frequency != Some(0))
+        .flat_map(|block| block.insts.iter().copied())
+        .filter(|&inst| {
+            matches!(
+                body.values[inst],
+                ValueDef::Operator(Operator::Call { function_index }, ..) if function_index == callee
+            )
+        })
+        .collect::<Vec<_>>();
+    for &call in &sites {
+        let site_frequency = body.blocks[body.value_blocks[call]].frequency;
+        let blocks = inline_call(body, call, &callee_body)?;
+        // Scale the callee's frequencies to this call site.
+        let entries = callee_body.blocks[callee_body.entry].frequency;
+        if let (Some(site), Some(entries)) = (site_frequency, entries.filter(|&n| n > 0)) {
+            for (old, def) in callee_body.blocks.entries() {
+                body.blocks[blocks[old.index()]].frequency = def
+                    .frequency
+                    .map(|n| (n as u128 * site as u128 / entries as u128) as u64);
+            }
+        }
+    }
+    Ok(sites.len())
+}
+
+/// Invert conditional branches that went to their `if_false` target
+/// more often than to their `if_true` target, returning how many were
+/// inverted.
+fn layout(body: &mut FunctionBody, profile: &FuncProfile) -> usize {
+    let mut inverted = 0;
+    for branch in &profile.branches {
+        let block = branch.block;
+        let (cond, if_true, if_false) = match &body.blocks[block].terminator {
+            Terminator::CondBr {
+                cond,
+                if_true,
+                if_false,
+            } if branch.counts[1] > branch.counts[0] => (*cond, if_true.clone(), if_false.clone()),
+            _ => continue,
+        };
+        let mut code = core::mem::take(&mut body.blocks[block].insts);
+        let cond = push_op(body, &mut code, Operator::I32Eqz, &[cond], Some(Type::I32));
+        body.value_blocks[cond] = block;
+        body.blocks[block].insts = code;
+        body.blocks[block].terminator = Terminator::CondBr {
+            cond,
+            if_true: if_false,
+            if_false: if_true,
+        };
+        inverted += 1;
+    }
+    if inverted > 0 {
+        body.recompute_edges();
+    }
+    inverted
+}
@@ -0,0 +1,159 @@
+//! Pre-initialization: run a module's initialization ahead of time in
+//! the interpreter and snapshot the resulting state into the module,
+//! as Wizer does.
+
+use crate::interp::{ConstVal, InterpContext, InterpResult};
+use crate::ir::*;
+use crate::prelude::*;
+use anyhow::Result;
+use core::ops::Range;
+
+const WASM_PAGE: usize = 0x1_0000;
+
+/// Zero runs shorter than this are kept inside a data segment rather
+/// than splitting it, as a segment header costs about as much.
+const MIN_ZERO_GAP: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreinitOptions {
+    /// The exported function, taking and returning nothing, to run
+    /// after the start function. It is removed from the exports.
+    pub init: String,
+    /// Whether initialization may call WASI imports, as far as the
+    /// interpreter supports them; other imports always fail it.
+    pub allow_wasi: bool,
+    /// The number of blocks initialization may run; unlimited if
+    /// `None`.
+    pub fuel: Option<u64>,
+}
+
+impl Default for PreinitOptions {
+    fn default() -> Self {
+        PreinitOptions {
+            init: "wizer.initialize".to_owned(),
+            allow_wasi: false,
+            fuel: None,
+        }
+    }
+}
+
+/// The ranges of `data` to keep in data segments.
+fn nonzero_runs(data: &[u8]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == 0 {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < data.len() && data[i] != 0 {
+            i += 1;
+        }
+        match runs.last_mut() {
+            Some(last) if start - last.end < MIN_ZERO_GAP => last.end = i,
+            _ => runs.push(start..i),
+        }
+    }
+    runs
+}
+
+fn const_bits(value: ConstVal) -> Option<u64> {
+    match value {
+        ConstVal::I32(value) | ConstVal::F32(value) => Some(value as u64),
+        ConstVal::I64(value) | ConstVal::F64(value) => Some(value),
+        ConstVal::None => None,
+    }
+}
+
+/// Run the start function and then `options.init` in the interpreter,
+/// and make the module start out in the resulting state: memory
+/// contents and sizes become its data segments and initial sizes, and
+/// global values its initializers. The start function is removed, and
+/// so is the init export. The module may only import functions, and
+/// initialization may not change tables, which the interpreter does
+/// not support anyway.
+pub fn run(module: &mut Module<'_>, options: &PreinitOptions) -> Result<()> {
+    for import in &module.imports {
+        if !matches!(import.kind, ImportKind::Func(_)) {
+            anyhow::bail!(
+                "Cannot pre-initialize a module importing {}.{}",
+                import.module,
+                import.name
+            );
+        }
+    }
+    let init = module
+        .exports
+        .iter()
+        .position(|export| export.name == options.init)
+        .ok_or_else(|| anyhow::anyhow!("No such export: {}", options.init))?;
+    let init_func = match module.exports[init].kind {
+        ExportKind::Func(func) => func,
+        _ => anyhow::bail!("Export {} is not a function", options.init),
+    };
+    let sig = &module.signatures[module.funcs[init_func].sig()];
+    if !sig.params.is_empty() || !sig.returns.is_empty() {
+        anyhow::bail!(
+            "Init function {} must take and return nothing",
+            options.init
+        );
+    }
+    module.expand_all_funcs()?;
+    for (func, decl) in module.funcs.entries() {
+        if let FuncDecl::Compiled(..) = decl {
+            anyhow::bail!("Cannot interpret {}: it is already compiled", func);
+        }
+    }
+
+    let mut ctx = InterpContext::new(module)?;
+    ctx.wasi = options.allow_wasi;
+    if let Some(fuel) = options.fuel {
+        ctx.fuel = fuel;
+    }
+    let tables = ctx.tables.clone();
+    for func in module.start_func.iter().copied().chain(Some(init_func)) {
+        match ctx.call(module, func, &[]) {
+            InterpResult::Ok(_) => {}
+            InterpResult::UnsupportedImport(import) => anyhow::bail!(
+                "Initialization called import {} ({}), which cannot be run ahead of time",
+                import,
+                module.funcs[import].name()
+            ),
+            result => anyhow::bail!("Initialization failed in {}: {:?}", func, result),
+        }
+    }
+    for (table, _) in module.tables.entries() {
+        if ctx.tables[table] != tables[table] {
+            anyhow::bail!("Initialization changed table {}", table);
+        }
+    }
+
+    // Replace the active segments of each memory with its contents.
+    for index in (0..module.data_segments.len()).rev() {
+        if let DataSegmentKind::Active { .. } = module.data_segments[index].kind {
+            module.remove_data_segment(index as u32)?;
+        }
+    }
+    let memories = module.memories.iter().collect::<Vec<_>>();
+    for memory in memories {
+        let data = &ctx.memories[memory].data;
+        module.memories[memory].initial_pages = data.len() / WASM_PAGE;
+        for run in nonzero_runs(data) {
+            module.add_data_segment(DataSegment {
+                kind: DataSegmentKind::Active {
+                    memory,
+                    offset: SegmentOffset::Const(run.start),
+                },
+                data: data[run].to_vec(),
+            })?;
+        }
+    }
+    for (global, data) in module.globals.entries_mut() {
+        data.init = const_bits(ctx.globals[global]).map(GlobalInit::Const);
+    }
+
+    module.start_func = None;
+    module.exports.remove(init);
+    Ok(())
+}
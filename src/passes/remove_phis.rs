@@ -2,6 +2,7 @@
 
 use crate::cfg::CFGInfo;
 use crate::ir::*;
+use crate::prelude::*;
 
 fn all_equal(mut vals: impl Iterator<Item = Value>) -> Option<Value> {
     match vals.next() {
@@ -53,8 +54,8 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
 
         // Gather arg-lists from each pred's terminator.
         let mut arglists = vec![];
-        for (i, &pred) in func.blocks[block].preds.iter().enumerate() {
-            let pos = func.blocks[block].pos_in_pred_succ[i];
+        for (i, &pred) in func.preds(block).iter().enumerate() {
+            let pos = func.pos_in_pred_succ(block)[i];
             func.blocks[pred].terminator.visit_target(pos, |target| {
                 assert_eq!(target.block, block);
                 assert_eq!(target.args.len(), func.blocks[block].params.len());
@@ -90,8 +91,8 @@ pub fn run(func: &mut FunctionBody, cfg: &CFGInfo) {
         if !deleted.is_empty() {
             delete_indices(&mut func.blocks[block].params, &deleted[..]);
             for i in 0..func.blocks[block].preds.len() {
-                let pred = func.blocks[block].preds[i];
-                let pos = func.blocks[block].pos_in_pred_succ[i];
+                let pred = func.preds(block)[i];
+                let pos = func.pos_in_pred_succ(block)[i];
                 func.blocks[pred].terminator.update_target(pos, |target| {
                     delete_indices(&mut target.args, &deleted[..]);
                 });
@@ -0,0 +1,280 @@
+//! Memory sandboxing by address masking.
+
+use super::hooks::push_op;
+use super::memtrace::{access, atomic_access};
+use crate::cfg::CFGInfo;
+use crate::collections::HashMap;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// How an effective address is forced into the region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SandboxMode {
+    /// Keep the low bits of the effective address. This is one
+    /// operation, but an access that starts near the end of the region
+    /// extends past it by up to seven bytes, so those should be
+    /// reserved too.
+    Mask,
+    /// Clamp the effective address so that the whole access is in the
+    /// region.
+    Clamp,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SandboxOptions {
+    pub memory: Memory,
+    /// The start of the region, a multiple of its size in `Mask` mode.
+    /// Atomic accesses, which trap unless aligned, stay aligned in
+    /// `Clamp` mode only if it is a multiple of eight.
+    pub base: u32,
+    /// The region is `1 << size_log2` bytes, at least eight.
+    pub size_log2: u32,
+    pub mode: SandboxMode,
+}
+
+/// How many accesses were sandboxed, and how many of those reused an
+/// earlier sandboxed address or needed no code at all.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SandboxStats {
+    pub accesses: usize,
+    pub reused: usize,
+    pub proven: usize,
+}
+
+impl SandboxOptions {
+    fn size(&self) -> u64 {
+        1 << self.size_log2
+    }
+
+    /// Where an access of `len` bytes at effective address `address`
+    /// goes.
+    fn sandboxed(&self, address: u32, len: u32) -> u32 {
+        let mask = (self.size() - 1) as u32;
+        match self.mode {
+            SandboxMode::Mask => self.base | (address & mask),
+            SandboxMode::Clamp => self.base + address.min((self.size() - len as u64) as u32),
+        }
+    }
+}
+
+/// Force the effective address of every access to `options.memory` in
+/// every defined function into the region, so that the module can
+/// share the memory with other data. Effective addresses that are
+/// already in the region are left unchanged if the region starts at
+/// zero, and otherwise moved by `base`. The code is skipped when the
+/// address is a constant, or a constant mask that keeps it in a
+/// zero-based region, and its result is reused for later accesses
+/// with the same address and offset where the first access dominates
+/// them. Bodies that have not been parsed yet are expanded;
+/// already-compiled bodies cannot be instrumented.
+pub fn run(module: &mut Module<'_>, options: &SandboxOptions) -> Result<SandboxStats> {
+    if module.memories.get(options.memory).is_none() {
+        anyhow::bail!("No such memory: {}", options.memory);
+    }
+    if !(3..=32).contains(&options.size_log2) {
+        anyhow::bail!("Sandbox size must be between 2^3 and 2^32 bytes");
+    }
+    if options.base as u64 + options.size() > 1 << 32 {
+        anyhow::bail!("Sandbox region ends past 4 GiB");
+    }
+    if options.mode == SandboxMode::Mask && options.base as u64 & (options.size() - 1) != 0 {
+        anyhow::bail!("Sandbox base must be a multiple of its size to mask addresses");
+    }
+
+    let mut stats = SandboxStats::default();
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot instrument {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        sandbox_body(body, options, &mut stats);
+    }
+    Ok(stats)
+}
+
+fn sandbox_body(body: &mut FunctionBody, options: &SandboxOptions, stats: &mut SandboxStats) {
+    let cfg = CFGInfo::new(body);
+    // Sandboxed addresses by original address, offset and access size,
+    // with the block computing them.
+    let mut done: HashMap<(Value, u32, u32), (Value, Block)> = HashMap::new();
+    for &block in cfg.rpo.values() {
+        let insts = core::mem::take(&mut body.blocks[block].insts);
+        let mut new_insts = vec![];
+        for inst in insts {
+            let (mut op, args, tys) = match &body.values[inst] {
+                ValueDef::Operator(op, args, tys) => (*op, *args, *tys),
+                _ => {
+                    new_insts.push(inst);
+                    continue;
+                }
+            };
+            let accessed = access(&op)
+                .map(|(memory, len, _)| (memory, len))
+                .or_else(|| atomic_access(&op));
+            let (memory, len) = match accessed {
+                Some((memory, len)) if memory.memory == options.memory => (memory, len),
+                _ => {
+                    new_insts.push(inst);
+                    continue;
+                }
+            };
+            stats.accesses += 1;
+            let address = body.resolve_alias(body.arg_pool[args][0]);
+            let key = (address, memory.offset, len);
+            let sandboxed = match done.get(&key) {
+                Some(&(value, def)) if cfg.dominates(def, block) => {
+                    stats.reused += 1;
+                    Some(value)
+                }
+                _ => match proven_address(body, address, memory.offset, len, options) {
+                    Some(None) => {
+                        stats.proven += 1;
+                        None
+                    }
+                    Some(Some(value)) => {
+                        stats.proven += 1;
+                        let op = Operator::I32Const { value };
+                        Some(push_op(body, &mut new_insts, op, &[], Some(Type::I32)))
+                    }
+                    None => {
+                        let value = sandbox_address(
+                            body,
+                            &mut new_insts,
+                            address,
+                            memory.offset,
+                            len,
+                            options,
+                        );
+                        done.insert(key, (value, block));
+                        Some(value)
+                    }
+                },
+            };
+            if let Some(sandboxed) = sandboxed {
+                op.memory_arg_mut().unwrap().offset = 0;
+                let mut new_args = body.arg_pool[args].to_vec();
+                new_args[0] = sandboxed;
+                let new_args = body.arg_pool.from_iter(new_args.into_iter());
+                body.values[inst] = ValueDef::Operator(op, new_args, tys);
+            }
+            new_insts.push(inst);
+        }
+        for &inst in &new_insts {
+            body.value_blocks[inst] = block;
+        }
+        body.blocks[block].insts = new_insts;
+    }
+}
+
+/// For an address that needs no runtime code: `Some(None)` if the
+/// access can stay as it is, `Some(Some(address))` for a constant
+/// sandboxed address to use with a zero offset.
+fn proven_address(
+    body: &FunctionBody,
+    address: Value,
+    offset: u32,
+    len: u32,
+    options: &SandboxOptions,
+) -> Option<Option<u32>> {
+    let in_zero_based_region = |end: u64| options.base == 0 && end + len as u64 <= options.size();
+    match &body.values[address] {
+        &ValueDef::Operator(Operator::I32Const { value }, ..) => {
+            let effective = value as u64 + offset as u64;
+            if in_zero_based_region(effective) {
+                Some(None)
+            } else {
+                Some(Some(options.sandboxed(effective as u32, len)))
+            }
+        }
+        ValueDef::Operator(Operator::I32And, args, _) => {
+            let mask = body.arg_pool[*args].iter().find_map(|&arg| {
+                match body.values[body.resolve_alias(arg)] {
+                    ValueDef::Operator(Operator::I32Const { value }, ..) => Some(value),
+                    _ => None,
+                }
+            })?;
+            if in_zero_based_region(mask as u64 + offset as u64) {
+                Some(None)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Code computing the sandboxed effective address.
+fn sandbox_address(
+    body: &mut FunctionBody,
+    code: &mut Vec<Value>,
+    address: Value,
+    offset: u32,
+    len: u32,
+    options: &SandboxOptions,
+) -> Value {
+    let i32_const = |body: &mut FunctionBody, code: &mut Vec<Value>, value: u32| {
+        push_op(
+            body,
+            code,
+            Operator::I32Const { value },
+            &[],
+            Some(Type::I32),
+        )
+    };
+    // Wrapping here only matters for accesses that would trap anyway.
+    let effective = if offset != 0 {
+        let offset = i32_const(body, code, offset);
+        push_op(
+            body,
+            code,
+            Operator::I32Add,
+            &[address, offset],
+            Some(Type::I32),
+        )
+    } else {
+        address
+    };
+    let sandboxed = match options.mode {
+        SandboxMode::Mask => {
+            let mask = i32_const(body, code, (options.size() - 1) as u32);
+            push_op(
+                body,
+                code,
+                Operator::I32And,
+                &[effective, mask],
+                Some(Type::I32),
+            )
+        }
+        SandboxMode::Clamp => {
+            let limit = i32_const(body, code, (options.size() - len as u64) as u32);
+            let below = push_op(
+                body,
+                code,
+                Operator::I32LeU,
+                &[effective, limit],
+                Some(Type::I32),
+            );
+            push_op(
+                body,
+                code,
+                Operator::Select,
+                &[effective, limit, below],
+                Some(Type::I32),
+            )
+        }
+    };
+    if options.base == 0 {
+        return sandboxed;
+    }
+    let base = i32_const(body, code, options.base);
+    let combine = match options.mode {
+        SandboxMode::Mask => Operator::I32Or,
+        SandboxMode::Clamp => Operator::I32Add,
+    };
+    push_op(body, code, combine, &[sandboxed, base], Some(Type::I32))
+}
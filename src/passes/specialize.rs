@@ -0,0 +1,186 @@
+//! Specialization of functions on constant arguments.
+
+use super::hooks::{insert, push_call, push_op};
+use super::inline::{body_size, inline_call};
+use crate::collections::HashMap;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpecializeOptions {
+    /// The most instructions all specialized copies together may add,
+    /// counted after optimizing them.
+    pub budget: usize,
+    /// Functions with more instructions than this are not specialized.
+    pub max_callee_size: usize,
+}
+
+impl Default for SpecializeOptions {
+    fn default() -> Self {
+        SpecializeOptions {
+            budget: 10_000,
+            max_callee_size: 500,
+        }
+    }
+}
+
+/// The bits and type of the constant passed for each parameter, if any.
+type Consts = Vec<Option<(u64, Type)>>;
+
+/// The bits and type of `value` if it is a constant.
+fn constant(body: &FunctionBody, value: Value) -> Option<(u64, Type)> {
+    match body.values[body.resolve_alias(value)] {
+        ValueDef::Operator(Operator::I32Const { value }, ..) => Some((value as u64, Type::I32)),
+        ValueDef::Operator(Operator::I64Const { value }, ..) => Some((value, Type::I64)),
+        ValueDef::Operator(Operator::F32Const { value }, ..) => Some((value as u64, Type::F32)),
+        ValueDef::Operator(Operator::F64Const { value }, ..) => Some((value, Type::F64)),
+        _ => None,
+    }
+}
+
+fn const_op(bits: u64, ty: Type) -> Operator {
+    match ty {
+        Type::I32 => Operator::I32Const { value: bits as u32 },
+        Type::I64 => Operator::I64Const { value: bits },
+        Type::F32 => Operator::F32Const { value: bits as u32 },
+        _ => Operator::F64Const { value: bits },
+    }
+}
+
+/// A copy of `callee`, with signature `sig`, in which the parameters
+/// with a constant in `consts` are that constant, and the others are
+/// the parameters of the copy in order.
+fn specialize(
+    module: &Module<'_>,
+    sig: Signature,
+    callee: Func,
+    callee_body: &FunctionBody,
+    consts: &[Option<(u64, Type)>],
+) -> Result<FunctionBody> {
+    let mut body = FunctionBody::new(module, sig);
+    let entry = body.entry;
+    let mut params = body.blocks[entry]
+        .params
+        .iter()
+        .map(|&(_, param)| param)
+        .collect::<Vec<_>>()
+        .into_iter();
+    let mut code = vec![];
+    let mut args = vec![];
+    for &constant in consts {
+        let arg = match constant {
+            Some((bits, ty)) => push_op(&mut body, &mut code, const_op(bits, ty), &[], Some(ty)),
+            None => params.next().unwrap(),
+        };
+        args.push(arg);
+    }
+    let call = code.len();
+    let results = push_call(&mut body, &mut code, callee, &args, &callee_body.rets);
+    let call = code[call];
+    insert(&mut body, entry, 0, &code);
+    body.set_terminator(entry, Terminator::Return { values: results });
+    inline_call(&mut body, call, callee_body)?;
+    body.optimize();
+    Ok(body)
+}
+
+/// Give each direct call that passes a constant to a defined function
+/// its own copy of the callee, with those parameters removed and the
+/// constants folded in, and redirect the call to the copy. Calls
+/// passing the same constants to the same function share a copy.
+/// Copies are made until they would exceed `options.budget`. Bodies
+/// that have not been parsed yet are expanded; already-compiled bodies
+/// cannot be rewritten. Returns the number of calls redirected.
+pub fn run(module: &mut Module<'_>, options: &SpecializeOptions) -> Result<usize> {
+    let mut specialized: HashMap<(Func, Consts), Func> = HashMap::new();
+    let mut budget = options.budget;
+    let mut redirected = 0;
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot rewrite {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        let mut calls = vec![];
+        for block in body.blocks.values() {
+            for &inst in &block.insts {
+                if let ValueDef::Operator(Operator::Call { function_index }, args, _) =
+                    body.values[inst]
+                {
+                    let consts = body.arg_pool[args]
+                        .iter()
+                        .map(|&arg| constant(body, arg))
+                        .collect::<Vec<_>>();
+                    if consts.iter().any(|constant| constant.is_some()) {
+                        calls.push((inst, function_index, consts));
+                    }
+                }
+            }
+        }
+
+        for (call, callee, consts) in calls {
+            let key = (callee, consts);
+            let spec = match specialized.get(&key) {
+                Some(&spec) => spec,
+                None => {
+                    let callee_body = match module.expand_func(callee)? {
+                        FuncDecl::Body(_, _, body)
+                            if body_size(body) <= options.max_callee_size =>
+                        {
+                            body.clone()
+                        }
+                        _ => continue,
+                    };
+                    let params = module.signatures[module.funcs[callee].sig()]
+                        .params
+                        .iter()
+                        .zip(key.1.iter())
+                        .filter(|(_, constant)| constant.is_none())
+                        .map(|(&ty, _)| ty)
+                        .collect();
+                    let sig = module.find_or_add_signature(SignatureData {
+                        params,
+                        returns: callee_body.rets.clone(),
+                    });
+                    let spec_body = specialize(module, sig, callee, &callee_body, &key.1)?;
+                    let size = body_size(&spec_body);
+                    if size > budget {
+                        continue;
+                    }
+                    budget -= size;
+                    let name = module.funcs[callee].name();
+                    let name = format!("{}$spec{}", name, specialized.len());
+                    let spec = module.add_function(sig, &name, spec_body);
+                    specialized.insert(key.clone(), spec);
+                    spec
+                }
+            };
+
+            let body = module.funcs[func].body_mut().unwrap();
+            let (args, tys) = match body.values[call] {
+                ValueDef::Operator(_, args, tys) => (args, tys),
+                _ => unreachable!(),
+            };
+            let args = body.arg_pool[args]
+                .iter()
+                .zip(key.1.iter())
+                .filter(|(_, constant)| constant.is_none())
+                .map(|(&arg, _)| arg)
+                .collect::<Vec<_>>();
+            let args = body.arg_pool.from_iter(args.into_iter());
+            body.values[call] = ValueDef::Operator(
+                Operator::Call {
+                    function_index: spec,
+                },
+                args,
+                tys,
+            );
+            redirected += 1;
+        }
+    }
+    Ok(redirected)
+}
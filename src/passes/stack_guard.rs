@@ -0,0 +1,147 @@
+//! Call-depth guard pass.
+
+use super::hooks::{insert, push_op};
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// The name under which a depth global added by the guard pass is
+/// exported. A trap leaves it counting the frames that were unwound,
+/// so an embedder that keeps using the instance afterwards should
+/// reset it to zero.
+pub const CALL_DEPTH_EXPORT: &str = "__waffle_call_depth";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackGuardOptions {
+    /// The number of nested calls of defined functions allowed; the
+    /// call that would exceed it traps on entry.
+    pub limit: u32,
+    /// A mutable `i32` global holding the current depth. If `None`,
+    /// one is added and exported as `CALL_DEPTH_EXPORT`.
+    pub depth: Option<Global>,
+}
+
+/// Make every defined function count itself in the depth global on
+/// entry, trapping if the limit is exceeded, and uncount itself on
+/// return. Bodies that have not been parsed yet are expanded;
+/// already-compiled bodies cannot be instrumented. Returns the depth
+/// global.
+pub fn run(module: &mut Module<'_>, options: &StackGuardOptions) -> Result<Global> {
+    let depth = match options.depth {
+        Some(depth) => {
+            match module.globals.get(depth) {
+                Some(data) if data.ty == Type::I32 && data.mutable => {}
+                Some(_) => anyhow::bail!("Depth global {} must be a mutable i32", depth),
+                None => anyhow::bail!("No such global: {}", depth),
+            }
+            depth
+        }
+        None => {
+            let depth = module.add_global(Type::I32, true, GlobalInit::Const(0))?;
+            module.exports.push(Export {
+                name: CALL_DEPTH_EXPORT.to_owned(),
+                kind: ExportKind::Global(depth),
+            });
+            depth
+        }
+    };
+
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot instrument {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        let returns = body
+            .blocks
+            .entries()
+            .filter(|(_, data)| matches!(data.terminator, Terminator::Return { .. }))
+            .map(|(block, _)| block)
+            .collect::<Vec<_>>();
+        for block in returns {
+            let at = body.blocks[block].insts.len();
+            let code = adjust_depth(body, depth, Operator::I32Sub).0;
+            insert(body, block, at, &code);
+        }
+        guard_entry(body, depth, options.limit);
+    }
+    Ok(depth)
+}
+
+/// Code that applies `op` to the depth and one, storing the result,
+/// along with the new depth.
+fn adjust_depth(body: &mut FunctionBody, depth: Global, op: Operator) -> (Vec<Value>, Value) {
+    let mut code = vec![];
+    let get = Operator::GlobalGet {
+        global_index: depth,
+    };
+    let old = push_op(body, &mut code, get, &[], Some(Type::I32));
+    let one = push_op(
+        body,
+        &mut code,
+        Operator::I32Const { value: 1 },
+        &[],
+        Some(Type::I32),
+    );
+    let new = push_op(body, &mut code, op, &[old, one], Some(Type::I32));
+    let set = Operator::GlobalSet {
+        global_index: depth,
+    };
+    push_op(body, &mut code, set, &[new], None);
+    (code, new)
+}
+
+/// Give the body a new entry block that counts the call and checks
+/// the limit before branching to the old entry. A separate block
+/// keeps the check out of any loop through the old entry.
+fn guard_entry(body: &mut FunctionBody, depth: Global, limit: u32) {
+    let old_entry = body.entry;
+    let entry = body.add_block();
+    let params = body.blocks[old_entry]
+        .params
+        .iter()
+        .map(|&(ty, _)| ty)
+        .collect::<Vec<_>>();
+    let args = params
+        .into_iter()
+        .map(|ty| body.add_blockparam(entry, ty))
+        .collect::<Vec<_>>();
+    body.entry = entry;
+
+    let (mut code, new) = adjust_depth(body, depth, Operator::I32Add);
+    let limit = push_op(
+        body,
+        &mut code,
+        Operator::I32Const { value: limit },
+        &[],
+        Some(Type::I32),
+    );
+    let cond = push_op(
+        body,
+        &mut code,
+        Operator::I32GtU,
+        &[new, limit],
+        Some(Type::I32),
+    );
+    insert(body, entry, 0, &code);
+
+    let trap = body.add_block();
+    body.set_terminator(trap, Terminator::Unreachable);
+    body.set_terminator(
+        entry,
+        Terminator::CondBr {
+            cond,
+            if_true: BlockTarget {
+                block: trap,
+                args: vec![],
+            },
+            if_false: BlockTarget {
+                block: old_entry,
+                args,
+            },
+        },
+    );
+}
@@ -0,0 +1,90 @@
+//! Promotion of shadow-stack slots to SSA values.
+
+use super::hooks::{insert, push_op};
+use super::memtrace::access;
+use crate::analysis::stack_frame::{StackFrame, StackSlot};
+use crate::collections::HashSet;
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// Keep the promotable slots of every function's stack frame (see
+/// `StackFrame`) in SSA values instead of memory, given the
+/// stack-pointer global. Each slot gets a parameter on every block but
+/// the entry; running `optimize` afterwards removes those that turn
+/// out to be unneeded. The frame itself is still allocated. Bodies
+/// that have not been parsed yet are expanded; already-compiled bodies
+/// cannot be optimized. Returns the number of slots promoted.
+pub fn run(module: &mut Module<'_>, sp: Global) -> Result<usize> {
+    let mut promoted = 0;
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot optimize {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        if let Some(frame) = StackFrame::compute(body, sp) {
+            promoted += promote(body, &frame.promotable_slots());
+        }
+    }
+    Ok(promoted)
+}
+
+/// Promote `slots` of the frame of `body`, returning how many were
+/// promoted. Loads before any store see zero; reading uninitialized
+/// stack memory is undefined in the languages that use the idiom.
+pub fn promote(body: &mut FunctionBody, slots: &[StackSlot]) -> usize {
+    // The entry block has no parameter to carry a slot's value in.
+    if slots.is_empty() || !body.blocks[body.entry].preds.is_empty() {
+        return 0;
+    }
+    let mut promoted = 0;
+    for slot in slots {
+        let zero = match slot.ty {
+            Type::I32 => Operator::I32Const { value: 0 },
+            Type::I64 => Operator::I64Const { value: 0 },
+            Type::F32 => Operator::F32Const { value: 0 },
+            Type::F64 => Operator::F64Const { value: 0 },
+            _ => continue,
+        };
+        let mut code = vec![];
+        let zero = push_op(body, &mut code, zero, &[], Some(slot.ty));
+        insert(body, body.entry, 0, &code);
+
+        let accesses = slot.accesses.iter().copied().collect::<HashSet<_>>();
+        for block in body.blocks.iter().collect::<Vec<_>>() {
+            let mut current = if block == body.entry {
+                zero
+            } else {
+                body.add_blockparam(block, slot.ty)
+            };
+            let insts = core::mem::take(&mut body.blocks[block].insts);
+            let mut new_insts = Vec::with_capacity(insts.len());
+            for inst in insts {
+                if !accesses.contains(&inst) {
+                    new_insts.push(inst);
+                    continue;
+                }
+                match body.values[inst] {
+                    ValueDef::Operator(op, args, _)
+                        if matches!(access(&op), Some((_, _, true))) =>
+                    {
+                        current = body.arg_pool[args][1];
+                    }
+                    _ => body.values[inst] = ValueDef::Alias(current),
+                }
+                body.value_blocks[inst] = Block::invalid();
+            }
+            body.blocks[block].insts = new_insts;
+            body.blocks[block]
+                .terminator
+                .update_targets(|target| target.args.push(current));
+        }
+        promoted += 1;
+    }
+    promoted
+}
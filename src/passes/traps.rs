@@ -0,0 +1,390 @@
+//! Trap virtualization pass: report traps to a handler before they
+//! happen.
+
+use super::hooks::{import_hook, insert, push_op, HookImport};
+use super::memtrace::access;
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use anyhow::Result;
+
+/// Why an operator was about to trap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrapKind {
+    Unreachable,
+    DivideByZero,
+    /// A signed division of the minimum value by -1.
+    IntegerOverflow,
+    /// A float-to-integer truncation of NaN or an out-of-range value.
+    InvalidConversion,
+    OutOfBounds,
+}
+
+impl TrapKind {
+    /// The code passed to the handler.
+    pub fn code(self) -> u32 {
+        match self {
+            TrapKind::Unreachable => 0,
+            TrapKind::DivideByZero => 1,
+            TrapKind::IntegerOverflow => 2,
+            TrapKind::InvalidConversion => 3,
+            TrapKind::OutOfBounds => 4,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<TrapKind> {
+        Some(match code {
+            0 => TrapKind::Unreachable,
+            1 => TrapKind::DivideByZero,
+            2 => TrapKind::IntegerOverflow,
+            3 => TrapKind::InvalidConversion,
+            4 => TrapKind::OutOfBounds,
+            _ => return None,
+        })
+    }
+}
+
+/// The handler is an imported function taking `(kind: i32, func: i32,
+/// offset: i32)`: the `TrapKind` code, the index of the trapping
+/// function in the instrumented module, and the offset in the original
+/// module of the trapping operator, or -1 if unknown. The trap happens
+/// when the handler returns, unless it traps or throws itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrapOptions {
+    pub handler: HookImport,
+    /// Also check every load and store against the size of its memory.
+    /// This is costly, so it is optional.
+    pub memory_accesses: bool,
+}
+
+/// The checks needed before `op`: for each, the kind of trap and a
+/// function adding code to compute whether it would happen.
+type Check = (
+    TrapKind,
+    fn(&mut FunctionBody, &mut Vec<Value>, &Operator, &[Value]) -> Value,
+);
+
+fn checks(op: &Operator, memory_accesses: bool) -> Vec<Check> {
+    match op {
+        Operator::I32DivS | Operator::I64DivS => vec![
+            (TrapKind::DivideByZero, divisor_is_zero),
+            (TrapKind::IntegerOverflow, division_overflows),
+        ],
+        Operator::I32DivU
+        | Operator::I32RemS
+        | Operator::I32RemU
+        | Operator::I64DivU
+        | Operator::I64RemS
+        | Operator::I64RemU => vec![(TrapKind::DivideByZero, divisor_is_zero)],
+        Operator::I32TruncF32S
+        | Operator::I32TruncF32U
+        | Operator::I32TruncF64S
+        | Operator::I32TruncF64U
+        | Operator::I64TruncF32S
+        | Operator::I64TruncF32U
+        | Operator::I64TruncF64S
+        | Operator::I64TruncF64U => vec![(TrapKind::InvalidConversion, truncation_is_invalid)],
+        _ if memory_accesses && access(op).is_some() => {
+            vec![(TrapKind::OutOfBounds, access_is_out_of_bounds)]
+        }
+        _ => vec![],
+    }
+}
+
+fn is_i64_op(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::I64DivS
+            | Operator::I64DivU
+            | Operator::I64RemS
+            | Operator::I64RemU
+            | Operator::I64TruncF32S
+            | Operator::I64TruncF32U
+            | Operator::I64TruncF64S
+            | Operator::I64TruncF64U
+    )
+}
+
+fn divisor_is_zero(
+    body: &mut FunctionBody,
+    code: &mut Vec<Value>,
+    op: &Operator,
+    args: &[Value],
+) -> Value {
+    let eqz = if is_i64_op(op) {
+        Operator::I64Eqz
+    } else {
+        Operator::I32Eqz
+    };
+    push_op(body, code, eqz, &[args[1]], Some(Type::I32))
+}
+
+fn division_overflows(
+    body: &mut FunctionBody,
+    code: &mut Vec<Value>,
+    op: &Operator,
+    args: &[Value],
+) -> Value {
+    let (min, minus_one, eq, ty) = if is_i64_op(op) {
+        (
+            Operator::I64Const { value: 1 << 63 },
+            Operator::I64Const { value: u64::MAX },
+            Operator::I64Eq,
+            Type::I64,
+        )
+    } else {
+        (
+            Operator::I32Const { value: 1 << 31 },
+            Operator::I32Const { value: u32::MAX },
+            Operator::I32Eq,
+            Type::I32,
+        )
+    };
+    let min = push_op(body, code, min, &[], Some(ty));
+    let minus_one = push_op(body, code, minus_one, &[], Some(ty));
+    let is_min = push_op(body, code, eq, &[args[0], min], Some(Type::I32));
+    let is_minus_one = push_op(body, code, eq, &[args[1], minus_one], Some(Type::I32));
+    push_op(
+        body,
+        code,
+        Operator::I32And,
+        &[is_min, is_minus_one],
+        Some(Type::I32),
+    )
+}
+
+fn truncation_is_invalid(
+    body: &mut FunctionBody,
+    code: &mut Vec<Value>,
+    op: &Operator,
+    args: &[Value],
+) -> Value {
+    // The range of valid inputs, whose bounds are exact in both float
+    // types; comparisons with NaN are false, so NaN is invalid.
+    const P31: f64 = 2147483648.0;
+    const P32: f64 = 4294967296.0;
+    const P63: f64 = 9223372036854775808.0;
+    const P64: f64 = 18446744073709551616.0;
+    let (low, low_inclusive, high) = match op {
+        Operator::I32TruncF32S => (-P31, true, P31),
+        Operator::I32TruncF64S => (-P31 - 1.0, false, P31),
+        Operator::I64TruncF32S | Operator::I64TruncF64S => (-P63, true, P63),
+        Operator::I32TruncF32U | Operator::I32TruncF64U => (-1.0, false, P32),
+        _ => (-1.0, false, P64),
+    };
+    let is_f32 = matches!(
+        op,
+        Operator::I32TruncF32S
+            | Operator::I32TruncF32U
+            | Operator::I64TruncF32S
+            | Operator::I64TruncF32U
+    );
+    let (low_op, high_op, ty) = if is_f32 {
+        (
+            Operator::F32Const {
+                value: (low as f32).to_bits(),
+            },
+            Operator::F32Const {
+                value: (high as f32).to_bits(),
+            },
+            Type::F32,
+        )
+    } else {
+        (
+            Operator::F64Const {
+                value: low.to_bits(),
+            },
+            Operator::F64Const {
+                value: high.to_bits(),
+            },
+            Type::F64,
+        )
+    };
+    let (ge, gt, lt) = if is_f32 {
+        (Operator::F32Ge, Operator::F32Gt, Operator::F32Lt)
+    } else {
+        (Operator::F64Ge, Operator::F64Gt, Operator::F64Lt)
+    };
+    let low = push_op(body, code, low_op, &[], Some(ty));
+    let high = push_op(body, code, high_op, &[], Some(ty));
+    let above = if low_inclusive { ge } else { gt };
+    let above = push_op(body, code, above, &[args[0], low], Some(Type::I32));
+    let below = push_op(body, code, lt, &[args[0], high], Some(Type::I32));
+    let valid = push_op(
+        body,
+        code,
+        Operator::I32And,
+        &[above, below],
+        Some(Type::I32),
+    );
+    push_op(body, code, Operator::I32Eqz, &[valid], Some(Type::I32))
+}
+
+fn access_is_out_of_bounds(
+    body: &mut FunctionBody,
+    code: &mut Vec<Value>,
+    op: &Operator,
+    args: &[Value],
+) -> Value {
+    let (memory, size, _) = access(op).unwrap();
+    let address = push_op(
+        body,
+        code,
+        Operator::I64ExtendI32U,
+        &[args[0]],
+        Some(Type::I64),
+    );
+    let extent = Operator::I64Const {
+        value: memory.offset as u64 + size as u64,
+    };
+    let extent = push_op(body, code, extent, &[], Some(Type::I64));
+    let end = push_op(
+        body,
+        code,
+        Operator::I64Add,
+        &[address, extent],
+        Some(Type::I64),
+    );
+    let pages = Operator::MemorySize { mem: memory.memory };
+    let pages = push_op(body, code, pages, &[], Some(Type::I32));
+    let pages = push_op(
+        body,
+        code,
+        Operator::I64ExtendI32U,
+        &[pages],
+        Some(Type::I64),
+    );
+    let sixteen = push_op(
+        body,
+        code,
+        Operator::I64Const { value: 16 },
+        &[],
+        Some(Type::I64),
+    );
+    let limit = push_op(
+        body,
+        code,
+        Operator::I64Shl,
+        &[pages, sixteen],
+        Some(Type::I64),
+    );
+    push_op(body, code, Operator::I64GtU, &[end, limit], Some(Type::I32))
+}
+
+/// Code calling the handler.
+fn report(
+    body: &mut FunctionBody,
+    handler: Func,
+    kind: TrapKind,
+    func: Func,
+    offset: Option<u32>,
+) -> Vec<Value> {
+    let mut code = vec![];
+    let args = [kind.code(), func.index() as u32, offset.unwrap_or(u32::MAX)]
+        .iter()
+        .map(|&value| {
+            let op = Operator::I32Const { value };
+            push_op(body, &mut code, op, &[], Some(Type::I32))
+        })
+        .collect::<Vec<_>>();
+    let call = Operator::Call {
+        function_index: handler,
+    };
+    push_op(body, &mut code, call, &args, None);
+    code
+}
+
+fn instrument(body: &mut FunctionBody, func: Func, handler: Func, options: &TrapOptions) {
+    for block in body.blocks.iter().collect::<Vec<_>>() {
+        let mut block = block;
+        let mut at = 0;
+        while at < body.blocks[block].insts.len() {
+            let inst = body.blocks[block].insts[at];
+            let (op, args) = match &body.values[inst] {
+                ValueDef::Operator(op, args, _) => (*op, body.arg_pool[*args].to_vec()),
+                _ => {
+                    at += 1;
+                    continue;
+                }
+            };
+            let offset = body.source_offsets[inst];
+            if matches!(op, Operator::Unreachable) {
+                let code = report(body, handler, TrapKind::Unreachable, func, offset);
+                insert(body, block, at, &code);
+                at += code.len() + 1;
+                continue;
+            }
+            let checks = checks(&op, options.memory_accesses);
+            if checks.is_empty() {
+                at += 1;
+                continue;
+            }
+            for (kind, check) in checks {
+                let cont = body.split_block(block, at);
+                let mut code = vec![];
+                let cond = check(body, &mut code, &op, &args);
+                let len = body.blocks[block].insts.len();
+                insert(body, block, len, &code);
+
+                let trap = body.add_block();
+                let code = report(body, handler, kind, func, offset);
+                insert(body, trap, 0, &code);
+                body.set_terminator(trap, Terminator::Unreachable);
+                body.set_terminator(
+                    block,
+                    Terminator::CondBr {
+                        cond,
+                        if_true: BlockTarget {
+                            block: trap,
+                            args: vec![],
+                        },
+                        if_false: BlockTarget {
+                            block: cont,
+                            args: vec![],
+                        },
+                    },
+                );
+                block = cont;
+                at = 0;
+            }
+            at += 1;
+        }
+        if matches!(body.blocks[block].terminator, Terminator::Unreachable) {
+            // The last operator with a known offset is the best guess
+            // at where the trap came from.
+            let offset = body.blocks[block]
+                .insts
+                .iter()
+                .rev()
+                .find_map(|&inst| body.source_offsets[inst]);
+            let code = report(body, handler, TrapKind::Unreachable, func, offset);
+            let len = body.blocks[block].insts.len();
+            insert(body, block, len, &code);
+        }
+    }
+}
+
+/// Make every trap that the operators of defined functions can cause,
+/// and every `unreachable`, call the handler first. Indirect calls,
+/// table accesses and traps in imports are not covered. Bodies that
+/// have not been parsed yet are expanded; already-compiled bodies
+/// cannot be instrumented.
+pub fn run(module: &mut Module<'_>, options: &TrapOptions) -> Result<()> {
+    let sig = SignatureData {
+        params: vec![Type::I32, Type::I32, Type::I32],
+        returns: vec![],
+    };
+    let handler = import_hook(module, &options.handler, sig)?.0;
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            FuncDecl::Compiled(..) => {
+                anyhow::bail!("Cannot instrument {}: it is already compiled", func)
+            }
+            _ => continue,
+        };
+        instrument(body, func, handler, options);
+    }
+    Ok(())
+}
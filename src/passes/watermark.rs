@@ -0,0 +1,309 @@
+//! Watermarking pass: embed a keyed mark in a module's code and in a
+//! custom section, and detect it again.
+//!
+//! In the code, each bit of the mark is carried by instructions with
+//! a constant operand, chosen by a keyed hash of the function, the
+//! operator and the constant: whether the constant comes first or
+//! last in a commutative operator, and whether `x + c` is written as
+//! `x - (-c)`. Both choices leave the behavior as it is, and both
+//! survive lifting and recompiling, stripping custom sections, and
+//! reordering blocks or functions' instructions. The order of locals
+//! carries nothing: the backend reassigns locals whenever it
+//! recompiles a body.
+
+use crate::entity::EntityRef;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::Operator;
+use alloc::borrow::Cow;
+use anyhow::{bail, Result};
+
+/// The custom section holding the mark, encrypted with the key.
+pub const SECTION_NAME: &str = "waffle.watermark";
+
+/// How many instructions must carry the mark, at the least, for
+/// `Verification::found` to trust the code alone.
+pub const MIN_SITES: usize = 16;
+
+/// What `verify` found of a mark.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// Whether the custom section holds the mark.
+    pub section: bool,
+    /// Instructions that carry a bit of the mark, if it is there.
+    pub sites: usize,
+    /// How many of them carry the right bit. In a module without the
+    /// mark, or with another key, about half do by chance.
+    pub matching: usize,
+}
+
+impl Verification {
+    /// Whether the module carries the mark: in the section, or in at
+    /// least `MIN_SITES` instructions of which nine in ten match.
+    pub fn found(&self) -> bool {
+        self.section || (self.sites >= MIN_SITES && self.matching * 10 >= self.sites * 9)
+    }
+}
+
+/// A finalizer of splitmix64, to hash with.
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// The commutative operators whose operand order carries a bit.
+const COMMUTATIVE: [Operator; 12] = [
+    Operator::I32Mul,
+    Operator::I32And,
+    Operator::I32Or,
+    Operator::I32Xor,
+    Operator::I32Eq,
+    Operator::I32Ne,
+    Operator::I64Mul,
+    Operator::I64And,
+    Operator::I64Or,
+    Operator::I64Xor,
+    Operator::I64Eq,
+    Operator::I64Ne,
+];
+
+/// An instruction that carries a bit of the mark.
+struct Site {
+    /// Keyed hash of what the instruction computes, which selects the
+    /// bit and the pad it is xored with.
+    hash: u64,
+    /// The choice the instruction makes: constant first, or subtract.
+    symbol: bool,
+}
+
+impl Site {
+    fn bit(&self, bits: usize) -> usize {
+        (self.hash % bits as u64) as usize
+    }
+
+    /// The bit of the mark the instruction carries.
+    fn value(&self) -> bool {
+        self.symbol ^ (self.hash >> 63 != 0)
+    }
+}
+
+fn constant(body: &FunctionBody, value: Value) -> Option<u64> {
+    match body.values[body.resolve_alias(value)] {
+        ValueDef::Operator(Operator::I32Const { value }, ..) => Some(value as u64),
+        ValueDef::Operator(Operator::I64Const { value }, ..) => Some(value),
+        _ => None,
+    }
+}
+
+/// The site `value` is, if any, for `key` in `func`.
+fn site(body: &FunctionBody, key: u64, func: Func, value: Value) -> Option<Site> {
+    let (op, args) = match &body.values[value] {
+        ValueDef::Operator(op, args, _) => (*op, &body.arg_pool[*args]),
+        _ => return None,
+    };
+    if args.len() != 2 {
+        return None;
+    }
+    let consts = (constant(body, args[0]), constant(body, args[1]));
+    let (tag, operand, symbol) = match op {
+        Operator::I32Add | Operator::I32Sub | Operator::I64Add | Operator::I64Sub => {
+            let wide = matches!(op, Operator::I64Add | Operator::I64Sub);
+            let subtract = matches!(op, Operator::I32Sub | Operator::I64Sub);
+            // The amount added, up to its sign, so that both ways of
+            // writing it select the same bit.
+            let amount = match consts {
+                (None, Some(c)) => c,
+                (Some(c), None) if !subtract => c,
+                _ => return None,
+            };
+            let negated = if wide {
+                amount.wrapping_neg()
+            } else {
+                (amount as u32).wrapping_neg() as u64
+            };
+            (wide as u64, amount.min(negated), subtract)
+        }
+        _ => {
+            let tag = COMMUTATIVE.iter().position(|&c| c == op)?;
+            match consts {
+                (Some(c), None) => (2 + tag as u64, c, true),
+                (None, Some(c)) => (2 + tag as u64, c, false),
+                _ => return None,
+            }
+        }
+    };
+    let hash = mix(mix(mix(key ^ func.index() as u64) ^ tag) ^ operand);
+    Some(Site { hash, symbol })
+}
+
+/// Have the site `value`, the `at`th instruction of `block`, make the
+/// other choice: swap its operands, or negate its constant and add
+/// instead of subtracting or the other way around.
+fn flip(body: &mut FunctionBody, block: Block, at: usize, value: Value) {
+    let (op, args, tys) = match body.values[value] {
+        ValueDef::Operator(op, args, tys) => (op, args, tys),
+        _ => unreachable!(),
+    };
+    let mut operands = body.arg_pool[args].to_vec();
+    let op = match op {
+        Operator::I32Add | Operator::I32Sub | Operator::I64Add | Operator::I64Sub => {
+            if constant(body, operands[1]).is_none() {
+                operands.swap(0, 1);
+            }
+            let c = constant(body, operands[1]).unwrap();
+            let (negated, ty) = if matches!(op, Operator::I64Add | Operator::I64Sub) {
+                let value = c.wrapping_neg();
+                (Operator::I64Const { value }, Type::I64)
+            } else {
+                let value = (c as u32).wrapping_neg();
+                (Operator::I32Const { value }, Type::I32)
+            };
+            let flipped = match op {
+                Operator::I32Add => Operator::I32Sub,
+                Operator::I32Sub => Operator::I32Add,
+                Operator::I64Add => Operator::I64Sub,
+                _ => Operator::I64Add,
+            };
+            let no_args = body.arg_pool.from_iter(core::iter::empty());
+            let tys = body.single_type_list(ty);
+            let negated = body.add_value(ValueDef::Operator(negated, no_args, tys));
+            body.blocks[block].insts.insert(at, negated);
+            body.value_blocks[negated] = block;
+            operands[1] = negated;
+            flipped
+        }
+        _ => {
+            operands.swap(0, 1);
+            op
+        }
+    };
+    let args = body.arg_pool.from_iter(operands.into_iter());
+    body.values[value] = ValueDef::Operator(op, args, tys);
+}
+
+/// The pad the section's `i`th byte is xored with.
+fn pad(key: u64, i: usize) -> u8 {
+    mix(mix(key ^ 0x5741_5445_524d_4152) ^ i as u64) as u8
+}
+
+/// Embed `mark` in `module` with `key`: in every defined function's
+/// instructions that can carry a bit of it, and in the custom section
+/// `SECTION_NAME`, replacing any there was. Bodies that have not been
+/// parsed yet are expanded; already-compiled bodies cannot be marked.
+/// Returns the number of instructions that carry the mark.
+pub fn embed(module: &mut Module<'_>, mark: &[u8], key: u64) -> Result<usize> {
+    if mark.is_empty() {
+        bail!("The watermark is empty");
+    }
+    let bits = mark.len() * 8;
+    let mut sites = 0;
+    for func in module.funcs.iter().collect::<Vec<_>>() {
+        if matches!(module.funcs[func], FuncDecl::Import(..) | FuncDecl::None) {
+            continue;
+        }
+        let body = match module.expand_func(func)? {
+            FuncDecl::Body(_, _, body) => body,
+            _ => bail!("Cannot watermark {}: it is already compiled", func),
+        };
+        for block in body.blocks.iter() {
+            let mut at = 0;
+            while at < body.blocks[block].insts.len() {
+                let value = body.blocks[block].insts[at];
+                if let Some(site) = site(body, key, func, value) {
+                    let bit = site.bit(bits);
+                    if site.value() != (mark[bit / 8] >> (bit % 8) & 1 != 0) {
+                        let len = body.blocks[block].insts.len();
+                        flip(body, block, at, value);
+                        at += body.blocks[block].insts.len() - len;
+                    }
+                    sites += 1;
+                }
+                at += 1;
+            }
+        }
+    }
+    module.remove_custom_sections(SECTION_NAME);
+    let data = mark
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| byte ^ pad(key, i))
+        .collect();
+    module.add_custom_section(SECTION_NAME, data);
+    Ok(sites)
+}
+
+/// The sites of every body in `module` that can be read.
+fn module_sites(module: &Module<'_>, key: u64) -> Result<Vec<Site>> {
+    let mut sites = vec![];
+    for (func, decl) in module.funcs.entries() {
+        let body = match decl {
+            FuncDecl::Body(_, _, body) => Cow::Borrowed(body),
+            FuncDecl::Lazy(..) => Cow::Owned(module.clone_and_expand_body(func)?),
+            _ => continue,
+        };
+        for block in body.blocks.values() {
+            sites.extend(
+                block
+                    .insts
+                    .iter()
+                    .filter_map(|&value| site(&body, key, func, value)),
+            );
+        }
+    }
+    Ok(sites)
+}
+
+/// Look for `mark`, embedded with `key`, in `module`.
+pub fn verify(module: &Module<'_>, mark: &[u8], key: u64) -> Result<Verification> {
+    if mark.is_empty() {
+        bail!("The watermark is empty");
+    }
+    let section = match module.custom_section(SECTION_NAME) {
+        Some(section) => {
+            section.data.len() == mark.len()
+                && section
+                    .data
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &byte)| byte ^ pad(key, i) == mark[i])
+        }
+        None => false,
+    };
+    let bits = mark.len() * 8;
+    let sites = module_sites(module, key)?;
+    let matching = sites
+        .iter()
+        .filter(|site| {
+            let bit = site.bit(bits);
+            site.value() == (mark[bit / 8] >> (bit % 8) & 1 != 0)
+        })
+        .count();
+    Ok(Verification {
+        section,
+        sites: sites.len(),
+        matching,
+    })
+}
+
+/// Recover a mark of `len` bytes embedded with `key` from the code of
+/// `module` alone, by majority vote over the instructions carrying
+/// each bit. Bits no instruction carries are zero.
+pub fn extract(module: &Module<'_>, len: usize, key: u64) -> Result<Vec<u8>> {
+    let bits = len * 8;
+    let mut votes = vec![0i64; bits];
+    if bits > 0 {
+        for site in module_sites(module, key)? {
+            votes[site.bit(bits)] += if site.value() { 1 } else { -1 };
+        }
+    }
+    let mut mark = vec![0; len];
+    for (bit, &vote) in votes.iter().enumerate() {
+        if vote > 0 {
+            mark[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+    Ok(mark)
+}
@@ -1,10 +1,11 @@
 //! Pooled list data structure.
 
-use std::convert::TryFrom;
-use std::default::Default;
-use std::fmt::Debug;
-use std::marker::PhantomData;
-use std::ops::{Index, IndexMut};
+use crate::prelude::*;
+use core::convert::TryFrom;
+use core::default::Default;
+use core::fmt::Debug;
+use core::marker::PhantomData;
+use core::ops::{Index, IndexMut};
 
 #[derive(Clone, Debug)]
 pub struct ListPool<T: Clone + Debug> {
@@ -33,21 +34,48 @@ impl<T: Clone + Debug> ListPool<T> {
         let end = u32::try_from(self.storage.len()).unwrap();
         ListRef(start, end, PhantomData)
     }
+    /// Make room for `additional` more elements in the pool's storage.
+    pub fn reserve(&mut self, additional: usize) {
+        self.storage.reserve(additional);
+    }
     pub fn single(&mut self, value: T) -> ListRef<T> {
-        self.from_iter(std::iter::once(value))
+        self.from_iter(core::iter::once(value))
     }
     pub fn double(&mut self, a: T, b: T) -> ListRef<T> {
-        self.from_iter(std::iter::once(a).chain(std::iter::once(b)))
+        self.from_iter(core::iter::once(a).chain(core::iter::once(b)))
     }
     pub fn triple(&mut self, a: T, b: T, c: T) -> ListRef<T> {
         self.from_iter(
-            std::iter::once(a)
-                .chain(std::iter::once(b))
-                .chain(std::iter::once(c)),
+            core::iter::once(a)
+                .chain(core::iter::once(b))
+                .chain(core::iter::once(c)),
         )
     }
     pub fn allocate(&mut self, size: usize, initial: T) -> ListRef<T> {
-        self.from_iter(std::iter::repeat(initial).take(size))
+        self.from_iter(core::iter::repeat(initial).take(size))
+    }
+    /// Append `value` to `list`, a list that is only ever grown with
+    /// `push`. Such a list sits in a slot of two, four, eight, ...
+    /// elements, and moves to a slot twice as large at the end of the
+    /// storage when full; the slot it leaves is not reused until
+    /// `clear`.
+    pub fn push(&mut self, list: &mut ListRef<T>, value: T) {
+        let len = list.len();
+        if len == 0 || (len >= 2 && len.is_power_of_two()) {
+            let start = self.storage.len();
+            self.storage
+                .extend_from_within(list.0 as usize..list.1 as usize);
+            self.storage.resize(start + (2 * len).max(2), value.clone());
+            let start = u32::try_from(start).unwrap();
+            *list = ListRef(start, start + len as u32, PhantomData);
+        }
+        self.storage[list.1 as usize] = value;
+        list.1 += 1;
+    }
+    /// Drop every list in the pool, leaving all `ListRef`s into it
+    /// dangling.
+    pub fn clear(&mut self) {
+        self.storage.clear();
     }
     pub fn deep_clone(&mut self, list: ListRef<T>) -> ListRef<T> {
         self.storage.reserve(list.len());
@@ -74,6 +102,10 @@ impl<T: Clone + Debug> IndexMut<ListRef<T>> for ListPool<T> {
 }
 
 impl<T> ListRef<T> {
+    /// Forget the list's elements, without touching its pool.
+    pub fn clear(&mut self) {
+        *self = ListRef(0, 0, PhantomData);
+    }
     pub fn len(&self) -> usize {
         (self.1 - self.0) as usize
     }
@@ -0,0 +1,7 @@
+//! The `alloc` items that the `std` prelude brings into scope, for the
+//! modules that also build without `std`.
+
+pub(crate) use alloc::borrow::ToOwned;
+pub(crate) use alloc::boxed::Box;
+pub(crate) use alloc::string::{String, ToString};
+pub(crate) use alloc::vec::Vec;
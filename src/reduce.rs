@@ -0,0 +1,522 @@
+//! Reducing test cases at the IR level: shrinking a module while it
+//! stays "interesting", e.g. while it still crashes some engine.
+//!
+//! The reducer removes exports, custom sections, segments, table
+//! elements and functions, stubs out bodies, cuts branches and control
+//! flow, and removes or zeroes instructions, keeping each change only
+//! if the module it compiles to is valid and still interesting.
+//! Changes are tried in chunks that halve down to single items, as in
+//! delta debugging, and the passes repeat until none of them makes
+//! progress.
+
+use crate::cfg::CFGInfo;
+use crate::entity::{EntityRef, PerEntity};
+use crate::ir::*;
+use crate::Operator;
+use anyhow::{bail, Result};
+
+/// The outcome of `reduce`.
+pub struct Reduced<'a> {
+    pub module: Module<'a>,
+    /// `module`, compiled.
+    pub bytes: Vec<u8>,
+    /// How many candidates were given to the predicate.
+    pub tests: usize,
+    /// How many of them were interesting, and kept.
+    pub accepted: usize,
+}
+
+/// Shrink `module` while `interesting`, given the compiled module,
+/// returns true. Bodies are parsed as they are reduced. Fails if the
+/// module is not interesting to begin with, or if the predicate fails.
+///
+/// ```ignore
+/// let reduced = reduce(module, |bytes| Ok(engine.crashes_on(bytes)))?;
+/// ```
+pub fn reduce<'a, P>(module: Module<'a>, interesting: P) -> Result<Reduced<'a>>
+where
+    P: FnMut(&[u8]) -> Result<bool>,
+{
+    let bytes = module.to_wasm_bytes()?;
+    let mut reducer = Reducer {
+        module,
+        bytes,
+        interesting,
+        tests: 1,
+        accepted: 0,
+    };
+    if !(reducer.interesting)(&reducer.bytes)? {
+        bail!("The module is not interesting to begin with");
+    }
+    loop {
+        let accepted = reducer.accepted;
+        reducer.reduce_module()?;
+        for func in reducer.module.funcs.iter().collect::<Vec<_>>() {
+            reducer.reduce_body(func)?;
+        }
+        log::debug!(
+            "reduce: {} bytes after {} tests",
+            reducer.bytes.len(),
+            reducer.tests
+        );
+        if reducer.accepted == accepted {
+            break;
+        }
+    }
+    Ok(Reduced {
+        module: reducer.module,
+        bytes: reducer.bytes,
+        tests: reducer.tests,
+        accepted: reducer.accepted,
+    })
+}
+
+struct Reducer<'a, P> {
+    module: Module<'a>,
+    bytes: Vec<u8>,
+    interesting: P,
+    tests: usize,
+    accepted: usize,
+}
+
+impl<'a, P: FnMut(&[u8]) -> Result<bool>> Reducer<'a, P> {
+    /// Keep `candidate` if it compiles to a valid module that is
+    /// still interesting.
+    fn test(&mut self, candidate: Module<'a>) -> Result<bool> {
+        let bytes = match candidate.to_wasm_bytes() {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                log::trace!("reduce: candidate does not compile: {}", err);
+                return Ok(false);
+            }
+        };
+        let features = wasmparser::WasmFeatures {
+            threads: true,
+            ..Default::default()
+        };
+        if let Err(err) = wasmparser::Validator::new_with_features(features).validate_all(&bytes) {
+            log::trace!("reduce: candidate is not valid: {}", err);
+            return Ok(false);
+        }
+        self.tests += 1;
+        if !(self.interesting)(&bytes)? {
+            log::trace!("reduce: candidate is not interesting");
+            return Ok(false);
+        }
+        self.module = candidate;
+        self.bytes = bytes;
+        self.accepted += 1;
+        Ok(true)
+    }
+
+    /// Try `change` on chunks of `items`, from all of them down to
+    /// one at a time, on a copy of the module. `change` returns false
+    /// if it changed nothing.
+    fn shrink<T: Copy>(
+        &mut self,
+        mut items: Vec<T>,
+        mut change: impl FnMut(&mut Module<'a>, &[T]) -> Result<bool>,
+    ) -> Result<()> {
+        let mut chunk = items.len();
+        while chunk > 0 {
+            let mut start = 0;
+            while start < items.len() {
+                let end = (start + chunk).min(items.len());
+                let mut candidate = self.module.clone();
+                if change(&mut candidate, &items[start..end])? && self.test(candidate)? {
+                    items.drain(start..end);
+                } else {
+                    start = end;
+                }
+            }
+            chunk /= 2;
+        }
+        Ok(())
+    }
+
+    /// Like `shrink`, on the body of `func`. Changed bodies are
+    /// validated before they are compiled.
+    fn shrink_body<T: Copy>(
+        &mut self,
+        func: Func,
+        items: Vec<T>,
+        mut change: impl FnMut(&mut FunctionBody, &[T]) -> bool,
+    ) -> Result<()> {
+        self.shrink(items, |module, items| {
+            let mut body = module.clone_and_expand_body(func)?;
+            if !change(&mut body, items) {
+                return Ok(false);
+            }
+            prune(&mut body);
+            if let Err(err) = body.validate() {
+                log::trace!("reduce: candidate body is not valid: {}", err);
+                return Ok(false);
+            }
+            module.replace_body(func, body);
+            Ok(true)
+        })
+    }
+
+    fn reduce_module(&mut self) -> Result<()> {
+        let exports = (0..self.module.exports.len()).collect::<Vec<_>>();
+        let names = self
+            .module
+            .exports
+            .iter()
+            .map(|export| export.name.clone())
+            .collect::<Vec<_>>();
+        self.shrink(exports, |module, exports| {
+            module
+                .exports
+                .retain(|export| !exports.iter().any(|&index| names[index] == export.name));
+            Ok(true)
+        })?;
+        if self.module.start_func.is_some() {
+            let mut candidate = self.module.clone();
+            candidate.start_func = None;
+            self.test(candidate)?;
+        }
+
+        if !self.module.dwarf_sections.is_empty() {
+            let mut candidate = self.module.clone();
+            candidate.strip(&StripOptions {
+                dwarf: true,
+                ..StripOptions::default()
+            });
+            self.test(candidate)?;
+        }
+        // Custom sections and segments, one at a time from the last,
+        // so that the indices of those before stay the same.
+        for index in (0..self.module.custom_sections.len()).rev() {
+            let mut candidate = self.module.clone();
+            candidate.custom_sections.remove(index);
+            self.test(candidate)?;
+        }
+        for index in (0..self.module.data_segments.len()).rev() {
+            let mut candidate = self.module.clone();
+            if candidate.remove_data_segment(index as u32).is_ok() {
+                self.test(candidate)?;
+            }
+        }
+        for index in (0..self.module.elem_segments.len()).rev() {
+            let mut candidate = self.module.clone();
+            if candidate.remove_elem_segment(index as u32).is_ok() {
+                self.test(candidate)?;
+            }
+        }
+
+        let elements = self
+            .module
+            .tables
+            .entries()
+            .flat_map(|(table, data)| {
+                let elts = data.func_elements.as_deref().unwrap_or(&[]);
+                (0..elts.len())
+                    .filter(move |&i| elts[i].is_valid())
+                    .map(move |i| (table, i))
+            })
+            .collect();
+        self.shrink(elements, |module, elements| {
+            for &(table, i) in elements {
+                module.tables[table].func_elements.as_mut().unwrap()[i] = Func::invalid();
+            }
+            Ok(true)
+        })?;
+
+        let funcs = self
+            .module
+            .funcs
+            .entries()
+            .filter(|(_, decl)| match decl {
+                FuncDecl::Lazy(..) => true,
+                FuncDecl::Body(_, _, body) => !is_stub(body),
+                _ => false,
+            })
+            .map(|(func, _)| func)
+            .collect::<Vec<_>>();
+        self.shrink(funcs, |module, funcs| {
+            for &func in funcs {
+                let body = stub(module, module.funcs[func].sig());
+                module.replace_body(func, body);
+            }
+            Ok(true)
+        })?;
+
+        let mut candidate = self.module.clone();
+        match candidate.remove_unreachable() {
+            Ok(_) if counts(&candidate) != counts(&self.module) => {
+                self.test(candidate)?;
+            }
+            Ok(_) => {}
+            Err(err) => log::trace!("reduce: cannot remove unreachable entities: {}", err),
+        }
+        // Unused entities that are not all unreachable together, one
+        // at a time from the last, as for segments.
+        for index in (0..self.module.funcs.len()).rev() {
+            let mut candidate = self.module.clone();
+            match candidate.remove_function(Func::new(index)) {
+                Ok(_) => {
+                    self.test(candidate)?;
+                }
+                Err(err) => log::trace!("reduce: {}", err),
+            }
+        }
+        for index in (0..self.module.globals.len()).rev() {
+            let mut candidate = self.module.clone();
+            if candidate.remove_global(Global::new(index)).is_ok() {
+                self.test(candidate)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn reduce_body(&mut self, func: Func) -> Result<()> {
+        match &self.module.funcs[func] {
+            FuncDecl::Body(_, _, body) if !is_stub(body) => {}
+            FuncDecl::Lazy(..) => {}
+            _ => return Ok(()),
+        }
+
+        // Take one side of each branch, then cut control flow off.
+        for last in [false, true] {
+            let blocks = branching_blocks(&self.module.clone_and_expand_body(func)?);
+            self.shrink_body(func, blocks, |body, blocks| {
+                for &block in blocks {
+                    let target = match &body.blocks[block].terminator {
+                        Terminator::CondBr {
+                            if_true, if_false, ..
+                        } => (if last { if_false } else { if_true }).clone(),
+                        Terminator::Select {
+                            targets, default, ..
+                        } => match targets.first() {
+                            Some(target) if !last => target.clone(),
+                            _ => default.clone(),
+                        },
+                        _ => continue,
+                    };
+                    body.blocks[block].terminator = Terminator::Br { target };
+                }
+                true
+            })?;
+        }
+        let body = self.module.clone_and_expand_body(func)?;
+        let blocks = CFGInfo::new(&body).rpo.values().copied().collect();
+        self.shrink_body(func, blocks, |body, blocks| {
+            let mut changed = false;
+            for &block in blocks {
+                if traps(body, block) {
+                    continue;
+                }
+                for inst in std::mem::take(&mut body.blocks[block].insts) {
+                    body.value_blocks[inst] = Block::invalid();
+                }
+                body.blocks[block].terminator = Terminator::Unreachable;
+                changed = true;
+            }
+            changed
+        })?;
+
+        // Remove unused instructions, and replace those that produce a
+        // number with zero, which leaves their operands unused. Only
+        // the instructions tried are removed, so that those that stay
+        // in the module, used or not, can keep it interesting.
+        let body = self.module.clone_and_expand_body(func)?;
+        let insts = body
+            .blocks
+            .values()
+            .flat_map(|block| block.insts.iter().copied())
+            .collect();
+        self.shrink_body(func, insts, |body, insts| {
+            let used = used_values(body);
+            let mut changed = false;
+            for &inst in insts {
+                let block = body.value_blocks[inst];
+                if block.is_invalid() {
+                    continue;
+                }
+                if !used[inst] {
+                    body.blocks[block].insts.retain(|&other| other != inst);
+                    body.value_blocks[inst] = Block::invalid();
+                    changed = true;
+                    continue;
+                }
+                let tys = match &body.values[inst] {
+                    ValueDef::Operator(op, _, tys) if !is_const(op) => *tys,
+                    _ => continue,
+                };
+                if let [ty] = body.type_pool[tys] {
+                    if let Some(op) = zero(ty) {
+                        let args = body.arg_pool.from_iter(std::iter::empty());
+                        body.values[inst] = ValueDef::Operator(op, args, tys);
+                        changed = true;
+                    }
+                }
+            }
+            changed
+        })
+    }
+}
+
+/// The values that the instructions and terminators of `body` use.
+fn used_values(body: &FunctionBody) -> PerEntity<Value, bool> {
+    let mut used = PerEntity::default();
+    let mut mark = |value: Value| {
+        used[value] = true;
+        used[body.resolve_alias(value)] = true;
+    };
+    for block in body.blocks.values() {
+        for &inst in &block.insts {
+            body.values[inst].visit_uses(&body.arg_pool, &mut mark);
+        }
+        block.terminator.visit_uses(&mut mark);
+    }
+    used
+}
+
+/// A body of `sig` that returns zeroes, or traps if it returns
+/// something that has no zero constant.
+fn stub(module: &Module<'_>, sig: Signature) -> FunctionBody {
+    let mut body = FunctionBody::new(module, sig);
+    let entry = body.entry;
+    let zeroes = body
+        .rets
+        .clone()
+        .into_iter()
+        .map(zero)
+        .collect::<Option<Vec<_>>>();
+    let terminator = match zeroes {
+        Some(zeroes) => {
+            let values = zeroes
+                .into_iter()
+                .zip(body.rets.clone())
+                .map(|(op, ty)| {
+                    let args = body.arg_pool.from_iter(std::iter::empty());
+                    let tys = body.single_type_list(ty);
+                    let value = body.add_value(ValueDef::Operator(op, args, tys));
+                    body.append_to_block(entry, value);
+                    value
+                })
+                .collect();
+            Terminator::Return { values }
+        }
+        None => Terminator::Unreachable,
+    };
+    body.set_terminator(entry, terminator);
+    body
+}
+
+/// Whether `body` is already as small as `stub` makes it.
+fn is_stub(body: &FunctionBody) -> bool {
+    body.blocks.len() == 1
+        && body.blocks[body.entry]
+            .insts
+            .iter()
+            .all(|&inst| matches!(&body.values[inst], ValueDef::Operator(op, _, _) if is_const(op)))
+        && matches!(
+            body.blocks[body.entry].terminator,
+            Terminator::Return { .. } | Terminator::Unreachable
+        )
+}
+
+/// The reachable blocks of `body` that end in a conditional branch.
+fn branching_blocks(body: &FunctionBody) -> Vec<Block> {
+    CFGInfo::new(body)
+        .rpo
+        .values()
+        .copied()
+        .filter(|&block| {
+            matches!(
+                body.blocks[block].terminator,
+                Terminator::CondBr { .. } | Terminator::Select { .. }
+            )
+        })
+        .collect()
+}
+
+/// Turn branches to blocks that only trap into traps, and empty the
+/// blocks of `body` that can no longer be reached, so that nothing
+/// they did, or were passed, stays live. Then forget the instructions
+/// that are no longer in a block.
+fn prune(body: &mut FunctionBody) {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in 0..body.blocks.len() {
+            let block = Block::new(block);
+            if let Terminator::Br { target } = &body.blocks[block].terminator {
+                if target.block != block && traps(body, target.block) {
+                    body.blocks[block].terminator = Terminator::Unreachable;
+                    changed = true;
+                }
+            }
+        }
+    }
+    body.recompute_edges();
+
+    let cfg = CFGInfo::new(body);
+    let mut pruned = false;
+    for block in 0..body.blocks.len() {
+        let block = Block::new(block);
+        if cfg.rpo_pos[block].is_none() && !traps(body, block) {
+            for inst in std::mem::take(&mut body.blocks[block].insts) {
+                body.value_blocks[inst] = Block::invalid();
+            }
+            body.blocks[block].terminator = Terminator::Unreachable;
+            pruned = true;
+        }
+    }
+    if pruned {
+        body.recompute_edges();
+    }
+
+    // What is no longer in a block no longer refers to anything, e.g.
+    // for `Module::remove_function`.
+    let mut placed = PerEntity::default();
+    for block in body.blocks.values() {
+        for &inst in &block.insts {
+            placed[inst] = true;
+        }
+    }
+    for (value, def) in body.values.entries_mut() {
+        if !placed[value] && matches!(def, ValueDef::Operator(..) | ValueDef::Trace(..)) {
+            *def = ValueDef::None;
+        }
+    }
+}
+
+fn traps(body: &FunctionBody, block: Block) -> bool {
+    body.blocks[block].insts.is_empty()
+        && matches!(body.blocks[block].terminator, Terminator::Unreachable)
+}
+
+/// How many of each kind of entity `module` has, that
+/// `remove_unreachable` could remove.
+fn counts(module: &Module<'_>) -> [usize; 5] {
+    [
+        module.funcs.len(),
+        module.globals.len(),
+        module.tables.len(),
+        module.memories.len(),
+        module.imports.len(),
+    ]
+}
+
+fn zero(ty: Type) -> Option<Operator> {
+    match ty {
+        Type::I32 => Some(Operator::I32Const { value: 0 }),
+        Type::I64 => Some(Operator::I64Const { value: 0 }),
+        Type::F32 => Some(Operator::F32Const { value: 0 }),
+        Type::F64 => Some(Operator::F64Const { value: 0 }),
+        _ => None,
+    }
+}
+
+fn is_const(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::I32Const { .. }
+            | Operator::I64Const { .. }
+            | Operator::F32Const { .. }
+            | Operator::F64Const { .. }
+    )
+}
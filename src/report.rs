@@ -0,0 +1,201 @@
+//! Structured results of a run, for tools that gate on code size or
+//! compile time: sizes before and after, per function, what each pass
+//! did, and the warnings and skipped functions along the way.
+
+use crate::backend::SizeProfile;
+use crate::entity::EntityRef;
+use crate::ir::{Func, Module};
+use crate::json;
+use crate::passes::manager::{PassStats, Statistics};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use wasmparser::{Payload, TypeRef};
+
+/// The size of one function's body, in the input and in the output.
+/// Sizes include local declarations but not the size prefix, as in
+/// `FuncSize`; they are `None` where the function has no body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuncReport {
+    pub func: Func,
+    pub name: String,
+    pub size_before: Option<u32>,
+    pub size_after: Option<u32>,
+}
+
+/// What a run of the optimizer did to a module.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub size_before: usize,
+    /// `None` if no module was emitted.
+    pub size_after: Option<usize>,
+    /// Wall-clock time of the whole run, if measured.
+    pub time: Option<Duration>,
+    /// In function index order.
+    pub funcs: Vec<FuncReport>,
+    /// Every pass run, in order; a pass run by several pass managers
+    /// appears once for each.
+    pub passes: Vec<PassStats>,
+    pub analysis_time: Duration,
+    pub analyses_computed: usize,
+    pub warnings: Vec<String>,
+    /// Bodies kept as their original bytes, with why.
+    pub skipped: Vec<(Func, String)>,
+}
+
+/// The body sizes of the module `bytes`, by function.
+fn body_sizes(bytes: &[u8]) -> Result<BTreeMap<Func, u32>> {
+    let mut sizes = BTreeMap::new();
+    let mut next_func = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(bytes) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    if let TypeRef::Func(_) = import?.ty {
+                        next_func += 1;
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                sizes.insert(Func::new(next_func), body.range().len() as u32);
+                next_func += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(sizes)
+}
+
+impl Report {
+    /// The report on `module`, parsed from `input`, and compiled to a
+    /// module with the size profile `output` if it was. Pass
+    /// statistics are added with `add_stats`.
+    pub fn new(module: &Module<'_>, input: &[u8], output: Option<&SizeProfile>) -> Result<Report> {
+        let before = body_sizes(input)?;
+        let after = output
+            .map(|profile| {
+                profile
+                    .funcs
+                    .iter()
+                    .map(|func| (func.func, func.size))
+                    .collect::<BTreeMap<_, _>>()
+            })
+            .unwrap_or_default();
+        let funcs = module
+            .funcs
+            .entries()
+            .map(|(func, decl)| FuncReport {
+                func,
+                name: decl.name().to_owned(),
+                size_before: before.get(&func).copied(),
+                size_after: after.get(&func).copied(),
+            })
+            .collect();
+        Ok(Report {
+            size_before: input.len(),
+            size_after: output.map(|profile| profile.total as usize),
+            time: None,
+            funcs,
+            passes: vec![],
+            analysis_time: Duration::ZERO,
+            analyses_computed: 0,
+            warnings: module.warnings(),
+            skipped: module
+                .skipped_funcs()
+                .iter()
+                .map(|(&func, err)| (func, err.to_string()))
+                .collect(),
+        })
+    }
+
+    /// Add what a pass manager's run did.
+    pub fn add_stats(&mut self, stats: &Statistics) {
+        self.passes.extend(stats.passes.iter().cloned());
+        self.analysis_time += stats.analysis_time;
+        self.analyses_computed += stats.analyses_computed;
+    }
+
+    /// The time spent in passes and analyses.
+    pub fn pass_time(&self) -> Duration {
+        self.passes.iter().map(|pass| pass.time).sum::<Duration>() + self.analysis_time
+    }
+
+    /// The report as a JSON object with `size_before`, `size_after`,
+    /// `time_ms`, `pass_time_ms`, `functions` (with `index`, `name`,
+    /// `size_before` and `size_after`), `passes` (with `name`, `runs`,
+    /// `time_ms`, `insts_before`, `insts_after`, `params_before` and
+    /// `params_after`), `analysis_time_ms`, `analyses_computed`,
+    /// `warnings`, and `skipped` (with `index`, `name` and `reason`).
+    /// What is not known is `null`.
+    pub fn to_json(&self) -> String {
+        fn opt<T: ToString>(value: Option<T>) -> String {
+            value.map_or("null".to_owned(), |value| value.to_string())
+        }
+        fn ms(time: Duration) -> String {
+            format!("{:.3}", time.as_secs_f64() * 1e3)
+        }
+        let funcs = self
+            .funcs
+            .iter()
+            .map(|func| {
+                format!(
+                    "{{\"index\":{},\"name\":{},\"size_before\":{},\"size_after\":{}}}",
+                    func.func.index(),
+                    json::string(&func.name),
+                    opt(func.size_before),
+                    opt(func.size_after)
+                )
+            })
+            .collect::<Vec<_>>();
+        let passes = self
+            .passes
+            .iter()
+            .map(|pass| {
+                format!(
+                    "{{\"name\":{},\"runs\":{},\"time_ms\":{},\"insts_before\":{},\"insts_after\":{},\"params_before\":{},\"params_after\":{}}}",
+                    json::string(&pass.name),
+                    pass.runs,
+                    ms(pass.time),
+                    pass.insts_before,
+                    pass.insts_after,
+                    pass.params_before,
+                    pass.params_after
+                )
+            })
+            .collect::<Vec<_>>();
+        let warnings = self
+            .warnings
+            .iter()
+            .map(|warning| json::string(warning))
+            .collect::<Vec<_>>();
+        let skipped = self
+            .skipped
+            .iter()
+            .map(|(func, reason)| {
+                let name = self
+                    .funcs
+                    .get(func.index())
+                    .map_or("", |func| &func.name[..]);
+                format!(
+                    "{{\"index\":{},\"name\":{},\"reason\":{}}}",
+                    func.index(),
+                    json::string(name),
+                    json::string(reason)
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\"size_before\":{},\"size_after\":{},\"time_ms\":{},\"pass_time_ms\":{},\"functions\":[{}],\"passes\":[{}],\"analysis_time_ms\":{},\"analyses_computed\":{},\"warnings\":[{}],\"skipped\":[{}]}}\n",
+            self.size_before,
+            opt(self.size_after),
+            opt(self.time.map(ms)),
+            ms(self.pass_time()),
+            funcs.join(","),
+            passes.join(","),
+            ms(self.analysis_time),
+            self.analyses_computed,
+            warnings.join(","),
+            skipped.join(",")
+        )
+    }
+}
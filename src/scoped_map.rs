@@ -1,8 +1,9 @@
 //! Scoped hashmap.
 
-use fxhash::FxHashMap;
-use std::fmt::Debug;
-use std::hash::Hash;
+use crate::collections::FxHashMap;
+use crate::prelude::*;
+use core::fmt::Debug;
+use core::hash::Hash;
 
 #[derive(Clone, Debug)]
 pub struct ScopedMap<K: Hash + Eq + Clone + Debug, V: Clone + Debug> {
@@ -11,7 +12,7 @@ pub struct ScopedMap<K: Hash + Eq + Clone + Debug, V: Clone + Debug> {
     gen_by_level: Vec<u32>,
 }
 
-impl<K: Hash + Eq + Clone + Debug, V: Clone + Debug> std::default::Default for ScopedMap<K, V> {
+impl<K: Hash + Eq + Clone + Debug, V: Clone + Debug> core::default::Default for ScopedMap<K, V> {
     fn default() -> Self {
         ScopedMap::new()
     }
@@ -0,0 +1,20 @@
+//! Spans and events for `tracing`, compiled out without the `tracing`
+//! feature. Field values are only evaluated with it enabled.
+
+/// Enter an info-level span, as `tracing::info_span!` takes its name and
+/// fields, until the end of the enclosing scope.
+macro_rules! span {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        let _span = ::tracing::info_span!($($arg)+).entered();
+    };
+}
+
+/// Emit a debug-level event, as `tracing::debug!` takes its fields and
+/// message.
+macro_rules! event {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::debug!($($arg)+);
+    };
+}
@@ -0,0 +1,223 @@
+//! Proptest strategies that generate well-typed function bodies, for
+//! property-testing transforms against `FunctionBody::validate` and
+//! the interpreter:
+//!
+//! ```ignore
+//! proptest! {
+//!     #[test]
+//!     fn my_pass_preserves_behavior(gen in function_body(BodyConfig::default())) {
+//!         let mut module = gen.module.clone();
+//!         my_pass(module.func_body(gen.func).unwrap().unwrap());
+//!         module.funcs[gen.func].body().unwrap().validate().unwrap();
+//!         assert_eq!(gen.run(&gen.module), gen.run(&module));
+//!     }
+//! }
+//! ```
+//!
+//! Bodies are built from structured regions (straight-line code,
+//! sequences, ifs and counted loops), so their control flow is always
+//! reducible and they always terminate, though they may trap.
+
+use crate::gen;
+use crate::ir::*;
+use crate::prelude::*;
+use crate::{ConstVal, InterpContext, InterpResult};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::sample::Index;
+
+/// The size and shape of generated bodies.
+#[derive(Clone, Copy, Debug)]
+pub struct BodyConfig {
+    /// How deeply regions (sequences, ifs and loops) nest.
+    pub max_depth: usize,
+    /// How deeply loops nest, at most `max_depth`.
+    pub max_loop_depth: usize,
+    /// Regions in each sequence.
+    pub max_seq_len: usize,
+    /// Instructions in each straight-line run.
+    pub max_insts: usize,
+    pub max_params: usize,
+    pub max_results: usize,
+    /// Iterations of each loop; every loop runs at least once.
+    pub max_trip_count: u32,
+}
+
+impl Default for BodyConfig {
+    fn default() -> Self {
+        BodyConfig {
+            max_depth: 4,
+            max_loop_depth: 2,
+            max_seq_len: 3,
+            max_insts: 8,
+            max_params: 4,
+            max_results: 2,
+            max_trip_count: 4,
+        }
+    }
+}
+
+/// A module with one function, `func`, with a generated body, and
+/// arguments to call it with.
+#[derive(Clone, Debug)]
+pub struct GeneratedBody {
+    pub module: Module<'static>,
+    pub func: Func,
+    pub args: Vec<ConstVal>,
+}
+
+impl GeneratedBody {
+    pub fn body(&self) -> &FunctionBody {
+        self.module.funcs[self.func].body().unwrap()
+    }
+
+    /// Run `func` with `args` in the interpreter, in `module`: this
+    /// module, or a transformed copy of it.
+    pub fn run(&self, module: &Module) -> InterpResult {
+        match InterpContext::new(module) {
+            Ok(mut ctx) => ctx.call(module, self.func, &self.args),
+            Err(err) => panic!("Cannot instantiate generated module: {:?}", err),
+        }
+    }
+}
+
+/// Generate bodies of the size and shape `config` allows.
+pub fn function_body(config: BodyConfig) -> impl Strategy<Value = GeneratedBody> {
+    (
+        vec(prop::sample::select(gen::TYPES), 0..=config.max_params),
+        vec(prop::sample::select(gen::TYPES), 0..=config.max_results),
+        shape(&config, config.max_depth, config.max_loop_depth),
+        vec(any::<u64>(), config.max_params),
+    )
+        .prop_map(|(params, results, shape, bits)| build(params, results, &shape, &bits))
+}
+
+/// A region of structured control flow.
+#[derive(Clone, Debug)]
+enum Shape {
+    Straight(Vec<InstSpec>),
+    Seq(Vec<Shape>),
+    If(ValueSpec, Box<Shape>, Box<Shape>),
+    Loop(u32, Box<Shape>),
+}
+
+/// An instruction: the operator, and a choice for each argument.
+#[derive(Clone, Debug)]
+struct InstSpec {
+    op: Index,
+    args: [ValueSpec; 2],
+}
+
+/// One of the available values of some type, or a constant with
+/// `bits` if there is none.
+#[derive(Clone, Debug)]
+struct ValueSpec {
+    choice: Index,
+    bits: u64,
+}
+
+fn value_spec() -> impl Strategy<Value = ValueSpec> {
+    (any::<Index>(), any::<u64>()).prop_map(|(choice, bits)| ValueSpec { choice, bits })
+}
+
+fn shape(config: &BodyConfig, depth: usize, loop_depth: usize) -> BoxedStrategy<Shape> {
+    let inst = (any::<Index>(), value_spec(), value_spec())
+        .prop_map(|(op, a, b)| InstSpec { op, args: [a, b] });
+    // Alternatives are listed simplest first, which is the way failing
+    // cases shrink.
+    let straight = vec(inst, 0..=config.max_insts).prop_map(Shape::Straight);
+    if depth == 0 {
+        return straight.boxed();
+    }
+    let inner = shape(config, depth - 1, loop_depth);
+    let seq = vec(inner.clone(), 1..=config.max_seq_len).prop_map(Shape::Seq);
+    let if_ = (value_spec(), inner.clone(), inner)
+        .prop_map(|(cond, t, f)| Shape::If(cond, Box::new(t), Box::new(f)));
+    if loop_depth == 0 {
+        return prop_oneof![straight, seq, if_].boxed();
+    }
+    let loop_ = (
+        1..=config.max_trip_count.max(1),
+        shape(config, depth - 1, loop_depth - 1),
+    )
+        .prop_map(|(trips, body)| Shape::Loop(trips, Box::new(body)));
+    prop_oneof![straight, seq, if_, loop_].boxed()
+}
+
+fn build(params: Vec<Type>, results: Vec<Type>, shape: &Shape, bits: &[u64]) -> GeneratedBody {
+    let mut module = Module::with_orig_bytes(&[]);
+    let sig = module.find_or_add_signature(SignatureData {
+        params: params.clone(),
+        returns: results.clone(),
+    });
+    let mut builder = Builder {
+        regions: gen::Regions::new(FunctionBody::new(&module, sig)),
+    };
+    builder.build(shape);
+    let body = builder.regions.finish(&results);
+
+    let func = module.add_function(sig, "run", body);
+    module.exports.push(Export {
+        name: "run".to_owned(),
+        kind: ExportKind::Func(func),
+    });
+    let args = params
+        .iter()
+        .zip(bits)
+        .map(|(&ty, &bits)| match ty {
+            Type::I32 => ConstVal::I32(bits as u32),
+            _ => ConstVal::I64(bits),
+        })
+        .collect();
+    GeneratedBody { module, func, args }
+}
+
+struct Builder {
+    regions: gen::Regions,
+}
+
+impl Builder {
+    fn build(&mut self, shape: &Shape) {
+        match shape {
+            Shape::Straight(insts) => {
+                for inst in insts {
+                    let (op, arg_tys, ty) = gen::OPS[inst.op.index(gen::OPS.len())];
+                    let args = arg_tys
+                        .iter()
+                        .zip(&inst.args)
+                        .map(|(&arg_ty, spec)| self.pick(arg_ty, spec))
+                        .collect::<Vec<_>>();
+                    let args = self.regions.body.arg_pool.from_iter(args.into_iter());
+                    let tys = self.regions.body.single_type_list(ty);
+                    self.regions.push(ty, ValueDef::Operator(op, args, tys));
+                }
+            }
+            Shape::Seq(shapes) => {
+                for shape in shapes {
+                    self.build(shape);
+                }
+            }
+            Shape::If(cond, if_true, if_false) => {
+                let cond = self.pick(Type::I32, cond);
+                let region = self.regions.begin_if(cond);
+                self.build(if_true);
+                self.regions.else_(&region);
+                self.build(if_false);
+                self.regions.end_if(region);
+            }
+            Shape::Loop(trips, shape) => {
+                let region = self.regions.begin_loop(*trips);
+                self.build(shape);
+                self.regions.end_loop(region);
+            }
+        }
+    }
+
+    fn pick(&mut self, ty: Type, spec: &ValueSpec) -> Value {
+        let candidates = self.regions.candidates(ty);
+        if candidates.is_empty() {
+            return self.regions.constant(ty, spec.bits);
+        }
+        candidates[spec.choice.index(candidates.len())]
+    }
+}
@@ -0,0 +1,127 @@
+//! Streaming rewriting of function bodies: the module is read and
+//! written section by section, and function bodies one at a time, so
+//! that only one body is held as IR at once.
+
+use crate::backend::WasmFuncBackend;
+use crate::entity::EntityRef;
+use crate::errors::locate;
+use crate::frontend::{handle_section, parse_body, SectionOrder};
+use crate::ir::*;
+use crate::leb128::padded_u32;
+use anyhow::Result;
+use std::io::{Read, Seek, SeekFrom, Write};
+use wasm_encoder::Encode;
+use wasmparser::{BinaryReader, Chunk, Parser, Payload};
+
+/// The smallest amount read from the input at once.
+const READ_SIZE: u64 = 64 * 1024;
+
+/// Read a module from `input`, call `transform` on each function body
+/// once it is parsed, and write the module with the transformed bodies
+/// to `output` as it goes. `transform` sees the module as described by
+/// the sections before the code: the other functions have no bodies,
+/// and data segments and names are not read.
+///
+/// Sections other than code are copied verbatim, except those that
+/// describe the original bodies: DWARF sections, and the local and
+/// label names in the name section, are dropped. Memory use is bounded
+/// by the largest section other than code plus one function body.
+/// Returns the number of bodies transformed.
+pub fn transform<R, W, F>(mut input: R, mut output: W, mut transform: F) -> Result<usize>
+where
+    R: Read,
+    W: Write + Seek,
+    F: FnMut(&Module<'_>, Func, &mut FunctionBody) -> Result<()>,
+{
+    let mut module = Module::with_orig_bytes(&[]);
+    let mut next_func = 0;
+    let mut order = SectionOrder::default();
+    // The position of the code section's size, and how many of its
+    // bodies are still to come.
+    let mut code: Option<(u64, u32)> = None;
+    let mut transformed = 0;
+
+    let mut parser = Parser::new(0);
+    let mut buf = vec![];
+    let mut eof = false;
+    loop {
+        let parsed = parser.parse(&buf[..], eof);
+        let (consumed, payload) = match parsed.map_err(|e| locate(e.into(), None, None))? {
+            Chunk::NeedMoreData(hint) => {
+                let read = (&mut input)
+                    .take(hint.max(READ_SIZE))
+                    .read_to_end(&mut buf)?;
+                eof = read == 0;
+                continue;
+            }
+            Chunk::Parsed { consumed, payload } => (consumed, payload),
+        };
+        match payload {
+            Payload::Version { .. } => output.write_all(&buf[..consumed])?,
+            Payload::CodeSectionStart { count, .. } => {
+                output.write_all(&[10])?;
+                let size_at = output.stream_position()?;
+                output.write_all(&padded_u32(0))?;
+                let mut count_bytes = vec![];
+                count.encode(&mut count_bytes);
+                output.write_all(&count_bytes[..])?;
+                code = Some((size_at, count));
+            }
+            Payload::CodeSectionEntry(mut reader) => {
+                let func = Func::new(next_func);
+                next_func += 1;
+                let sig = module.funcs[func].sig();
+                let mut body = parse_body(&module, sig, &mut reader)
+                    .map_err(|e| locate(e, Some(func), None))?;
+                transform(&module, func, &mut body)?;
+                let backend = WasmFuncBackend::with_spill_config(&body, None)?;
+                let mut bytes = vec![];
+                backend.compile()?.encode(&mut bytes);
+                output.write_all(&bytes[..])?;
+                transformed += 1;
+                if let Some((_, remaining)) = &mut code {
+                    *remaining -= 1;
+                }
+            }
+            Payload::CustomSection(reader) if reader.name().starts_with(".debug_") => {}
+            Payload::CustomSection(reader) if reader.name() == "name" => {
+                let mut contents = vec![];
+                "name".encode(&mut contents);
+                let mut subsections = BinaryReader::new(reader.data());
+                while !subsections.eof() {
+                    let id = subsections.read_u8()?;
+                    let len = subsections.read_var_u32()?;
+                    let data = subsections.read_bytes(len as usize)?;
+                    // Local and label names.
+                    if id != 2 && id != 3 {
+                        contents.push(id);
+                        data.encode(&mut contents);
+                    }
+                }
+                let mut section = vec![0];
+                contents.encode(&mut section);
+                output.write_all(&section[..])?;
+            }
+            Payload::CustomSection(_) | Payload::DataSection(_) => {
+                output.write_all(&buf[..consumed])?
+            }
+            Payload::End(_) => break,
+            payload => {
+                output.write_all(&buf[..consumed])?;
+                handle_section(&mut module, payload, &mut next_func, &mut order)
+                    .map_err(|e| locate(e, None, None))?;
+            }
+        }
+        if let Some((size_at, 0)) = code {
+            let end = output.stream_position()?;
+            let size = (end - size_at - 5) as u32;
+            output.seek(SeekFrom::Start(size_at))?;
+            output.write_all(&padded_u32(size))?;
+            output.seek(SeekFrom::Start(end))?;
+            code = None;
+        }
+        buf.drain(..consumed);
+    }
+    output.flush()?;
+    Ok(transformed)
+}
@@ -0,0 +1,29 @@
+//! `std::sync::Mutex`, or without `std`, where there are no threads to
+//! share with, a `RefCell` behind the same interface.
+
+#[cfg(feature = "std")]
+pub(crate) use std::sync::Mutex;
+
+#[cfg(not(feature = "std"))]
+pub(crate) use self::cell::Mutex;
+
+#[cfg(not(feature = "std"))]
+mod cell {
+    use core::cell::{RefCell, RefMut};
+    use core::convert::Infallible;
+
+    #[derive(Debug, Default)]
+    pub(crate) struct Mutex<T>(RefCell<T>);
+
+    impl<T> Mutex<T> {
+        pub(crate) fn new(value: T) -> Mutex<T> {
+            Mutex(RefCell::new(value))
+        }
+
+        /// Borrow the value; like `std`'s, this returns a `Result`, but
+        /// it can't fail.
+        pub(crate) fn lock(&self) -> Result<RefMut<'_, T>, Infallible> {
+            Ok(self.0.borrow_mut())
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! A timeline of where a run spends its time: parsing, lifting and
+//! optimizing each function, compiling it and emitting each section,
+//! written out in the Trace Event Format that `chrome://tracing` and
+//! Perfetto read.
+
+use crate::prelude::*;
+use crate::sync::Mutex;
+use alloc::sync::Arc;
+use core::fmt::Write as _;
+#[cfg(feature = "std")]
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+#[cfg(all(
+    feature = "std",
+    not(all(target_arch = "wasm32", target_os = "unknown"))
+))]
+use std::time::Instant;
+
+/// One span of a timeline.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub name: String,
+    /// What kind of work it was: `parse`, `lift`, `pass`, `compile`
+    /// or `emit`.
+    pub category: &'static str,
+    /// When it started, since the timeline was created.
+    pub start: Duration,
+    pub duration: Duration,
+    /// The thread it ran on, numbered from 1 in the order threads
+    /// first recorded a span. Without `std`, it is always 1.
+    pub thread: u64,
+    pub args: Vec<(&'static str, String)>,
+}
+
+/// Spans recorded from any thread. Clones share their spans, so a
+/// timeline given in `FrontendOptions` collects those of every module
+/// parsed with it.
+#[derive(Clone, Debug)]
+pub struct Timeline {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    #[cfg(all(
+        feature = "std",
+        not(all(target_arch = "wasm32", target_os = "unknown"))
+    ))]
+    start: Instant,
+    events: Mutex<Vec<TraceEvent>>,
+}
+
+#[cfg(feature = "std")]
+static NEXT_THREAD: AtomicU64 = AtomicU64::new(1);
+
+#[cfg(feature = "std")]
+thread_local! {
+    static THREAD: u64 = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(feature = "std")]
+fn current_thread() -> u64 {
+    THREAD.with(|&thread| thread)
+}
+
+#[cfg(not(feature = "std"))]
+fn current_thread() -> u64 {
+    1
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Timeline::new()
+    }
+}
+
+impl Timeline {
+    pub fn new() -> Timeline {
+        Timeline {
+            inner: Arc::new(Inner {
+                #[cfg(all(
+                    feature = "std",
+                    not(all(target_arch = "wasm32", target_os = "unknown"))
+                ))]
+                start: Instant::now(),
+                events: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    /// The time since the timeline was created. There is no clock
+    /// without `std`, or on `wasm32-unknown-unknown`, where
+    /// `Instant::now` panics, so there it is always zero.
+    #[cfg(all(
+        feature = "std",
+        not(all(target_arch = "wasm32", target_os = "unknown"))
+    ))]
+    pub fn now(&self) -> Duration {
+        self.inner.start.elapsed()
+    }
+
+    #[cfg(not(all(
+        feature = "std",
+        not(all(target_arch = "wasm32", target_os = "unknown"))
+    )))]
+    pub fn now(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Record a span from `start` until now on the current thread.
+    pub fn record(
+        &self,
+        category: &'static str,
+        name: &str,
+        start: Duration,
+        args: Vec<(&'static str, String)>,
+    ) {
+        let event = TraceEvent {
+            name: name.to_owned(),
+            category,
+            start,
+            duration: self.now().saturating_sub(start),
+            thread: current_thread(),
+            args,
+        };
+        self.inner.events.lock().unwrap().push(event);
+    }
+
+    /// Start a span, recorded when it is dropped.
+    pub fn span(&self, category: &'static str, name: &str) -> TimelineSpan<'_> {
+        TimelineSpan {
+            timeline: self,
+            category,
+            name: name.to_owned(),
+            start: self.now(),
+            args: vec![],
+        }
+    }
+
+    /// The spans recorded so far, by start time.
+    pub fn events(&self) -> Vec<TraceEvent> {
+        let mut events = self.inner.events.lock().unwrap().clone();
+        events.sort_by_key(|event| (event.start, event.thread));
+        events
+    }
+
+    /// The spans recorded so far as a trace-event JSON object, with
+    /// each span a complete (`"X"`) event in microseconds.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"traceEvents\":[");
+        for (i, event) in self.events().iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(
+                out,
+                "\n{{\"name\":{},\"cat\":{},\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":1,\"tid\":{},\"args\":{{",
+                crate::json::string(&event.name),
+                crate::json::string(event.category),
+                event.start.as_secs_f64() * 1e6,
+                event.duration.as_secs_f64() * 1e6,
+                event.thread
+            )
+            .unwrap();
+            for (j, (key, value)) in event.args.iter().enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                write!(
+                    out,
+                    "{}:{}",
+                    crate::json::string(key),
+                    crate::json::string(value)
+                )
+                .unwrap();
+            }
+            out.push_str("}}");
+        }
+        out.push_str("\n],\"displayTimeUnit\":\"ms\"}\n");
+        out
+    }
+}
+
+/// A span of a `Timeline` in progress.
+pub struct TimelineSpan<'a> {
+    timeline: &'a Timeline,
+    category: &'static str,
+    name: String,
+    start: Duration,
+    args: Vec<(&'static str, String)>,
+}
+
+impl<'a> TimelineSpan<'a> {
+    /// Attach `value` to the span as `key`.
+    pub fn arg<T: core::fmt::Display>(mut self, key: &'static str, value: T) -> Self {
+        self.args.push((key, value.to_string()));
+        self
+    }
+}
+
+impl<'a> Drop for TimelineSpan<'a> {
+    fn drop(&mut self) {
+        self.timeline.record(
+            self.category,
+            &self.name,
+            self.start,
+            core::mem::take(&mut self.args),
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! A module instrumented by `passes::asyncify`, unwound out of an
+//! import and rewound under Wasmtime, against the uninstrumented
+//! module calling the same import synchronously.
+
+use waffle::passes::asyncify::{self, AsyncifyOptions};
+use waffle::{FrontendOptions, Module};
+use wasmtime::{Caller, Engine, Instance, Linker, Store};
+
+/// `run` calls `work`, which keeps values live across its call to the
+/// import `sleep`.
+const SLEEP: &str = r#"
+(module
+  (import "env" "sleep" (func $sleep (param i32) (result i32)))
+  (memory (export "memory") 1)
+  (func $work (param i32 i32) (result i32)
+    (local i32)
+    local.get 0
+    i32.const 10
+    i32.mul
+    local.set 2
+    local.get 2
+    local.get 1
+    call $sleep
+    i32.add
+    local.get 0
+    i32.sub
+    local.get 1
+    i32.mul)
+  (func (export "run") (param i32) (result i32)
+    local.get 0
+    i32.const 1
+    i32.add
+    local.get 0
+    call $work
+    i32.const 3
+    i32.xor))
+"#;
+
+/// Where the unwind data, the buffer's position and end, is kept; the
+/// buffer follows it.
+const DATA: i32 = 16;
+const DATA_END: i32 = 1024;
+
+fn sleep_result(x: i32) -> i32 {
+    x * 7 + 1
+}
+
+fn call<P: wasmtime::WasmParams, R: wasmtime::WasmResults, T>(
+    mut store: impl wasmtime::AsContextMut<Data = T>,
+    instance: &Instance,
+    name: &str,
+    params: P,
+) -> R {
+    instance
+        .get_typed_func::<P, R>(&mut store, name)
+        .unwrap()
+        .call(&mut store, params)
+        .unwrap()
+}
+
+fn export_call<P: wasmtime::WasmParams, R: wasmtime::WasmResults>(
+    caller: &mut Caller<'_, ()>,
+    name: &str,
+    params: P,
+) -> R {
+    let func = caller.get_export(name).unwrap().into_func().unwrap();
+    func.typed::<P, R>(&*caller)
+        .unwrap()
+        .call(&mut *caller, params)
+        .unwrap()
+}
+
+fn read_i32(store: &mut Store<()>, instance: &Instance, addr: i32) -> i32 {
+    let memory = instance.get_memory(&mut *store, "memory").unwrap();
+    let mut bytes = [0; 4];
+    memory.read(&*store, addr as usize, &mut bytes).unwrap();
+    i32::from_le_bytes(bytes)
+}
+
+#[test]
+fn unwind_and_rewind() {
+    let bytes = wat::parse_str(SLEEP).unwrap();
+    let engine = Engine::default();
+
+    // The uninstrumented module, with `sleep` returning at once.
+    let mut linker = Linker::new(&engine);
+    linker
+        .func_wrap("env", "sleep", |x: i32| sleep_result(x))
+        .unwrap();
+    let mut store = Store::new(&engine, ());
+    let orig = wasmtime::Module::new(&engine, &bytes).unwrap();
+    let instance = linker.instantiate(&mut store, &orig).unwrap();
+    let expected: i32 = call(&mut store, &instance, "run", 5);
+
+    let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+    asyncify::run(&mut module, &AsyncifyOptions::default()).unwrap();
+    let instrumented = module.to_wasm_bytes().unwrap();
+
+    // `sleep` unwinds the first time, and returns its result once
+    // rewound.
+    let mut linker = Linker::new(&engine);
+    linker
+        .func_wrap("env", "sleep", |mut caller: Caller<'_, ()>, x: i32| {
+            let state: i32 = export_call(&mut caller, "asyncify_get_state", ());
+            if state == 2 {
+                export_call::<(), ()>(&mut caller, "asyncify_stop_rewind", ());
+                return sleep_result(x);
+            }
+            let memory = caller.get_export("memory").unwrap().into_memory().unwrap();
+            let mut data = (DATA + 8).to_le_bytes().to_vec();
+            data.extend_from_slice(&DATA_END.to_le_bytes());
+            memory.write(&mut caller, DATA as usize, &data).unwrap();
+            export_call::<i32, ()>(&mut caller, "asyncify_start_unwind", DATA);
+            0
+        })
+        .unwrap();
+    let mut store = Store::new(&engine, ());
+    let instrumented = wasmtime::Module::new(&engine, &instrumented).unwrap();
+    let instance = linker.instantiate(&mut store, &instrumented).unwrap();
+
+    let _: i32 = call(&mut store, &instance, "run", 5);
+    let state: i32 = call(&mut store, &instance, "asyncify_get_state", ());
+    assert_eq!(state, 1);
+    call::<(), (), _>(&mut store, &instance, "asyncify_stop_unwind", ());
+    // The frames of `work` and `run` were saved.
+    assert!(read_i32(&mut store, &instance, DATA) > DATA + 8);
+
+    call::<i32, (), _>(&mut store, &instance, "asyncify_start_rewind", DATA);
+    let result: i32 = call(&mut store, &instance, "run", 5);
+    let state: i32 = call(&mut store, &instance, "asyncify_get_state", ());
+    assert_eq!(state, 0);
+    assert_eq!(result, expected);
+}
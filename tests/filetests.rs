@@ -0,0 +1,115 @@
+//! Runs the filetests in `tests/filetests`. Each `.wat` file there is
+//! parsed into IR, and transformed as its directives say; the IR is
+//! then printed and compared against the `.waffle` file next to it.
+//! Run with `WAFFLE_BLESS=1` to write the `.waffle` files instead.
+//!
+//! Directives are comments at the start of a line:
+//!
+//! - `;; passes: <pipeline>`: run the passes, comma-separated, as
+//!   `PassManager::from_pipeline` accepts them.
+//! - `;; roundtrip`: compile the module and parse it back before
+//!   running the passes, to test the backend.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use waffle::passes::manager::PassManager;
+use waffle::{FrontendOptions, Module};
+
+fn find_filetests(dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_filetests(&path, out)?;
+        } else if path.extension() == Some("wat".as_ref()) {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The printed IR of the filetest in `text`.
+fn run_filetest(text: &str) -> Result<String> {
+    let mut passes = None;
+    let mut roundtrip = false;
+    for line in text.lines() {
+        let directive = match line.trim().strip_prefix(";;") {
+            Some(directive) => directive.trim(),
+            None => continue,
+        };
+        if let Some(pipeline) = directive.strip_prefix("passes:") {
+            passes = Some(PassManager::from_pipeline(pipeline.trim())?);
+        } else if directive == "roundtrip" {
+            roundtrip = true;
+        }
+    }
+
+    let mut bytes = wat::parse_str(text)?;
+    if roundtrip {
+        let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default())?;
+        module.expand_all_funcs()?;
+        bytes = module.to_wasm_bytes()?;
+    }
+    let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default())?;
+    module.expand_all_funcs()?;
+    if let Some(passes) = passes {
+        passes.run(&mut module)?;
+    }
+    // The printed IR has trailing spaces here and there, which editors
+    // would strip from the expected output.
+    let mut output = String::new();
+    for line in format!("{}", module.display()).lines() {
+        output.push_str(line.trim_end());
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+#[test]
+fn filetests() {
+    let bless = std::env::var_os("WAFFLE_BLESS").is_some();
+    let mut paths = vec![];
+    find_filetests(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/filetests"),
+        &mut paths,
+    )
+    .unwrap();
+    paths.sort();
+    assert!(!paths.is_empty(), "No filetests found");
+
+    let mut failures = vec![];
+    for path in &paths {
+        let expected_path = path.with_extension("waffle");
+        let result = std::fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| run_filetest(&text))
+            .with_context(|| format!("Running {}", path.display()));
+        let actual = match result {
+            Ok(actual) => actual,
+            Err(err) => {
+                failures.push(format!("{:?}", err));
+                continue;
+            }
+        };
+        if bless {
+            std::fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&expected_path).unwrap_or_default();
+        if expected != actual {
+            failures.push(format!(
+                "{}: output differs from {}; run with WAFFLE_BLESS=1 to update it. Output:\n{}",
+                path.display(),
+                expected_path.display(),
+                actual
+            ));
+        }
+    }
+    if !failures.is_empty() {
+        panic!(
+            "{} of {} filetests failed:\n\n{}",
+            failures.len(),
+            paths.len(),
+            failures.join("\n\n")
+        );
+    }
+}
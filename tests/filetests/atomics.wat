@@ -0,0 +1,34 @@
+;; passes: gvn,load-store,resolve-aliases
+;;
+;; Atomics and fences order memory accesses across threads. The two
+;; plain loads before the fence are merged, but the one after it is
+;; not, and neither are the two atomic loads. The store is not
+;; forwarded past the read-modify-write to the load after it.
+(module
+  (memory 1 1 shared)
+  (func (param i32) (result i32)
+    local.get 0
+    i32.load
+    local.get 0
+    i32.load
+    i32.add
+    atomic.fence
+    local.get 0
+    i32.load
+    i32.add
+    local.get 0
+    i32.atomic.load
+    local.get 0
+    i32.atomic.load
+    i32.add
+    i32.add
+    local.get 0
+    i32.const 5
+    i32.store
+    local.get 0
+    i32.const 1
+    i32.atomic.rmw.add
+    drop
+    local.get 0
+    i32.load
+    i32.add))
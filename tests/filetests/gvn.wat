@@ -0,0 +1,8 @@
+;; passes: gvn
+;;
+;; The second constant is the same value as the first.
+(module
+  (func (result i32)
+    i32.const 7
+    i32.const 7
+    i32.add))
@@ -0,0 +1,13 @@
+;; passes: remove-phis,resolve-aliases
+;;
+;; The loop never changes local 1, so it needs no blockparam in the
+;; loop header.
+(module
+  (func (param i32 i32) (result i32)
+    (loop $l
+      local.get 0
+      i32.const 1
+      i32.sub
+      local.tee 0
+      br_if $l)
+    local.get 1))
@@ -0,0 +1,24 @@
+;; roundtrip
+;; passes: resolve-aliases,empty-blocks
+;;
+;; The backend keeps atomics in order with the plain accesses around
+;; them, rather than folding the first load into its use after them.
+(module
+  (memory 1 1 shared)
+  (func (param i32) (result i32)
+    local.get 0
+    i32.load
+    local.get 0
+    i32.const 1
+    i32.atomic.store
+    local.get 0
+    i32.const 0
+    i32.const 2
+    i32.atomic.rmw.cmpxchg
+    atomic.fence
+    local.get 0
+    i32.const 0
+    i64.const -1
+    memory.atomic.wait32
+    i32.add
+    i32.add))
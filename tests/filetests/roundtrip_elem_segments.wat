@@ -0,0 +1,16 @@
+;; roundtrip
+;;
+;; Passive and declared segments after an active one, which must keep
+;; their indices for `table.init` and `elem.drop`. The last segment
+;; goes into the table contents; the first can't, as it comes before
+;; the others.
+(module
+  (table 4 funcref)
+  (func $a (result i32)
+    i32.const 1)
+  (func $b (result i32)
+    i32.const 2)
+  (elem (i32.const 0) func $a)
+  (elem declare func $a)
+  (elem $p funcref (ref.func $b))
+  (elem (i32.const 1) func $b))
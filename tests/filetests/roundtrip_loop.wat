@@ -0,0 +1,18 @@
+;; roundtrip
+;; passes: resolve-aliases,empty-blocks
+;;
+;; A counted loop, through the backend and parsed back.
+(module
+  (func (param i32) (result i32)
+    (local i32)
+    (loop $l
+      local.get 1
+      local.get 0
+      i32.add
+      local.set 1
+      local.get 0
+      i32.const 1
+      i32.sub
+      local.tee 0
+      br_if $l)
+    local.get 1))
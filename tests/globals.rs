@@ -0,0 +1,64 @@
+//! Global initializers, kept through parsing, renumbering and
+//! serializing.
+
+use waffle::{FrontendOptions, Global, GlobalInit, Module, Type};
+
+const MODULE: &str = r#"
+(module
+  (import "env" "base" (global $base i32))
+  (global $unused (mut i32) (i32.const 7))
+  (global $derived i32 (global.get $base))
+  (func (export "get") (result i32)
+    global.get $derived))
+"#;
+
+#[test]
+fn global_get_init_survives_removal() {
+    let bytes = wat::parse_str(MODULE).unwrap();
+    let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+    let (base, unused, derived) = (Global::from(0u32), Global::from(1u32), Global::from(2u32));
+    assert_eq!(
+        module.globals[derived].init,
+        Some(GlobalInit::GlobalGet(base))
+    );
+    assert_eq!(module.global_value(derived), None);
+
+    // The import is read by `derived`'s initializer.
+    assert!(module.remove_global(base).is_err());
+
+    let mapping = module.remove_global(unused).unwrap();
+    assert_eq!(mapping.get(derived), Some(unused));
+    let output = module.to_wasm_bytes().unwrap();
+    wasmparser::Validator::new().validate_all(&output).unwrap();
+
+    let module = Module::from_wasm_bytes(&output, &FrontendOptions::default()).unwrap();
+    assert_eq!(module.globals.len(), 2);
+    assert_eq!(
+        module.globals[Global::from(1u32)].init,
+        Some(GlobalInit::GlobalGet(base))
+    );
+}
+
+#[test]
+fn add_global_checks_init() {
+    let bytes = wat::parse_str(MODULE).unwrap();
+    let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+    let base = Global::from(0u32);
+    assert!(module
+        .add_global(Type::I64, false, GlobalInit::GlobalGet(base))
+        .is_err());
+    assert!(module
+        .add_global(Type::I32, false, GlobalInit::GlobalGet(Global::from(1u32)))
+        .is_err());
+
+    let global = module
+        .add_global(Type::I32, true, GlobalInit::GlobalGet(base))
+        .unwrap();
+    let output = module.to_wasm_bytes().unwrap();
+    wasmparser::Validator::new().validate_all(&output).unwrap();
+    let module = Module::from_wasm_bytes(&output, &FrontendOptions::default()).unwrap();
+    assert_eq!(
+        module.globals[global].init,
+        Some(GlobalInit::GlobalGet(base))
+    );
+}
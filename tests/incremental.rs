@@ -0,0 +1,74 @@
+//! Serializing a module more than once with `set_incremental`, which
+//! reuses the code of the bodies that have not changed.
+
+use waffle::{FrontendOptions, Func, Module, Operator, ValueDef};
+
+const MODULE: &str = r#"
+(module
+  (func $main (export "main") (result i32)
+    call $one)
+  (func $one (result i32)
+    i32.const 1)
+  (func $two (result i32)
+    i32.const 2))
+"#;
+
+fn ops(module: &mut Module<'_>, func: Func) -> Vec<Operator> {
+    let body = module.func_body(func).unwrap().unwrap();
+    body.values
+        .values()
+        .filter_map(|def| match def {
+            ValueDef::Operator(op, ..) => Some(*op),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The operators of `func` in the serialized module `bytes`.
+fn ops_in(bytes: &[u8], func: Func) -> Vec<Operator> {
+    let mut module = Module::from_wasm_bytes(bytes, &FrontendOptions::default()).unwrap();
+    ops(&mut module, func)
+}
+
+fn parse(bytes: &[u8]) -> Module<'_> {
+    let mut module = Module::from_wasm_bytes(bytes, &FrontendOptions::default()).unwrap();
+    module.set_incremental(true);
+    module.expand_all_funcs().unwrap();
+    module
+}
+
+#[test]
+fn changed_body_is_recompiled() {
+    let bytes = wat::parse_str(MODULE).unwrap();
+    let mut module = parse(&bytes);
+    let one = Func::from(1u32);
+    let first = module.to_wasm_bytes().unwrap();
+    assert_eq!(ops_in(&first, one), vec![Operator::I32Const { value: 1 }]);
+
+    let body = module.func_body(one).unwrap().unwrap();
+    for def in body.values.values_mut() {
+        if let ValueDef::Operator(Operator::I32Const { value }, ..) = def {
+            *value = 3;
+        }
+    }
+    let second = module.to_wasm_bytes().unwrap();
+    assert_eq!(ops_in(&second, one), vec![Operator::I32Const { value: 3 }]);
+}
+
+#[test]
+fn renumbering_clears_cache() {
+    let bytes = wat::parse_str(MODULE).unwrap();
+    let mut module = parse(&bytes);
+    let main = Func::from(0u32);
+    module.to_wasm_bytes().unwrap();
+
+    // `main` keeps its index, but `one`, which it calls, moves.
+    module
+        .reorder_functions(&[main, Func::from(2u32), Func::from(1u32)])
+        .unwrap();
+    let output = module.to_wasm_bytes().unwrap();
+    let call = Operator::Call {
+        function_index: Func::from(2u32),
+    };
+    assert!(ops_in(&output, main).contains(&call));
+}
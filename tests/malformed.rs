@@ -0,0 +1,56 @@
+//! Bodies that don't validate, which the frontend must refuse with an
+//! error rather than a panic.
+
+use waffle::{FrontendOptions, Func, Module, WaffleError};
+
+/// A module whose second function, after an import, has `body`.
+fn module_with_body(body: &str) -> Vec<u8> {
+    wat::parse_str(format!(
+        r#"
+(module
+  (type $t (func (param i32) (result i32)))
+  (import "env" "f" (func $f (param i32) (result i32)))
+  (table 1 funcref)
+  (func (result i32)
+    {}))
+"#,
+        body
+    ))
+    .unwrap()
+}
+
+fn expect_malformed(body: &str) {
+    let bytes = module_with_body(body);
+    let mut module = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+    let err = module.expand_all_funcs().unwrap_err();
+    match err.downcast_ref::<WaffleError>() {
+        Some(WaffleError::MalformedInput { func, offset, .. }) => {
+            assert_eq!(*func, Some(Func::from(1u32)), "{}", body);
+            assert!(offset.is_some(), "{}", body);
+        }
+        _ => panic!("{}: {:?}", body, err),
+    }
+}
+
+#[test]
+fn stack_underflow() {
+    expect_malformed("i32.const 0 call_indirect (type $t)");
+    expect_malformed("i32.const 1 i32.add");
+    expect_malformed("i32.const 1 select");
+    expect_malformed("block (result i32) i32.const 1 end i32.const 2 drop drop drop i32.const 3");
+    expect_malformed("i32.const 1 (block (result i32) drop i32.const 0)");
+}
+
+#[test]
+fn type_mismatch() {
+    expect_malformed("i64.const 1 i32.const 2 i32.add");
+    expect_malformed("f32.const 0 call $f");
+}
+
+#[test]
+fn bad_index() {
+    expect_malformed("local.get 3");
+    expect_malformed("i32.const 0 br 2");
+    expect_malformed("i32.const 0 call 7");
+    expect_malformed("global.get 0");
+}
@@ -0,0 +1,56 @@
+//! Bodies kept as their original bytes by `set_skip_unsupported`, run
+//! under Wasmtime against the original module.
+
+use waffle::differential::{engine_diff, EngineDiffOptions};
+use waffle::{FrontendOptions, Module};
+
+/// `run` uses `table.init`, which the IR can't represent, on a passive
+/// segment between a declared and an active one.
+const TABLE_INIT: &str = r#"
+(module
+  (type $t (func (result i32)))
+  (table 4 funcref)
+  (func $a (result i32) i32.const 1)
+  (func $b (result i32) i32.const 2)
+  (elem declare func $a)
+  (elem $p funcref (ref.func $b))
+  (elem (i32.const 0) func $a)
+  (func (export "run") (result i32)
+    i32.const 1
+    i32.const 0
+    i32.const 1
+    table.init $p
+    i32.const 1
+    call_indirect (type $t)))
+"#;
+
+fn parse_and_skip(bytes: &[u8]) -> Module<'_> {
+    let mut module = Module::from_wasm_bytes(bytes, &FrontendOptions::default()).unwrap();
+    module.set_skip_unsupported(true);
+    module.expand_all_funcs().unwrap();
+    assert_eq!(module.skipped_funcs().len(), 1);
+    module
+}
+
+#[test]
+fn skipped_body_runs() {
+    let bytes = wat::parse_str(TABLE_INIT).unwrap();
+    let module = parse_and_skip(&bytes);
+    let output = module.to_wasm_bytes().unwrap();
+    // Instantiation, then each run of `run`.
+    let compared = engine_diff(&bytes, &output, &EngineDiffOptions::default()).unwrap();
+    assert!(compared > 1);
+}
+
+#[test]
+fn renumbered_segment_is_refused() {
+    let bytes = wat::parse_str(TABLE_INIT).unwrap();
+    let mut module = parse_and_skip(&bytes);
+    module.elem_segments.swap(0, 1);
+    let err = module.to_wasm_bytes().unwrap_err();
+    assert!(
+        format!("{:?}", err).contains("element segment 1 has changed"),
+        "{:?}",
+        err
+    );
+}
@@ -0,0 +1,84 @@
+//! Modules streamed through `stream::transform`, checked by parsing and
+//! validating the output.
+
+use std::io::Cursor;
+use waffle::{stream, FrontendOptions, Func, Module};
+
+fn identity(bytes: &[u8]) -> (usize, Vec<u8>) {
+    let mut output = Cursor::new(vec![]);
+    let transformed = stream::transform(bytes, &mut output, |_, _, _| Ok(())).unwrap();
+    let output = output.into_inner();
+    wasmparser::Validator::new().validate_all(&output).unwrap();
+    (transformed, output)
+}
+
+#[test]
+fn identity_transform() {
+    let bytes = wat::parse_str(
+        r#"
+(module
+  (import "env" "f" (func $f (param i32) (result i32)))
+  (memory 1)
+  (func (export "g") (param i32) (result i32)
+    local.get 0
+    call $f
+    i32.const 1
+    i32.add)
+  (func (export "h") (param i32 i32) (result i32)
+    local.get 0
+    if (result i32)
+      local.get 1
+    else
+      i32.const 0
+      i32.load
+    end)
+  (data (i32.const 0) "\2a"))
+"#,
+    )
+    .unwrap();
+    let (transformed, output) = identity(&bytes);
+    assert_eq!(transformed, 2);
+    let module = Module::from_wasm_bytes(&output, &FrontendOptions::default()).unwrap();
+    assert_eq!(module.funcs.len(), 3);
+    assert_eq!(module.data_segments.len(), 1);
+}
+
+#[test]
+fn no_code_section() {
+    let bytes = wat::parse_str(
+        r#"
+(module
+  (import "env" "f" (func (param i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 8) "hello"))
+"#,
+    )
+    .unwrap();
+    let (transformed, output) = identity(&bytes);
+    assert_eq!(transformed, 0);
+    assert_eq!(output, bytes);
+}
+
+#[test]
+fn local_names_are_dropped() {
+    let bytes = wat::parse_str(
+        r#"
+(module $m
+  (func $add (export "add") (param $x i32) (param $y i32) (result i32)
+    (local $sum i32)
+    local.get $x
+    local.get $y
+    i32.add
+    local.tee $sum))
+"#,
+    )
+    .unwrap();
+    let orig = Module::from_wasm_bytes(&bytes, &FrontendOptions::default()).unwrap();
+    assert!(!orig.names.locals.is_empty());
+
+    let (_, output) = identity(&bytes);
+    let module = Module::from_wasm_bytes(&output, &FrontendOptions::default()).unwrap();
+    assert_eq!(module.names.module.as_deref(), Some("m"));
+    assert_eq!(module.funcs[Func::from(0u32)].name(), "add");
+    assert!(module.names.locals.is_empty());
+}